@@ -0,0 +1,52 @@
+// Times the interpreter loop on small versions of the usual Lua benchmarks:
+// recursive calls, numeric loops, table and field access and string
+// building. Each is run a few times and the best time kept.
+//
+// Run with `cargo bench --bench interpreter`.
+
+extern crate syx;
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use syx::compiler::parse;
+use syx::state::SyxState;
+use syx::stdlib;
+
+const ROUNDS: usize = 5;
+
+const BENCHMARKS: &[(&str, &str)] = &[
+    ("fib", "local function fib(n) if n < 2 then return n end \
+             return fib(n - 1) + fib(n - 2) end \
+             return fib(27)"),
+    ("loops", "local sum = 0 for i = 1, 3000000 do \
+               if i % 3 == 0 then sum = sum + i else sum = sum - 1 end end \
+               return sum"),
+    ("sieve", "local count = 0 for _ = 1, 20 do \
+               local flags = {} for i = 2, 20000 do flags[i] = true end \
+               count = 0 for i = 2, 20000 do if flags[i] then count = count + 1 \
+               for k = i + i, 20000, i do flags[k] = false end end end end \
+               return count"),
+    ("fields", "local point = {x = 0, y = 0} for i = 1, 1000000 do \
+                point.x = point.x + 1 point.y = point.x * 2 end \
+                return point.y"),
+    ("strings", "local parts = {} for i = 1, 100000 do parts[#parts + 1] = i .. ',' end \
+                 return #table.concat(parts)"),
+];
+
+fn main() {
+    for &(name, source) in BENCHMARKS {
+        let proto = Arc::new(parse(source.as_bytes(), name).expect("benchmarks compile"));
+        let mut best = Duration::MAX;
+        let mut result = String::new();
+        for _ in 0..ROUNDS {
+            let mut state = SyxState::new();
+            stdlib::open_libs(&mut state);
+            let start = Instant::now();
+            let results = state.call(proto.clone(), Vec::new()).expect("benchmarks run");
+            best = best.min(start.elapsed());
+            result = format!("{:?}", results.first());
+        }
+        println!("{:<8} {:>10?}  ({})", name, best, result);
+    }
+}
@@ -0,0 +1,64 @@
+// Compares SyxValue with the NaN-boxed PackedValue on the work a register
+// file does: filling it, copying values around and reading numbers back.
+//
+// Run with `cargo bench --features nanbox`.
+
+extern crate syx;
+
+use std::mem::size_of;
+use std::time::{Duration, Instant};
+
+use syx::nanbox::PackedValue;
+use syx::object::{number_to_f64, SyxInteger, SyxNumber, SyxValue};
+
+const REGISTERS: usize = 1 << 16;
+const ROUNDS: usize = 200;
+
+fn sample(i: usize) -> SyxValue {
+    match i % 4 {
+        0 => SyxValue::Integer(i as SyxInteger),
+        1 => SyxValue::Number(i as SyxNumber / 2.0),
+        2 => SyxValue::Bool(i & 8 == 0),
+        _ => SyxValue::Nil,
+    }
+}
+
+fn sum(value: &SyxValue) -> f64 {
+    match *value {
+        SyxValue::Integer(i) => i as f64,
+        SyxValue::Number(n) => number_to_f64(n),
+        _ => 0.0,
+    }
+}
+
+fn time(name: &str, size: usize, mut f: impl FnMut() -> f64) {
+    let mut best = Duration::MAX;
+    let mut total = 0.0;
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        total += f();
+        best = best.min(start.elapsed());
+    }
+    println!("{:<12} {:>2} bytes  {:>10?} per round  (checksum {})", name, size, best, total);
+}
+
+fn main() {
+    let values: Vec<SyxValue> = (0..REGISTERS).map(sample).collect();
+    let packed: Vec<PackedValue> = values.iter().cloned().map(PackedValue::from).collect();
+
+    time("SyxValue", size_of::<SyxValue>(), || {
+        let mut registers = values.clone();
+        // MOVE R(i) := R(i + 1), then read every register
+        for i in 0..REGISTERS - 1 {
+            registers[i] = registers[i + 1].clone();
+        }
+        registers.iter().map(sum).sum()
+    });
+    time("PackedValue", size_of::<PackedValue>(), || {
+        let mut registers = packed.clone();
+        for i in 0..REGISTERS - 1 {
+            registers[i] = registers[i + 1].clone();
+        }
+        registers.iter().map(|value| sum(&value.get())).sum()
+    });
+}
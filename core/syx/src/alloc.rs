@@ -0,0 +1,65 @@
+// Allocator hook
+//
+// A host can hand the heap an Allocator, which hears of every collectable
+// object allocated and freed, of tables growing, and of strings entering and
+// leaving the state's string table, with its size as the collector counts it
+// (see gc.rs). Sizes balance: what is freed for an object is what was
+// allocated for it, and whatever is still alive when the state is dropped is
+// freed then. It can track memory use by kind, or keep a budget of its own:
+// once it is exhausted, allocation points try a full collection and raise
+// "not enough memory" if that does not help, as they do when a lua_Alloc
+// fails. Strings are built in buffers before the state hears of them, and
+// those ask it with `try_allocate` before they grow, so it can refuse a
+// string before it is built rather than after.
+//
+// Unlike a lua_Alloc it does not hand out the memory itself, which still
+// comes from the global allocator: stable Rust has no way to give the arenas
+// and the vectors inside objects another one. Strings are reference
+// counted rather than heap objects. The string table holds the short strings
+// and the long strings made through the state (concatenation, `intern`,
+// `undump`), and reports them until a collection finds nothing else refers to
+// them; strings built with SyxString::new are not seen.
+//
+// Consult the versioned lmem.c (lua_Alloc, luaM_realloc_) for more
+// information.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ObjectKind {
+    Table,
+    Function,
+    Upvalue,
+    Thread,
+    Native,
+    UserData,
+    String, // interned, or long and made through the state
+}
+
+impl ObjectKind {
+    // in the order the collector sweeps them, strings last
+    pub const ALL: [ObjectKind; 7] = [
+        ObjectKind::Table, ObjectKind::Function, ObjectKind::Upvalue, ObjectKind::Thread,
+        ObjectKind::Native, ObjectKind::UserData, ObjectKind::String,
+    ];
+}
+
+// Told of allocations, and asked before string buffers grow
+pub trait Allocator: Send {
+    // `size` more bytes for an object of `kind`
+    fn allocate(&mut self, kind: ObjectKind, size: usize);
+
+    // `size` bytes of objects of `kind` freed
+    fn free(&mut self, kind: ObjectKind, size: usize);
+
+    // whether allocating more should fail
+    fn exhausted(&self) -> bool {
+        false
+    }
+
+    // whether `size` bytes for an object of `kind` may be allocated, asked
+    // before a string buffer grows to that size; refusing raises "not enough
+    // memory" there. The bytes are only reported with `allocate` once the
+    // string is made.
+    fn try_allocate(&mut self, _kind: ObjectKind, _size: usize) -> bool {
+        !self.exhausted()
+    }
+}
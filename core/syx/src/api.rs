@@ -0,0 +1,163 @@
+// Stack API
+//
+// For code ported from the C API, a stack of values the host pushes to and
+// pops from, indexed as in Lua: 1 is the bottom, -1 the top, and an index
+// past the top is acceptable to read, as nil. It is separate from the
+// interpreter's registers and kept alive by the collector like them, but
+// nothing in the VM reads it; values still go in and out of scripts through
+// the calls in vm.rs and protect.rs.
+//
+// Every native function called sees only what it pushes itself: the values
+// below are hidden until it returns, when whatever it left is dropped. An
+// invalid index is a bug in the host and panics, as api_check would abort.
+//
+// Consult the versioned lapi.c for more information.
+
+use super::object::SyxValue;
+use super::state::SyxState;
+
+impl SyxState {
+    // Index counting from the bottom for `index`, None if it is 0 or
+    // below the bottom. Positive indices are returned as they are.
+    pub fn abs_index(&self, index: isize) -> Option<usize> {
+        let from_top = self.get_top() as isize + index + 1;
+        if index > 0 {
+            Some(index as usize)
+        } else if index < 0 && from_top > 0 {
+            Some(from_top as usize)
+        } else {
+            None
+        }
+    }
+
+    // number of values on the stack, the index of the top one
+    pub fn get_top(&self) -> usize {
+        self.api_stack.len() - self.api_base
+    }
+
+    // Pop values or push nils until the top is at `index`
+    pub fn set_top(&mut self, index: isize) {
+        let top = if index >= 0 { index as usize } else { self.valid_index(index) };
+        let len = self.api_base + top;
+        self.api_stack.resize(len, SyxValue::Nil);
+    }
+
+    pub fn push_value(&mut self, value: SyxValue) {
+        self.api_stack.push(value);
+    }
+
+    // top value, removed from the stack
+    pub fn pop(&mut self) -> Option<SyxValue> {
+        if self.get_top() == 0 {
+            return None;
+        }
+        self.api_stack.pop()
+    }
+
+    // value at `index`, nil past the top
+    pub fn get(&self, index: isize) -> SyxValue {
+        match self.abs_index(index) {
+            Some(i) if i <= self.get_top() => self.api_stack[self.api_base + i - 1].clone(),
+            Some(_) => SyxValue::Nil,
+            None => panic!("invalid stack index {}", index),
+        }
+    }
+
+    // Move the top value to `index`, shifting the ones above it up
+    pub fn insert(&mut self, index: isize) {
+        let i = self.api_base + self.valid_index(index) - 1;
+        let value = self.api_stack.pop().expect("value on the stack");
+        self.api_stack.insert(i, value);
+    }
+
+    // Remove the value at `index`, shifting the ones above it down
+    pub fn remove(&mut self, index: isize) -> SyxValue {
+        let i = self.api_base + self.valid_index(index) - 1;
+        self.api_stack.remove(i)
+    }
+
+    // index counting from the bottom of a value on the stack
+    fn valid_index(&self, index: isize) -> usize {
+        match self.abs_index(index) {
+            Some(i) if i <= self.get_top() => i,
+            _ => panic!("invalid stack index {}", index),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use super::super::compiler::parse;
+    use super::super::object::{MultiValue, SyxInteger};
+    use super::super::stdlib::set_field;
+    use super::super::testing::ints;
+
+    fn values(state: &SyxState) -> Vec<SyxValue> {
+        (1..=state.get_top() as isize).map(|i| state.get(i)).collect()
+    }
+
+    #[test]
+    fn test_stack() {
+        let mut state = SyxState::new();
+        for i in 1..=4 {
+            state.push_value(SyxValue::Integer(i));
+        }
+        assert_eq!(state.get_top(), 4);
+        assert_eq!(state.abs_index(-1), Some(4));
+        assert_eq!(state.abs_index(-4), Some(1));
+        assert_eq!(state.abs_index(-5), None);
+        assert_eq!(state.abs_index(0), None);
+        assert_eq!(state.get(-2), SyxValue::Integer(3));
+        assert_eq!(state.get(5), SyxValue::Nil);
+
+        state.insert(1);
+        assert_eq!(values(&state), ints(&[4, 1, 2, 3]));
+        assert_eq!(state.remove(-3), SyxValue::Integer(1));
+        assert_eq!(state.pop(), Some(SyxValue::Integer(3)));
+        state.set_top(4);
+        assert_eq!(values(&state), vec![
+            SyxValue::Integer(4), SyxValue::Integer(2), SyxValue::Nil, SyxValue::Nil,
+        ]);
+        state.set_top(-4);
+        assert_eq!(values(&state), ints(&[4]));
+        state.set_top(0);
+        assert_eq!(state.pop(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid stack index -2")]
+    fn test_invalid_index() {
+        let mut state = SyxState::new();
+        state.push_value(SyxValue::Nil);
+        state.remove(-2);
+    }
+
+    #[test]
+    fn test_natives() {
+        let mut state = SyxState::new();
+        // the values of the host are out of reach of natives, and a table
+        // kept only on the stack is not collected
+        let table = state.new_table(0, 0);
+        set_field(&mut state, table, "x", SyxValue::Integer(1));
+        state.push_value(SyxValue::Table(table));
+        state.register("count", |state, args| {
+            assert_eq!(state.get_top(), 0);
+            state.collect_garbage();
+            for value in args.values() {
+                state.push_value(value.clone());
+            }
+            let count = state.get_top() as SyxInteger;
+            state.push_value(SyxValue::Bool(true)); // dropped on return
+            Ok(MultiValue::from(vec![SyxValue::Integer(count)]))
+        });
+        let proto = parse(b"return count(1, 2, count(3, 4, 5))", "=test").unwrap();
+        let results = state.call(Arc::new(proto), vec![]).unwrap();
+        assert_eq!(results, vec![SyxValue::Integer(3)]);
+        assert_eq!(state.get_top(), 1);
+        assert_eq!(state.get(1), SyxValue::Table(table));
+        assert_eq!(state.table(table).get(&SyxValue::from("x")), SyxValue::Integer(1));
+    }
+}
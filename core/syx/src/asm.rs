@@ -0,0 +1,530 @@
+// Assembler
+//
+// Builds a Proto from text, so that test cases and odd chunks can be written
+// by hand without going through the compiler. Instructions are written the
+// way a listing shows them, the opcode name and then its operands, with
+// constants (RK operands, and those of LOADK and EXTRAARG) counting down from
+// -1; a "[line]" in front of one gives it a line, and "[line:column]" a
+// column as well. Directives set the rest:
+//
+//   .source "=name"       source, the chunk name or that of the enclosing
+//                         function otherwise
+//   .lines 1 4            linedefined and lastlinedefined
+//   .params 2             numparams
+//   .vararg 1             is_vararg
+//   .stack 5              maxstacksize, 2 otherwise
+//   .upval _ENV 1 0       name ("-" for none), instack and index
+//   .const "x"            nil, true, false, an integer, a float or a string
+//   .local x 2 6          name, and the instructions it is in scope from and
+//                         up to, counting from 1 as listings do
+//   .function             a function defined in this one, with directives
+//   .end                  and code of its own up to the matching .end
+//
+// Anything from a ";" outside of a string to the end of the line is a comment.
+// Strings are quoted with '"' and take the escapes of listings: \" \\ \a \b
+// \f \n \r \t \v and three decimal digits.
+//
+// The result is not verified; run `Proto::verify` on it when that matters.
+//
+// `Proto::assembly` writes any Proto out in this form, every directive and
+// instruction included and the comments of a listing after instructions, so
+// that assembling it again gives the same Proto back (see diff.rs). Chunks
+// can be kept as text where changes to them are reviewed.
+
+use std::convert::TryFrom;
+use std::fmt::Write;
+use std::sync::Arc;
+
+use super::debug::short_source;
+use super::errors::*;
+use super::limits::MAXINDEXRK;
+use super::listing::{constant_text, decode, opname, operands};
+use super::object::{LocVar, Proto, SyxInteger, SyxNumber, SyxString, SyxValue, Upvalue};
+use super::opcodes::{Decoded, Instruction, OpCode, Word};
+
+const BITRK: i64 = 1 << 8; // see vm.rs
+
+// Compile the assembly in `text` into a Proto, with `chunkname` as its source
+// unless it says otherwise
+pub fn assemble(text: &str, chunkname: &str) -> Result<Proto> {
+    let mut assembler = Assembler {
+        chunkname: short_source(chunkname).into_owned(),
+        line: 0,
+        functions: vec![function(chunkname)],
+    };
+    for (i, line) in text.lines().enumerate() {
+        assembler.line = i + 1;
+        let tokens = assembler.tokens(line)?;
+        if let Some((first, rest)) = tokens.split_first() {
+            assembler.statement(first, rest)?;
+        }
+    }
+    if assembler.functions.len() > 1 {
+        return assembler.error(".end expected");
+    }
+    let proto = assembler.functions.pop().expect("main function");
+    assembler.finish(proto)
+}
+
+fn function(source: &str) -> Proto {
+    let mut proto = Proto::new();
+    proto.source = source.to_owned();
+    proto.maxstacksize = 2;
+    proto
+}
+
+// The opcode printed as `name` in listings
+fn opcode(name: &str) -> Option<OpCode> {
+    (0..=u8::MAX)
+        .map_while(|n| OpCode::try_from(n).ok())
+        .find(|&op| opname(op).eq_ignore_ascii_case(name))
+}
+
+struct Assembler {
+    chunkname: String,
+    line: usize,
+    functions: Vec<Proto>, // the one being assembled last, enclosing ones before
+}
+
+impl Assembler {
+    fn error<T>(&self, message: &str) -> Result<T> {
+        bail!(ErrorKind::SyntaxError(format!("{}:{}: {}", self.chunkname, self.line, message)))
+    }
+
+    fn current(&mut self) -> &mut Proto {
+        self.functions.last_mut().expect("function being assembled")
+    }
+
+    // Words and quoted strings (with their quotes) of `line`
+    fn tokens<'t>(&self, line: &'t str) -> Result<Vec<&'t str>> {
+        let mut tokens = Vec::new();
+        let bytes = line.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                b';' => break,
+                c if c.is_ascii_whitespace() => i += 1,
+                b'"' => {
+                    let start = i;
+                    i += 1;
+                    while i < bytes.len() && bytes[i] != b'"' {
+                        i += if bytes[i] == b'\\' { 2 } else { 1 };
+                    }
+                    if i >= bytes.len() {
+                        return self.error("unfinished string");
+                    }
+                    i += 1;
+                    tokens.push(&line[start..i]);
+                }
+                _ => {
+                    let start = i;
+                    while i < bytes.len() && !bytes[i].is_ascii_whitespace() && bytes[i] != b';' {
+                        i += 1;
+                    }
+                    tokens.push(&line[start..i]);
+                }
+            }
+        }
+        Ok(tokens)
+    }
+
+    fn number(&self, token: Option<&&str>) -> Result<i64> {
+        match token.and_then(|token| token.parse().ok()) {
+            Some(n) => Ok(n),
+            None => self.error(&format!("number expected near '{}'",
+                                        token.cloned().unwrap_or("<eol>"))),
+        }
+    }
+
+    fn byte(&self, token: Option<&&str>) -> Result<u8> {
+        let n = self.number(token)?;
+        match u8::try_from(n) {
+            Ok(n) => Ok(n),
+            Err(_) => self.error(&format!("{} is out of range", n)),
+        }
+    }
+
+    fn name(&self, token: Option<&&str>) -> Result<SyxString> {
+        match token {
+            Some(&"-") => Ok(SyxString::from("")),
+            Some(token) if token.starts_with('"') => self.string(token),
+            Some(token) => Ok(SyxString::from(*token)),
+            None => self.error("name expected"),
+        }
+    }
+
+    fn string(&self, token: &str) -> Result<SyxString> {
+        let bytes = &token.as_bytes()[1..token.len() - 1];
+        let mut string = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] != b'\\' {
+                string.push(bytes[i]);
+                i += 1;
+                continue;
+            }
+            let escape = bytes.get(i + 1).cloned().unwrap_or(b'\\');
+            i += 2;
+            string.push(match escape {
+                b'a' => 0x07,
+                b'b' => 0x08,
+                b'f' => 0x0c,
+                b'n' => b'\n',
+                b'r' => b'\r',
+                b't' => b'\t',
+                b'v' => 0x0b,
+                b'"' | b'\\' => escape,
+                b'0'..=b'9' => {
+                    let digits = &bytes[i - 1..(i + 2).min(bytes.len())];
+                    let code = String::from_utf8_lossy(digits).parse::<u8>();
+                    match code {
+                        Ok(code) if digits.len() == 3 => {
+                            i += 2;
+                            code
+                        }
+                        _ => return self.error(&format!("invalid escape in {}", token)),
+                    }
+                }
+                _ => return self.error(&format!("invalid escape in {}", token)),
+            });
+        }
+        Ok(SyxString::from(string))
+    }
+
+    fn constant(&self, token: Option<&&str>) -> Result<SyxValue> {
+        let token = match token {
+            Some(token) => *token,
+            None => return self.error("constant expected"),
+        };
+        Ok(match token {
+            "nil" => SyxValue::Nil,
+            "true" => SyxValue::Bool(true),
+            "false" => SyxValue::Bool(false),
+            _ if token.starts_with('"') => SyxValue::String(self.string(token)?),
+            _ => if let Ok(n) = token.parse::<SyxInteger>() {
+                SyxValue::Integer(n)
+            } else if let Ok(n) = token.parse::<SyxNumber>() {
+                SyxValue::Number(n)
+            } else {
+                return self.error(&format!("constant expected near '{}'", token));
+            },
+        })
+    }
+
+    // RK operand: a register, or a constant counting down from -1
+    fn rk(&self, token: Option<&&str>) -> Result<u16> {
+        let n = self.number(token)?;
+        if n < 0 {
+            let index = -1 - n;
+            if index > i64::from(MAXINDEXRK) {
+                return self.error(&format!("constant {} can not be an RK operand", n));
+            }
+            Ok((BITRK | index) as u16)
+        } else {
+            self.operand(n)
+        }
+    }
+
+    // operand that is checked against its field by `encode`
+    fn operand<T: TryFrom<i64>>(&self, n: i64) -> Result<T> {
+        match T::try_from(n) {
+            Ok(n) => Ok(n),
+            Err(_) => self.error(&format!("{} is out of range", n)),
+        }
+    }
+
+    fn statement(&mut self, first: &str, rest: &[&str]) -> Result<()> {
+        let mut args = rest.iter();
+        match first {
+            ".source" => {
+                let source = self.name(args.next())?;
+                self.current().source = String::from_utf8_lossy(&source).into_owned();
+            }
+            ".lines" => {
+                let linedefined = self.number(args.next())?;
+                let lastlinedefined = self.number(args.next())?;
+                self.current().linedefined = self.operand(linedefined)?;
+                self.current().lastlinedefined = self.operand(lastlinedefined)?;
+            }
+            ".params" => self.current().numparams = self.byte(args.next())?,
+            ".vararg" => self.current().is_vararg = self.byte(args.next())?,
+            ".stack" => self.current().maxstacksize = self.byte(args.next())?,
+            ".upval" => {
+                let name = self.name(args.next())?;
+                let instack = self.byte(args.next())?;
+                let idx = self.byte(args.next())?;
+                self.current().upvalues.push(Upvalue { name, instack, idx });
+            }
+            ".const" => {
+                let constant = self.constant(args.next())?;
+                self.current().constants.push(constant);
+            }
+            ".local" => {
+                let varname = self.name(args.next())?;
+                let startpc = self.number(args.next())? - 1;
+                let endpc = self.number(args.next())? - 1;
+                let (startpc, endpc) = (self.operand(startpc)?, self.operand(endpc)?);
+                self.current().locvars.push(LocVar { varname, startpc, endpc });
+            }
+            ".function" => {
+                let source = self.current().source.clone();
+                self.functions.push(function(&source));
+            }
+            ".end" => {
+                if self.functions.len() == 1 {
+                    return self.error("no function to end");
+                }
+                let proto = self.functions.pop().expect("nested function");
+                let proto = self.finish(proto)?;
+                self.current().protos.push(Arc::new(proto));
+            }
+            _ if first.starts_with('[') && first.ends_with(']') => {
+                let mut position = first[1..first.len() - 1].splitn(2, ':');
+                let line = self.number(position.next().as_ref())?;
+                let line = self.operand(line)?;
+                let column = match position.next() {
+                    Some(column) => {
+                        let column = self.number(Some(&column))?;
+                        Some(self.operand::<i32>(column)?)
+                    }
+                    None => None,
+                };
+                let (op, rest) = match rest.split_first() {
+                    Some((op, rest)) => (op, rest),
+                    None => return self.error("instruction expected"),
+                };
+                self.instruction(op, rest)?;
+                self.current().lineinfo.push(line);
+                self.current().columninfo.extend(column);
+                return Ok(());
+            }
+            _ if first.starts_with('.') => {
+                return self.error(&format!("unknown directive '{}'", first));
+            }
+            _ => {
+                self.instruction(first, rest)?;
+                return Ok(());
+            }
+        }
+        match args.next() {
+            Some(extra) => self.error(&format!("unexpected '{}'", extra)),
+            None => Ok(()),
+        }
+    }
+
+    fn instruction(&mut self, name: &str, rest: &[&str]) -> Result<()> {
+        let op = match opcode(name) {
+            Some(op) => op,
+            None => return self.error(&format!("unknown opcode '{}'", name)),
+        };
+        let decoded = Decoded::try_from(op as Word)?;
+        let mut args = rest.iter();
+        let register = |this: &Self, args: &mut ::std::slice::Iter<&str>| {
+            let n = this.number(args.next())?;
+            this.operand::<u8>(n)
+        };
+        let instruction = match Instruction::from(&decoded) {
+            Instruction::ABC { .. } => {
+                let (has_b, has_c) = operands(op);
+                let a = register(self, &mut args)?;
+                let b = if has_b { self.rk(args.next())? } else { 0 };
+                let c = if has_c { self.rk(args.next())? } else { 0 };
+                Instruction::ABC { instruction: op, a, b, c }
+            }
+            Instruction::ABx { .. } => {
+                let a = register(self, &mut args)?;
+                let n = self.number(args.next())?;
+                // constants count down from -1, functions from 0
+                let bx = if op == OpCode::LoadK { -1 - n } else { n };
+                Instruction::ABx { instruction: op, a, bx: self.operand(bx)? }
+            }
+            Instruction::AsBx { .. } => {
+                let a = register(self, &mut args)?;
+                let sbx = self.number(args.next())?;
+                Instruction::AsBx { instruction: op, a, sbx: self.operand(sbx)? }
+            }
+            Instruction::Ax { .. } => {
+                let ax = -1 - self.number(args.next())?;
+                Instruction::Ax { instruction: op, ax: self.operand(ax)? }
+            }
+        };
+        if let Some(extra) = args.next() {
+            return self.error(&format!("unexpected '{}'", extra));
+        }
+        if let Err(error) = instruction.encode() {
+            return self.error(&error.to_string());
+        }
+        self.current().instructions.push(instruction);
+        Ok(())
+    }
+
+    fn finish(&self, proto: Proto) -> Result<Proto> {
+        if !proto.lineinfo.is_empty() && proto.lineinfo.len() != proto.instructions.len() {
+            return self.error("some instructions of a function have lines and some do not");
+        }
+        if !proto.columninfo.is_empty() && proto.columninfo.len() != proto.instructions.len() {
+            return self.error("some instructions of a function have columns and some do not");
+        }
+        Ok(proto)
+    }
+}
+
+impl Proto {
+    // This function and those defined in it as `assemble` reads them
+    pub fn assembly(&self) -> String {
+        let mut output = String::new();
+        write_function(&mut output, self, None, "");
+        output
+    }
+}
+
+// Names are written as they are unless that would not read back
+fn name_text(name: &SyxString) -> String {
+    let plain = !name.is_empty() && name.iter()
+        .all(|&c| c.is_ascii_graphic() && c != b'"' && c != b';' && c != b'[');
+    if plain && &name[..] != b"-" && name[0] != b'.' {
+        name.to_string()
+    } else if name.is_empty() {
+        "-".to_owned()
+    } else {
+        constant_text(&SyxValue::String(name.clone()))
+    }
+}
+
+fn write_function(output: &mut String, proto: &Proto, parent: Option<&str>, indent: &str) {
+    if parent != Some(proto.source.as_str()) {
+        let source = SyxValue::String(SyxString::from(proto.source.as_str()));
+        let _ = writeln!(output, "{}.source {}", indent, constant_text(&source));
+    }
+    if proto.linedefined != 0 || proto.lastlinedefined != 0 {
+        let _ = writeln!(output, "{}.lines {} {}",
+                         indent, proto.linedefined, proto.lastlinedefined);
+    }
+    if proto.numparams != 0 {
+        let _ = writeln!(output, "{}.params {}", indent, proto.numparams);
+    }
+    if proto.is_vararg != 0 {
+        let _ = writeln!(output, "{}.vararg {}", indent, proto.is_vararg);
+    }
+    let _ = writeln!(output, "{}.stack {}", indent, proto.maxstacksize);
+    for upvalue in &proto.upvalues {
+        let _ = writeln!(output, "{}.upval {} {} {}",
+                         indent, name_text(&upvalue.name), upvalue.instack, upvalue.idx);
+    }
+    for constant in &proto.constants {
+        // floats exactly, and never to be read as integers
+        let text = match *constant {
+            SyxValue::Number(n) => format!("{:?}", n),
+            ref constant => constant_text(constant),
+        };
+        let _ = writeln!(output, "{}.const {}", indent, text);
+    }
+    for local in &proto.locvars {
+        let _ = writeln!(output, "{}.local {} {} {}",
+                         indent, name_text(&local.varname), local.startpc + 1, local.endpc + 1);
+    }
+    for pc in 0..proto.instructions.len() {
+        let (op, args, comment) = decode(proto, pc, false);
+        output.push_str(indent);
+        match (proto.lineinfo.get(pc), proto.columninfo.get(pc)) {
+            (Some(line), Some(column)) => { let _ = write!(output, "[{}:{}] ", line, column); }
+            (Some(line), None) => { let _ = write!(output, "[{}] ", line); }
+            _ => {}
+        }
+        let _ = write!(output, "{:<9} {}", opname(op), args);
+        if !comment.is_empty() {
+            let _ = write!(output, "  ; {}", comment.replace('\n', " "));
+        }
+        output.push('\n');
+    }
+    let nested = format!("{}    ", indent);
+    for child in &proto.protos {
+        let _ = writeln!(output, "{}.function", indent);
+        write_function(output, child, Some(&proto.source), &nested);
+        let _ = writeln!(output, "{}.end", indent);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::compiler::parse_with_columns;
+    #[cfg(not(any(feature = "int32", feature = "float32")))]
+    use super::super::dump::DumpState;
+    use super::super::state::SyxState;
+    #[cfg(not(any(feature = "int32", feature = "float32")))]
+    use super::super::undump::LoadState;
+
+    #[test]
+    fn test_assemble() {
+        let text = r#"
+            ; returns 20 + 20 and a string with escapes
+            .upval _ENV 1 0
+            .const 20
+            .const "a\t\"b\"\092"
+            .stack 4
+            [1] CLOSURE 0 0
+            [1] LOADK 1 -1
+            [1] ADD 2 1 1        ; 20 + 20 in a register
+            [2] LOADK 3 -2
+            [2] RETURN 2 3
+            .function
+                .lines 1 1
+                .params 1
+                ADD 1 0 -1
+                RETURN 1 2
+                .const 2
+            .end
+        "#;
+        let proto = assemble(text, "=asm").unwrap();
+        proto.verify().unwrap();
+        assert_eq!(proto.source, "=asm");
+        assert_eq!(proto.lineinfo, vec![1, 1, 1, 2, 2]);
+        assert_eq!(proto.protos[0].source, "=asm");
+        assert_eq!(proto.protos[0].instructions[0],
+                   Instruction::ABC { instruction: OpCode::Add, a: 1, b: 0, c: 256 });
+
+        let mut state = SyxState::new();
+        let results = state.call(Arc::new(proto), Vec::new()).unwrap().into_vec();
+        assert!(results[0] == SyxValue::Integer(40));
+        assert!(results[1] == SyxValue::String(state.intern(b"a\t\"b\"\\")));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let source = "local t = {0.1, 1e100, 2^53, 'tab\\t\\0\\255', [\"a b\"] = true}\n\
+                      local function f(x, ...) return function() return x + #t end end\n\
+                      for i = 1, 3 do t[i] = f(i)() end return t";
+        let proto = parse_with_columns(source.as_bytes(), "@round.lua").unwrap();
+        let text = proto.assembly();
+        let reassembled = assemble(&text, "=other").unwrap();
+        assert!(proto.diff(&reassembled).is_empty(), "{}", proto.diff(&reassembled));
+        assert_eq!(reassembled.assembly(), text);
+        assert!(text.contains(".const 0.1\n"));
+        assert!(text.contains(".const \"tab\\t\\000\\255\"\n"));
+        assert!(text.contains("    .function\n        .lines 2 2\n"));
+    }
+
+    #[test]
+    #[cfg(not(any(feature = "int32", feature = "float32")))] // luac.out has 64-bit numbers
+    fn test_luac_round_trip() {
+        // a stripped chunk
+        let chunk = include_bytes!("../luac.out").to_vec();
+        let proto = LoadState::from_u8(chunk.clone(), "luac.out").unwrap();
+        let reassembled = assemble(&proto.assembly(), "=other").unwrap();
+        assert_eq!(DumpState::to_u8(&reassembled).unwrap(), chunk);
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        let error = |text| assemble(text, "=asm").unwrap_err().to_string();
+        assert_eq!(error("MOVE 0 1\nFOO 1"), "asm:2: unknown opcode 'FOO'");
+        assert_eq!(error("LOADK 0 -262145"), "asm:1: operand Bx of LoadK out of range: 262144");
+        assert_eq!(error("ADD 0 1 -257"), "asm:1: constant -257 can not be an RK operand");
+        assert_eq!(error("RETURN 0"), "asm:1: number expected near '<eol>'");
+        assert_eq!(error(".const \"x"), "asm:1: unfinished string");
+        assert_eq!(error(".function\nRETURN 0 1"), "asm:2: .end expected");
+        assert_eq!(error("[1] RETURN 0 1\nRETURN 0 1"),
+                   "asm:2: some instructions of a function have lines and some do not");
+    }
+}
@@ -0,0 +1,190 @@
+// syxc
+//
+// Compiles Lua source files into a binary chunk, like luac. Several files
+// end up in one chunk whose main function runs each of them in order. The
+// compiled functions can also be listed, once for their instructions and
+// twice for their constants, locals and upvalues as well.
+//
+// Consult the versioned luac.c for more information.
+
+extern crate syx;
+
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::process;
+use std::sync::Arc;
+
+use syx::compiler;
+use syx::dump::DumpState;
+use syx::errors::Result;
+use syx::object::Proto;
+
+const USAGE: &str = "usage: syxc [options] [filenames]
+Available options are:
+  -l       list (use -l -l for full listing)
+  -o name  output to file 'name' (default is \"syxc.out\")
+  -p       parse only
+  -s       strip debug information
+  -v       show version information
+  --       stop handling options
+  -        stop handling options and process stdin";
+
+const OUTPUT: &str = "syxc.out";
+
+struct Options {
+    listing: usize, // times -l was given
+    output: Option<String>, // None for stdout
+    parse_only: bool,
+    strip: bool,
+    version: bool,
+    files: Vec<String>,
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let options = match parse_options(&args) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("syxc: {}\n{}", message, USAGE);
+            process::exit(1);
+        }
+    };
+    if let Err(error) = run(&options) {
+        eprintln!("syxc: {}", error);
+        process::exit(1);
+    }
+}
+
+fn parse_options(args: &[String]) -> ::std::result::Result<Options, String> {
+    let mut options = Options {
+        listing: 0,
+        output: Some(OUTPUT.to_owned()),
+        parse_only: false,
+        strip: false,
+        version: false,
+        files: Vec::new(),
+    };
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--" => {
+                i += 1;
+                break;
+            }
+            "-" => break,
+            "-o" => {
+                i += 1;
+                match args.get(i).map(String::as_str) {
+                    Some("-") => options.output = None,
+                    Some(output) if !output.starts_with('-') => {
+                        options.output = Some(output.to_owned())
+                    }
+                    _ => return Err("'-o' needs argument".to_owned()),
+                }
+            }
+            "-l" => options.listing += 1,
+            "-p" => options.parse_only = true,
+            "-s" => options.strip = true,
+            "-v" => options.version = true,
+            option if option.starts_with('-') => {
+                return Err(format!("unrecognized option '{}'", option));
+            }
+            _ => break,
+        }
+        i += 1;
+    }
+    options.files = args[i..].to_vec();
+    if options.files.is_empty() && !options.version {
+        return Err("no input files given".to_owned());
+    }
+    Ok(options)
+}
+
+fn run(options: &Options) -> Result<()> {
+    if options.version {
+        println!("Syx {} (Lua 5.3)", env!("CARGO_PKG_VERSION"));
+        if options.files.is_empty() {
+            return Ok(());
+        }
+    }
+    let mut protos = Vec::new();
+    for file in &options.files {
+        protos.push(if file == "-" {
+            let mut source = Vec::new();
+            io::stdin().read_to_end(&mut source)
+                .map_err(|error| format!("cannot read stdin: {}", error))?;
+            compiler::load(source, "=stdin")?
+        } else {
+            compiler::load_file(file)?
+        });
+    }
+    let proto = combine(protos)?;
+    if options.listing > 0 {
+        print!("{}", proto.listing(options.listing > 1));
+    }
+    if options.parse_only {
+        return Ok(());
+    }
+    let chunk = DumpState::to_u8_strip(&proto, options.strip)?;
+    match options.output {
+        Some(ref output) => fs::write(output, chunk)
+            .map_err(|error| format!("cannot write {}: {}", output, error))?,
+        None => io::stdout().write_all(&chunk)
+            .map_err(|error| format!("cannot write stdout: {}", error))?,
+    }
+    Ok(())
+}
+
+// One function calling each of `protos` in turn, or the only one given
+fn combine(mut protos: Vec<Proto>) -> Result<Proto> {
+    if protos.len() == 1 {
+        return Ok(protos.remove(0));
+    }
+    let source = "(function()end)();\n".repeat(protos.len());
+    let mut main = compiler::parse(source.as_bytes(), "=(syxc)")?;
+    for (slot, mut proto) in main.protos.iter_mut().zip(protos) {
+        // _ENV is now an upvalue of the combining function, not a local
+        if let Some(env) = proto.upvalues.first_mut() {
+            env.instack = 0;
+        }
+        *slot = Arc::new(proto);
+    }
+    main.lineinfo.clear();
+    Ok(main)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syx::object::SyxValue;
+    use syx::state::SyxState;
+    use syx::undump::LoadState;
+
+    #[test]
+    fn test_combine_and_strip() {
+        let first = compiler::parse(b"x = 1", "@first.lua").unwrap();
+        let second = compiler::parse(b"local y = x + 1 return y", "@second.lua").unwrap();
+        let mut proto = combine(vec![first, second]).unwrap();
+        proto.strip_debug();
+        let chunk = DumpState::to_u8(&proto).unwrap();
+        let proto = LoadState::from_u8(chunk, "=test").unwrap();
+        assert!(proto.protos[1].lineinfo.is_empty());
+        assert!(proto.protos[1].locvars.is_empty());
+        let mut state = SyxState::new();
+        state.call(Arc::new(proto), Vec::new()).unwrap();
+        let key = SyxValue::String(state.intern(b"x"));
+        assert!(state.table(state.globals()).get(&key) == SyxValue::Integer(1));
+    }
+
+    #[test]
+    fn test_options() {
+        let args: Vec<String> = ["syxc", "-l", "-s", "-l", "-o", "-", "a.lua", "b.lua"]
+            .iter().map(|s| s.to_string()).collect();
+        let options = parse_options(&args).unwrap();
+        assert!(options.strip && options.output.is_none());
+        assert_eq!(options.listing, 2);
+        assert_eq!(options.files, vec!["a.lua", "b.lua"]);
+        assert!(parse_options(&["syxc".to_owned()]).is_err());
+    }
+}
@@ -0,0 +1,1205 @@
+// Code generator
+//
+// Emits instructions into the Proto of the function being parsed. An
+// expression is described by an ExpDesc until the parser knows where its
+// value has to go, so most expressions are only emitted once, straight into
+// their final register. Conditional jumps are chained into lists through
+// their own sBx fields and patched once the target is known.
+//
+// Consult the versioned lcode.c for more information.
+
+use std::collections::HashMap;
+
+use super::super::errors::*;
+use super::super::object::{
+    float_to_integer, integer_value, number_to_f64, Proto, SyxInteger, SyxNumber, SyxString,
+    SyxValue,
+};
+use super::super::opcodes::{Instruction, OpCode};
+use super::super::vm::arith;
+use super::parser::Parser;
+
+pub(super) const NO_JUMP: i32 = -1; // marks the end of a jump list
+pub(super) const MULTRET: usize = !0; // "as many results as there are"
+
+const MAXARG_A: usize = (1 << 8) - 1;
+const MAXARG_C: usize = (1 << 9) - 1;
+const MAXARG_BX: usize = (1 << 18) - 1;
+const MAXARG_SBX: i32 = (MAXARG_BX >> 1) as i32;
+const MAXARG_AX: usize = (1 << 26) - 1;
+const BITRK: usize = 1 << 8; // see vm.rs
+const MAXINDEXRK: usize = BITRK - 1;
+const NO_REG: usize = MAXARG_A; // no register to put a TESTSET result in
+const MAXREGS: usize = 255;
+pub(super) const LFIELDS_PER_FLUSH: usize = 50; // see vm.rs
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum ExpKind {
+    Void,                // empty expression list, or no expression at all
+    Nil,
+    True,
+    False,
+    K(usize),            // constant index
+    KFlt(SyxNumber),
+    KInt(SyxInteger),
+    NonReloc(usize),     // value in a fixed register
+    Local(usize),        // local variable register
+    Upval(usize),        // upvalue index
+    // t[idx]; t is a register or, for `upval`, an upvalue; idx is an RK
+    Indexed { t: usize, idx: usize, upval: bool },
+    Jmp(usize),          // pc of the jump of a comparison
+    Relocable(usize),    // pc of an instruction that can put its result anywhere
+    Call(usize),         // pc of a CALL
+    VarArg(usize),       // pc of a VARARG
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(super) struct ExpDesc {
+    pub k: ExpKind,
+    pub t: i32, // patch list of "exit when true"
+    pub f: i32, // patch list of "exit when false"
+}
+
+impl ExpDesc {
+    pub fn new(k: ExpKind) -> ExpDesc {
+        ExpDesc { k, t: NO_JUMP, f: NO_JUMP }
+    }
+
+    fn has_jumps(&self) -> bool {
+        self.t != self.f
+    }
+
+    pub fn has_multret(&self) -> bool {
+        matches!(self.k, ExpKind::Call(_) | ExpKind::VarArg(_))
+    }
+
+    pub fn is_var(&self) -> bool {
+        matches!(self.k, ExpKind::Local(_) | ExpKind::Upval(_) | ExpKind::Indexed { .. })
+    }
+
+    fn numeral(&self) -> Option<SyxValue> {
+        if self.has_jumps() {
+            return None;
+        }
+        match self.k {
+            ExpKind::KInt(i) => Some(SyxValue::Integer(i)),
+            ExpKind::KFlt(n) => Some(SyxValue::Number(n)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum UnOpr {
+    Minus,
+    BNot,
+    Not,
+    Len,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum BinOpr {
+    Add, Sub, Mul, Mod, Pow, Div, IDiv,
+    BAnd, BOr, BXOr, Shl, Shr,
+    Concat,
+    Eq, Lt, Le, Ne, Gt, Ge,
+    And, Or,
+}
+
+impl BinOpr {
+    // left and right priority, for precedence climbing
+    pub fn priority(self) -> (u8, u8) {
+        match self {
+            BinOpr::Add | BinOpr::Sub => (10, 10),
+            BinOpr::Mul | BinOpr::Mod | BinOpr::Div | BinOpr::IDiv => (11, 11),
+            BinOpr::Pow => (14, 13), // right associative
+            BinOpr::BAnd => (6, 6),
+            BinOpr::BOr => (4, 4),
+            BinOpr::BXOr => (5, 5),
+            BinOpr::Shl | BinOpr::Shr => (7, 7),
+            BinOpr::Concat => (9, 8), // right associative
+            BinOpr::Eq | BinOpr::Lt | BinOpr::Le |
+            BinOpr::Ne | BinOpr::Gt | BinOpr::Ge => (3, 3),
+            BinOpr::And => (2, 2),
+            BinOpr::Or => (1, 1),
+        }
+    }
+
+    fn arith_opcode(self) -> Option<OpCode> {
+        Some(match self {
+            BinOpr::Add => OpCode::Add,
+            BinOpr::Sub => OpCode::Sub,
+            BinOpr::Mul => OpCode::Mul,
+            BinOpr::Mod => OpCode::Mod,
+            BinOpr::Pow => OpCode::Pow,
+            BinOpr::Div => OpCode::Div,
+            BinOpr::IDiv => OpCode::IDiv,
+            BinOpr::BAnd => OpCode::BAnd,
+            BinOpr::BOr => OpCode::BOr,
+            BinOpr::BXOr => OpCode::BXOr,
+            BinOpr::Shl => OpCode::Shl,
+            BinOpr::Shr => OpCode::Shr,
+            _ => return None,
+        })
+    }
+}
+
+pub(super) const UNARY_PRIORITY: u8 = 12;
+
+// Constants are shared within a function; integers and floats that compare
+// equal are still different constants
+#[derive(PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Nil,
+    Bool(bool),
+    Integer(SyxInteger),
+    Float(u64),
+    String(SyxString),
+}
+
+#[derive(Clone, Copy)]
+pub(super) struct BlockCnt {
+    pub firstlabel: usize, // index of the first label of this block
+    pub firstgoto: usize,  // index of the first pending goto of this block
+    pub nactvar: usize,    // active locals outside the block
+    pub upval: bool,       // some variable of the block is an upvalue
+    pub isloop: bool,
+}
+
+// State of a function being compiled
+pub(super) struct FuncState {
+    pub f: Proto,
+    pub blocks: Vec<BlockCnt>,
+    pub lasttarget: usize, // pc of the last jump target
+    pub jpc: i32,          // jumps to the next instruction
+    pub nactvar: usize,    // number of active locals
+    pub firstlocal: usize, // index of the first local in Parser::actvar
+    pub freereg: usize,    // first free register
+    constants: HashMap<ConstantKey, usize>,
+}
+
+impl FuncState {
+    pub fn new(f: Proto, firstlocal: usize) -> FuncState {
+        FuncState {
+            f,
+            blocks: Vec::new(),
+            lasttarget: 0,
+            jpc: NO_JUMP,
+            nactvar: 0,
+            firstlocal,
+            freereg: 0,
+            constants: HashMap::new(),
+        }
+    }
+
+    pub fn pc(&self) -> usize {
+        self.f.instructions.len()
+    }
+}
+
+pub(super) fn is_k(x: usize) -> bool {
+    x & BITRK != 0
+}
+
+fn rk_as_k(x: usize) -> usize {
+    x | BITRK
+}
+
+fn opcode(i: &Instruction) -> OpCode {
+    match *i {
+        Instruction::ABC { instruction, .. } | Instruction::ABx { instruction, .. } |
+        Instruction::AsBx { instruction, .. } | Instruction::Ax { instruction, .. } => instruction,
+    }
+}
+
+fn set_opcode(i: &mut Instruction, op: OpCode) {
+    match *i {
+        Instruction::ABC { ref mut instruction, .. } | Instruction::ABx { ref mut instruction, .. } |
+        Instruction::AsBx { ref mut instruction, .. } |
+        Instruction::Ax { ref mut instruction, .. } => *instruction = op,
+    }
+}
+
+fn arg_a(i: &Instruction) -> usize {
+    match *i {
+        Instruction::ABC { a, .. } | Instruction::ABx { a, .. } | Instruction::AsBx { a, .. } =>
+            a as usize,
+        Instruction::Ax { .. } => unreachable!("no A argument"),
+    }
+}
+
+fn set_arg_a(i: &mut Instruction, value: usize) {
+    match *i {
+        Instruction::ABC { ref mut a, .. } | Instruction::ABx { ref mut a, .. } |
+        Instruction::AsBx { ref mut a, .. } => *a = value as u8,
+        Instruction::Ax { .. } => unreachable!("no A argument"),
+    }
+}
+
+fn arg_b(i: &Instruction) -> usize {
+    match *i {
+        Instruction::ABC { b, .. } => b as usize,
+        _ => unreachable!("no B argument"),
+    }
+}
+
+pub(super) fn set_arg_b(i: &mut Instruction, value: usize) {
+    match *i {
+        Instruction::ABC { ref mut b, .. } => *b = value as u16,
+        _ => unreachable!("no B argument"),
+    }
+}
+
+fn arg_c(i: &Instruction) -> usize {
+    match *i {
+        Instruction::ABC { c, .. } => c as usize,
+        _ => unreachable!("no C argument"),
+    }
+}
+
+pub(super) fn set_arg_c(i: &mut Instruction, value: usize) {
+    match *i {
+        Instruction::ABC { ref mut c, .. } => *c = value as u16,
+        _ => unreachable!("no C argument"),
+    }
+}
+
+fn arg_sbx(i: &Instruction) -> i32 {
+    match *i {
+        Instruction::AsBx { sbx, .. } => sbx,
+        _ => unreachable!("no sBx argument"),
+    }
+}
+
+fn set_arg_sbx(i: &mut Instruction, value: i32) {
+    match *i {
+        Instruction::AsBx { ref mut sbx, .. } => *sbx = value,
+        _ => unreachable!("no sBx argument"),
+    }
+}
+
+// instructions whose next instruction is a jump they may skip
+fn is_test(op: OpCode) -> bool {
+    matches!(op, OpCode::Eq | OpCode::Lt | OpCode::Le | OpCode::Test | OpCode::TestSet)
+}
+
+// converts an integer to a "floating point byte", eeeeexxx, rounding up
+pub(super) fn int2fb(mut x: usize) -> usize {
+    let mut e = 0;
+    if x < 8 {
+        return x;
+    }
+    while x >= (8 << 4) {
+        x = (x + 0xf) >> 4;
+        e += 4;
+    }
+    while x >= (8 << 1) {
+        x = (x + 1) >> 1;
+        e += 1;
+    }
+    ((e + 1) << 3) | (x - 8)
+}
+
+// whether folding `op` at compile time can not raise an error
+fn valid_fold(op: OpCode, lhs: &SyxValue, rhs: &SyxValue) -> bool {
+    let integral = |v: &SyxValue| match *v {
+        SyxValue::Integer(_) => true,
+        SyxValue::Number(n) => float_to_integer(n).is_some(),
+        _ => false,
+    };
+    match op {
+        OpCode::BAnd | OpCode::BOr | OpCode::BXOr | OpCode::Shl | OpCode::Shr | OpCode::BNot =>
+            integral(lhs) && integral(rhs),
+        OpCode::Div | OpCode::IDiv | OpCode::Mod => match *rhs {
+            SyxValue::Integer(i) => i != 0,
+            SyxValue::Number(n) => n != 0.0,
+            _ => false,
+        },
+        _ => true,
+    }
+}
+
+// `lhs op rhs` (just `lhs` for UNM and BNOT) worked out ahead of time, if it
+// can be without raising an error and the result can be a constant
+pub(crate) fn fold(op: OpCode, lhs: &SyxValue, rhs: &SyxValue) -> Option<SyxValue> {
+    if !valid_fold(op, lhs, rhs) {
+        return None;
+    }
+    let result = match op {
+        OpCode::Unm => match *lhs {
+            SyxValue::Integer(i) => integer_value(i.wrapping_neg()),
+            SyxValue::Number(n) => SyxValue::Number(-n),
+            _ => return None,
+        },
+        OpCode::BNot => match *lhs {
+            SyxValue::Integer(i) => integer_value(!i),
+            SyxValue::Number(n) => integer_value(!float_to_integer(n)?),
+            _ => return None,
+        },
+        _ => arith(op, lhs, rhs).ok()?,
+    };
+    match result {
+        SyxValue::Integer(_) => Some(result),
+        // NaN and -0.0 can not be constants, they would be merged with other
+        // ones
+        SyxValue::Number(n) if !n.is_nan() && n != 0.0 => Some(result),
+        _ => None,
+    }
+}
+
+impl<'a> Parser<'a> {
+    fn instruction(&mut self, pc: usize) -> &mut Instruction {
+        &mut self.fs.f.instructions[pc]
+    }
+
+    pub(super) fn code(&mut self, i: Instruction) -> usize {
+        self.discharge_jpc();
+        self.fs.f.instructions.push(i);
+        self.fs.f.lineinfo.push(self.lastline as i32);
+        if self.columns {
+            self.fs.f.columninfo.push(self.lastcolumn as i32);
+        }
+        self.fs.pc() - 1
+    }
+
+    pub(super) fn code_abc(&mut self, op: OpCode, a: usize, b: usize, c: usize) -> usize {
+        self.code(Instruction::ABC { instruction: op, a: a as u8, b: b as u16, c: c as u16 })
+    }
+
+    pub(super) fn code_abx(&mut self, op: OpCode, a: usize, bx: usize) -> usize {
+        self.code(Instruction::ABx { instruction: op, a: a as u8, bx: bx as u32 })
+    }
+
+    pub(super) fn code_asbx(&mut self, op: OpCode, a: usize, sbx: i32) -> usize {
+        self.code(Instruction::AsBx { instruction: op, a: a as u8, sbx })
+    }
+
+    fn code_extra_arg(&mut self, ax: usize) -> usize {
+        self.code(Instruction::Ax { instruction: OpCode::ExtraArg, ax: ax as u32 })
+    }
+
+    pub(super) fn code_k(&mut self, reg: usize, k: usize) -> usize {
+        if k <= MAXARG_BX {
+            self.code_abx(OpCode::LoadK, reg, k)
+        } else {
+            let pc = self.code_abx(OpCode::LoadKX, reg, 0);
+            self.code_extra_arg(k);
+            pc
+        }
+    }
+
+    // give the last instruction emitted the line of the construct it is for
+    pub(super) fn fix_line(&mut self, line: usize) {
+        if let Some(last) = self.fs.f.lineinfo.last_mut() {
+            *last = line as i32;
+        }
+    }
+
+    pub(super) fn code_nil(&mut self, mut from: usize, n: usize) {
+        let mut last = from + n - 1;
+        let pc = self.fs.pc();
+        if pc > self.fs.lasttarget {
+            // no jumps lead here, so a LOADNIL just before can be extended
+            let previous = self.instruction(pc - 1);
+            if opcode(previous) == OpCode::LoadNil {
+                let pfrom = arg_a(previous);
+                let plast = pfrom + arg_b(previous);
+                if (pfrom <= from && from <= plast + 1) || (from <= pfrom && pfrom <= last + 1) {
+                    from = from.min(pfrom);
+                    last = last.max(plast);
+                    set_arg_a(previous, from);
+                    set_arg_b(previous, last - from);
+                    return;
+                }
+            }
+        }
+        self.code_abc(OpCode::LoadNil, from, n - 1, 0);
+    }
+
+    pub(super) fn ret(&mut self, first: usize, nret: usize) {
+        self.code_abc(OpCode::Return, first, nret.wrapping_add(1), 0);
+    }
+
+    // registers
+
+    pub(super) fn check_stack(&mut self, n: usize) -> Result<()> {
+        let newstack = self.fs.freereg + n;
+        if newstack > self.fs.f.maxstacksize as usize {
+            if newstack >= MAXREGS {
+                return self.error("function or expression needs too many registers");
+            }
+            self.fs.f.maxstacksize = newstack as u8;
+        }
+        Ok(())
+    }
+
+    pub(super) fn reserve_regs(&mut self, n: usize) -> Result<()> {
+        self.check_stack(n)?;
+        self.fs.freereg += n;
+        Ok(())
+    }
+
+    fn free_reg(&mut self, reg: usize) {
+        if !is_k(reg) && reg >= self.fs.nactvar {
+            self.fs.freereg -= 1;
+            debug_assert_eq!(reg, self.fs.freereg);
+        }
+    }
+
+    fn free_exp(&mut self, e: &ExpDesc) {
+        if let ExpKind::NonReloc(reg) = e.k {
+            self.free_reg(reg);
+        }
+    }
+
+    // free the registers of two expressions, the higher one first
+    fn free_exps(&mut self, e1: &ExpDesc, e2: &ExpDesc) {
+        let reg = |e: &ExpDesc| match e.k {
+            ExpKind::NonReloc(reg) => Some(reg),
+            _ => None,
+        };
+        let (r1, r2) = (reg(e1), reg(e2));
+        let (first, second) = if r1 > r2 { (r1, r2) } else { (r2, r1) };
+        for reg in first.into_iter().chain(second) {
+            self.free_reg(reg);
+        }
+    }
+
+    // constants
+
+    fn add_k(&mut self, key: ConstantKey, value: SyxValue) -> usize {
+        if let Some(&index) = self.fs.constants.get(&key) {
+            return index;
+        }
+        let index = self.fs.f.constants.len();
+        self.fs.f.constants.push(value);
+        self.fs.constants.insert(key, index);
+        index
+    }
+
+    pub(super) fn string_k(&mut self, s: SyxString) -> usize {
+        self.add_k(ConstantKey::String(s.clone()), SyxValue::String(s))
+    }
+
+    pub(super) fn int_k(&mut self, i: SyxInteger) -> usize {
+        match integer_value(i) {
+            SyxValue::Number(n) => self.number_k(n),
+            _ => self.add_k(ConstantKey::Integer(i), SyxValue::Integer(i)),
+        }
+    }
+
+    fn number_k(&mut self, n: SyxNumber) -> usize {
+        self.add_k(ConstantKey::Float(number_to_f64(n).to_bits()), SyxValue::Number(n))
+    }
+
+    fn bool_k(&mut self, b: bool) -> usize {
+        self.add_k(ConstantKey::Bool(b), SyxValue::Bool(b))
+    }
+
+    fn nil_k(&mut self) -> usize {
+        self.add_k(ConstantKey::Nil, SyxValue::Nil)
+    }
+
+    // jumps
+
+    fn get_jump(&self, pc: usize) -> i32 {
+        let offset = arg_sbx(&self.fs.f.instructions[pc]);
+        if offset == NO_JUMP {
+            NO_JUMP // end of the list
+        } else {
+            pc as i32 + 1 + offset
+        }
+    }
+
+    fn fix_jump(&mut self, pc: usize, dest: usize) -> Result<()> {
+        let offset = dest as i32 - (pc as i32 + 1);
+        if offset.abs() > MAXARG_SBX {
+            return self.error("control structure too long");
+        }
+        set_arg_sbx(self.instruction(pc), offset);
+        Ok(())
+    }
+
+    // append the jump list `l2` to `l1`
+    pub(super) fn concat(&mut self, l1: &mut i32, l2: i32) -> Result<()> {
+        if l2 == NO_JUMP {
+            return Ok(());
+        }
+        if *l1 == NO_JUMP {
+            *l1 = l2;
+            return Ok(());
+        }
+        let mut list = *l1 as usize;
+        loop {
+            let next = self.get_jump(list);
+            if next == NO_JUMP {
+                break;
+            }
+            list = next as usize;
+        }
+        self.fix_jump(list, l2 as usize)
+    }
+
+    pub(super) fn jump(&mut self) -> Result<i32> {
+        let jpc = self.fs.jpc;
+        self.fs.jpc = NO_JUMP;
+        let mut j = self.code_asbx(OpCode::Jmp, 0, NO_JUMP) as i32;
+        self.concat(&mut j, jpc)?;
+        Ok(j)
+    }
+
+    fn cond_jump(&mut self, op: OpCode, a: usize, b: usize, c: usize) -> Result<i32> {
+        self.code_abc(op, a, b, c);
+        self.jump()
+    }
+
+    // mark the current pc as a jump target, so nothing is merged across it
+    pub(super) fn get_label(&mut self) -> usize {
+        self.fs.lasttarget = self.fs.pc();
+        self.fs.lasttarget
+    }
+
+    // the instruction controlling the jump at `pc`: its test, if any
+    fn jump_control(&mut self, pc: usize) -> &mut Instruction {
+        if pc >= 1 && is_test(opcode(&self.fs.f.instructions[pc - 1])) {
+            self.instruction(pc - 1)
+        } else {
+            self.instruction(pc)
+        }
+    }
+
+    // make the TESTSET before the jump at `node` put its value in `reg`, or
+    // turn it into a TEST if there is no register; false if it is no TESTSET
+    fn patch_test_reg(&mut self, node: usize, reg: usize) -> bool {
+        let i = self.jump_control(node);
+        if opcode(i) != OpCode::TestSet {
+            return false;
+        }
+        if reg != NO_REG && reg != arg_b(i) {
+            set_arg_a(i, reg);
+        } else {
+            let (b, c) = (arg_b(i), arg_c(i));
+            *i = Instruction::ABC { instruction: OpCode::Test, a: b as u8, b: 0, c: c as u16 };
+        }
+        true
+    }
+
+    fn remove_values(&mut self, mut list: i32) {
+        while list != NO_JUMP {
+            self.patch_test_reg(list as usize, NO_REG);
+            list = self.get_jump(list as usize);
+        }
+    }
+
+    // jumps whose test produces a value go to `vtarget` with the value in
+    // `reg`, the others to `dtarget`
+    fn patch_list_aux(&mut self, mut list: i32, vtarget: usize, reg: usize, dtarget: usize)
+        -> Result<()>
+    {
+        while list != NO_JUMP {
+            let next = self.get_jump(list as usize);
+            if self.patch_test_reg(list as usize, reg) {
+                self.fix_jump(list as usize, vtarget)?;
+            } else {
+                self.fix_jump(list as usize, dtarget)?;
+            }
+            list = next;
+        }
+        Ok(())
+    }
+
+    fn discharge_jpc(&mut self) {
+        let (jpc, pc) = (self.fs.jpc, self.fs.pc());
+        self.fs.jpc = NO_JUMP;
+        // every jump in the list is backed by an instruction already emitted,
+        // and the targets are never too far, so this can not fail
+        self.patch_list_aux(jpc, pc, NO_REG, pc).expect("jump to the next instruction");
+    }
+
+    pub(super) fn patch_to_here(&mut self, list: i32) -> Result<()> {
+        self.get_label();
+        let mut jpc = self.fs.jpc;
+        self.concat(&mut jpc, list)?;
+        self.fs.jpc = jpc;
+        Ok(())
+    }
+
+    pub(super) fn patch_list(&mut self, list: i32, target: usize) -> Result<()> {
+        if target == self.fs.pc() {
+            self.patch_to_here(list)
+        } else {
+            debug_assert!(target < self.fs.pc());
+            self.patch_list_aux(list, target, NO_REG, target)
+        }
+    }
+
+    // make every jump in `list` close upvalues from `level` up
+    pub(super) fn patch_close(&mut self, mut list: i32, level: usize) {
+        while list != NO_JUMP {
+            // A is the level plus one, so 0 can mean "close nothing"
+            set_arg_a(self.instruction(list as usize), level + 1);
+            list = self.get_jump(list as usize);
+        }
+    }
+
+    // expressions
+
+    pub(super) fn set_returns(&mut self, e: &mut ExpDesc, nresults: usize) -> Result<()> {
+        match e.k {
+            ExpKind::Call(pc) => set_arg_c(self.instruction(pc), nresults.wrapping_add(1)),
+            ExpKind::VarArg(pc) => {
+                let freereg = self.fs.freereg;
+                let i = self.instruction(pc);
+                set_arg_b(i, nresults.wrapping_add(1));
+                set_arg_a(i, freereg);
+                self.reserve_regs(1)?;
+            }
+            _ => debug_assert_eq!(nresults, MULTRET),
+        }
+        Ok(())
+    }
+
+    pub(super) fn set_multret(&mut self, e: &mut ExpDesc) -> Result<()> {
+        self.set_returns(e, MULTRET)
+    }
+
+    pub(super) fn set_one_ret(&mut self, e: &mut ExpDesc) {
+        match e.k {
+            ExpKind::Call(pc) => e.k = ExpKind::NonReloc(arg_a(&self.fs.f.instructions[pc])),
+            ExpKind::VarArg(pc) => {
+                set_arg_b(self.instruction(pc), 2);
+                e.k = ExpKind::Relocable(pc);
+            }
+            _ => {}
+        }
+    }
+
+    // turn a variable into a value
+    pub(super) fn discharge_vars(&mut self, e: &mut ExpDesc) {
+        match e.k {
+            ExpKind::Local(reg) => e.k = ExpKind::NonReloc(reg),
+            ExpKind::Upval(idx) => {
+                e.k = ExpKind::Relocable(self.code_abc(OpCode::GetUpval, 0, idx, 0));
+            }
+            ExpKind::Indexed { t, idx, upval } => {
+                self.free_reg(idx);
+                let op = if upval {
+                    OpCode::GetTabUp
+                } else {
+                    self.free_reg(t);
+                    OpCode::GetTable
+                };
+                e.k = ExpKind::Relocable(self.code_abc(op, 0, t, idx));
+            }
+            ExpKind::VarArg(_) | ExpKind::Call(_) => self.set_one_ret(e),
+            _ => {}
+        }
+    }
+
+    fn discharge_to_reg(&mut self, e: &mut ExpDesc, reg: usize) {
+        self.discharge_vars(e);
+        match e.k {
+            ExpKind::Nil => self.code_nil(reg, 1),
+            ExpKind::False => {
+                self.code_abc(OpCode::LoadBool, reg, 0, 0);
+            }
+            ExpKind::True => {
+                self.code_abc(OpCode::LoadBool, reg, 1, 0);
+            }
+            ExpKind::K(k) => {
+                self.code_k(reg, k);
+            }
+            ExpKind::KFlt(n) => {
+                let k = self.number_k(n);
+                self.code_k(reg, k);
+            }
+            ExpKind::KInt(i) => {
+                let k = self.int_k(i);
+                self.code_k(reg, k);
+            }
+            ExpKind::Relocable(pc) => set_arg_a(self.instruction(pc), reg),
+            ExpKind::NonReloc(src) => {
+                if src != reg {
+                    self.code_abc(OpCode::Move, reg, src, 0);
+                }
+            }
+            _ => {
+                debug_assert!(matches!(e.k, ExpKind::Jmp(_)));
+                return; // nothing to do yet
+            }
+        }
+        e.k = ExpKind::NonReloc(reg);
+    }
+
+    fn discharge_to_any_reg(&mut self, e: &mut ExpDesc) -> Result<()> {
+        if let ExpKind::NonReloc(_) = e.k {
+            return Ok(());
+        }
+        self.reserve_regs(1)?;
+        let reg = self.fs.freereg - 1;
+        self.discharge_to_reg(e, reg);
+        Ok(())
+    }
+
+    fn code_load_bool(&mut self, a: usize, b: usize, jump: usize) -> usize {
+        self.get_label();
+        self.code_abc(OpCode::LoadBool, a, b, jump)
+    }
+
+    // whether some jump in `list` does not produce a value
+    fn need_value(&mut self, mut list: i32) -> bool {
+        while list != NO_JUMP {
+            if opcode(self.jump_control(list as usize)) != OpCode::TestSet {
+                return true;
+            }
+            list = self.get_jump(list as usize);
+        }
+        false
+    }
+
+    fn exp_to_reg(&mut self, e: &mut ExpDesc, reg: usize) -> Result<()> {
+        self.discharge_to_reg(e, reg);
+        if let ExpKind::Jmp(pc) = e.k {
+            let mut t = e.t;
+            self.concat(&mut t, pc as i32)?;
+            e.t = t;
+        }
+        if e.has_jumps() {
+            let mut p_f = NO_JUMP as usize; // position of a LOADBOOL false
+            let mut p_t = NO_JUMP as usize; // position of a LOADBOOL true
+            if self.need_value(e.t) || self.need_value(e.f) {
+                let fj = match e.k {
+                    ExpKind::Jmp(_) => NO_JUMP,
+                    _ => self.jump()?,
+                };
+                p_f = self.code_load_bool(reg, 0, 1);
+                p_t = self.code_load_bool(reg, 1, 0);
+                self.patch_to_here(fj)?;
+            }
+            let end = self.get_label();
+            self.patch_list_aux(e.f, end, reg, p_f)?;
+            self.patch_list_aux(e.t, end, reg, p_t)?;
+        }
+        e.t = NO_JUMP;
+        e.f = NO_JUMP;
+        e.k = ExpKind::NonReloc(reg);
+        Ok(())
+    }
+
+    pub(super) fn exp_to_next_reg(&mut self, e: &mut ExpDesc) -> Result<()> {
+        self.discharge_vars(e);
+        self.free_exp(e);
+        self.reserve_regs(1)?;
+        let reg = self.fs.freereg - 1;
+        self.exp_to_reg(e, reg)
+    }
+
+    pub(super) fn exp_to_any_reg(&mut self, e: &mut ExpDesc) -> Result<usize> {
+        self.discharge_vars(e);
+        if let ExpKind::NonReloc(reg) = e.k {
+            if !e.has_jumps() {
+                return Ok(reg);
+            }
+            if reg >= self.fs.nactvar {
+                // not a local, so the jumps can put their value there too
+                self.exp_to_reg(e, reg)?;
+                return Ok(reg);
+            }
+        }
+        self.exp_to_next_reg(e)?;
+        match e.k {
+            ExpKind::NonReloc(reg) => Ok(reg),
+            _ => unreachable!("expression is in a register"),
+        }
+    }
+
+    // an upvalue can be indexed in place
+    pub(super) fn exp_to_any_reg_up(&mut self, e: &mut ExpDesc) -> Result<()> {
+        match e.k {
+            ExpKind::Upval(_) if !e.has_jumps() => Ok(()),
+            _ => self.exp_to_any_reg(e).map(|_| ()),
+        }
+    }
+
+    pub(super) fn exp_to_val(&mut self, e: &mut ExpDesc) -> Result<()> {
+        if e.has_jumps() {
+            self.exp_to_any_reg(e)?;
+        } else {
+            self.discharge_vars(e);
+        }
+        Ok(())
+    }
+
+    // an RK argument: a constant index with BITRK set if it fits, else a register
+    pub(super) fn exp_to_rk(&mut self, e: &mut ExpDesc) -> Result<usize> {
+        self.exp_to_val(e)?;
+        let k = match e.k {
+            ExpKind::True => Some(self.bool_k(true)),
+            ExpKind::False => Some(self.bool_k(false)),
+            ExpKind::Nil => Some(self.nil_k()),
+            ExpKind::KInt(i) => Some(self.int_k(i)),
+            ExpKind::KFlt(n) => Some(self.number_k(n)),
+            ExpKind::K(k) => Some(k),
+            _ => None,
+        };
+        if let Some(k) = k {
+            e.k = ExpKind::K(k);
+            if k <= MAXINDEXRK {
+                return Ok(rk_as_k(k));
+            }
+        }
+        self.exp_to_any_reg(e)
+    }
+
+    pub(super) fn store_var(&mut self, var: &ExpDesc, ex: &mut ExpDesc) -> Result<()> {
+        match var.k {
+            ExpKind::Local(reg) => {
+                self.free_exp(ex);
+                return self.exp_to_reg(ex, reg);
+            }
+            ExpKind::Upval(idx) => {
+                let e = self.exp_to_any_reg(ex)?;
+                self.code_abc(OpCode::SetUpval, e, idx, 0);
+            }
+            ExpKind::Indexed { t, idx, upval } => {
+                let op = if upval { OpCode::SetTabUp } else { OpCode::SetTable };
+                let e = self.exp_to_rk(ex)?;
+                self.code_abc(op, t, idx, e);
+            }
+            _ => unreachable!("invalid variable kind to store"),
+        }
+        self.free_exp(ex);
+        Ok(())
+    }
+
+    // e:key, leaving the method and then `e` in consecutive registers
+    pub(super) fn code_self(&mut self, e: &mut ExpDesc, key: &mut ExpDesc) -> Result<()> {
+        let ereg = self.exp_to_any_reg(e)?;
+        self.free_exp(e);
+        let base = self.fs.freereg;
+        e.k = ExpKind::NonReloc(base);
+        self.reserve_regs(2)?;
+        let rk = self.exp_to_rk(key)?;
+        self.code_abc(OpCode::SelfLoad, base, ereg, rk);
+        self.free_exp(key);
+        Ok(())
+    }
+
+    fn negate_condition(&mut self, e: &ExpDesc) {
+        if let ExpKind::Jmp(pc) = e.k {
+            let i = self.jump_control(pc);
+            let a = arg_a(i);
+            set_arg_a(i, (a == 0) as usize);
+        }
+    }
+
+    // jump if `e` is `cond`
+    fn jump_on_cond(&mut self, e: &mut ExpDesc, cond: bool) -> Result<i32> {
+        if let ExpKind::Relocable(pc) = e.k {
+            let ie = &self.fs.f.instructions[pc];
+            if opcode(ie) == OpCode::Not {
+                // test the operand of the NOT instead, the other way round
+                let b = arg_b(ie);
+                self.fs.f.instructions.pop();
+                self.fs.f.lineinfo.pop();
+                self.fs.f.columninfo.pop();
+                return self.cond_jump(OpCode::Test, b, 0, (!cond) as usize);
+            }
+        }
+        self.discharge_to_any_reg(e)?;
+        self.free_exp(e);
+        let reg = match e.k {
+            ExpKind::NonReloc(reg) => reg,
+            _ => unreachable!("expression is in a register"),
+        };
+        self.cond_jump(OpCode::TestSet, NO_REG, reg, cond as usize)
+    }
+
+    // fall through when `e` is true, jump out when it is false
+    pub(super) fn go_if_true(&mut self, e: &mut ExpDesc) -> Result<()> {
+        self.discharge_vars(e);
+        let pc = match e.k {
+            ExpKind::Jmp(pc) => {
+                self.negate_condition(e);
+                pc as i32
+            }
+            ExpKind::K(_) | ExpKind::KFlt(_) | ExpKind::KInt(_) | ExpKind::True => NO_JUMP,
+            _ => self.jump_on_cond(e, false)?,
+        };
+        let mut f = e.f;
+        self.concat(&mut f, pc)?;
+        e.f = f;
+        self.patch_to_here(e.t)?;
+        e.t = NO_JUMP;
+        Ok(())
+    }
+
+    // fall through when `e` is false, jump out when it is true
+    pub(super) fn go_if_false(&mut self, e: &mut ExpDesc) -> Result<()> {
+        self.discharge_vars(e);
+        let pc = match e.k {
+            ExpKind::Jmp(pc) => pc as i32,
+            ExpKind::Nil | ExpKind::False => NO_JUMP,
+            _ => self.jump_on_cond(e, true)?,
+        };
+        let mut t = e.t;
+        self.concat(&mut t, pc)?;
+        e.t = t;
+        self.patch_to_here(e.f)?;
+        e.f = NO_JUMP;
+        Ok(())
+    }
+
+    fn code_not(&mut self, e: &mut ExpDesc) -> Result<()> {
+        self.discharge_vars(e);
+        match e.k {
+            ExpKind::Nil | ExpKind::False => e.k = ExpKind::True,
+            ExpKind::K(_) | ExpKind::KFlt(_) | ExpKind::KInt(_) | ExpKind::True => {
+                e.k = ExpKind::False;
+            }
+            ExpKind::Jmp(_) => self.negate_condition(e),
+            ExpKind::Relocable(_) | ExpKind::NonReloc(_) => {
+                self.discharge_to_any_reg(e)?;
+                self.free_exp(e);
+                let reg = match e.k {
+                    ExpKind::NonReloc(reg) => reg,
+                    _ => unreachable!("expression is in a register"),
+                };
+                e.k = ExpKind::Relocable(self.code_abc(OpCode::Not, 0, reg, 0));
+            }
+            _ => unreachable!("cannot negate {:?}", e.k),
+        }
+        ::std::mem::swap(&mut e.t, &mut e.f);
+        self.remove_values(e.f);
+        self.remove_values(e.t);
+        Ok(())
+    }
+
+    // t[k], where `t` is already in a register or an upvalue
+    pub(super) fn indexed(&mut self, t: &mut ExpDesc, k: &mut ExpDesc) -> Result<()> {
+        let idx = self.exp_to_rk(k)?;
+        t.k = match t.k {
+            ExpKind::Upval(t) => ExpKind::Indexed { t, idx, upval: true },
+            ExpKind::NonReloc(t) | ExpKind::Local(t) => ExpKind::Indexed { t, idx, upval: false },
+            _ => unreachable!("indexed expression is not in a register"),
+        };
+        Ok(())
+    }
+
+    // try to work out `e1 op e2` now, leaving the result in `e1`
+    fn constant_folding(&self, op: OpCode, e1: &mut ExpDesc, e2: &ExpDesc) -> bool {
+        let (v1, v2) = match (e1.numeral(), e2.numeral()) {
+            (Some(v1), Some(v2)) => (v1, v2),
+            _ => return false,
+        };
+        match fold(op, &v1, &v2) {
+            Some(SyxValue::Integer(i)) => e1.k = ExpKind::KInt(i),
+            Some(SyxValue::Number(n)) => e1.k = ExpKind::KFlt(n),
+            _ => return false,
+        }
+        true
+    }
+
+    fn code_un_exp_val(&mut self, op: OpCode, e: &mut ExpDesc, line: usize) -> Result<()> {
+        let r = self.exp_to_any_reg(e)?;
+        self.free_exp(e);
+        e.k = ExpKind::Relocable(self.code_abc(op, 0, r, 0));
+        self.fix_line(line);
+        Ok(())
+    }
+
+    fn code_bin_exp_val(&mut self, op: OpCode, e1: &mut ExpDesc, e2: &mut ExpDesc, line: usize)
+        -> Result<()>
+    {
+        let rk2 = self.exp_to_rk(e2)?;
+        let rk1 = self.exp_to_rk(e1)?;
+        self.free_exps(e1, e2);
+        e1.k = ExpKind::Relocable(self.code_abc(op, 0, rk1, rk2));
+        self.fix_line(line);
+        Ok(())
+    }
+
+    fn code_comp(&mut self, opr: BinOpr, e1: &mut ExpDesc, e2: &mut ExpDesc) -> Result<()> {
+        let rk1 = match e1.k {
+            ExpKind::K(k) => rk_as_k(k),
+            ExpKind::NonReloc(reg) => reg,
+            _ => unreachable!("left operand is not in a register"),
+        };
+        let rk2 = self.exp_to_rk(e2)?;
+        self.free_exps(e1, e2);
+        let pc = match opr {
+            BinOpr::Ne => self.cond_jump(OpCode::Eq, 0, rk1, rk2)?,
+            // a > b is b < a, a >= b is b <= a
+            BinOpr::Gt => self.cond_jump(OpCode::Lt, 1, rk2, rk1)?,
+            BinOpr::Ge => self.cond_jump(OpCode::Le, 1, rk2, rk1)?,
+            BinOpr::Eq => self.cond_jump(OpCode::Eq, 1, rk1, rk2)?,
+            BinOpr::Lt => self.cond_jump(OpCode::Lt, 1, rk1, rk2)?,
+            _ => self.cond_jump(OpCode::Le, 1, rk1, rk2)?,
+        };
+        e1.k = ExpKind::Jmp(pc as usize);
+        Ok(())
+    }
+
+    pub(super) fn prefix(&mut self, op: UnOpr, e: &mut ExpDesc, line: usize) -> Result<()> {
+        let zero = ExpDesc::new(ExpKind::KInt(0));
+        match op {
+            UnOpr::Minus => {
+                if !self.constant_folding(OpCode::Unm, e, &zero) {
+                    self.code_un_exp_val(OpCode::Unm, e, line)?;
+                }
+            }
+            UnOpr::BNot => {
+                if !self.constant_folding(OpCode::BNot, e, &zero) {
+                    self.code_un_exp_val(OpCode::BNot, e, line)?;
+                }
+            }
+            UnOpr::Len => self.code_un_exp_val(OpCode::Len, e, line)?,
+            UnOpr::Not => self.code_not(e)?,
+        }
+        Ok(())
+    }
+
+    // deal with the first operand of `op` before the second is read
+    pub(super) fn infix(&mut self, op: BinOpr, v: &mut ExpDesc) -> Result<()> {
+        match op {
+            BinOpr::And => self.go_if_true(v),
+            BinOpr::Or => self.go_if_false(v),
+            // operands of a concatenation must be in consecutive registers
+            BinOpr::Concat => self.exp_to_next_reg(v),
+            _ if op.arith_opcode().is_some() => {
+                // numerals are kept as they are, to be folded
+                if v.numeral().is_none() {
+                    self.exp_to_rk(v)?;
+                }
+                Ok(())
+            }
+            _ => self.exp_to_rk(v).map(|_| ()),
+        }
+    }
+
+    pub(super) fn posfix(&mut self, op: BinOpr, e1: &mut ExpDesc, e2: &mut ExpDesc, line: usize)
+        -> Result<()>
+    {
+        match op {
+            BinOpr::And => {
+                debug_assert_eq!(e1.t, NO_JUMP);
+                self.discharge_vars(e2);
+                let mut f = e2.f;
+                self.concat(&mut f, e1.f)?;
+                e2.f = f;
+                *e1 = *e2;
+            }
+            BinOpr::Or => {
+                debug_assert_eq!(e1.f, NO_JUMP);
+                self.discharge_vars(e2);
+                let mut t = e2.t;
+                self.concat(&mut t, e1.t)?;
+                e2.t = t;
+                *e1 = *e2;
+            }
+            BinOpr::Concat => {
+                self.exp_to_val(e2)?;
+                match e2.k {
+                    ExpKind::Relocable(pc)
+                        if opcode(&self.fs.f.instructions[pc]) == OpCode::Concat => {
+                        // a .. (b .. c) is a single CONCAT of all three
+                        self.free_exp(e1);
+                        let reg = match e1.k {
+                            ExpKind::NonReloc(reg) => reg,
+                            _ => unreachable!("concatenation operand is not in a register"),
+                        };
+                        set_arg_b(self.instruction(pc), reg);
+                        e1.k = ExpKind::Relocable(pc);
+                    }
+                    _ => {
+                        self.exp_to_next_reg(e2)?;
+                        self.code_bin_exp_val(OpCode::Concat, e1, e2, line)?;
+                    }
+                }
+            }
+            BinOpr::Eq | BinOpr::Lt | BinOpr::Le | BinOpr::Ne | BinOpr::Gt | BinOpr::Ge => {
+                self.code_comp(op, e1, e2)?;
+            }
+            _ => {
+                let opcode = op.arith_opcode().expect("arithmetic operator");
+                if !self.constant_folding(opcode, e1, e2) {
+                    self.code_bin_exp_val(opcode, e1, e2, line)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // store the `tostore` values above `base` into the table at `base`
+    pub(super) fn set_list(&mut self, base: usize, nelems: usize, tostore: usize) -> Result<()> {
+        let c = (nelems - 1) / LFIELDS_PER_FLUSH + 1;
+        let b = if tostore == MULTRET { 0 } else { tostore };
+        if c <= MAXARG_C {
+            self.code_abc(OpCode::SetList, base, b, c);
+        } else if c <= MAXARG_AX {
+            self.code_abc(OpCode::SetList, base, b, 0);
+            self.code_extra_arg(c);
+        } else {
+            return self.error("constructor too long");
+        }
+        self.fs.freereg = base + 1;
+        Ok(())
+    }
+
+    pub(super) fn set_opcode(&mut self, pc: usize, op: OpCode) {
+        set_opcode(self.instruction(pc), op);
+    }
+
+    pub(super) fn instruction_a(&self, pc: usize) -> usize {
+        arg_a(&self.fs.f.instructions[pc])
+    }
+
+    pub(super) fn set_instruction_b(&mut self, pc: usize, b: usize) {
+        set_arg_b(self.instruction(pc), b);
+    }
+
+    pub(super) fn set_instruction_c(&mut self, pc: usize, c: usize) {
+        set_arg_c(self.instruction(pc), c);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::opcodes::{Instruction, OpCode};
+    use super::super::parser::parse;
+    use super::int2fb;
+
+    fn opcodes(source: &str) -> Vec<OpCode> {
+        let proto = parse(source.as_bytes(), "=test").expect("compiles");
+        proto.instructions.iter().map(super::opcode).collect()
+    }
+
+    #[test]
+    fn test_constant_folding() {
+        let proto = parse(b"local a = 2 * 3 + 1", "=test").unwrap();
+        assert_eq!(proto.instructions[0],
+                   Instruction::ABx { instruction: OpCode::LoadK, a: 0, bx: 0 });
+        assert_eq!(proto.constants[0], super::SyxValue::Integer(7));
+        // division by zero is left for run time
+        assert_eq!(opcodes("local b = 1 // 0"), vec![OpCode::IDiv, OpCode::Return]);
+    }
+
+    #[test]
+    fn test_jumps() {
+        assert_eq!(opcodes("local a, b if a < b then a = b end"),
+                   vec![OpCode::LoadNil, OpCode::Lt, OpCode::Jmp, OpCode::Move,
+                        OpCode::Return]);
+        assert_eq!(opcodes("local a, b local c = a and b"),
+                   vec![OpCode::LoadNil, OpCode::TestSet, OpCode::Jmp, OpCode::Move,
+                        OpCode::Return]);
+        assert_eq!(opcodes("local a local b = not a"),
+                   vec![OpCode::LoadNil, OpCode::Not, OpCode::Return]);
+    }
+
+    #[test]
+    fn test_int2fb() {
+        assert_eq!(int2fb(7), 7);
+        assert_eq!(int2fb(8), 8);
+        assert_eq!(int2fb(100), 0x25); // rounds up to 104
+    }
+}
@@ -0,0 +1,763 @@
+// Lexer
+//
+// Splits source text into tokens, each with the line and column (both from
+// 1, the column in bytes) where it starts. Newlines are "\n", "\r", "\r\n" or
+// "\n\r", and in long strings each of them becomes a single "\n".
+//
+// Errors read "chunkname:line: message near 'token'", as in Lua.
+//
+// Consult the versioned llex.c for more information.
+
+use std::fmt;
+
+use super::super::debug::short_source;
+use super::super::errors::*;
+use super::super::object::{string_to_number, SyxInteger, SyxNumber, SyxString, SyxValue};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Token {
+    // reserved words
+    And,
+    Break,
+    Do,
+    Else,
+    Elseif,
+    End,
+    False,
+    For,
+    Function,
+    Goto,
+    If,
+    In,
+    Local,
+    Nil,
+    Not,
+    Or,
+    Repeat,
+    Return,
+    Then,
+    True,
+    Until,
+    While,
+    // symbols
+    Plus,      // +
+    Minus,     // -
+    Star,      // *
+    Slash,     // /
+    DoubleSlash, // //
+    Percent,   // %
+    Caret,     // ^
+    Hash,      // #
+    Ampersand, // &
+    Tilde,     // ~
+    Pipe,      // |
+    Shl,       // <<
+    Shr,       // >>
+    Eq,        // ==
+    Ne,        // ~=
+    Le,        // <=
+    Ge,        // >=
+    Lt,        // <
+    Gt,        // >
+    Assign,    // =
+    LParen,    // (
+    RParen,    // )
+    LBrace,    // {
+    RBrace,    // }
+    LBracket,  // [
+    RBracket,  // ]
+    DbColon,   // ::
+    Semicolon, // ;
+    Colon,     // :
+    Comma,     // ,
+    Dot,       // .
+    Concat,    // ..
+    Dots,      // ...
+    // values
+    Float(SyxNumber),
+    Int(SyxInteger),
+    Name(SyxString),
+    String(SyxString),
+    Eos,
+}
+
+const RESERVED: [(&str, Token); 22] = [
+    ("and", Token::And),
+    ("break", Token::Break),
+    ("do", Token::Do),
+    ("else", Token::Else),
+    ("elseif", Token::Elseif),
+    ("end", Token::End),
+    ("false", Token::False),
+    ("for", Token::For),
+    ("function", Token::Function),
+    ("goto", Token::Goto),
+    ("if", Token::If),
+    ("in", Token::In),
+    ("local", Token::Local),
+    ("nil", Token::Nil),
+    ("not", Token::Not),
+    ("or", Token::Or),
+    ("repeat", Token::Repeat),
+    ("return", Token::Return),
+    ("then", Token::Then),
+    ("true", Token::True),
+    ("until", Token::Until),
+    ("while", Token::While),
+];
+
+// Tokens as luaX_token2str shows them: fixed ones quoted, the others by
+// kind. Errors about a particular token show its text instead.
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let symbol = match *self {
+            Token::Plus => "+",
+            Token::Minus => "-",
+            Token::Star => "*",
+            Token::Slash => "/",
+            Token::DoubleSlash => "//",
+            Token::Percent => "%",
+            Token::Caret => "^",
+            Token::Hash => "#",
+            Token::Ampersand => "&",
+            Token::Tilde => "~",
+            Token::Pipe => "|",
+            Token::Shl => "<<",
+            Token::Shr => ">>",
+            Token::Eq => "==",
+            Token::Ne => "~=",
+            Token::Le => "<=",
+            Token::Ge => ">=",
+            Token::Lt => "<",
+            Token::Gt => ">",
+            Token::Assign => "=",
+            Token::LParen => "(",
+            Token::RParen => ")",
+            Token::LBrace => "{",
+            Token::RBrace => "}",
+            Token::LBracket => "[",
+            Token::RBracket => "]",
+            Token::DbColon => "::",
+            Token::Semicolon => ";",
+            Token::Colon => ":",
+            Token::Comma => ",",
+            Token::Dot => ".",
+            Token::Concat => "..",
+            Token::Dots => "...",
+            Token::Float(_) => return write!(f, "<number>"),
+            Token::Int(_) => return write!(f, "<integer>"),
+            Token::Name(_) => return write!(f, "<name>"),
+            Token::String(_) => return write!(f, "<string>"),
+            Token::Eos => return write!(f, "<eof>"),
+            ref word => RESERVED.iter().find(|(_, t)| t == word)
+                .map(|&(name, _)| name).expect("every other token is a reserved word"),
+        };
+        write!(f, "'{}'", symbol)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+// A token and where it is in the source
+#[derive(Clone, Debug, PartialEq)]
+pub struct Spanned {
+    pub token: Token,
+    pub position: Position,
+    start: usize, // byte offsets of its text
+    end: usize,
+}
+
+pub struct Lexer<'a> {
+    source: &'a [u8],
+    chunkname: String,
+    pos: usize,
+    line: usize,
+    line_start: usize, // offset of the first byte of `line`
+    ahead: Option<Spanned>,
+}
+
+fn is_newline(c: Option<u8>) -> bool {
+    c == Some(b'\n') || c == Some(b'\r')
+}
+
+fn is_name_start(c: u8) -> bool {
+    c.is_ascii_alphabetic() || c == b'_'
+}
+
+fn is_name_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_'
+}
+
+impl<'a> Lexer<'a> {
+    // `chunkname` as for a Proto's source: "@file", "=name", or the text
+    pub fn new(source: &'a [u8], chunkname: &str) -> Lexer<'a> {
+        Lexer {
+            source,
+            chunkname: short_source(chunkname).into_owned(),
+            pos: 0,
+            line: 1,
+            line_start: 0,
+            ahead: None,
+        }
+    }
+
+    // line of the last byte read
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn next_token(&mut self) -> Result<Spanned> {
+        match self.ahead.take() {
+            Some(token) => Ok(token),
+            None => self.scan(),
+        }
+    }
+
+    // the token `next_token` will return, without consuming it
+    pub fn peek_token(&mut self) -> Result<&Spanned> {
+        if self.ahead.is_none() {
+            self.ahead = Some(self.scan()?);
+        }
+        Ok(self.ahead.as_ref().expect("just scanned"))
+    }
+
+    // how errors show `token`: its text for names, strings and numbers
+    pub fn token_text(&self, token: &Spanned) -> String {
+        match token.token {
+            Token::Name(_) | Token::String(_) | Token::Int(_) | Token::Float(_) => {
+                let text = &self.source[token.start..token.end];
+                format!("'{}'", String::from_utf8_lossy(text))
+            }
+            ref other => other.to_string(),
+        }
+    }
+
+    // "chunkname:line: message near token"
+    pub fn syntax_error<T>(&self, message: &str, token: Option<&Spanned>) -> Result<T> {
+        let mut message = format!("{}:{}: {}", self.chunkname, self.line, message);
+        if let Some(token) = token {
+            message = format!("{} near {}", message, self.token_text(token));
+        }
+        bail!(ErrorKind::SyntaxError(message))
+    }
+
+    // an error about the text read since `start`
+    fn error_at<T>(&self, message: &str, start: usize) -> Result<T> {
+        let text = String::from_utf8_lossy(&self.source[start..self.pos]);
+        let message = format!("{}:{}: {} near '{}'", self.chunkname, self.line, message, text);
+        bail!(ErrorKind::SyntaxError(message))
+    }
+
+    fn current(&self) -> Option<u8> {
+        self.source.get(self.pos).cloned()
+    }
+
+    fn peek(&self, offset: usize) -> Option<u8> {
+        self.source.get(self.pos + offset).cloned()
+    }
+
+    // skip a newline sequence, counting the line
+    fn newline(&mut self) -> Result<()> {
+        let old = self.current();
+        self.pos += 1;
+        if is_newline(self.current()) && self.current() != old {
+            self.pos += 1; // "\n\r" or "\r\n"
+        }
+        self.line += 1;
+        self.line_start = self.pos;
+        if self.line >= i32::MAX as usize {
+            return self.syntax_error("chunk has too many lines", None);
+        }
+        Ok(())
+    }
+
+    fn scan(&mut self) -> Result<Spanned> {
+        loop {
+            let start = self.pos;
+            let position = Position { line: self.line, column: start - self.line_start + 1 };
+            let spanned = |lexer: &Lexer, token| {
+                Ok(Spanned { token, position, start, end: lexer.pos })
+            };
+            let c = match self.current() {
+                None => return spanned(self, Token::Eos),
+                Some(c) => c,
+            };
+            let single = |lexer: &mut Lexer, token| {
+                lexer.pos += 1;
+                spanned(lexer, token)
+            };
+            // a symbol of two bytes when the next one is `second`
+            let double = |lexer: &mut Lexer, second, long, short| {
+                if lexer.peek(1) == Some(second) {
+                    lexer.pos += 2;
+                    spanned(lexer, long)
+                } else {
+                    lexer.pos += 1;
+                    spanned(lexer, short)
+                }
+            };
+            match c {
+                b'\n' | b'\r' => self.newline()?,
+                b' ' | b'\t' | b'\x0b' | b'\x0c' => self.pos += 1,
+                b'-' => {
+                    if self.peek(1) != Some(b'-') {
+                        return single(self, Token::Minus);
+                    }
+                    self.pos += 2;
+                    if self.current() == Some(b'[') {
+                        if let Some(level) = self.long_bracket()? {
+                            self.read_long(level, false)?;
+                            continue;
+                        }
+                    }
+                    while self.current().is_some() && !is_newline(self.current()) {
+                        self.pos += 1;
+                    }
+                }
+                b'[' => match self.long_bracket()? {
+                    Some(level) => {
+                        let contents = self.read_long(level, true)?;
+                        return spanned(self, Token::String(contents.into()));
+                    }
+                    None => return single(self, Token::LBracket),
+                },
+                b'=' => return double(self, b'=', Token::Eq, Token::Assign),
+                b'<' => match self.peek(1) {
+                    Some(b'=') => return double(self, b'=', Token::Le, Token::Lt),
+                    _ => return double(self, b'<', Token::Shl, Token::Lt),
+                },
+                b'>' => match self.peek(1) {
+                    Some(b'=') => return double(self, b'=', Token::Ge, Token::Gt),
+                    _ => return double(self, b'>', Token::Shr, Token::Gt),
+                },
+                b'/' => return double(self, b'/', Token::DoubleSlash, Token::Slash),
+                b'~' => return double(self, b'=', Token::Ne, Token::Tilde),
+                b':' => return double(self, b':', Token::DbColon, Token::Colon),
+                b'"' | b'\'' => {
+                    let contents = self.read_string(c, start)?;
+                    return spanned(self, Token::String(contents.into()));
+                }
+                b'.' => {
+                    if self.peek(1) == Some(b'.') {
+                        if self.peek(2) == Some(b'.') {
+                            self.pos += 3;
+                            return spanned(self, Token::Dots);
+                        }
+                        self.pos += 2;
+                        return spanned(self, Token::Concat);
+                    }
+                    if !self.peek(1).is_some_and(|c| c.is_ascii_digit()) {
+                        return single(self, Token::Dot);
+                    }
+                    let token = self.read_numeral(start)?;
+                    return spanned(self, token);
+                }
+                b'0'..=b'9' => {
+                    let token = self.read_numeral(start)?;
+                    return spanned(self, token);
+                }
+                b'+' => return single(self, Token::Plus),
+                b'*' => return single(self, Token::Star),
+                b'%' => return single(self, Token::Percent),
+                b'^' => return single(self, Token::Caret),
+                b'#' => return single(self, Token::Hash),
+                b'&' => return single(self, Token::Ampersand),
+                b'|' => return single(self, Token::Pipe),
+                b'(' => return single(self, Token::LParen),
+                b')' => return single(self, Token::RParen),
+                b'{' => return single(self, Token::LBrace),
+                b'}' => return single(self, Token::RBrace),
+                b']' => return single(self, Token::RBracket),
+                b';' => return single(self, Token::Semicolon),
+                b',' => return single(self, Token::Comma),
+                c if is_name_start(c) => {
+                    while self.current().is_some_and(is_name_char) {
+                        self.pos += 1;
+                    }
+                    let name = &self.source[start..self.pos];
+                    let token = RESERVED.iter()
+                        .find(|&&(word, _)| word.as_bytes() == name)
+                        .map_or_else(|| Token::Name(name.into()), |(_, token)| token.clone());
+                    return spanned(self, token);
+                }
+                c => {
+                    self.pos += 1;
+                    let text = if c.is_ascii_graphic() {
+                        format!("'{}'", c as char)
+                    } else {
+                        format!("'<\\{}>'", c)
+                    };
+                    let message = format!("{}:{}: unexpected symbol near {}",
+                                          self.chunkname, self.line, text);
+                    bail!(ErrorKind::SyntaxError(message))
+                }
+            }
+        }
+    }
+
+    // At a "[", the level of the long bracket starting there ("[[" is 0,
+    // "[=[" is 1, ...), consuming it, or None for a lone "[". A "[=" not
+    // followed by another "[" is an error.
+    fn long_bracket(&mut self) -> Result<Option<usize>> {
+        let start = self.pos;
+        let mut level = 0;
+        while self.peek(level + 1) == Some(b'=') {
+            level += 1;
+        }
+        match self.peek(level + 1) {
+            Some(b'[') => {
+                self.pos += level + 2;
+                Ok(Some(level))
+            }
+            _ if level == 0 => Ok(None),
+            _ => {
+                self.pos += level + 1;
+                self.error_at("invalid long string delimiter", start)
+            }
+        }
+    }
+
+    // The contents of a long string or comment whose opening bracket has
+    // been read, up to the matching closing one. A first newline is skipped.
+    fn read_long(&mut self, level: usize, string: bool) -> Result<Vec<u8>> {
+        let mut contents = Vec::new();
+        if is_newline(self.current()) {
+            self.newline()?;
+        }
+        loop {
+            match self.current() {
+                None => {
+                    let what = if string { "string" } else { "comment" };
+                    let message = format!("{}:{}: unfinished long {} near <eof>",
+                                          self.chunkname, self.line, what);
+                    bail!(ErrorKind::SyntaxError(message))
+                }
+                Some(b']') => {
+                    let closing = (1..=level).all(|i| self.peek(i) == Some(b'='))
+                        && self.peek(level + 1) == Some(b']');
+                    if closing {
+                        self.pos += level + 2;
+                        return Ok(contents);
+                    }
+                    contents.push(b']');
+                    self.pos += 1;
+                }
+                Some(b'\n') | Some(b'\r') => {
+                    contents.push(b'\n');
+                    self.newline()?;
+                }
+                Some(c) => {
+                    contents.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    // the contents of a short string, from its opening `delimiter`
+    fn read_string(&mut self, delimiter: u8, start: usize) -> Result<Vec<u8>> {
+        let mut contents = Vec::new();
+        self.pos += 1;
+        loop {
+            match self.current() {
+                None => {
+                    let message = format!("{}:{}: unfinished string near <eof>",
+                                          self.chunkname, self.line);
+                    bail!(ErrorKind::SyntaxError(message))
+                }
+                Some(b'\n') | Some(b'\r') => return self.error_at("unfinished string", start),
+                Some(b'\\') => self.read_escape(&mut contents, start)?,
+                Some(c) if c == delimiter => {
+                    self.pos += 1;
+                    return Ok(contents);
+                }
+                Some(c) => {
+                    contents.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    fn escape_error<T>(&mut self, message: &str, start: usize) -> Result<T> {
+        // the text shown ends just after the offending escape
+        if self.current().is_some() {
+            self.pos += 1;
+        }
+        self.error_at(message, start)
+    }
+
+    fn read_escape(&mut self, contents: &mut Vec<u8>, start: usize) -> Result<()> {
+        self.pos += 1; // the backslash
+        let c = match self.current() {
+            None => return Ok(()), // reported as an unfinished string
+            Some(c) => c,
+        };
+        let simple = match c {
+            b'a' => Some(b'\x07'),
+            b'b' => Some(b'\x08'),
+            b'f' => Some(b'\x0c'),
+            b'n' => Some(b'\n'),
+            b'r' => Some(b'\r'),
+            b't' => Some(b'\t'),
+            b'v' => Some(b'\x0b'),
+            b'\\' | b'"' | b'\'' => Some(c),
+            _ => None,
+        };
+        if let Some(byte) = simple {
+            contents.push(byte);
+            self.pos += 1;
+            return Ok(());
+        }
+        match c {
+            b'\n' | b'\r' => {
+                contents.push(b'\n');
+                self.newline()
+            }
+            b'x' => {
+                let mut value = 0;
+                for _ in 0..2 {
+                    self.pos += 1;
+                    match self.current().and_then(|c| (c as char).to_digit(16)) {
+                        Some(digit) => value = value * 16 + digit,
+                        None => return self.escape_error("hexadecimal digit expected", start),
+                    }
+                }
+                contents.push(value as u8);
+                self.pos += 1;
+                Ok(())
+            }
+            b'z' => {
+                self.pos += 1;
+                while let Some(c) = self.current() {
+                    if is_newline(Some(c)) {
+                        self.newline()?;
+                    } else if c.is_ascii_whitespace() || c == b'\x0b' {
+                        self.pos += 1;
+                    } else {
+                        break;
+                    }
+                }
+                Ok(())
+            }
+            b'u' => self.read_utf8_escape(contents, start),
+            b'0'..=b'9' => {
+                let mut value: u32 = 0;
+                for _ in 0..3 {
+                    match self.current() {
+                        Some(c) if c.is_ascii_digit() => {
+                            value = value * 10 + (c - b'0') as u32;
+                            self.pos += 1;
+                        }
+                        _ => break,
+                    }
+                }
+                if value > 255 {
+                    // the text shown ends at the last digit
+                    return self.error_at("decimal escape too large", start);
+                }
+                contents.push(value as u8);
+                Ok(())
+            }
+            _ => self.escape_error("invalid escape sequence", start),
+        }
+    }
+
+    // "\u{XXX}", as the UTF-8 encoding of up to 2^31 - 1
+    fn read_utf8_escape(&mut self, contents: &mut Vec<u8>, start: usize) -> Result<()> {
+        self.pos += 1;
+        if self.current() != Some(b'{') {
+            return self.escape_error("missing '{'", start);
+        }
+        self.pos += 1;
+        let mut value: u32 = match self.current().and_then(|c| (c as char).to_digit(16)) {
+            Some(digit) => digit,
+            None => return self.escape_error("hexadecimal digit expected", start),
+        };
+        self.pos += 1;
+        while let Some(digit) = self.current().and_then(|c| (c as char).to_digit(16)) {
+            value = match value.checked_mul(16).map(|v| v + digit) {
+                Some(v) if v <= 0x7fff_ffff => v,
+                _ => return self.escape_error("UTF-8 value too large", start),
+            };
+            self.pos += 1;
+        }
+        if self.current() != Some(b'}') {
+            return self.escape_error("missing '}'", start);
+        }
+        self.pos += 1;
+        utf8_encode(value, contents);
+        Ok(())
+    }
+
+    // A numeral as llex reads it: anything that looks like one, converted
+    // afterwards, so "3..2" or "0xep" are malformed rather than split up
+    fn read_numeral(&mut self, start: usize) -> Result<Token> {
+        let mut exponent = [b'E', b'e'];
+        if self.current() == Some(b'0') && matches!(self.peek(1), Some(b'x') | Some(b'X')) {
+            self.pos += 2;
+            exponent = [b'P', b'p'];
+        }
+        loop {
+            match self.current() {
+                Some(c) if exponent.contains(&c) => {
+                    self.pos += 1;
+                    if matches!(self.current(), Some(b'+') | Some(b'-')) {
+                        self.pos += 1;
+                    }
+                }
+                Some(c) if c.is_ascii_hexdigit() || c == b'.' => self.pos += 1,
+                _ => break,
+            }
+        }
+        match string_to_number(&self.source[start..self.pos]) {
+            Some(SyxValue::Integer(i)) => Ok(Token::Int(i)),
+            Some(SyxValue::Number(n)) => Ok(Token::Float(n)),
+            _ => {
+                // llex reads on through the rest of the name
+                while self.current().is_some_and(is_name_char) {
+                    self.pos += 1;
+                }
+                self.error_at("malformed number", start)
+            }
+        }
+    }
+}
+
+// The extended UTF-8 luaO_utf8esc writes, with sequences of up to 6 bytes
+pub(crate) fn utf8_encode(value: u32, out: &mut Vec<u8>) {
+    if value < 0x80 {
+        out.push(value as u8);
+        return;
+    }
+    let mut continuation = Vec::new();
+    let mut value = value;
+    let mut first_max = 0x3f; // largest value that fits in the first byte
+    while value > first_max {
+        continuation.push(0x80 | (value & 0x3f) as u8);
+        value >>= 6;
+        first_max >>= 1;
+    }
+    let lead = (!first_max << 1) as u8 | value as u8;
+    out.push(lead);
+    out.extend(continuation.iter().rev());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(source: &str) -> Result<Vec<Token>> {
+        let mut lexer = Lexer::new(source.as_bytes(), "=test");
+        let mut tokens = Vec::new();
+        loop {
+            match lexer.next_token()?.token {
+                Token::Eos => return Ok(tokens),
+                token => tokens.push(token),
+            }
+        }
+    }
+
+    fn string(s: &[u8]) -> Token {
+        Token::String(s.into())
+    }
+
+    fn error(source: &str) -> String {
+        tokens(source).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_symbols_and_names() {
+        let found = tokens("local x_1 = a.b..c ... // ~= ~ :: <= << >= >> == [ ]").unwrap();
+        assert_eq!(found, vec![
+            Token::Local, Token::Name("x_1".into()), Token::Assign, Token::Name("a".into()),
+            Token::Dot, Token::Name("b".into()), Token::Concat, Token::Name("c".into()),
+            Token::Dots, Token::DoubleSlash, Token::Ne, Token::Tilde, Token::DbColon, Token::Le,
+            Token::Shl, Token::Ge, Token::Shr, Token::Eq, Token::LBracket, Token::RBracket,
+        ]);
+        assert_eq!(Token::Function.to_string(), "'function'");
+        assert_eq!(Token::Concat.to_string(), "'..'");
+        assert_eq!(Token::Eos.to_string(), "<eof>");
+    }
+
+    #[test]
+    // 64-bit integer numerals
+    #[cfg(not(any(feature = "int32", feature = "float32", feature = "float_only")))]
+    fn test_numbers() {
+        let found = tokens("3 345 0xff 0xBEBADA 3.0 2.5 250.0e-2 0.25E1 34e1 \
+                            0x0.1E 0xA23p-4 0X1.921FB54442D18P+1 .5 \
+                            9223372036854775807 9223372036854775808 0xffffffffffffffff")
+            .unwrap();
+        assert_eq!(found, vec![
+            Token::Int(3), Token::Int(345), Token::Int(255), Token::Int(0xBEBADA),
+            Token::Float(3.0), Token::Float(2.5), Token::Float(2.5),
+            Token::Float(2.5), Token::Float(340.0), Token::Float(0.1171875),
+            Token::Float(162.1875), Token::Float(std::f64::consts::PI), Token::Float(0.5),
+            Token::Int(SyxInteger::MAX), Token::Float(9_223_372_036_854_775_808.0),
+            Token::Int(-1),
+        ]);
+        assert_eq!(error("x = 3..2"), "test:1: malformed number near '3..2'");
+        assert_eq!(error("0xep"), "test:1: malformed number near '0xep'");
+        assert_eq!(error("12abc"), "test:1: malformed number near '12abc'");
+    }
+
+    #[test]
+    fn test_strings() {
+        let found = tokens(r#"'a\tb' "it's" '\65\066\x43\u{44}\u{20AC}' 'a\z
+                              b' "\\\"" 'line\
+two'"#).unwrap();
+        assert_eq!(found, vec![
+            string(b"a\tb"), string(b"it's"), string(b"ABCD\xe2\x82\xac"), string(b"ab"),
+            string(b"\\\""), string(b"line\ntwo"),
+        ]);
+        assert_eq!(tokens("'\\u{7FFFFFFF}'").unwrap(),
+                   vec![string(b"\xfd\xbf\xbf\xbf\xbf\xbf")]);
+
+        assert_eq!(error("'abc"), "test:1: unfinished string near <eof>");
+        assert_eq!(error("'abc\n'"), "test:1: unfinished string near ''abc'");
+        assert_eq!(error("'\\q'"), "test:1: invalid escape sequence near ''\\q'");
+        assert_eq!(error("'\\300'"), "test:1: decimal escape too large near ''\\300'");
+        assert_eq!(error("'\\xg'"), "test:1: hexadecimal digit expected near ''\\xg'");
+        assert_eq!(error("'\\u{80000000}'"),
+                   "test:1: UTF-8 value too large near ''\\u{80000000'");
+    }
+
+    #[test]
+    fn test_long_brackets_and_comments() {
+        let source = "[[\nfirst\r\nsecond]] [==[a]]b]=]c]==] -- comment\n\
+                      --[[ long\ncomment ]] x --[=[ another ]=] y";
+        assert_eq!(tokens(source).unwrap(), vec![
+            string(b"first\nsecond"), string(b"a]]b]=]c"),
+            Token::Name("x".into()), Token::Name("y".into()),
+        ]);
+        assert_eq!(error("[==[ abc ]=]"), "test:1: unfinished long string near <eof>");
+        assert_eq!(error("--[[ abc"), "test:1: unfinished long comment near <eof>");
+        assert_eq!(error("[=x"), "test:1: invalid long string delimiter near '[='");
+        assert_eq!(error("a @"), "test:1: unexpected symbol near '@'");
+    }
+
+    #[test]
+    fn test_positions() {
+        let mut lexer = Lexer::new(b"local a\n  = [[x\ny]]\n\r\tb", "@file.lua");
+        let mut positions = Vec::new();
+        loop {
+            let token = lexer.next_token().unwrap();
+            if token.token == Token::Eos {
+                break;
+            }
+            positions.push((token.position.line, token.position.column));
+        }
+        assert_eq!(positions, vec![(1, 1), (1, 7), (2, 3), (2, 5), (4, 2)]);
+
+        let mut lexer = Lexer::new(b"a b", "@file.lua");
+        assert_eq!(lexer.peek_token().unwrap().token, Token::Name("a".into()));
+        let a = lexer.next_token().unwrap();
+        assert_eq!(lexer.next_token().unwrap().token, Token::Name("b".into()));
+        let error = lexer.syntax_error::<()>("unexpected symbol", Some(&a)).unwrap_err();
+        assert_eq!(error.to_string(), "file.lua:1: unexpected symbol near 'a'");
+    }
+}
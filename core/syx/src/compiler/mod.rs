@@ -0,0 +1,67 @@
+// Compiler
+//
+// Turns Lua 5.3 source text into the same Proto trees undump produces from
+// binary chunks. The lexer splits the text into tokens, which the parser reads
+// while the code generator emits instructions for them in a single pass.
+//
+// Consult the versioned llex.c, lparser.c and lcode.c for more information.
+
+use std::fs;
+
+use super::conf::SYX_HEADER;
+use super::errors::*;
+use super::object::Proto;
+use super::stdlib::io::error_message;
+use super::undump::LoadState;
+
+mod codegen;
+pub mod lexer;
+pub mod parser;
+
+pub use self::parser::{parse, parse_with_columns};
+pub(crate) use self::codegen::fold;
+
+// Compile `chunk`, or load and verify it if it is a binary chunk
+pub fn load(chunk: Vec<u8>, chunkname: &str) -> Result<Proto> {
+    load_with_mode(chunk, chunkname, "bt")
+}
+
+// `load`, allowing binary chunks only if `mode` has a 'b' and text ones only
+// if it has a 't', as `load` in Lua. A chunk is binary if it starts with the
+// first byte of the header, which no source text can.
+pub fn load_with_mode(chunk: Vec<u8>, chunkname: &str, mode: &str) -> Result<Proto> {
+    let binary = chunk.first() == Some(&SYX_HEADER[0]);
+    let (kind, allowed) = if binary { ("binary", 'b') } else { ("text", 't') };
+    if !mode.contains(allowed) {
+        bail!(ErrorKind::ChunkModeError(kind, mode.to_owned()));
+    }
+    if binary {
+        let proto = LoadState::from_u8(chunk, chunkname)?;
+        proto.verify()?;
+        Ok(proto)
+    } else {
+        parse(&chunk, chunkname)
+    }
+}
+
+// `load` for the contents of the file at `path`, named "@path". A first line
+// starting with '#' is skipped, keeping its newline so line numbers stay right.
+pub fn load_file(path: &str) -> Result<Proto> {
+    let chunk = fs::read(path)
+        .map_err(|error| format!("cannot open {}", error_message(&error, Some(path))))?;
+    load_contents(chunk, path, "bt")
+}
+
+// `load_file` for contents already read from `path`, as `load_with_mode`
+pub(crate) fn load_contents(chunk: Vec<u8>, path: &str, mode: &str) -> Result<Proto> {
+    load_with_mode(skip_comment(chunk), &format!("@{}", path), mode)
+}
+
+// `chunk` without a first line starting with '#', but with its newline
+pub(crate) fn skip_comment(mut chunk: Vec<u8>) -> Vec<u8> {
+    if chunk.first() == Some(&b'#') {
+        let end = chunk.iter().position(|&c| c == b'\n').unwrap_or(chunk.len());
+        chunk.drain(..end);
+    }
+    chunk
+}
@@ -0,0 +1,1405 @@
+// Parser
+//
+// A recursive descent parser that generates code as it reads, with no syntax
+// tree in between (see codegen.rs). Each function being parsed has a
+// FuncState; those of the functions around it are kept so a name can be
+// resolved to a local of an enclosing function, which becomes an upvalue.
+// The main function is vararg and has _ENV as its only upvalue, and global
+// names are fields of _ENV.
+//
+// Consult the versioned lparser.c for more information.
+
+use std::mem;
+use std::sync::Arc;
+
+use super::super::errors::*;
+use super::super::object::{LocVar, Proto, SyxInt, SyxString, Upvalue};
+use super::super::opcodes::OpCode;
+use super::codegen::{int2fb, BinOpr, BlockCnt, ExpDesc, ExpKind, FuncState, UnOpr,
+                     LFIELDS_PER_FLUSH, MULTRET, NO_JUMP, UNARY_PRIORITY};
+use super::lexer::{Lexer, Spanned, Token};
+
+const MAXVARS: usize = 200; // active locals per function
+const MAXUPVAL: usize = 255; // upvalues per function
+const MAXCCALLS: usize = 200; // nesting of syntactical structures
+
+// A label, or a goto waiting for its label
+struct LabelDesc {
+    name: SyxString,
+    pc: i32,        // position of the label, or the jump list of the goto
+    line: usize,
+    nactvar: usize, // active locals at that point
+}
+
+// Items of a table constructor
+struct ConsControl {
+    v: ExpDesc,     // last list item read
+    table: usize,   // register of the table
+    nh: usize,      // number of record items
+    na: usize,      // number of list items
+    tostore: usize, // list items waiting for a SETLIST
+}
+
+pub(super) struct Parser<'a> {
+    lexer: Lexer<'a>,
+    current: Spanned,
+    pub lastline: usize, // line of the last token consumed
+    pub lastcolumn: usize, // column it starts at
+    pub(super) columns: bool, // whether to fill in columninfo
+    pub fs: FuncState,
+    enclosing: Vec<FuncState>,
+    actvar: Vec<usize>, // locvars index of every active local, by function
+    gotos: Vec<LabelDesc>,
+    labels: Vec<LabelDesc>,
+    source: String,
+    depth: usize,
+}
+
+// Compile the Lua source text in `source` into the Proto of its main function.
+// `chunkname` is recorded as the source of every function, and names the chunk
+// in error messages.
+pub fn parse(source: &[u8], chunkname: &str) -> Result<Proto> {
+    parse_source(source, chunkname, false)
+}
+
+// `parse`, also recording the column of every instruction in `columninfo`:
+// that of the token the instruction was emitted after
+pub fn parse_with_columns(source: &[u8], chunkname: &str) -> Result<Proto> {
+    parse_source(source, chunkname, true)
+}
+
+fn parse_source(source: &[u8], chunkname: &str, columns: bool) -> Result<Proto> {
+    let mut f = Proto::new();
+    f.source = chunkname.to_owned();
+    f.maxstacksize = 2;
+    f.is_vararg = 1;
+    f.upvalues.push(Upvalue { name: "_ENV".into(), instack: 1, idx: 0 });
+
+    let mut lexer = Lexer::new(source, chunkname);
+    let current = lexer.next_token()?;
+    let mut parser = Parser {
+        lexer,
+        current,
+        lastline: 1,
+        lastcolumn: 1,
+        columns,
+        fs: FuncState::new(f, 0),
+        enclosing: Vec::new(),
+        actvar: Vec::new(),
+        gotos: Vec::new(),
+        labels: Vec::new(),
+        source: chunkname.to_owned(),
+        depth: 0,
+    };
+    parser.enter_block(false);
+    parser.stat_list()?;
+    parser.check(&Token::Eos)?;
+    parser.close_func()
+}
+
+fn unary_op(token: &Token) -> Option<UnOpr> {
+    match *token {
+        Token::Not => Some(UnOpr::Not),
+        Token::Minus => Some(UnOpr::Minus),
+        Token::Tilde => Some(UnOpr::BNot),
+        Token::Hash => Some(UnOpr::Len),
+        _ => None,
+    }
+}
+
+fn binary_op(token: &Token) -> Option<BinOpr> {
+    Some(match *token {
+        Token::Plus => BinOpr::Add,
+        Token::Minus => BinOpr::Sub,
+        Token::Star => BinOpr::Mul,
+        Token::Percent => BinOpr::Mod,
+        Token::Caret => BinOpr::Pow,
+        Token::Slash => BinOpr::Div,
+        Token::DoubleSlash => BinOpr::IDiv,
+        Token::Ampersand => BinOpr::BAnd,
+        Token::Pipe => BinOpr::BOr,
+        Token::Tilde => BinOpr::BXOr,
+        Token::Shl => BinOpr::Shl,
+        Token::Shr => BinOpr::Shr,
+        Token::Concat => BinOpr::Concat,
+        Token::Ne => BinOpr::Ne,
+        Token::Eq => BinOpr::Eq,
+        Token::Lt => BinOpr::Lt,
+        Token::Le => BinOpr::Le,
+        Token::Gt => BinOpr::Gt,
+        Token::Ge => BinOpr::Ge,
+        Token::And => BinOpr::And,
+        Token::Or => BinOpr::Or,
+        _ => return None,
+    })
+}
+
+impl<'a> Parser<'a> {
+    // errors
+
+    // an error near the current token
+    pub(super) fn error<T>(&self, message: &str) -> Result<T> {
+        self.lexer.syntax_error(message, Some(&self.current))
+    }
+
+    // an error that is not about the current token
+    fn semantic_error<T>(&self, message: &str) -> Result<T> {
+        self.lexer.syntax_error(message, None)
+    }
+
+    fn error_expected<T>(&self, token: &Token) -> Result<T> {
+        self.error(&format!("{} expected", token))
+    }
+
+    fn error_limit<T>(&self, linedefined: SyxInt, limit: usize, what: &str) -> Result<T> {
+        let location = if linedefined == 0 {
+            "main function".to_owned()
+        } else {
+            format!("function at line {}", linedefined)
+        };
+        self.error(&format!("too many {} (limit is {}) in {}", what, limit, location))
+    }
+
+    fn enter_level(&mut self) -> Result<()> {
+        self.depth += 1;
+        if self.depth > MAXCCALLS {
+            return self.error_limit(self.fs.f.linedefined, MAXCCALLS, "C levels");
+        }
+        Ok(())
+    }
+
+    fn leave_level(&mut self) {
+        self.depth -= 1;
+    }
+
+    // tokens
+
+    fn next(&mut self) -> Result<()> {
+        self.lastline = self.lexer.line();
+        self.lastcolumn = self.current.position.column;
+        self.current = self.lexer.next_token()?;
+        Ok(())
+    }
+
+    fn test_next(&mut self, token: &Token) -> Result<bool> {
+        if self.current.token == *token {
+            self.next()?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn check(&self, token: &Token) -> Result<()> {
+        if self.current.token != *token {
+            return self.error_expected(token);
+        }
+        Ok(())
+    }
+
+    fn check_next(&mut self, token: &Token) -> Result<()> {
+        self.check(token)?;
+        self.next()
+    }
+
+    // `what` closing the `who` at `line`
+    fn check_match(&mut self, what: &Token, who: &Token, line: usize) -> Result<()> {
+        if !self.test_next(what)? {
+            if line == self.lexer.line() {
+                return self.error_expected(what);
+            }
+            return self.error(&format!("{} expected (to close {} at line {})", what, who, line));
+        }
+        Ok(())
+    }
+
+    fn str_check_name(&mut self) -> Result<SyxString> {
+        let name = match self.current.token {
+            Token::Name(ref name) => name.clone(),
+            _ => return self.error_expected(&Token::Name("".into())),
+        };
+        self.next()?;
+        Ok(name)
+    }
+
+    fn code_string(&mut self, s: SyxString) -> ExpDesc {
+        ExpDesc::new(ExpKind::K(self.string_k(s)))
+    }
+
+    fn check_name(&mut self) -> Result<ExpDesc> {
+        let name = self.str_check_name()?;
+        Ok(self.code_string(name))
+    }
+
+    fn block_follow(&self, withuntil: bool) -> bool {
+        match self.current.token {
+            Token::Else | Token::Elseif | Token::End | Token::Eos => true,
+            Token::Until => withuntil,
+            _ => false,
+        }
+    }
+
+    // variables
+
+    // the FuncState `level` functions down from the main one
+    fn func(&self, level: usize) -> &FuncState {
+        if level == self.enclosing.len() {
+            &self.fs
+        } else {
+            &self.enclosing[level]
+        }
+    }
+
+    fn func_mut(&mut self, level: usize) -> &mut FuncState {
+        if level == self.enclosing.len() {
+            &mut self.fs
+        } else {
+            &mut self.enclosing[level]
+        }
+    }
+
+    // the `i`th active local of the current function
+    fn local_var(&mut self, i: usize) -> &mut LocVar {
+        &mut self.fs.f.locvars[self.actvar[self.fs.firstlocal + i]]
+    }
+
+    fn new_local_var(&mut self, name: SyxString) -> Result<()> {
+        let index = self.fs.f.locvars.len();
+        self.fs.f.locvars.push(LocVar { varname: name, startpc: 0, endpc: 0 });
+        if self.actvar.len() + 1 - self.fs.firstlocal > MAXVARS {
+            return self.error_limit(self.fs.f.linedefined, MAXVARS, "local variables");
+        }
+        self.actvar.push(index);
+        Ok(())
+    }
+
+    // make the last `nvars` locals declared visible
+    fn adjust_local_vars(&mut self, nvars: usize) {
+        self.fs.nactvar += nvars;
+        let pc = self.fs.pc() as SyxInt;
+        for i in self.fs.nactvar - nvars..self.fs.nactvar {
+            self.local_var(i).startpc = pc;
+        }
+    }
+
+    fn remove_vars(&mut self, tolevel: usize) {
+        let remaining = self.actvar.len() - (self.fs.nactvar - tolevel);
+        let pc = self.fs.pc() as SyxInt;
+        while self.fs.nactvar > tolevel {
+            self.fs.nactvar -= 1;
+            let i = self.fs.nactvar;
+            self.local_var(i).endpc = pc;
+        }
+        self.actvar.truncate(remaining);
+    }
+
+    fn search_var(&self, level: usize, name: &SyxString) -> Option<usize> {
+        let fs = self.func(level);
+        (0..fs.nactvar).rev()
+            .find(|&i| fs.f.locvars[self.actvar[fs.firstlocal + i]].varname == *name)
+    }
+
+    fn search_upvalue(&self, level: usize, name: &SyxString) -> Option<usize> {
+        self.func(level).f.upvalues.iter().position(|up| up.name == *name)
+    }
+
+    fn new_upvalue(&mut self, level: usize, name: &SyxString, v: ExpKind) -> Result<usize> {
+        let (instack, idx) = match v {
+            ExpKind::Local(reg) => (1, reg),
+            ExpKind::Upval(idx) => (0, idx),
+            _ => unreachable!("upvalue of a {:?}", v),
+        };
+        let fs = self.func(level);
+        if fs.f.upvalues.len() + 1 > MAXUPVAL {
+            return self.error_limit(fs.f.linedefined, MAXUPVAL, "upvalues");
+        }
+        let upvalues = &mut self.func_mut(level).f.upvalues;
+        upvalues.push(Upvalue { name: name.clone(), instack, idx: idx as u8 });
+        Ok(upvalues.len() - 1)
+    }
+
+    // the block a local at `reg` belongs to must close its upvalues
+    fn mark_upval(&mut self, level: usize, reg: usize) {
+        let blocks = &mut self.func_mut(level).blocks;
+        if let Some(bl) = blocks.iter_mut().rev().find(|bl| bl.nactvar <= reg) {
+            bl.upval = true;
+        }
+    }
+
+    // find `name` in the function at `level` or one around it; `base` is
+    // whether that is where it is used
+    fn single_var_aux(&mut self, level: usize, name: &SyxString, base: bool) -> Result<ExpKind> {
+        if let Some(reg) = self.search_var(level, name) {
+            if !base {
+                self.mark_upval(level, reg); // a local used as an upvalue
+            }
+            return Ok(ExpKind::Local(reg));
+        }
+        let idx = match self.search_upvalue(level, name) {
+            Some(idx) => idx,
+            None => {
+                if level == 0 {
+                    return Ok(ExpKind::Void); // a global
+                }
+                match self.single_var_aux(level - 1, name, false)? {
+                    ExpKind::Void => return Ok(ExpKind::Void),
+                    outer => self.new_upvalue(level, name, outer)?,
+                }
+            }
+        };
+        Ok(ExpKind::Upval(idx))
+    }
+
+    fn single_var(&mut self) -> Result<ExpDesc> {
+        let name = self.str_check_name()?;
+        let level = self.enclosing.len();
+        match self.single_var_aux(level, &name, true)? {
+            ExpKind::Void => {
+                // _ENV.name
+                let env = self.single_var_aux(level, &"_ENV".into(), true)?;
+                let mut var = ExpDesc::new(env);
+                let mut key = self.code_string(name);
+                self.indexed(&mut var, &mut key)?;
+                Ok(var)
+            }
+            k => Ok(ExpDesc::new(k)),
+        }
+    }
+
+    // make `nexps` values (the last of them `e`) into `nvars` values
+    fn adjust_assign(&mut self, nvars: usize, nexps: usize, e: &mut ExpDesc) -> Result<()> {
+        let mut extra = nvars as isize - nexps as isize;
+        if e.has_multret() {
+            extra = (extra + 1).max(0); // the call itself counts
+            self.set_returns(e, extra as usize)?;
+            if extra > 1 {
+                self.reserve_regs(extra as usize - 1)?;
+            }
+        } else {
+            if e.k != ExpKind::Void {
+                self.exp_to_next_reg(e)?;
+            }
+            if extra > 0 {
+                let reg = self.fs.freereg;
+                self.reserve_regs(extra as usize)?;
+                self.code_nil(reg, extra as usize);
+            }
+        }
+        if nexps > nvars {
+            self.fs.freereg -= nexps - nvars; // drop the extra values
+        }
+        Ok(())
+    }
+
+    // blocks, labels and gotos
+
+    pub(super) fn enter_block(&mut self, isloop: bool) {
+        self.fs.blocks.push(BlockCnt {
+            firstlabel: self.labels.len(),
+            firstgoto: self.gotos.len(),
+            nactvar: self.fs.nactvar,
+            upval: false,
+            isloop,
+        });
+        debug_assert_eq!(self.fs.freereg, self.fs.nactvar);
+    }
+
+    fn leave_block(&mut self) -> Result<()> {
+        let bl = *self.fs.blocks.last().expect("a block to leave");
+        let outermost = self.fs.blocks.len() == 1;
+        if !outermost && bl.upval {
+            // a jump to the next instruction, to close the upvalues
+            let j = self.jump()?;
+            self.patch_close(j, bl.nactvar);
+            self.patch_to_here(j)?;
+        }
+        if bl.isloop {
+            self.break_label()?;
+        }
+        self.fs.blocks.pop();
+        self.remove_vars(bl.nactvar);
+        self.fs.freereg = self.fs.nactvar;
+        self.labels.truncate(bl.firstlabel);
+        if !outermost {
+            self.move_gotos_out(bl)
+        } else if bl.firstgoto < self.gotos.len() {
+            self.undefined_goto(bl.firstgoto)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn new_label_entry(&mut self, goto: bool, name: SyxString, line: usize, pc: i32) -> usize {
+        let list = if goto { &mut self.gotos } else { &mut self.labels };
+        list.push(LabelDesc { name, pc, line, nactvar: self.fs.nactvar });
+        list.len() - 1
+    }
+
+    // resolve the goto `g` to the label `l`
+    fn close_goto(&mut self, g: usize, l: usize) -> Result<()> {
+        let (gt, lb) = (&self.gotos[g], &self.labels[l]);
+        debug_assert!(gt.name == lb.name);
+        if gt.nactvar < lb.nactvar {
+            let nactvar = gt.nactvar;
+            let vname = self.fs.f.locvars[self.actvar[self.fs.firstlocal + nactvar]].varname.clone();
+            return self.semantic_error(&format!(
+                "<goto {}> at line {} jumps into the scope of local '{}'",
+                String::from_utf8_lossy(&gt.name), gt.line, String::from_utf8_lossy(&vname)));
+        }
+        let (list, target) = (gt.pc, lb.pc as usize);
+        self.patch_list(list, target)?;
+        self.gotos.remove(g);
+        Ok(())
+    }
+
+    // resolve the goto `g` to a visible label of the current block, if any
+    fn find_label(&mut self, g: usize) -> Result<bool> {
+        let bl = *self.fs.blocks.last().expect("an open block");
+        for l in bl.firstlabel..self.labels.len() {
+            if self.labels[l].name == self.gotos[g].name {
+                let (gt, lb) = (&self.gotos[g], &self.labels[l]);
+                if gt.nactvar > lb.nactvar && (bl.upval || self.labels.len() > bl.firstlabel) {
+                    let (list, level) = (gt.pc, lb.nactvar);
+                    self.patch_close(list, level);
+                }
+                self.close_goto(g, l)?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // resolve the pending gotos of the current block to the new label `l`
+    fn find_gotos(&mut self, l: usize) -> Result<()> {
+        let mut g = self.fs.blocks.last().expect("an open block").firstgoto;
+        while g < self.gotos.len() {
+            if self.gotos[g].name == self.labels[l].name {
+                self.close_goto(g, l)?;
+            } else {
+                g += 1;
+            }
+        }
+        Ok(())
+    }
+
+    // the pending gotos of a block that was left move to the enclosing one
+    fn move_gotos_out(&mut self, bl: BlockCnt) -> Result<()> {
+        let mut g = bl.firstgoto;
+        while g < self.gotos.len() {
+            if self.gotos[g].nactvar > bl.nactvar {
+                if bl.upval {
+                    let list = self.gotos[g].pc;
+                    self.patch_close(list, bl.nactvar);
+                }
+                self.gotos[g].nactvar = bl.nactvar;
+            }
+            if !self.find_label(g)? {
+                g += 1;
+            }
+        }
+        Ok(())
+    }
+
+    // breaks are gotos to an implicit label at the end of the loop
+    fn break_label(&mut self) -> Result<()> {
+        let pc = self.fs.pc() as i32;
+        let l = self.new_label_entry(false, "break".into(), 0, pc);
+        self.find_gotos(l)
+    }
+
+    fn undefined_goto<T>(&self, g: usize) -> Result<T> {
+        let gt = &self.gotos[g];
+        let name = String::from_utf8_lossy(&gt.name);
+        if &*gt.name == b"break" {
+            self.semantic_error(&format!("<{}> at line {} not inside a loop", name, gt.line))
+        } else {
+            self.semantic_error(&format!("no visible label '{}' for <goto> at line {}",
+                                         name, gt.line))
+        }
+    }
+
+    // functions
+
+    fn open_func(&mut self, linedefined: usize) {
+        let mut f = Proto::new();
+        f.source = self.source.clone();
+        f.linedefined = linedefined as SyxInt;
+        f.maxstacksize = 2; // registers 0 and 1 are always valid
+        let fs = FuncState::new(f, self.actvar.len());
+        let outer = mem::replace(&mut self.fs, fs);
+        self.enclosing.push(outer);
+        self.enter_block(false);
+    }
+
+    fn close_func(&mut self) -> Result<Proto> {
+        self.ret(0, 0); // final return
+        self.leave_block()?;
+        let outer = self.enclosing.pop().unwrap_or_else(|| FuncState::new(Proto::new(), 0));
+        Ok(mem::replace(&mut self.fs, outer).f)
+    }
+
+    fn par_list(&mut self) -> Result<()> {
+        let mut nparams = 0;
+        self.fs.f.is_vararg = 0;
+        if self.current.token != Token::RParen {
+            loop {
+                match self.current.token {
+                    Token::Name(_) => {
+                        let name = self.str_check_name()?;
+                        self.new_local_var(name)?;
+                        nparams += 1;
+                    }
+                    Token::Dots => {
+                        self.next()?;
+                        self.fs.f.is_vararg = 1;
+                    }
+                    _ => return self.error("<name> or '...' expected"),
+                }
+                if self.fs.f.is_vararg != 0 || !self.test_next(&Token::Comma)? {
+                    break;
+                }
+            }
+        }
+        self.adjust_local_vars(nparams);
+        self.fs.f.numparams = self.fs.nactvar as u8;
+        let nactvar = self.fs.nactvar;
+        self.reserve_regs(nactvar)
+    }
+
+    // parameters and body of a function, leaving its closure in a register
+    fn body(&mut self, ismethod: bool, line: usize) -> Result<ExpDesc> {
+        self.open_func(line);
+        self.check_next(&Token::LParen)?;
+        if ismethod {
+            self.new_local_var("self".into())?;
+            self.adjust_local_vars(1);
+        }
+        self.par_list()?;
+        self.check_next(&Token::RParen)?;
+        self.stat_list()?;
+        self.fs.f.lastlinedefined = self.lexer.line() as SyxInt;
+        self.check_match(&Token::End, &Token::Function, line)?;
+        let f = self.close_func()?;
+        self.fs.f.protos.push(Arc::new(f));
+        let index = self.fs.f.protos.len() - 1;
+        let mut e = ExpDesc::new(ExpKind::Relocable(self.code_abx(OpCode::Closure, 0, index)));
+        self.exp_to_next_reg(&mut e)?;
+        Ok(e)
+    }
+
+    // expressions
+
+    fn exp_list(&mut self) -> Result<(ExpDesc, usize)> {
+        let mut n = 1;
+        let mut v = self.expr()?;
+        while self.test_next(&Token::Comma)? {
+            self.exp_to_next_reg(&mut v)?;
+            v = self.expr()?;
+            n += 1;
+        }
+        Ok((v, n))
+    }
+
+    fn func_args(&mut self, f: &mut ExpDesc, line: usize) -> Result<()> {
+        let mut args = match self.current.token {
+            Token::LParen => {
+                self.next()?;
+                let args = if self.current.token == Token::RParen {
+                    ExpDesc::new(ExpKind::Void)
+                } else {
+                    let (mut args, _) = self.exp_list()?;
+                    self.set_multret(&mut args)?;
+                    args
+                };
+                self.check_match(&Token::RParen, &Token::LParen, line)?;
+                args
+            }
+            Token::LBrace => self.constructor()?,
+            Token::String(ref s) => {
+                let s = s.clone();
+                let args = self.code_string(s);
+                self.next()?;
+                args
+            }
+            _ => return self.error("function arguments expected"),
+        };
+        let base = match f.k {
+            ExpKind::NonReloc(reg) => reg,
+            _ => unreachable!("called expression is not in a register"),
+        };
+        let nparams = if args.has_multret() {
+            MULTRET
+        } else {
+            if args.k != ExpKind::Void {
+                self.exp_to_next_reg(&mut args)?; // close the last argument
+            }
+            self.fs.freereg - (base + 1)
+        };
+        *f = ExpDesc::new(ExpKind::Call(self.code_abc(OpCode::Call, base,
+                                                      nparams.wrapping_add(1), 2)));
+        self.fix_line(line);
+        // the call removes the function and its arguments, leaving one result
+        self.fs.freereg = base + 1;
+        Ok(())
+    }
+
+    fn primary_exp(&mut self) -> Result<ExpDesc> {
+        match self.current.token {
+            Token::LParen => {
+                let line = self.lexer.line();
+                self.next()?;
+                let mut v = self.expr()?;
+                self.check_match(&Token::RParen, &Token::LParen, line)?;
+                self.discharge_vars(&mut v);
+                Ok(v)
+            }
+            Token::Name(_) => self.single_var(),
+            _ => self.error("unexpected symbol"),
+        }
+    }
+
+    fn field_sel(&mut self, v: &mut ExpDesc) -> Result<()> {
+        self.exp_to_any_reg_up(v)?;
+        self.next()?; // skip the dot or colon
+        let mut key = self.check_name()?;
+        self.indexed(v, &mut key)
+    }
+
+    fn y_index(&mut self) -> Result<ExpDesc> {
+        self.next()?; // skip the '['
+        let mut v = self.expr()?;
+        self.exp_to_val(&mut v)?;
+        self.check_next(&Token::RBracket)?;
+        Ok(v)
+    }
+
+    fn suffixed_exp(&mut self) -> Result<ExpDesc> {
+        let line = self.lexer.line();
+        let mut v = self.primary_exp()?;
+        loop {
+            match self.current.token {
+                Token::Dot => self.field_sel(&mut v)?,
+                Token::LBracket => {
+                    self.exp_to_any_reg_up(&mut v)?;
+                    let mut key = self.y_index()?;
+                    self.indexed(&mut v, &mut key)?;
+                }
+                Token::Colon => {
+                    self.next()?;
+                    let mut key = self.check_name()?;
+                    self.code_self(&mut v, &mut key)?;
+                    self.func_args(&mut v, line)?;
+                }
+                Token::LParen | Token::String(_) | Token::LBrace => {
+                    self.exp_to_next_reg(&mut v)?;
+                    self.func_args(&mut v, line)?;
+                }
+                _ => return Ok(v),
+            }
+        }
+    }
+
+    fn rec_field(&mut self, cc: &mut ConsControl) -> Result<()> {
+        let reg = self.fs.freereg;
+        let mut key = match self.current.token {
+            Token::Name(_) => self.check_name()?,
+            _ => self.y_index()?,
+        };
+        cc.nh += 1;
+        self.check_next(&Token::Assign)?;
+        let rkkey = self.exp_to_rk(&mut key)?;
+        let mut val = self.expr()?;
+        let rkval = self.exp_to_rk(&mut val)?;
+        self.code_abc(OpCode::SetTable, cc.table, rkkey, rkval);
+        self.fs.freereg = reg; // free the registers of the key and value
+        Ok(())
+    }
+
+    fn close_list_field(&mut self, cc: &mut ConsControl) -> Result<()> {
+        if cc.v.k == ExpKind::Void {
+            return Ok(()); // there is no list item
+        }
+        self.exp_to_next_reg(&mut cc.v)?;
+        cc.v.k = ExpKind::Void;
+        if cc.tostore == LFIELDS_PER_FLUSH {
+            self.set_list(cc.table, cc.na, cc.tostore)?;
+            cc.tostore = 0;
+        }
+        Ok(())
+    }
+
+    fn last_list_field(&mut self, cc: &mut ConsControl) -> Result<()> {
+        if cc.tostore == 0 {
+            return Ok(());
+        }
+        if cc.v.has_multret() {
+            self.set_multret(&mut cc.v)?;
+            self.set_list(cc.table, cc.na, MULTRET)?;
+            cc.na -= 1; // do not count the last item, its size is unknown
+        } else {
+            if cc.v.k != ExpKind::Void {
+                self.exp_to_next_reg(&mut cc.v)?;
+            }
+            self.set_list(cc.table, cc.na, cc.tostore)?;
+        }
+        Ok(())
+    }
+
+    fn list_field(&mut self, cc: &mut ConsControl) -> Result<()> {
+        cc.v = self.expr()?;
+        cc.na += 1;
+        cc.tostore += 1;
+        Ok(())
+    }
+
+    fn field(&mut self, cc: &mut ConsControl) -> Result<()> {
+        match self.current.token {
+            Token::Name(_) => {
+                if self.lexer.peek_token()?.token != Token::Assign {
+                    self.list_field(cc)
+                } else {
+                    self.rec_field(cc)
+                }
+            }
+            Token::LBracket => self.rec_field(cc),
+            _ => self.list_field(cc),
+        }
+    }
+
+    fn constructor(&mut self) -> Result<ExpDesc> {
+        let line = self.lexer.line();
+        let pc = self.code_abc(OpCode::NewTable, 0, 0, 0);
+        let mut t = ExpDesc::new(ExpKind::Relocable(pc));
+        self.exp_to_next_reg(&mut t)?;
+        let table = match t.k {
+            ExpKind::NonReloc(reg) => reg,
+            _ => unreachable!("table is in a register"),
+        };
+        let mut cc = ConsControl { v: ExpDesc::new(ExpKind::Void), table, nh: 0, na: 0, tostore: 0 };
+        self.check_next(&Token::LBrace)?;
+        loop {
+            if self.current.token == Token::RBrace {
+                break;
+            }
+            self.close_list_field(&mut cc)?;
+            self.field(&mut cc)?;
+            if !self.test_next(&Token::Comma)? && !self.test_next(&Token::Semicolon)? {
+                break;
+            }
+        }
+        self.check_match(&Token::RBrace, &Token::LBrace, line)?;
+        self.last_list_field(&mut cc)?;
+        self.set_instruction_b(pc, int2fb(cc.na)); // initial sizes
+        self.set_instruction_c(pc, int2fb(cc.nh));
+        Ok(t)
+    }
+
+    fn simple_exp(&mut self) -> Result<ExpDesc> {
+        let e = match self.current.token {
+            Token::Float(n) => ExpDesc::new(ExpKind::KFlt(n)),
+            Token::Int(i) => ExpDesc::new(ExpKind::KInt(i)),
+            Token::String(ref s) => {
+                let s = s.clone();
+                self.code_string(s)
+            }
+            Token::Nil => ExpDesc::new(ExpKind::Nil),
+            Token::True => ExpDesc::new(ExpKind::True),
+            Token::False => ExpDesc::new(ExpKind::False),
+            Token::Dots => {
+                if self.fs.f.is_vararg == 0 {
+                    return self.error("cannot use '...' outside a vararg function");
+                }
+                ExpDesc::new(ExpKind::VarArg(self.code_abc(OpCode::VarArg, 0, 1, 0)))
+            }
+            Token::LBrace => return self.constructor(),
+            Token::Function => {
+                self.next()?;
+                let line = self.lexer.line();
+                return self.body(false, line);
+            }
+            _ => return self.suffixed_exp(),
+        };
+        self.next()?;
+        Ok(e)
+    }
+
+    // an expression whose binary operators bind tighter than `limit`, and the
+    // first operator that does not
+    fn sub_expr(&mut self, limit: u8) -> Result<(ExpDesc, Option<BinOpr>)> {
+        self.enter_level()?;
+        let mut v = match unary_op(&self.current.token) {
+            Some(op) => {
+                let line = self.lexer.line();
+                self.next()?;
+                let (mut v, _) = self.sub_expr(UNARY_PRIORITY)?;
+                self.prefix(op, &mut v, line)?;
+                v
+            }
+            None => self.simple_exp()?,
+        };
+        let mut op = binary_op(&self.current.token);
+        while let Some(current) = op {
+            let (left, right) = current.priority();
+            if left <= limit {
+                break;
+            }
+            let line = self.lexer.line();
+            self.next()?;
+            self.infix(current, &mut v)?;
+            let (mut v2, next) = self.sub_expr(right)?;
+            self.posfix(current, &mut v, &mut v2, line)?;
+            op = next;
+        }
+        self.leave_level();
+        Ok((v, op))
+    }
+
+    fn expr(&mut self) -> Result<ExpDesc> {
+        Ok(self.sub_expr(0)?.0)
+    }
+
+    // statements
+
+    fn stat_list(&mut self) -> Result<()> {
+        while !self.block_follow(true) {
+            if self.current.token == Token::Return {
+                return self.statement(); // 'return' must be the last statement
+            }
+            self.statement()?;
+        }
+        Ok(())
+    }
+
+    fn block(&mut self) -> Result<()> {
+        self.enter_block(false);
+        self.stat_list()?;
+        self.leave_block()
+    }
+
+    // when `v` is assigned to, a table or index of an earlier target in the
+    // same assignment must be read from a copy of its old value
+    fn check_conflict(&mut self, lhs: &mut [ExpDesc], v: &ExpDesc) -> Result<()> {
+        let extra = self.fs.freereg;
+        let mut conflict = false;
+        for lh in lhs.iter_mut() {
+            if let ExpKind::Indexed { ref mut t, ref mut idx, ref mut upval } = lh.k {
+                let same_table = match v.k {
+                    ExpKind::Upval(i) => *upval && *t == i,
+                    ExpKind::Local(reg) => !*upval && *t == reg,
+                    _ => false,
+                };
+                if same_table {
+                    conflict = true;
+                    *upval = false;
+                    *t = extra;
+                }
+                if let ExpKind::Local(reg) = v.k {
+                    if *idx == reg {
+                        conflict = true;
+                        *idx = extra;
+                    }
+                }
+            }
+        }
+        if conflict {
+            match v.k {
+                ExpKind::Local(reg) => self.code_abc(OpCode::Move, extra, reg, 0),
+                ExpKind::Upval(idx) => self.code_abc(OpCode::GetUpval, extra, idx, 0),
+                _ => unreachable!("conflict with a {:?}", v.k),
+            };
+            self.reserve_regs(1)?;
+        }
+        Ok(())
+    }
+
+    // the rest of an assignment to the targets in `lhs`, the last one being
+    // the one just read
+    fn assignment(&mut self, lhs: &mut Vec<ExpDesc>) -> Result<()> {
+        if !lhs.last().expect("a target").is_var() {
+            return self.error("syntax error");
+        }
+        let nvars = lhs.len();
+        if self.test_next(&Token::Comma)? {
+            let v = self.suffixed_exp()?;
+            if let ExpKind::Indexed { .. } = v.k {
+            } else {
+                self.check_conflict(lhs, &v)?;
+            }
+            if nvars + self.depth > MAXCCALLS {
+                return self.error_limit(self.fs.f.linedefined, MAXCCALLS, "C levels");
+            }
+            lhs.push(v);
+            self.assignment(lhs)?;
+            lhs.pop();
+        } else {
+            self.check_next(&Token::Assign)?;
+            let (mut e, nexps) = self.exp_list()?;
+            if nexps == nvars {
+                self.set_one_ret(&mut e);
+                let var = *lhs.last().expect("a target");
+                return self.store_var(&var, &mut e);
+            }
+            self.adjust_assign(nvars, nexps, &mut e)?;
+        }
+        // the value for this target is the one on top of the stack
+        let mut e = ExpDesc::new(ExpKind::NonReloc(self.fs.freereg - 1));
+        let var = *lhs.last().expect("a target");
+        self.store_var(&var, &mut e)
+    }
+
+    // a condition, returning the jumps out when it is false
+    fn cond(&mut self) -> Result<i32> {
+        let mut v = self.expr()?;
+        if v.k == ExpKind::Nil {
+            v.k = ExpKind::False; // "falses" are all equal here
+        }
+        self.go_if_true(&mut v)?;
+        Ok(v.f)
+    }
+
+    // 'goto name' or 'break', where `pc` is the jump list to the label
+    fn goto_stat(&mut self, pc: i32) -> Result<()> {
+        let line = self.lexer.line();
+        let label = if self.test_next(&Token::Goto)? {
+            self.str_check_name()?
+        } else {
+            self.next()?; // skip 'break'
+            "break".into()
+        };
+        let g = self.new_label_entry(true, label, line, pc);
+        self.find_label(g)?;
+        Ok(())
+    }
+
+    fn check_repeated(&self, label: &SyxString) -> Result<()> {
+        let firstlabel = self.fs.blocks.last().expect("an open block").firstlabel;
+        if let Some(lb) = self.labels[firstlabel..].iter().find(|lb| lb.name == *label) {
+            return self.semantic_error(&format!("label '{}' already defined on line {}",
+                                                String::from_utf8_lossy(label), lb.line));
+        }
+        Ok(())
+    }
+
+    fn skip_noop_stat(&mut self) -> Result<()> {
+        while self.current.token == Token::Semicolon || self.current.token == Token::DbColon {
+            self.statement()?;
+        }
+        Ok(())
+    }
+
+    fn label_stat(&mut self, label: SyxString, line: usize) -> Result<()> {
+        self.check_repeated(&label)?;
+        self.check_next(&Token::DbColon)?;
+        let pc = self.get_label() as i32;
+        let l = self.new_label_entry(false, label, line, pc);
+        self.skip_noop_stat()?;
+        if self.block_follow(false) {
+            // the locals of the block are already dead at a label ending it
+            self.labels[l].nactvar = self.fs.blocks.last().expect("an open block").nactvar;
+        }
+        self.find_gotos(l)
+    }
+
+    fn while_stat(&mut self, line: usize) -> Result<()> {
+        self.next()?;
+        let whileinit = self.get_label();
+        let condexit = self.cond()?;
+        self.enter_block(true);
+        self.check_next(&Token::Do)?;
+        self.block()?;
+        let back = self.jump()?;
+        self.patch_list(back, whileinit)?;
+        self.check_match(&Token::End, &Token::While, line)?;
+        self.leave_block()?;
+        self.patch_to_here(condexit)
+    }
+
+    fn repeat_stat(&mut self, line: usize) -> Result<()> {
+        let repeat_init = self.get_label();
+        self.enter_block(true); // loop block
+        self.enter_block(false); // scope block, which the condition can see
+        self.next()?;
+        self.stat_list()?;
+        self.check_match(&Token::Until, &Token::Repeat, line)?;
+        let condexit = self.cond()?;
+        let scope = *self.fs.blocks.last().expect("an open block");
+        if scope.upval {
+            self.patch_close(condexit, scope.nactvar);
+        }
+        self.leave_block()?;
+        self.patch_list(condexit, repeat_init)?;
+        self.leave_block()
+    }
+
+    // an expression into the next register
+    fn exp1(&mut self) -> Result<()> {
+        let mut e = self.expr()?;
+        self.exp_to_next_reg(&mut e)
+    }
+
+    fn for_body(&mut self, base: usize, line: usize, nvars: usize, isnum: bool) -> Result<()> {
+        self.adjust_local_vars(3); // control variables
+        self.check_next(&Token::Do)?;
+        let prep = if isnum {
+            self.code_asbx(OpCode::ForPrep, base, NO_JUMP) as i32
+        } else {
+            self.jump()?
+        };
+        self.enter_block(false); // scope of the declared variables
+        self.adjust_local_vars(nvars);
+        self.reserve_regs(nvars)?;
+        self.block()?;
+        self.leave_block()?;
+        self.patch_to_here(prep)?;
+        let endfor = if isnum {
+            self.code_asbx(OpCode::ForLoop, base, NO_JUMP)
+        } else {
+            self.code_abc(OpCode::TForCall, base, 0, nvars);
+            self.fix_line(line);
+            self.code_asbx(OpCode::TForLoop, base + 2, NO_JUMP)
+        };
+        self.patch_list(endfor as i32, prep as usize + 1)?;
+        self.fix_line(line);
+        Ok(())
+    }
+
+    fn for_num(&mut self, varname: SyxString, line: usize) -> Result<()> {
+        let base = self.fs.freereg;
+        self.new_local_var("(for index)".into())?;
+        self.new_local_var("(for limit)".into())?;
+        self.new_local_var("(for step)".into())?;
+        self.new_local_var(varname)?;
+        self.check_next(&Token::Assign)?;
+        self.exp1()?; // initial value
+        self.check_next(&Token::Comma)?;
+        self.exp1()?; // limit
+        if self.test_next(&Token::Comma)? {
+            self.exp1()?; // optional step
+        } else {
+            let (reg, k) = (self.fs.freereg, self.int_k(1));
+            self.code_k(reg, k); // default step is 1
+            self.reserve_regs(1)?;
+        }
+        self.for_body(base, line, 1, true)
+    }
+
+    fn for_list(&mut self, indexname: SyxString) -> Result<()> {
+        let base = self.fs.freereg;
+        let mut nvars = 4; // generator, state, control and the first variable
+        self.new_local_var("(for generator)".into())?;
+        self.new_local_var("(for state)".into())?;
+        self.new_local_var("(for control)".into())?;
+        self.new_local_var(indexname)?;
+        while self.test_next(&Token::Comma)? {
+            let name = self.str_check_name()?;
+            self.new_local_var(name)?;
+            nvars += 1;
+        }
+        self.check_next(&Token::In)?;
+        let line = self.lexer.line();
+        let (mut e, nexps) = self.exp_list()?;
+        self.adjust_assign(3, nexps, &mut e)?;
+        self.check_stack(3)?; // room for the call to the generator
+        self.for_body(base, line, nvars - 3, false)
+    }
+
+    fn for_stat(&mut self, line: usize) -> Result<()> {
+        self.enter_block(true); // scope of the loop and its control variables
+        self.next()?;
+        let varname = self.str_check_name()?;
+        match self.current.token {
+            Token::Assign => self.for_num(varname, line)?,
+            Token::Comma | Token::In => self.for_list(varname)?,
+            _ => return self.error("'=' or 'in' expected"),
+        }
+        self.check_match(&Token::End, &Token::For, line)?;
+        self.leave_block() // loop scope ('break' jumps here)
+    }
+
+    // 'if cond then block' or 'elseif cond then block'
+    fn test_then_block(&mut self, escapelist: &mut i32) -> Result<()> {
+        self.next()?;
+        let mut v = self.expr()?;
+        self.check_next(&Token::Then)?;
+        // the jump over the block when the condition is false
+        let jf = if self.current.token == Token::Goto || self.current.token == Token::Break {
+            // 'if cond then goto', jumping straight to the label when true
+            self.go_if_false(&mut v)?;
+            self.enter_block(false);
+            self.goto_stat(v.t)?;
+            while self.test_next(&Token::Semicolon)? {}
+            if self.block_follow(false) {
+                return self.leave_block(); // that was the whole block
+            }
+            self.jump()?
+        } else {
+            self.go_if_true(&mut v)?;
+            self.enter_block(false);
+            v.f
+        };
+        self.stat_list()?;
+        self.leave_block()?;
+        if self.current.token == Token::Else || self.current.token == Token::Elseif {
+            let j = self.jump()?; // out of the whole 'if' after the block
+            self.concat(escapelist, j)?;
+        }
+        self.patch_to_here(jf)
+    }
+
+    fn if_stat(&mut self, line: usize) -> Result<()> {
+        let mut escapelist = NO_JUMP;
+        self.test_then_block(&mut escapelist)?;
+        while self.current.token == Token::Elseif {
+            self.test_then_block(&mut escapelist)?;
+        }
+        if self.test_next(&Token::Else)? {
+            self.block()?;
+        }
+        self.check_match(&Token::End, &Token::If, line)?;
+        self.patch_to_here(escapelist)
+    }
+
+    fn local_func(&mut self) -> Result<()> {
+        let name = self.str_check_name()?;
+        self.new_local_var(name)?;
+        self.adjust_local_vars(1); // the function can see itself
+        let line = self.lexer.line();
+        let b = self.body(false, line)?;
+        // debug information only sees the variable once it has its value
+        if let ExpKind::NonReloc(reg) = b.k {
+            let pc = self.fs.pc() as SyxInt;
+            self.local_var(reg).startpc = pc;
+        }
+        Ok(())
+    }
+
+    fn local_stat(&mut self) -> Result<()> {
+        let mut nvars = 0;
+        loop {
+            let name = self.str_check_name()?;
+            self.new_local_var(name)?;
+            nvars += 1;
+            if !self.test_next(&Token::Comma)? {
+                break;
+            }
+        }
+        let (mut e, nexps) = if self.test_next(&Token::Assign)? {
+            self.exp_list()?
+        } else {
+            (ExpDesc::new(ExpKind::Void), 0)
+        };
+        self.adjust_assign(nvars, nexps, &mut e)?;
+        self.adjust_local_vars(nvars);
+        Ok(())
+    }
+
+    fn func_name(&mut self) -> Result<(ExpDesc, bool)> {
+        let mut v = self.single_var()?;
+        while self.current.token == Token::Dot {
+            self.field_sel(&mut v)?;
+        }
+        if self.current.token == Token::Colon {
+            self.field_sel(&mut v)?;
+            return Ok((v, true));
+        }
+        Ok((v, false))
+    }
+
+    fn func_stat(&mut self, line: usize) -> Result<()> {
+        self.next()?; // skip 'function'
+        let (v, ismethod) = self.func_name()?;
+        let mut b = self.body(ismethod, line)?;
+        self.store_var(&v, &mut b)?;
+        self.fix_line(line); // the definition happens in the first line
+        Ok(())
+    }
+
+    fn expr_stat(&mut self) -> Result<()> {
+        let v = self.suffixed_exp()?;
+        if self.current.token == Token::Assign || self.current.token == Token::Comma {
+            return self.assignment(&mut vec![v]);
+        }
+        match v.k {
+            ExpKind::Call(pc) => {
+                self.set_instruction_c(pc, 1); // the call statement uses no results
+                Ok(())
+            }
+            _ => self.error("syntax error"),
+        }
+    }
+
+    fn ret_stat(&mut self) -> Result<()> {
+        let (first, nret);
+        if self.block_follow(true) || self.current.token == Token::Semicolon {
+            first = 0; // return no values
+            nret = 0;
+        } else {
+            let (mut e, n) = self.exp_list()?;
+            if e.has_multret() {
+                self.set_multret(&mut e)?;
+                if let ExpKind::Call(pc) = e.k {
+                    if n == 1 {
+                        self.set_opcode(pc, OpCode::TailCall);
+                        debug_assert_eq!(self.instruction_a(pc), self.fs.nactvar);
+                    }
+                }
+                first = self.fs.nactvar;
+                nret = MULTRET;
+            } else if n == 1 {
+                first = self.exp_to_any_reg(&mut e)?;
+                nret = 1;
+            } else {
+                self.exp_to_next_reg(&mut e)?; // values must go to the stack
+                first = self.fs.nactvar;
+                nret = n;
+                debug_assert_eq!(nret, self.fs.freereg - first);
+            }
+        }
+        self.ret(first, nret);
+        self.test_next(&Token::Semicolon)?; // skip an optional semicolon
+        Ok(())
+    }
+
+    fn statement(&mut self) -> Result<()> {
+        let line = self.lexer.line();
+        self.enter_level()?;
+        match self.current.token {
+            Token::Semicolon => self.next()?, // an empty statement
+            Token::If => self.if_stat(line)?,
+            Token::While => self.while_stat(line)?,
+            Token::Do => {
+                self.next()?;
+                self.block()?;
+                self.check_match(&Token::End, &Token::Do, line)?;
+            }
+            Token::For => self.for_stat(line)?,
+            Token::Repeat => self.repeat_stat(line)?,
+            Token::Function => self.func_stat(line)?,
+            Token::Local => {
+                self.next()?;
+                if self.test_next(&Token::Function)? {
+                    self.local_func()?;
+                } else {
+                    self.local_stat()?;
+                }
+            }
+            Token::DbColon => {
+                self.next()?;
+                let name = self.str_check_name()?;
+                self.label_stat(name, line)?;
+            }
+            Token::Return => {
+                self.next()?;
+                self.ret_stat()?;
+            }
+            Token::Break | Token::Goto => {
+                let pc = self.jump()?;
+                self.goto_stat(pc)?;
+            }
+            _ => self.expr_stat()?, // a function call or an assignment
+        }
+        debug_assert!(self.fs.f.maxstacksize as usize >= self.fs.freereg &&
+                      self.fs.freereg >= self.fs.nactvar);
+        self.fs.freereg = self.fs.nactvar; // free the registers of temporaries
+        self.leave_level();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::object::{MultiValue, SyxValue};
+    use super::super::super::testing::run_source;
+    use super::*;
+
+    fn run(source: &str) -> MultiValue {
+        run_source(source).expect("runs")
+    }
+
+    fn error(source: &str) -> String {
+        parse(source.as_bytes(), "=test").expect_err("fails to compile").to_string()
+    }
+
+    #[test]
+    fn test_expressions() {
+        assert_eq!(run("local a, b = 6, 4 return a + b * 2, (a + b) * 2, 2 ^ 3 ^ 2, -a // b"),
+                   vec![SyxValue::Integer(14), SyxValue::Integer(20), SyxValue::Number(512.0),
+                        SyxValue::Integer(-2)]);
+        assert_eq!(run("local a, b = nil, 3 return a or b, a and b, not a, a == nil, b >= 3"),
+                   vec![SyxValue::Integer(3), SyxValue::Nil, SyxValue::Bool(true),
+                        SyxValue::Bool(true), SyxValue::Bool(true)]);
+        assert_eq!(run("local s = 'a' return s .. 'b' .. s, #'four', 1 << 4 | 1"),
+                   vec![SyxValue::from("aba"), SyxValue::Integer(4), SyxValue::Integer(17)]);
+    }
+
+    #[test]
+    fn test_functions_and_upvalues() {
+        let source = "
+            local function counter()
+              local n = 0
+              return function() n = n + 1 return n end
+            end
+            local c1, c2 = counter(), counter()
+            c1() c1()
+            local t = {value = 10}
+            function t:get(plus, ...) return self.value + plus, select('#', ...) end
+            return c1(), c2(), t:get(5, nil, nil)
+        ";
+        assert_eq!(run(source), vec![SyxValue::Integer(3), SyxValue::Integer(1),
+                                     SyxValue::Integer(15), SyxValue::Integer(2)]);
+    }
+
+    #[test]
+    fn test_control_flow() {
+        let source = "
+            local sum = 0
+            for i = 1, 10 do
+              if i % 2 == 0 then goto continue end
+              if i > 7 then break end
+              sum = sum + i
+              ::continue::
+            end
+            local n = 0
+            while n < 5 do n = n + 1 end
+            repeat local m = n n = n - 2 until m < 3
+            local keys = 0
+            for _, v in ipairs({1, 2, 3, 4}) do keys = keys + v end
+            local closures = {}
+            for i = 1, 3 do closures[i] = function() return i end end
+            return sum, n, keys, closures[1]() + closures[3]()
+        ";
+        assert_eq!(run(source), vec![SyxValue::Integer(16), SyxValue::Integer(-1),
+                                     SyxValue::Integer(10), SyxValue::Integer(4)]);
+    }
+
+    #[test]
+    fn test_tables_and_assignment() {
+        let source = "
+            local t = {1, 2, 3; x = 'x', ['y'] = 'y', select(2, 'a', 'b', 'c')}
+            local i = 1
+            i, t[i] = i + 1, 20
+            local a, b, c = (function() return 1, 2, 3 end)()
+            return #t, t.x .. t.y, i, t[1], a + b + c
+        ";
+        assert_eq!(run(source), vec![SyxValue::Integer(5), SyxValue::from("xy"),
+                                     SyxValue::Integer(2), SyxValue::Integer(20),
+                                     SyxValue::Integer(6)]);
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        assert_eq!(error("x = = 1"), "test:1: unexpected symbol near '='");
+        assert_eq!(error("for i do end"), "test:1: '=' or 'in' expected near 'do'");
+        assert_eq!(error("if x then\n\n"),
+                   "test:3: 'end' expected (to close 'if' at line 1) near <eof>");
+        assert_eq!(error("local function f() return ... end"),
+                   "test:1: cannot use '...' outside a vararg function near '...'");
+        assert_eq!(error("goto nowhere"), "test:1: no visible label 'nowhere' for <goto> at line 1");
+        assert_eq!(error("break"), "test:1: <break> at line 1 not inside a loop");
+        assert_eq!(error("::a:: ::a::"), "test:1: label 'a' already defined on line 1");
+        assert_eq!(error("goto l local x ::l:: print(x)"),
+                   "test:1: <goto l> at line 1 jumps into the scope of local 'x'");
+        assert_eq!(error("f() = 1"), "test:1: syntax error near '='");
+        assert_eq!(error("x"), "test:1: syntax error near <eof>");
+    }
+}
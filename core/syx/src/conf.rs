@@ -1,5 +1,3 @@
-use super::object::{SyxInteger, SyxNumber};
-
 // Syx VM version information
 
 pub const SYX_VERSION_MAJOR: u8 = 0x5;
@@ -13,5 +11,38 @@ pub const SYX_HEADER: &[u8] = b"\x1bLua";
 pub const SYX_DATA: &[u8] = b"\x19\x93\r\n\x1a\n";
 pub const SYX_VERSION: u8 = SYX_VERSION_MAJOR * 16 + SYX_VERSION_MINOR;
 pub const SYX_FORMAT: u8 = 0; // official PUC-Rio format
-pub const SYX_INT: SyxInteger = 0x5678;
-pub const SYX_NUM: SyxNumber = (370.5f32 as SyxNumber);
+pub const SYX_FORMAT_COLUMNS: u8 = 2; // added to it, debug info has columns too
+// stored as a lua_Integer and a lua_Number, of whichever widths
+pub const SYX_INT: i64 = 0x5678;
+pub const SYX_NUM: f64 = 370.5;
+// significant digits tostring shows of a float (LUAI_NUMFFORMAT, "%.14g")
+#[cfg(not(feature = "float32"))]
+pub const SYX_NUMBER_DIGITS: usize = 14;
+#[cfg(feature = "float32")]
+pub const SYX_NUMBER_DIGITS: usize = 7;
+pub const SYX_MAXNUMBER2STR: usize = 44; // longest number tostring gives (MAXNUMBER2STR)
+
+// Interpreter limits
+
+pub const SYX_MAXTAGLOOP: usize = 2000; // default limit for __index/__newindex chains
+pub const SYX_MAXSTACK: usize = 1_000_000; // default limit for stack slots, per thread
+pub const SYX_ERRORSTACK: usize = 200; // slots past the limit for a message handler
+pub const SYX_MAXCCALLS: usize = 200; // calls from Rust into Lua nested in one another
+
+// Standard library
+
+pub const SYX_RANDOMSEED: u64 = 0x2545_f491_4f6c_dd1d; // math.random before randomseed
+
+// where require looks for modules (package.path and package.cpath)
+pub const SYX_PATH_DEFAULT: &str =
+    "/usr/local/share/lua/5.3/?.lua;/usr/local/share/lua/5.3/?/init.lua;\
+     /usr/local/lib/lua/5.3/?.lua;/usr/local/lib/lua/5.3/?/init.lua;./?.lua;./?/init.lua";
+pub const SYX_CPATH_DEFAULT: &str =
+    "/usr/local/lib/lua/5.3/?.so;/usr/local/lib/lua/5.3/loadall.so;./?.so";
+
+// Garbage collector
+
+pub const SYX_GCPAUSE: usize = 200; // collect again once memory use has doubled (in %)
+pub const SYX_GCMINTHRESHOLD: usize = 64 * 1024; // never collect below this many bytes
+pub const SYX_GCSTEPMUL: usize = 200; // incremental work per step (in %)
+pub const SYX_GCSTEPSIZE: usize = 8 * 1024; // bytes allocated between steps
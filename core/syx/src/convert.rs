@@ -0,0 +1,422 @@
+// Conversions between Rust types and values
+//
+// `IntoSyx` turns a Rust value into a SyxValue and `FromSyx` back, for
+// booleans, numbers, strings, `Option` (None being nil), and `Vec` and
+// `HashMap` as sequences and tables. Both can be derived for structs, whose
+// fields become string keys of a table (or, for tuple structs, the items of
+// a sequence):
+//
+//     #[derive(IntoSyx, FromSyx)]
+//     struct Point { x: f64, y: f64 }
+//
+// Calls take and return several values, converted as tuples by
+// `IntoSyxMulti` and `FromSyxMulti`, with missing values read as nil and
+// extra ones ignored. A single value is a 1-tuple. `Args::unpack` reports a
+// failed conversion as a bad argument.
+//
+// Consult the versioned lauxlib.c (luaL_check*, luaL_opt*) for more
+// information.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::hash::Hash;
+
+use super::errors::*;
+use super::native::Args;
+use super::object::{
+    float_to_integer, MultiValue, SyxInteger, SyxNumber, SyxString, SyxValue, TableRef,
+};
+use super::state::SyxState;
+use super::vm::{append_string, bad_argument};
+
+pub use syx_codegen::{FromSyx, IntoSyx};
+
+pub trait IntoSyx {
+    fn into_syx(self, state: &mut SyxState) -> Result<SyxValue>;
+}
+
+pub trait FromSyx: Sized {
+    fn from_syx(value: SyxValue, state: &SyxState) -> Result<Self>;
+}
+
+pub trait IntoSyxMulti {
+    fn into_syx_multi(self, state: &mut SyxState) -> Result<MultiValue>;
+}
+
+pub trait FromSyxMulti: Sized {
+    fn from_syx_multi(values: MultiValue, state: &SyxState) -> Result<Self>;
+}
+
+fn conversion_error<T>(value: &SyxValue, to: &'static str) -> Result<T> {
+    Err(ErrorKind::FromSyxError(value.type_name(), to).into())
+}
+
+// t[key] = value, for derived conversions
+pub fn set_field(state: &mut SyxState, table: TableRef, key: SyxValue, value: SyxValue)
+    -> Result<()>
+{
+    state.table_set(table, key, value)
+}
+
+pub fn get_field(state: &SyxState, table: TableRef, field: &str) -> SyxValue {
+    state.table(table).get(&SyxValue::from(field))
+}
+
+// The table a derived conversion reads, `to` naming the type converted to
+pub fn check_table(value: &SyxValue, to: &'static str) -> Result<TableRef> {
+    match *value {
+        SyxValue::Table(table) => Ok(table),
+        _ => conversion_error(value, to),
+    }
+}
+
+impl IntoSyx for SyxValue {
+    fn into_syx(self, _: &mut SyxState) -> Result<SyxValue> {
+        Ok(self)
+    }
+}
+
+impl FromSyx for SyxValue {
+    fn from_syx(value: SyxValue, _: &SyxState) -> Result<SyxValue> {
+        Ok(value)
+    }
+}
+
+impl IntoSyx for bool {
+    fn into_syx(self, _: &mut SyxState) -> Result<SyxValue> {
+        Ok(SyxValue::Bool(self))
+    }
+}
+
+// anything but nil and false is true, as in a condition
+impl FromSyx for bool {
+    fn from_syx(value: SyxValue, _: &SyxState) -> Result<bool> {
+        Ok(!value.is_falsy())
+    }
+}
+
+impl IntoSyx for TableRef {
+    fn into_syx(self, _: &mut SyxState) -> Result<SyxValue> {
+        Ok(SyxValue::Table(self))
+    }
+}
+
+impl FromSyx for TableRef {
+    fn from_syx(value: SyxValue, _: &SyxState) -> Result<TableRef> {
+        check_table(&value, "table")
+    }
+}
+
+// integers convert from floats with an exact integer value, and fail when
+// out of range of the type
+macro_rules! integer_conversions {
+    ($($t:ty)*) => {$(
+        impl IntoSyx for $t {
+            fn into_syx(self, _: &mut SyxState) -> Result<SyxValue> {
+                match SyxInteger::try_from(self) {
+                    Ok(i) => Ok(SyxValue::Integer(i)),
+                    Err(_) => Ok(SyxValue::Number(self as SyxNumber)),
+                }
+            }
+        }
+
+        impl FromSyx for $t {
+            fn from_syx(value: SyxValue, _: &SyxState) -> Result<$t> {
+                let integer = match value {
+                    SyxValue::Integer(i) => Some(i),
+                    SyxValue::Number(n) => float_to_integer(n),
+                    _ => return conversion_error(&value, stringify!($t)),
+                };
+                match integer.and_then(|i| <$t>::try_from(i).ok()) {
+                    Some(i) => Ok(i),
+                    None => Err(ErrorKind::FromSyxError("number", stringify!($t)).into()),
+                }
+            }
+        }
+    )*}
+}
+
+integer_conversions!(i8 i16 i32 i64 isize u8 u16 u32 u64 usize);
+
+macro_rules! float_conversions {
+    ($($t:ty)*) => {$(
+        impl IntoSyx for $t {
+            fn into_syx(self, _: &mut SyxState) -> Result<SyxValue> {
+                Ok(SyxValue::Number(self as SyxNumber))
+            }
+        }
+
+        impl FromSyx for $t {
+            fn from_syx(value: SyxValue, _: &SyxState) -> Result<$t> {
+                match value {
+                    SyxValue::Integer(i) => Ok(i as $t),
+                    SyxValue::Number(n) => Ok(n as $t),
+                    _ => conversion_error(&value, stringify!($t)),
+                }
+            }
+        }
+    )*}
+}
+
+float_conversions!(f32 f64);
+
+impl IntoSyx for SyxString {
+    fn into_syx(self, _: &mut SyxState) -> Result<SyxValue> {
+        Ok(SyxValue::String(self))
+    }
+}
+
+// numbers are converted as CONCAT does
+impl FromSyx for SyxString {
+    fn from_syx(value: SyxValue, _: &SyxState) -> Result<SyxString> {
+        match value {
+            SyxValue::String(s) => Ok(s),
+            SyxValue::Integer(_) | SyxValue::Number(_) => {
+                let mut buffer = Vec::new();
+                append_string(&mut buffer, &value);
+                Ok(SyxString::new(buffer))
+            }
+            _ => conversion_error(&value, "string"),
+        }
+    }
+}
+
+impl IntoSyx for &str {
+    fn into_syx(self, state: &mut SyxState) -> Result<SyxValue> {
+        Ok(SyxValue::String(state.intern(self.as_bytes())))
+    }
+}
+
+impl IntoSyx for String {
+    fn into_syx(self, state: &mut SyxState) -> Result<SyxValue> {
+        self.as_str().into_syx(state)
+    }
+}
+
+// fails for strings that are not UTF-8
+impl FromSyx for String {
+    fn from_syx(value: SyxValue, state: &SyxState) -> Result<String> {
+        let s = SyxString::from_syx(value, state)?;
+        match ::std::str::from_utf8(&s) {
+            Ok(s) => Ok(s.to_owned()),
+            Err(_) => Err(ErrorKind::FromSyxError("string", "String").into()),
+        }
+    }
+}
+
+impl<T: IntoSyx> IntoSyx for Option<T> {
+    fn into_syx(self, state: &mut SyxState) -> Result<SyxValue> {
+        match self {
+            Some(value) => value.into_syx(state),
+            None => Ok(SyxValue::Nil),
+        }
+    }
+}
+
+impl<T: FromSyx> FromSyx for Option<T> {
+    fn from_syx(value: SyxValue, state: &SyxState) -> Result<Option<T>> {
+        match value {
+            SyxValue::Nil => Ok(None),
+            value => T::from_syx(value, state).map(Some),
+        }
+    }
+}
+
+// a sequence
+impl<T: IntoSyx> IntoSyx for Vec<T> {
+    fn into_syx(self, state: &mut SyxState) -> Result<SyxValue> {
+        let table = state.new_table(self.len(), 0);
+        for (i, item) in self.into_iter().enumerate() {
+            let value = item.into_syx(state)?;
+            state.table_set(table, SyxValue::Integer(i as SyxInteger + 1), value)?;
+        }
+        Ok(SyxValue::Table(table))
+    }
+}
+
+// the items from 1 up to the length of a table
+impl<T: FromSyx> FromSyx for Vec<T> {
+    fn from_syx(value: SyxValue, state: &SyxState) -> Result<Vec<T>> {
+        let table = check_table(&value, "Vec")?;
+        let length = state.table(table).length();
+        (1..=length).map(|i| T::from_syx(state.table(table).get_int(i), state)).collect()
+    }
+}
+
+impl<K: IntoSyx, V: IntoSyx, S> IntoSyx for HashMap<K, V, S> {
+    fn into_syx(self, state: &mut SyxState) -> Result<SyxValue> {
+        let table = state.new_table(0, self.len());
+        for (key, value) in self {
+            let key = key.into_syx(state)?;
+            let value = value.into_syx(state)?;
+            state.table_set(table, key, value)?;
+        }
+        Ok(SyxValue::Table(table))
+    }
+}
+
+impl<K: FromSyx + Eq + Hash, V: FromSyx> FromSyx for HashMap<K, V> {
+    fn from_syx(value: SyxValue, state: &SyxState) -> Result<HashMap<K, V>> {
+        let table = check_table(&value, "HashMap")?;
+        let mut map = HashMap::new();
+        let mut key = SyxValue::Nil;
+        while let Some((next, value)) = state.table(table).next(&key)? {
+            map.insert(K::from_syx(next.clone(), state)?, V::from_syx(value, state)?);
+            key = next;
+        }
+        Ok(map)
+    }
+}
+
+impl IntoSyxMulti for MultiValue {
+    fn into_syx_multi(self, _: &mut SyxState) -> Result<MultiValue> {
+        Ok(self)
+    }
+}
+
+impl FromSyxMulti for MultiValue {
+    fn from_syx_multi(values: MultiValue, _: &SyxState) -> Result<MultiValue> {
+        Ok(values)
+    }
+}
+
+impl IntoSyxMulti for () {
+    fn into_syx_multi(self, _: &mut SyxState) -> Result<MultiValue> {
+        Ok(MultiValue::new())
+    }
+}
+
+impl FromSyxMulti for () {
+    fn from_syx_multi(_: MultiValue, _: &SyxState) -> Result<()> {
+        Ok(())
+    }
+}
+
+// A failed conversion of item `n` (counting from 1) is reported as an
+// ArgumentError
+macro_rules! tuple_conversions {
+    ($(($($name:ident $n:tt),+))*) => {$(
+        impl<$($name: IntoSyx),+> IntoSyxMulti for ($($name,)+) {
+            fn into_syx_multi(self, state: &mut SyxState) -> Result<MultiValue> {
+                Ok(vec![$(self.$n.into_syx(state)?),+].into())
+            }
+        }
+
+        impl<$($name: FromSyx),+> FromSyxMulti for ($($name,)+) {
+            fn from_syx_multi(values: MultiValue, state: &SyxState) -> Result<($($name,)+)> {
+                let mut values = values.into_iter();
+                Ok(($({
+                    let value = values.next().unwrap_or(SyxValue::Nil);
+                    $name::from_syx(value, state).map_err(|error| match *error.kind() {
+                        ErrorKind::FromSyxError(from, to) => {
+                            Error::from(ErrorKind::ArgumentError($n + 1, from, to))
+                        }
+                        _ => error,
+                    })?
+                },)+))
+            }
+        }
+    )*}
+}
+
+tuple_conversions! {
+    (A 0)
+    (A 0, B 1)
+    (A 0, B 1, C 2)
+    (A 0, B 1, C 2, D 3)
+    (A 0, B 1, C 2, D 3, E 4)
+    (A 0, B 1, C 2, D 3, E 4, F 5)
+    (A 0, B 1, C 2, D 3, E 4, F 5, G 6)
+    (A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7)
+}
+
+impl Args {
+    // The arguments converted to T, usually a tuple. An argument that does
+    // not convert is reported as a bad argument.
+    pub fn unpack<T: FromSyxMulti>(self, state: &SyxState) -> Result<T> {
+        let name = self.name().to_owned();
+        match T::from_syx_multi(self.into_values().into(), state) {
+            Err(error) => match *error.kind() {
+                ErrorKind::ArgumentError(n, from, to) => {
+                    bad_argument(n, &name, &format!("{} expected, got {}", to, from))
+                }
+                _ => Err(error),
+            },
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use super::super::compiler::parse;
+    use super::super::stdlib;
+
+    #[derive(Debug, PartialEq, IntoSyx, FromSyx)]
+    struct Point {
+        x: f64,
+        y: f64,
+        label: Option<String>,
+    }
+
+    #[derive(Debug, PartialEq, IntoSyx, FromSyx)]
+    struct Pair(i32, String);
+
+    fn roundtrip<T: IntoSyx + FromSyx>(state: &mut SyxState, value: T) -> T {
+        let value = value.into_syx(state).unwrap();
+        T::from_syx(value, state).unwrap()
+    }
+
+    #[test]
+    fn test_conversions() {
+        let mut state = SyxState::new();
+        assert_eq!(roundtrip(&mut state, 42u8), 42);
+        assert_eq!(roundtrip(&mut state, -1.5f64), -1.5);
+        assert_eq!(roundtrip(&mut state, "text".to_owned()), "text");
+        assert_eq!(roundtrip(&mut state, Some(vec![1i64, 2, 3])), Some(vec![1, 2, 3]));
+        assert_eq!(roundtrip::<Option<bool>>(&mut state, None), None);
+        let map: HashMap<String, i32> = [("a".to_owned(), 1), ("b".to_owned(), 2)]
+            .iter().cloned().collect();
+        assert_eq!(roundtrip(&mut state, map.clone()), map);
+        assert_eq!(i64::from_syx(SyxValue::Number(3.0), &state).unwrap(), 3);
+        assert!(u8::from_syx(SyxValue::Integer(256), &state).is_err());
+        assert!(i32::from_syx(SyxValue::Number(1.5), &state).is_err());
+        assert_eq!(String::from_syx(SyxValue::Integer(7), &state).unwrap(), "7");
+        let error = f64::from_syx(SyxValue::from("x"), &state).err().unwrap();
+        assert_eq!(error.to_string(), "f64 expected, got string");
+    }
+
+    #[test]
+    fn test_derive() {
+        let mut state = SyxState::new();
+        let point = Point { x: 1.0, y: -2.0, label: None };
+        assert_eq!(roundtrip(&mut state, point), Point { x: 1.0, y: -2.0, label: None });
+        let pair = Pair(3, "three".to_owned());
+        assert_eq!(roundtrip(&mut state, pair), Pair(3, "three".to_owned()));
+        let error = Point::from_syx(SyxValue::Integer(1), &state).err().unwrap();
+        assert_eq!(error.to_string(), "Point expected, got number");
+    }
+
+    #[test]
+    fn test_multi_values() {
+        let mut state = SyxState::new();
+        stdlib::open_libs(&mut state);
+        state.register("scale", |state, args| {
+            let (point, factor): (Point, Option<f64>) = args.unpack(state)?;
+            let factor = factor.unwrap_or(2.0);
+            let point = Point { x: point.x * factor, y: point.y * factor, ..point };
+            (point.x + point.y, point).into_syx_multi(state)
+        });
+        let proto = parse(b"local sum, p = scale({x = 1, y = 2, label = 'p'}, 3) \
+                            return sum, p.x, p.label, pcall(scale, {x = 1}, 'big')", "=test")
+            .unwrap();
+        let results = state.call(Arc::new(proto), vec![]).unwrap();
+        let (sum, x, label, ok, message): (f64, f64, String, bool, String) =
+            FromSyxMulti::from_syx_multi(results, &state).unwrap();
+        assert_eq!((sum, x, label.as_str(), ok), (9.0, 3.0, "p", false));
+        assert_eq!(message, "bad argument #1 to 'scale' (f64 expected, got nil)");
+    }
+}
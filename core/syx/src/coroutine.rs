@@ -0,0 +1,617 @@
+// Coroutines
+//
+// Every coroutine has its own stack, frames and open upvalues. Only those of
+// the running thread are in SyxState itself: resuming a coroutine swaps them
+// with the ones kept in its SyxThread, so while it runs that object holds the
+// resumer's instead, and yielding or returning swaps them back. The main
+// thread has no object of its own and is `None` wherever a thread is named.
+// Scripts still get a thread for it from `coroutine.running`: an empty one
+// standing in for it, made the first time it is asked for, whose status is
+// that of the main thread and which can not be resumed.
+//
+// A coroutine yields by calling a native function that leaves the values in
+// `SyxState.yielded`, after which the interpreter loop saves every frame and
+// returns to `resume`. Lua functions called from Rust (metamethods, natives
+// calling functions) can not be suspended that way, so yielding while one is
+// running is an error.
+//
+// When Lua code calls `coroutine.resume` itself, the interpreter loop does
+// the switch instead of the native: it swaps in the coroutine's stack and
+// carries on running its frames, and a yield, return or error in there swaps
+// the resumer's back and completes its call. Chains of coroutines resuming
+// each other then take no Rust stack, as do Lua calls, see vm.rs. Functions
+// made by `coroutine.wrap` are native closures, and resume through Rust.
+//
+// Consult the versioned ldo.c and lcorolib.c for more information.
+
+use std::mem;
+
+use super::conf::SYX_MAXCCALLS;
+use super::errors::*;
+use super::object::{MultiValue, SyxValue, ThreadRef, UpvalRef};
+use super::state::{CallInfo, SyxState};
+use super::stdlib::new_lib;
+use super::vm::{bad_argument, runtime_error};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CoStatus {
+    Suspended, // not started yet, or yielded
+    Running,
+    Normal,    // resumed another coroutine
+    Dead,      // returned or raised an error
+}
+
+impl CoStatus {
+    // name used by `coroutine.status`
+    pub fn name(self) -> &'static str {
+        match self {
+            CoStatus::Suspended => "suspended",
+            CoStatus::Running => "running",
+            CoStatus::Normal => "normal",
+            CoStatus::Dead => "dead",
+        }
+    }
+}
+
+// How a resumed coroutine gave control back
+#[derive(Debug, PartialEq)]
+pub enum Resumed {
+    Yield(MultiValue),
+    Return(MultiValue),
+}
+
+#[derive(Default)]
+pub struct SyxThread {
+    pub(crate) stack: Vec<SyxValue>,
+    pub(crate) frames: Vec<CallInfo>,
+    pub(crate) open_upvalues: Vec<UpvalRef>,
+    pub(crate) nny: usize,
+    pub(crate) body: Option<SyxValue>, // function to call on the first resume
+    pub(crate) dead: bool,
+}
+
+impl SyxState {
+    // New suspended coroutine that runs `body` when first resumed
+    pub fn new_thread(&mut self, body: SyxValue) -> ThreadRef {
+        self.alloc_thread(SyxThread {
+            body: Some(body),
+            ..SyxThread::default()
+        })
+    }
+
+    // running coroutine, None for the main thread
+    pub fn running(&self) -> Option<ThreadRef> {
+        self.current
+    }
+
+    // the thread standing in for the main one
+    pub(crate) fn main_thread(&mut self) -> ThreadRef {
+        match self.main_thread {
+            Some(thread) => thread,
+            None => {
+                let thread = self.alloc_thread(SyxThread::default());
+                self.main_thread = Some(thread);
+                thread
+            }
+        }
+    }
+
+    // whether the running coroutine can yield, false for the main thread
+    pub fn is_yieldable(&self) -> bool {
+        self.current.is_some() && self.nny == 0
+    }
+
+    pub fn thread_status(&self, thread: ThreadRef) -> CoStatus {
+        if self.main_thread == Some(thread) {
+            if self.current.is_none() { CoStatus::Running } else { CoStatus::Normal }
+        } else if self.current == Some(thread) {
+            CoStatus::Running
+        } else if self.resumers.contains(&Some(thread)) {
+            CoStatus::Normal
+        } else if self.heap.threads.get(thread.0).dead {
+            CoStatus::Dead
+        } else {
+            CoStatus::Suspended
+        }
+    }
+
+    // Run `thread` until it yields or returns. The arguments are passed to
+    // the body on the first resume and returned by the yield after that. An
+    // error kills the coroutine and is returned as is.
+    pub fn resume(&mut self, thread: ThreadRef, args: Vec<SyxValue>) -> Result<Resumed> {
+        self.check_resumable(thread)?;
+        if self.ccalls >= SYX_MAXCCALLS {
+            return runtime_error("C stack overflow".to_owned());
+        }
+        self.switch_to(thread);
+        self.ccalls += 1;
+        let result = self.run_thread(thread, args);
+        self.ccalls -= 1;
+        self.switch_back(matches!(result, Ok(Resumed::Yield(_))));
+        result
+    }
+
+    pub(crate) fn check_resumable(&self, thread: ThreadRef) -> Result<()> {
+        match self.thread_status(thread) {
+            CoStatus::Suspended => Ok(()),
+            CoStatus::Dead => runtime_error("cannot resume dead coroutine".to_owned()),
+            _ => runtime_error("cannot resume non-suspended coroutine".to_owned()),
+        }
+    }
+
+    // Make `thread` the running one, keeping the resumer's stack in it
+    pub(crate) fn switch_to(&mut self, thread: ThreadRef) {
+        self.swap_thread(thread);
+        let resumer = self.current.replace(thread);
+        self.resumers.push(resumer);
+    }
+
+    // Give the stack back to the resumer of the running coroutine, which is
+    // dead unless it `yielded`
+    pub(crate) fn switch_back(&mut self, yielded: bool) {
+        let thread = self.current.expect("running coroutine");
+        if !yielded {
+            // keep the frames of a failed coroutine around for tracebacks,
+            // but let its closures outlive the stack
+            self.close_upvalues(0);
+            self.heap.threads.get_mut(thread.0).dead = true;
+        }
+        self.current = self.resumers.pop().expect("resumer of a running coroutine");
+        self.swap_thread(thread);
+    }
+
+    fn run_thread(&mut self, thread: ThreadRef, args: Vec<SyxValue>) -> Result<Resumed> {
+        if let Some(resumed) = self.start_thread(thread, args)? {
+            return Ok(resumed);
+        }
+        let values = self.execute(0)?;
+        Ok(if self.frames.is_empty() {
+            Resumed::Return(values)
+        } else {
+            Resumed::Yield(values)
+        })
+    }
+
+    // Get the coroutine just switched to going: None once its innermost Lua
+    // frame is ready to carry on, or how it gave control back if it has none
+    pub(crate) fn start_thread(&mut self, thread: ThreadRef, mut args: Vec<SyxValue>)
+        -> Result<Option<Resumed>>
+    {
+        let body = self.heap.threads.get_mut(thread.0).body.take();
+        if let Some(body) = body {
+            match self.resolve_call(body, &mut args)? {
+                SyxValue::Function(closure) => self.enter(closure, 0, args)?,
+                native => {
+                    let results = self.run_native(native, args)?;
+                    return Ok(Some(match self.yielded.take() {
+                        Some(values) => Resumed::Yield(values.into()),
+                        None => Resumed::Return(results),
+                    }));
+                }
+            }
+        } else if self.frames.is_empty() {
+            // a native body yielded, what it is resumed with is returned
+            return Ok(Some(Resumed::Return(args.into())));
+        } else {
+            self.finish_call(args.into());
+        }
+        Ok(None)
+    }
+
+    // coroutine.resume(co, ...) called by the interpreter loop, which counts
+    // in `resumes` the coroutines it is running for Lua resumers. It carries
+    // on in the coroutine, unless that gave control back straight away.
+    pub(crate) fn resume_from_loop(&mut self, mut args: Vec<SyxValue>, resumes: &mut usize)
+        -> Result<()>
+    {
+        let thread = check_thread(&args, "resume")?;
+        args.remove(0);
+        if let Err(error) = self.check_resumable(thread) {
+            let value = self.error_value(&error);
+            return self.finish_resume(false, vec![value].into());
+        }
+        self.switch_to(thread);
+        match self.start_thread(thread, args) {
+            Ok(None) => {
+                *resumes += 1;
+                Ok(())
+            }
+            Ok(Some(Resumed::Yield(values))) => {
+                self.switch_back(true);
+                self.finish_resume(true, values)
+            }
+            Ok(Some(Resumed::Return(values))) => {
+                self.switch_back(false);
+                self.finish_resume(true, values)
+            }
+            Err(error) => {
+                let value = self.error_value(&error);
+                self.switch_back(false);
+                self.finish_resume(false, vec![value].into())
+            }
+        }
+    }
+
+    // Return `values` from the coroutine.resume call the frame switched back
+    // to is in, after `ok` as the native does
+    pub(crate) fn finish_resume(&mut self, ok: bool, values: MultiValue) -> Result<()> {
+        let mut results = MultiValue::new();
+        results.push(SyxValue::Bool(ok));
+        results.extend(values);
+        self.finish_call(results);
+        self.hook_return()
+    }
+
+    // Kill a suspended coroutine, closing the upvalues of its stack
+    pub(crate) fn kill_thread(&mut self, thread: ThreadRef) {
+        self.swap_thread(thread);
+        self.close_upvalues(0);
+        self.swap_thread(thread);
+        self.heap.threads.get_mut(thread.0).dead = true;
+    }
+
+    // exchange the running thread's stack with the one kept in `thread`
+    fn swap_thread(&mut self, thread: ThreadRef) {
+        self.thread_barrier(thread);
+        let saved = self.heap.threads.get_mut(thread.0);
+        mem::swap(&mut self.stack, &mut saved.stack);
+        mem::swap(&mut self.frames, &mut saved.frames);
+        mem::swap(&mut self.open_upvalues, &mut saved.open_upvalues);
+        mem::swap(&mut self.nny, &mut saved.nny);
+    }
+
+    // Coroutine object holding the stack of `thread` if it is not running.
+    // A thread waiting on a resume left its stack in the coroutine it
+    // resumed.
+    fn stack_holder(&self, thread: Option<ThreadRef>) -> Option<ThreadRef> {
+        if thread == self.current {
+            return None;
+        }
+        match self.resumers.iter().position(|&t| t == thread) {
+            Some(i) => self.resumers.get(i + 1).cloned().unwrap_or(self.current),
+            None => thread,
+        }
+    }
+
+    pub(crate) fn thread_stack(&self, thread: Option<ThreadRef>) -> &Vec<SyxValue> {
+        match self.stack_holder(thread) {
+            Some(holder) => &self.heap.threads.get(holder.0).stack,
+            None => &self.stack,
+        }
+    }
+
+    pub(crate) fn thread_stack_mut(&mut self, thread: Option<ThreadRef>) -> &mut Vec<SyxValue> {
+        match self.stack_holder(thread) {
+            Some(holder) => {
+                self.thread_barrier(holder);
+                &mut self.heap.threads.get_mut(holder.0).stack
+            }
+            None => &mut self.stack,
+        }
+    }
+}
+
+pub fn open(state: &mut SyxState) {
+    new_lib(state, "coroutine", &[
+        ("create", cocreate),
+        ("isyieldable", coisyieldable),
+        ("resume", coresume),
+        ("running", corunning),
+        ("status", costatus),
+        ("wrap", cowrap),
+        ("yield", coyield),
+    ]);
+}
+
+pub(crate) fn check_thread(args: &[SyxValue], name: &str) -> Result<ThreadRef> {
+    match args.first() {
+        Some(&SyxValue::Thread(thread)) => Ok(thread),
+        _ => bad_argument(1, name, "coroutine expected"),
+    }
+}
+
+// coroutine.create(f)
+pub fn cocreate(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    match args.into_iter().next() {
+        Some(body) if body.is_function() => {
+            Ok(vec![SyxValue::Thread(state.new_thread(body))])
+        }
+        _ => bad_argument(1, "create", "function expected"),
+    }
+}
+
+// coroutine.resume(co, ...), when called from Rust rather than by Lua code
+pub fn coresume(state: &mut SyxState, mut args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let thread = check_thread(&args, "resume")?;
+    args.remove(0);
+    match state.resume(thread, args) {
+        Ok(Resumed::Yield(values)) | Ok(Resumed::Return(values)) => {
+            let mut results = vec![SyxValue::Bool(true)];
+            results.extend(values);
+            Ok(results)
+        }
+        Err(error) => Ok(vec![SyxValue::Bool(false), state.error_value(&error)]),
+    }
+}
+
+// coroutine.yield(...)
+pub fn coyield(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    if state.current.is_none() {
+        return runtime_error("attempt to yield from outside a coroutine".to_owned());
+    }
+    if state.nny > 0 {
+        return runtime_error("attempt to yield across a C-call boundary".to_owned());
+    }
+    state.yielded = Some(args);
+    Ok(vec![])
+}
+
+// coroutine.status(co)
+pub fn costatus(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let thread = check_thread(&args, "status")?;
+    let name = state.thread_status(thread).name();
+    Ok(vec![SyxValue::String(state.intern(name.as_bytes()))])
+}
+
+// coroutine.wrap(f), a function resuming a new coroutine running `f` with
+// its arguments and returning what it yields, raising its errors instead
+pub fn cowrap(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let thread = match args.into_iter().next() {
+        Some(body) if body.is_function() => state.new_thread(body),
+        _ => return bad_argument(1, "wrap", "function expected"),
+    };
+    let upvalues = vec![SyxValue::Thread(thread)];
+    let function = state.create_closure("wrap", upvalues, move |state, args| {
+        match state.resume(thread, args.into_values())? {
+            Resumed::Yield(values) | Resumed::Return(values) => Ok(values),
+        }
+    });
+    Ok(vec![function])
+}
+
+// coroutine.isyieldable()
+pub fn coisyieldable(state: &mut SyxState, _: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    Ok(vec![SyxValue::Bool(state.is_yieldable())])
+}
+
+// coroutine.running(), the running coroutine and whether it is the main
+// thread, for which it is the thread standing in for it
+pub fn corunning(state: &mut SyxState, _: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    Ok(match state.running() {
+        Some(thread) => vec![SyxValue::Thread(thread), SyxValue::Bool(false)],
+        None => vec![SyxValue::Thread(state.main_thread()), SyxValue::Bool(true)],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use super::super::object::{Proto, Upvalue};
+    use super::super::opcodes::OpCode;
+    use super::super::testing::{abc, abx};
+
+    fn function(state: &mut SyxState, proto: Proto) -> SyxValue {
+        SyxValue::Function(state.new_closure(Arc::new(proto), vec![]))
+    }
+
+    #[test]
+    fn test_resume_and_yield() {
+        // function(a) local b = coroutine.yield(a + 1); return b * 2 end
+        let mut proto = Proto::new();
+        proto.numparams = 1;
+        proto.maxstacksize = 4;
+        proto.constants = vec![
+            SyxValue::Native(coyield), SyxValue::Integer(1), SyxValue::Integer(2),
+        ];
+        proto.instructions = vec![
+            abx(OpCode::LoadK, 1, 0),
+            abc(OpCode::Add, 2, 0, 257),
+            abc(OpCode::Call, 1, 2, 2),
+            abc(OpCode::Mul, 1, 1, 258),
+            abc(OpCode::Return, 1, 2, 0),
+        ];
+        let mut state = SyxState::new();
+        let body = function(&mut state, proto);
+        let thread = state.new_thread(body);
+        assert_eq!(state.thread_status(thread), CoStatus::Suspended);
+        assert_eq!(state.resume(thread, vec![SyxValue::Integer(10)]).unwrap(),
+                   Resumed::Yield(vec![SyxValue::Integer(11)].into()));
+        assert_eq!(state.thread_status(thread), CoStatus::Suspended);
+        assert_eq!(state.resume(thread, vec![SyxValue::Integer(5)]).unwrap(),
+                   Resumed::Return(vec![SyxValue::Integer(10)].into()));
+        assert_eq!(state.thread_status(thread), CoStatus::Dead);
+        let error = state.resume(thread, vec![]).unwrap_err();
+        assert_eq!(error.to_string(), "cannot resume dead coroutine");
+        assert!(state.running().is_none());
+    }
+
+    #[test]
+    fn test_upvalues_across_threads() {
+        // local x = 1; local get = function() return x end
+        // coroutine.yield(get); x = 2; coroutine.yield()
+        let mut getter = Proto::new();
+        getter.maxstacksize = 1;
+        getter.upvalues = vec![Upvalue { name: "x".into(), instack: 1, idx: 0 }];
+        getter.instructions = vec![
+            abc(OpCode::GetUpval, 0, 0, 0),
+            abc(OpCode::Return, 0, 2, 0),
+        ];
+        let mut proto = Proto::new();
+        proto.maxstacksize = 4;
+        proto.protos = vec![Arc::new(getter)];
+        proto.constants = vec![
+            SyxValue::Integer(1), SyxValue::Native(coyield), SyxValue::Integer(2),
+        ];
+        proto.instructions = vec![
+            abx(OpCode::LoadK, 0, 0),
+            abx(OpCode::Closure, 1, 0),
+            abx(OpCode::LoadK, 2, 1),
+            abc(OpCode::Move, 3, 1, 0),
+            abc(OpCode::Call, 2, 2, 1),
+            abx(OpCode::LoadK, 0, 2),
+            abx(OpCode::LoadK, 2, 1),
+            abc(OpCode::Call, 2, 1, 1),
+            abc(OpCode::Return, 0, 1, 0),
+        ];
+        let mut state = SyxState::new();
+        let body = function(&mut state, proto);
+        let thread = state.new_thread(body);
+        state.stack.push(SyxValue::Thread(thread));
+
+        let get = match state.resume(thread, vec![]).unwrap() {
+            Resumed::Yield(values) => values.into_first(),
+            other => panic!("unexpected {:?}", other),
+        };
+        state.stack.push(get.clone());
+        state.collect_garbage();
+        assert_eq!(state.call_value(get.clone(), vec![]).unwrap(), vec![SyxValue::Integer(1)]);
+        assert_eq!(state.resume(thread, vec![]).unwrap(), Resumed::Yield(MultiValue::new()));
+        assert_eq!(state.call_value(get.clone(), vec![]).unwrap(), vec![SyxValue::Integer(2)]);
+        assert_eq!(state.resume(thread, vec![]).unwrap(), Resumed::Return(MultiValue::new()));
+        state.collect_garbage();
+        assert_eq!(state.call_value(get, vec![]).unwrap(), vec![SyxValue::Integer(2)]);
+    }
+
+    #[test]
+    fn test_library() {
+        let mut state = SyxState::new();
+        let error = coyield(&mut state, vec![]).unwrap_err();
+        assert_eq!(error.to_string(), "attempt to yield from outside a coroutine");
+
+        // the coroutine yields back into the one that resumed it
+        let inner = cocreate(&mut state, vec![SyxValue::Native(coyield)]).unwrap().remove(0);
+        let results = coresume(&mut state, vec![inner.clone(), SyxValue::Integer(1)]).unwrap();
+        assert_eq!(results, vec![SyxValue::Bool(true), SyxValue::Integer(1)]);
+        let results = costatus(&mut state, vec![inner.clone()]).unwrap();
+        assert_eq!(results, vec![SyxValue::from("suspended")]);
+        let results = coresume(&mut state, vec![inner.clone(), SyxValue::Integer(2)]).unwrap();
+        assert_eq!(results, vec![SyxValue::Bool(true), SyxValue::Integer(2)]);
+        let results = coresume(&mut state, vec![inner]).unwrap();
+        assert_eq!(results, vec![
+            SyxValue::Bool(false), SyxValue::from("cannot resume dead coroutine"),
+        ]);
+
+        // errors end up in resume, and kill the coroutine
+        let mut proto = Proto::new();
+        proto.maxstacksize = 2;
+        proto.instructions = vec![abc(OpCode::Call, 0, 1, 1)];
+        let body = function(&mut state, proto);
+        let thread = cocreate(&mut state, vec![body]).unwrap().remove(0);
+        let results = coresume(&mut state, vec![thread.clone()]).unwrap();
+        assert_eq!(results, vec![
+            SyxValue::Bool(false), SyxValue::from("attempt to call a nil value"),
+        ]);
+        let results = costatus(&mut state, vec![thread]).unwrap();
+        assert_eq!(results, vec![SyxValue::from("dead")]);
+    }
+
+    #[test]
+    fn test_resume_in_loop() {
+        let mut state = SyxState::new();
+        super::super::stdlib::open_libs(&mut state);
+        let mut run = |source: &str| {
+            let proto = super::super::compiler::parse(source.as_bytes(), "=test").unwrap();
+            state.call(Arc::new(proto), vec![]).unwrap().into_vec()
+        };
+
+        // far more coroutines resuming each other than Rust could recurse
+        let results = run("
+            local function nest(n)
+                if n == 0 then return coroutine.yield('bottom') end
+                local co = coroutine.create(nest)
+                local ok, value = coroutine.resume(co, n - 1)
+                return value, coroutine.status(co)
+            end
+            local co = coroutine.create(nest)
+            return coroutine.resume(co, 10000)
+        ");
+        assert_eq!(results, vec![
+            SyxValue::Bool(true), SyxValue::from("bottom"), SyxValue::from("dead"),
+        ]);
+
+        let results = run("
+            local gen = coroutine.create(function(a)
+                local b = coroutine.yield(a + 1)
+                local c, d = coroutine.yield(b * 2)
+                return c + d
+            end)
+            local _, x = coroutine.resume(gen, 1)
+            local _, y = coroutine.resume(gen, 10)
+            local _, z = coroutine.resume(gen, 3, 4)
+            local failed = coroutine.create(function() local t = nil; return t.x end)
+            local ok, message = coroutine.resume(gen)
+            local ok2, message2 = coroutine.resume(failed)
+            return x, y, z, coroutine.status(gen), ok, message, ok2, message2,
+                coroutine.status(failed)
+        ");
+        assert_eq!(results, vec![
+            SyxValue::Integer(2), SyxValue::Integer(20), SyxValue::Integer(7),
+            SyxValue::from("dead"),
+            SyxValue::Bool(false), SyxValue::from("cannot resume dead coroutine"),
+            SyxValue::Bool(false), SyxValue::from("test:10: attempt to index a nil value"),
+            SyxValue::from("dead"),
+        ]);
+    }
+
+    #[test]
+    fn test_wrap_and_running() {
+        let mut state = SyxState::new();
+        super::super::stdlib::open_libs(&mut state);
+        let run = |state: &mut SyxState, source: &str| {
+            let proto = super::super::compiler::parse(source.as_bytes(), "=test").unwrap();
+            state.call(Arc::new(proto), vec![]).map(MultiValue::into_vec)
+        };
+
+        let results = run(&mut state, "
+            local main, ismain = coroutine.running()
+            local co, inner
+            co = coroutine.create(function()
+                local running, ismain = coroutine.running()
+                inner = {running == co, ismain, coroutine.isyieldable(),
+                         coroutine.status(main)}
+            end)
+            coroutine.resume(co)
+            return type(main), ismain, coroutine.isyieldable(), inner[1], inner[2], inner[3],
+                   coroutine.status(main), inner[4], main == coroutine.running(),
+                   coroutine.resume(main)
+        ").unwrap();
+        assert_eq!(results, vec![
+            SyxValue::from("thread"), SyxValue::Bool(true), SyxValue::Bool(false),
+            SyxValue::Bool(true), SyxValue::Bool(false), SyxValue::Bool(true),
+            // the main thread, which can not be resumed
+            SyxValue::from("running"), SyxValue::from("normal"), SyxValue::Bool(true),
+            SyxValue::Bool(false), SyxValue::from("cannot resume non-suspended coroutine"),
+        ]);
+
+        // the function keeps its coroutine alive
+        let counter = run(&mut state, "
+            return coroutine.wrap(function(step)
+                local n = 0
+                while true do n = n + step; step = coroutine.yield(n) end
+            end)
+        ").unwrap().remove(0);
+        state.push_value(counter.clone());
+        state.collect_garbage();
+        let results = state.call_value(counter.clone(), vec![SyxValue::Integer(2)]).unwrap();
+        assert!(results == vec![SyxValue::Integer(2)]);
+        let results = state.call_value(counter, vec![SyxValue::Integer(3)]).unwrap();
+        assert!(results == vec![SyxValue::Integer(5)]);
+
+        // errors are raised in the caller, after which the coroutine is dead
+        let results = run(&mut state, "
+            local f = coroutine.wrap(function() local t = nil; return t.x end)
+            local ok, message = pcall(f)
+            local ok2, message2 = pcall(f)
+            return ok, message, ok2, message2
+        ").unwrap();
+        assert_eq!(results, vec![
+            SyxValue::Bool(false), SyxValue::from("test:2: attempt to index a nil value"),
+            SyxValue::Bool(false), SyxValue::from("cannot resume dead coroutine"),
+        ]);
+        let error = run(&mut state, "coroutine.wrap(1)").unwrap_err();
+        assert_eq!(error.to_string(),
+                   "test:1: bad argument #1 to 'wrap' (function expected)");
+    }
+}
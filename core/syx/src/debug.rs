@@ -0,0 +1,370 @@
+// Debug information
+//
+// Source positions and names for error messages and tracebacks, worked out
+// from the debug tables of a Proto: `lineinfo` maps instructions to lines,
+// `columninfo` (when compiled with columns) to columns, `locvars` names
+// registers, and upvalues carry their own names. Stripped chunks have none of
+// these, and their frames are reported without them.
+//
+// Consult the versioned ldebug.c for more information.
+
+use std::borrow::Cow;
+
+use super::object::{LocVar, Proto, SyxInteger, SyxValue};
+use super::opcodes::{Instruction, OpCode};
+use super::state::{CallInfo, SyxState};
+
+const BITRK: u16 = 1 << 8; // see vm.rs
+const IDSIZE: usize = 60; // longest source name shown, counting a terminator as in C
+
+// Source name as printed in messages, as luaO_chunkid makes it: "=literal"
+// names without the "=", cut at the end if too long, "@file" names without
+// the "@", cut at the start, and chunks named after their source text, as
+// `load` names strings, as much of its first line as fits in [string "..."].
+pub fn short_source(source: &str) -> Cow<'_, str> {
+    if let Some(literal) = source.strip_prefix('=') {
+        if literal.len() < IDSIZE {
+            return Cow::Borrowed(literal);
+        }
+        return Cow::Borrowed(&literal[..floor_boundary(literal, IDSIZE - 1)]);
+    }
+    if let Some(file) = source.strip_prefix('@') {
+        if file.len() < IDSIZE {
+            return Cow::Borrowed(file);
+        }
+        let mut start = file.len() - (IDSIZE - 1 - "...".len());
+        while !file.is_char_boundary(start) {
+            start += 1;
+        }
+        return Cow::Owned(format!("...{}", &file[start..]));
+    }
+    let available = IDSIZE - "[string \"...\"]".len() - 1;
+    let line = source.split('\n').next().unwrap_or("");
+    if line.len() == source.len() && source.len() < available {
+        return Cow::Owned(format!("[string \"{}\"]", source));
+    }
+    let end = floor_boundary(line, available);
+    Cow::Owned(format!("[string \"{}...\"]", &line[..end]))
+}
+
+// `at`, or the character boundary of `text` before it
+fn floor_boundary(text: &str, at: usize) -> usize {
+    let mut at = at.min(text.len());
+    while !text.is_char_boundary(at) {
+        at -= 1;
+    }
+    at
+}
+
+// line of the instruction before `pc`, the one a frame is running
+pub(crate) fn current_line(proto: &Proto, pc: usize) -> Option<i32> {
+    if pc == 0 {
+        return None;
+    }
+    proto.lineinfo.get(pc - 1).cloned()
+}
+
+// column of the instruction before `pc`, for functions compiled with them
+pub(crate) fn current_column(proto: &Proto, pc: usize) -> Option<i32> {
+    if pc == 0 {
+        return None;
+    }
+    proto.columninfo.get(pc - 1).cloned()
+}
+
+// name of the local in `register` at instruction `pc`, if it is one
+pub(crate) fn local_name(proto: &Proto, register: usize, pc: usize) -> Option<String> {
+    proto.local_at(pc, register).map(|locvar| locvar.varname.to_string())
+}
+
+impl Proto {
+    // The local variable held in register `index` while instruction `pc`
+    // runs. Locals are in scope from their startpc up to, not including,
+    // their endpc, and take registers in the order they come in `locvars`,
+    // so the one in `index` is the index-th of those in scope at `pc`.
+    pub fn local_at(&self, pc: usize, index: usize) -> Option<&LocVar> {
+        let mut index = index;
+        for locvar in &self.locvars {
+            if locvar.startpc as usize > pc {
+                break;
+            }
+            if pc < locvar.endpc as usize {
+                if index == 0 {
+                    return Some(locvar);
+                }
+                index -= 1;
+            }
+        }
+        None
+    }
+}
+
+fn upvalue_name(proto: &Proto, index: usize) -> String {
+    match proto.upvalues.get(index) {
+        Some(upvalue) if !upvalue.name.is_empty() => upvalue.name.to_string(),
+        _ => "?".to_owned(),
+    }
+}
+
+// name of the key in RK(`rk`), when it is a constant string
+fn constant_name(proto: &Proto, rk: u16) -> String {
+    if rk & BITRK != 0 {
+        if let Some(SyxValue::String(name)) = proto.constants.get((rk & !BITRK) as usize) {
+            return name.to_string();
+        }
+    }
+    "?".to_owned()
+}
+
+// instructions that store into R(A)
+fn sets_register_a(op: OpCode) -> bool {
+    !matches!(op, OpCode::SetTabUp | OpCode::SetUpval | OpCode::SetTable |
+                  OpCode::SetList | OpCode::Eq | OpCode::Lt | OpCode::Le |
+                  OpCode::Test | OpCode::Jmp | OpCode::Return |
+                  OpCode::TForCall | OpCode::ExtraArg | OpCode::Check)
+}
+
+// Last instruction before `lastpc` that changed `register`, or None if that
+// depends on a jump taken in between
+fn find_set_register(proto: &Proto, lastpc: usize, register: usize) -> Option<usize> {
+    let mut setter = None;
+    let mut jump_target = 0; // any code before this is conditional
+    for (pc, instruction) in proto.instructions.iter().enumerate().take(lastpc) {
+        let changes = match *instruction {
+            Instruction::ABC { instruction: OpCode::LoadNil, a, b, .. } => {
+                let a = a as usize;
+                a <= register && register <= a + b as usize
+            }
+            Instruction::ABC { instruction: OpCode::TForCall, a, .. } => {
+                register >= a as usize + 2
+            }
+            Instruction::ABC { instruction: OpCode::Call, a, .. } |
+            Instruction::ABC { instruction: OpCode::TailCall, a, .. } => register >= a as usize,
+            Instruction::AsBx { instruction: OpCode::Jmp, sbx, .. } => {
+                let target = (pc as i64 + 1 + sbx as i64) as usize;
+                if pc < target && target <= lastpc && target > jump_target {
+                    jump_target = target;
+                }
+                false
+            }
+            Instruction::ABC { instruction: op, a, .. } |
+            Instruction::ABx { instruction: op, a, .. } |
+            Instruction::AsBx { instruction: op, a, .. } => {
+                sets_register_a(op) && a as usize == register
+            }
+            Instruction::Ax { .. } => false,
+        };
+        if changes {
+            setter = if pc < jump_target { None } else { Some(pc) };
+        }
+    }
+    setter
+}
+
+// What the value in `register` at `pc` is called in the source, as a kind
+// ("local", "global", "field", ...) and a name
+fn object_name(proto: &Proto, pc: usize, register: usize) -> Option<(&'static str, String)> {
+    if let Some(name) = local_name(proto, register, pc) {
+        return Some(("local", name));
+    }
+    let setter = find_set_register(proto, pc, register)?;
+    match proto.instructions[setter] {
+        Instruction::ABC { instruction: OpCode::Move, a, b, .. } if b < a as u16 => {
+            object_name(proto, setter, b as usize)
+        }
+        Instruction::ABC { instruction: OpCode::GetTabUp, b, c, .. } => {
+            let kind = if upvalue_name(proto, b as usize) == "_ENV" { "global" } else { "field" };
+            Some((kind, constant_name(proto, c)))
+        }
+        Instruction::ABC { instruction: OpCode::GetTable, b, c, .. } => {
+            let table = local_name(proto, b as usize, setter);
+            let kind = if table.as_deref() == Some("_ENV") {
+                "global"
+            } else {
+                "field"
+            };
+            Some((kind, constant_name(proto, c)))
+        }
+        Instruction::ABC { instruction: OpCode::GetUpval, b, .. } => {
+            Some(("upvalue", upvalue_name(proto, b as usize)))
+        }
+        Instruction::ABx { instruction: OpCode::LoadK, bx, .. } => {
+            match proto.constants.get(bx as usize) {
+                Some(SyxValue::String(name)) => Some(("constant", name.to_string())),
+                _ => None,
+            }
+        }
+        Instruction::ABC { instruction: OpCode::SelfLoad, c, .. } => {
+            Some(("method", constant_name(proto, c)))
+        }
+        _ => None,
+    }
+}
+
+// What the function `caller` is calling is called in its source
+fn function_name(caller: &CallInfo) -> Option<(&'static str, String)> {
+    let pc = caller.pc.checked_sub(1)?;
+    match caller.proto.instructions[pc] {
+        Instruction::ABC { instruction: OpCode::Call, a, .. } |
+        Instruction::ABC { instruction: OpCode::TailCall, a, .. } => {
+            object_name(&caller.proto, pc, a as usize)
+        }
+        Instruction::ABC { instruction: OpCode::TForCall, .. } => {
+            Some(("for iterator", "for iterator".to_owned()))
+        }
+        _ => None,
+    }
+}
+
+// Where local `n` of a frame is kept, see SyxState::find_local
+pub(crate) enum LocalSlot {
+    Stack(usize),  // stack index
+    Vararg(usize), // index in the frame's varargs
+}
+
+impl SyxState {
+    // index in `frames` of the Lua function `level` frames down, counting
+    // the running one as 1
+    pub(crate) fn frame_at(&self, level: usize) -> Option<usize> {
+        if level == 0 {
+            return None;
+        }
+        self.frames.len().checked_sub(level)
+    }
+
+    // what the function of frame `index` is called by its caller, if the
+    // caller is a Lua function
+    pub(crate) fn frame_name(&self, index: usize) -> Option<(&'static str, String)> {
+        // the frame below is not the caller of one that was tail called
+        let ci = &self.frames[index];
+        if ci.from_lua && !ci.tailcall && index > 0 {
+            function_name(&self.frames[index - 1])
+        } else {
+            None
+        }
+    }
+
+    // Local `n` of frame `index`, counting from 1 through the locals active
+    // at its current instruction and then the other registers it uses, which
+    // are "(*temporary)"; negative `n` count through its varargs
+    pub(crate) fn find_local(&self, index: usize, n: SyxInteger) -> Option<(String, LocalSlot)> {
+        let ci = &self.frames[index];
+        if n < 0 {
+            let i = (-n - 1) as usize;
+            return if i < ci.varargs.len() {
+                Some(("(*vararg)".to_owned(), LocalSlot::Vararg(i)))
+            } else {
+                None
+            };
+        }
+        let register = (n as usize).checked_sub(1)?;
+        let slot = ci.base + register;
+        if let Some(name) = local_name(&ci.proto, register, ci.pc.saturating_sub(1)) {
+            return Some((name, LocalSlot::Stack(slot)));
+        }
+        if register < ci.proto.maxstacksize as usize && slot < self.stack.len() {
+            Some(("(*temporary)".to_owned(), LocalSlot::Stack(slot)))
+        } else {
+            None
+        }
+    }
+
+    // "source:line:" of the running Lua function, or None without line info;
+    // "source:line:column:" when it has columns
+    pub fn location(&self) -> Option<String> {
+        self.location_at(1)
+    }
+
+    // as `location`, for the Lua function `level` frames down, counting the
+    // running one as 1
+    pub fn location_at(&self, level: usize) -> Option<String> {
+        if level == 0 {
+            return None;
+        }
+        let index = self.frames.len().checked_sub(level)?;
+        let ci = &self.frames[index];
+        let line = current_line(&ci.proto, ci.pc)?;
+        let source = short_source(&ci.proto.source);
+        Some(match current_column(&ci.proto, ci.pc) {
+            Some(column) => format!("{}:{}:{}:", source, line, column),
+            None => format!("{}:{}:", source, line),
+        })
+    }
+
+    // Describe the active Lua frames, innermost first, starting `level`
+    // frames down from the running one
+    pub fn traceback(&self, message: Option<&str>, level: usize) -> String {
+        let mut traceback = String::new();
+        if let Some(message) = message {
+            traceback.push_str(message);
+            traceback.push('\n');
+        }
+        traceback.push_str("stack traceback:");
+        let count = self.frames.len().saturating_sub(level);
+        for i in (0..count).rev() {
+            let ci = &self.frames[i];
+            let source = short_source(&ci.proto.source);
+            match current_line(&ci.proto, ci.pc) {
+                Some(line) => traceback.push_str(&format!("\n\t{}:{}: in ", source, line)),
+                None => traceback.push_str(&format!("\n\t{}: in ", source)),
+            }
+            match self.frame_name(i) {
+                Some(("for iterator", _)) => traceback.push_str("for iterator"),
+                Some((kind, name)) => traceback.push_str(&format!("{} '{}'", kind, name)),
+                None if ci.proto.linedefined == 0 => traceback.push_str("main chunk"),
+                None => traceback.push_str(&format!("function <{}:{}>",
+                                                    source, ci.proto.linedefined)),
+            }
+            if ci.tailcall {
+                traceback.push_str("\n\t(...tail calls...)");
+            }
+        }
+        traceback
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use super::super::compiler::parse;
+
+    #[test]
+    fn test_local_at() {
+        let source = b"local a = 1 do local b = 2 a = b end local c = 3 return a + c";
+        let proto = parse(source, "=test").unwrap();
+        let name = |pc, index| proto.local_at(pc, index).map(|l| l.varname.to_string());
+        // c takes the register of b once b is out of scope
+        assert_eq!(name(1, 0).as_deref(), Some("a"));
+        assert_eq!(name(2, 1).as_deref(), Some("b"));
+        assert_eq!(name(3, 1), None);
+        assert_eq!(name(4, 1).as_deref(), Some("c"));
+        assert_eq!(name(4, 2), None);
+        assert_eq!(name(0, 0), None);
+    }
+
+    #[test]
+    fn test_short_source() {
+        assert_eq!(short_source("=stdin"), "stdin");
+        assert_eq!(short_source("@script.lua"), "script.lua");
+        assert_eq!(short_source("x = 1"), "[string \"x = 1\"]");
+        assert_eq!(short_source("x = 1\ny = 2"), "[string \"x = 1...\"]");
+        let long = "a".repeat(100);
+        assert_eq!(short_source(&format!("={}", long)), &long[..59]);
+        assert_eq!(short_source(&format!("@{}.lua", long)), format!("...{}.lua", &long[..52]));
+        assert_eq!(short_source(&long), format!("[string \"{}...\"]", &long[..45]));
+        // never cut inside a character
+        assert_eq!(short_source(&format!("{}é", &long[..44])),
+                   format!("[string \"{}...\"]", &long[..44]));
+
+        // in messages
+        let source = "local t = nil\nreturn t.x";
+        let proto = parse(source.as_bytes(), source).unwrap();
+        let error = SyxState::new().call(Arc::new(proto), vec![]).unwrap_err();
+        assert_eq!(error.to_string(),
+                   "[string \"local t = nil...\"]:2: attempt to index a nil value");
+        let error = parse(b"return +", "x = +").unwrap_err();
+        assert!(error.to_string().starts_with("[string \"x = +\"]:1:"), "{}", error);
+    }
+}
@@ -0,0 +1,208 @@
+// Differences between functions
+//
+// `Proto::diff` compares two functions field by field and entry by entry,
+// and the functions defined in both of them the same way, for tools that
+// produce bytecode to check that their output stays the same or to find out
+// how it changed. Constants are compared as constants rather than as values:
+// 1 and 1.0 differ, and floats are compared by their bits.
+//
+// A ProtoDiff displays one difference per line, the way a listing shows the
+// instructions and constants involved, with nested functions named by their
+// index in each function above them ("function 2.1").
+
+use std::fmt;
+
+use super::listing::{constant_text, instruction_text};
+use super::object::{LocVar, Proto, SyxValue, Upvalue};
+use super::opcodes::Instruction;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Difference {
+    // source, linedefined, lastlinedefined, numparams, is_vararg,
+    // maxstacksize, or the number of functions defined
+    Header(&'static str, String, String),
+    // the rest by index, None past the end of one of them
+    Instruction(usize, Option<Instruction>, Option<Instruction>),
+    Constant(usize, Option<SyxValue>, Option<SyxValue>),
+    Upvalue(usize, Option<Upvalue>, Option<Upvalue>),
+    Line(usize, Option<i32>, Option<i32>),
+    Column(usize, Option<i32>, Option<i32>),
+    Local(usize, Option<LocVar>, Option<LocVar>),
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProtoDiff {
+    pub differences: Vec<Difference>,
+    pub protos: Vec<(usize, ProtoDiff)>, // functions defined in both that differ
+}
+
+impl ProtoDiff {
+    pub fn is_empty(&self) -> bool {
+        self.differences.is_empty() && self.protos.is_empty()
+    }
+
+    fn write(&self, f: &mut fmt::Formatter, path: &str) -> fmt::Result {
+        for difference in &self.differences {
+            if !path.is_empty() {
+                write!(f, "function {}: ", path)?;
+            }
+            writeln!(f, "{}", difference)?;
+        }
+        for &(index, ref diff) in &self.protos {
+            let path = if path.is_empty() {
+                (index + 1).to_string()
+            } else {
+                format!("{}.{}", path, index + 1)
+            };
+            diff.write(f, &path)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ProtoDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, "")
+    }
+}
+
+fn side<T>(value: &Option<T>, text: impl Fn(&T) -> String) -> String {
+    value.as_ref().map_or_else(|| "(none)".to_owned(), text)
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let upvalue = |u: &Upvalue| format!("{} {} {}", u.name, u.instack, u.idx);
+        let local = |l: &LocVar| format!("{} {} {}", l.varname, l.startpc + 1, l.endpc + 1);
+        // numbered as a full listing numbers them
+        let (what, index, left, right) = match *self {
+            Difference::Header(field, ref left, ref right) => {
+                return write!(f, "{}: {} -> {}", field, left, right);
+            }
+            Difference::Instruction(pc, ref left, ref right) => {
+                ("instruction", pc + 1, side(left, instruction_text), side(right, instruction_text))
+            }
+            Difference::Constant(i, ref left, ref right) => {
+                ("constant", i + 1, side(left, constant_text), side(right, constant_text))
+            }
+            Difference::Upvalue(i, ref left, ref right) => {
+                ("upvalue", i, side(left, upvalue), side(right, upvalue))
+            }
+            Difference::Line(pc, ref left, ref right) => {
+                ("line of instruction", pc + 1, side(left, i32::to_string),
+                 side(right, i32::to_string))
+            }
+            Difference::Column(pc, ref left, ref right) => {
+                ("column of instruction", pc + 1, side(left, i32::to_string),
+                 side(right, i32::to_string))
+            }
+            Difference::Local(i, ref left, ref right) => {
+                ("local", i, side(left, local), side(right, local))
+            }
+        };
+        write!(f, "{} {}: {} -> {}", what, index, left, right)
+    }
+}
+
+fn same_constant(left: &SyxValue, right: &SyxValue) -> bool {
+    match (left, right) {
+        (&SyxValue::Number(a), &SyxValue::Number(b)) => a.to_bits() == b.to_bits(),
+        (&SyxValue::Integer(_), &SyxValue::Number(_)) |
+        (&SyxValue::Number(_), &SyxValue::Integer(_)) => false,
+        _ => left == right,
+    }
+}
+
+// Entries of `left` and `right` that differ, by index
+fn compare<T: Clone>(left: &[T], right: &[T], same: impl Fn(&T, &T) -> bool,
+                     difference: impl Fn(usize, Option<T>, Option<T>) -> Difference,
+                     differences: &mut Vec<Difference>) {
+    for i in 0..left.len().max(right.len()) {
+        let (l, r) = (left.get(i), right.get(i));
+        let equal = match (l, r) {
+            (Some(l), Some(r)) => same(l, r),
+            _ => false,
+        };
+        if !equal {
+            differences.push(difference(i, l.cloned(), r.cloned()));
+        }
+    }
+}
+
+impl Proto {
+    // What differs between this function and `other`, and between the
+    // functions defined in both
+    pub fn diff(&self, other: &Proto) -> ProtoDiff {
+        let mut differences = Vec::new();
+        let header = [
+            ("source", self.source.clone(), other.source.clone()),
+            ("linedefined", self.linedefined.to_string(), other.linedefined.to_string()),
+            ("lastlinedefined", self.lastlinedefined.to_string(),
+             other.lastlinedefined.to_string()),
+            ("numparams", self.numparams.to_string(), other.numparams.to_string()),
+            ("is_vararg", self.is_vararg.to_string(), other.is_vararg.to_string()),
+            ("maxstacksize", self.maxstacksize.to_string(), other.maxstacksize.to_string()),
+            ("functions", self.protos.len().to_string(), other.protos.len().to_string()),
+        ];
+        for (field, left, right) in header.iter().cloned() {
+            if left != right {
+                differences.push(Difference::Header(field, left, right));
+            }
+        }
+        compare(&self.instructions, &other.instructions, |a, b| a == b,
+                Difference::Instruction, &mut differences);
+        compare(&self.constants, &other.constants, same_constant,
+                Difference::Constant, &mut differences);
+        compare(&self.upvalues, &other.upvalues, |a, b| a == b,
+                Difference::Upvalue, &mut differences);
+        compare(&self.lineinfo, &other.lineinfo, |a, b| a == b,
+                Difference::Line, &mut differences);
+        compare(&self.columninfo, &other.columninfo, |a, b| a == b,
+                Difference::Column, &mut differences);
+        compare(&self.locvars, &other.locvars, |a, b| a == b,
+                Difference::Local, &mut differences);
+
+        let protos = self.protos.iter().zip(&other.protos)
+            .map(|(left, right)| left.diff(right))
+            .enumerate()
+            .filter(|(_, diff)| !diff.is_empty())
+            .collect();
+        ProtoDiff { differences, protos }
+    }
+}
+
+#[cfg(all(test, not(feature = "float_only")))] // 1 and 1.0 are both floats
+mod tests {
+    use super::*;
+    use super::super::compiler::parse;
+
+    fn compile(source: &str) -> Proto {
+        parse(source.as_bytes(), "=test").unwrap()
+    }
+
+    #[test]
+    fn test_diff() {
+        let source = "local x = 1 local function f() return x, 'a' end return f";
+        assert!(compile(source).diff(&compile(source)).is_empty());
+
+        let left = compile(source);
+        let right = compile("local x = 1.0 local function f() return x, 'b', 1 end\nreturn f");
+        let diff = left.diff(&right);
+        assert_eq!(diff.differences[0], Difference::Constant(
+            0, Some(SyxValue::Integer(1)), Some(SyxValue::Number(1.0))));
+        assert_eq!(diff.protos.len(), 1);
+        assert_eq!(diff.protos[0].0, 0);
+        assert_eq!(diff.to_string(), "\
+constant 1: 1 -> 1.0
+line of instruction 3: 1 -> 2
+line of instruction 4: 1 -> 2
+function 1: maxstacksize: 2 -> 3
+function 1: instruction 3: RETURN 0 3 -> LOADK 2 -2
+function 1: instruction 4: RETURN 0 1 -> RETURN 0 4
+function 1: instruction 5: (none) -> RETURN 0 1
+function 1: constant 1: \"a\" -> \"b\"
+function 1: constant 2: (none) -> 1
+function 1: line of instruction 5: (none) -> 1
+");
+    }
+}
@@ -0,0 +1,315 @@
+use std::io::Write;
+
+use super::conf::{
+    SYX_HEADER, SYX_DATA, SYX_VERSION, SYX_FORMAT, SYX_FORMAT_COLUMNS, SYX_INT, SYX_NUM,
+};
+
+use super::object::{
+    Proto, SyxInt, SyxType, SyxInteger, SyxNumber, SyxValue,
+    SYX_TNUMFLT, SYX_TNUMINT, SYX_TSHRSTR, SYX_TLNGSTR,
+};
+use super::limits;
+use super::opcodes::Word;
+use super::undump::Primitives;
+use super::errors::*;
+
+pub struct DumpState {
+    output: Vec<u8>,
+    format: u8,
+    strip: bool, // leave out debug information
+}
+
+impl DumpState {
+    pub fn to_write(proto: &Proto, mut output: impl Write, name: impl Into<String>)
+        -> Result<()>
+    {
+        let buffer = DumpState::to_u8(proto)?;
+        output.write_all(&buffer)
+            .chain_err(|| ErrorKind::BufferNotWritable(name.into()))
+    }
+
+    pub fn to_u8(proto: &Proto) -> Result<Vec<u8>> {
+        DumpState::to_u8_strip(proto, false)
+    }
+
+    // The chunk `luac -s` would write when `strip` is set, with no lines,
+    // locals, upvalue names or source
+    pub fn to_u8_strip(proto: &Proto, strip: bool) -> Result<Vec<u8>> {
+        let mut state = DumpState {
+            output: Vec::new(),
+            format: SYX_FORMAT,
+            strip,
+        };
+        state.dump_chunk(proto)?;
+        Ok(state.output)
+    }
+
+    fn dump_range(&mut self, range: &[u8]) {
+        self.output.extend_from_slice(range);
+    }
+
+    fn dump<T: Copy + Primitives>(&mut self, value: T) {
+        /*
+         * Safety of this method
+         * ---
+         * The mirror of LoadState::load; the bytes of a primitive are copied
+         * out in native order, which is the same order they are read in.
+         */
+        let size = ::std::mem::size_of::<T>();
+        let bytes = unsafe {
+            ::std::slice::from_raw_parts(&value as *const T as *const u8, size)
+        };
+        self.dump_range(bytes);
+    }
+
+    fn dump_size(&mut self, size: usize) {
+        if size < 0xFF {
+            self.dump::<u8>(size as u8);
+        } else {
+            self.dump::<u8>(0xFF);
+            self.dump::<usize>(size);
+        }
+    }
+
+    fn dump_string(&mut self, string: &[u8]) {
+        // Sizes include the trailing NUL that C Lua keeps on its strings
+        self.dump_size(string.len() + 1);
+        self.dump_range(string);
+    }
+
+    fn dump_optional_string(&mut self, string: &[u8]) {
+        // Empty names are what LoadState produces from NULL strings, which is
+        // what stripped chunks have in place of debug information
+        if string.is_empty() {
+            self.dump_size(0);
+        } else {
+            self.dump_string(string);
+        }
+    }
+
+    fn dump_code(&mut self, proto: &Proto) -> Result<()> {
+        self.dump::<SyxInt>(proto.instructions.len() as SyxInt);
+        for instr in &proto.instructions {
+            self.dump::<Word>(instr.encode()?);
+        }
+        Ok(())
+    }
+
+    fn dump_constants(&mut self, proto: &Proto) -> Result<()> {
+        self.dump::<SyxInt>(proto.constants.len() as SyxInt);
+        for constant in &proto.constants {
+            match *constant {
+                SyxValue::Nil => self.dump::<u8>(0),
+                SyxValue::Bool(b) => {
+                    self.dump::<u8>(1);
+                    self.dump::<u8>(b as u8);
+                },
+                SyxValue::Number(n) => {
+                    self.dump::<u8>(SYX_TNUMFLT);
+                    self.dump::<SyxNumber>(n);
+                },
+                SyxValue::Integer(n) => {
+                    self.dump::<u8>(SYX_TNUMINT);
+                    self.dump::<SyxInteger>(n);
+                },
+                SyxValue::String(ref s) => {
+                    if s.len() <= limits::SYX_MAXSHORTLEN {
+                        self.dump::<u8>(SYX_TSHRSTR);
+                    } else {
+                        self.dump::<u8>(SYX_TLNGSTR);
+                    }
+                    self.dump_string(s);
+                },
+                SyxValue::Table(_) => {
+                    bail!(ErrorKind::InvalidConstantType(SyxType::TTABLE));
+                },
+                SyxValue::Function(_) | SyxValue::Native(_) | SyxValue::NativeClosure(_) => {
+                    bail!(ErrorKind::InvalidConstantType(SyxType::TFUNCTION));
+                },
+                SyxValue::Thread(_) => {
+                    bail!(ErrorKind::InvalidConstantType(SyxType::TTHREAD));
+                },
+                SyxValue::UserData(_) => {
+                    bail!(ErrorKind::InvalidConstantType(SyxType::TUSERDATA));
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn dump_upvalues(&mut self, proto: &Proto) {
+        self.dump::<SyxInt>(proto.upvalues.len() as SyxInt);
+        for upvalue in &proto.upvalues {
+            self.dump::<u8>(upvalue.instack);
+            self.dump::<u8>(upvalue.idx);
+        }
+    }
+
+    fn dump_protos(&mut self, proto: &Proto) -> Result<()> {
+        self.dump::<SyxInt>(proto.protos.len() as SyxInt);
+        for child in &proto.protos {
+            self.dump_function(child, &proto.source)?;
+        }
+        Ok(())
+    }
+
+    fn dump_debug(&mut self, proto: &Proto) {
+        if self.strip {
+            self.dump::<SyxInt>(0);
+            self.dump::<SyxInt>(0);
+            self.dump::<SyxInt>(0);
+            return;
+        }
+        self.dump::<SyxInt>(proto.lineinfo.len() as SyxInt);
+        for line in &proto.lineinfo {
+            self.dump::<SyxInt>(*line);
+        }
+        self.dump::<SyxInt>(proto.locvars.len() as SyxInt);
+        for locvar in &proto.locvars {
+            self.dump_optional_string(&locvar.varname);
+            self.dump::<SyxInt>(locvar.startpc);
+            self.dump::<SyxInt>(locvar.endpc);
+        }
+        // stripped chunks carry no upvalue names at all
+        if proto.upvalues.iter().all(|upvalue| upvalue.name.is_empty()) {
+            self.dump::<SyxInt>(0);
+        } else {
+            self.dump::<SyxInt>(proto.upvalues.len() as SyxInt);
+            for upvalue in &proto.upvalues {
+                self.dump_optional_string(&upvalue.name);
+            }
+        }
+        if self.format & SYX_FORMAT_COLUMNS != 0 {
+            self.dump::<SyxInt>(proto.columninfo.len() as SyxInt);
+            for column in &proto.columninfo {
+                self.dump::<SyxInt>(*column);
+            }
+        }
+    }
+
+    fn dump_function(&mut self, proto: &Proto, source: &str) -> Result<()> {
+        // Nested functions share the source of their parent, so it is only
+        // written out when it differs, the same way LoadState reads it back
+        if self.strip || proto.source == source {
+            self.dump_size(0);
+        } else {
+            self.dump_optional_string(proto.source.as_bytes());
+        }
+        self.dump::<SyxInt>(proto.linedefined);
+        self.dump::<SyxInt>(proto.lastlinedefined);
+        self.dump::<u8>(proto.numparams);
+        self.dump::<u8>(proto.is_vararg);
+        self.dump::<u8>(proto.maxstacksize);
+        self.dump_code(proto)?;
+        self.dump_constants(proto)?;
+        self.dump_upvalues(proto);
+        self.dump_protos(proto)?;
+        self.dump_debug(proto);
+        Ok(())
+    }
+
+    fn dump_header(&mut self) {
+        self.dump_range(SYX_HEADER);
+        self.dump::<u8>(SYX_VERSION);
+        self.dump::<u8>(self.format);
+        self.dump_range(SYX_DATA);
+        self.dump::<u8>(::std::mem::size_of::<i32>() as u8);
+        self.dump::<u8>(::std::mem::size_of::<usize>() as u8);
+        self.dump::<u8>(::std::mem::size_of::<Word>() as u8);
+        self.dump::<u8>(::std::mem::size_of::<SyxInteger>() as u8);
+        self.dump::<u8>(::std::mem::size_of::<SyxNumber>() as u8);
+        self.dump::<SyxInteger>(SYX_INT as SyxInteger);
+        self.dump::<SyxNumber>(SYX_NUM as SyxNumber);
+    }
+
+    fn dump_chunk(&mut self, proto: &Proto) -> Result<()> {
+        // chunks without columns stay readable by luac
+        if !self.strip && has_columns(proto) {
+            self.format |= SYX_FORMAT_COLUMNS;
+        }
+        self.dump_header();
+        self.dump::<u8>(proto.upvalues.len() as u8);
+        self.dump_function(proto, "")
+    }
+}
+
+fn has_columns(proto: &Proto) -> bool {
+    !proto.columninfo.is_empty() || proto.protos.iter().any(|child| has_columns(child))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use super::super::compiler::{parse, parse_with_columns};
+    use super::super::state::SyxState;
+    use super::super::undump::LoadState;
+
+    const HELLO_WORLD: &[u8] = include_bytes!("../luac.out");
+
+    #[test]
+    #[cfg(not(any(feature = "int32", feature = "float32")))] // luac.out has 64-bit numbers
+    fn test_round_trip() {
+        let proto = LoadState::from_u8(HELLO_WORLD.to_vec(), "luac.out").unwrap();
+        let dumped = DumpState::to_u8(&proto).unwrap();
+        assert_eq!(dumped, HELLO_WORLD);
+
+        let reloaded = LoadState::from_u8(dumped.clone(), "dumped").unwrap();
+        assert_eq!(DumpState::to_u8(&reloaded).unwrap(), dumped);
+    }
+
+    #[test]
+    #[cfg(not(any(feature = "int32", feature = "float32")))] // luac.out has 64-bit numbers
+    fn test_to_write() {
+        let proto = LoadState::from_u8(HELLO_WORLD.to_vec(), "luac.out").unwrap();
+        let mut output = Vec::new();
+        DumpState::to_write(&proto, &mut output, "output").unwrap();
+        assert_eq!(output, HELLO_WORLD);
+    }
+
+    #[test]
+    fn test_strip() {
+        let mut proto = LoadState::from_u8(HELLO_WORLD.to_vec(), "luac.out").unwrap();
+        let stripped = DumpState::to_u8_strip(&proto, true).unwrap();
+        assert!(stripped.len() < HELLO_WORLD.len());
+        let reloaded = LoadState::from_u8(stripped.clone(), "stripped").unwrap();
+        assert!(reloaded.source.is_empty() && reloaded.lineinfo.is_empty());
+        assert!(reloaded.upvalues.iter().all(|upvalue| upvalue.name.is_empty()));
+        assert_eq!(reloaded.instructions, proto.instructions);
+
+        // the same chunk as stripping in place first
+        proto.strip_debug();
+        assert_eq!(DumpState::to_u8(&proto).unwrap(), stripped);
+    }
+
+    #[test]
+    fn test_columns() {
+        let source = b"local t = {}\nreturn t.x.y";
+        let proto = parse_with_columns(source, "=test").unwrap();
+        assert_eq!(proto.columninfo.len(), proto.instructions.len());
+        let chunk = DumpState::to_u8(&proto).unwrap();
+        assert_eq!(chunk[5], SYX_FORMAT | SYX_FORMAT_COLUMNS);
+        let reloaded = LoadState::from_u8(chunk, "columns").unwrap();
+        assert_eq!(reloaded.columninfo, proto.columninfo);
+        // left out of chunks without them, and of stripped ones
+        assert_eq!(DumpState::to_u8(&parse(source, "=test").unwrap()).unwrap()[5], SYX_FORMAT);
+        assert_eq!(DumpState::to_u8_strip(&proto, true).unwrap()[5], SYX_FORMAT);
+
+        let mut state = SyxState::new();
+        let error = state.call(Arc::new(reloaded), Vec::new()).unwrap_err();
+        assert_eq!(error.to_string(), "test:2:12: attempt to index a nil value");
+
+        // the column count, last in the chunk before its columns
+        let mut corrupt = DumpState::to_u8(&proto).unwrap();
+        let width = ::std::mem::size_of::<SyxInt>();
+        let count = corrupt.len() - width * (proto.columninfo.len() + 1);
+        corrupt[count..count + width].iter_mut().for_each(|byte| *byte = 0xFF);
+        let error = match LoadState::from_u8(corrupt, "columns") {
+            Err(Error(ErrorKind::Undump(error), _)) => error,
+            _ => panic!("expected an undump error"),
+        };
+        assert_eq!((error.offset, error.section), (count, Section::Debug));
+        assert_eq!(error.reason.to_string(), "negative column count: -1");
+    }
+}
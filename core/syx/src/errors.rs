@@ -1,31 +1,134 @@
-use super::object::{SyxType};
+use std::error;
+use std::fmt;
+
+use super::object::{SyxType, SyxValue};
 
 error_chain! {
     errors {
         // undump.rs
 
-        BufferNotReadable(t: String) {
-            display("no values read from buffer: {}", t),
+        Undump(error: UndumpError) {
+            display("{}", error),
+        }
+
+        // string.rs
+
+        HashSeedChosen(seed: u64) {
+            display("hash seed already chosen: {:#x}", seed),
+        }
+
+        // format/
+
+        UnsupportedOpCode(name: String) {
+            display("opcode can not be translated: {}", name),
+        }
+
+        // compiler/
+
+        SyntaxError(message: String) {
+            display("{}", message),
+        }
+
+        ChunkModeError(kind: &'static str, mode: String) {
+            display("attempt to load a {} chunk (mode is '{}')", kind, mode),
+        }
+
+        // verify.rs
+
+        InvalidFunction(reason: &'static str) {
+            display("{}", reason),
+        }
+
+        InvalidRegister(pc: usize, register: usize) {
+            display("instruction {}: register {} is outside the stack", pc + 1, register),
+        }
+
+        InvalidConstant(pc: usize, index: usize) {
+            display("instruction {}: constant {} does not exist", pc + 1, index),
+        }
+
+        InvalidUpvalue(pc: usize, index: usize) {
+            display("instruction {}: upvalue {} does not exist", pc + 1, index),
+        }
+
+        InvalidProto(pc: usize, index: usize) {
+            display("instruction {}: function {} does not exist", pc + 1, index),
         }
 
-        BufferNotEmpty {
-            display("bytes left over from buffer"),
+        InvalidJump(pc: usize, target: i64) {
+            display("instruction {}: jump to {} is out of range", pc + 1, target + 1),
         }
 
-        InvalidVerification(name: String, err: String) {
-            display("error verifying {}: {}", name, err),
+        MissingInstruction(pc: usize, expected: super::opcodes::OpCode) {
+            display("instruction {}: must be followed by {:?}", pc + 1, expected),
         }
 
+        // dump.rs
+
         InvalidConstantType(t: SyxType) {
             display("bad value for constant: {:?}", t),
         }
 
-        InvalidUpvalueIndex(index: usize) {
-            display("could not find upvalue index: {}", index),
+        BufferNotWritable(t: String) {
+            display("no values written to buffer: {}", t),
+        }
+
+        // vm.rs
+
+        RuntimeError(message: String) {
+            display("{}", message),
+        }
+
+        LuaError(value: SyxValue) {
+            display("{}", super::protect::error_message(value)),
+        }
+
+        // gc.rs
+
+        MemoryError {
+            display("not enough memory"),
+        }
+
+        // convert.rs
+
+        FromSyxError(from: &'static str, to: &'static str) {
+            display("{} expected, got {}", to, from),
+        }
+
+        ArgumentError(n: usize, from: &'static str, to: &'static str) {
+            display("bad argument #{} ({} expected, got {})", n, to, from),
+        }
+
+        // serialize.rs
+
+        SerdeError(message: String) {
+            display("{}", message),
+        }
+
+        // msgpack.rs
+
+        MsgPackError(message: String) {
+            display("{}", message),
+        }
+
+        // stdlib/json.rs
+
+        JsonError(message: String) {
+            display("{}", message),
+        }
+
+        // userdata.rs
+
+        UserDataTypeMismatch(expected: &'static str) {
+            display("userdata is not a {}", expected),
+        }
+
+        UserDataBorrowError {
+            display("userdata already mutably borrowed"),
         }
 
-        InvalidSourceName {
-            display("could not match source name from UTF8"),
+        UserDataBorrowMutError {
+            display("userdata already borrowed"),
         }
 
         // opcodes.rs
@@ -34,10 +137,108 @@ error_chain! {
             display("opcode is not valid"),
         }
 
+        InvalidOperand(opcode: super::opcodes::OpCode, field: &'static str, value: i64) {
+            display("operand {} of {:?} out of range: {}", field, opcode, value),
+        }
+
         // objects.rs
 
         InvalidType(t: u8) {
             display("invalid type parameter loaded: {}", t),
         }
+
+        InvalidTableKey(t: &'static str) {
+            display("table index is {}", t),
+        }
+
+        InvalidNextKey {
+            display("invalid key to 'next'"),
+        }
+    }
+}
+
+// Part of a chunk being parsed when loading it failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Section {
+    Header,
+    Function, // source, lines and sizes of a function, before its code
+    Code,
+    Constants,
+    Upvalues,
+    Debug,
+    Trailer, // anything left over after the main function
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Section::Header => "header",
+            Section::Function => "function",
+            Section::Code => "code",
+            Section::Constants => "constants",
+            Section::Upvalues => "upvalues",
+            Section::Debug => "debug info",
+            Section::Trailer => "trailer",
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum UndumpReason {
+    Truncated(usize),     // bytes the value needed
+    Unreadable,           // the reader failed, see the cause
+    Verification(String), // the chunk does not pass a check
+    ConstantType(u8),
+    UpvalueIndex(usize),
+    SourceName,
+    TrailingBytes,
+    Invalid(String), // error raised translating the chunk, see the cause
+}
+
+impl fmt::Display for UndumpReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UndumpReason::Truncated(n) => write!(f, "not enough bytes: {}", n),
+            UndumpReason::Unreadable => f.write_str("could not read chunk"),
+            UndumpReason::Verification(ref message) |
+            UndumpReason::Invalid(ref message) => f.write_str(message),
+            UndumpReason::ConstantType(t) => write!(f, "bad value for constant: {}", t),
+            UndumpReason::UpvalueIndex(i) => write!(f, "could not find upvalue index: {}", i),
+            UndumpReason::SourceName => f.write_str("could not match source name from UTF8"),
+            UndumpReason::TrailingBytes => f.write_str("bytes left over from buffer"),
+        }
+    }
+}
+
+// Failure to load a chunk, and where in it that happened; undump.rs, the
+// loaders in format/ and mmap.rs raise nothing else
+#[derive(Clone, Debug, PartialEq)]
+pub struct UndumpError {
+    pub name: String,
+    pub offset: usize, // where the value being read starts
+    pub section: Section,
+    pub reason: UndumpReason,
+}
+
+impl UndumpError {
+    pub fn new(name: &str, offset: usize, section: Section, reason: UndumpReason)
+        -> UndumpError
+    {
+        UndumpError { name: name.to_owned(), offset, section, reason }
+    }
+}
+
+impl fmt::Display for UndumpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "error loading {}: {} (byte {}, {})",
+               self.name, self.reason, self.offset, self.section)
+    }
+}
+
+impl error::Error for UndumpError {}
+
+impl From<UndumpError> for Error {
+    fn from(error: UndumpError) -> Error {
+        ErrorKind::Undump(error).into()
     }
 }
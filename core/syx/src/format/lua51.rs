@@ -0,0 +1,477 @@
+// Lua 5.1 binary chunks
+//
+// The 5.1 layout differs from 5.3 in a few ways that matter here:
+//
+// * the header carries an endianness flag and an "integral numbers" flag
+//   instead of the LUAC_DATA/LUAC_INT/LUAC_NUM checks
+// * strings are prefixed by a full size_t instead of a single byte
+// * there are no upvalue descriptors; CLOSURE is followed by one MOVE or
+//   GETUPVAL pseudo-instruction per upvalue of the new function
+// * constants are followed directly by the nested functions, and the debug
+//   section ends with the upvalue names
+// * globals live in the function environment (GETGLOBAL/SETGLOBAL) rather
+//   than in an `_ENV` upvalue
+//
+// Instructions are translated one to one, so jump offsets and line info stay
+// valid. Every function gets a trailing `_ENV` upvalue that globals are
+// accessed through, pseudo-instructions become `Jmp 0` no-ops, CLOSE becomes
+// the 5.3 `Jmp` that closes upvalues, and TFORLOOP + JMP becomes the
+// TFORCALL + TFORLOOP pair.
+
+use std::sync::Arc;
+
+use super::super::object::{integer_value, LocVar, Proto, SyxString, SyxValue, Upvalue};
+use super::super::opcodes::{Instruction, OpCode, Word};
+use super::super::undump::LoadState;
+use super::super::errors::*;
+
+const LUA51_FORMAT: u8 = 0;
+
+// Only VARARG_ISVARARG matters, the others are for the 5.0 `arg` table
+const VARARG_ISVARARG: u8 = 2;
+
+const BITRK: u16 = 1 << 8;
+const MAXINDEXRK: u32 = (BITRK - 1) as u32;
+
+const MAXARG_SBX: i32 = (1 << 17) - 1;
+
+// Function as laid out in the chunk, before its code is translated
+struct Function {
+    proto: Proto,
+    nups: u8,
+    code: Vec<Word>,
+    upvalue_names: Vec<SyxString>,
+    protos: Vec<Function>,
+}
+
+pub(crate) fn load_chunk(state: &mut LoadState) -> Result<Proto> {
+    let integral = check_header(state)?;
+    let main = load_function(state, "", integral)?;
+    // translated once it is all read
+    state.section = Section::Code;
+    let env = Upvalue {
+        name: state.intern(b"_ENV"),
+        instack: 1,
+        idx: 0,
+    };
+    translate(state, main, vec![env])
+}
+
+// Returns whether lua_Number was an integral type for this chunk; shared
+// with 5.2, which only adds LUAC_TAIL after it
+pub(super) fn check_header(state: &mut LoadState) -> Result<bool> {
+    let format = state.load::<u8>()?;
+    state.assert_verification(format == LUA51_FORMAT, "format mismatch")?;
+    let little_endian = state.load::<u8>()? == 1;
+    state.swap = little_endian != cfg!(target_endian = "little");
+    state.sizes.int = state.check_width("int", &[2, 4, 8])?;
+    state.sizes.size = state.check_width("size_t", &[4, 8])?;
+    state.check_width("Instruction", &[::std::mem::size_of::<Word>()])?;
+    let number = state.check_width("lua_Number", &[4, 8])?;
+    let integral = state.load::<u8>()? != 0;
+    if integral {
+        state.sizes.integer = number;
+    } else {
+        state.sizes.number = number;
+    }
+    Ok(integral)
+}
+
+pub(super) fn load_string(state: &mut LoadState) -> Result<SyxString> {
+    let size = state.load_size()?;
+    if size == 0 {
+        Ok(state.intern(&[]))
+    } else {
+        // strip the trailing NUL
+        let mut string = state.load_range(size)?;
+        string.pop();
+        Ok(state.intern(&string))
+    }
+}
+
+pub(super) fn load_constants(state: &mut LoadState, proto: &mut Proto, integral: bool)
+    -> Result<()>
+{
+    state.section = Section::Constants;
+    let count = state.load_int()?;
+    proto.constants.clear();
+    for _ in 0..count {
+        proto.constants.push(match state.load::<u8>()? {
+            0 => SyxValue::Nil,
+            1 => SyxValue::Bool(state.load::<u8>()? != 0),
+            3 if integral => integer_value(state.load_integer()?),
+            3 => SyxValue::Number(state.load_number()?),
+            4 => SyxValue::String(load_string(state)?),
+            t => {
+                let error = state.undump_error(UndumpReason::ConstantType(t));
+                state.diagnose(error)?;
+                SyxValue::Nil
+            }
+        });
+    }
+    Ok(())
+}
+
+fn load_debug(state: &mut LoadState, function: &mut Function) -> Result<()> {
+    state.section = Section::Debug;
+    let lines = state.load_int()?;
+    for _ in 0..lines {
+        let line = state.load_int()?;
+        function.proto.lineinfo.push(line);
+    }
+    let size = state.load_int()?;
+    for _ in 0..size {
+        function.proto.locvars.push(LocVar {
+            varname: load_string(state)?,
+            startpc: state.load_int()?,
+            endpc: state.load_int()?,
+        });
+    }
+    let names = state.load_int()?;
+    for _ in 0..names {
+        let name = load_string(state)?;
+        function.upvalue_names.push(name);
+    }
+    Ok(())
+}
+
+fn load_function(state: &mut LoadState, source: &str, integral: bool)
+    -> Result<Function>
+{
+    state.section = Section::Function;
+    let mut proto = Proto::new();
+    let loaded_source = load_string(state)?;
+    proto.source = if !loaded_source.is_empty() {
+        state.source_name(&loaded_source)?
+    } else {
+        source.to_owned()
+    };
+    proto.linedefined = state.load_int()?;
+    proto.lastlinedefined = state.load_int()?;
+    let nups = state.load::<u8>()?;
+    proto.numparams = state.load::<u8>()?;
+    proto.is_vararg = (state.load::<u8>()? & VARARG_ISVARARG != 0) as u8;
+    proto.maxstacksize = state.load::<u8>()?;
+
+    state.section = Section::Code;
+    let count = state.load_count("instruction")?;
+    let mut code = Vec::with_capacity(state.reservation(count));
+    for _ in 0..count {
+        code.push(state.load::<Word>()?);
+    }
+    load_constants(state, &mut proto, integral)?;
+
+    let count = state.load_int()?;
+    let mut protos = Vec::new();
+    for _ in 0..count {
+        protos.push(load_function(state, &proto.source, integral)?);
+    }
+
+    let mut function = Function {
+        proto,
+        nups,
+        code,
+        upvalue_names: vec![],
+        protos,
+    };
+    load_debug(state, &mut function)?;
+    Ok(function)
+}
+
+fn abc(instruction: OpCode, a: u8, b: u16, c: u16) -> Instruction {
+    Instruction::ABC { instruction, a, b, c }
+}
+
+fn nop() -> Instruction {
+    Instruction::AsBx { instruction: OpCode::Jmp, a: 0, sbx: 0 }
+}
+
+fn global_key(state: &mut LoadState, bx: u32) -> Result<u16> {
+    // 5.3 only has RK operands for table keys, which are one bit shorter
+    state.assert_verification(
+        bx <= MAXINDEXRK,
+        format!("global name constant out of RK range: {}", bx))?;
+    Ok(bx as u16 | BITRK)
+}
+
+// `upvalues` are the descriptors found after the CLOSURE in the parent (or
+// `_ENV` alone for the main function), with the `_ENV` descriptor last
+fn translate(state: &mut LoadState, function: Function, upvalues: Vec<Upvalue>)
+    -> Result<Proto>
+{
+    let Function { mut proto, nups, code, upvalue_names, protos } = function;
+    let env = u16::from(nups);
+    let mut descriptors: Vec<Option<Vec<Upvalue>>> = protos.iter()
+        .map(|_| None)
+        .collect();
+    let mut instructions = Vec::with_capacity(code.len());
+
+    let mut pc = 0;
+    while pc < code.len() {
+        let word = code[pc];
+        let op = word & 0x3F;
+        let a = ((word >> 6) & 0xFF) as u8;
+        let c = ((word >> 14) & 0x1FF) as u16;
+        let b = ((word >> 23) & 0x1FF) as u16;
+        let bx = (word >> 14) & 0x3FFFF;
+        let sbx = bx as i32 - MAXARG_SBX;
+        pc += 1;
+        let instruction = match op {
+            0 => abc(OpCode::Move, a, b, 0),
+            1 => Instruction::ABx { instruction: OpCode::LoadK, a, bx },
+            2 => abc(OpCode::LoadBool, a, b, c),
+            3 => {
+                // R(A) .. R(B) in 5.1, R(A) .. R(A + B) in 5.3
+                state.assert_verification(b >= u16::from(a), "bad LOADNIL range")?;
+                abc(OpCode::LoadNil, a, b - u16::from(a), 0)
+            },
+            4 => abc(OpCode::GetUpval, a, b, 0),
+            5 => {
+                let key = global_key(state, bx)?;
+                abc(OpCode::GetTabUp, a, env, key)
+            },
+            6 => abc(OpCode::GetTable, a, b, c),
+            7 => {
+                let key = global_key(state, bx)?;
+                abc(OpCode::SetTabUp, env as u8, key, u16::from(a))
+            },
+            8 => abc(OpCode::SetUpval, a, b, 0),
+            9 => abc(OpCode::SetTable, a, b, c),
+            10 => abc(OpCode::NewTable, a, b, c),
+            11 => abc(OpCode::SelfLoad, a, b, c),
+            12 => abc(OpCode::Add, a, b, c),
+            13 => abc(OpCode::Sub, a, b, c),
+            14 => abc(OpCode::Mul, a, b, c),
+            15 => abc(OpCode::Div, a, b, c),
+            16 => abc(OpCode::Mod, a, b, c),
+            17 => abc(OpCode::Pow, a, b, c),
+            18 => abc(OpCode::Unm, a, b, 0),
+            19 => abc(OpCode::Not, a, b, 0),
+            20 => abc(OpCode::Len, a, b, 0),
+            21 => abc(OpCode::Concat, a, b, c),
+            22 => Instruction::AsBx { instruction: OpCode::Jmp, a: 0, sbx },
+            23 => abc(OpCode::Eq, a, b, c),
+            24 => abc(OpCode::Lt, a, b, c),
+            25 => abc(OpCode::Le, a, b, c),
+            26 => abc(OpCode::Test, a, 0, c),
+            27 => abc(OpCode::TestSet, a, b, c),
+            28 => abc(OpCode::Call, a, b, c),
+            29 => abc(OpCode::TailCall, a, b, c),
+            30 => abc(OpCode::Return, a, b, 0),
+            31 => Instruction::AsBx { instruction: OpCode::ForLoop, a, sbx },
+            32 => Instruction::AsBx { instruction: OpCode::ForPrep, a, sbx },
+            33 => {
+                // The JMP back to the loop body always follows TFORLOOP, and
+                // is where the 5.3 TFORLOOP goes
+                let jump = code.get(pc).cloned().unwrap_or(0);
+                state.assert_verification(jump & 0x3F == 22,
+                                          "TFORLOOP not followed by JMP")?;
+                let sbx = ((jump >> 14) & 0x3FFFF) as i32 - MAXARG_SBX;
+                pc += 1;
+                instructions.push(abc(OpCode::TForCall, a, 0, c));
+                Instruction::AsBx {
+                    instruction: OpCode::TForLoop,
+                    a: a.wrapping_add(2),
+                    sbx,
+                }
+            },
+            34 => {
+                instructions.push(abc(OpCode::SetList, a, b, c));
+                if c != 0 {
+                    continue;
+                }
+                // the real C is stored as a raw word, EXTRAARG in 5.3
+                let ax = code.get(pc).cloned().unwrap_or(0);
+                pc += 1;
+                Instruction::Ax { instruction: OpCode::ExtraArg, ax }
+            },
+            // close upvalues >= R(A)
+            35 => Instruction::AsBx { instruction: OpCode::Jmp, a: a.saturating_add(1), sbx: 0 },
+            36 => {
+                let child = protos.get(bx as usize).ok_or_else(|| {
+                    state.verification_error(format!("bad function index: {}", bx))
+                })?;
+                instructions.push(Instruction::ABx {
+                    instruction: OpCode::Closure,
+                    a,
+                    bx,
+                });
+                // one pseudo-instruction per upvalue, each becoming a no-op
+                let mut found = Vec::with_capacity(child.nups as usize + 1);
+                for _ in 0..child.nups {
+                    let pseudo = code.get(pc).cloned().unwrap_or(0);
+                    let instack = match pseudo & 0x3F {
+                        0 => 1,
+                        4 => 0,
+                        _ => return Err(state.verification_error(
+                            "bad upvalue pseudo-instruction")),
+                    };
+                    found.push(Upvalue {
+                        name: state.intern(&[]),
+                        instack,
+                        idx: ((pseudo >> 23) & 0x1FF) as u8,
+                    });
+                    instructions.push(nop());
+                    pc += 1;
+                }
+                found.push(Upvalue {
+                    name: state.intern(&[]),
+                    instack: 0,
+                    idx: env as u8,
+                });
+                descriptors[bx as usize] = Some(found);
+                continue;
+            },
+            37 => abc(OpCode::VarArg, a, b, 0),
+            _ => return Err(ErrorKind::InvalidOpCode.into()),
+        };
+        instructions.push(instruction);
+    }
+
+    let mut children = Vec::with_capacity(protos.len());
+    for (child, found) in protos.into_iter().zip(descriptors) {
+        let found = match found {
+            Some(found) => found,
+            None => {
+                // never closed over, so the descriptors are never used either
+                let mut found: Vec<_> = (0..=child.nups)
+                    .map(|_| Upvalue { name: state.intern(&[]), instack: 0, idx: 0 })
+                    .collect();
+                found[child.nups as usize].idx = env as u8;
+                found
+            }
+        };
+        children.push(Arc::new(translate(state, child, found)?));
+    }
+
+    proto.upvalues = upvalues;
+    for (upvalue, name) in proto.upvalues.iter_mut().zip(upvalue_names) {
+        upvalue.name = name;
+    }
+    if let Some(upvalue) = proto.upvalues.last_mut() {
+        upvalue.name = state.intern(b"_ENV");
+    }
+    proto.instructions = instructions;
+    proto.protos = children;
+    Ok(proto)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn op_abc(op: u32, a: u32, b: u32, c: u32) -> u32 {
+        op | (a << 6) | (c << 14) | (b << 23)
+    }
+
+    fn op_abx(op: u32, a: u32, bx: u32) -> u32 {
+        op | (a << 6) | (bx << 14)
+    }
+
+    struct Builder(Vec<u8>);
+
+    impl Builder {
+        fn int(&mut self, value: i32) {
+            self.0.extend_from_slice(&value.to_ne_bytes());
+        }
+
+        fn string(&mut self, value: &[u8]) {
+            self.0.extend_from_slice(&(value.len() + 1).to_ne_bytes());
+            self.0.extend_from_slice(value);
+            self.0.push(0);
+        }
+
+        fn code(&mut self, code: &[u32]) {
+            self.int(code.len() as i32);
+            for word in code {
+                self.0.extend_from_slice(&word.to_ne_bytes());
+            }
+        }
+    }
+
+    // local a = 1.0
+    // local function f() y = a end
+    // local b = x
+    fn chunk() -> Vec<u8> {
+        let mut b = Builder(vec![]);
+        b.0.extend_from_slice(b"\x1bLua\x51\x00");
+        b.0.push(cfg!(target_endian = "little") as u8);
+        b.0.extend_from_slice(&[4, ::std::mem::size_of::<usize>() as u8, 4, 8, 0]);
+
+        b.string(b"@test.lua");
+        b.int(0);
+        b.int(0);
+        b.0.extend_from_slice(&[0, 0, 2, 3]);
+        b.code(&[
+            op_abx(1, 0, 0),      // LOADK 0 K0
+            op_abx(36, 1, 0),     // CLOSURE 1 P0
+            op_abc(0, 0, 0, 0),   // MOVE 0 0, upvalue a
+            op_abx(5, 2, 1),      // GETGLOBAL 2 K1
+            op_abc(30, 0, 1, 0),  // RETURN 0 1
+        ]);
+        b.int(2);
+        b.0.push(3);
+        b.0.extend_from_slice(&1.0f64.to_ne_bytes());
+        b.0.push(4);
+        b.string(b"x");
+
+        b.int(1);
+        {
+            b.0.extend_from_slice(&0usize.to_ne_bytes()); // source
+            b.int(2);
+            b.int(2);
+            b.0.extend_from_slice(&[1, 0, 0, 2]);
+            b.code(&[
+                op_abc(4, 0, 0, 0),   // GETUPVAL 0 0
+                op_abx(7, 0, 0),      // SETGLOBAL 0 K0
+                op_abc(30, 0, 1, 0),  // RETURN 0 1
+            ]);
+            b.int(1);
+            b.0.push(4);
+            b.string(b"y");
+            b.int(0);
+            b.int(0);
+            b.int(0);
+            b.int(1);
+            b.string(b"a");
+        }
+
+        b.int(0);
+        b.int(0);
+        b.int(0);
+        b.0
+    }
+
+    #[test]
+    fn test_load_lua51() {
+        let proto = LoadState::from_u8(chunk(), "lua51").unwrap();
+        assert_eq!(proto.source, "@test.lua");
+        assert_eq!(proto.upvalues.len(), 1);
+        assert_eq!(proto.upvalues[0].name, b"_ENV");
+        assert_eq!(proto.upvalues[0].instack, 1);
+        match proto.constants[0] {
+            SyxValue::Number(n) => assert_eq!(n, 1.0),
+            _ => panic!("expected number constant"),
+        }
+        assert_eq!(proto.instructions, vec![
+            Instruction::ABx { instruction: OpCode::LoadK, a: 0, bx: 0 },
+            Instruction::ABx { instruction: OpCode::Closure, a: 1, bx: 0 },
+            nop(),
+            abc(OpCode::GetTabUp, 2, 0, BITRK | 1),
+            abc(OpCode::Return, 0, 1, 0),
+        ]);
+
+        let child = &proto.protos[0];
+        assert_eq!(child.source, "@test.lua");
+        assert_eq!(child.upvalues.len(), 2);
+        assert_eq!(child.upvalues[0].name, b"a");
+        assert_eq!((child.upvalues[0].instack, child.upvalues[0].idx), (1, 0));
+        assert_eq!(child.upvalues[1].name, b"_ENV");
+        assert_eq!((child.upvalues[1].instack, child.upvalues[1].idx), (0, 0));
+        assert_eq!(child.instructions, vec![
+            abc(OpCode::GetUpval, 0, 0, 0),
+            abc(OpCode::SetTabUp, 1, BITRK, 0),
+            abc(OpCode::Return, 0, 1, 0),
+        ]);
+    }
+}
@@ -0,0 +1,193 @@
+// Lua 5.2 binary chunks
+//
+// 5.2 already has `_ENV` upvalues and upvalue descriptors, so only the layout
+// needs mapping:
+//
+// * the header is the 5.1 one followed by LUAC_TAIL (the same bytes as
+//   SYX_DATA), and there is no upvalue count before the main function
+// * strings and constants are encoded like 5.1
+// * the source name is part of the debug section of every function
+// * the instruction set lacks the 5.3 integer division and bitwise opcodes,
+//   so opcode numbers are remapped, everything else is unchanged
+
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+use super::super::conf::SYX_DATA;
+use super::super::object::{LocVar, Proto, Upvalue};
+use super::super::opcodes::{Instruction, OpCode, Word};
+use super::super::undump::LoadState;
+use super::super::errors::*;
+use super::lua51;
+
+// 5.2 opcodes, in order
+const OPCODES: [OpCode; 40] = [
+    OpCode::Move, OpCode::LoadK, OpCode::LoadKX, OpCode::LoadBool,
+    OpCode::LoadNil, OpCode::GetUpval, OpCode::GetTabUp, OpCode::GetTable,
+    OpCode::SetTabUp, OpCode::SetUpval, OpCode::SetTable, OpCode::NewTable,
+    OpCode::SelfLoad, OpCode::Add, OpCode::Sub, OpCode::Mul, OpCode::Div,
+    OpCode::Mod, OpCode::Pow, OpCode::Unm, OpCode::Not, OpCode::Len,
+    OpCode::Concat, OpCode::Jmp, OpCode::Eq, OpCode::Lt, OpCode::Le,
+    OpCode::Test, OpCode::TestSet, OpCode::Call, OpCode::TailCall,
+    OpCode::Return, OpCode::ForLoop, OpCode::ForPrep, OpCode::TForCall,
+    OpCode::TForLoop, OpCode::SetList, OpCode::Closure, OpCode::VarArg,
+    OpCode::ExtraArg,
+];
+
+pub(crate) fn load_chunk(state: &mut LoadState) -> Result<Proto> {
+    let integral = lua51::check_header(state)?;
+    state.check_literal(SYX_DATA, "load order verification")?;
+    let mut proto = Proto::new();
+    load_function(state, &mut proto, integral)?;
+    Ok(proto)
+}
+
+fn translate(word: Word) -> Result<Instruction> {
+    let op = (word & 0x3F) as usize;
+    let opcode = OPCODES.get(op).ok_or(ErrorKind::InvalidOpCode)?;
+    Instruction::try_from((word & !0x3F) | *opcode as Word)
+}
+
+fn load_function(state: &mut LoadState, proto: &mut Proto, integral: bool)
+    -> Result<()>
+{
+    state.section = Section::Function;
+    proto.linedefined = state.load_int()?;
+    proto.lastlinedefined = state.load_int()?;
+    proto.numparams = state.load::<u8>()?;
+    proto.is_vararg = state.load::<u8>()?;
+    proto.maxstacksize = state.load::<u8>()?;
+
+    state.section = Section::Code;
+    let count = state.load_int()?;
+    for _ in 0..count {
+        let word = state.load::<Word>()?;
+        proto.instructions.push(translate(word)?);
+    }
+    lua51::load_constants(state, proto, integral)?;
+
+    let count = state.load_int()?;
+    for _ in 0..count {
+        let mut child = Proto::new();
+        load_function(state, &mut child, integral)?;
+        proto.protos.push(Arc::new(child));
+    }
+
+    state.section = Section::Upvalues;
+    let count = state.load_int()?;
+    for _ in 0..count {
+        proto.upvalues.push(Upvalue {
+            name: state.intern(&[]),
+            instack: state.load::<u8>()?,
+            idx: state.load::<u8>()?,
+        });
+    }
+
+    state.section = Section::Debug;
+    let source = lua51::load_string(state)?;
+    proto.source = state.source_name(&source)?;
+    let lines = state.load_int()?;
+    for _ in 0..lines {
+        let line = state.load_int()?;
+        proto.lineinfo.push(line);
+    }
+    let size = state.load_int()?;
+    for _ in 0..size {
+        proto.locvars.push(LocVar {
+            varname: lua51::load_string(state)?,
+            startpc: state.load_int()?,
+            endpc: state.load_int()?,
+        });
+    }
+    let names = state.load_int()?;
+    for i in 0..names {
+        let name = lua51::load_string(state)?;
+        match proto.upvalues.get_mut(i as usize) {
+            Some(value) => value.name = name,
+            None => {
+                let error = state.undump_error(UndumpReason::UpvalueIndex(i as usize));
+                state.diagnose(error)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::super::object::SyxValue;
+
+    struct Builder(Vec<u8>);
+
+    impl Builder {
+        fn int(&mut self, value: i32) {
+            self.0.extend_from_slice(&value.to_ne_bytes());
+        }
+
+        fn string(&mut self, value: &[u8]) {
+            self.0.extend_from_slice(&(value.len() + 1).to_ne_bytes());
+            self.0.extend_from_slice(value);
+            self.0.push(0);
+        }
+    }
+
+    // print(10 / 4)
+    fn chunk() -> Vec<u8> {
+        let mut b = Builder(vec![]);
+        b.0.extend_from_slice(b"\x1bLua\x52\x00");
+        b.0.push(cfg!(target_endian = "little") as u8);
+        b.0.extend_from_slice(&[4, ::std::mem::size_of::<usize>() as u8, 4, 8, 0]);
+        b.0.extend_from_slice(SYX_DATA);
+
+        b.int(0);
+        b.int(0);
+        b.0.extend_from_slice(&[0, 1, 3]);
+        b.int(4);
+        for word in &[0x0040_0006u32, // GETTABUP 0 0 K0
+                      0x80C0_8050,    // DIV 1 K1 K2
+                      0x0100_401D,    // CALL 0 2 1
+                      0x0080_001F] {  // RETURN 0 1
+            b.0.extend_from_slice(&word.to_ne_bytes());
+        }
+        b.int(3);
+        b.0.push(4);
+        b.string(b"print");
+        b.0.push(3);
+        b.0.extend_from_slice(&10.0f64.to_ne_bytes());
+        b.0.push(3);
+        b.0.extend_from_slice(&4.0f64.to_ne_bytes());
+        b.int(0);
+        b.int(1);
+        b.0.extend_from_slice(&[1, 0]);
+        b.string(b"=stdin");
+        b.int(4);
+        for _ in 0..4 {
+            b.int(1);
+        }
+        b.int(0);
+        b.int(1);
+        b.string(b"_ENV");
+        b.0
+    }
+
+    #[test]
+    fn test_load_lua52() {
+        let proto = LoadState::from_u8(chunk(), "lua52").unwrap();
+        assert_eq!(proto.source, "=stdin");
+        assert_eq!(proto.is_vararg, 1);
+        assert_eq!(proto.upvalues.len(), 1);
+        assert_eq!(proto.upvalues[0].name, b"_ENV");
+        assert_eq!(proto.lineinfo, vec![1, 1, 1, 1]);
+        match proto.constants[1] {
+            SyxValue::Number(n) => assert_eq!(n, 10.0),
+            _ => panic!("expected number constant"),
+        }
+        assert_eq!(proto.instructions, vec![
+            Instruction::ABC { instruction: OpCode::GetTabUp, a: 0, b: 0, c: 256 },
+            Instruction::ABC { instruction: OpCode::Div, a: 1, b: 257, c: 258 },
+            Instruction::ABC { instruction: OpCode::Call, a: 0, b: 2, c: 1 },
+            Instruction::ABC { instruction: OpCode::Return, a: 0, b: 1, c: 0 },
+        ]);
+    }
+}
@@ -0,0 +1,704 @@
+// Lua 5.4 binary chunks
+//
+// Layout differences from 5.3:
+//
+// * the header drops the int/size_t widths, since every count, size and
+//   line number is written as a variable-length integer (7 bits per byte,
+//   the last byte has its high bit set)
+// * constant tags carry variants: false/true are separate tags, and the
+//   integer/float tags are the other way around
+// * upvalue descriptors have an extra `kind` byte
+// * line info is a signed byte delta per instruction, with `abslineinfo`
+//   anchors every so often (and wherever a delta does not fit)
+//
+// The 5.4 instruction set is re-encoded (7 bit opcodes, a `k` flag, 8 bit B
+// and C) and has many new opcodes. Most translate one to one onto 5.3: the
+// immediate and constant operand variants become RK operands, with
+// immediates appended to the constant table, and the metamethod fallbacks
+// (MMBIN*) and VARARGPREP become no-ops.
+//
+// The generic `for` keeps a fourth value, the one to close, between its
+// control variable and the loop variables. TFORPREP moves the iterator, state
+// and control up a register over it, so TFORCALL and TFORLOOP can run as 5.3
+// ones a register higher; the value to close is dropped. That takes more
+// instructions than one, so jumps, lines and local ranges are moved to match.
+// Anything else with to-be-closed variables (TBC) can not be translated, and
+// a chunk with it fails to load.
+
+use std::sync::Arc;
+
+use super::super::object::{
+    integer_value, AbsLineInfo, LocVar, Proto, SyxInt, SyxInteger, SyxNumber, SyxString,
+    SyxValue, Upvalue,
+};
+use super::super::opcodes::{Instruction, OpCode, Word};
+use super::super::undump::LoadState;
+use super::super::conf::SYX_DATA;
+use super::super::errors::*;
+
+const LUA54_FORMAT: u8 = 0;
+
+const LUA_VNIL: u8 = 0;
+const LUA_VFALSE: u8 = 1;
+const LUA_VTRUE: u8 = 1 | (1 << 4);
+const LUA_VNUMINT: u8 = 3;
+const LUA_VNUMFLT: u8 = 3 | (1 << 4);
+const LUA_VSHRSTR: u8 = 4;
+const LUA_VLNGSTR: u8 = 4 | (1 << 4);
+
+// marks an instruction whose line is found in abslineinfo
+const ABSLINEINFO: i8 = -0x80;
+
+const OFFSET_SC: i32 = 127;
+const OFFSET_SBX: i32 = (1 << 16) - 1;
+const OFFSET_SJ: i32 = (1 << 24) - 1;
+
+// 5.3 limits for the translated operands
+const BITRK: u16 = 1 << 8;
+const MAXINDEXRK: usize = (BITRK - 1) as usize;
+const MAXARG_BX: usize = (1 << 18) - 1;
+const MAXARG_SBX: i32 = (1 << 17) - 1;
+const MAXARG_C: u32 = (1 << 9) - 1;
+const LFIELDS_PER_FLUSH: u32 = 50;
+
+const OPNAMES: [&str; 83] = [
+    "MOVE", "LOADI", "LOADF", "LOADK", "LOADKX", "LOADFALSE", "LFALSESKIP",
+    "LOADTRUE", "LOADNIL", "GETUPVAL", "SETUPVAL", "GETTABUP", "GETTABLE",
+    "GETI", "GETFIELD", "SETTABUP", "SETTABLE", "SETI", "SETFIELD",
+    "NEWTABLE", "SELF", "ADDI", "ADDK", "SUBK", "MULK", "MODK", "POWK",
+    "DIVK", "IDIVK", "BANDK", "BORK", "BXORK", "SHRI", "SHLI", "ADD", "SUB",
+    "MUL", "MOD", "POW", "DIV", "IDIV", "BAND", "BOR", "BXOR", "SHL", "SHR",
+    "MMBIN", "MMBINI", "MMBINK", "UNM", "BNOT", "NOT", "LEN", "CONCAT",
+    "CLOSE", "TBC", "JMP", "EQ", "LT", "LE", "EQK", "EQI", "LTI", "LEI",
+    "GTI", "GEI", "TEST", "TESTSET", "CALL", "TAILCALL", "RETURN", "RETURN0",
+    "RETURN1", "FORLOOP", "FORPREP", "TFORPREP", "TFORCALL", "TFORLOOP",
+    "SETLIST", "CLOSURE", "VARARG", "VARARGPREP", "EXTRAARG",
+];
+
+pub(crate) fn load_chunk(state: &mut LoadState) -> Result<Proto> {
+    check_header(state)?;
+    let _upvals = state.load::<u8>()?;
+    let mut proto = Proto::new();
+    load_function(state, &mut proto, "")?;
+    Ok(proto)
+}
+
+fn check_header(state: &mut LoadState) -> Result<()> {
+    let format = state.load::<u8>()?;
+    state.assert_verification(format == LUA54_FORMAT, "format mismatch")?;
+    state.check_literal(SYX_DATA, "load order verification")?;
+    state.check_width("Instruction", &[::std::mem::size_of::<Word>()])?;
+    state.sizes.integer = state.check_width("lua_Integer", &[4, 8])?;
+    state.sizes.number = state.check_width("lua_Number", &[4, 8])?;
+    state.check_numbers()
+}
+
+fn load_unsigned(state: &mut LoadState, limit: u64) -> Result<u64> {
+    let mut x: u64 = 0;
+    loop {
+        let byte = state.load::<u8>()?;
+        if x > (limit >> 7) {
+            return Err(state.verification_error("integer overflow"));
+        }
+        x = (x << 7) | u64::from(byte & 0x7F);
+        if byte & 0x80 != 0 {
+            return Ok(x);
+        }
+    }
+}
+
+fn load_size(state: &mut LoadState) -> Result<usize> {
+    Ok(load_unsigned(state, usize::MAX as u64)? as usize)
+}
+
+fn load_int(state: &mut LoadState) -> Result<SyxInt> {
+    Ok(load_unsigned(state, SyxInt::MAX as u64)? as SyxInt)
+}
+
+fn load_string(state: &mut LoadState) -> Result<SyxString> {
+    match load_size(state)? {
+        0 => Ok(state.intern(&[])),
+        size => {
+            let bytes = state.load_range(size - 1)?;
+            Ok(state.intern(&bytes))
+        }
+    }
+}
+
+fn load_constants(state: &mut LoadState, proto: &mut Proto) -> Result<()> {
+    state.section = Section::Constants;
+    let count = load_int(state)?;
+    for _ in 0..count {
+        proto.constants.push(match state.load::<u8>()? {
+            LUA_VNIL => SyxValue::Nil,
+            LUA_VFALSE => SyxValue::Bool(false),
+            LUA_VTRUE => SyxValue::Bool(true),
+            LUA_VNUMINT => integer_value(state.load_integer()?),
+            LUA_VNUMFLT => SyxValue::Number(state.load_number()?),
+            LUA_VSHRSTR | LUA_VLNGSTR => SyxValue::String(load_string(state)?),
+            t => {
+                let error = state.undump_error(UndumpReason::ConstantType(t));
+                state.diagnose(error)?;
+                SyxValue::Nil
+            }
+        });
+    }
+    Ok(())
+}
+
+fn load_debug(state: &mut LoadState, proto: &mut Proto) -> Result<()> {
+    state.section = Section::Debug;
+    let count = load_int(state)?;
+    let mut deltas = Vec::with_capacity(state.reservation(count as usize));
+    for _ in 0..count {
+        deltas.push(state.load::<i8>()?);
+    }
+    let count = load_int(state)?;
+    for _ in 0..count {
+        proto.abslineinfo.push(AbsLineInfo {
+            pc: load_int(state)?,
+            line: load_int(state)?,
+        });
+    }
+    // Resolve to absolute lines, the same way luaG_getfuncline would, so
+    // everything downstream can keep using lineinfo as-is
+    let mut line = proto.linedefined;
+    for (pc, delta) in deltas.into_iter().enumerate() {
+        if delta == ABSLINEINFO {
+            match proto.abslineinfo.iter().find(|abs| abs.pc as usize == pc) {
+                Some(abs) => line = abs.line,
+                None => return Err(state.verification_error(
+                    format!("missing abslineinfo for pc {}", pc))),
+            }
+        } else {
+            line += SyxInt::from(delta);
+        }
+        proto.lineinfo.push(line);
+    }
+
+    let count = load_int(state)?;
+    for _ in 0..count {
+        proto.locvars.push(LocVar {
+            varname: load_string(state)?,
+            startpc: load_int(state)?,
+            endpc: load_int(state)?,
+        });
+    }
+    let count = load_int(state)?;
+    for i in 0..count as usize {
+        let name = load_string(state)?;
+        match proto.upvalues.get_mut(i) {
+            Some(value) => value.name = name,
+            None => {
+                let error = state.undump_error(UndumpReason::UpvalueIndex(i));
+                state.diagnose(error)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn load_function(state: &mut LoadState, proto: &mut Proto, source: &str)
+    -> Result<()>
+{
+    state.section = Section::Function;
+    let loaded_source = load_string(state)?;
+    proto.source = if !loaded_source.is_empty() {
+        state.source_name(&loaded_source)?
+    } else {
+        source.to_owned()
+    };
+    proto.linedefined = load_int(state)?;
+    proto.lastlinedefined = load_int(state)?;
+    proto.numparams = state.load::<u8>()?;
+    proto.is_vararg = state.load::<u8>()?;
+    proto.maxstacksize = state.load::<u8>()?;
+
+    state.section = Section::Code;
+    let count = load_int(state)?;
+    let mut code = Vec::with_capacity(state.reservation(count as usize));
+    for _ in 0..count {
+        code.push(state.load::<Word>()?);
+    }
+    load_constants(state, proto)?;
+
+    state.section = Section::Upvalues;
+    let count = load_int(state)?;
+    for _ in 0..count {
+        let instack = state.load::<u8>()?;
+        let idx = state.load::<u8>()?;
+        let _kind = state.load::<u8>()?;
+        let name = state.intern(&[]);
+        proto.upvalues.push(Upvalue { name, instack, idx });
+    }
+
+    let count = load_int(state)?;
+    for _ in 0..count {
+        let mut child = Proto::new();
+        load_function(state, &mut child, &proto.source)?;
+        proto.protos.push(Arc::new(child));
+    }
+    load_debug(state, proto)?;
+    state.section = Section::Code;
+    proto.instructions = translate(state, proto, &code)?;
+    Ok(())
+}
+
+// Operand access for 5.4 instruction words
+struct Op54(Word);
+
+impl Op54 {
+    fn op(&self) -> usize { (self.0 & 0x7F) as usize }
+    fn a(&self) -> u8 { ((self.0 >> 7) & 0xFF) as u8 }
+    fn k(&self) -> u16 { ((self.0 >> 15) & 1) as u16 }
+    fn b(&self) -> u16 { ((self.0 >> 16) & 0xFF) as u16 }
+    fn c(&self) -> u16 { ((self.0 >> 24) & 0xFF) as u16 }
+    fn sb(&self) -> SyxInteger { SyxInteger::from(self.b() as i32 - OFFSET_SC) }
+    fn sc(&self) -> SyxInteger { SyxInteger::from(self.c() as i32 - OFFSET_SC) }
+    fn bx(&self) -> u32 { self.0 >> 15 }
+    fn sbx(&self) -> i32 { self.bx() as i32 - OFFSET_SBX }
+    fn ax(&self) -> u32 { self.0 >> 7 }
+    fn sj(&self) -> i32 { (self.0 >> 7) as i32 - OFFSET_SJ }
+    // C as an RK operand: a constant when k is set, a register otherwise
+    fn rkc(&self) -> u16 { self.c() | (self.k() << 8) }
+}
+
+fn abc(instruction: OpCode, a: u8, b: u16, c: u16) -> Instruction {
+    Instruction::ABC { instruction, a, b, c }
+}
+
+fn asbx(instruction: OpCode, a: u8, sbx: i32) -> Instruction {
+    Instruction::AsBx { instruction, a, sbx }
+}
+
+fn nop() -> Instruction {
+    asbx(OpCode::Jmp, 0, 0)
+}
+
+// Find or append a constant for an immediate operand
+fn constant(state: &LoadState, proto: &mut Proto, value: SyxValue) -> Result<u32> {
+    let found = proto.constants.iter().position(|k| match (k, &value) {
+        (&SyxValue::Integer(x), &SyxValue::Integer(y)) => x == y,
+        (&SyxValue::Number(x), &SyxValue::Number(y)) => x.to_bits() == y.to_bits(),
+        _ => false,
+    });
+    let index = found.unwrap_or_else(|| {
+        proto.constants.push(value);
+        proto.constants.len() - 1
+    });
+    assert_range(state, index <= MAXARG_BX, "constant")?;
+    Ok(index as u32)
+}
+
+fn rk_integer(state: &LoadState, proto: &mut Proto, value: SyxInteger) -> Result<u16> {
+    let index = constant(state, proto, integer_value(value))? as usize;
+    assert_range(state, index <= MAXINDEXRK, "constant")?;
+    Ok(index as u16 | BITRK)
+}
+
+fn jump(state: &LoadState, sbx: i32) -> Result<i32> {
+    assert_range(state, (-MAXARG_SBX..=MAXARG_SBX).contains(&sbx), "jump")?;
+    Ok(sbx)
+}
+
+fn setlist_block(state: &LoadState, count: u32) -> Result<u32> {
+    // 5.4 stores the number of items already set, 5.3 the block number
+    assert_range(state, count.is_multiple_of(LFIELDS_PER_FLUSH), "SETLIST")?;
+    Ok(count / LFIELDS_PER_FLUSH + 1)
+}
+
+// a register `n` above `a`
+fn register(state: &LoadState, a: u8, n: u8) -> Result<u8> {
+    let register = a.checked_add(n);
+    assert_range(state, register.is_some(), "register")?;
+    Ok(register.unwrap_or(a))
+}
+
+fn assert_range(state: &LoadState, val: bool, what: &str) -> Result<()> {
+    if val {
+        Ok(())
+    } else {
+        Err(state.verification_error(
+            format!("{} out of range for 5.3 encoding", what)))
+    }
+}
+
+fn translate(state: &mut LoadState, proto: &mut Proto, code: &[Word])
+    -> Result<Vec<Instruction>>
+{
+    let mut instructions = Vec::with_capacity(code.len());
+    // where the translation of each instruction starts, jumps still being
+    // counted in 5.4 instructions until they are moved to match
+    let mut start = Vec::with_capacity(code.len() + 1);
+    let mut pc = 0;
+    while pc < code.len() {
+        start.resize(pc + 1, instructions.len());
+        let i = Op54(code[pc]);
+        pc += 1;
+        let a = i.a();
+        let instruction = match i.op() {
+            0 => abc(OpCode::Move, a, i.b(), 0),
+            1 => {
+                let bx = constant(state, proto, integer_value(i.sbx() as SyxInteger))?;
+                Instruction::ABx { instruction: OpCode::LoadK, a, bx }
+            },
+            2 => {
+                let value = SyxValue::Number(i.sbx() as SyxNumber);
+                let bx = constant(state, proto, value)?;
+                Instruction::ABx { instruction: OpCode::LoadK, a, bx }
+            },
+            3 => Instruction::ABx { instruction: OpCode::LoadK, a, bx: i.bx() },
+            4 => abc(OpCode::LoadKX, a, 0, 0),
+            5 => abc(OpCode::LoadBool, a, 0, 0),
+            6 => abc(OpCode::LoadBool, a, 0, 1),
+            7 => abc(OpCode::LoadBool, a, 1, 0),
+            8 => abc(OpCode::LoadNil, a, i.b(), 0),
+            9 => abc(OpCode::GetUpval, a, i.b(), 0),
+            10 => abc(OpCode::SetUpval, a, i.b(), 0),
+            11 => abc(OpCode::GetTabUp, a, i.b(), i.c() | BITRK),
+            12 => abc(OpCode::GetTable, a, i.b(), i.c()),
+            13 => {
+                let key = rk_integer(state, proto, SyxInteger::from(i.c()))?;
+                abc(OpCode::GetTable, a, i.b(), key)
+            },
+            14 => abc(OpCode::GetTable, a, i.b(), i.c() | BITRK),
+            15 => abc(OpCode::SetTabUp, a, i.b() | BITRK, i.rkc()),
+            16 => abc(OpCode::SetTable, a, i.b(), i.rkc()),
+            17 => {
+                let key = rk_integer(state, proto, SyxInteger::from(i.b()))?;
+                abc(OpCode::SetTable, a, key, i.rkc())
+            },
+            18 => abc(OpCode::SetTable, a, i.b() | BITRK, i.rkc()),
+            // sizes are only hints, and the EXTRAARG that follows is skipped
+            19 => abc(OpCode::NewTable, a, 0, 0),
+            20 => abc(OpCode::SelfLoad, a, i.b(), i.rkc()),
+            21 => {
+                let value = rk_integer(state, proto, i.sc())?;
+                abc(OpCode::Add, a, i.b(), value)
+            },
+            op @ 22..=31 => {
+                let instruction = [
+                    OpCode::Add, OpCode::Sub, OpCode::Mul, OpCode::Mod,
+                    OpCode::Pow, OpCode::Div, OpCode::IDiv, OpCode::BAnd,
+                    OpCode::BOr, OpCode::BXOr,
+                ][op - 22];
+                abc(instruction, a, i.b(), i.c() | BITRK)
+            },
+            32 => {
+                let value = rk_integer(state, proto, i.sc())?;
+                abc(OpCode::Shr, a, i.b(), value)
+            },
+            33 => {
+                let value = rk_integer(state, proto, i.sc())?;
+                abc(OpCode::Shl, a, value, i.b())
+            },
+            op @ 34..=45 => {
+                let instruction = [
+                    OpCode::Add, OpCode::Sub, OpCode::Mul, OpCode::Mod,
+                    OpCode::Pow, OpCode::Div, OpCode::IDiv, OpCode::BAnd,
+                    OpCode::BOr, OpCode::BXOr, OpCode::Shl, OpCode::Shr,
+                ][op - 34];
+                abc(instruction, a, i.b(), i.c())
+            },
+            46..=48 => nop(),
+            49 => abc(OpCode::Unm, a, i.b(), 0),
+            50 => abc(OpCode::BNot, a, i.b(), 0),
+            51 => abc(OpCode::Not, a, i.b(), 0),
+            52 => abc(OpCode::Len, a, i.b(), 0),
+            53 => abc(OpCode::Concat, a, u16::from(a),
+                      (u16::from(a) + i.b()).saturating_sub(1)),
+            54 => asbx(OpCode::Jmp, a.saturating_add(1), 0),
+            56 => asbx(OpCode::Jmp, 0, jump(state, i.sj())?),
+            57 => abc(OpCode::Eq, i.k() as u8, u16::from(a), i.b()),
+            58 => abc(OpCode::Lt, i.k() as u8, u16::from(a), i.b()),
+            59 => abc(OpCode::Le, i.k() as u8, u16::from(a), i.b()),
+            60 => abc(OpCode::Eq, i.k() as u8, u16::from(a), i.b() | BITRK),
+            op @ 61..=65 => {
+                let value = rk_integer(state, proto, i.sb())?;
+                let (instruction, b, c) = match op {
+                    61 => (OpCode::Eq, u16::from(a), value),
+                    62 => (OpCode::Lt, u16::from(a), value),
+                    63 => (OpCode::Le, u16::from(a), value),
+                    64 => (OpCode::Lt, value, u16::from(a)),
+                    _ => (OpCode::Le, value, u16::from(a)),
+                };
+                abc(instruction, i.k() as u8, b, c)
+            },
+            66 => abc(OpCode::Test, a, 0, i.k()),
+            67 => abc(OpCode::TestSet, a, i.b(), i.k()),
+            68 => abc(OpCode::Call, a, i.b(), i.c()),
+            69 => abc(OpCode::TailCall, a, i.b(), 0),
+            70 => abc(OpCode::Return, a, i.b(), 0),
+            71 => abc(OpCode::Return, a, 1, 0),
+            72 => abc(OpCode::Return, a, 2, 0),
+            73 => asbx(OpCode::ForLoop, a, jump(state, -(i.bx() as i32))?),
+            74 => asbx(OpCode::ForPrep, a, jump(state, i.bx() as i32)?),
+            75 => {
+                for n in (0..3).rev() {
+                    let to = register(state, a, n + 1)?;
+                    instructions.push(abc(OpCode::Move, to, u16::from(a + n), 0));
+                }
+                asbx(OpCode::Jmp, 0, jump(state, i.bx() as i32)?)
+            },
+            76 => abc(OpCode::TForCall, register(state, a, 1)?, 0, i.c()),
+            77 => asbx(OpCode::TForLoop, register(state, a, 3)?, jump(state, -(i.bx() as i32))?),
+            78 => {
+                let mut count = u32::from(i.c());
+                if i.k() != 0 {
+                    let extra = Op54(code.get(pc).cloned().unwrap_or(0));
+                    count += extra.ax() * (0xFF + 1);
+                }
+                let block = setlist_block(state, count)?;
+                if block <= MAXARG_C && i.k() == 0 {
+                    abc(OpCode::SetList, a, i.b(), block as u16)
+                } else {
+                    instructions.push(abc(OpCode::SetList, a, i.b(), 0));
+                    if i.k() != 0 {
+                        pc += 1;
+                    }
+                    Instruction::Ax { instruction: OpCode::ExtraArg, ax: block }
+                }
+            },
+            79 => Instruction::ABx { instruction: OpCode::Closure, a, bx: i.bx() },
+            80 => abc(OpCode::VarArg, a, i.c(), 0),
+            81 => nop(),
+            82 => match instructions.last() {
+                Some(&Instruction::ABC { instruction: OpCode::NewTable, .. }) => nop(),
+                _ => Instruction::Ax { instruction: OpCode::ExtraArg, ax: i.ax() },
+            },
+            op => {
+                let name = OPNAMES.get(op).cloned().unwrap_or("unknown");
+                return Err(ErrorKind::UnsupportedOpCode(name.to_owned()).into());
+            },
+        };
+        instructions.push(instruction);
+    }
+    start.resize(code.len() + 1, instructions.len());
+    relocate(state, proto, &mut instructions, &start)?;
+    Ok(instructions)
+}
+
+// Move jumps, lines and local ranges from 5.4 instructions to where their
+// translations start
+fn relocate(state: &LoadState, proto: &mut Proto, instructions: &mut [Instruction],
+            start: &[usize]) -> Result<()>
+{
+    if start.iter().enumerate().all(|(pc, &to)| pc == to) {
+        return Ok(());
+    }
+    let moved = |pc: SyxInt| start.get(pc as usize).map_or(pc, |&to| to as SyxInt);
+    for pc in 0..start.len() - 1 {
+        // a jump is always the last of its translation
+        let last = start[pc + 1].wrapping_sub(1);
+        if let Some(&mut Instruction::AsBx { ref mut sbx, .. }) = instructions.get_mut(last) {
+            let target = (pc as i64 + 1 + i64::from(*sbx)).max(0) as usize;
+            let target = start.get(target).map_or(target as i64, |&to| to as i64);
+            *sbx = jump(state, (target - last as i64 - 1) as i32)?;
+        }
+    }
+    let mut lineinfo = Vec::with_capacity(instructions.len());
+    for (pc, &line) in proto.lineinfo.iter().enumerate() {
+        let count = start.get(pc + 1).map_or(1, |&next| next - start[pc]);
+        lineinfo.extend(::std::iter::repeat_n(line, count));
+    }
+    proto.lineinfo = lineinfo;
+    for abs in &mut proto.abslineinfo {
+        abs.pc = moved(abs.pc);
+    }
+    for local in &mut proto.locvars {
+        local.startpc = moved(local.startpc);
+        local.endpc = moved(local.endpc);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::super::conf::{SYX_HEADER, SYX_INT, SYX_NUM};
+
+    fn varint(out: &mut Vec<u8>, mut value: u64) {
+        let mut bytes = vec![(value & 0x7F) as u8 | 0x80];
+        value >>= 7;
+        while value != 0 {
+            bytes.push((value & 0x7F) as u8);
+            value >>= 7;
+        }
+        bytes.reverse();
+        out.extend_from_slice(&bytes);
+    }
+
+    fn iabc(op: u32, a: u32, k: u32, b: u32, c: u32) -> u32 {
+        op | (a << 7) | (k << 15) | (b << 16) | (c << 24)
+    }
+
+    fn header() -> Vec<u8> {
+        let mut b = Vec::new();
+        b.extend_from_slice(SYX_HEADER);
+        b.extend_from_slice(&[0x54, 0]);
+        b.extend_from_slice(SYX_DATA);
+        b.extend_from_slice(&[4, 8, 8]);
+        b.extend_from_slice(&SYX_INT.to_ne_bytes());
+        b.extend_from_slice(&SYX_NUM.to_ne_bytes());
+        b.push(1);
+        b
+    }
+
+    // local x = 300; x = x + 1; return x
+    fn chunk(last: u32) -> Vec<u8> {
+        let mut b = header();
+        varint(&mut b, 7);
+        b.extend_from_slice(b"=stdin");
+        varint(&mut b, 0);
+        varint(&mut b, 0);
+        b.extend_from_slice(&[0, 1, 2]);
+        let code = [
+            81,                                   // VARARGPREP 0
+            1 | ((300 + 65535) << 15),            // LOADI 0 300
+            iabc(21, 0, 0, 0, 1 + 127),           // ADDI 0 0 1
+            iabc(47, 0, 0, 1 + 127, 6),           // MMBINI 0 1 6
+            last,                                 // RETURN1 0
+        ];
+        varint(&mut b, code.len() as u64);
+        for word in &code {
+            b.extend_from_slice(&word.to_ne_bytes());
+        }
+        varint(&mut b, 1);
+        b.push(LUA_VLNGSTR);
+        varint(&mut b, 4);
+        b.extend_from_slice(b"abc");
+        varint(&mut b, 1);
+        b.extend_from_slice(&[1, 0, 0]);
+        varint(&mut b, 0);
+
+        varint(&mut b, 5);
+        b.extend_from_slice(&[1, 0, ABSLINEINFO as u8, 1, 0]);
+        varint(&mut b, 1);
+        varint(&mut b, 2);
+        varint(&mut b, 200);
+        varint(&mut b, 1);
+        varint(&mut b, 2);
+        b.push(b'x');
+        varint(&mut b, 2);
+        varint(&mut b, 5);
+        varint(&mut b, 1);
+        varint(&mut b, 5);
+        b.extend_from_slice(b"_ENV");
+        b
+    }
+
+    #[test]
+    fn test_load_lua54() {
+        let proto = LoadState::from_u8(chunk(iabc(72, 0, 0, 0, 0)), "lua54").unwrap();
+        assert_eq!(proto.source, "=stdin");
+        assert_eq!(proto.upvalues[0].name, b"_ENV");
+        assert_eq!(proto.locvars[0].varname, b"x");
+        assert_eq!(proto.abslineinfo[0].pc, 2);
+        assert_eq!(proto.abslineinfo[0].line, 200);
+        assert_eq!(proto.lineinfo, vec![1, 1, 200, 201, 201]);
+        match proto.constants[0] {
+            SyxValue::String(ref s) => assert_eq!(s, b"abc"),
+            _ => panic!("expected string constant"),
+        }
+        match (&proto.constants[1], &proto.constants[2]) {
+            #[cfg(not(feature = "float_only"))]
+            (&SyxValue::Integer(300), &SyxValue::Integer(1)) => (),
+            #[cfg(feature = "float_only")]
+            (&SyxValue::Number(x), &SyxValue::Number(y)) if x == 300.0 && y == 1.0 => (),
+            _ => panic!("expected immediates as constants"),
+        }
+        assert_eq!(proto.instructions, vec![
+            nop(),
+            Instruction::ABx { instruction: OpCode::LoadK, a: 0, bx: 1 },
+            abc(OpCode::Add, 0, 0, BITRK | 2),
+            nop(),
+            abc(OpCode::Return, 0, 2, 0),
+        ]);
+    }
+
+    // function(f, s) local total = 0 for _, v in f, s do total = total + v end
+    // return total end, without the MMBIN after its ADD
+    fn generic_for() -> Vec<u8> {
+        let mut b = header();
+        varint(&mut b, 0);
+        varint(&mut b, 0);
+        varint(&mut b, 0);
+        b.extend_from_slice(&[2, 0, 9]);
+        let code = [
+            1 | (2 << 7) | (65535 << 15),         // LOADI 2 0
+            iabc(0, 3, 0, 0, 0),                  // MOVE 3 0
+            iabc(0, 4, 0, 1, 0),                  // MOVE 4 1
+            iabc(8, 5, 0, 1, 0),                  // LOADNIL 5 1
+            75 | (3 << 7) | (1 << 15),            // TFORPREP 3 1
+            iabc(34, 2, 0, 2, 8),                 // ADD 2 2 8
+            iabc(76, 3, 0, 0, 2),                 // TFORCALL 3 2
+            77 | (3 << 7) | (3 << 15),            // TFORLOOP 3 3
+            iabc(72, 2, 0, 0, 0),                 // RETURN1 2
+        ];
+        varint(&mut b, code.len() as u64);
+        for word in &code {
+            b.extend_from_slice(&word.to_ne_bytes());
+        }
+        for _ in 0..3 {
+            // constants, upvalues, protos
+            varint(&mut b, 0);
+        }
+        varint(&mut b, 9);
+        b.extend_from_slice(&[1, 0, 0, 0, 0, 1, 0, 0, 1]);
+        varint(&mut b, 0);
+        varint(&mut b, 1);
+        varint(&mut b, 2);
+        b.push(b'v');
+        varint(&mut b, 5);
+        varint(&mut b, 6);
+        varint(&mut b, 0);
+        b
+    }
+
+    #[test]
+    fn test_generic_for() {
+        use super::super::super::compiler::parse;
+        use super::super::super::state::SyxState;
+        use super::super::super::stdlib::open_libs;
+
+        let proto = LoadState::from_u8(generic_for(), "lua54").unwrap();
+        assert_eq!(proto.instructions, vec![
+            Instruction::ABx { instruction: OpCode::LoadK, a: 2, bx: 0 },
+            abc(OpCode::Move, 3, 0, 0),
+            abc(OpCode::Move, 4, 1, 0),
+            abc(OpCode::LoadNil, 5, 1, 0),
+            // the iterator, state and control moved up over the value to close
+            abc(OpCode::Move, 6, 5, 0),
+            abc(OpCode::Move, 5, 4, 0),
+            abc(OpCode::Move, 4, 3, 0),
+            asbx(OpCode::Jmp, 0, 1),
+            abc(OpCode::Add, 2, 2, 8),
+            abc(OpCode::TForCall, 4, 0, 2),
+            asbx(OpCode::TForLoop, 6, -3),
+            abc(OpCode::Return, 2, 2, 0),
+        ]);
+        assert_eq!(proto.lineinfo, vec![1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 3]);
+        assert_eq!((proto.locvars[0].startpc, proto.locvars[0].endpc), (8, 9));
+
+        let mut state = SyxState::new();
+        open_libs(&mut state);
+        let source = parse(b"return next, {10, 20, 12}", "=test").unwrap();
+        let args = state.call(Arc::new(source), Vec::new()).unwrap().into_vec();
+        let results = state.call(Arc::new(proto), args).unwrap().into_vec();
+        assert_eq!(results, vec![integer_value(42)]);
+    }
+
+    #[test]
+    fn test_unsupported_opcode() {
+        let chunk = chunk(iabc(55, 0, 0, 0, 0));
+        // raised translating the code, once it is read
+        match LoadState::from_u8(chunk, "lua54") {
+            Err(Error(ErrorKind::Undump(error), _)) => {
+                assert_eq!(error.section, Section::Code);
+                let message = "opcode can not be translated: TBC".to_owned();
+                assert_eq!(error.reason, UndumpReason::Invalid(message));
+            }
+            _ => panic!("expected UnsupportedOpCode"),
+        }
+    }
+}
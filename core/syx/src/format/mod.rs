@@ -0,0 +1,11 @@
+// Loaders for binary chunks from other Lua versions. Each one maps its own
+// layout and instruction set onto the Syx (Lua 5.3) `Proto`, and is picked by
+// `LoadState` from the version byte in the header.
+
+pub mod lua51;
+pub mod lua52;
+pub mod lua54;
+
+pub const LUA51_VERSION: u8 = 0x51;
+pub const LUA52_VERSION: u8 = 0x52;
+pub const LUA54_VERSION: u8 = 0x54;
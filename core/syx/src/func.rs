@@ -0,0 +1,128 @@
+// Closures and upvalues
+//
+// A Lua closure pairs a Proto with the upvalues it captured. An upvalue is
+// open while the local it refers to is still in a register, and reads and
+// writes go to that stack slot; when the local goes out of scope its value
+// is moved into the upvalue (it is closed). Open upvalues are kept in
+// `SyxState.open_upvalues`, so closures capturing the same local share one
+// upvalue and see each other's assignments. A closure may be called while
+// the coroutine it was created in is suspended, so open upvalues also
+// remember which thread's stack they point into.
+//
+// Consult the versioned lfunc.c for more information.
+
+use std::sync::Arc;
+
+use super::object::{FunctionRef, Proto, SyxValue, TableRef, ThreadRef, UpvalRef};
+use super::state::SyxState;
+
+pub struct LClosure {
+    pub proto: Arc<Proto>,
+    pub upvalues: Vec<UpvalRef>,
+    pub(crate) caches: Vec<FieldCache>, // by instruction, allocated on first use
+}
+
+// Where a GETTABUP or GETTABLE with a constant key last found its value,
+// still there as long as the table has the same version
+#[derive(Clone)]
+pub(crate) struct FieldCache {
+    pub table: TableRef,
+    pub version: u64,
+    pub value: SyxValue,
+}
+
+impl Default for FieldCache {
+    fn default() -> FieldCache {
+        // no table has version 0
+        FieldCache { table: TableRef(0), version: 0, value: SyxValue::Nil }
+    }
+}
+
+pub(crate) enum UpVal {
+    Open(Option<ThreadRef>, usize), // thread and stack index of the local
+    Closed(SyxValue),
+}
+
+impl SyxState {
+    pub fn new_closure(&mut self, proto: Arc<Proto>, upvalues: Vec<UpvalRef>) -> FunctionRef {
+        self.alloc_function(LClosure { proto, upvalues, caches: Vec::new() })
+    }
+
+    pub fn closure(&self, function: FunctionRef) -> &LClosure {
+        self.heap.functions.get(function.0)
+    }
+
+    // Closure for a main function. Its first upvalue is _ENV, closed over the
+    // globals table, and any others are closed over nil.
+    pub(crate) fn new_main_closure(&mut self, proto: Arc<Proto>) -> FunctionRef {
+        let globals = SyxValue::Table(self.globals);
+        self.new_env_closure(proto, globals)
+    }
+
+    // `new_main_closure` with `env` for _ENV instead of the globals
+    pub(crate) fn new_env_closure(&mut self, proto: Arc<Proto>, env: SyxValue) -> FunctionRef {
+        let upvalues = (0..proto.upvalues.len())
+            .map(|i| {
+                let value = if i == 0 { env.clone() } else { SyxValue::Nil };
+                self.alloc_upvalue(UpVal::Closed(value))
+            })
+            .collect();
+        self.new_closure(proto, upvalues)
+    }
+
+    // upvalue for the local at stack index `level`, shared with any closure
+    // that already captured it
+    pub(crate) fn find_upvalue(&mut self, level: usize) -> UpvalRef {
+        let mut position = self.open_upvalues.len();
+        while position > 0 {
+            let upvalue = self.open_upvalues[position - 1];
+            match *self.heap.upvalues.get(upvalue.0) {
+                UpVal::Open(_, l) if l == level => return upvalue,
+                UpVal::Open(_, l) if l < level => break,
+                _ => position -= 1,
+            }
+        }
+        let upvalue = self.alloc_upvalue(UpVal::Open(self.current, level));
+        self.open_upvalues.insert(position, upvalue);
+        upvalue
+    }
+
+    // close every open upvalue at or above stack index `level`
+    pub(crate) fn close_upvalues(&mut self, level: usize) {
+        while let Some(&upvalue) = self.open_upvalues.last() {
+            let index = match *self.heap.upvalues.get(upvalue.0) {
+                UpVal::Open(_, index) if index >= level => index,
+                _ => break,
+            };
+            self.open_upvalues.pop();
+            let value = self.stack.get(index).cloned().unwrap_or(SyxValue::Nil);
+            self.upvalue_barrier(upvalue);
+            *self.heap.upvalues.get_mut(upvalue.0) = UpVal::Closed(value);
+        }
+    }
+
+    pub(crate) fn get_upvalue(&self, upvalue: UpvalRef) -> SyxValue {
+        match *self.heap.upvalues.get(upvalue.0) {
+            UpVal::Open(thread, index) => self.thread_stack(thread)[index].clone(),
+            UpVal::Closed(ref value) => value.clone(),
+        }
+    }
+
+    pub(crate) fn set_upvalue(&mut self, upvalue: UpvalRef, value: SyxValue) {
+        self.upvalue_barrier(upvalue);
+        let (thread, index) = match *self.heap.upvalues.get_mut(upvalue.0) {
+            UpVal::Open(thread, index) => (thread, index),
+            UpVal::Closed(ref mut slot) => {
+                *slot = value;
+                return;
+            }
+        };
+        self.thread_stack_mut(thread)[index] = value;
+    }
+}
+
+// typedef struct LClosure {
+//   ClosureHeader;
+//   struct Proto *p;
+//   UpVal *upvals[1];  /* list of upvalues */
+// } LClosure;
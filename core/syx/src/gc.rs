@@ -0,0 +1,1361 @@
+// Garbage collector
+//
+// Collectable objects live in arenas owned by the Heap of a state and are
+// referred to by index (TableRef and friends). Collection is tri-color mark
+// and sweep: objects reachable from the roots go from white to gray (marked,
+// contents not yet traversed) to black (traversed), then every object still
+// white is freed and its slot reused by later allocations.
+//
+// By default a cycle runs all at once. In incremental mode it is split into
+// steps interleaved with the program; storing into a black table or upvalue
+// turns it gray again (see `table_mut` and `set_upvalue`), and the stack,
+// which has no barrier, is marked again in the atomic step before sweeping
+// starts.
+//
+// Strings are reference counted and free themselves, but interned short
+// strings are kept alive by the string table, so a cycle also drops the ones
+// nothing else refers to.
+//
+// A state can be given a memory limit. Once the estimate goes over it a full
+// collection is tried, and if that does not bring it back under, the
+// allocation point raises a "not enough memory" error scripts can catch.
+// Strings are built in buffers the collector does not see, so those are
+// checked against the limit before they grow instead (see `reserve`), and a
+// buffer the global allocator cannot provide is the same error. An
+// Allocator (see alloc.rs) hears of the same allocations, and can have its
+// own budget.
+//
+// Native closures are objects too, the Rust closure itself being dropped
+// once nothing refers to it.
+//
+// A table whose metatable has a __mode containing 'k' or 'v' does not keep
+// its keys or values alive: once marking is over, entries referring to an
+// unmarked object are cleared. Weak keys make the table an ephemeron table,
+// where a value is only marked once its key is, so marking goes over those
+// tables again until no more values are reached. Strings and other values
+// that are not objects are never cleared.
+//
+// A table or userdata whose metatable has a __gc field when it is set is
+// marked for finalization. Once such an object is found unreachable, it is
+// marked again with everything it refers to, and its finalizer is called
+// after the cycle, the most recently marked first. It is freed by the next
+// cycle that finds it unreachable, unless the finalizer stored it somewhere
+// (resurrected it), and its finalizer does not run again unless it is given
+// a metatable with __gc anew. Resurrected objects are removed from weak
+// values before their finalizers run, but only from weak keys once freed.
+//
+// Handles held only by Rust code are not roots. They stay valid until the
+// next collection, which happens at allocation points in the interpreter or
+// when `collect_garbage` is called.
+//
+// Consult the versioned lgc.c for more information.
+
+use std::collections::VecDeque;
+use std::mem::{self, size_of};
+
+use super::alloc::{Allocator, ObjectKind};
+use super::conf::{SYX_GCMINTHRESHOLD, SYX_GCPAUSE, SYX_GCSTEPMUL, SYX_GCSTEPSIZE};
+use super::coroutine::SyxThread;
+use super::errors::*;
+use super::func::{FieldCache, LClosure, UpVal};
+use super::native::NativeClosure;
+use super::userdata::AnyUserData;
+use super::object::{
+    FunctionRef, NativeRef, SyxTable, SyxValue, TableRef, ThreadRef, UpvalRef, UserDataRef,
+};
+use super::state::CallInfo;
+use super::state::SyxState;
+use super::tm::TagMethod;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Color {
+    White,
+    Gray,
+    Black,
+}
+
+// an object in one of the arenas, by arena and slot
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum GcRef {
+    Table(usize),
+    Function(usize),
+    Upvalue(usize),
+    Thread(usize),
+    Native(usize),
+    UserData(usize),
+}
+
+// arenas in the order they are swept
+const SWEEP_TABLES: usize = 0;
+const SWEEP_FUNCTIONS: usize = 1;
+const SWEEP_UPVALUES: usize = 2;
+const SWEEP_THREADS: usize = 3;
+const SWEEP_NATIVES: usize = 4;
+const SWEEP_USERDATA: usize = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Phase {
+    Pause,
+    Propagate,
+    Sweep(usize, usize), // arena and next slot to sweep
+}
+
+pub(crate) struct Arena<T> {
+    slots: Vec<Option<T>>,
+    colors: Vec<Color>,
+    sizes: Vec<usize>, // bytes allocated for each object, see alloc.rs
+    free: Vec<usize>,
+}
+
+impl<T> Arena<T> {
+    fn new() -> Arena<T> {
+        Arena {
+            slots: Vec::new(),
+            colors: Vec::new(),
+            sizes: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+
+    fn alloc(&mut self, value: T, size: usize) -> usize {
+        match self.free.pop() {
+            Some(index) => {
+                self.slots[index] = Some(value);
+                self.colors[index] = Color::White;
+                self.sizes[index] = size;
+                index
+            }
+            None => {
+                self.slots.push(Some(value));
+                self.colors.push(Color::White);
+                self.sizes.push(size);
+                self.slots.len() - 1
+            }
+        }
+    }
+
+    // bytes allocated for the live objects
+    fn live_size(&self) -> usize {
+        self.slots.iter().zip(&self.sizes)
+            .filter(|&(slot, _)| slot.is_some())
+            .map(|(_, &size)| size)
+            .sum()
+    }
+
+    pub(crate) fn get(&self, index: usize) -> &T {
+        self.slots[index].as_ref().expect("use of a collected object")
+    }
+
+    pub(crate) fn get_mut(&mut self, index: usize) -> &mut T {
+        self.slots[index].as_mut().expect("use of a collected object")
+    }
+
+    // number of live objects
+    pub(crate) fn live(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    // Free white objects and whiten the rest, starting at `cursor` and adding
+    // the size of survivors to `swept` and what was allocated for the others
+    // to `freed`; returns the next cursor, or None once the whole arena has
+    // been swept
+    fn sweep(&mut self, mut cursor: usize, budget: &mut usize, swept: &mut usize,
+             freed: &mut usize, size: fn(&T) -> usize) -> Option<usize> {
+        while cursor < self.slots.len() {
+            if let Some(ref object) = self.slots[cursor] {
+                if self.colors[cursor] == Color::White {
+                    self.slots[cursor] = None;
+                    self.free.push(cursor);
+                    *freed += self.sizes[cursor];
+                } else {
+                    self.colors[cursor] = Color::White;
+                    *swept += size(object);
+                }
+            }
+            cursor += 1;
+            *budget = budget.saturating_sub(size_of::<T>());
+            if *budget == 0 {
+                return Some(cursor);
+            }
+        }
+        None
+    }
+}
+
+pub struct Heap {
+    pub(crate) tables: Arena<SyxTable>,
+    pub(crate) functions: Arena<LClosure>,
+    pub(crate) upvalues: Arena<UpVal>,
+    pub(crate) threads: Arena<SyxThread>,
+    pub(crate) natives: Arena<NativeClosure>,
+    pub(crate) userdata: Arena<AnyUserData>,
+    gray: Vec<GcRef>,
+    children: Vec<GcRef>, // scratch space for `propagate`
+    weak: Vec<usize>,     // weak tables traversed this cycle
+    finobj: Vec<SyxValue>, // objects with a finalizer, in the order they got it
+    tobefnz: VecDeque<SyxValue>, // unreachable ones, waiting for their finalizer
+    finalizing: bool,     // running finalizers, which do not trigger collections
+    versions: u64,        // last table version handed out
+    phase: Phase,
+    incremental: bool,
+    pause: usize,     // wait for memory use to grow by this much (in %)
+    step_mul: usize,  // work done per step relative to allocation (in %)
+    estimate: usize,  // approximate bytes in use
+    threshold: usize, // estimate that triggers the next collection or step
+    swept: usize,     // bytes found alive by the current sweep
+    limit: Option<usize>, // estimate past which allocating fails
+    allocator: Option<Box<dyn Allocator>>,
+    strings: usize, // bytes of the strings in the state's string table
+}
+
+impl Heap {
+    pub(crate) fn new() -> Heap {
+        Heap {
+            tables: Arena::new(),
+            functions: Arena::new(),
+            upvalues: Arena::new(),
+            threads: Arena::new(),
+            natives: Arena::new(),
+            userdata: Arena::new(),
+            gray: Vec::new(),
+            children: Vec::new(),
+            weak: Vec::new(),
+            finobj: Vec::new(),
+            tobefnz: VecDeque::new(),
+            finalizing: false,
+            versions: 0,
+            phase: Phase::Pause,
+            incremental: false,
+            pause: SYX_GCPAUSE,
+            step_mul: SYX_GCSTEPMUL,
+            estimate: 0,
+            threshold: SYX_GCMINTHRESHOLD,
+            swept: 0,
+            limit: None,
+            allocator: None,
+            strings: 0,
+        }
+    }
+
+    // a table version no table has had yet
+    pub(crate) fn next_version(&mut self) -> u64 {
+        self.versions += 1;
+        self.versions
+    }
+
+    // account for memory the collector should know about
+    pub(crate) fn charge(&mut self, bytes: usize) {
+        self.estimate += bytes;
+    }
+
+    // Switch between incremental and stop-the-world collection
+    pub fn set_incremental(&mut self, incremental: bool) {
+        self.incremental = incremental;
+    }
+
+    // Set how much memory use has to grow after a cycle before the next one
+    // starts, as a percentage; returns the previous value
+    pub fn set_pause(&mut self, pause: usize) -> usize {
+        mem::replace(&mut self.pause, pause)
+    }
+
+    // Set how much work each incremental step does relative to the memory
+    // allocated since the last one, as a percentage; returns the previous
+    // value
+    pub fn set_step_mul(&mut self, step_mul: usize) -> usize {
+        mem::replace(&mut self.step_mul, step_mul.max(1))
+    }
+
+    // Set the most memory collectable objects may use before allocating
+    // raises an error, None for no limit; returns the previous limit
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) -> Option<usize> {
+        mem::replace(&mut self.limit, limit)
+    }
+
+    // Hand allocations and frees to `allocator`, None for no hook; returns
+    // the previous one. The objects alive are freed from the previous one and
+    // allocated from the new one.
+    pub fn set_allocator(&mut self, allocator: Option<Box<dyn Allocator>>)
+        -> Option<Box<dyn Allocator>>
+    {
+        let sizes = self.live_sizes();
+        let mut previous = mem::replace(&mut self.allocator, allocator);
+        for (&kind, &size) in ObjectKind::ALL.iter().zip(&sizes) {
+            if let Some(ref mut previous) = previous {
+                previous.free(kind, size);
+            }
+            if let Some(ref mut allocator) = self.allocator {
+                allocator.allocate(kind, size);
+            }
+        }
+        previous
+    }
+
+    // bytes allocated for the live objects, by kind
+    fn live_sizes(&self) -> [usize; 7] {
+        [
+            self.tables.live_size(), self.functions.live_size(), self.upvalues.live_size(),
+            self.threads.live_size(), self.natives.live_size(), self.userdata.live_size(),
+            self.strings,
+        ]
+    }
+
+    // account for `size` bytes more of strings in the string table
+    pub(crate) fn allocate_strings(&mut self, size: usize) {
+        if size > 0 {
+            self.strings += size;
+            self.allocate(ObjectKind::String, size);
+        }
+    }
+
+    fn free_strings(&mut self, size: usize) {
+        self.strings -= size;
+        if let Some(ref mut allocator) = self.allocator {
+            if size > 0 {
+                allocator.free(ObjectKind::String, size);
+            }
+        }
+    }
+
+    // account for `size` bytes more of objects of `kind`
+    fn allocate(&mut self, kind: ObjectKind, size: usize) {
+        self.charge(size);
+        if let Some(ref mut allocator) = self.allocator {
+            allocator.allocate(kind, size);
+        }
+    }
+
+    fn over_limit(&self) -> bool {
+        matches!(self.limit, Some(limit) if self.estimate > limit) ||
+            self.allocator.as_ref().is_some_and(|allocator| allocator.exhausted())
+    }
+
+    // whether `size` more bytes of strings would take memory use over the
+    // limit, or the allocator refuses them
+    fn refuses(&mut self, size: usize) -> bool {
+        matches!(self.limit, Some(limit) if self.estimate.saturating_add(size) > limit) ||
+            self.allocator.as_mut()
+                .is_some_and(|allocator| !allocator.try_allocate(ObjectKind::String, size))
+    }
+
+    fn color(&mut self, object: GcRef) -> &mut Color {
+        match object {
+            GcRef::Table(i) => &mut self.tables.colors[i],
+            GcRef::Function(i) => &mut self.functions.colors[i],
+            GcRef::Upvalue(i) => &mut self.upvalues.colors[i],
+            GcRef::Thread(i) => &mut self.threads.colors[i],
+            GcRef::Native(i) => &mut self.natives.colors[i],
+            GcRef::UserData(i) => &mut self.userdata.colors[i],
+        }
+    }
+
+    // Color for an object just allocated in `arena`. During a sweep, objects
+    // in slots not swept yet must be black or they would be freed; the
+    // others are counted as survivors right away.
+    fn alloc_color(&mut self, arena: usize, index: usize, size: usize) -> Color {
+        self.allocate(ObjectKind::ALL[arena], size);
+        match self.phase {
+            Phase::Sweep(a, cursor) if arena > a || (arena == a && index >= cursor) => {
+                Color::Black
+            }
+            Phase::Sweep(..) => {
+                self.swept += size;
+                Color::White
+            }
+            _ => Color::White,
+        }
+    }
+
+    fn is_white(&self, object: GcRef) -> bool {
+        let color = match object {
+            GcRef::Table(i) => self.tables.colors[i],
+            GcRef::Function(i) => self.functions.colors[i],
+            GcRef::Upvalue(i) => self.upvalues.colors[i],
+            GcRef::Thread(i) => self.threads.colors[i],
+            GcRef::Native(i) => self.natives.colors[i],
+            GcRef::UserData(i) => self.userdata.colors[i],
+        };
+        color == Color::White
+    }
+
+    // whether `value` is an object not marked (yet)
+    fn unmarked(&self, value: &SyxValue) -> bool {
+        matches!(reference(value), Some(object) if self.is_white(object))
+    }
+
+    // Whether a table has weak keys and weak values, from the __mode of its
+    // metatable
+    fn weakness(&self, table: &SyxTable, mode: &SyxValue) -> (bool, bool) {
+        let metatable = match table.metatable() {
+            Some(metatable) => self.tables.get(metatable.0),
+            None => return (false, false),
+        };
+        match metatable.get(mode) {
+            SyxValue::String(ref mode) => {
+                (mode.as_bytes().contains(&b'k'), mode.as_bytes().contains(&b'v'))
+            }
+            _ => (false, false),
+        }
+    }
+
+    // white -> gray
+    fn mark_object(&mut self, object: GcRef) {
+        let color = self.color(object);
+        if *color == Color::White {
+            *color = Color::Gray;
+            self.gray.push(object);
+        }
+    }
+
+    // A black object may be about to refer to a white one, so it has to be
+    // traversed again
+    fn barrier(&mut self, object: GcRef) {
+        if self.phase == Phase::Propagate {
+            let color = self.color(object);
+            if *color == Color::Black {
+                *color = Color::Gray;
+                self.gray.push(object);
+            }
+        }
+    }
+
+    // Traverse gray objects until `budget` bytes of them have been visited,
+    // returning true if that emptied the gray list first. `mode` is the
+    // "__mode" key.
+    fn propagate(&mut self, budget: &mut usize, mode: &SyxValue) -> bool {
+        let mut children = mem::take(&mut self.children);
+        let mut done = true;
+        while let Some(object) = self.gray.pop() {
+            *self.color(object) = Color::Black;
+            let size = match object {
+                GcRef::Table(i) => {
+                    let table = self.tables.get(i);
+                    let (weak_keys, weak_values) = self.weakness(table, mode);
+                    if let Some(metatable) = table.metatable() {
+                        children.push(GcRef::Table(metatable.0));
+                    }
+                    if !weak_values {
+                        children.extend(table.array.iter().filter_map(reference));
+                    }
+                    for (key, value) in &table.hash {
+                        // dead keys are only kept for traversals
+                        if value.is_nil() {
+                            continue;
+                        }
+                        if !weak_keys {
+                            children.extend(reference(key));
+                        }
+                        // an ephemeron's value waits for its key to be marked
+                        if weak_values || (weak_keys && self.unmarked(key)) {
+                            continue;
+                        }
+                        children.extend(reference(value));
+                    }
+                    let size = table_size(table);
+                    if weak_keys || weak_values {
+                        self.weak.push(i);
+                    }
+                    size
+                }
+                GcRef::Function(i) => {
+                    let function = self.functions.get(i);
+                    children.extend(function.upvalues.iter().map(|u| GcRef::Upvalue(u.0)));
+                    function_size(function)
+                }
+                GcRef::Upvalue(i) => {
+                    // an open upvalue's value is on the stack
+                    if let UpVal::Closed(ref value) = *self.upvalues.get(i) {
+                        children.extend(reference(value));
+                    }
+                    size_of::<UpVal>()
+                }
+                GcRef::Thread(i) => {
+                    let thread = self.threads.get(i);
+                    children.extend(thread.body.as_ref().and_then(reference));
+                    stack_references(&mut children, &thread.stack, &thread.frames,
+                                     &thread.open_upvalues);
+                    thread_size(thread)
+                }
+                GcRef::Native(i) => {
+                    let native = self.natives.get(i);
+                    children.extend(native.upvalues.iter().filter_map(reference));
+                    size_of::<NativeClosure>()
+                }
+                // its metatable is marked as a root, being per type
+                GcRef::UserData(_) => size_of::<AnyUserData>(),
+            };
+            for child in children.drain(..) {
+                self.mark_object(child);
+            }
+            *budget = budget.saturating_sub(size);
+            if *budget == 0 {
+                done = false;
+                break;
+            }
+        }
+        self.children = children;
+        done
+    }
+
+    // Mark the values of ephemeron tables whose keys have been marked, until
+    // that marks nothing new
+    fn converge_ephemerons(&mut self, mode: &SyxValue) {
+        loop {
+            let mut values = mem::take(&mut self.children);
+            // traversing may find more weak tables
+            let mut n = 0;
+            while n < self.weak.len() {
+                let table = self.tables.get(self.weak[n]);
+                if let (true, false) = self.weakness(table, mode) {
+                    values.extend(table.hash.iter()
+                        .filter(|&(key, _)| !self.unmarked(key))
+                        .filter_map(|(_, value)| reference(value))
+                        .filter(|&value| self.is_white(value)));
+                }
+                n += 1;
+            }
+            if values.is_empty() {
+                self.children = values;
+                return;
+            }
+            for value in values.drain(..) {
+                self.mark_object(value);
+            }
+            self.children = values;
+            let mut budget = usize::MAX;
+            self.propagate(&mut budget, mode);
+        }
+    }
+
+    // Clear the entries of weak tables that refer to objects about to be
+    // freed, or to be finalized, by their values and also by their keys if
+    // `keys` is set
+    fn clear_weak(&mut self, mode: &SyxValue, keys: bool) {
+        let weak = mem::take(&mut self.weak);
+        for &i in &weak {
+            let table = self.tables.get(i);
+            let (weak_keys, weak_values) = self.weakness(table, mode);
+            let weak_keys = weak_keys && keys;
+            let array: Vec<usize> = if weak_values {
+                (0..table.array.len()).filter(|&n| self.unmarked(&table.array[n])).collect()
+            } else {
+                Vec::new()
+            };
+            let keys: Vec<SyxValue> = table.hash.iter()
+                .filter(|&(key, value)| {
+                    (weak_keys && self.unmarked(key)) || (weak_values && self.unmarked(value))
+                })
+                .map(|(key, _)| key.clone())
+                .collect();
+            // left as dead keys, so a traversal can go on past them
+            self.versions += 1;
+            let table = self.tables.get_mut(i);
+            table.version = self.versions;
+            for n in array {
+                table.array[n] = SyxValue::Nil;
+            }
+            for key in keys {
+                table.hash.insert(key, SyxValue::Nil);
+            }
+        }
+        self.weak = weak;
+    }
+
+    // Queue the unreachable objects marked for finalization and mark them
+    // again, the last one marked first
+    fn separate_unreachable(&mut self) {
+        let (unreachable, reachable): (Vec<SyxValue>, Vec<SyxValue>) =
+            mem::take(&mut self.finobj).into_iter().partition(|object| self.unmarked(object));
+        self.finobj = reachable;
+        for object in unreachable.into_iter().rev() {
+            self.mark_object(reference(&object).expect("only objects are finalized"));
+            self.tobefnz.push_back(object);
+        }
+    }
+
+    // Sweep part of `arena` from `cursor`, returning the next position, or
+    // None once every arena has been swept
+    fn sweep(&mut self, arena: usize, cursor: usize, budget: &mut usize)
+        -> Option<(usize, usize)>
+    {
+        let swept = &mut self.swept;
+        let freed = &mut 0;
+        let next = match arena {
+            SWEEP_TABLES => self.tables.sweep(cursor, budget, swept, freed, table_size),
+            SWEEP_FUNCTIONS => {
+                self.functions.sweep(cursor, budget, swept, freed, function_size)
+            }
+            SWEEP_UPVALUES => {
+                self.upvalues.sweep(cursor, budget, swept, freed, |_| size_of::<UpVal>())
+            }
+            SWEEP_THREADS => self.threads.sweep(cursor, budget, swept, freed, thread_size),
+            SWEEP_NATIVES => {
+                self.natives.sweep(cursor, budget, swept, freed, |_| size_of::<NativeClosure>())
+            }
+            _ => self.userdata.sweep(cursor, budget, swept, freed, |_| size_of::<AnyUserData>()),
+        };
+        if let Some(ref mut allocator) = self.allocator {
+            if *freed > 0 {
+                allocator.free(ObjectKind::ALL[arena], *freed);
+            }
+        }
+        match next {
+            Some(cursor) => Some((arena, cursor)),
+            None if arena < SWEEP_USERDATA => Some((arena + 1, 0)),
+            None => None,
+        }
+    }
+}
+
+// the objects still alive go with the state
+impl Drop for Heap {
+    fn drop(&mut self) {
+        if self.allocator.is_some() {
+            self.set_allocator(None);
+        }
+    }
+}
+
+pub(crate) fn is_collectable(value: &SyxValue) -> bool {
+    reference(value).is_some()
+}
+
+// the collectable object a value refers to, if any
+fn reference(value: &SyxValue) -> Option<GcRef> {
+    match *value {
+        SyxValue::Table(t) => Some(GcRef::Table(t.0)),
+        SyxValue::Function(f) => Some(GcRef::Function(f.0)),
+        SyxValue::Thread(t) => Some(GcRef::Thread(t.0)),
+        SyxValue::NativeClosure(f) => Some(GcRef::Native(f.0)),
+        SyxValue::UserData(u) => Some(GcRef::UserData(u.0)),
+        _ => None,
+    }
+}
+
+// Everything a thread's stack keeps alive: its registers, the functions
+// running on it, their varargs, and its open upvalues, as closing one needs
+// it even if no closure refers to it any more
+fn stack_references(references: &mut Vec<GcRef>, stack: &[SyxValue], frames: &[CallInfo],
+                    open_upvalues: &[UpvalRef]) {
+    references.extend(stack.iter().filter_map(reference));
+    for ci in frames {
+        references.push(GcRef::Function(ci.closure.0));
+        references.extend(ci.varargs.iter().filter_map(reference));
+    }
+    references.extend(open_upvalues.iter().map(|u| GcRef::Upvalue(u.0)));
+}
+
+fn table_size(table: &SyxTable) -> usize {
+    size_of::<SyxTable>() + table.array.capacity() * size_of::<SyxValue>() +
+        table.hash.capacity() * (2 * size_of::<SyxValue>() + size_of::<u64>())
+}
+
+fn function_size(function: &LClosure) -> usize {
+    size_of::<LClosure>() + function.upvalues.len() * size_of::<UpvalRef>() +
+        function.caches.capacity() * size_of::<FieldCache>()
+}
+
+fn thread_size(thread: &SyxThread) -> usize {
+    size_of::<SyxThread>() + thread.stack.capacity() * size_of::<SyxValue>() +
+        thread.frames.capacity() * size_of::<CallInfo>()
+}
+
+impl SyxState {
+    pub fn gc(&mut self) -> &mut Heap {
+        &mut self.heap
+    }
+
+    pub(crate) fn alloc_table(&mut self, mut table: SyxTable) -> TableRef {
+        table.version = self.heap.next_version();
+        let size = table_size(&table);
+        let index = self.heap.tables.alloc(table, size);
+        self.heap.tables.colors[index] = self.heap.alloc_color(SWEEP_TABLES, index, size);
+        TableRef(index)
+    }
+
+    pub(crate) fn alloc_function(&mut self, function: LClosure) -> FunctionRef {
+        let size = function_size(&function);
+        let index = self.heap.functions.alloc(function, size);
+        self.heap.functions.colors[index] = self.heap.alloc_color(SWEEP_FUNCTIONS, index, size);
+        FunctionRef(index)
+    }
+
+    pub(crate) fn alloc_upvalue(&mut self, upvalue: UpVal) -> UpvalRef {
+        let size = size_of::<UpVal>();
+        let index = self.heap.upvalues.alloc(upvalue, size);
+        self.heap.upvalues.colors[index] = self.heap.alloc_color(SWEEP_UPVALUES, index, size);
+        UpvalRef(index)
+    }
+
+    pub(crate) fn alloc_thread(&mut self, thread: SyxThread) -> ThreadRef {
+        let size = thread_size(&thread);
+        let index = self.heap.threads.alloc(thread, size);
+        self.heap.threads.colors[index] = self.heap.alloc_color(SWEEP_THREADS, index, size);
+        ThreadRef(index)
+    }
+
+    pub(crate) fn alloc_native(&mut self, native: NativeClosure) -> NativeRef {
+        let size = size_of::<NativeClosure>();
+        let index = self.heap.natives.alloc(native, size);
+        self.heap.natives.colors[index] = self.heap.alloc_color(SWEEP_NATIVES, index, size);
+        NativeRef(index)
+    }
+
+    pub(crate) fn alloc_userdata(&mut self, userdata: AnyUserData) -> UserDataRef {
+        let size = size_of::<AnyUserData>();
+        let index = self.heap.userdata.alloc(userdata, size);
+        self.heap.userdata.colors[index] = self.heap.alloc_color(SWEEP_USERDATA, index, size);
+        UserDataRef(index)
+    }
+
+    // write barriers, see Heap::barrier
+    pub(crate) fn barrier(&mut self, table: TableRef) {
+        self.heap.barrier(GcRef::Table(table.0));
+    }
+
+    pub(crate) fn upvalue_barrier(&mut self, upvalue: UpvalRef) {
+        self.heap.barrier(GcRef::Upvalue(upvalue.0));
+    }
+
+    pub(crate) fn thread_barrier(&mut self, thread: ThreadRef) {
+        self.heap.barrier(GcRef::Thread(thread.0));
+    }
+
+    // Collect or do a step of work if enough has been allocated since the
+    // last one, and fail if memory use is still over the limit after a full
+    // collection; only called where every live value is reachable from the
+    // stack.
+    pub(crate) fn check_gc(&mut self) -> Result<()> {
+        if self.heap.estimate >= self.heap.threshold && !self.heap.finalizing {
+            if self.heap.incremental {
+                self.gc_step();
+            } else {
+                self.collect_garbage();
+            }
+        }
+        if self.heap.over_limit() {
+            self.collect_garbage();
+            if self.heap.over_limit() {
+                return Err(ErrorKind::MemoryError.into());
+            }
+        }
+        Ok(())
+    }
+
+    // Make room for `additional` more bytes in `buffer`, a string being built,
+    // or fail with a memory error if that would take memory use over the limit
+    // even after a full collection, or the global allocator cannot provide
+    // them. Unlike check_gc this is checked before allocating, from natives
+    // whose arguments are kept on the stack of api.rs (see `run_native`), so
+    // every live value is reachable; like an emergency collection in Lua, it
+    // leaves finalizers for later.
+    pub(crate) fn reserve(&mut self, buffer: &mut Vec<u8>, additional: usize) -> Result<()> {
+        let size = buffer.len().saturating_add(additional);
+        if size <= buffer.capacity() {
+            return Ok(());
+        }
+        // double it, as a Vec would, unless only the exact size fits
+        let mut capacity = size.max(buffer.capacity().saturating_mul(2));
+        if self.heap.refuses(capacity) {
+            capacity = size;
+            if self.heap.refuses(size) && !self.heap.finalizing {
+                self.full_cycle();
+            }
+            if self.heap.refuses(size) {
+                return Err(ErrorKind::MemoryError.into());
+            }
+        }
+        buffer.try_reserve_exact(capacity - buffer.len()).map_err(|_| ErrorKind::MemoryError.into())
+    }
+
+    // Store into a table, accounting for any growth of its parts
+    pub(crate) fn table_set(&mut self, table: TableRef, key: SyxValue, value: SyxValue)
+        -> Result<()>
+    {
+        let before = table_size(self.table(table));
+        let result = self.table_mut(table).set(key, value);
+        let growth = table_size(self.table(table)).saturating_sub(before);
+        if growth > 0 {
+            self.heap.tables.sizes[table.0] += growth;
+            self.heap.allocate(ObjectKind::Table, growth);
+        }
+        result
+    }
+
+    // The registry, the globals, the string and userdata metatables, the
+    // debug.sethook function, the running thread's stack, the stack of
+    // api.rs, and the coroutines holding the stacks of the threads waiting
+    // on it
+    fn mark_roots(&mut self) {
+        let mut roots = mem::take(&mut self.heap.children);
+        stack_references(&mut roots, &self.stack, &self.frames, &self.open_upvalues);
+        roots.push(GcRef::Table(self.registry.0));
+        roots.push(GcRef::Table(self.globals.0));
+        roots.extend(self.string_metatable.iter().map(|t| GcRef::Table(t.0)));
+        roots.extend(self.userdata_metatables.values().map(|t| GcRef::Table(t.0)));
+        roots.extend(self.io.roots().map(|t| GcRef::Table(t.0)));
+        roots.extend(reference(&self.lua_hook));
+        roots.extend(self.api_stack.iter().filter_map(reference));
+        roots.extend(self.main_thread.iter().map(|t| GcRef::Thread(t.0)));
+        roots.extend(self.current.iter().map(|t| GcRef::Thread(t.0)));
+        roots.extend(self.resumers.iter().flatten().map(|t| GcRef::Thread(t.0)));
+        roots.extend(self.tasks.threads().map(|t| GcRef::Thread(t.0)));
+        roots.extend(self.tasks.values().filter_map(reference));
+        roots.extend(self.heap.tobefnz.iter().filter_map(reference));
+        for root in roots.drain(..) {
+            self.heap.mark_object(root);
+        }
+        self.heap.children = roots;
+    }
+
+    // Finish marking in one go, the stack may have changed since the roots
+    // were first marked, then clear weak tables
+    fn atomic(&mut self) {
+        self.mark_roots();
+        let mode = self.mode_key();
+        let mut budget = usize::MAX;
+        self.heap.propagate(&mut budget, &mode);
+        self.heap.converge_ephemerons(&mode);
+        self.heap.clear_weak(&mode, false);
+        self.heap.separate_unreachable();
+        self.heap.propagate(&mut budget, &mode);
+        self.heap.converge_ephemerons(&mode);
+        self.heap.clear_weak(&mode, true);
+        self.heap.weak.clear();
+        self.heap.swept = 0;
+        self.heap.phase = Phase::Sweep(SWEEP_TABLES, 0);
+    }
+
+    fn mode_key(&self) -> SyxValue {
+        SyxValue::String(self.tm_names[TagMethod::Mode as usize].clone())
+    }
+
+    fn finish_cycle(&mut self) {
+        // last, once the objects swept have let go of theirs
+        let freed = self.strings.sweep();
+        self.heap.free_strings(freed);
+        let heap = &mut self.heap;
+        heap.phase = Phase::Pause;
+        heap.estimate = heap.swept + heap.strings;
+        heap.threshold = (heap.estimate / 100 * heap.pause).max(SYX_GCMINTHRESHOLD);
+    }
+
+    // Advance the collector by `budget` bytes worth of work, returning true
+    // when a cycle was completed
+    fn gc_work(&mut self, mut budget: usize) -> bool {
+        loop {
+            match self.heap.phase {
+                Phase::Pause => {
+                    self.mark_roots();
+                    self.heap.phase = Phase::Propagate;
+                }
+                Phase::Propagate => {
+                    let mode = self.mode_key();
+                    if self.heap.propagate(&mut budget, &mode) {
+                        self.atomic();
+                    }
+                }
+                Phase::Sweep(arena, cursor) => match self.heap.sweep(arena, cursor, &mut budget) {
+                    Some((arena, cursor)) => self.heap.phase = Phase::Sweep(arena, cursor),
+                    None => {
+                        self.finish_cycle();
+                        return true;
+                    }
+                },
+            }
+            if budget == 0 {
+                return false;
+            }
+        }
+    }
+
+    // one incremental step
+    fn gc_step(&mut self) {
+        let budget = SYX_GCSTEPSIZE / 100 * self.heap.step_mul;
+        if !self.gc_work(budget) {
+            self.heap.threshold = self.heap.estimate + SYX_GCSTEPSIZE;
+        }
+        self.call_finalizers();
+    }
+
+    // Run a full collection cycle, finishing any incremental one first, then
+    // the finalizers of the objects it found unreachable
+    pub fn collect_garbage(&mut self) {
+        if self.heap.finalizing {
+            return;
+        }
+        self.full_cycle();
+        self.call_finalizers();
+    }
+
+    // finish any cycle in progress, then run a whole one
+    fn full_cycle(&mut self) {
+        if self.heap.phase != Phase::Pause {
+            self.gc_work(usize::MAX);
+        }
+        self.gc_work(usize::MAX);
+    }
+
+    // Mark `value` for finalization if its metatable has a __gc field, as
+    // adding one to the metatable later has no effect
+    pub(crate) fn check_finalizer(&mut self, value: &SyxValue) {
+        if self.metamethod(value, TagMethod::Gc).is_some() && !self.heap.finobj.contains(value) {
+            self.heap.finobj.push(value.clone());
+        }
+    }
+
+    // Call the finalizers of the objects queued by the last cycles, with no
+    // collection running meanwhile; errors in finalizers are ignored, there
+    // being nobody to report them to
+    fn call_finalizers(&mut self) {
+        if self.heap.finalizing {
+            return;
+        }
+        self.heap.finalizing = true;
+        while let Some(object) = self.heap.tobefnz.pop_front() {
+            if let Some(finalizer) = self.metamethod(&object, TagMethod::Gc) {
+                let _ = self.call_value(finalizer, vec![object]);
+            }
+        }
+        self.heap.finalizing = false;
+    }
+
+    // Call the finalizer of every object marked for finalization, reachable
+    // or not, as closing a Lua state does, and drop the state
+    pub fn close(mut self) {
+        self.call_finalizers();
+        let objects = mem::take(&mut self.heap.finobj);
+        self.heap.tobefnz.extend(objects.into_iter().rev());
+        self.call_finalizers();
+    }
+
+    // approximate bytes used by collectable objects
+    pub fn gc_count(&self) -> usize {
+        self.heap.estimate
+    }
+
+    // number of live collectable objects
+    pub fn gc_objects(&self) -> usize {
+        self.heap.tables.live() + self.heap.functions.live() + self.heap.upvalues.live() +
+            self.heap.threads.live() + self.heap.natives.live() +
+            self.heap.userdata.live()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+    use super::super::object::{MultiValue, Proto, SyxInteger};
+    use super::super::opcodes::{Instruction, OpCode};
+    use super::super::testing::{abc, run_in};
+
+    // table with a metatable giving it `mode`, kept alive by the globals
+    fn weak_table(state: &mut SyxState, mode: &str) -> TableRef {
+        let table = state.new_table(0, 0);
+        let metatable = state.new_table(0, 1);
+        state.table_mut(metatable).set("__mode".into(), mode.into()).unwrap();
+        state.table_mut(table).set_metatable(Some(metatable));
+        let globals = state.globals();
+        state.table_mut(globals).set("weak".into(), SyxValue::Table(table)).unwrap();
+        table
+    }
+
+    #[test]
+    fn test_weak_values() {
+        let mut state = SyxState::new();
+        let weak = weak_table(&mut state, "v");
+        let (dropped, kept) = (state.new_table(0, 0), state.new_table(0, 0));
+        let globals = state.globals();
+        state.table_mut(globals).set("kept".into(), SyxValue::Table(kept)).unwrap();
+        let table = state.table_mut(weak);
+        table.set_int(1, SyxValue::Table(dropped));
+        table.set_int(2, SyxValue::Table(kept));
+        table.set("key".into(), SyxValue::Table(dropped)).unwrap();
+        table.set("string".into(), "not an object".into()).unwrap();
+        state.collect_garbage();
+        let table = state.table(weak);
+        assert!(table.get_int(1).is_nil() && table.get(&"key".into()).is_nil());
+        assert_eq!(table.get_int(2), SyxValue::Table(kept));
+        assert_eq!(table.get(&"string".into()), "not an object".into());
+        assert!(table.next(&SyxValue::Nil).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_ephemerons() {
+        let mut state = SyxState::new();
+        let weak = weak_table(&mut state, "k");
+        let [a, b, c, d] = [(); 4].map(|_| state.new_table(0, 0));
+        let globals = state.globals();
+        state.table_mut(globals).set("b".into(), SyxValue::Table(b)).unwrap();
+        // a value referring to its own key does not keep the entry, but the
+        // values of keys reachable only from other values are kept
+        let table = state.table_mut(weak);
+        table.set(SyxValue::Table(a), SyxValue::Table(a)).unwrap();
+        table.set(SyxValue::Table(c), SyxValue::Table(d)).unwrap();
+        table.set(SyxValue::Table(b), SyxValue::Table(c)).unwrap();
+        state.collect_garbage();
+        let table = state.table(weak);
+        assert!(table.get(&SyxValue::Table(a)).is_nil());
+        assert_eq!(table.get(&SyxValue::Table(b)), SyxValue::Table(c));
+        assert_eq!(table.get(&SyxValue::Table(c)), SyxValue::Table(d));
+        // the globals, the registry, the weak table and its metatable, b, c and d
+        assert_eq!(state.gc_objects(), 7);
+
+        // both weak
+        let weak = weak_table(&mut state, "kv");
+        state.table_mut(weak).set(SyxValue::Table(b), SyxValue::Table(c)).unwrap();
+        state.collect_garbage();
+        assert!(state.table(weak).get(&SyxValue::Table(b)).is_nil());
+    }
+
+    fn run(state: &mut SyxState, source: &str) -> MultiValue {
+        run_in(state, source).unwrap()
+    }
+
+    #[test]
+    fn test_finalizers() {
+        let mut state = SyxState::new();
+        super::super::stdlib::open_libs(&mut state);
+        run(&mut state, "
+            order = {}
+            for i = 1, 3 do
+                setmetatable({}, {__gc = function() order[#order + 1] = i end})
+            end
+            -- a __gc added afterwards does not count
+            local mt = {}
+            setmetatable({}, mt)
+            mt.__gc = function() order[#order + 1] = 'late' end
+        ");
+        state.collect_garbage();
+        let results = run(&mut state, "return #order, table.concat(order, ' ')");
+        assert_eq!(results, vec![SyxValue::Integer(3), "3 2 1".into()]);
+    }
+
+    #[test]
+    fn test_resurrection() {
+        let mut state = SyxState::new();
+        super::super::stdlib::open_libs(&mut state);
+        run(&mut state, "
+            calls = 0
+            weak_keys = setmetatable({}, {__mode = 'k'})
+            weak_values = setmetatable({}, {__mode = 'v'})
+            local object = setmetatable({name = 'x'}, {__gc = function(o)
+                calls = calls + 1
+                saved = o
+            end})
+            weak_keys[object] = true
+            weak_values[1] = object
+        ");
+        state.collect_garbage();
+        let results = run(&mut state, "
+            local kept = weak_keys[saved]
+            saved = nil
+            return calls, weak_values[1], kept
+        ");
+        assert_eq!(results, vec![SyxValue::Integer(1), SyxValue::Nil, SyxValue::Bool(true)]);
+        // freed this time, without being finalized again
+        let objects = state.gc_objects();
+        state.collect_garbage();
+        assert!(state.gc_objects() < objects);
+        let results = run(&mut state, "return calls, next(weak_keys)");
+        assert_eq!(results, vec![SyxValue::Integer(1), SyxValue::Nil]);
+    }
+
+    #[test]
+    fn test_close() {
+        let mut state = SyxState::new();
+        super::super::stdlib::open_libs(&mut state);
+        let closed = Arc::new(AtomicUsize::new(0));
+        let seen = closed.clone();
+        state.register("closed", move |_, _| {
+            seen.fetch_add(1, Ordering::Relaxed);
+            Ok(MultiValue::new())
+        });
+        run(&mut state, "kept = setmetatable({}, {__gc = closed})");
+        state.collect_garbage();
+        assert_eq!(closed.load(Ordering::Relaxed), 0);
+        state.close();
+        assert_eq!(closed.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_unreachable_tables() {
+        let mut state = SyxState::new();
+        let a = state.new_table(0, 0);
+        let b = state.new_table(0, 0);
+        // a cycle, including through a metatable
+        state.table_mut(a).set("b".into(), SyxValue::Table(b)).unwrap();
+        state.table_mut(b).set_metatable(Some(a));
+        assert_eq!(state.gc_objects(), 4);
+        state.collect_garbage();
+        assert_eq!(state.gc_objects(), 2); // the globals and the registry
+        // slots are reused
+        let c = state.new_table(0, 0);
+        assert!(c == a || c == b);
+    }
+
+    // local keep = {}; for i = 1, N do local t = {}; t.x = keep; keep[1] = t end
+    // return keep
+    fn collect_while_running(mut state: SyxState) {
+        const N: SyxInteger = 20000;
+        let mut proto = Proto::new();
+        proto.maxstacksize = 6;
+        proto.constants = vec![SyxValue::Integer(1), SyxValue::Integer(N), "x".into()];
+        proto.instructions = vec![
+            abc(OpCode::NewTable, 0, 0, 0),
+            Instruction::ABx { instruction: OpCode::LoadK, a: 1, bx: 0 },
+            Instruction::ABx { instruction: OpCode::LoadK, a: 2, bx: 1 },
+            Instruction::ABx { instruction: OpCode::LoadK, a: 3, bx: 0 },
+            Instruction::AsBx { instruction: OpCode::ForPrep, a: 1, sbx: 3 },
+            abc(OpCode::NewTable, 5, 0, 0),
+            abc(OpCode::SetTable, 5, 258, 0),
+            abc(OpCode::SetTable, 0, 256, 5),
+            Instruction::AsBx { instruction: OpCode::ForLoop, a: 1, sbx: -4 },
+            abc(OpCode::Return, 0, 2, 0),
+        ];
+        let results = state.call(Arc::new(proto), vec![]).unwrap();
+        assert!(state.gc_objects() < N as usize);
+
+        // the survivors are intact
+        let keep = match results[0] {
+            SyxValue::Table(t) => t,
+            _ => panic!("expected a table"),
+        };
+        let last = match state.table(keep).get_int(1) {
+            SyxValue::Table(t) => t,
+            _ => panic!("expected a table"),
+        };
+        assert_eq!(state.table(last).get(&"x".into()), SyxValue::Table(keep));
+
+        state.collect_garbage();
+        assert_eq!(state.gc_objects(), 2);
+    }
+
+    #[test]
+    fn test_collect_while_running() {
+        collect_while_running(SyxState::new());
+    }
+
+    #[test]
+    fn test_incremental() {
+        let mut state = SyxState::new();
+        state.gc().set_incremental(true);
+        assert_eq!(state.gc().set_step_mul(50), SYX_GCSTEPMUL);
+        assert_eq!(state.gc().set_pause(100), SYX_GCPAUSE);
+        collect_while_running(state);
+    }
+
+    #[test]
+    fn test_barrier() {
+        let mut state = SyxState::new();
+        let root = state.globals();
+        // blacken the root, then hide a new table where only the barrier
+        // can make the collector see it
+        state.gc_work(1);
+        assert_eq!(state.heap.phase, Phase::Propagate);
+        assert_eq!(state.heap.tables.colors[root.0], Color::Black);
+        let child = state.new_table(0, 0);
+        state.table_mut(root).set_int(1, SyxValue::Table(child));
+        state.gc_work(usize::MAX);
+        assert_eq!(state.gc_objects(), 3);
+        assert!(state.table(child).get_int(1).is_nil());
+        assert_eq!(state.heap.phase, Phase::Pause);
+    }
+
+    #[test]
+    fn test_closures() {
+        let mut state = SyxState::new();
+        let table = state.new_table(0, 0);
+        let upvalue = state.alloc_upvalue(UpVal::Closed(SyxValue::Table(table)));
+        let function = state.new_closure(Arc::new(Proto::new()), vec![upvalue]);
+        state.stack.push(SyxValue::Function(function));
+        state.collect_garbage();
+        assert_eq!(state.gc_objects(), 5);
+        assert!(state.table(table).get_int(1).is_nil());
+
+        state.stack.clear();
+        state.collect_garbage();
+        assert_eq!(state.gc_objects(), 2);
+    }
+
+    #[test]
+    fn test_sweep_strings() {
+        let mut state = SyxState::new();
+        let before = state.strings.len();
+        let kept = state.intern(b"kept");
+        state.intern(b"dropped");
+        assert_eq!(state.strings.len(), before + 2);
+        state.collect_garbage();
+        assert_eq!(state.strings.len(), before + 1);
+        assert!(kept.ptr_eq(&state.intern(b"kept")));
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let mut state = SyxState::new();
+        super::super::stdlib::open_libs(&mut state);
+        state.gc().set_memory_limit(Some(256 * 1024));
+        let proto = super::super::compiler::parse(b"
+            local ok, message = pcall(function()
+                local t = {}
+                for i = 1, 1e7 do t[i] = {} end
+            end)
+            local t = {}
+            for i = 1, 100 do t[i] = {} end
+            return ok, message, #t
+        ", "=limit").unwrap();
+        let results = state.call(Arc::new(proto), Vec::new()).unwrap();
+        assert!(results[0] == SyxValue::Bool(false));
+        assert!(results[1] == SyxValue::from("not enough memory"));
+        assert!(results[2] == SyxValue::Integer(100));
+        assert!(state.gc_count() <= 256 * 1024);
+    }
+
+    #[test]
+    fn test_memory_limit_strings() {
+        let mut state = SyxState::new();
+        super::super::stdlib::open_libs(&mut state);
+        let limit = state.gc_count() + (1 << 20);
+        state.gc().set_memory_limit(Some(limit));
+        // each would build a string of a megabyte or more
+        let results = run_in(&mut state, "
+            local s = ('x'):rep(1e5)
+            local many = {}
+            for i = 1, 20 do many[i] = s end
+            local function fails(f, ...) return select(2, pcall(f, ...)) end
+            return fails(string.rep, 'x', 1e8),
+                fails(string.rep, s, 20, s),
+                fails(string.gsub, s, 'x', 'yyyyyyyyyy'),
+                fails(string.format, ('%s'):rep(20), table.unpack(many)),
+                fails(table.concat, many),
+                fails(function() local t = s for i = 1, 20 do t = t .. s end end),
+                #('x'):rep(1000)
+        ").unwrap();
+        assert_eq!(results[results.len() - 1], SyxValue::Integer(1000));
+        for result in &results[..results.len() - 1] {
+            assert_eq!(result, &SyxValue::from("not enough memory"));
+        }
+    }
+
+    // counts the bytes in use, which should stay within a budget
+    struct Tracking {
+        in_use: Arc<AtomicUsize>,
+        budget: usize,
+    }
+
+    impl Allocator for Tracking {
+        fn allocate(&mut self, _: ObjectKind, size: usize) {
+            self.in_use.fetch_add(size, Ordering::Relaxed);
+        }
+
+        fn free(&mut self, _: ObjectKind, size: usize) {
+            self.in_use.fetch_sub(size, Ordering::Relaxed);
+        }
+
+        fn exhausted(&self) -> bool {
+            self.in_use.load(Ordering::Relaxed) > self.budget
+        }
+
+        fn try_allocate(&mut self, _: ObjectKind, size: usize) -> bool {
+            self.in_use.load(Ordering::Relaxed) + size <= self.budget
+        }
+    }
+
+    // counts the bytes of strings only
+    struct Strings(Arc<AtomicUsize>);
+
+    impl Allocator for Strings {
+        fn allocate(&mut self, kind: ObjectKind, size: usize) {
+            if kind == ObjectKind::String {
+                self.0.fetch_add(size, Ordering::Relaxed);
+            }
+        }
+
+        fn free(&mut self, kind: ObjectKind, size: usize) {
+            if kind == ObjectKind::String {
+                self.0.fetch_sub(size, Ordering::Relaxed);
+            }
+        }
+    }
+
+    #[test]
+    fn test_allocator_strings() {
+        let in_use = Arc::new(AtomicUsize::new(0));
+        let mut state = SyxState::new();
+        super::super::stdlib::open_libs(&mut state);
+        state.gc().set_allocator(Some(Box::new(Strings(in_use.clone()))));
+        let before = in_use.load(Ordering::Relaxed);
+        assert!(before > 0);
+        // interned and long strings, both only kept until the collection
+        let proto = Arc::new(super::super::compiler::parse(b"
+            local t = {}
+            for i = 1, 100 do t[i] = 'key' .. i end
+            t[101] = ('x'):rep(50) .. ('y'):rep(50)
+            return #t
+        ", "=alloc").unwrap());
+        state.call(proto, vec![]).unwrap();
+        assert!(in_use.load(Ordering::Relaxed) > before + 100 * 4);
+        state.collect_garbage();
+        assert!(in_use.load(Ordering::Relaxed) < before + 100 * 4);
+        drop(state);
+        assert_eq!(in_use.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_allocator() {
+        let in_use = Arc::new(AtomicUsize::new(0));
+        let mut state = SyxState::new();
+        super::super::stdlib::open_libs(&mut state);
+        let tracking = Tracking { in_use: in_use.clone(), budget: usize::MAX };
+        assert!(state.gc().set_allocator(Some(Box::new(tracking))).is_none());
+        let before = in_use.load(Ordering::Relaxed);
+        assert!(before > 0);
+        let proto = Arc::new(super::super::compiler::parse(b"
+            local t = {}
+            for i = 1, 1000 do t[i] = {i} end
+            t = nil
+            local keep = {}
+            for i = 1, 100 do keep[i] = function() return i end end
+            return keep
+        ", "=alloc").unwrap());
+        let keep = state.call(proto.clone(), vec![]).unwrap().into_first();
+        let key = state.create_ref(keep);
+        state.collect_garbage();
+        let kept = in_use.load(Ordering::Relaxed);
+        assert!(kept > before);
+        state.remove_ref(key);
+        state.collect_garbage();
+        assert!(in_use.load(Ordering::Relaxed) < kept);
+        drop(state);
+        assert_eq!(in_use.load(Ordering::Relaxed), 0);
+
+        // going over the budget raises an error scripts can catch
+        let mut state = SyxState::new();
+        super::super::stdlib::open_libs(&mut state);
+        let tracking = Tracking { in_use: in_use.clone(), budget: 256 * 1024 };
+        state.gc().set_allocator(Some(Box::new(tracking)));
+        let proto = super::super::compiler::parse(b"
+            local ok, message = pcall(function()
+                local t = {}
+                for i = 1, 1e7 do t[i] = {} end
+            end)
+            local t = {}
+            for i = 1, 100 do t[i] = {} end
+            return ok, message, #t
+        ", "=alloc").unwrap();
+        let results = state.call(Arc::new(proto), Vec::new()).unwrap();
+        assert_eq!(results, vec![
+            SyxValue::Bool(false), SyxValue::from("not enough memory"), SyxValue::Integer(100),
+        ]);
+        assert!(in_use.load(Ordering::Relaxed) <= 256 * 1024);
+
+        // a string over the budget is refused before it is built
+        let results = run_in(&mut state, "return pcall(string.rep, 'x', 1e6)").unwrap();
+        assert_eq!(results, vec![SyxValue::Bool(false), SyxValue::from("not enough memory")]);
+        assert_eq!(run_in(&mut state, "return #('x'):rep(1000)").unwrap(),
+                   vec![SyxValue::Integer(1000)]);
+    }
+}
@@ -0,0 +1,186 @@
+// Debug hooks
+//
+// A state can have one hook, a Rust closure called on the events its mask
+// selects: calls and returns of functions, Lua or native, each new line a
+// Lua function runs (or the same line again after a jump back), and every
+// `count` instructions. An error the hook returns is raised where the
+// script is, so it can also stop scripts that run too long.
+//
+// The hook is taken out of the state while it runs, so anything it calls
+// does not trigger it again; it may replace or remove itself meanwhile.
+//
+// Consult the versioned ldo.c (luaD_hook) and ldebug.c (luaG_traceexec) for
+// more information.
+
+use super::errors::Result;
+use super::object::Proto;
+use super::state::SyxState;
+
+// Events a hook is called for
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HookMask {
+    pub call: bool,
+    pub ret: bool,
+    pub line: bool,
+    pub count: usize, // instructions between count events, 0 for none
+}
+
+impl HookMask {
+    pub fn is_empty(&self) -> bool {
+        !self.call && !self.ret && !self.line && self.count == 0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HookEvent {
+    Call,      // a function was entered; for Lua ones, before their first instruction
+    TailCall,  // a Lua function was entered in place of the one tail calling it
+    Return,    // a function is about to return, its frame still running
+    Line(i32), // a Lua function is about to run an instruction of this line
+    Count,     // `count` more instructions have run
+}
+
+pub type Hook = Box<dyn FnMut(&mut SyxState, HookEvent) -> Result<()> + Send>;
+
+impl SyxState {
+    // Call `hook` on the events in `mask`, replacing any hook already set.
+    // An empty mask removes the hook.
+    pub fn set_hook(&mut self, mask: HookMask,
+                    hook: impl FnMut(&mut SyxState, HookEvent) -> Result<()> + Send + 'static)
+    {
+        if mask.is_empty() {
+            self.remove_hook();
+        } else {
+            self.hook = Some(Box::new(hook));
+            self.hook_mask = mask;
+            self.hook_count = mask.count;
+        }
+    }
+
+    // Call `hook` after every `count` instructions run by Lua functions, like
+    // a LUA_MASKCOUNT hook. A count of 0 removes the hook.
+    pub fn set_count_hook(&mut self, count: usize,
+                          mut hook: impl FnMut(&mut SyxState) -> Result<()> + Send + 'static)
+    {
+        let mask = HookMask { count, ..HookMask::default() };
+        self.set_hook(mask, move |state, _| hook(state));
+    }
+
+    pub fn remove_hook(&mut self) {
+        self.hook = None;
+        self.hook_mask = HookMask::default();
+        self.hook_count = 0;
+    }
+
+    pub fn hook_mask(&self) -> HookMask {
+        self.hook_mask
+    }
+
+    pub(crate) fn call_hook(&mut self, event: HookEvent) -> Result<()> {
+        let mut hook = match self.hook.take() {
+            Some(hook) => hook,
+            None => return Ok(()),
+        };
+        let result = hook(self, event);
+        if self.hook.is_none() && !self.hook_mask.is_empty() {
+            self.hook = Some(hook);
+        }
+        result
+    }
+
+    // function entry and exit, when the mask asks for them
+    pub(crate) fn hook_call(&mut self) -> Result<()> {
+        if self.hook.is_some() && self.hook_mask.call {
+            self.call_hook(HookEvent::Call)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn hook_tail_call(&mut self) -> Result<()> {
+        if self.hook.is_some() && self.hook_mask.call {
+            self.call_hook(HookEvent::TailCall)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn hook_return(&mut self) -> Result<()> {
+        if self.hook.is_some() && self.hook_mask.ret {
+            self.call_hook(HookEvent::Return)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Count and line events for instruction `pc` of `proto`, which is about
+    // to run. A line event is for a new function, a new line, or a jump back
+    // to an instruction already run, as in a loop on one line.
+    pub(crate) fn trace_exec(&mut self, proto: &Proto, pc: usize) -> Result<()> {
+        let mask = self.hook_mask;
+        if mask.count > 0 {
+            self.hook_count -= 1;
+            if self.hook_count == 0 {
+                self.hook_count = mask.count;
+                self.call_hook(HookEvent::Count)?;
+            }
+        }
+        if mask.line && self.hook.is_some() {
+            if let Some(&line) = proto.lineinfo.get(pc) {
+                let oldpc = self.oldpc;
+                if pc == 0 || pc <= oldpc || proto.lineinfo.get(oldpc) != Some(&line) {
+                    self.oldpc = pc;
+                    return self.call_hook(HookEvent::Line(line));
+                }
+            }
+        }
+        self.oldpc = pc;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use super::super::compiler::parse;
+    use super::super::stdlib;
+
+    fn events(source: &str, mask: HookMask) -> Vec<HookEvent> {
+        let proto = parse(source.as_bytes(), "=test").unwrap();
+        let mut state = SyxState::new();
+        stdlib::open_libs(&mut state);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = events.clone();
+        state.set_hook(mask, move |_, event| {
+            seen.lock().unwrap().push(event);
+            Ok(())
+        });
+        state.call(Arc::new(proto), Vec::new()).unwrap();
+        let events = events.lock().unwrap().clone();
+        events
+    }
+
+    #[test]
+    fn test_line_events() {
+        let mask = HookMask { line: true, ..HookMask::default() };
+        let lines: Vec<_> = events("local x = 1\nfor i = 1, 2 do\nx = x + i\nend\nreturn x", mask)
+            .into_iter()
+            .map(|event| match event {
+                HookEvent::Line(line) => line,
+                event => panic!("unexpected event {:?}", event),
+            })
+            .collect();
+        assert_eq!(lines, vec![1, 2, 3, 2, 3, 2, 5]);
+    }
+
+    #[test]
+    fn test_call_events() {
+        let mask = HookMask { call: true, ret: true, ..HookMask::default() };
+        let events = events("local function f() return type(1) end\nf()", mask);
+        // the main function, f, and type
+        assert_eq!(events, vec![HookEvent::Call, HookEvent::Call, HookEvent::Call,
+                                HookEvent::Return, HookEvent::Return, HookEvent::Return]);
+    }
+}
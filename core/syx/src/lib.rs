@@ -0,0 +1,65 @@
+// error_chain! expands recursively, once per error kind
+#![recursion_limit = "256"]
+
+#[macro_use]
+extern crate error_chain;
+
+extern crate syx_codegen;
+
+#[cfg(unix)]
+extern crate libc;
+
+#[cfg(feature = "serde")]
+extern crate serde;
+
+// derived conversions refer to ::syx, this crate included
+extern crate self as syx;
+
+pub mod errors;
+pub mod conf;
+pub mod opcodes;
+pub mod limits;
+pub mod object;
+pub mod string;
+pub mod state;
+pub mod alloc;
+pub mod gc;
+pub mod func;
+pub mod native;
+pub mod userdata;
+pub mod registry;
+pub mod convert;
+#[cfg(feature = "serde")]
+pub mod serialize;
+#[cfg(feature = "msgpack")]
+pub mod msgpack;
+#[cfg(feature = "nanbox")]
+pub mod nanbox;
+pub mod coroutine;
+pub mod task;
+pub mod protect;
+pub mod api;
+pub mod debug;
+pub mod hook;
+pub mod trace;
+pub mod preempt;
+pub mod tm;
+pub mod numfmt;
+pub mod vm;
+pub mod undump;
+#[cfg(unix)]
+pub mod mmap;
+pub mod dump;
+pub mod listing;
+pub mod diff;
+pub mod asm;
+pub mod verify;
+pub mod optimize;
+pub mod format;
+pub mod compiler;
+pub mod stdlib;
+#[cfg(test)]
+mod testing;
+
+#[macro_use]
+mod macros;
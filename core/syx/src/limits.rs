@@ -1 +1,31 @@
 pub const SYX_MAXSHORTLEN: usize = 40;
+
+// Instruction layout: field sizes and offsets in a Word, and the largest
+// operand each field holds (see opcodes.rs)
+
+pub const SIZE_OP: u32 = 6;
+pub const SIZE_A: u32 = 8;
+pub const SIZE_B: u32 = 9;
+pub const SIZE_C: u32 = 9;
+pub const SIZE_BX: u32 = SIZE_C + SIZE_B;
+pub const SIZE_AX: u32 = SIZE_C + SIZE_B + SIZE_A;
+
+pub const OFFSET_OP: u32 = 0;
+pub const OFFSET_A: u32 = OFFSET_OP + SIZE_OP;
+pub const OFFSET_C: u32 = OFFSET_A + SIZE_A;
+pub const OFFSET_B: u32 = OFFSET_C + SIZE_C;
+pub const OFFSET_BX: u32 = OFFSET_C;
+pub const OFFSET_AX: u32 = OFFSET_A;
+
+pub const MAXARG_OP: u32 = (1 << SIZE_OP) - 1;
+pub const MAXARG_A: u32 = (1 << SIZE_A) - 1;
+pub const MAXARG_B: u32 = (1 << SIZE_B) - 1;
+pub const MAXARG_C: u32 = (1 << SIZE_C) - 1;
+pub const MAXARG_BX: u32 = (1 << SIZE_BX) - 1;
+pub const MAXARG_AX: u32 = (1 << SIZE_AX) - 1;
+// sBx is stored in excess-K: the raw field minus MAXARG_SBX
+pub const MAXARG_SBX: i32 = (MAXARG_BX >> 1) as i32;
+
+// RK(x) refers to a constant when this is set in a B or C operand
+pub const BITRK: u32 = 1 << (SIZE_B - 1);
+pub const MAXINDEXRK: u32 = BITRK - 1;
@@ -0,0 +1,383 @@
+// Listings of compiled functions
+//
+// The text `luac -l` prints for a Proto: a header per function followed by
+// its instructions, with operands decoded and constants, upvalue names and
+// jump targets noted beside them. The full listing (`luac -l -l`) also
+// prints the constant, local and upvalue tables of each function.
+//
+// `Proto::pretty` (and `Pretty`, and the Debug impl of Proto) is the same
+// code in a shorter form for tests and tools: no addresses, so it can be
+// compared, and nested functions indented under the one they are defined in
+// and named by their index there.
+//
+// Consult the versioned luac.c for more information.
+
+use std::fmt::{self, Write};
+
+use super::conf::SYX_HEADER;
+use super::object::{Proto, SyxValue};
+use super::opcodes::{Instruction, OpCode};
+use super::vm::append_string;
+
+const BITRK: u16 = 1 << 8; // see vm.rs
+
+impl Proto {
+    // Listing of this function and all the functions defined in it, with
+    // their constants, locals and upvalues when `full`
+    pub fn listing(&self, full: bool) -> String {
+        let mut output = String::new();
+        list_function(&mut output, self, full);
+        output
+    }
+}
+
+// Display of a function as `Proto::pretty` prints it
+pub struct Pretty<'a>(pub &'a Proto);
+
+impl<'a> fmt::Display for Pretty<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut output = String::new();
+        pretty_function(&mut output, self.0, "main", "");
+        f.write_str(&output)
+    }
+}
+
+impl fmt::Debug for Proto {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&Pretty(self), f)
+    }
+}
+
+impl Proto {
+    // Source, sizes, upvalues and decoded code of this function and all the
+    // functions defined in it
+    pub fn pretty(&self) -> String {
+        Pretty(self).to_string()
+    }
+}
+
+fn plural(n: usize) -> &'static str {
+    if n == 1 { "" } else { "s" }
+}
+
+// constant indices are shown negative, counting from -1
+fn rk(x: u16) -> i32 {
+    if x & BITRK != 0 { -1 - (x & !BITRK) as i32 } else { x as i32 }
+}
+
+// whether B and C of an ABC instruction are used at all
+pub(crate) fn operands(op: OpCode) -> (bool, bool) {
+    match op {
+        OpCode::LoadKX | OpCode::Check => (false, false),
+        OpCode::Move | OpCode::LoadNil | OpCode::GetUpval | OpCode::SetUpval |
+        OpCode::Unm | OpCode::BNot | OpCode::Not | OpCode::Len | OpCode::Return |
+        OpCode::VarArg => (true, false),
+        OpCode::Test | OpCode::TForCall => (false, true),
+        _ => (true, true),
+    }
+}
+
+pub(crate) fn opname(op: OpCode) -> String {
+    match op {
+        OpCode::SelfLoad => "SELF".to_owned(),
+        _ => format!("{:?}", op).to_uppercase(),
+    }
+}
+
+fn upvalue_name(proto: &Proto, index: usize) -> String {
+    match proto.upvalues.get(index) {
+        Some(upvalue) if !upvalue.name.is_empty() => upvalue.name.to_string(),
+        _ => "-".to_owned(),
+    }
+}
+
+fn constant(proto: &Proto, index: usize) -> String {
+    match proto.constants.get(index) {
+        Some(value) => constant_text(value),
+        None => "?".to_owned(),
+    }
+}
+
+// A constant as a listing shows it, strings quoted
+pub(crate) fn constant_text(value: &SyxValue) -> String {
+    match *value {
+        SyxValue::Nil => "nil".to_owned(),
+        SyxValue::Bool(b) => b.to_string(),
+        SyxValue::String(ref s) => {
+            let mut quoted = String::from("\"");
+            for &c in s.iter() {
+                match c {
+                    b'"' => quoted.push_str("\\\""),
+                    b'\\' => quoted.push_str("\\\\"),
+                    0x07 => quoted.push_str("\\a"),
+                    0x08 => quoted.push_str("\\b"),
+                    0x0c => quoted.push_str("\\f"),
+                    b'\n' => quoted.push_str("\\n"),
+                    b'\r' => quoted.push_str("\\r"),
+                    b'\t' => quoted.push_str("\\t"),
+                    0x0b => quoted.push_str("\\v"),
+                    c if c.is_ascii_graphic() || c == b' ' => quoted.push(c as char),
+                    c => { let _ = write!(quoted, "\\{:03}", c); }
+                }
+            }
+            quoted.push('"');
+            quoted
+        }
+        _ => {
+            let mut buffer = Vec::new();
+            if append_string(&mut buffer, value) {
+                String::from_utf8_lossy(&buffer).into_owned()
+            } else {
+                "?".to_owned()
+            }
+        }
+    }
+}
+
+// RK(`x`) as a constant when it is one, "-" otherwise
+fn rk_constant(proto: &Proto, x: u16) -> String {
+    if x & BITRK != 0 {
+        constant(proto, (x & !BITRK) as usize)
+    } else {
+        "-".to_owned()
+    }
+}
+
+fn list_header(output: &mut String, proto: &Proto) {
+    let source = if proto.source.starts_with('@') || proto.source.starts_with('=') {
+        &proto.source[1..]
+    } else if proto.source.as_bytes().starts_with(SYX_HEADER) {
+        "(bstring)"
+    } else if proto.source.is_empty() {
+        "?"
+    } else {
+        "(string)"
+    };
+    let n = proto.instructions.len();
+    let _ = writeln!(output, "\n{} <{}:{},{}> ({} instruction{} at {:p})",
+                     if proto.linedefined == 0 { "main" } else { "function" },
+                     source, proto.linedefined, proto.lastlinedefined, n, plural(n), proto);
+    let params = proto.numparams as usize;
+    let slots = proto.maxstacksize as usize;
+    let upvalues = proto.upvalues.len();
+    let locals = proto.locvars.len();
+    let constants = proto.constants.len();
+    let functions = proto.protos.len();
+    let _ = writeln!(output, "{}{} param{}, {} slot{}, {} upvalue{}, {} local{}, \
+                              {} constant{}, {} function{}",
+                     params, if proto.is_vararg != 0 { "+" } else { "" }, plural(params),
+                     slots, plural(slots), upvalues, plural(upvalues), locals, plural(locals),
+                     constants, plural(constants), functions, plural(functions));
+}
+
+// Opcode and operands of an instruction
+fn arguments(instruction: &Instruction) -> (OpCode, String) {
+    match *instruction {
+        Instruction::ABC { instruction: op, a, b, c } => {
+            let (has_b, has_c) = operands(op);
+            let mut args = a.to_string();
+            if has_b {
+                let _ = write!(args, " {}", rk(b));
+            }
+            if has_c {
+                let _ = write!(args, " {}", rk(c));
+            }
+            (op, args)
+        }
+        Instruction::ABx { instruction: OpCode::LoadK, a, bx } => {
+            (OpCode::LoadK, format!("{} {}", a, -1 - bx as i64))
+        }
+        Instruction::ABx { instruction: OpCode::Closure, a, bx } => {
+            (OpCode::Closure, format!("{} {}", a, bx))
+        }
+        Instruction::ABx { instruction: op, a, .. } => (op, a.to_string()),
+        Instruction::AsBx { instruction: op, a, sbx } => (op, format!("{} {}", a, sbx)),
+        Instruction::Ax { instruction: op, ax } => (op, (-1 - ax as i64).to_string()),
+    }
+}
+
+// An instruction as a listing shows it, without its comment
+pub(crate) fn instruction_text(instruction: &Instruction) -> String {
+    let (op, args) = arguments(instruction);
+    format!("{} {}", opname(op), args)
+}
+
+// Opcode, operands and comment of instruction `pc`, with functions named by
+// their address or by their index
+pub(crate) fn decode(proto: &Proto, pc: usize, addresses: bool) -> (OpCode, String, String) {
+    let (op, args) = arguments(&proto.instructions[pc]);
+    let comment = match proto.instructions[pc] {
+        Instruction::ABC { a, b, c, .. } => match op {
+            OpCode::GetUpval | OpCode::SetUpval => upvalue_name(proto, b as usize),
+            OpCode::GetTabUp if c & BITRK != 0 => {
+                format!("{} {}", upvalue_name(proto, b as usize), rk_constant(proto, c))
+            }
+            OpCode::GetTabUp => upvalue_name(proto, b as usize),
+            OpCode::SetTabUp => {
+                let mut comment = upvalue_name(proto, a as usize);
+                for &x in &[b, c] {
+                    if x & BITRK != 0 {
+                        let _ = write!(comment, " {}", rk_constant(proto, x));
+                    }
+                }
+                comment
+            }
+            OpCode::GetTable | OpCode::SelfLoad if c & BITRK != 0 => {
+                rk_constant(proto, c)
+            }
+            OpCode::SetTable | OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::Mod |
+            OpCode::Pow | OpCode::Div | OpCode::IDiv | OpCode::BAnd | OpCode::BOr |
+            OpCode::BXOr | OpCode::Shl | OpCode::Shr | OpCode::Eq | OpCode::Lt |
+            OpCode::Le if (b | c) & BITRK != 0 => {
+                format!("{} {}", rk_constant(proto, b), rk_constant(proto, c))
+            }
+            OpCode::SetList if c == 0 => match proto.instructions.get(pc + 1) {
+                Some(&Instruction::Ax { ax, .. }) => ax.to_string(),
+                _ => "?".to_owned(),
+            },
+            OpCode::SetList => c.to_string(),
+            _ => String::new(),
+        },
+        Instruction::ABx { bx, .. } => match op {
+            OpCode::LoadK => constant(proto, bx as usize),
+            OpCode::Closure => match proto.protos.get(bx as usize) {
+                Some(child) if addresses => format!("{:p}", &**child),
+                Some(_) => format!("function {}", bx + 1),
+                None => "?".to_owned(),
+            },
+            _ => String::new(),
+        },
+        Instruction::AsBx { sbx, .. } => match op {
+            OpCode::Jmp | OpCode::ForLoop | OpCode::ForPrep | OpCode::TForLoop => {
+                format!("to {}", sbx as i64 + pc as i64 + 2)
+            }
+            _ => String::new(),
+        },
+        Instruction::Ax { .. } => String::new(),
+    };
+    (op, args, comment)
+}
+
+fn line(proto: &Proto, pc: usize) -> String {
+    match proto.lineinfo.get(pc) {
+        Some(line) => line.to_string(),
+        None => "-".to_owned(),
+    }
+}
+
+fn list_code(output: &mut String, proto: &Proto) {
+    for pc in 0..proto.instructions.len() {
+        let (op, args, comment) = decode(proto, pc, true);
+        let _ = write!(output, "\t{}\t[{}]\t{:<9}\t{}", pc + 1, line(proto, pc), opname(op), args);
+        if !comment.is_empty() {
+            let _ = write!(output, "\t; {}", comment);
+        }
+        output.push('\n');
+    }
+}
+
+fn list_debug(output: &mut String, proto: &Proto) {
+    let _ = writeln!(output, "constants ({}) for {:p}:", proto.constants.len(), proto);
+    for i in 0..proto.constants.len() {
+        let _ = writeln!(output, "\t{}\t{}", i + 1, constant(proto, i));
+    }
+    let _ = writeln!(output, "locals ({}) for {:p}:", proto.locvars.len(), proto);
+    for (i, locvar) in proto.locvars.iter().enumerate() {
+        let _ = writeln!(output, "\t{}\t{}\t{}\t{}",
+                         i, locvar.varname, locvar.startpc + 1, locvar.endpc + 1);
+    }
+    let _ = writeln!(output, "upvalues ({}) for {:p}:", proto.upvalues.len(), proto);
+    for (i, upvalue) in proto.upvalues.iter().enumerate() {
+        let _ = writeln!(output, "\t{}\t{}\t{}\t{}",
+                         i, upvalue_name(proto, i), upvalue.instack, upvalue.idx);
+    }
+}
+
+fn list_function(output: &mut String, proto: &Proto, full: bool) {
+    list_header(output, proto);
+    list_code(output, proto);
+    if full {
+        list_debug(output, proto);
+    }
+    for child in &proto.protos {
+        list_function(output, child, full);
+    }
+}
+
+fn pretty_function(output: &mut String, proto: &Proto, name: &str, indent: &str) {
+    let _ = writeln!(output, "{}{} <{}:{},{}>", indent, name,
+                     proto.source, proto.linedefined, proto.lastlinedefined);
+    let upvalues: Vec<String> = (0..proto.upvalues.len())
+        .map(|i| upvalue_name(proto, i))
+        .collect();
+    let _ = writeln!(output, "{}  params: {}{}, stack: {}, upvalues: [{}]", indent,
+                     proto.numparams, if proto.is_vararg != 0 { "+" } else { "" },
+                     proto.maxstacksize, upvalues.join(", "));
+    for pc in 0..proto.instructions.len() {
+        let (op, args, comment) = decode(proto, pc, false);
+        let _ = write!(output, "{}  {:>4} [{}] {:<9} {}",
+                       indent, pc + 1, line(proto, pc), opname(op), args);
+        if !comment.is_empty() {
+            let _ = write!(output, "  ; {}", comment);
+        }
+        output.push('\n');
+    }
+    let indent = format!("{}  ", indent);
+    for (i, child) in proto.protos.iter().enumerate() {
+        pretty_function(output, child, &format!("function {}", i + 1), &indent);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::compiler::parse;
+
+    #[test]
+    fn test_listing() {
+        let proto = parse(b"local t = {} t.x = 'a\\n' print(t.x)", "@test.lua").unwrap();
+        let listing = proto.listing(false);
+        assert!(listing.starts_with("\nmain <test.lua:0,0> (6 instructions at "));
+        assert!(listing.contains("0+ params, 3 slots, 1 upvalue, 1 local, 3 constants, 0 functions\n"));
+        assert!(listing.contains("\t2\t[1]\tSETTABLE \t0 -1 -2\t; \"x\" \"a\\n\"\n"));
+        assert!(listing.contains("\t3\t[1]\tGETTABUP \t1 0 -3\t; _ENV \"print\"\n"));
+        assert!(listing.contains("\t6\t[1]\tRETURN   \t0 1\n"));
+        assert!(!listing.contains("constants (3)"));
+
+        let listing = proto.listing(true);
+        assert!(listing.contains("constants (3) for "));
+        assert!(listing.contains("\t1\t\"x\"\n"));
+        assert!(listing.contains("\t0\tt\t2\t7\n"));
+        assert!(listing.contains("\t0\t_ENV\t1\t0\n"));
+    }
+
+    #[test]
+    fn test_jumps_and_functions() {
+        let proto = parse(b"for i = 1, 2 do end local function f(...) end", "=test").unwrap();
+        let listing = proto.listing(false);
+        assert!(listing.contains("FORPREP  \t0 0\t; to 5\n"));
+        assert!(listing.contains("FORLOOP  \t0 -1\t; to 5\n"));
+        assert!(listing.contains("\nfunction <test:1,1> (1 instruction at "));
+        assert!(listing.contains("0+ params, 2 slots, 0 upvalues, 0 locals"));
+    }
+
+    #[test]
+    fn test_pretty() {
+        let proto = parse(b"local function f(a, ...) return a end print(f(1.5))", "=test").unwrap();
+        assert_eq!(proto.pretty(), "\
+main <=test:0,0>
+  params: 0+, stack: 4, upvalues: [_ENV]
+     1 [1] CLOSURE   0 0  ; function 1
+     2 [1] GETTABUP  1 0 -1  ; _ENV \"print\"
+     3 [1] MOVE      2 0
+     4 [1] LOADK     3 -2  ; 1.5
+     5 [1] CALL      2 2 0
+     6 [1] CALL      1 0 1
+     7 [1] RETURN    0 1
+  function 1 <=test:1,1>
+    params: 1+, stack: 2, upvalues: []
+       1 [1] RETURN    0 2
+       2 [1] RETURN    0 1
+");
+        assert_eq!(format!("{:?}", proto), proto.pretty());
+    }
+}
@@ -1,96 +1,241 @@
-#[macro_use]
-extern crate error_chain;
+// syx
+//
+// Runs Lua scripts, source or binary chunks, and without one reads lines
+// interactively: each line is tried as an expression whose values get
+// printed, then as statements, and keeps reading while the statement is
+// incomplete. Every chunk runs in the same state, so globals persist.
+//
+// Consult the versioned lua.c for more information.
 
-extern crate syx_codegen;
+extern crate syx;
 
-mod errors;
-mod conf;
-mod opcodes;
-mod limits;
-mod object;
-mod state;
-mod undump;
+use std::env;
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::process;
+use std::sync::Arc;
 
-#[macro_use]
-mod macros;
+use syx::errors::{Error, Result};
+use syx::object::{Proto, SyxInteger, SyxValue};
+use syx::state::SyxState;
+use syx::{compiler, stdlib};
 
-use object::SyxValue;
-use std::fs::File;
+const USAGE: &str = "usage: syx [options] [script [args]]
+Available options are:
+  -e stat  execute string 'stat'
+  -i       enter interactive mode after executing 'script'
+  -v       show version information
+  --       stop handling options
+  -        stop handling options and execute stdin";
 
-fn main() {
-    if let Err(e) = run() {
-        use std::io::Write;
-        let stderr = &mut ::std::io::stderr();
-        let errmsg = "failed to write to stdout";
+const PROMPT: &str = "> ";
+const PROMPT2: &str = ">> ";
 
-        writeln!(stderr, "error: {}", e).expect(errmsg);
+struct Options {
+    execute: Vec<String>, // -e statements, in order
+    interactive: bool,
+    version: bool,
+    script: Option<usize>, // index of the script in the arguments
+}
 
-        for e in e.iter().skip(1) {
-            writeln!(stderr, "caused by: {}", e).expect(errmsg);
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let options = match parse_options(&args) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("syx: {}\n{}", message, USAGE);
+            process::exit(1);
         }
+    };
+    let mut state = SyxState::new();
+    stdlib::open_libs(&mut state);
+    if let Err(error) = run(&mut state, &args, &options) {
+        eprintln!("syx: {}", error);
+        process::exit(1);
     }
 }
 
-fn run() -> errors::Result<()> {
-    let args: Vec<_> = ::std::env::args().collect();
-    let main_chunk = match args.get(1) {
-        None => {
-            println!("test");
-            panic!("Usage: {} [filename]", args[0]);
-        }
-        Some(file) => {
-            let handle = File::open(file).unwrap();
-            undump::LoadState::from_read(handle, file.clone())?
-        }
+fn parse_options(args: &[String]) -> ::std::result::Result<Options, String> {
+    let mut options = Options {
+        execute: Vec::new(),
+        interactive: false,
+        version: false,
+        script: None,
     };
-    if !main_chunk.constants.is_empty() {
-        println!();
-        println!("constants:");
-        for constant in main_chunk.constants {
-            match constant {
-                SyxValue::Bool(boolean) => {
-                    println!("bool: {}", boolean);
-                }
-                SyxValue::Number(n) => {
-                    println!("number: {}", n);
-                }
-                SyxValue::Integer(n) => {
-                    println!("integer: {}", n);
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--" => {
+                i += 1;
+                break;
+            }
+            "-" => break,
+            "-i" => {
+                options.interactive = true;
+                options.version = true;
+            }
+            "-v" => options.version = true,
+            "-e" => {
+                i += 1;
+                match args.get(i) {
+                    Some(stat) => options.execute.push(stat.clone()),
+                    None => return Err("'-e' needs argument".to_owned()),
                 }
-                SyxValue::String(s) => match String::from_utf8(s.clone()) {
-                    Ok(string) => println!("string: {}", string),
-                    Err(_) => println!("vec<u8>: {:?}", s),
-                },
-                _ => (),
             }
+            option if option.starts_with('-') => {
+                return Err(format!("unrecognized option '{}'", option));
+            }
+            _ => break,
         }
+        i += 1;
     }
-    if !main_chunk.locvars.is_empty() {
-        println!();
-        println!("locals:");
-        for local in main_chunk.locvars {
-            if let Ok(name) = String::from_utf8(local.varname) {
-                println!("local: {}", name)
-            }
+    if i < args.len() {
+        options.script = Some(i);
+    }
+    Ok(options)
+}
+
+fn run(state: &mut SyxState, args: &[String], options: &Options) -> Result<()> {
+    if options.version {
+        print_version();
+    }
+    create_arg_table(state, args, options.script.unwrap_or(0));
+    for stat in &options.execute {
+        let proto = compiler::parse(stat.as_bytes(), "=(command line)")?;
+        state.call(Arc::new(proto), Vec::new())?;
+    }
+    if let Some(script) = options.script {
+        let script_args = args[script + 1..].iter()
+            .map(|arg| SyxValue::String(state.intern(arg.as_bytes())))
+            .collect();
+        let proto = if args[script] == "-" {
+            compiler::load(read_stdin()?, "=stdin")?
+        } else {
+            compiler::load_file(&args[script])?
+        };
+        state.call(Arc::new(proto), script_args)?;
+    }
+    if options.interactive {
+        repl(state);
+    } else if options.script.is_none() && options.execute.is_empty() && !options.version {
+        if io::stdin().is_terminal() {
+            print_version();
+            repl(state);
+        } else {
+            let proto = compiler::load(read_stdin()?, "=stdin")?;
+            state.call(Arc::new(proto), Vec::new())?;
         }
     }
-    if !main_chunk.upvalues.is_empty() {
-        println!();
-        println!("upvalues:");
-        for upval in main_chunk.upvalues {
-            if upval.name == vec![] {
-                println!("instack: {}, idx: {}", upval.instack, upval.idx);
-            } else if let Ok(string) = String::from_utf8(upval.name) {
-                println!("{} [{}, {}]", string, upval.instack, upval.idx);
+    Ok(())
+}
+
+fn print_version() {
+    println!("Syx {} (Lua 5.3)", env!("CARGO_PKG_VERSION"));
+}
+
+// arg[0] is the script and arg[1..] its arguments; the interpreter and the
+// options before it get the negative indices
+fn create_arg_table(state: &mut SyxState, args: &[String], script: usize) {
+    let table = state.new_table(args.len(), 0);
+    for (i, arg) in args.iter().enumerate() {
+        let value = SyxValue::String(state.intern(arg.as_bytes()));
+        state.table_mut(table).set_int(i as SyxInteger - script as SyxInteger, value);
+    }
+    let globals = state.globals();
+    let key = SyxValue::String(state.intern(b"arg"));
+    state.table_mut(globals).set(key, SyxValue::Table(table))
+        .expect("string keys are valid");
+}
+
+fn read_stdin() -> Result<Vec<u8>> {
+    let mut source = Vec::new();
+    io::stdin().read_to_end(&mut source)
+        .map_err(|error| format!("cannot read stdin: {}", error))?;
+    Ok(source)
+}
+
+// whether compiling failed only because the text ended too soon
+fn incomplete(error: &Error) -> bool {
+    error.to_string().ends_with("<eof>")
+}
+
+fn read_line(input: &mut impl BufRead, prompt: &str) -> Option<String> {
+    print!("{}", prompt);
+    io::stdout().flush().ok()?;
+    let mut line = String::new();
+    match input.read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => {
+            let len = line.trim_end_matches(['\n', '\r']).len();
+            line.truncate(len);
+            Some(line)
+        }
+    }
+}
+
+// Compile the next chunk typed: as 'return <line>' if that works, otherwise
+// as statements, reading more lines for as long as they are incomplete. None
+// at the end of the input.
+fn load_line(input: &mut impl BufRead) -> Option<Result<Proto>> {
+    let mut line = read_line(input, PROMPT)?;
+    if let Some(expression) = line.strip_prefix('=') {
+        line = format!("return {}", expression);
+    }
+    if let Ok(proto) = compiler::parse(format!("return {}", line).as_bytes(), "=stdin") {
+        return Some(Ok(proto));
+    }
+    loop {
+        match compiler::parse(line.as_bytes(), "=stdin") {
+            Err(ref error) if incomplete(error) => {
+                let more = read_line(input, PROMPT2)?;
+                line.push('\n');
+                line.push_str(&more);
             }
+            result => return Some(result),
         }
     }
-    if !main_chunk.instructions.is_empty() {
-        println!();
-        println!("instructions:");
-        for instr in main_chunk.instructions {
-            println!("{:?}", instr);
+}
+
+fn repl(state: &mut SyxState) {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    while let Some(result) = load_line(&mut input) {
+        let results = result.and_then(|proto| state.call(Arc::new(proto), Vec::new()));
+        match results {
+            Ok(ref values) if values.is_empty() => {}
+            Ok(values) => {
+                // through the global print, so scripts can replace it
+                let key = SyxValue::String(state.intern(b"print"));
+                let print = state.table(state.globals()).get(&key);
+                if let Err(error) = state.pcall(print, values.into_vec()) {
+                    eprintln!("error calling 'print' ({})", error);
+                }
+            }
+            Err(error) => eprintln!("{}", error),
         }
     }
-    Ok(())
+    println!();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_incomplete() {
+        let error = compiler::parse(b"for i = 1, 2 do", "=stdin").err().unwrap();
+        assert!(incomplete(&error));
+        let error = compiler::parse(b"x = = 1", "=stdin").err().unwrap();
+        assert!(!incomplete(&error));
+    }
+
+    #[test]
+    fn test_options() {
+        let args: Vec<String> = ["syx", "-e", "x = 1", "-i", "script.lua", "-v"]
+            .iter().map(|s| s.to_string()).collect();
+        let options = parse_options(&args).unwrap();
+        assert_eq!(options.execute, vec!["x = 1"]);
+        assert!(options.interactive);
+        assert_eq!(options.script, Some(4));
+        assert!(parse_options(&["syx".to_owned(), "-x".to_owned()]).is_err());
+    }
 }
@@ -0,0 +1,131 @@
+// Files mapped into memory
+//
+// A MappedFile is the contents of a file as read-only pages the kernel reads
+// in when they are first touched, rather than a buffer filled up front.
+// Loaded with `LoadState::from_shared`, a chunk costs the pages its code and
+// short strings are read from, and its long string constants point into the
+// mapping, which lives as long as any of them does.
+//
+// The mapping is private, but that only keeps writes to the mapping out of the
+// file, not changes to the file out of the mapping: reading pages a truncation
+// cut off faults (SIGBUS), and rewriting the file may change bytes Rust has
+// been promised are immutable. Opening one is therefore unsafe, as with
+// memmap2::Mmap::map; read the file into a buffer when that cannot be ruled
+// out.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+use std::slice;
+
+use libc;
+
+use super::errors::*;
+
+pub struct MappedFile {
+    address: *mut libc::c_void,
+    length: usize,
+}
+
+// the pages are never written, and unmapped only once nothing refers to them
+unsafe impl Send for MappedFile {}
+unsafe impl Sync for MappedFile {}
+
+impl MappedFile {
+    /// Map the whole of the file at `path`.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be truncated or modified, by this process or any
+    /// other, for as long as the MappedFile or any slice or string taken from
+    /// it lives.
+    pub unsafe fn open(path: impl AsRef<Path>) -> Result<MappedFile> {
+        // failures come before the first byte of the chunk is read
+        let name = path.as_ref().display().to_string();
+        let unreadable = || {
+            let error = UndumpError::new(&name, 0, Section::Header, UndumpReason::Unreadable);
+            ErrorKind::Undump(error)
+        };
+        let file = File::open(path.as_ref()).chain_err(unreadable)?;
+        let length = file.metadata().chain_err(unreadable)?.len() as usize;
+        if length == 0 {
+            // nothing to map, and mmap refuses empty mappings
+            return Ok(MappedFile { address: ptr::null_mut(), length });
+        }
+        // the mapping stays valid once the file is closed
+        let address = libc::mmap(ptr::null_mut(), length, libc::PROT_READ, libc::MAP_PRIVATE,
+                                 file.as_raw_fd(), 0);
+        if address == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error()).chain_err(unreadable);
+        }
+        Ok(MappedFile { address, length })
+    }
+}
+
+impl AsRef<[u8]> for MappedFile {
+    fn as_ref(&self) -> &[u8] {
+        if self.length == 0 {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.address as *const u8, self.length) }
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        if self.length > 0 {
+            unsafe {
+                libc::munmap(self.address, self.length);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::sync::Arc;
+
+    use super::*;
+    use super::super::compiler::parse;
+    use super::super::dump::DumpState;
+    use super::super::object::SyxValue;
+    use super::super::state::SyxState;
+    use super::super::string::SharedBuffer;
+    use super::super::undump::LoadState;
+
+    #[test]
+    fn test_mapped_chunk() {
+        let long = "x".repeat(100);
+        let source = format!("local s = '{}' return #s, s == string.rep('x', 100)", long);
+        let chunk = DumpState::to_u8(&parse(source.as_bytes(), "=test").unwrap()).unwrap();
+        let path = env::temp_dir().join(format!("syx-mmap-{}.out", process::id()));
+        fs::write(&path, &chunk).unwrap();
+
+        // nothing else knows of the file
+        let mapped = unsafe { MappedFile::open(&path) }.unwrap();
+        assert_eq!(mapped.as_ref(), &chunk[..]);
+        let buffer: SharedBuffer = Arc::new(mapped);
+        let proto = LoadState::from_shared(buffer.clone(), "mapped").unwrap();
+        fs::remove_file(&path).unwrap();
+        // the long string constant keeps the mapping
+        drop(buffer);
+        let mut state = SyxState::new();
+        super::super::stdlib::open_libs(&mut state);
+        let results = state.call(Arc::new(proto), Vec::new()).unwrap().into_vec();
+        assert!(results == vec![SyxValue::Integer(100), SyxValue::Bool(true)]);
+
+        let missing = env::temp_dir().join("syx-mmap-missing");
+        match unsafe { MappedFile::open(&missing) } {
+            Err(Error(ErrorKind::Undump(error), _)) => {
+                assert_eq!((error.offset, error.section), (0, Section::Header));
+                assert_eq!(error.reason, UndumpReason::Unreadable);
+            }
+            _ => panic!("expected an undump error"),
+        }
+    }
+}
@@ -0,0 +1,505 @@
+// MessagePack support
+//
+// With the `msgpack` feature, `SyxValue::to_msgpack` writes a value as
+// MessagePack and `SyxValue::from_msgpack` reads one back, for hosts that
+// exchange structured data with scripts over RPC. Tables whose keys are the
+// integers 1 to n become arrays and other tables maps, whose keys can be any
+// value; the empty table is an empty map. Metatables are not consulted.
+// Integers take the smallest format that holds them and floats are always
+// float 64. Strings that are valid UTF-8 are written as str, the others as
+// bin, and both are read back as strings. Functions, threads and userdata
+// can't be encoded, nor can tables referring back to themselves.
+//
+// Integers past the integer range are read as floats, as numerals are. Nil
+// in an array leaves a hole, and a map entry with a nil value is dropped.
+// Extension types are not supported.
+//
+// `Proto::msgpack_metadata` describes a function and those nested in it,
+// without their code, as a map that reads back as a table.
+//
+// Consult the MessagePack specification
+// (https://github.com/msgpack/msgpack/blob/master/spec.md) for more
+// information.
+
+use std::convert::TryFrom;
+
+use super::errors::*;
+use super::object::{
+    integer_to_i64, integer_value, number_to_f64, Proto, SyxInteger, SyxNumber, SyxValue,
+    TableRef,
+};
+use super::state::SyxState;
+
+const MAX_DEPTH: usize = 200; // arrays and maps nested in one another
+
+fn msgpack_error<T>(message: String) -> Result<T> {
+    Err(ErrorKind::MsgPackError(message).into())
+}
+
+impl SyxValue {
+    pub fn to_msgpack(&self, state: &SyxState) -> Result<Vec<u8>> {
+        let mut encoder = Encoder { state, out: Writer(Vec::new()), tables: Vec::new() };
+        encoder.value(self)?;
+        Ok(encoder.out.0)
+    }
+
+    // The value `bytes` hold, which must be exactly one
+    pub fn from_msgpack(state: &mut SyxState, bytes: &[u8]) -> Result<SyxValue> {
+        let mut decoder = Decoder { state, bytes, pos: 0, depth: 0 };
+        let value = decoder.value()?;
+        if decoder.pos < bytes.len() {
+            return msgpack_error(format!("bytes left over after the value at byte {}",
+                                         decoder.pos));
+        }
+        Ok(value)
+    }
+}
+
+impl Proto {
+    // A map of source, linedefined, lastlinedefined, numparams, is_vararg,
+    // maxstacksize, the number of instructions and constants, the names of
+    // the upvalues, the locals (each a map of name, startpc and endpc) and
+    // the same for each of the protos
+    pub fn msgpack_metadata(&self) -> Vec<u8> {
+        let mut out = Writer(Vec::new());
+        self.write_metadata(&mut out);
+        out.0
+    }
+
+    fn write_metadata(&self, out: &mut Writer) {
+        out.map_header(11);
+        out.field("source").bytes(self.source.as_bytes());
+        out.field("linedefined").integer(i64::from(self.linedefined));
+        out.field("lastlinedefined").integer(i64::from(self.lastlinedefined));
+        out.field("numparams").integer(i64::from(self.numparams));
+        out.field("is_vararg").boolean(self.is_vararg != 0);
+        out.field("maxstacksize").integer(i64::from(self.maxstacksize));
+        out.field("instructions").integer(self.instructions.len() as i64);
+        out.field("constants").integer(self.constants.len() as i64);
+        out.field("upvalues").array_header(self.upvalues.len());
+        for upvalue in &self.upvalues {
+            out.bytes(&upvalue.name);
+        }
+        out.field("locals").array_header(self.locvars.len());
+        for local in &self.locvars {
+            out.map_header(3);
+            out.field("name").bytes(&local.varname);
+            out.field("startpc").integer(i64::from(local.startpc));
+            out.field("endpc").integer(i64::from(local.endpc));
+        }
+        out.field("protos").array_header(self.protos.len());
+        for proto in &self.protos {
+            proto.write_metadata(out);
+        }
+    }
+}
+
+// The formats, each written with the smallest header that fits
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn nil(&mut self) {
+        self.0.push(0xc0);
+    }
+
+    fn boolean(&mut self, b: bool) {
+        self.0.push(if b { 0xc3 } else { 0xc2 });
+    }
+
+    fn integer(&mut self, i: i64) {
+        match i {
+            0..=0x7f => self.0.push(i as u8),
+            -32..=-1 => self.0.push(i as u8),
+            0x80..=0xff => self.0.extend_from_slice(&[0xcc, i as u8]),
+            0x100..=0xffff => {
+                self.0.push(0xcd);
+                self.0.extend_from_slice(&(i as u16).to_be_bytes());
+            }
+            0x1_0000..=0xffff_ffff => {
+                self.0.push(0xce);
+                self.0.extend_from_slice(&(i as u32).to_be_bytes());
+            }
+            -0x80..=-33 => self.0.extend_from_slice(&[0xd0, i as u8]),
+            -0x8000..=-0x81 => {
+                self.0.push(0xd1);
+                self.0.extend_from_slice(&(i as i16).to_be_bytes());
+            }
+            -0x8000_0000..=-0x8001 => {
+                self.0.push(0xd2);
+                self.0.extend_from_slice(&(i as i32).to_be_bytes());
+            }
+            _ if i > 0 => {
+                self.0.push(0xcf);
+                self.0.extend_from_slice(&(i as u64).to_be_bytes());
+            }
+            _ => {
+                self.0.push(0xd3);
+                self.0.extend_from_slice(&i.to_be_bytes());
+            }
+        }
+    }
+
+    fn float(&mut self, n: f64) {
+        self.0.push(0xcb);
+        self.0.extend_from_slice(&n.to_bits().to_be_bytes());
+    }
+
+    // str when `s` is valid UTF-8, bin otherwise
+    fn bytes(&mut self, s: &[u8]) {
+        if std::str::from_utf8(s).is_ok() {
+            self.header(s.len(), Some((0xa0, 32)), Some(0xd9), 0xda, 0xdb);
+        } else {
+            self.header(s.len(), None, Some(0xc4), 0xc5, 0xc6);
+        }
+        self.0.extend_from_slice(s);
+    }
+
+    fn array_header(&mut self, len: usize) {
+        self.header(len, Some((0x90, 16)), None, 0xdc, 0xdd);
+    }
+
+    fn map_header(&mut self, len: usize) {
+        self.header(len, Some((0x80, 16)), None, 0xde, 0xdf);
+    }
+
+    // A length, in the fix format (first byte and the lengths it holds) if
+    // there is one and it fits, else the 8, 16 or 32 bit format
+    fn header(&mut self, len: usize, fix: Option<(u8, usize)>, format8: Option<u8>,
+              format16: u8, format32: u8) {
+        match (fix, format8) {
+            (Some((fix, limit)), _) if len < limit => self.0.push(fix | len as u8),
+            (_, Some(format8)) if len <= 0xff => self.0.extend_from_slice(&[format8, len as u8]),
+            _ if len <= 0xffff => {
+                self.0.push(format16);
+                self.0.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            _ => {
+                self.0.push(format32);
+                self.0.extend_from_slice(&(len as u32).to_be_bytes());
+            }
+        }
+    }
+
+    // the key of a map entry, leaving its value to write
+    fn field(&mut self, key: &str) -> &mut Writer {
+        self.bytes(key.as_bytes());
+        self
+    }
+}
+
+struct Encoder<'s> {
+    state: &'s SyxState,
+    out: Writer,
+    tables: Vec<TableRef>, // being written, to catch cycles
+}
+
+impl<'s> Encoder<'s> {
+    fn value(&mut self, value: &SyxValue) -> Result<()> {
+        match *value {
+            SyxValue::Nil => self.out.nil(),
+            SyxValue::Bool(b) => self.out.boolean(b),
+            SyxValue::Integer(i) => self.out.integer(integer_to_i64(i)),
+            SyxValue::Number(n) => self.out.float(number_to_f64(n)),
+            SyxValue::String(ref s) => self.out.bytes(s),
+            SyxValue::Table(table) => self.table(table)?,
+            _ => return msgpack_error(format!("cannot encode a {} value", value.type_name())),
+        }
+        Ok(())
+    }
+
+    fn table(&mut self, table: TableRef) -> Result<()> {
+        if self.tables.contains(&table) {
+            return msgpack_error("cannot encode a table with a cycle".to_owned());
+        }
+        if self.tables.len() >= MAX_DEPTH {
+            return msgpack_error("cannot encode a table nested this deep".to_owned());
+        }
+        self.tables.push(table);
+        let entries: Vec<(SyxValue, SyxValue)> = self.state.table(table).iter().collect();
+        let is_array = !entries.is_empty() && entries.iter().enumerate()
+            .all(|(i, (key, _))| *key == SyxValue::Integer(i as SyxInteger + 1));
+        if is_array {
+            self.out.array_header(entries.len());
+            for (_, value) in &entries {
+                self.value(value)?;
+            }
+        } else {
+            self.out.map_header(entries.len());
+            for (key, value) in &entries {
+                self.value(key)?;
+                self.value(value)?;
+            }
+        }
+        self.tables.pop();
+        Ok(())
+    }
+}
+
+struct Decoder<'a> {
+    state: &'a mut SyxState,
+    bytes: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let bytes = self.bytes;
+        match bytes.get(self.pos..self.pos.saturating_add(n)) {
+            Some(taken) => {
+                self.pos += n;
+                Ok(taken)
+            }
+            None => msgpack_error(format!("unexpected end of data at byte {}", bytes.len())),
+        }
+    }
+
+    // a big-endian unsigned number of `n` bytes
+    fn uint(&mut self, n: usize) -> Result<u64> {
+        Ok(self.take(n)?.iter().fold(0, |value, &byte| value << 8 | u64::from(byte)))
+    }
+
+    // a big-endian signed number of `n` bytes
+    fn int(&mut self, n: usize) -> Result<i64> {
+        let shift = 64 - 8 * n as u32;
+        Ok(((self.uint(n)? << shift) as i64) >> shift)
+    }
+
+    fn value(&mut self) -> Result<SyxValue> {
+        let marker = self.take(1)?[0];
+        // arrays and maps recurse through here, so the other formats are read
+        // apart, keeping this frame small
+        match marker {
+            0x80..=0x8f | 0xde | 0xdf => {
+                let len = self.length(marker)?;
+                self.map(len)
+            }
+            0x90..=0x9f | 0xdc | 0xdd => {
+                let len = self.length(marker)?;
+                self.array(len)
+            }
+            _ => self.scalar(marker),
+        }
+    }
+
+    // The length of the str, bin, array or map starting with `marker`
+    fn length(&mut self, marker: u8) -> Result<usize> {
+        Ok(match marker {
+            0x80..=0x9f => usize::from(marker & 0x0f),
+            0xa0..=0xbf => usize::from(marker & 0x1f),
+            0xc4 | 0xd9 => self.uint(1)? as usize,
+            0xc5 | 0xda | 0xdc | 0xde => self.uint(2)? as usize,
+            _ => self.uint(4)? as usize,
+        })
+    }
+
+    fn scalar(&mut self, marker: u8) -> Result<SyxValue> {
+        let start = self.pos - 1;
+        Ok(match marker {
+            0x00..=0x7f => integer(i64::from(marker)),
+            0xe0..=0xff => integer(i64::from(marker as i8)),
+            0xa0..=0xbf | 0xc4..=0xc6 | 0xd9..=0xdb => {
+                let len = self.length(marker)?;
+                let bytes = self.take(len)?;
+                SyxValue::String(self.state.intern(bytes))
+            }
+            0xc0 => SyxValue::Nil,
+            0xc2 => SyxValue::Bool(false),
+            0xc3 => SyxValue::Bool(true),
+            0xca => SyxValue::Number(f32::from_bits(self.uint(4)? as u32) as SyxNumber),
+            0xcb => SyxValue::Number(f64::from_bits(self.uint(8)?) as SyxNumber),
+            0xcc => integer(self.uint(1)? as i64),
+            0xcd => integer(self.uint(2)? as i64),
+            0xce => integer(self.uint(4)? as i64),
+            0xcf => {
+                let u = self.uint(8)?;
+                match i64::try_from(u) {
+                    Ok(i) => integer(i),
+                    Err(_) => SyxValue::Number(u as SyxNumber),
+                }
+            }
+            0xd0 => integer(self.int(1)?),
+            0xd1 => integer(self.int(2)?),
+            0xd2 => integer(self.int(4)?),
+            0xd3 => integer(self.int(8)?),
+            0xc7..=0xc9 | 0xd4..=0xd8 => {
+                return msgpack_error(format!("unsupported extension type at byte {}", start));
+            }
+            _ => return msgpack_error(format!("invalid format {:#04x} at byte {}", marker, start)),
+        })
+    }
+
+    fn enter(&mut self) -> Result<TableRef> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return msgpack_error(format!("too many nested arrays and maps at byte {}",
+                                         self.pos - 1));
+        }
+        Ok(self.state.new_table(0, 0))
+    }
+
+    fn array(&mut self, len: usize) -> Result<SyxValue> {
+        let table = self.enter()?;
+        for i in 1..=len {
+            let value = self.value()?;
+            self.state.table_set(table, SyxValue::Integer(i as SyxInteger), value)?;
+        }
+        self.depth -= 1;
+        Ok(SyxValue::Table(table))
+    }
+
+    fn map(&mut self, len: usize) -> Result<SyxValue> {
+        let table = self.enter()?;
+        for _ in 0..len {
+            let key = self.value()?;
+            let value = self.value()?;
+            self.state.table_set(table, key, value)?;
+        }
+        self.depth -= 1;
+        Ok(SyxValue::Table(table))
+    }
+}
+
+// an integer read, as a float when it doesn't fit
+fn integer(i: i64) -> SyxValue {
+    match SyxInteger::try_from(i) {
+        Ok(i) => integer_value(i),
+        Err(_) => SyxValue::Number(i as SyxNumber),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::compiler::parse;
+    use super::super::object::MultiValue;
+
+    fn table(state: &mut SyxState, entries: Vec<(SyxValue, SyxValue)>) -> SyxValue {
+        let table = state.new_table(0, 0);
+        for (key, value) in entries {
+            state.table_set(table, key, value).unwrap();
+        }
+        SyxValue::Table(table)
+    }
+
+    fn field(state: &SyxState, value: &SyxValue, key: &str) -> SyxValue {
+        match *value {
+            SyxValue::Table(table) => state.table(table).get(&SyxValue::from(key)),
+            _ => panic!("{:?} is not a table", value),
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut state = SyxState::new();
+        let encode = |state: &SyxState, value: SyxValue| value.to_msgpack(state).unwrap();
+        assert_eq!(encode(&state, SyxValue::Integer(0)), [0x00]);
+        assert_eq!(encode(&state, SyxValue::Integer(-1)), [0xff]);
+        assert_eq!(encode(&state, SyxValue::Integer(200)), [0xcc, 200]);
+        assert_eq!(encode(&state, SyxValue::Integer(-100)), [0xd0, 0x9c]);
+        assert_eq!(encode(&state, SyxValue::Integer(70000)), [0xce, 0, 1, 0x11, 0x70]);
+        assert_eq!(encode(&state, SyxValue::Number(0.5)), [0xcb, 0x3f, 0xe0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(encode(&state, SyxValue::Nil), [0xc0]);
+        assert_eq!(encode(&state, SyxValue::from("hi")), [0xa2, b'h', b'i']);
+        let binary = SyxValue::String(state.intern(b"\xff"));
+        assert_eq!(encode(&state, binary), [0xc4, 1, 0xff]);
+        let long = SyxValue::String(state.intern(&[b'x'; 40]));
+        assert_eq!(encode(&state, long)[..2], [0xd9, 40]);
+
+        let list = table(&mut state, vec![(SyxValue::Integer(1), SyxValue::Bool(false)),
+                                          (SyxValue::Integer(2), SyxValue::Integer(1))]);
+        assert_eq!(encode(&state, list), [0x92, 0xc2, 0x01]);
+        let map = table(&mut state, vec![(SyxValue::from("a"), SyxValue::Bool(true))]);
+        assert_eq!(encode(&state, map), [0x81, 0xa1, b'a', 0xc3]);
+        let empty = table(&mut state, vec![]);
+        assert_eq!(encode(&state, empty.clone()), [0x80]);
+
+        let error = |state: &SyxState, value: SyxValue| value.to_msgpack(state).unwrap_err()
+            .to_string();
+        if let SyxValue::Table(t) = empty {
+            state.table_set(t, SyxValue::from("self"), SyxValue::Table(t)).unwrap();
+        }
+        assert_eq!(error(&state, empty), "cannot encode a table with a cycle");
+        let function = state.create_function("f", |_, _| Ok(MultiValue::new()));
+        assert_eq!(error(&state, function), "cannot encode a function value");
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut state = SyxState::new();
+        let inner = table(&mut state, vec![(SyxValue::Integer(1), SyxValue::Number(-2.5)),
+                                           (SyxValue::Integer(3), SyxValue::from("three"))]);
+        let binary = SyxValue::String(state.intern(b"\x00\xff"));
+        let value = table(&mut state, vec![
+            (SyxValue::from("list"), inner),
+            (SyxValue::Integer(-7), SyxValue::Integer(SyxInteger::MIN)),
+            (SyxValue::Bool(true), binary.clone()),
+        ]);
+        let bytes = value.to_msgpack(&state).unwrap();
+        let decoded = SyxValue::from_msgpack(&mut state, &bytes).unwrap();
+        let list = field(&state, &decoded, "list");
+        if let SyxValue::Table(list) = list {
+            let list = state.table(list);
+            assert_eq!(list.get_int(1), SyxValue::Number(-2.5));
+            assert_eq!(list.get_int(2), SyxValue::Nil);
+            assert_eq!(list.get_int(3), SyxValue::from("three"));
+        } else {
+            panic!("{:?}", list);
+        }
+        if let SyxValue::Table(t) = decoded {
+            let t = state.table(t);
+            assert_eq!(t.get(&SyxValue::Integer(-7)), SyxValue::Integer(SyxInteger::MIN));
+            assert_eq!(t.get(&SyxValue::Bool(true)), binary);
+        }
+
+        // past the integers, and other formats than the ones written
+        let mut decode = |bytes: &[u8]| SyxValue::from_msgpack(&mut state, bytes)
+            .map_err(|e| e.to_string());
+        assert_eq!(decode(&[0xcf, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).unwrap(),
+                   SyxValue::Number(18446744073709551615.0));
+        assert_eq!(decode(&[0xca, 0x3f, 0xc0, 0, 0]).unwrap(), SyxValue::Number(1.5));
+        assert_eq!(decode(&[0xd1, 0xff, 0x00]).unwrap(), SyxValue::Integer(-256));
+        assert_eq!(decode(&[0xda, 0, 1, b'x']).unwrap(), SyxValue::from("x"));
+        assert_eq!(decode(&[0x92, 0x01]).unwrap_err(), "unexpected end of data at byte 2");
+        assert_eq!(decode(&[0x01, 0x02]).unwrap_err(),
+                   "bytes left over after the value at byte 1");
+        assert_eq!(decode(&[0xd4, 0x01, 0x02]).unwrap_err(),
+                   "unsupported extension type at byte 0");
+        assert_eq!(decode(&[0xc1]).unwrap_err(), "invalid format 0xc1 at byte 0");
+        assert_eq!(decode(&[0x81, 0xc0, 0x01]).unwrap_err(), "table index is nil");
+        assert_eq!(decode(&[0x91; MAX_DEPTH + 1]).unwrap_err(),
+                   format!("too many nested arrays and maps at byte {}", MAX_DEPTH));
+    }
+
+    #[test]
+    fn test_proto_metadata() {
+        let source = "local a = 1 return function(x, ...) return a + x end";
+        let proto = parse(source.as_bytes(), "=test").unwrap();
+        let mut state = SyxState::new();
+        let metadata = SyxValue::from_msgpack(&mut state, &proto.msgpack_metadata()).unwrap();
+        assert_eq!(field(&state, &metadata, "source"), SyxValue::from("=test"));
+        assert_eq!(field(&state, &metadata, "is_vararg"), SyxValue::Bool(true));
+        assert_eq!(field(&state, &metadata, "instructions"),
+                   SyxValue::Integer(proto.instructions.len() as SyxInteger));
+        let locals = field(&state, &metadata, "locals");
+        let local = match locals {
+            SyxValue::Table(locals) => state.table(locals).get_int(1),
+            _ => panic!("{:?}", locals),
+        };
+        assert_eq!(field(&state, &local, "name"), SyxValue::from("a"));
+        let protos = field(&state, &metadata, "protos");
+        let child = match protos {
+            SyxValue::Table(protos) => state.table(protos).get_int(1),
+            _ => panic!("{:?}", protos),
+        };
+        assert_eq!(field(&state, &child, "numparams"), SyxValue::Integer(1));
+        assert_eq!(field(&state, &child, "is_vararg"), SyxValue::Bool(true));
+        let upvalues = field(&state, &child, "upvalues");
+        match upvalues {
+            SyxValue::Table(upvalues) => {
+                assert_eq!(state.table(upvalues).get_int(1), SyxValue::from("a"))
+            }
+            _ => panic!("{:?}", upvalues),
+        }
+    }
+}
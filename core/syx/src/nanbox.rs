@@ -0,0 +1,237 @@
+// NaN-boxed values
+//
+// A PackedValue holds any SyxValue in 8 bytes, where the enum takes 16. Floats
+// are stored as themselves, every NaN being made the one positive quiet NaN,
+// which leaves the negative quiet NaNs free: those carry a 3 bit tag and a 48
+// bit payload.
+//
+//   tag 0  nil, false or true
+//   tag 1  integer that fits in 48 bits, sign extended
+//   tag 2  any other integer, boxed
+//   tag 3  string, the pointer to its shared data
+//   tag 4  collectable object, by kind (bits 32-47) and arena slot (bits 0-31)
+//   tag 5  native function, the function pointer
+//
+// Pointers are assumed to fit in 48 bits, as they do on x86-64 and AArch64.
+// Values convert to and from SyxValue, which is what the rest of the crate
+// works with: the stack and registers hold SyxValue, as every object kind would
+// otherwise need its reference count or arena slot handled by hand in each
+// opcode. The module is behind the `nanbox` feature, and `benches/values.rs`
+// compares the two on the work a register file does.
+
+use std::mem::ManuallyDrop;
+
+use super::object::{
+    number_to_f64, FunctionRef, NativeFunction, NativeRef, SyxInteger, SyxNumber, SyxValue,
+    TableRef, ThreadRef, UserDataRef,
+};
+use super::string::SyxString;
+
+const CANONICAL_NAN: u64 = 0x7FF8_0000_0000_0000;
+const TAGGED: u64 = 0xFFF8_0000_0000_0000;
+const TAG_SHIFT: u32 = 48;
+const PAYLOAD: u64 = (1 << TAG_SHIFT) - 1;
+
+const TAG_SPECIAL: u64 = 0;
+const TAG_INTEGER: u64 = 1;
+const TAG_BIG_INTEGER: u64 = 2;
+const TAG_STRING: u64 = 3;
+const TAG_OBJECT: u64 = 4;
+const TAG_NATIVE: u64 = 5;
+
+const NIL: u64 = 0;
+const FALSE: u64 = 1;
+const TRUE: u64 = 2;
+
+const KIND_TABLE: u64 = 0;
+const KIND_FUNCTION: u64 = 1;
+const KIND_NATIVE_CLOSURE: u64 = 2;
+const KIND_THREAD: u64 = 3;
+const KIND_USERDATA: u64 = 4;
+
+pub struct PackedValue(u64);
+
+fn tagged(tag: u64, payload: u64) -> PackedValue {
+    debug_assert!(payload <= PAYLOAD, "payload does not fit in 48 bits");
+    PackedValue(TAGGED | tag << TAG_SHIFT | payload)
+}
+
+fn pointer(address: usize) -> u64 {
+    assert!(address as u64 <= PAYLOAD, "pointer does not fit in 48 bits");
+    address as u64
+}
+
+fn object(kind: u64, index: usize) -> PackedValue {
+    assert!(index <= u32::MAX as usize, "arena slot does not fit in 32 bits");
+    tagged(TAG_OBJECT, kind << 32 | index as u64)
+}
+
+impl PackedValue {
+    fn tag(&self) -> Option<u64> {
+        if self.0 & TAGGED == TAGGED {
+            Some((self.0 >> TAG_SHIFT) & 0x7)
+        } else {
+            None
+        }
+    }
+
+    fn payload(&self) -> u64 {
+        self.0 & PAYLOAD
+    }
+
+    pub fn is_nil(&self) -> bool {
+        self.0 == TAGGED | NIL
+    }
+
+    // The value, sharing any string with this one
+    pub fn get(&self) -> SyxValue {
+        let payload = self.payload();
+        match self.tag() {
+            None => SyxValue::Number(f64::from_bits(self.0) as SyxNumber),
+            Some(TAG_SPECIAL) => match payload {
+                FALSE => SyxValue::Bool(false),
+                TRUE => SyxValue::Bool(true),
+                _ => SyxValue::Nil,
+            },
+            // sign extend from 48 bits
+            Some(TAG_INTEGER) => SyxValue::Integer((((payload << 16) as i64) >> 16) as SyxInteger),
+            Some(TAG_BIG_INTEGER) => {
+                SyxValue::Integer(unsafe { *(payload as usize as *const SyxInteger) })
+            }
+            Some(TAG_STRING) => {
+                // borrowed from this value, which keeps its own reference
+                let string = ManuallyDrop::new(unsafe { SyxString::from_raw(payload as usize) });
+                SyxValue::String((*string).clone())
+            }
+            Some(TAG_OBJECT) => {
+                let index = (payload & 0xFFFF_FFFF) as usize;
+                match payload >> 32 {
+                    KIND_TABLE => SyxValue::Table(TableRef(index)),
+                    KIND_FUNCTION => SyxValue::Function(FunctionRef(index)),
+                    KIND_NATIVE_CLOSURE => SyxValue::NativeClosure(NativeRef(index)),
+                    KIND_THREAD => SyxValue::Thread(ThreadRef(index)),
+                    _ => SyxValue::UserData(UserDataRef(index)),
+                }
+            }
+            _ => SyxValue::Native(unsafe {
+                ::std::mem::transmute::<usize, NativeFunction>(payload as usize)
+            }),
+        }
+    }
+
+    pub fn into_value(self) -> SyxValue {
+        self.get()
+    }
+}
+
+impl From<SyxValue> for PackedValue {
+    fn from(value: SyxValue) -> PackedValue {
+        match value {
+            SyxValue::Nil => tagged(TAG_SPECIAL, NIL),
+            SyxValue::Bool(false) => tagged(TAG_SPECIAL, FALSE),
+            SyxValue::Bool(true) => tagged(TAG_SPECIAL, TRUE),
+            SyxValue::Number(n) if n.is_nan() => PackedValue(CANONICAL_NAN),
+            SyxValue::Number(n) => PackedValue(number_to_f64(n).to_bits()),
+            SyxValue::Integer(i) if ((i << 16) >> 16) == i => {
+                tagged(TAG_INTEGER, i as u64 & PAYLOAD)
+            }
+            SyxValue::Integer(i) => {
+                tagged(TAG_BIG_INTEGER, pointer(Box::into_raw(Box::new(i)) as usize))
+            }
+            SyxValue::String(s) => tagged(TAG_STRING, pointer(s.into_raw())),
+            SyxValue::Table(t) => object(KIND_TABLE, t.0),
+            SyxValue::Function(f) => object(KIND_FUNCTION, f.0),
+            SyxValue::NativeClosure(f) => object(KIND_NATIVE_CLOSURE, f.0),
+            SyxValue::Thread(t) => object(KIND_THREAD, t.0),
+            SyxValue::UserData(u) => object(KIND_USERDATA, u.0),
+            SyxValue::Native(f) => tagged(TAG_NATIVE, pointer(f as usize)),
+        }
+    }
+}
+
+impl From<PackedValue> for SyxValue {
+    fn from(value: PackedValue) -> SyxValue {
+        value.into_value()
+    }
+}
+
+impl Clone for PackedValue {
+    fn clone(&self) -> PackedValue {
+        match self.tag() {
+            Some(TAG_BIG_INTEGER) | Some(TAG_STRING) => PackedValue::from(self.get()),
+            _ => PackedValue(self.0),
+        }
+    }
+}
+
+impl Drop for PackedValue {
+    fn drop(&mut self) {
+        let payload = self.payload() as usize;
+        match self.tag() {
+            Some(TAG_BIG_INTEGER) => drop(unsafe { Box::from_raw(payload as *mut SyxInteger) }),
+            Some(TAG_STRING) => drop(unsafe { SyxString::from_raw(payload) }),
+            _ => {}
+        }
+    }
+}
+
+impl PartialEq for PackedValue {
+    fn eq(&self, other: &PackedValue) -> bool {
+        self.get() == other.get()
+    }
+}
+
+impl ::std::fmt::Debug for PackedValue {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        self.get().fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::size_of;
+
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        assert_eq!(size_of::<PackedValue>(), 8);
+        let values = vec![
+            SyxValue::Nil, SyxValue::Bool(false), SyxValue::Bool(true),
+            SyxValue::Number(1.5), SyxValue::Number(-0.0), SyxValue::Number(SyxNumber::INFINITY),
+            SyxValue::Integer(0), SyxValue::Integer(-1), SyxValue::Integer(SyxInteger::MAX),
+            SyxValue::Integer(SyxInteger::MIN), "a string".into(),
+            SyxValue::Table(TableRef(7)), SyxValue::UserData(UserDataRef(u32::MAX as usize)),
+        ];
+        // either side of the 48 bit payload, which only 64-bit integers reach
+        #[cfg(not(feature = "int32"))]
+        let values = [values, vec![SyxValue::Integer(1 << 47), SyxValue::Integer(-(1 << 47))]]
+            .concat();
+        for value in values {
+            let packed = PackedValue::from(value.clone());
+            let copy = packed.clone();
+            drop(packed);
+            assert_eq!(copy.into_value(), value);
+        }
+        let nan = PackedValue::from(SyxValue::Number(SyxNumber::NAN));
+        assert!(matches!(nan.get(), SyxValue::Number(n) if n.is_nan()));
+        assert!(PackedValue::from(SyxValue::Nil).is_nil());
+        // -0.0 stays a float rather than looking like a tagged value
+        assert!(matches!(PackedValue::from(SyxValue::Number(-0.0)).get(),
+                         SyxValue::Number(n) if n == 0.0 && n.is_sign_negative()));
+    }
+
+    #[test]
+    fn test_shared_strings() {
+        let string = SyxString::new("shared");
+        let packed = PackedValue::from(SyxValue::String(string.clone()));
+        let copies: Vec<PackedValue> = (0..4).map(|_| packed.clone()).collect();
+        drop(packed);
+        for copy in &copies {
+            match copy.get() {
+                SyxValue::String(s) => assert!(s.ptr_eq(&string)),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+}
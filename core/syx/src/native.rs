@@ -0,0 +1,276 @@
+// Native functions registered by the host
+//
+// Besides the plain `fn` pointers the standard library is made of, a host
+// can expose any Rust closure to scripts with `register` (as a global) or
+// `create_function` (as a value to store anywhere), as long as it is Send and
+// Sync so that the state stays Send (see state.rs). Such a closure is called
+// with its arguments wrapped in `Args`, whose `check_*` and `opt_*` helpers
+// report bad arguments under the name it was registered with, as luaL_check*
+// does. Closures are collectable objects, dropped once scripts no longer
+// refer to them; handles they capture are not roots (see gc.rs) unless they
+// are also given to `create_closure` as upvalues.
+//
+// Consult the versioned lapi.c (lua_pushcclosure) and lauxlib.c for more
+// information.
+
+use std::sync::{Arc, Mutex};
+
+use super::errors::*;
+use super::gc::is_collectable;
+#[cfg(feature = "float_only")]
+use super::object::number_value;
+use super::object::{MultiValue, NativeRef, SyxInteger, SyxNumber, SyxString, SyxValue, TableRef};
+use super::state::SyxState;
+use super::stdlib;
+use super::vm::bad_argument;
+
+pub type NativeFn = dyn Fn(&mut SyxState, Args) -> Result<MultiValue> + Send + Sync;
+
+pub struct NativeClosure {
+    pub name: Arc<str>,
+    pub(crate) function: Arc<NativeFn>,
+    pub(crate) upvalues: Vec<SyxValue>, // kept alive with the closure
+}
+
+// Arguments of a call to a native closure
+pub struct Args {
+    values: Vec<SyxValue>,
+    name: Arc<str>,
+}
+
+impl Args {
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    // argument `n`, counting from 1
+    pub fn get(&self, n: usize) -> Option<&SyxValue> {
+        n.checked_sub(1).and_then(|i| self.values.get(i))
+    }
+
+    // name of the function called, for error messages
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn values(&self) -> &[SyxValue] {
+        &self.values
+    }
+
+    pub fn into_values(self) -> Vec<SyxValue> {
+        self.values
+    }
+
+    // fail unless there are at least `n` arguments
+    pub fn check_count(&self, n: usize) -> Result<()> {
+        if self.values.len() < n {
+            bad_argument(n, &self.name, "value expected")
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn check_any(&self, n: usize) -> Result<SyxValue> {
+        stdlib::check_any(&self.values, n, &self.name)
+    }
+
+    pub fn check_number(&self, n: usize) -> Result<SyxNumber> {
+        stdlib::check_number(&self.values, n, &self.name)
+    }
+
+    pub fn check_integer(&self, n: usize) -> Result<SyxInteger> {
+        stdlib::check_integer(&self.values, n, &self.name)
+    }
+
+    pub fn check_string(&self, n: usize) -> Result<SyxString> {
+        stdlib::check_string(&self.values, n, &self.name)
+    }
+
+    pub fn check_table(&self, n: usize) -> Result<TableRef> {
+        stdlib::check_table(&self.values, n, &self.name)
+    }
+
+    pub fn opt_integer(&self, n: usize, default: SyxInteger) -> Result<SyxInteger> {
+        stdlib::opt_integer(&self.values, n, &self.name, default)
+    }
+
+    pub fn opt_string(&self, n: usize, default: &str) -> Result<SyxString> {
+        stdlib::opt_string(&self.values, n, &self.name, default)
+    }
+
+    // fail with argument `n` not being an `expected`
+    pub fn type_error<T>(&self, n: usize, expected: &str) -> Result<T> {
+        stdlib::type_error(&self.values, n, &self.name, expected)
+    }
+}
+
+impl SyxState {
+    // Function value calling `function`, which reports bad arguments as
+    // arguments to `name`
+    pub fn create_function(&mut self, name: &str,
+                           function: impl Fn(&mut SyxState, Args) -> Result<MultiValue>
+                                         + Send + Sync + 'static)
+        -> SyxValue
+    {
+        self.create_closure(name, vec![], function)
+    }
+
+    // As create_function, keeping `upvalues` alive for as long as the
+    // function is, so handles to them that it captures stay valid
+    pub fn create_closure(&mut self, name: &str, upvalues: Vec<SyxValue>,
+                          function: impl Fn(&mut SyxState, Args) -> Result<MultiValue>
+                                        + Send + Sync + 'static)
+        -> SyxValue
+    {
+        let native = NativeClosure { name: name.into(), function: Arc::new(function), upvalues };
+        SyxValue::NativeClosure(self.alloc_native(native))
+    }
+
+    // Iterator function, state and control value for a generic `for` over
+    // `iter`, whose items are the values of the loop variables. The loop
+    // ends with the iterator, or at an item whose first value is nil.
+    pub fn create_iterator<I>(&mut self, name: &str, iter: I) -> MultiValue
+        where I: Iterator<Item = Vec<SyxValue>> + Send + 'static
+    {
+        let iter = Mutex::new(iter);
+        let function = self.create_function(name, move |_, _| {
+            let item = iter.lock().unwrap().next();
+            Ok(item.unwrap_or_else(|| vec![SyxValue::Nil]).into())
+        });
+        vec![function, SyxValue::Nil, SyxValue::Nil].into()
+    }
+
+    pub fn native(&self, native: NativeRef) -> &NativeClosure {
+        self.heap.natives.get(native.0)
+    }
+
+    // Store `function` as global `name`
+    pub fn register(&mut self, name: &str,
+                    function: impl Fn(&mut SyxState, Args) -> Result<MultiValue>
+                                  + Send + Sync + 'static)
+    {
+        let function = self.create_function(name, function);
+        let globals = self.globals();
+        stdlib::set_field(self, globals, name, function);
+    }
+
+    // Run a native function or closure, without hook events
+    pub(crate) fn run_native(&mut self, function: SyxValue, args: Vec<SyxValue>)
+        -> Result<MultiValue>
+    {
+        // it gets the stack of api.rs to itself, above its arguments, which
+        // are kept there in case it collects (see `reserve` in gc.rs)
+        let base = self.api_base;
+        let mark = self.api_stack.len();
+        self.api_stack.extend(args.iter().filter(|value| is_collectable(value)).cloned());
+        self.api_base = self.api_stack.len();
+        let results = match function {
+            SyxValue::Native(function) => function(self, args).map(MultiValue::from),
+            SyxValue::NativeClosure(native) => {
+                // the closure may be collected while it runs
+                let native = self.native(native);
+                let function = native.function.clone();
+                let args = Args { values: args, name: native.name.clone() };
+                function(self, args)
+            }
+            _ => unreachable!(),
+        };
+        self.api_stack.truncate(mark);
+        self.api_base = base;
+        results.map(float_results)
+    }
+}
+
+// natives count and index with integers, which Lua code sees as floats
+// without them (see object.rs)
+#[cfg(not(feature = "float_only"))]
+fn float_results(results: MultiValue) -> MultiValue {
+    results
+}
+#[cfg(feature = "float_only")]
+fn float_results(mut results: MultiValue) -> MultiValue {
+    for value in results.iter_mut() {
+        *value = number_value(value.clone());
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+    use super::super::stdlib;
+    use super::super::testing::run_in;
+
+    #[test]
+    fn test_register() {
+        let mut state = SyxState::new();
+        stdlib::open_libs(&mut state);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let seen = calls.clone();
+        state.register("add", move |_, args| {
+            seen.fetch_add(1, Ordering::Relaxed);
+            let sum = args.check_integer(1)? + args.opt_integer(2, 10)?;
+            Ok(vec![SyxValue::Integer(sum)].into())
+        });
+        let results = run_in(&mut state, "return add(1, 2), add(5), type(add), add == add")
+            .unwrap();
+        assert!(results == vec![SyxValue::Integer(3), SyxValue::Integer(15),
+                                SyxValue::from("function"), SyxValue::Bool(true)]);
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        let error = run_in(&mut state, "return add('x')").err().unwrap();
+        assert_eq!(error.to_string(),
+                   "test:1: bad argument #1 to 'add' (number expected, got string)");
+    }
+
+    #[test]
+    fn test_create_function() {
+        let mut state = SyxState::new();
+        stdlib::open_libs(&mut state);
+        let function = state.create_function("pair", |_, args| {
+            args.check_count(2)?;
+            Ok(vec![args.check_any(2)?, args.check_any(1)?].into())
+        });
+        let globals = state.globals();
+        stdlib::set_field(&mut state, globals, "swap", function);
+        let results = run_in(&mut state, "local t = {} t[swap] = 1 \
+                                       return t[swap], swap(1, 2), pcall(swap, 1)").unwrap();
+        assert!(results == vec![SyxValue::Integer(1), SyxValue::Integer(2), SyxValue::Bool(false),
+                                SyxValue::from("bad argument #2 to 'pair' (value expected)")]);
+        // nothing refers to it once the global is gone
+        let objects = state.gc_objects();
+        stdlib::set_field(&mut state, globals, "swap", SyxValue::Nil);
+        state.collect_garbage();
+        assert!(state.gc_objects() < objects);
+    }
+
+    #[test]
+    fn test_create_iterator() {
+        let mut state = SyxState::new();
+        stdlib::open_libs(&mut state);
+        // a proxy whose entries come from Rust through __pairs
+        state.register("proxy", |state, _| {
+            let metatable = state.new_table(0, 0);
+            let pairs = state.create_function("__pairs", |state, _| {
+                let squares = (1..=3).map(|i| vec![SyxValue::Integer(i), SyxValue::Integer(i * i)]);
+                Ok(state.create_iterator("squares", squares))
+            });
+            stdlib::set_field(state, metatable, "__pairs", pairs);
+            let proxy = state.new_table(0, 0);
+            state.table_mut(proxy).set_metatable(Some(metatable));
+            Ok(vec![SyxValue::Table(proxy)].into())
+        });
+        let results = run_in(&mut state, "local sum, n = 0, 0 \
+                                       for k, v in pairs(proxy()) do \
+                                           sum = sum + k * v n = n + 1 \
+                                       end \
+                                       return sum, n").unwrap();
+        assert!(results == vec![SyxValue::Integer(36), SyxValue::Integer(3)]);
+    }
+}
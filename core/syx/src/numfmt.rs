@@ -0,0 +1,137 @@
+// Number formatting
+//
+// Numbers are shown the way PUC-Rio Lua prints them: integers in decimal,
+// and floats with "%.14g" (seven digits with the float32 feature), followed
+// by ".0" when that alone would read as an integer, so that 1.0 and 1 print
+// differently. With the float_only feature there is nothing to tell them
+// apart from, and floats are printed as in 5.1 and 5.2. Infinities print as
+// "inf" and "-inf", and NaN as "nan" or "-nan" depending on its sign bit, as
+// glibc's printf shows it. The forms below also make up string.format's
+// float conversions.
+//
+// Consult the versioned lobject.c (tostringbuff) and lstrlib.c for more
+// information.
+
+use super::conf::SYX_NUMBER_DIGITS;
+use super::object::{number_to_f64, SyxInteger, SyxNumber};
+
+pub fn integer_to_string(i: SyxInteger) -> String {
+    i.to_string()
+}
+
+// a float as tostring and print show it
+pub fn number_to_string(n: SyxNumber) -> String {
+    let sign = if n.is_sign_negative() { "-" } else { "" };
+    let mut text = if n.is_nan() {
+        format!("{}nan", sign)
+    } else if n.is_infinite() {
+        format!("{}inf", sign)
+    } else {
+        format!("{}{}", sign, general_form(n.abs(), SYX_NUMBER_DIGITS, false))
+    };
+    if cfg!(not(feature = "float_only")) &&
+        text.bytes().all(|b| b == b'-' || b.is_ascii_digit()) {
+        text.push_str(".0");
+    }
+    text
+}
+
+// "d.ddde+xx", with at least two exponent digits as in C
+pub(crate) fn exponent_form(x: SyxNumber, precision: usize, alt: bool) -> String {
+    let formatted = format!("{:.*e}", precision, x);
+    let (mantissa, exponent) = formatted.split_at(formatted.find('e').unwrap());
+    let exponent: i32 = exponent[1..].parse().unwrap();
+    let point = if alt && precision == 0 { "." } else { "" };
+    let sign = if exponent < 0 { '-' } else { '+' };
+    format!("{}{}e{}{:02}", mantissa, point, sign, exponent.abs())
+}
+
+// shortest of the fixed and exponent forms with `precision` significant
+// digits, as %g picks them
+pub(crate) fn general_form(x: SyxNumber, precision: usize, alt: bool) -> String {
+    let precision = precision.max(1);
+    let exponent = if x == 0.0 {
+        0
+    } else {
+        let formatted = format!("{:.*e}", precision - 1, x);
+        formatted[formatted.find('e').unwrap() + 1..].parse().unwrap()
+    };
+    let mut formatted = if exponent < -4 || exponent >= precision as i32 {
+        exponent_form(x, precision - 1, alt)
+    } else {
+        let decimals = (precision as i32 - 1 - exponent) as usize;
+        let mut fixed = format!("{:.*}", decimals, x);
+        if alt && decimals == 0 {
+            fixed.push('.');
+        }
+        fixed
+    };
+    if !alt && formatted.contains('.') {
+        let end = formatted.find('e').unwrap_or(formatted.len());
+        let trimmed = formatted[..end].trim_end_matches('0').trim_end_matches('.').len();
+        formatted.replace_range(trimmed..end, "");
+    }
+    formatted
+}
+
+// "h.hhhp+d", the digits after "0x" of a hexadecimal float
+pub(crate) fn hex_form(x: SyxNumber, precision: Option<usize>, alt: bool) -> String {
+    let bits = number_to_f64(x).to_bits();
+    let biased = ((bits >> 52) & 0x7ff) as i64;
+    let fraction = bits & ((1 << 52) - 1);
+    let (mut lead, exponent) = match (biased, fraction) {
+        (0, 0) => (0, 0),
+        (0, _) => (0, -1022), // subnormal
+        _ => (1, biased - 1023),
+    };
+    let mut digits = format!("{:013x}", fraction);
+    match precision {
+        Some(precision) if precision < 13 => {
+            // round half to even on the dropped bits
+            let shift = (13 - precision) * 4;
+            let full = (lead << 52) | fraction;
+            let dropped = full & ((1 << shift) - 1);
+            let half = 1 << (shift - 1);
+            let mut kept = full >> shift;
+            if dropped > half || (dropped == half && kept & 1 == 1) {
+                kept += 1;
+            }
+            lead = kept >> (precision * 4);
+            let mask = (1u64 << (precision * 4)) - 1;
+            digits = format!("{:0width$x}", kept & mask, width = precision);
+            digits.truncate(precision);
+        }
+        Some(precision) => digits.push_str(&"0".repeat(precision - 13)),
+        None => digits.truncate(digits.trim_end_matches('0').len()),
+    }
+    let point = if !digits.is_empty() || alt { "." } else { "" };
+    let sign = if exponent < 0 { '-' } else { '+' };
+    format!("{:x}{}{}p{}{}", lead, point, digits, sign, exponent.abs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_number_to_string() {
+        let suffix = if cfg!(feature = "float_only") { "" } else { ".0" };
+        assert_eq!(integer_to_string(-42), "-42");
+        assert_eq!(number_to_string(1.0), format!("1{}", suffix));
+        assert_eq!(number_to_string(-0.0), format!("-0{}", suffix));
+        assert_eq!(number_to_string(0.1), "0.1");
+        assert_eq!(number_to_string(1e30), "1e+30");
+        assert_eq!(number_to_string(SyxNumber::INFINITY), "inf");
+        assert_eq!(number_to_string(SyxNumber::NEG_INFINITY), "-inf");
+        assert_eq!(number_to_string(-SyxNumber::NAN.abs()), "-nan");
+        assert_eq!(number_to_string(SyxNumber::NAN.abs()), "nan");
+        #[cfg(not(feature = "float32"))]
+        {
+            assert_eq!(number_to_string(1.0 / 3.0), "0.33333333333333");
+            assert_eq!(number_to_string(2f64.powi(53)), "9.007199254741e+15");
+            assert_eq!(number_to_string(1e14), "1e+14");
+            assert_eq!(number_to_string(123456789012.5), "123456789012.5");
+            assert_eq!(number_to_string(1e13), format!("10000000000000{}", suffix));
+        }
+    }
+}
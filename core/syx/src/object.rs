@@ -1,13 +1,75 @@
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
 use super::errors::*;
+use super::state::SyxState;
+
+use super::opcodes::Instruction;
+use super::string::SeededState;
 
-use super::opcodes::{Instruction, Word};
+pub use super::string::SyxString;
 
 pub type SyxInt = i32; // because Lua hates me
+
+// lua_Integer and lua_Number, 64 bits wide unless the int32 and float32
+// features narrow them (both together make a LUA_32BITS build). Chunks are
+// dumped with the widths built in, and loaded from either.
+#[cfg(not(feature = "int32"))]
 pub type SyxInteger = i64;
+#[cfg(not(feature = "int32"))]
+pub type SyxUnsigned = u64; // for bitwise operations
+#[cfg(feature = "int32")]
+pub type SyxInteger = i32;
+#[cfg(feature = "int32")]
+pub type SyxUnsigned = u32;
+
+#[cfg(not(feature = "float32"))]
 pub type SyxNumber = f64;
-pub type SyxString = Vec<u8>;
+#[cfg(feature = "float32")]
+pub type SyxNumber = f32;
+
+// `i` as a 64-bit integer and `n` as a double, exactly
+#[cfg(not(feature = "int32"))]
+pub fn integer_to_i64(i: SyxInteger) -> i64 {
+    i
+}
+#[cfg(feature = "int32")]
+pub fn integer_to_i64(i: SyxInteger) -> i64 {
+    i64::from(i)
+}
 
-#[derive(Debug)]
+#[cfg(not(feature = "float32"))]
+pub fn number_to_f64(n: SyxNumber) -> f64 {
+    n
+}
+#[cfg(feature = "float32")]
+pub fn number_to_f64(n: SyxNumber) -> f64 {
+    f64::from(n)
+}
+
+// The value of an integer. With the float_only feature there is no integer
+// subtype, as in Lua 5.1 and 5.2, and every number is a float: integers a
+// chunk, a numeral or a native function produces become floats here.
+#[cfg(not(feature = "float_only"))]
+pub fn integer_value(i: SyxInteger) -> SyxValue {
+    SyxValue::Integer(i)
+}
+#[cfg(feature = "float_only")]
+pub fn integer_value(i: SyxInteger) -> SyxValue {
+    SyxValue::Number(i as SyxNumber)
+}
+
+// `value`, with an integer in the form `integer_value` gives it
+pub fn number_value(value: SyxValue) -> SyxValue {
+    match value {
+        SyxValue::Integer(i) => integer_value(i),
+        value => value,
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum SyxType {
     TNIL,
     TBOOLEAN,
@@ -24,10 +86,10 @@ pub enum SyxType {
     TLNGSTR,
 }
 
-pub const SYX_TNUMFLT: u8 = (SyxType::TNUMBER as u8) | (0 << 4);
+pub const SYX_TNUMFLT: u8 = SyxType::TNUMBER as u8;
 pub const SYX_TNUMINT: u8 = (SyxType::TNUMBER as u8) | (1 << 4);
 
-pub const SYX_TSHRSTR: u8 = (SyxType::TSTRING as u8) | (0 << 4);
+pub const SYX_TSHRSTR: u8 = SyxType::TSTRING as u8;
 pub const SYX_TLNGSTR: u8 = (SyxType::TSTRING as u8) | (1 << 4);
 
 impl std::convert::TryFrom<u8> for SyxType {
@@ -53,38 +115,679 @@ impl std::convert::TryFrom<u8> for SyxType {
     }
 }
 
+#[derive(Clone, Debug)]
 pub enum SyxValue {
     Bool(bool),
     Number(SyxNumber),
     Integer(SyxInteger),
     String(SyxString),
+    Table(TableRef),
+    Function(FunctionRef),
+    Native(NativeFunction),
+    NativeClosure(NativeRef),
+    Thread(ThreadRef),
+    UserData(UserDataRef),
     Nil,
 }
 
+// Function implemented in Rust, called with its arguments and returning its
+// results
+pub type NativeFunction = fn(&mut SyxState, Vec<SyxValue>) -> Result<Vec<SyxValue>>;
+
+const MULTI_INLINE: usize = 3; // values a MultiValue holds without allocating
+
+// Values returned by a call. Most calls return few, so up to MULTI_INLINE of
+// them are kept inline; unused slots hold nil.
+#[derive(Clone)]
+pub struct MultiValue(MultiRepr);
+
+#[derive(Clone)]
+enum MultiRepr {
+    Inline(usize, [SyxValue; MULTI_INLINE]),
+    Heap(Vec<SyxValue>),
+}
+
+impl MultiValue {
+    pub fn new() -> MultiValue {
+        MultiValue(MultiRepr::Inline(0, [SyxValue::Nil, SyxValue::Nil, SyxValue::Nil]))
+    }
+
+    pub fn push(&mut self, value: SyxValue) {
+        match self.0 {
+            MultiRepr::Inline(ref mut len, ref mut values) if *len < MULTI_INLINE => {
+                values[*len] = value;
+                *len += 1;
+            }
+            MultiRepr::Inline(..) => {
+                let mut values = Vec::with_capacity(MULTI_INLINE * 2);
+                values.extend(self.drain_inline());
+                values.push(value);
+                self.0 = MultiRepr::Heap(values);
+            }
+            MultiRepr::Heap(ref mut values) => values.push(value),
+        }
+    }
+
+    fn drain_inline(&mut self) -> impl Iterator<Item = SyxValue> {
+        let old = ::std::mem::replace(&mut self.0, MultiRepr::Heap(Vec::new()));
+        match old {
+            MultiRepr::Inline(len, values) => IntoIterator::into_iter(values).take(len),
+            MultiRepr::Heap(_) => unreachable!(),
+        }
+    }
+
+    // Keep the first `n` values, adding nils if there are fewer, as the
+    // results of a call are adjusted to the number wanted
+    pub fn adjust(&mut self, n: usize) {
+        match self.0 {
+            MultiRepr::Inline(ref mut len, ref mut values) if n <= MULTI_INLINE => {
+                for value in &mut values[n.min(*len)..] {
+                    *value = SyxValue::Nil;
+                }
+                *len = n;
+            }
+            MultiRepr::Heap(ref mut values) => values.resize(n, SyxValue::Nil),
+            MultiRepr::Inline(..) => {
+                while self.len() < n {
+                    self.push(SyxValue::Nil);
+                }
+            }
+        }
+    }
+
+    // the first value, nil if there are none
+    pub fn into_first(self) -> SyxValue {
+        self.into_iter().next().unwrap_or(SyxValue::Nil)
+    }
+
+    pub fn into_vec(self) -> Vec<SyxValue> {
+        match self.0 {
+            MultiRepr::Heap(values) => values,
+            MultiRepr::Inline(..) => self.into_iter().collect(),
+        }
+    }
+}
+
+impl Default for MultiValue {
+    fn default() -> MultiValue {
+        MultiValue::new()
+    }
+}
+
+impl ::std::ops::Deref for MultiValue {
+    type Target = [SyxValue];
+
+    fn deref(&self) -> &[SyxValue] {
+        match self.0 {
+            MultiRepr::Inline(len, ref values) => &values[..len],
+            MultiRepr::Heap(ref values) => values,
+        }
+    }
+}
+
+impl ::std::ops::DerefMut for MultiValue {
+    fn deref_mut(&mut self) -> &mut [SyxValue] {
+        match self.0 {
+            MultiRepr::Inline(len, ref mut values) => &mut values[..len],
+            MultiRepr::Heap(ref mut values) => values,
+        }
+    }
+}
+
+impl ::std::fmt::Debug for MultiValue {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl PartialEq for MultiValue {
+    fn eq(&self, other: &MultiValue) -> bool {
+        **self == **other
+    }
+}
+
+impl PartialEq<Vec<SyxValue>> for MultiValue {
+    fn eq(&self, other: &Vec<SyxValue>) -> bool {
+        **self == other[..]
+    }
+}
+
+impl From<Vec<SyxValue>> for MultiValue {
+    fn from(values: Vec<SyxValue>) -> MultiValue {
+        if values.len() <= MULTI_INLINE {
+            values.into_iter().collect()
+        } else {
+            MultiValue(MultiRepr::Heap(values))
+        }
+    }
+}
+
+impl From<MultiValue> for Vec<SyxValue> {
+    fn from(values: MultiValue) -> Vec<SyxValue> {
+        values.into_vec()
+    }
+}
+
+impl ::std::iter::FromIterator<SyxValue> for MultiValue {
+    fn from_iter<I: IntoIterator<Item = SyxValue>>(iter: I) -> MultiValue {
+        let mut values = MultiValue::new();
+        values.extend(iter);
+        values
+    }
+}
+
+impl Extend<SyxValue> for MultiValue {
+    fn extend<I: IntoIterator<Item = SyxValue>>(&mut self, iter: I) {
+        for value in iter {
+            self.push(value);
+        }
+    }
+}
+
+impl IntoIterator for MultiValue {
+    type Item = SyxValue;
+    type IntoIter = MultiIter;
+
+    fn into_iter(self) -> MultiIter {
+        match self.0 {
+            MultiRepr::Inline(len, values) => {
+                MultiIter::Inline(IntoIterator::into_iter(values).take(len))
+            }
+            MultiRepr::Heap(values) => MultiIter::Heap(values.into_iter()),
+        }
+    }
+}
+
+pub enum MultiIter {
+    Inline(::std::iter::Take<::std::array::IntoIter<SyxValue, MULTI_INLINE>>),
+    Heap(::std::vec::IntoIter<SyxValue>),
+}
+
+impl Iterator for MultiIter {
+    type Item = SyxValue;
+
+    fn next(&mut self) -> Option<SyxValue> {
+        match *self {
+            MultiIter::Inline(ref mut values) => values.next(),
+            MultiIter::Heap(ref mut values) => values.next(),
+        }
+    }
+}
+
+impl SyxValue {
+    // name used by `type()` and runtime error messages
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            SyxValue::Bool(_) => "boolean",
+            SyxValue::Number(_) | SyxValue::Integer(_) => "number",
+            SyxValue::String(_) => "string",
+            SyxValue::Table(_) => "table",
+            SyxValue::Function(_) | SyxValue::Native(_) | SyxValue::NativeClosure(_) => "function",
+            SyxValue::Thread(_) => "thread",
+            SyxValue::UserData(_) => "userdata",
+            SyxValue::Nil => "nil",
+        }
+    }
+
+    // type tag of the value, telling integers and floats apart
+    pub fn syx_type(&self) -> SyxType {
+        match *self {
+            SyxValue::Nil => SyxType::TNIL,
+            SyxValue::Bool(_) => SyxType::TBOOLEAN,
+            SyxValue::Integer(_) => SyxType::TNUMINT,
+            SyxValue::Number(_) => SyxType::TNUMFLT,
+            SyxValue::String(_) => SyxType::TSTRING,
+            SyxValue::Table(_) => SyxType::TTABLE,
+            SyxValue::Function(_) | SyxValue::Native(_) | SyxValue::NativeClosure(_) => {
+                SyxType::TFUNCTION
+            }
+            SyxValue::Thread(_) => SyxType::TTHREAD,
+            SyxValue::UserData(_) => SyxType::TUSERDATA,
+        }
+    }
+
+    // Lua function, native function or closure
+    pub fn is_function(&self) -> bool {
+        matches!(*self, SyxValue::Function(_) | SyxValue::Native(_) | SyxValue::NativeClosure(_))
+    }
+
+    pub fn is_nil(&self) -> bool {
+        matches!(*self, SyxValue::Nil)
+    }
+
+    // only nil and false are false
+    pub fn is_falsy(&self) -> bool {
+        matches!(*self, SyxValue::Nil | SyxValue::Bool(false))
+    }
+
+    // The number this value converts to, as tonumber, arithmetic and the
+    // library's number arguments see it: numbers as they are and strings as
+    // numerals (see `string_to_number`). With a `base`, from 2 to 36, only a
+    // string converts, to the integer its digits and letters denote in that
+    // base.
+    pub fn coerce_to_number(&self, base: Option<u32>) -> Option<SyxValue> {
+        match (self, base) {
+            (&SyxValue::Integer(_), None) | (&SyxValue::Number(_), None) => Some(self.clone()),
+            (SyxValue::String(s), None) => string_to_number(s),
+            (SyxValue::String(s), Some(base)) => digits_to_integer(s, base).map(integer_value),
+            _ => None,
+        }
+    }
+
+    // The integer this value converts to, as luaV_tointeger has it for the
+    // bitwise operators, string.format's integer conversions and the
+    // library's integer arguments: integers as they are, floats only when
+    // they have an exact integer value (see `float_to_integer`), and
+    // strings as the numerals they are.
+    pub fn coerce_to_integer(&self) -> Option<SyxInteger> {
+        match self.coerce_to_number(None)? {
+            SyxValue::Integer(i) => Some(i),
+            SyxValue::Number(n) => float_to_integer(n),
+            _ => None,
+        }
+    }
+}
+
+// Raw equality: integers and floats compare by mathematical value
+impl PartialEq for SyxValue {
+    fn eq(&self, other: &SyxValue) -> bool {
+        match (self, other) {
+            (&SyxValue::Nil, &SyxValue::Nil) => true,
+            (&SyxValue::Bool(a), &SyxValue::Bool(b)) => a == b,
+            (&SyxValue::Integer(a), &SyxValue::Integer(b)) => a == b,
+            (&SyxValue::Number(a), &SyxValue::Number(b)) => a == b,
+            // exactly, 2^53 + 1 is not equal to 2^53 as a float
+            (&SyxValue::Integer(a), &SyxValue::Number(b)) |
+            (&SyxValue::Number(b), &SyxValue::Integer(a)) => float_to_integer(b) == Some(a),
+            (SyxValue::String(a), SyxValue::String(b)) => a == b,
+            (&SyxValue::Table(a), &SyxValue::Table(b)) => a == b,
+            (&SyxValue::Function(a), &SyxValue::Function(b)) => a == b,
+            (&SyxValue::Native(a), &SyxValue::Native(b)) => a as usize == b as usize,
+            (&SyxValue::NativeClosure(a), &SyxValue::NativeClosure(b)) => a == b,
+            (&SyxValue::Thread(a), &SyxValue::Thread(b)) => a == b,
+            (&SyxValue::UserData(a), &SyxValue::UserData(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl<'a> From<&'a str> for SyxValue {
+    fn from(string: &'a str) -> SyxValue {
+        SyxValue::String(string.into())
+    }
+}
+
+// NaN is the only value not equal to itself, and it is never used as a key
+impl Eq for SyxValue {}
+
+// Consistent with `eq`: floats with an integer value hash like the integer
+impl Hash for SyxValue {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match *self {
+            SyxValue::Nil => 0u8.hash(state),
+            SyxValue::Bool(b) => b.hash(state),
+            SyxValue::Integer(i) => i.hash(state),
+            SyxValue::Number(n) => match float_to_integer(n) {
+                Some(i) => i.hash(state),
+                None => n.to_bits().hash(state),
+            },
+            SyxValue::String(ref s) => s.hash(state),
+            SyxValue::Table(t) => t.hash(state),
+            SyxValue::Function(f) => f.hash(state),
+            SyxValue::Native(f) => (f as usize).hash(state),
+            SyxValue::NativeClosure(f) => f.hash(state),
+            SyxValue::Thread(t) => t.hash(state),
+            SyxValue::UserData(u) => u.hash(state),
+        }
+    }
+}
+
+// float -> integer conversion, only when no precision is lost: 3.0 is 3,
+// while 3.5, NaN, the infinities and floats out of the integer range have
+// no integer value. Table keys are normalized with it too, so t[3.0] is
+// t[3].
+pub fn float_to_integer(n: SyxNumber) -> Option<SyxInteger> {
+    if n.floor() == n && n >= SyxInteger::MIN as SyxNumber &&
+        n < -(SyxInteger::MIN as SyxNumber) {
+        Some(n as SyxInteger)
+    } else {
+        None
+    }
+}
+
+// The number a numeral denotes, as luaO_str2num reads it: decimal or
+// hexadecimal, integer or float, maybe signed and surrounded by whitespace.
+// Decimal integers too large for an integer are read as floats, while
+// hexadecimal ones wrap around.
+pub fn string_to_number(bytes: &[u8]) -> Option<SyxValue> {
+    let text = std::str::from_utf8(bytes).ok()?
+        .trim_matches(|c: char| c.is_ascii_whitespace() || c == '\x0b');
+    let (negative, body) = match text.as_bytes().first() {
+        Some(b'-') => (true, &text[1..]),
+        Some(b'+') => (false, &text[1..]),
+        _ => (false, text),
+    };
+    if body.starts_with("0x") || body.starts_with("0X") {
+        return hex_to_number(&body[2..], negative);
+    }
+    // no "inf" or "nan", which Rust would accept
+    if !body.starts_with(|c: char| c.is_ascii_digit() || c == '.') ||
+        !body.bytes().all(|b| b.is_ascii_digit() || b"eE+-.".contains(&b)) {
+        return None;
+    }
+    if body.bytes().all(|b| b.is_ascii_digit()) {
+        if let Ok(i) = text.parse::<SyxInteger>() {
+            return Some(integer_value(i));
+        }
+    }
+    text.parse::<SyxNumber>().ok().map(SyxValue::Number)
+}
+
+// the integer `digits` denote in `base`, maybe negative and surrounded by
+// whitespace, wrapping around when too large as l_str2int does
+fn digits_to_integer(digits: &[u8], base: u32) -> Option<SyxInteger> {
+    debug_assert!((2..=36).contains(&base));
+    let text = std::str::from_utf8(digits).ok()?
+        .trim_matches(|c: char| c.is_ascii_whitespace() || c == '\x0b');
+    let (negative, text) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    if text.is_empty() {
+        return None;
+    }
+    let mut value: SyxInteger = 0;
+    for c in text.chars() {
+        let digit = c.to_digit(36).filter(|&d| d < base)?;
+        value = value.wrapping_mul(base as SyxInteger).wrapping_add(digit as SyxInteger);
+    }
+    Some(if negative { value.wrapping_neg() } else { value })
+}
+
+fn hex_to_number(body: &str, negative: bool) -> Option<SyxValue> {
+    let (mantissa, exponent) = match body.find(['p', 'P']) {
+        Some(i) => (&body[..i], Some(body[i + 1..].parse::<i32>().ok()?)),
+        None => (body, None),
+    };
+    let (whole, fraction) = match mantissa.find('.') {
+        Some(i) => (&mantissa[..i], Some(&mantissa[i + 1..])),
+        None => (mantissa, None),
+    };
+    let digits = whole.len() + fraction.map_or(0, str::len);
+    if digits == 0 || !whole.chars().chain(fraction.unwrap_or("").chars())
+        .all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    if fraction.is_none() && exponent.is_none() {
+        let mut value: SyxInteger = 0;
+        for c in whole.chars() {
+            value = value.wrapping_mul(16).wrapping_add(c.to_digit(16)? as SyxInteger);
+        }
+        return Some(integer_value(if negative { value.wrapping_neg() } else { value }));
+    }
+    let mut value: SyxNumber = 0.0;
+    for c in whole.chars().chain(fraction.unwrap_or("").chars()) {
+        value = value * 16.0 + c.to_digit(16)? as SyxNumber;
+    }
+    let scale = exponent.unwrap_or(0) - 4 * fraction.map_or(0, str::len) as i32;
+    let value = value * (2.0 as SyxNumber).powi(scale);
+    Some(SyxValue::Number(if negative { -value } else { value }))
+}
+
+// Handle to a table owned by a SyxState
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TableRef(pub(crate) usize);
+
+// Handle to a closure owned by a SyxState
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FunctionRef(pub(crate) usize);
+
+// Handle to a native closure owned by a SyxState, see native.rs
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct NativeRef(pub(crate) usize);
+
+// Handle to an upvalue owned by a SyxState, shared by the closures that
+// captured the same variable
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct UpvalRef(pub(crate) usize);
+
+// Handle to a coroutine owned by a SyxState
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ThreadRef(pub(crate) usize);
+
+// Handle to a userdata owned by a SyxState, see userdata.rs
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct UserDataRef(pub(crate) usize);
+
+// Tables keep the values for keys 1..n in `array` and everything else in
+// `hash`. Integer keys just past the array part are moved into it as they
+// are set, so sequences built in order never touch the hash part.
+//
+// Clearing a key of the hash part leaves it in place with a nil value, so
+// `next` can still continue from it; these dead keys are only dropped when
+// the hash part would otherwise have to grow.
+pub struct SyxTable {
+    pub(crate) array: Vec<SyxValue>,
+    pub(crate) hash: HashMap<SyxValue, SyxValue, SeededState>,
+    metatable: Option<TableRef>,
+    pub(crate) version: u64, // changed by every write, see vm.rs field caches
+}
+
+impl SyxTable {
+    pub fn new(narray: usize, nhash: usize) -> SyxTable {
+        SyxTable {
+            array: Vec::with_capacity(narray),
+            hash: HashMap::with_capacity_and_hasher(nhash, SeededState::default()),
+            metatable: None,
+            version: 0,
+        }
+    }
+
+    pub fn metatable(&self) -> Option<TableRef> {
+        self.metatable
+    }
+
+    pub fn set_metatable(&mut self, metatable: Option<TableRef>) {
+        self.metatable = metatable;
+    }
+
+    pub fn get(&self, key: &SyxValue) -> SyxValue {
+        match *key {
+            SyxValue::Integer(i) => self.get_int(i),
+            SyxValue::Number(n) => match float_to_integer(n) {
+                Some(i) => self.get_int(i),
+                None => self.hash.get(key).cloned().unwrap_or(SyxValue::Nil),
+            },
+            SyxValue::Nil => SyxValue::Nil,
+            _ => self.hash.get(key).cloned().unwrap_or(SyxValue::Nil),
+        }
+    }
+
+    pub fn get_int(&self, key: SyxInteger) -> SyxValue {
+        if key >= 1 && key as u64 <= self.array.len() as u64 {
+            self.array[key as usize - 1].clone()
+        } else {
+            self.hash.get(&SyxValue::Integer(key)).cloned().unwrap_or(SyxValue::Nil)
+        }
+    }
+
+    pub fn set(&mut self, key: SyxValue, value: SyxValue) -> Result<()> {
+        match key {
+            SyxValue::Integer(i) => self.set_int(i, value),
+            SyxValue::Number(n) => match float_to_integer(n) {
+                Some(i) => self.set_int(i, value),
+                None if n.is_nan() => bail!(ErrorKind::InvalidTableKey("NaN")),
+                None => self.set_hash(key, value),
+            },
+            SyxValue::Nil => bail!(ErrorKind::InvalidTableKey("nil")),
+            _ => self.set_hash(key, value),
+        }
+        Ok(())
+    }
+
+    pub fn set_int(&mut self, key: SyxInteger, value: SyxValue) {
+        let len = self.array.len() as u64;
+        if key >= 1 && key as u64 <= len {
+            self.array[key as usize - 1] = value;
+        } else if key >= 1 && key as u64 == len + 1 {
+            if let SyxValue::Nil = value {
+                self.set_hash(SyxValue::Integer(key), value);
+                return;
+            }
+            self.array.push(value);
+            self.migrate();
+        } else {
+            self.set_hash(SyxValue::Integer(key), value);
+        }
+    }
+
+    fn set_hash(&mut self, key: SyxValue, value: SyxValue) {
+        if let Some(slot) = self.hash.get_mut(&key) {
+            *slot = value;
+        } else if !value.is_nil() {
+            if self.hash.len() == self.hash.capacity() {
+                self.hash.retain(|_, value| !value.is_nil());
+            }
+            self.hash.insert(key, value);
+        }
+    }
+
+    // pull keys that now continue the array part out of the hash part
+    fn migrate(&mut self) {
+        let mut next = self.array.len() as SyxInteger + 1;
+        while let Some(value) = self.hash.remove(&SyxValue::Integer(next)) {
+            if value.is_nil() {
+                break;
+            }
+            self.array.push(value);
+            next += 1;
+        }
+    }
+
+    // The entry following `key` in the table's traversal order, starting
+    // with the first for nil: the array part in order, then the hash part.
+    // Existing fields may be assigned or cleared during a traversal, and
+    // every field not cleared is still visited exactly once; assigning to a
+    // key the table did not have leaves the order undefined, and `key` may
+    // then be reported invalid.
+    pub fn next(&self, key: &SyxValue) -> Result<Option<(SyxValue, SyxValue)>> {
+        let key = match *key {
+            SyxValue::Number(n) => float_to_integer(n).map_or(key.clone(), SyxValue::Integer),
+            _ => key.clone(),
+        };
+        let start = match key {
+            SyxValue::Nil => 0,
+            SyxValue::Integer(i) if i >= 1 && i as u64 <= self.array.len() as u64 => i as usize,
+            _ => match self.hash.keys().position(|k| *k == key) {
+                Some(i) => self.array.len() + i + 1,
+                None => bail!(ErrorKind::InvalidNextKey),
+            },
+        };
+        if let Some(i) = self.array.iter().skip(start).position(|value| !value.is_nil()) {
+            let i = start + i;
+            return Ok(Some((SyxValue::Integer(i as SyxInteger + 1), self.array[i].clone())));
+        }
+        let start = start.saturating_sub(self.array.len());
+        Ok(self.hash.iter().skip(start)
+            .find(|&(_, value)| !value.is_nil())
+            .map(|(key, value)| (key.clone(), value.clone())))
+    }
+
+    // Owned copies of the table's entries, in the order of `next`
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = (SyxValue, SyxValue)> + 'a {
+        let array = self.array.iter().enumerate()
+            .map(|(i, value)| (SyxValue::Integer(i as SyxInteger + 1), value.clone()));
+        let hash = self.hash.iter().map(|(key, value)| (key.clone(), value.clone()));
+        array.chain(hash).filter(|(_, value)| !value.is_nil())
+    }
+
+    // Any border of the table, as `#`: an index n where t[n] is non-nil and
+    // t[n + 1] is nil, or 0 when t[1] is nil.
+    pub fn length(&self) -> SyxInteger {
+        let len = self.array.len();
+        if len > 0 && self.array[len - 1].is_nil() {
+            // binary search, t[i] is non-nil (or i = 0) and t[j] is nil
+            let (mut i, mut j) = (0, len);
+            while j - i > 1 {
+                let m = (i + j) / 2;
+                if self.array[m - 1].is_nil() {
+                    j = m;
+                } else {
+                    i = m;
+                }
+            }
+            return i as SyxInteger;
+        }
+        if self.hash.is_empty() {
+            return len as SyxInteger;
+        }
+        self.unbound_search(len as SyxInteger)
+    }
+
+    fn unbound_search(&self, start: SyxInteger) -> SyxInteger {
+        let (mut i, mut j) = (start, start + 1);
+        while !self.get_int(j).is_nil() {
+            i = j;
+            if j > SyxInteger::MAX / 2 {
+                // pathological table, fall back to a linear search
+                let mut i = 1;
+                while !self.get_int(i).is_nil() {
+                    i += 1;
+                }
+                return i - 1;
+            }
+            j *= 2;
+        }
+        while j - i > 1 {
+            let m = i + (j - i) / 2;
+            if self.get_int(m).is_nil() {
+                j = m;
+            } else {
+                i = m;
+            }
+        }
+        i
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct Upvalue {
     pub name: SyxString,
     pub instack: u8, // ::TODO:: bool?
     pub idx: u8,
 }
 
+// Line anchor for chunks that store line info as deltas (5.4)
+pub struct AbsLineInfo {
+    pub pc: SyxInt,
+    pub line: SyxInt,
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct LocVar {
     pub varname: SyxString, // name of local variable
     pub startpc: SyxInt,    // point where variable is alive
     pub endpc: SyxInt,      // point where variable is dead
 }
 
+// A Proto is never changed once loaded, so a loaded chunk is shared rather
+// than copied: closures hold an Arc of their Proto, nested functions are
+// Arcs of their own, and string constants share their bytes. Any number of
+// states, on any threads, can run one `Arc<Proto>`.
 pub struct Proto {
     // Function Prototypes
     pub numparams: u8,       // number of fixed parameters (does not include vararg)
-    pub is_vararg: bool,     // should be obvious
+    pub is_vararg: u8,       // vararg flags as stored by luac, 0 if none
     pub maxstacksize: u8,    // amount of registers needed
     pub linedefined: SyxInt, // debug
     pub lastlinedefined: SyxInt, // debug
     pub constants: Vec<SyxValue>, // constants used by the function
     pub ip: i32,             // instruction pointer, used for instruction index
     pub instructions: Vec<Instruction>, // function opcodes
-    pub protos: Vec<Proto>,  // functions defined in this function
+    pub protos: Vec<Arc<Proto>>, // functions defined in this function
     pub lineinfo: Vec<i32>,  // map from opcode to source lines ::TODO:: what?
+    pub abslineinfo: Vec<AbsLineInfo>, // line anchors, empty before 5.4
+    pub columninfo: Vec<i32>, // map from opcode to source columns, usually empty
     pub upvalues: Vec<Upvalue>, // upvalue information
     pub locvars: Vec<LocVar>, // local variables
     pub source: String,
@@ -94,7 +797,7 @@ impl Proto {
     pub fn new() -> Proto {
         Proto {
             numparams: 0,
-            is_vararg: false,
+            is_vararg: 0,
             maxstacksize: 0,
             linedefined: 0,
             lastlinedefined: 0,
@@ -103,11 +806,37 @@ impl Proto {
             instructions: Vec::new(),
             protos: Vec::new(),
             lineinfo: Vec::new(),
+            abslineinfo: Vec::new(),
+            columninfo: Vec::new(),
             upvalues: Vec::new(),
             locvars: Vec::new(),
             source: "".to_owned(),
         }
     }
+
+    // Drop the debug information of this function and the functions defined
+    // in it, as `luac -s` does; functions shared with another Proto are kept
+    pub fn strip_debug(&mut self) {
+        self.source.clear();
+        self.lineinfo.clear();
+        self.abslineinfo.clear();
+        self.columninfo.clear();
+        self.locvars.clear();
+        for upvalue in &mut self.upvalues {
+            upvalue.name = SyxString::from("");
+        }
+        for child in &mut self.protos {
+            if let Some(child) = Arc::get_mut(child) {
+                child.strip_debug();
+            }
+        }
+    }
+}
+
+impl Default for Proto {
+    fn default() -> Proto {
+        Proto::new()
+    }
 }
 
 // typedef struct Proto {
@@ -133,3 +862,136 @@ impl Proto {
 //   TString  *source;  /* used for debug information */
 //   GCObject *gclist;
 // } Proto;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_multi_value() {
+        let mut values: MultiValue = (1..=3).map(SyxValue::Integer).collect();
+        values.adjust(2);
+        assert_eq!(values, vec![SyxValue::Integer(1), SyxValue::Integer(2)]);
+        values.adjust(3);
+        assert!(values[2].is_nil());
+        // spills to the heap past the inline capacity and keeps its order
+        values.push(SyxValue::Integer(4));
+        values.adjust(5);
+        assert_eq!(values.len(), 5);
+        assert_eq!(values[3], SyxValue::Integer(4));
+        values.adjust(0);
+        assert!(values.is_empty() && values.into_first().is_nil());
+    }
+
+    #[test]
+    fn test_coerce_to_number() {
+        let coerce = |value: SyxValue, base| value.coerce_to_number(base);
+        assert!(matches!(coerce(SyxValue::from("\t0x1p4\n"), None), Some(SyxValue::Number(n))
+                         if n == 16.0));
+        assert_eq!(coerce(SyxValue::from(" 10 "), None), Some(SyxValue::Integer(10)));
+        assert_eq!(coerce(SyxValue::Number(0.5), None), Some(SyxValue::Number(0.5)));
+        assert_eq!(coerce(SyxValue::Bool(true), None), None);
+        assert_eq!(coerce(SyxValue::from("1e"), None), None);
+        assert_eq!(coerce(SyxValue::from("Z"), Some(36)), Some(SyxValue::Integer(35)));
+        assert_eq!(coerce(SyxValue::from("102"), Some(2)), None);
+        // only strings have digits to read in a base
+        assert_eq!(coerce(SyxValue::Integer(10), Some(10)), None);
+    }
+
+    #[test]
+    fn test_coerce_to_integer() {
+        let min = SyxInteger::MIN as SyxNumber;
+        assert_eq!(SyxValue::Number(3.0).coerce_to_integer(), Some(3));
+        assert_eq!(SyxValue::Number(-0.0).coerce_to_integer(), Some(0));
+        assert_eq!(SyxValue::Number(min).coerce_to_integer(), Some(SyxInteger::MIN));
+        // the largest integer is not exactly a float, and the next one is out of range
+        assert_eq!(SyxValue::Number(-min).coerce_to_integer(), None);
+        assert_eq!(SyxValue::Number(3.5).coerce_to_integer(), None);
+        assert_eq!(SyxValue::Number(SyxNumber::NAN).coerce_to_integer(), None);
+        assert_eq!(SyxValue::Number(SyxNumber::INFINITY).coerce_to_integer(), None);
+        assert_eq!(SyxValue::from(" 0x10 ").coerce_to_integer(), Some(16));
+        assert_eq!(SyxValue::from("1e2").coerce_to_integer(), Some(100));
+        assert_eq!(SyxValue::from("1.5").coerce_to_integer(), None);
+        assert_eq!(SyxValue::Bool(true).coerce_to_integer(), None);
+    }
+
+    #[test]
+    fn test_table_array_part() {
+        let mut table = SyxTable::new(0, 0);
+        table.set_int(2, SyxValue::Integer(20));
+        table.set_int(3, SyxValue::Integer(30));
+        assert_eq!(table.length(), 0);
+        // setting 1 pulls 2 and 3 out of the hash part
+        table.set(SyxValue::Number(1.0), SyxValue::Integer(10)).unwrap();
+        assert_eq!(table.array.len(), 3);
+        assert!(table.hash.is_empty());
+        assert_eq!(table.length(), 3);
+        assert_eq!(table.get(&SyxValue::Number(2.0)), SyxValue::Integer(20));
+    }
+
+    #[test]
+    fn test_table_border() {
+        let mut table = SyxTable::new(0, 0);
+        for i in 1..=8 {
+            table.set_int(i, SyxValue::Bool(true));
+        }
+        table.set_int(8, SyxValue::Nil);
+        assert_eq!(table.length(), 7);
+        table.set_int(3, SyxValue::Nil);
+        let border = table.length();
+        assert!(!table.get_int(border).is_nil() && table.get_int(border + 1).is_nil());
+
+        let mut table = SyxTable::new(0, 0);
+        table.set_int(1, SyxValue::Bool(true));
+        table.set(SyxValue::String("x".into()), SyxValue::Bool(true)).unwrap();
+        assert_eq!(table.length(), 1);
+    }
+
+    #[test]
+    fn test_table_keys() {
+        let mut table = SyxTable::new(0, 0);
+        table.set(SyxValue::Number(0.5), SyxValue::Integer(1)).unwrap();
+        table.set(SyxValue::String("a".into()), SyxValue::Integer(2)).unwrap();
+        assert_eq!(table.get(&SyxValue::Number(0.5)), SyxValue::Integer(1));
+        assert_eq!(table.get(&SyxValue::String("a".into())), SyxValue::Integer(2));
+        table.set(SyxValue::String("a".into()), SyxValue::Nil).unwrap();
+        // the key stays behind, dead, for traversals
+        assert_eq!(table.hash.values().filter(|value| !value.is_nil()).count(), 1);
+        assert!(table.next(&SyxValue::String("a".into())).is_ok());
+
+        let error = table.set(SyxValue::Nil, SyxValue::Integer(1)).unwrap_err();
+        assert_eq!(error.to_string(), "table index is nil");
+        let error = table.set(SyxValue::Number(SyxNumber::NAN), SyxValue::Integer(1));
+        assert_eq!(error.unwrap_err().to_string(), "table index is NaN");
+    }
+
+    #[test]
+    fn test_table_traversal() {
+        let mut table = SyxTable::new(0, 0);
+        for i in 1..=3 {
+            table.set_int(i, SyxValue::Integer(i));
+        }
+        for i in 5..=40 {
+            table.set_int(i, SyxValue::Integer(i));
+        }
+        table.set(SyxValue::from("a"), SyxValue::Bool(true)).unwrap();
+        let expected = table.iter().collect::<Vec<_>>();
+        assert_eq!(expected.len(), 40);
+
+        // clear every other field and double the rest as they are visited
+        let (mut key, mut visited) = (SyxValue::Nil, vec![]);
+        while let Some((next, value)) = table.next(&key).unwrap() {
+            visited.push((next.clone(), value.clone()));
+            if visited.len() % 2 == 0 {
+                table.set(next.clone(), SyxValue::Nil).unwrap();
+            } else if let SyxValue::Integer(i) = value {
+                table.set(next.clone(), SyxValue::Integer(i * 2)).unwrap();
+            }
+            key = next;
+        }
+        assert_eq!(visited, expected);
+        assert_eq!(table.iter().count(), 20);
+        assert_eq!(table.get_int(1), SyxValue::Integer(2));
+        assert!(table.next(&SyxValue::from("b")).is_err());
+    }
+}
@@ -1,13 +1,11 @@
 // Set up VM instructions
 #![allow(dead_code)]
 
-#[macro_use]
 use syx_codegen::bytecode;
 
+#[cfg(test)]
 use std::convert::{TryFrom, TryInto};
 
-use super::object;
-
 /* Word Format:
  * |0bBBBBBBBBB_CCCCCCCCC_AAAAAAAA_IIIIII| -> B, C, A, Instruction
  * |0bBBBBBBBBB_BBBBBBBBB_AAAAAAAA_IIIIII| -> Bx, A, Instruction
@@ -21,15 +19,19 @@ use super::object;
  * more values than could be used normally. The value sBx is used regardless of
  * whether the space is needed for B, and the result is a signed integer.
  *
+ * Instruction keeps only the format of an instruction, which is all the VM
+ * needs; Decoded has a variant for each opcode with the operands it uses
+ * (`Decoded::Add { a, b, c }`). Both convert to and from Words, and the
+ * Operands trait reads fields out of either the way GETARG_* does. Plain
+ * conversion to a Word drops the bits an operand has past its field; `encode`
+ * refuses such operands instead.
+ *
  * Consult the versioned lopcodes.h for more information.
  */
 
 use super::errors::*;
 
-#[macro_use]
-use super::try_from_enum;
-
-bytecode! { Instruction | OpCode | Error = ErrorKind::InvalidOpCode.into() =>
+bytecode! { Instruction | OpCode | Decoded | Error = ErrorKind::InvalidOpCode.into() =>
     Move: AB = Register, Register; // R(A) := R(B)
     LoadK: ABx = Register, Constant; // R(A) = Kst(Bx)
     LoadKX: A = Register; // R(A) = Kst(extra arg); see ExtraArg
@@ -89,7 +91,7 @@ bytecode! { Instruction | OpCode | Error = ErrorKind::InvalidOpCode.into() =>
     Return: AB = Register, Integer; // return R(A), ... ,R(A+B-2) (see note)
 
     ForLoop: AsBx = Register, SInteger; // R(A)+=R(A+2); if R(A) <?= R(A+1) { pc += sBx; R(A+3)=R(A) }
-    ForPrep: AsBx = Register, SInteger; // R(A)-=R(A+2); pc += sBx
+    ForPrep: AsBx = Register, SInteger; // if R(A) <?= R(A+1) { R(A+3)=R(A) } else { pc += sBx+1 }
 
     // set register A+3 through A+2+C to return values of call R(A) for R(A+1) and R(A+2)
     // i think this means you can only have two arguments to an iterator?
@@ -104,6 +106,97 @@ bytecode! { Instruction | OpCode | Error = ErrorKind::InvalidOpCode.into() =>
     VarArg: AB = Register, Integer; // R(A+1), ..., R(A+B-2) = vararg
 
     ExtraArg: Ax = Integer; // ExtraArg = Ax
+
+    // not part of Lua, inserted by Proto::with_checks; see preempt.rs
+    Check: A = Integer; // yield if the host asked to preempt
+}
+
+// Fields of an encoded instruction, whether its format uses them or not
+pub trait Operands {
+    fn opcode(&self) -> OpCode;
+    fn word(&self) -> Word;
+
+    fn a(&self) -> u8 {
+        ((self.word() >> OFFSET_A) & MAXARG_A) as u8
+    }
+
+    fn b(&self) -> u16 {
+        ((self.word() >> OFFSET_B) & MAXARG_B) as u16
+    }
+
+    fn c(&self) -> u16 {
+        ((self.word() >> OFFSET_C) & MAXARG_C) as u16
+    }
+
+    fn bx(&self) -> u32 {
+        (self.word() >> OFFSET_BX) & MAXARG_BX
+    }
+
+    fn sbx(&self) -> i32 {
+        self.bx() as i32 - MAXARG_SBX
+    }
+
+    fn ax(&self) -> u32 {
+        (self.word() >> OFFSET_AX) & MAXARG_AX
+    }
+}
+
+impl Instruction {
+    // The Word for this instruction, if every operand fits its field
+    pub fn encode(&self) -> Result<Word> {
+        let check = |field, value: i64, max: i64| if value < 0 || value > max {
+            Err(Error::from(ErrorKind::InvalidOperand(self.opcode(), field, value)))
+        } else {
+            Ok(())
+        };
+        match *self {
+            Instruction::ABC { b, c, .. } => {
+                check("B", i64::from(b), i64::from(MAXARG_B))?;
+                check("C", i64::from(c), i64::from(MAXARG_C))?;
+            }
+            Instruction::ABx { bx, .. } => check("Bx", i64::from(bx), i64::from(MAXARG_BX))?,
+            Instruction::AsBx { sbx, .. } => {
+                // checked as stored, in excess-K
+                let stored = i64::from(sbx) + i64::from(MAXARG_SBX);
+                if stored < 0 || stored > i64::from(MAXARG_BX) {
+                    bail!(ErrorKind::InvalidOperand(self.opcode(), "sBx", i64::from(sbx)));
+                }
+            }
+            Instruction::Ax { ax, .. } => check("Ax", i64::from(ax), i64::from(MAXARG_AX))?,
+        }
+        Ok(Word::from(self))
+    }
+}
+
+impl Decoded {
+    pub fn encode(&self) -> Result<Word> {
+        Instruction::from(self).encode()
+    }
+}
+
+impl Operands for Instruction {
+    fn opcode(&self) -> OpCode {
+        match *self {
+            Instruction::ABC { instruction, .. } | Instruction::ABx { instruction, .. } |
+            Instruction::AsBx { instruction, .. } | Instruction::Ax { instruction, .. } => {
+                instruction
+            }
+        }
+    }
+
+    fn word(&self) -> Word {
+        Word::from(self)
+    }
+}
+
+impl Operands for Decoded {
+    fn opcode(&self) -> OpCode {
+        Decoded::opcode(self)
+    }
+
+    fn word(&self) -> Word {
+        Word::from(self)
+    }
 }
 
 /*===========================================================================
@@ -130,6 +223,7 @@ bytecode! { Instruction | OpCode | Error = ErrorKind::InvalidOpCode.into() =>
 ===========================================================================*/
 
 #[cfg(test)]
+#[allow(clippy::unusual_byte_groupings)] // grouped by field, see above
 mod tests {
     use super::*;
 
@@ -176,4 +270,37 @@ mod tests {
             assert_eq!(instr, instr_comp);
         }
     }
+
+    #[test]
+    fn test_decoded() {
+        let word = Word::from(&Instruction::ABC { instruction: OpCode::Add, a: 1, b: 2, c: 259 });
+        let decoded: Decoded = word.try_into().unwrap();
+        assert_eq!(decoded, Decoded::Add { a: 1, b: 2, c: 259 });
+        assert_eq!(decoded.opcode(), OpCode::Add);
+        assert_eq!(Word::from(&decoded), word);
+        assert_eq!(decoded.c() & BITRK as u16, BITRK as u16);
+
+        let jump = Instruction::AsBx { instruction: OpCode::Jmp, a: 0, sbx: -5 };
+        assert_eq!(Decoded::from(&jump), Decoded::Jmp { a: 0, sbx: -5 });
+        assert_eq!(jump.sbx(), -5);
+        assert_eq!(Instruction::from(&Decoded::Jmp { a: 0, sbx: -5 }), jump);
+        // an AB instruction has no C to keep
+        let ret = Instruction::ABC { instruction: OpCode::Return, a: 0, b: 1, c: 0 };
+        assert_eq!(Decoded::from(&ret), Decoded::Return { a: 0, b: 1 });
+        let extra = Decoded::ExtraArg { ax: MAXARG_AX };
+        assert_eq!(Instruction::from(&extra).ax(), MAXARG_AX);
+        assert!(Decoded::try_from(63 as Word).is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        let word = Decoded::Jmp { a: 0, sbx: -MAXARG_SBX }.encode().unwrap();
+        assert_eq!(Decoded::try_from(word).unwrap(), Decoded::Jmp { a: 0, sbx: -MAXARG_SBX });
+        let error = Decoded::Jmp { a: 0, sbx: -MAXARG_SBX - 1 }.encode().unwrap_err();
+        assert_eq!(error.to_string(), "operand sBx of Jmp out of range: -131072");
+        let error = Decoded::Add { a: 0, b: 512, c: 0 }.encode().unwrap_err();
+        assert_eq!(error.to_string(), "operand B of Add out of range: 512");
+        assert!(Decoded::LoadK { a: 0, bx: MAXARG_BX + 1 }.encode().is_err());
+        assert!(Decoded::ExtraArg { ax: MAXARG_AX }.encode().is_ok());
+    }
 }
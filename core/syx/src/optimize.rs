@@ -0,0 +1,514 @@
+// Optimization passes over loaded bytecode
+//
+// The compiler folds constant expressions as it goes, but a chunk from
+// another compiler, or from an older Lua (see format/), may not. The passes
+// here rewrite a Proto in place, along with the functions defined in it,
+// without changing what it does. A nested function that is shared (its Arc
+// has other owners) is left as it is.
+//
+// `fold_constants` works out arithmetic on operands known to be constant: K
+// operands, and registers set by a LOADK (or an earlier fold) in the same
+// straight run of code. The instruction becomes a LOADK of the result, and
+// the LOADKs it read from are left for later passes to remove.
+//
+// `dce` follows every path through the code from the first instruction and
+// drops the instructions none of them reach, such as those after a RETURN or
+// an unconditional JMP. Jumps, line info and the ranges of locals are moved
+// along with the code.
+//
+// `peephole` rewrites short sequences into tighter ones: a JMP to a JMP goes
+// straight to where the last one does, and a JMP to the next instruction is
+// dropped, as are a MOVE to itself, a MOVE overwritten by the next one and a
+// MOVE back of what was just moved. A GETTABUP of the same constant key right
+// after another becomes a MOVE; that takes reading a global twice in a row to
+// give the same value, which an __index on _ENV with side effects does not.
+// `peephole_listing` shows the code before and after it.
+//
+// Consult the versioned lcode.c (constfolding) for more information.
+
+use std::sync::Arc;
+
+use super::compiler::fold;
+use super::object::{AbsLineInfo, LocVar, Proto, SyxInt, SyxValue};
+use super::opcodes::{Instruction, OpCode};
+
+const MAXARG_BX: u32 = (1 << 18) - 1;
+const BITRK: u16 = 1 << 8; // see vm.rs
+
+// Instructions that can be reached other than from the one before them: jump
+// targets, and those a test or LOADBOOL skips to
+pub(crate) fn entries(proto: &Proto) -> Vec<bool> {
+    let code = &proto.instructions;
+    let mut entries = vec![false; code.len() + 1];
+    for (pc, instruction) in code.iter().enumerate() {
+        let target = match *instruction {
+            Instruction::AsBx { sbx, .. } => pc as i64 + 1 + sbx as i64,
+            Instruction::ABC { instruction: op, c, .. } => match op {
+                OpCode::Eq | OpCode::Lt | OpCode::Le | OpCode::Test | OpCode::TestSet => {
+                    pc as i64 + 2
+                }
+                OpCode::LoadBool if c != 0 => pc as i64 + 2,
+                _ => continue,
+            },
+            _ => continue,
+        };
+        if target >= 0 && (target as usize) < entries.len() {
+            entries[target as usize] = true;
+        }
+    }
+    entries.truncate(code.len());
+    entries
+}
+
+// Index of `value` in the constants, added if it is not there
+fn constant_index(proto: &mut Proto, value: SyxValue) -> usize {
+    let found = proto.constants.iter().position(|k| match (k, &value) {
+        (&SyxValue::Integer(a), &SyxValue::Integer(b)) => a == b,
+        (&SyxValue::Number(a), &SyxValue::Number(b)) => a.to_bits() == b.to_bits(),
+        _ => false,
+    });
+    found.unwrap_or_else(|| {
+        proto.constants.push(value);
+        proto.constants.len() - 1
+    })
+}
+
+fn is_arith(op: OpCode) -> bool {
+    matches!(op, OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::Mod | OpCode::Pow |
+                 OpCode::Div | OpCode::IDiv | OpCode::BAnd | OpCode::BOr | OpCode::BXOr |
+                 OpCode::Shl | OpCode::Shr | OpCode::Unm | OpCode::BNot)
+}
+
+// Fold constant arithmetic in `proto` and the functions defined in it,
+// returning how many instructions were folded
+pub fn fold_constants(proto: &mut Proto) -> usize {
+    let entries = entries(proto);
+    // constant index held by each register, as far as is known
+    let mut known: Vec<Option<usize>> = vec![None; 256];
+    let mut folded = 0;
+    for (pc, &entry) in entries.iter().enumerate() {
+        if entry {
+            known.iter_mut().for_each(|k| *k = None);
+        }
+        let (op, a, b, c) = match proto.instructions[pc] {
+            Instruction::ABx { instruction: OpCode::LoadK, a, bx } => {
+                known[a as usize] = Some(bx as usize);
+                continue;
+            }
+            Instruction::ABC { instruction: op, a, b, c } if is_arith(op) => (op, a, b, c),
+            _ => {
+                known.iter_mut().for_each(|k| *k = None);
+                continue;
+            }
+        };
+        let operand = |x: u16, rk: bool| -> Option<SyxValue> {
+            let index = if rk && x & BITRK != 0 {
+                (x & !BITRK) as usize
+            } else {
+                known[x as usize]?
+            };
+            match proto.constants.get(index) {
+                Some(value @ &SyxValue::Integer(_)) | Some(value @ &SyxValue::Number(_)) => {
+                    Some(value.clone())
+                }
+                _ => None,
+            }
+        };
+        let unary = op == OpCode::Unm || op == OpCode::BNot;
+        let lhs = operand(b, !unary);
+        let rhs = if unary { lhs.clone() } else { operand(c, true) };
+        let result = match (lhs, rhs) {
+            (Some(lhs), Some(rhs)) => fold(op, &lhs, &rhs),
+            _ => None,
+        };
+        known[a as usize] = None;
+        if let Some(result) = result {
+            let index = constant_index(proto, result);
+            if index as u32 <= MAXARG_BX {
+                proto.instructions[pc] =
+                    Instruction::ABx { instruction: OpCode::LoadK, a, bx: index as u32 };
+                known[a as usize] = Some(index);
+                folded += 1;
+            }
+        }
+    }
+    for child in &mut proto.protos {
+        if let Some(child) = Arc::get_mut(child) {
+            folded += fold_constants(child);
+        }
+    }
+    folded
+}
+
+// Instructions control can go to after instruction `pc`
+fn successors(code: &[Instruction], pc: usize) -> Vec<usize> {
+    let next = pc + 1;
+    match code[pc] {
+        Instruction::AsBx { instruction: op, sbx, .. } => {
+            let target = (pc as i64 + 1 + sbx as i64) as usize;
+            match op {
+                OpCode::Jmp => vec![target],
+                // the body, or past the ForLoop when the loop doesn't run,
+                // keeping the ForLoop it jumps by
+                OpCode::ForPrep => vec![next, target, target + 1],
+                _ => vec![next, target],
+            }
+        }
+        Instruction::ABC { instruction: op, c, .. } => match op {
+            OpCode::Return => vec![],
+            OpCode::Eq | OpCode::Lt | OpCode::Le | OpCode::Test | OpCode::TestSet => {
+                vec![next, pc + 2]
+            }
+            // the instruction skipped over stays, or the skip would land on
+            // the wrong one
+            OpCode::LoadBool if c != 0 => vec![next, pc + 2],
+            _ => vec![next],
+        },
+        _ => vec![next],
+    }
+}
+
+// Drop the instructions not to `keep`, moving jumps, line info and locals
+// along with the others
+fn compact(proto: &mut Proto, keep: &[bool]) {
+    // where each instruction, or the first kept one after it, ends up
+    let mut start = Vec::with_capacity(keep.len() + 1);
+    let mut kept = 0;
+    for &k in keep {
+        start.push(kept);
+        if k {
+            kept += 1;
+        }
+    }
+    start.push(kept);
+    let moved = |pc: SyxInt| start.get(pc as usize).map_or(pc, |&to| to as SyxInt);
+
+    let code = ::std::mem::take(&mut proto.instructions);
+    let lineinfo = ::std::mem::take(&mut proto.lineinfo);
+    let columninfo = ::std::mem::take(&mut proto.columninfo);
+    for (pc, mut instruction) in code.into_iter().enumerate() {
+        if !keep[pc] {
+            continue;
+        }
+        if let Instruction::AsBx { ref mut sbx, .. } = instruction {
+            let target = (pc as i64 + 1 + *sbx as i64) as usize;
+            let target = start.get(target).cloned().unwrap_or(target);
+            *sbx = (target as i64 - (start[pc] as i64 + 1)) as i32;
+        }
+        proto.instructions.push(instruction);
+        proto.lineinfo.extend(lineinfo.get(pc));
+        proto.columninfo.extend(columninfo.get(pc));
+    }
+    proto.abslineinfo = proto.abslineinfo.iter()
+        .map(|abs| AbsLineInfo { pc: moved(abs.pc), line: abs.line })
+        .collect();
+    proto.locvars = proto.locvars.iter()
+        .map(|local| LocVar {
+            varname: local.varname.clone(),
+            startpc: moved(local.startpc),
+            endpc: moved(local.endpc),
+        })
+        .collect();
+}
+
+// whether the instruction at `pc` is skipped over by the one before it, and
+// so can not be removed
+fn skipped(code: &[Instruction], pc: usize) -> bool {
+    match pc.checked_sub(1).map(|pc| &code[pc]) {
+        Some(&Instruction::ABC { instruction: op, c, .. }) => match op {
+            OpCode::Eq | OpCode::Lt | OpCode::Le | OpCode::Test | OpCode::TestSet => true,
+            OpCode::LoadBool => c != 0,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+fn jump(code: &[Instruction], pc: usize) -> Option<(u8, usize)> {
+    match code.get(pc) {
+        Some(&Instruction::AsBx { instruction: OpCode::Jmp, a, sbx }) => {
+            Some((a, (pc as i64 + 1 + sbx as i64) as usize))
+        }
+        _ => None,
+    }
+}
+
+// Rewrite sequences in `proto`, and in the functions defined in it, into
+// tighter ones, returning how many instructions were rewritten or removed
+pub fn peephole(proto: &mut Proto) -> usize {
+    let entries = entries(proto);
+    let code = &mut proto.instructions;
+    let mut rewritten = 0;
+
+    // jumps to jumps (that close no upvalues) go to the end of the chain
+    for pc in 0..code.len() {
+        let (_, mut target) = match jump(code, pc) {
+            Some(jump) => jump,
+            None => continue,
+        };
+        let mut steps = 0;
+        while let Some((0, next)) = jump(code, target) {
+            if next == target || steps == code.len() {
+                break;
+            }
+            target = next;
+            steps += 1;
+        }
+        if steps > 0 {
+            if let Instruction::AsBx { ref mut sbx, .. } = code[pc] {
+                *sbx = (target as i64 - (pc as i64 + 1)) as i32;
+            }
+            rewritten += 1;
+        }
+    }
+
+    // the same global read twice
+    for pc in 1..code.len() {
+        if entries[pc] {
+            continue;
+        }
+        if let (Instruction::ABC { instruction: OpCode::GetTabUp, a: first, b, c },
+                Instruction::ABC { instruction: OpCode::GetTabUp, a, b: b2, c: c2 }) =
+            (code[pc - 1].clone(), code[pc].clone())
+        {
+            if b == b2 && c == c2 && c & BITRK != 0 && !skipped(code, pc - 1) {
+                code[pc] = Instruction::ABC { instruction: OpCode::Move, a, b: first as u16, c: 0 };
+                rewritten += 1;
+            }
+        }
+    }
+
+    let mut keep = vec![true; code.len()];
+    for pc in 0..code.len() {
+        if skipped(code, pc) {
+            continue;
+        }
+        let redundant = match code[pc] {
+            Instruction::AsBx { instruction: OpCode::Jmp, a: 0, sbx: 0 } => true,
+            Instruction::ABC { instruction: OpCode::Move, a, b, .. } => {
+                let next = match code.get(pc + 1) {
+                    Some(next) if !entries[pc + 1] => Some(next),
+                    _ => None,
+                };
+                let previous = match pc.checked_sub(1) {
+                    Some(previous) if !entries[pc] && keep[previous] => code.get(previous),
+                    _ => None,
+                };
+                u16::from(a) == b ||
+                    // overwritten straight away
+                    matches!(next, Some(&Instruction::ABC { instruction: OpCode::Move, a: a2,
+                                                            b: b2, .. })
+                             if a2 == a && b2 != u16::from(a)) ||
+                    // moved back
+                    matches!(previous, Some(&Instruction::ABC { instruction: OpCode::Move,
+                                                                a: a2, b: b2, .. })
+                             if u16::from(a2) == b && b2 == u16::from(a))
+            }
+            _ => false,
+        };
+        if redundant {
+            keep[pc] = false;
+            rewritten += 1;
+        }
+    }
+    if keep.contains(&false) {
+        compact(proto, &keep);
+    }
+
+    for child in &mut proto.protos {
+        if let Some(child) = Arc::get_mut(child) {
+            rewritten += peephole(child);
+        }
+    }
+    rewritten
+}
+
+// `proto` before and after `peephole`, to check what it did
+pub fn peephole_listing(proto: &mut Proto) -> String {
+    let before = proto.pretty();
+    let rewritten = peephole(proto);
+    format!("before:\n{}after ({} rewritten):\n{}", before, rewritten, proto.pretty())
+}
+
+// Remove the instructions of `proto`, and of the functions defined in it,
+// that can never run, returning how many were removed
+pub fn dce(proto: &mut Proto) -> usize {
+    let code = &proto.instructions;
+    let mut reached = vec![false; code.len()];
+    let mut pending = vec![0];
+    while let Some(pc) = pending.pop() {
+        if pc >= code.len() || reached[pc] {
+            continue;
+        }
+        reached[pc] = true;
+        // an EXTRAARG is read by the instruction before it
+        if let Some(&Instruction::Ax { instruction: OpCode::ExtraArg, .. }) = code.get(pc + 1) {
+            reached[pc + 1] = true;
+        }
+        pending.extend(successors(code, pc));
+    }
+    let mut removed = reached.iter().filter(|&&r| !r).count();
+    if removed > 0 {
+        compact(proto, &reached);
+    }
+    for child in &mut proto.protos {
+        if let Some(child) = Arc::get_mut(child) {
+            removed += dce(child);
+        }
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::compiler::parse;
+    use super::super::state::SyxState;
+    use super::super::stdlib;
+    use super::super::testing::abc;
+
+    fn loadk(a: u8, bx: u32) -> Instruction {
+        Instruction::ABx { instruction: OpCode::LoadK, a, bx }
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        // what another compiler could emit for `local x = 2 local y = (x + 3) * 1.5 - -x`
+        // with `f(y // 0)` after
+        let mut proto = Proto::new();
+        proto.maxstacksize = 4;
+        proto.constants = vec![SyxValue::Integer(2), SyxValue::Integer(3),
+                               SyxValue::Number(1.5), SyxValue::Integer(0)];
+        proto.instructions = vec![
+            loadk(0, 0),
+            abc(OpCode::Add, 1, 0, BITRK | 1),
+            abc(OpCode::Mul, 1, 1, BITRK | 2),
+            abc(OpCode::Unm, 2, 0, 0),
+            abc(OpCode::Sub, 1, 1, 2),
+            abc(OpCode::IDiv, 2, 1, BITRK | 3),
+            abc(OpCode::Return, 1, 3, 0),
+        ];
+        proto.lineinfo = vec![1; 7];
+        assert_eq!(fold_constants(&mut proto), 4);
+        // 5, 7.5, -2 and 9.5 added; the division by zero is left to raise
+        assert!(proto.constants[4..] == [SyxValue::Integer(5), SyxValue::Number(7.5),
+                                         SyxValue::Integer(-2), SyxValue::Number(9.5)]);
+        assert_eq!(proto.instructions[1..5], [loadk(1, 4), loadk(1, 5), loadk(2, 6),
+                                              loadk(1, 7)]);
+        assert_eq!(proto.instructions[5], abc(OpCode::IDiv, 2, 1, BITRK | 3));
+        proto.verify().unwrap();
+    }
+
+    #[test]
+    fn test_fold_stops_at_jumps() {
+        // the loop body adds to what the last iteration left in r0
+        let source = "local n = 1 for i = 1, 3 do n = n + 1 end return n";
+        let mut proto = parse(source.as_bytes(), "=test").unwrap();
+        let before = proto.instructions.clone();
+        assert_eq!(fold_constants(&mut proto), 0);
+        assert_eq!(proto.instructions, before);
+
+        let mut state = SyxState::new();
+        stdlib::open_libs(&mut state);
+        let results = state.call(Arc::new(proto), Vec::new()).unwrap().into_vec();
+        assert!(results == vec![SyxValue::Integer(4)]);
+    }
+
+    #[test]
+    fn test_dce() {
+        let source = "local function f(x) \
+                          if x then return 1 else return 2 end \
+                          return 3 \
+                      end \
+                      local t = {} \
+                      for i = 1, 3 do t[i] = f(i > 1) end \
+                      do return t[1] + t[2] end \
+                      print('unreachable')";
+        let original = parse(source.as_bytes(), "=test").unwrap();
+        let mut proto = parse(source.as_bytes(), "=test").unwrap();
+        // the print call and the RETURN after it, and in f the JMP past the
+        // else branch, `return 3` and the RETURN after it
+        assert_eq!(dce(&mut proto), 8);
+        assert_eq!(proto.instructions.len() + 4, original.instructions.len());
+        assert_eq!(proto.instructions.last(), original.instructions.get(17));
+        assert_eq!(proto.protos[0].instructions.len(), 6);
+        assert_eq!(proto.lineinfo.len(), proto.instructions.len());
+        for local in &proto.locvars {
+            assert!(local.endpc as usize <= proto.instructions.len());
+        }
+        proto.verify().unwrap();
+        assert_eq!(dce(&mut proto), 0);
+
+        let mut state = SyxState::new();
+        stdlib::open_libs(&mut state);
+        let results = state.call(Arc::new(proto), Vec::new()).unwrap().into_vec();
+        assert!(results == vec![SyxValue::Integer(3)]);
+    }
+
+    #[test]
+    fn test_dce_keeps_for_loop() {
+        // the FORLOOP is never reached, but a loop that doesn't run skips past it
+        let source = "local n = 0 for i = 1, 0 do break end return n";
+        let mut proto = parse(source.as_bytes(), "=test").unwrap();
+        dce(&mut proto);
+        proto.verify().unwrap();
+        assert!(proto.instructions.iter().any(|i| matches!(*i,
+            Instruction::AsBx { instruction: OpCode::ForLoop, .. })));
+
+        let mut state = SyxState::new();
+        stdlib::open_libs(&mut state);
+        let results = state.call(Arc::new(proto), Vec::new()).unwrap().into_vec();
+        assert!(results == vec![SyxValue::Integer(0)]);
+    }
+
+    #[test]
+    fn test_peephole() {
+        use super::super::object::Upvalue;
+        use super::super::string::SyxString;
+
+        let jmp = |sbx| Instruction::AsBx { instruction: OpCode::Jmp, a: 0, sbx };
+        let mut proto = Proto::new();
+        proto.maxstacksize = 4;
+        proto.constants = vec![SyxValue::from("x"), SyxValue::Integer(1)];
+        proto.upvalues = vec![Upvalue { name: SyxString::from("_ENV"), instack: 1, idx: 0 }];
+        proto.instructions = vec![
+            abc(OpCode::GetTabUp, 0, 0, BITRK),
+            abc(OpCode::GetTabUp, 1, 0, BITRK), // MOVE 1 0
+            abc(OpCode::Move, 2, 2, 0),         // removed
+            abc(OpCode::Move, 3, 0, 0),         // removed, overwritten
+            abc(OpCode::Move, 3, 1, 0),
+            abc(OpCode::Move, 1, 3, 0),         // removed, moved back
+            jmp(1),                             // to 11
+            loadk(3, 1),
+            jmp(1),                             // to 11
+            loadk(3, 1),
+            jmp(0),                             // removed
+            abc(OpCode::Add, 0, 0, 1),
+            abc(OpCode::Return, 0, 2, 0),
+        ];
+        proto.lineinfo = (1..14).collect();
+        let listing = peephole_listing(&mut proto);
+        assert!(listing.starts_with("before:\nmain <:0,0>\n"));
+        assert!(listing.contains("after (7 rewritten):\n"));
+        assert_eq!(proto.instructions, vec![
+            abc(OpCode::GetTabUp, 0, 0, BITRK),
+            abc(OpCode::Move, 1, 0, 0),
+            abc(OpCode::Move, 3, 1, 0),
+            jmp(3),
+            loadk(3, 1),
+            jmp(1),
+            loadk(3, 1),
+            abc(OpCode::Add, 0, 0, 1),
+            abc(OpCode::Return, 0, 2, 0),
+        ]);
+        assert_eq!(proto.lineinfo, vec![1, 2, 5, 7, 8, 9, 10, 12, 13]);
+        proto.verify().unwrap();
+        assert_eq!(peephole(&mut proto), 0);
+
+        let mut state = SyxState::new();
+        let globals = state.globals();
+        stdlib::set_field(&mut state, globals, "x", SyxValue::Integer(21));
+        let results = state.call(Arc::new(proto), Vec::new()).unwrap().into_vec();
+        assert!(results == vec![SyxValue::Integer(42)]);
+    }
+}
@@ -0,0 +1,266 @@
+// Preemption points
+//
+// A count hook can stop a script that runs too long, but only by raising an
+// error, and it costs a check on every instruction. `Proto::with_checks`
+// copies a function, and every function defined in it, with a CHECK at the
+// head of every loop (any instruction a jump goes back to) and before every
+// call, which are the places a script can keep running from without end.
+// Jumps to those instructions land on their CHECK instead.
+//
+// A CHECK does nothing until the host asks for preemption, through
+// `SyxState::preempt` or a `PreemptHandle` from any other thread. The next
+// CHECK to run clears the request and yields the running coroutine with no
+// values, so it can be resumed later as if nothing happened; a preempted task
+// (see task.rs) is resumed on the next poll. Where nothing can yield, on the
+// main thread or in a function called from Rust, it raises "interrupted".
+//
+// An instruction that is skipped over or read by the one before it (the JMP
+// of a test, an EXTRAARG) gets no CHECK, as that would come between them.
+//
+// Lua has nothing like it; lua_sethook with LUA_MASKCOUNT comes closest.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use super::errors::*;
+use super::object::{AbsLineInfo, LocVar, Proto, SyxInt, Upvalue};
+use super::opcodes::{Instruction, OpCode};
+use super::state::SyxState;
+use super::vm::runtime_error;
+
+const MAXARG_SBX: i32 = (1 << 17) - 1;
+
+// Requests preemption of a state from any thread
+#[derive(Clone)]
+pub struct PreemptHandle(Arc<AtomicBool>);
+
+impl PreemptHandle {
+    pub fn preempt(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+impl SyxState {
+    // Stop the script at its next CHECK
+    pub fn preempt(&mut self) {
+        self.preempt.store(true, Ordering::Relaxed);
+    }
+
+    pub fn preempt_handle(&self) -> PreemptHandle {
+        PreemptHandle(self.preempt.clone())
+    }
+
+    // A CHECK is running: true to yield there
+    pub(crate) fn check_preempt(&mut self) -> Result<bool> {
+        if !self.preempt.swap(false, Ordering::Relaxed) {
+            Ok(false)
+        } else if self.current.is_some() && self.nny == 0 {
+            Ok(true)
+        } else {
+            runtime_error("interrupted".to_owned())
+        }
+    }
+}
+
+fn opcode(i: &Instruction) -> OpCode {
+    match *i {
+        Instruction::ABC { instruction, .. } | Instruction::ABx { instruction, .. } |
+        Instruction::AsBx { instruction, .. } | Instruction::Ax { instruction, .. } => instruction,
+    }
+}
+
+// whether the instruction after `i` is skipped by it or read along with it
+fn has_companion(i: &Instruction) -> bool {
+    match *i {
+        Instruction::ABC { instruction: op, c, .. } => match op {
+            OpCode::Eq | OpCode::Lt | OpCode::Le | OpCode::Test | OpCode::TestSet |
+            OpCode::LoadKX | OpCode::TForCall | OpCode::Check => true,
+            OpCode::LoadBool => c != 0,
+            OpCode::SetList => c == 0,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+fn jump_target(pc: usize, i: &Instruction) -> Option<usize> {
+    match *i {
+        Instruction::AsBx { sbx, .. } => Some((pc as i64 + 1 + sbx as i64) as usize),
+        _ => None,
+    }
+}
+
+impl Proto {
+    // Copy of this function and all the functions defined in it with CHECK
+    // instructions added. Functions that have them already get no more.
+    pub fn with_checks(&self) -> Result<Proto> {
+        let code = &self.instructions;
+        let mut checked = vec![false; code.len()];
+        for (pc, instruction) in code.iter().enumerate() {
+            match jump_target(pc, instruction) {
+                Some(target) if target <= pc && target < code.len() => checked[target] = true,
+                _ => {}
+            }
+            if matches!(opcode(instruction), OpCode::Call | OpCode::TailCall) {
+                checked[pc] = true;
+            }
+        }
+        for pc in 0..code.len() {
+            if opcode(&code[pc]) == OpCode::Check || pc > 0 && has_companion(&code[pc - 1]) {
+                checked[pc] = false;
+            }
+        }
+
+        // where each instruction, or the CHECK before it, ends up
+        let mut start = Vec::with_capacity(code.len() + 1);
+        let mut added = 0;
+        for (pc, &check) in checked.iter().enumerate() {
+            start.push(pc + added);
+            if check {
+                added += 1;
+            }
+        }
+        start.push(code.len() + added);
+        let moved = |pc: SyxInt| start.get(pc as usize).map_or(pc, |&to| to as SyxInt);
+
+        let check = || Instruction::ABC { instruction: OpCode::Check, a: 0, b: 0, c: 0 };
+        let mut instructions = Vec::with_capacity(code.len() + added);
+        let mut lineinfo = Vec::with_capacity(self.lineinfo.len() + added);
+        let mut columninfo = Vec::with_capacity(self.columninfo.len() + added);
+        for (pc, instruction) in code.iter().enumerate() {
+            let line = self.lineinfo.get(pc).cloned();
+            let column = self.columninfo.get(pc).cloned();
+            if checked[pc] {
+                instructions.push(check());
+                lineinfo.extend(line);
+                columninfo.extend(column);
+            }
+            let mut instruction = instruction.clone();
+            if let Some(target) = jump_target(pc, &instruction) {
+                let target = start.get(target).cloned().unwrap_or(target);
+                let offset = target as i64 - (instructions.len() as i64 + 1);
+                if offset.abs() > MAXARG_SBX as i64 {
+                    return Err(ErrorKind::InvalidFunction("control structure too long").into());
+                }
+                if let Instruction::AsBx { ref mut sbx, .. } = instruction {
+                    *sbx = offset as i32;
+                }
+            }
+            instructions.push(instruction);
+            lineinfo.extend(line);
+            columninfo.extend(column);
+        }
+
+        let mut protos = Vec::with_capacity(self.protos.len());
+        for child in &self.protos {
+            protos.push(Arc::new(child.with_checks()?));
+        }
+        Ok(Proto {
+            numparams: self.numparams,
+            is_vararg: self.is_vararg,
+            maxstacksize: self.maxstacksize,
+            linedefined: self.linedefined,
+            lastlinedefined: self.lastlinedefined,
+            constants: self.constants.clone(),
+            ip: self.ip,
+            instructions,
+            protos,
+            lineinfo,
+            abslineinfo: self.abslineinfo.iter()
+                .map(|abs| AbsLineInfo { pc: moved(abs.pc), line: abs.line })
+                .collect(),
+            columninfo,
+            upvalues: self.upvalues.iter()
+                .map(|upvalue| Upvalue {
+                    name: upvalue.name.clone(),
+                    instack: upvalue.instack,
+                    idx: upvalue.idx,
+                })
+                .collect(),
+            locvars: self.locvars.iter()
+                .map(|local| LocVar {
+                    varname: local.varname.clone(),
+                    startpc: moved(local.startpc),
+                    endpc: moved(local.endpc),
+                })
+                .collect(),
+            source: self.source.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use super::super::compiler::parse;
+    use super::super::coroutine::Resumed;
+    use super::super::object::{MultiValue, SyxValue};
+    use super::super::stdlib;
+
+    fn checked(source: &str) -> Arc<Proto> {
+        let proto = parse(source.as_bytes(), "=test").unwrap().with_checks().unwrap();
+        proto.verify().unwrap();
+        Arc::new(proto)
+    }
+
+    fn count_checks(proto: &Proto) -> usize {
+        proto.instructions.iter().filter(|i| opcode(i) == OpCode::Check).count()
+    }
+
+    #[test]
+    fn test_insert_checks() {
+        let source = "local t, n = {}, 0 \
+                      for i = 1, 10 do t[i] = i end \
+                      for _, v in ipairs(t) do n = n + v end \
+                      while n > 1 do n = n // 2 end \
+                      repeat n = n + 1 until n >= 3 \
+                      local function f(x) if x < 3 and x > 0 then return f(x + 1) end return x end \
+                      return n, f(1), #t";
+        let proto = checked(source);
+        // four loops, and calls to ipairs and f
+        assert_eq!(count_checks(&proto), 6);
+        // and the recursive tail call
+        assert_eq!(count_checks(&proto.protos[0]), 1);
+        assert_eq!(proto.lineinfo.len(), proto.instructions.len());
+        // nothing more the second time
+        let again = proto.with_checks().unwrap();
+        assert_eq!(again.instructions, proto.instructions);
+
+        let mut state = SyxState::new();
+        stdlib::open_libs(&mut state);
+        let original = Arc::new(parse(source.as_bytes(), "=test").unwrap());
+        let expected = state.call(original, Vec::new()).unwrap().into_vec();
+        let results = state.call(proto, Vec::new()).unwrap().into_vec();
+        assert!(results == expected);
+        assert!(results == vec![SyxValue::Integer(3), SyxValue::Integer(3),
+                                SyxValue::Integer(10)]);
+    }
+
+    #[test]
+    fn test_preempt() {
+        let mut state = SyxState::new();
+        stdlib::open_libs(&mut state);
+        let proto = checked("local n = 0 while true do n = n + 1 if n == 5 then return n end end");
+        let body = SyxValue::Function(state.new_main_closure(proto.clone()));
+        let thread = state.new_thread(body);
+        state.preempt();
+        // yields at the first loop iteration and carries on where it was
+        assert!(state.resume(thread, Vec::new()).unwrap() == Resumed::Yield(MultiValue::new()));
+        assert!(state.resume(thread, Vec::new()).unwrap() ==
+                Resumed::Return(vec![SyxValue::Integer(5)].into()));
+
+        // stopped from another thread, and on the main thread by an error
+        let handle = state.preempt_handle();
+        let stopper = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(5));
+            handle.preempt();
+        });
+        let error = state.call(checked("while true do end"), Vec::new()).unwrap_err();
+        assert_eq!(error.to_string(), "test:1: interrupted");
+        stopper.join().unwrap();
+        state.call(proto, Vec::new()).unwrap();
+    }
+}
@@ -0,0 +1,221 @@
+// Protected calls and error values
+//
+// Errors unwind as `Err` through the interpreter and any Rust code in
+// between, and a protected call is only where one stops. The frames of the
+// failed call are still on the stack at that point, so the message handler
+// of `xpcall` can look at them (for a traceback, say) before they are
+// dropped and the stack is back to what it was before the call.
+//
+// Errors raised by scripts carry any value (ErrorKind::LuaError). Others,
+// runtime errors and whatever a native function failed with, are seen by
+// scripts as their message.
+//
+// Consult the versioned ldo.c and lbaselib.c for more information.
+
+use super::errors::*;
+use super::object::{float_to_integer, MultiValue, SyxInteger, SyxValue};
+use super::state::SyxState;
+use super::vm::{append_string, bad_argument};
+
+// message of an error raised with `value`
+pub fn error_message(value: &SyxValue) -> String {
+    let mut buffer = Vec::new();
+    if append_string(&mut buffer, value) {
+        String::from_utf8_lossy(&buffer).into_owned()
+    } else {
+        format!("(error object is a {} value)", value.type_name())
+    }
+}
+
+impl SyxState {
+    // the value a script catching `error` sees
+    pub fn error_value(&mut self, error: &Error) -> SyxValue {
+        match *error.kind() {
+            ErrorKind::LuaError(ref value) => value.clone(),
+            _ => SyxValue::String(self.intern(error.to_string().as_bytes())),
+        }
+    }
+
+    // Call `func` with `args`. If it fails, everything it left on the stack
+    // is dropped and the error is returned.
+    pub fn pcall(&mut self, func: SyxValue, args: Vec<SyxValue>) -> Result<MultiValue> {
+        self.protected_call(func, args, None)
+    }
+
+    // As `pcall`, but an error value is first passed to `handler`, which
+    // runs before the stack is unwound; the error is then what it returns.
+    pub fn xpcall(&mut self, func: SyxValue, args: Vec<SyxValue>, handler: SyxValue)
+        -> Result<MultiValue>
+    {
+        self.protected_call(func, args, Some(handler))
+    }
+}
+
+// results of a protected call as pcall returns them
+fn status(state: &mut SyxState, results: Result<MultiValue>) -> Vec<SyxValue> {
+    match results {
+        Ok(values) => {
+            let mut results = vec![SyxValue::Bool(true)];
+            results.extend(values);
+            results
+        }
+        Err(error) => vec![SyxValue::Bool(false), state.error_value(&error)],
+    }
+}
+
+// pcall(f, ...)
+pub fn pcall(state: &mut SyxState, mut args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    if args.is_empty() {
+        return bad_argument(1, "pcall", "value expected");
+    }
+    let func = args.remove(0);
+    let results = state.pcall(func, args);
+    Ok(status(state, results))
+}
+
+// xpcall(f, msgh, ...)
+pub fn xpcall(state: &mut SyxState, mut args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    if args.len() < 2 {
+        return bad_argument(2, "xpcall", "value expected");
+    }
+    let func = args.remove(0);
+    let handler = args.remove(0);
+    let results = state.xpcall(func, args, handler);
+    Ok(status(state, results))
+}
+
+// error(message [, level])
+pub fn error(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let mut args = args.into_iter();
+    let value = args.next().unwrap_or(SyxValue::Nil);
+    let level = match args.next() {
+        None | Some(SyxValue::Nil) => 1,
+        Some(SyxValue::Integer(level)) => level,
+        Some(SyxValue::Number(n)) => match float_to_integer(n) {
+            Some(level) => level,
+            None => return bad_argument(2, "error", "number has no integer representation"),
+        },
+        Some(_) => return bad_argument(2, "error", "number expected"),
+    };
+    // level 1 is the function that called error
+    let value = match value {
+        SyxValue::String(ref message) if level > 0 => {
+            match state.location_at(level as usize) {
+                Some(location) => {
+                    let message = format!("{} {}", location, message);
+                    SyxValue::String(state.intern(message.as_bytes()))
+                }
+                None => value.clone(),
+            }
+        }
+        value => value,
+    };
+    bail!(ErrorKind::LuaError(value))
+}
+
+// debug.traceback([message [, level]]), for message handlers
+pub fn traceback(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let mut args = args.into_iter();
+    let message = match args.next() {
+        None | Some(SyxValue::Nil) => None,
+        Some(SyxValue::String(message)) => Some(message.to_string()),
+        // anything else is returned untouched
+        Some(value) => return Ok(vec![value]),
+    };
+    let level = match args.next() {
+        Some(SyxValue::Integer(level)) => level,
+        Some(SyxValue::Number(n)) => float_to_integer(n).unwrap_or(1),
+        _ => 1 as SyxInteger,
+    };
+    let traceback = state.traceback(message.as_deref(),
+                                    level.max(1) as usize - 1);
+    Ok(vec![SyxValue::String(state.intern(traceback.as_bytes()))])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use super::super::object::{LocVar, Proto};
+    use super::super::opcodes::OpCode;
+    use super::super::testing::{abc, abx};
+
+    // local function f() error("boom") end   -- lines 1-3
+    // f()                                    -- line 5
+    fn chunk() -> Proto {
+        let mut f = Proto::new();
+        f.source = "@test.lua".to_owned();
+        f.linedefined = 1;
+        f.maxstacksize = 2;
+        f.constants = vec![SyxValue::Native(error), SyxValue::from("boom")];
+        f.instructions = vec![
+            abx(OpCode::LoadK, 0, 0),
+            abx(OpCode::LoadK, 1, 1),
+            abc(OpCode::Call, 0, 2, 1),
+            abc(OpCode::Return, 0, 1, 0),
+        ];
+        f.lineinfo = vec![2, 2, 2, 3];
+        let mut main = Proto::new();
+        main.source = "@test.lua".to_owned();
+        main.maxstacksize = 2;
+        main.protos = vec![Arc::new(f)];
+        main.instructions = vec![
+            abx(OpCode::Closure, 0, 0),
+            abc(OpCode::Move, 1, 0, 0),
+            abc(OpCode::Call, 1, 1, 1),
+            abc(OpCode::Return, 0, 1, 0),
+        ];
+        main.lineinfo = vec![3, 5, 5, 5];
+        main.locvars = vec![LocVar { varname: "f".into(), startpc: 1, endpc: 4 }];
+        main
+    }
+
+    #[test]
+    fn test_pcall() {
+        let mut state = SyxState::new();
+        let main = state.new_main_closure(Arc::new(chunk()));
+        let results = pcall(&mut state, vec![SyxValue::Function(main)]).unwrap();
+        assert_eq!(results, vec![SyxValue::Bool(false), SyxValue::from("test.lua:2: boom")]);
+        assert!(state.stack.is_empty() && state.frames.is_empty());
+
+        // any value can be raised, and runtime errors are seen as strings
+        let table = SyxValue::Table(state.new_table(0, 0));
+        let results = pcall(&mut state, vec![
+            SyxValue::Native(error), table.clone(),
+        ]).unwrap();
+        assert_eq!(results, vec![SyxValue::Bool(false), table]);
+        let results = pcall(&mut state, vec![SyxValue::Integer(1)]).unwrap();
+        assert_eq!(results, vec![
+            SyxValue::Bool(false), SyxValue::from("attempt to call a number value"),
+        ]);
+        let results = pcall(&mut state, vec![
+            SyxValue::Native(pcall), SyxValue::Integer(1),
+        ]).unwrap();
+        assert_eq!(results[0], SyxValue::Bool(true));
+    }
+
+    #[test]
+    fn test_xpcall_traceback() {
+        let mut state = SyxState::new();
+        let main = state.new_main_closure(Arc::new(chunk()));
+        let results = xpcall(&mut state, vec![
+            SyxValue::Function(main), SyxValue::Native(traceback),
+        ]).unwrap();
+        assert_eq!(results, vec![
+            SyxValue::Bool(false),
+            SyxValue::from("test.lua:2: boom\n\
+                            stack traceback:\n\
+                            \ttest.lua:2: in local 'f'\n\
+                            \ttest.lua:5: in main chunk"),
+        ]);
+        assert!(state.frames.is_empty());
+
+        let results = xpcall(&mut state, vec![
+            SyxValue::Function(main), SyxValue::Native(error),
+        ]).unwrap();
+        assert_eq!(results, vec![
+            SyxValue::Bool(false), SyxValue::from("error in error handling"),
+        ]);
+    }
+}
@@ -0,0 +1,109 @@
+// Registry
+//
+// A table private to the host: no script can reach it, and everything in it
+// is kept alive by the collector, so it is where an embedder stashes values
+// between calls into the state. Fields can be stored under names of the
+// host's choosing (prefixed, say, with the name of the library storing them),
+// or under integer keys handed out by `create_ref`.
+//
+// A RegistryKey is a strong reference: the value it refers to is not
+// collected until it is given back to `remove_ref`, which frees the key for
+// reuse. Keys are only meaningful to the state that created them.
+//
+// Consult the versioned lauxlib.c (luaL_ref, luaL_unref) for more
+// information.
+
+use super::object::{SyxInteger, SyxValue, TableRef};
+use super::state::SyxState;
+
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub struct RegistryKey(SyxInteger);
+
+impl RegistryKey {
+    // integer key of the value in the registry
+    pub fn id(&self) -> SyxInteger {
+        self.0
+    }
+}
+
+impl SyxState {
+    pub fn registry(&self) -> TableRef {
+        self.registry
+    }
+
+    // Store `value` in the registry until the key is removed
+    pub fn create_ref(&mut self, value: SyxValue) -> RegistryKey {
+        let key = match self.free_refs.pop() {
+            Some(key) => key,
+            None => {
+                self.last_ref += 1;
+                self.last_ref
+            }
+        };
+        let registry = self.registry;
+        self.table_set(registry, SyxValue::Integer(key), value)
+            .expect("integer keys are always valid");
+        RegistryKey(key)
+    }
+
+    pub fn get_ref(&self, key: &RegistryKey) -> SyxValue {
+        self.table(self.registry).get_int(key.0)
+    }
+
+    // Replace the value a key refers to
+    pub fn set_ref(&mut self, key: &RegistryKey, value: SyxValue) {
+        let registry = self.registry;
+        self.table_mut(registry).set_int(key.0, value);
+    }
+
+    // Release the value, returning it
+    pub fn remove_ref(&mut self, key: RegistryKey) -> SyxValue {
+        let value = self.get_ref(&key);
+        let registry = self.registry;
+        self.table_mut(registry).set_int(key.0, SyxValue::Nil);
+        self.free_refs.push(key.0);
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::stdlib::set_field;
+
+    #[test]
+    fn test_refs() {
+        let mut state = SyxState::new();
+        let table = state.new_table(0, 0);
+        set_field(&mut state, table, "x", SyxValue::Integer(1));
+        let key = state.create_ref(SyxValue::Table(table));
+        let nil = state.create_ref(SyxValue::Nil);
+        assert!(key != nil);
+        // only the registry refers to the table
+        state.collect_garbage();
+        assert_eq!(state.get_ref(&key), SyxValue::Table(table));
+        assert_eq!(state.table(table).get(&SyxValue::from("x")), SyxValue::Integer(1));
+        assert!(state.get_ref(&nil).is_nil());
+
+        let objects = state.gc_objects();
+        assert_eq!(state.remove_ref(key), SyxValue::Table(table));
+        state.collect_garbage();
+        assert!(state.gc_objects() < objects);
+        // the key is reused
+        let reused = state.create_ref(SyxValue::Bool(true));
+        state.set_ref(&nil, SyxValue::Integer(2));
+        assert_eq!(state.get_ref(&reused), SyxValue::Bool(true));
+        assert_eq!(state.get_ref(&nil), SyxValue::Integer(2));
+    }
+
+    #[test]
+    fn test_named_fields() {
+        let mut state = SyxState::new();
+        let registry = state.registry();
+        set_field(&mut state, registry, "mylib.config", SyxValue::Integer(3));
+        let globals = state.globals();
+        assert!(registry != globals);
+        assert_eq!(state.table(registry).get(&SyxValue::from("mylib.config")),
+                   SyxValue::Integer(3));
+    }
+}
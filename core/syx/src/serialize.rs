@@ -0,0 +1,777 @@
+// Serde support
+//
+// With the `serde` feature, any Rust value implementing Serialize can be
+// turned into a value with `to_value`, and a value back into any type
+// implementing Deserialize with `from_value`, so tables built by scripts map
+// onto Rust structs. Values refer to tables in a state, so both go through
+// one: structs and maps become tables with their fields as keys, sequences
+// and tuples tables counting from 1, and enum variants either their name or
+// a table with the name as the only key.
+//
+// `SerdeOptions` decide how keys other than strings are read and written,
+// and which value stands for a missing one: None and () become nil by
+// default, which leaves holes in sequences, but a sentinel such as a shared
+// empty table can be chosen instead.
+//
+// Consult the serde data model (https://serde.rs/data-model.html) for more
+// information.
+
+use std::convert::TryInto;
+use std::fmt::Display;
+
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use serde::ser::{self, Serialize};
+
+use super::errors::*;
+use super::object::{
+    float_to_integer, integer_to_i64, number_to_f64, SyxInteger, SyxNumber, SyxString, SyxValue,
+    TableRef,
+};
+use super::state::SyxState;
+use super::vm::append_string;
+
+// What happens to table keys that are not strings
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NonStringKeys {
+    Keep,      // read and written as what they are
+    Skip,      // left out of maps and structs
+    Stringify, // numbers are read and written as strings
+}
+
+#[derive(Clone, Debug)]
+pub struct SerdeOptions {
+    pub non_string_keys: NonStringKeys,
+    pub null: SyxValue, // what None and () become, and read back as
+}
+
+impl Default for SerdeOptions {
+    fn default() -> SerdeOptions {
+        SerdeOptions {
+            non_string_keys: NonStringKeys::Keep,
+            null: SyxValue::Nil,
+        }
+    }
+}
+
+impl ser::Error for Error {
+    fn custom<T: Display>(message: T) -> Error {
+        ErrorKind::SerdeError(message.to_string()).into()
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: Display>(message: T) -> Error {
+        ErrorKind::SerdeError(message.to_string()).into()
+    }
+}
+
+impl SyxState {
+    pub fn to_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<SyxValue> {
+        self.to_value_with(value, &SerdeOptions::default())
+    }
+
+    pub fn to_value_with<T: Serialize + ?Sized>(&mut self, value: &T, options: &SerdeOptions)
+        -> Result<SyxValue>
+    {
+        value.serialize(ValueSerializer { state: self, options })
+    }
+
+    pub fn from_value<T: DeserializeOwned>(&self, value: SyxValue) -> Result<T> {
+        self.from_value_with(value, &SerdeOptions::default())
+    }
+
+    pub fn from_value_with<T: DeserializeOwned>(&self, value: SyxValue, options: &SerdeOptions)
+        -> Result<T>
+    {
+        T::deserialize(ValueDeserializer { state: self, options, value })
+    }
+}
+
+// a number key as a string, for NonStringKeys::Stringify
+fn stringify(key: SyxValue) -> SyxValue {
+    match key {
+        SyxValue::Integer(_) | SyxValue::Number(_) => {
+            let mut buffer = Vec::new();
+            append_string(&mut buffer, &key);
+            SyxValue::String(SyxString::new(buffer))
+        }
+        key => key,
+    }
+}
+
+struct ValueSerializer<'s> {
+    state: &'s mut SyxState,
+    options: &'s SerdeOptions,
+}
+
+impl<'s> ValueSerializer<'s> {
+    fn string(self, s: &[u8]) -> Result<SyxValue> {
+        Ok(SyxValue::String(self.state.intern(s)))
+    }
+
+    // `v` as an integer, or past the integers as the float `x`
+    fn integer(self, v: impl TryInto<SyxInteger>, x: f64) -> Result<SyxValue> {
+        match v.try_into() {
+            Ok(i) => Ok(SyxValue::Integer(i)),
+            Err(_) => Ok(SyxValue::Number(x as SyxNumber)),
+        }
+    }
+
+    // table of `len` items, wrapped in {variant = ...} when it is for one
+    fn table(self, len: Option<usize>, variant: Option<&'static str>) -> TableSerializer<'s> {
+        let table = self.state.new_table(0, len.unwrap_or(0));
+        TableSerializer {
+            state: self.state,
+            options: self.options,
+            table,
+            next: 1,
+            key: None,
+            variant,
+        }
+    }
+
+    fn variant(self, variant: &'static str, value: SyxValue) -> Result<SyxValue> {
+        let table = self.state.new_table(0, 1);
+        let key = SyxValue::String(self.state.intern(variant.as_bytes()));
+        self.state.table_set(table, key, value)?;
+        Ok(SyxValue::Table(table))
+    }
+}
+
+impl<'s> ser::Serializer for ValueSerializer<'s> {
+    type Ok = SyxValue;
+    type Error = Error;
+    type SerializeSeq = TableSerializer<'s>;
+    type SerializeTuple = TableSerializer<'s>;
+    type SerializeTupleStruct = TableSerializer<'s>;
+    type SerializeTupleVariant = TableSerializer<'s>;
+    type SerializeMap = TableSerializer<'s>;
+    type SerializeStruct = TableSerializer<'s>;
+    type SerializeStructVariant = TableSerializer<'s>;
+
+    fn serialize_bool(self, v: bool) -> Result<SyxValue> {
+        Ok(SyxValue::Bool(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<SyxValue> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<SyxValue> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<SyxValue> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<SyxValue> {
+        self.integer(v, v as f64)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<SyxValue> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<SyxValue> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<SyxValue> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<SyxValue> {
+        self.integer(v, v as f64)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<SyxValue> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<SyxValue> {
+        Ok(SyxValue::Number(v as SyxNumber))
+    }
+
+    fn serialize_char(self, v: char) -> Result<SyxValue> {
+        self.string(v.encode_utf8(&mut [0; 4]).as_bytes())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<SyxValue> {
+        self.string(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<SyxValue> {
+        self.string(v)
+    }
+
+    fn serialize_none(self) -> Result<SyxValue> {
+        Ok(self.options.null.clone())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<SyxValue> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<SyxValue> {
+        Ok(self.options.null.clone())
+    }
+
+    fn serialize_unit_struct(self, _: &'static str) -> Result<SyxValue> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(self, _: &'static str, _: u32, variant: &'static str)
+        -> Result<SyxValue>
+    {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _: &'static str, value: &T)
+        -> Result<SyxValue>
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _: &'static str, _: u32,
+                                                        variant: &'static str, value: &T)
+        -> Result<SyxValue>
+    {
+        let value = value.serialize(ValueSerializer { state: &mut *self.state,
+                                                      options: self.options })?;
+        self.variant(variant, value)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<TableSerializer<'s>> {
+        Ok(self.table(len, None))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<TableSerializer<'s>> {
+        Ok(self.table(Some(len), None))
+    }
+
+    fn serialize_tuple_struct(self, _: &'static str, len: usize) -> Result<TableSerializer<'s>> {
+        Ok(self.table(Some(len), None))
+    }
+
+    fn serialize_tuple_variant(self, _: &'static str, _: u32, variant: &'static str, len: usize)
+        -> Result<TableSerializer<'s>>
+    {
+        Ok(self.table(Some(len), Some(variant)))
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<TableSerializer<'s>> {
+        Ok(self.table(len, None))
+    }
+
+    fn serialize_struct(self, _: &'static str, len: usize) -> Result<TableSerializer<'s>> {
+        Ok(self.table(Some(len), None))
+    }
+
+    fn serialize_struct_variant(self, _: &'static str, _: u32, variant: &'static str, len: usize)
+        -> Result<TableSerializer<'s>>
+    {
+        Ok(self.table(Some(len), Some(variant)))
+    }
+}
+
+struct TableSerializer<'s> {
+    state: &'s mut SyxState,
+    options: &'s SerdeOptions,
+    table: TableRef,
+    next: SyxInteger,       // index of the next sequence item
+    key: Option<SyxValue>,  // of the map entry being written, None to skip it
+    variant: Option<&'static str>,
+}
+
+impl<'s> TableSerializer<'s> {
+    fn value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<SyxValue> {
+        value.serialize(ValueSerializer { state: &mut *self.state, options: self.options })
+    }
+
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let value = self.value(value)?;
+        let key = SyxValue::Integer(self.next);
+        self.next += 1;
+        self.state.table_set(self.table, key, value)
+    }
+
+    fn field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<()> {
+        let value = self.value(value)?;
+        let key = SyxValue::String(self.state.intern(key.as_bytes()));
+        self.state.table_set(self.table, key, value)
+    }
+
+    fn finish(self) -> Result<SyxValue> {
+        let table = SyxValue::Table(self.table);
+        match self.variant {
+            Some(variant) => ValueSerializer { state: self.state, options: self.options }
+                .variant(variant, table),
+            None => Ok(table),
+        }
+    }
+}
+
+impl<'s> ser::SerializeSeq for TableSerializer<'s> {
+    type Ok = SyxValue;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<SyxValue> {
+        self.finish()
+    }
+}
+
+impl<'s> ser::SerializeTuple for TableSerializer<'s> {
+    type Ok = SyxValue;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<SyxValue> {
+        self.finish()
+    }
+}
+
+impl<'s> ser::SerializeTupleStruct for TableSerializer<'s> {
+    type Ok = SyxValue;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<SyxValue> {
+        self.finish()
+    }
+}
+
+impl<'s> ser::SerializeTupleVariant for TableSerializer<'s> {
+    type Ok = SyxValue;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<SyxValue> {
+        self.finish()
+    }
+}
+
+impl<'s> ser::SerializeMap for TableSerializer<'s> {
+    type Ok = SyxValue;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        let key = self.value(key)?;
+        self.key = match (self.options.non_string_keys, key) {
+            (_, key @ SyxValue::String(_)) | (NonStringKeys::Keep, key) => Some(key),
+            (NonStringKeys::Skip, _) => None,
+            (NonStringKeys::Stringify, key) => Some(stringify(key)),
+        };
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        match self.key.take() {
+            Some(key) => {
+                let value = self.value(value)?;
+                self.state.table_set(self.table, key, value)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn end(self) -> Result<SyxValue> {
+        self.finish()
+    }
+}
+
+impl<'s> ser::SerializeStruct for TableSerializer<'s> {
+    type Ok = SyxValue;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T)
+        -> Result<()>
+    {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<SyxValue> {
+        self.finish()
+    }
+}
+
+impl<'s> ser::SerializeStructVariant for TableSerializer<'s> {
+    type Ok = SyxValue;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T)
+        -> Result<()>
+    {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<SyxValue> {
+        self.finish()
+    }
+}
+
+struct ValueDeserializer<'s> {
+    state: &'s SyxState,
+    options: &'s SerdeOptions,
+    value: SyxValue,
+}
+
+impl<'s> ValueDeserializer<'s> {
+    fn with(&self, value: SyxValue) -> ValueDeserializer<'s> {
+        ValueDeserializer { state: self.state, options: self.options, value }
+    }
+
+    fn is_null(&self) -> bool {
+        self.value.is_nil() || self.value == self.options.null
+    }
+
+    // items 1 up to the length of a table
+    fn items(&self, table: TableRef) -> Vec<SyxValue> {
+        let table = self.state.table(table);
+        (1..=table.length()).map(|i| table.get_int(i)).collect()
+    }
+
+    // every pair of a table, with keys as NonStringKeys says
+    fn pairs(&self, table: TableRef) -> Result<Vec<(SyxValue, SyxValue)>> {
+        let mut pairs = Vec::new();
+        let mut key = SyxValue::Nil;
+        while let Some((next, value)) = self.state.table(table).next(&key)? {
+            key = next.clone();
+            match (self.options.non_string_keys, next) {
+                (_, next @ SyxValue::String(_)) | (NonStringKeys::Keep, next) => {
+                    pairs.push((next, value))
+                }
+                (NonStringKeys::Skip, _) => {}
+                (NonStringKeys::Stringify, next) => pairs.push((stringify(next), value)),
+            }
+        }
+        Ok(pairs)
+    }
+
+    // whether a table is a sequence with nothing else in it
+    fn is_sequence(&self, table: TableRef) -> Result<bool> {
+        let table = self.state.table(table);
+        let length = table.length();
+        let mut count = 0;
+        let mut key = SyxValue::Nil;
+        while let Some((next, _)) = table.next(&key)? {
+            match next {
+                SyxValue::Integer(i) if i >= 1 && i <= length => count += 1,
+                _ => return Ok(false),
+            }
+            key = next;
+        }
+        Ok(count > 0 && count == length)
+    }
+
+    fn type_error<T>(&self, expected: &str) -> Result<T> {
+        Err(ErrorKind::SerdeError(format!("{} expected, got {}", expected,
+                                          self.value.type_name())).into())
+    }
+}
+
+// integers may be given as floats with an integer value
+macro_rules! deserialize_integer {
+    ($($method:ident)*) => {$(
+        fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+            match self.value {
+                SyxValue::Number(n) => match float_to_integer(n) {
+                    Some(i) => visitor.visit_i64(integer_to_i64(i)),
+                    None => self.type_error("integer"),
+                },
+                _ => self.deserialize_any(visitor),
+            }
+        }
+    )*}
+}
+
+impl<'de, 's> de::Deserializer<'de> for ValueDeserializer<'s> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        if self.is_null() {
+            return visitor.visit_unit();
+        }
+        match self.value {
+            SyxValue::Bool(b) => visitor.visit_bool(b),
+            SyxValue::Integer(i) => visitor.visit_i64(integer_to_i64(i)),
+            SyxValue::Number(n) => visitor.visit_f64(number_to_f64(n)),
+            SyxValue::String(ref s) => match ::std::str::from_utf8(s) {
+                Ok(s) => visitor.visit_string(s.to_owned()),
+                Err(_) => visitor.visit_byte_buf(s.to_vec()),
+            },
+            SyxValue::Table(table) => {
+                if self.is_sequence(table)? {
+                    self.deserialize_seq(visitor)
+                } else {
+                    self.deserialize_map(visitor)
+                }
+            }
+            _ => self.type_error("serializable value"),
+        }
+    }
+
+    deserialize_integer!(deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64
+                         deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64);
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        if self.is_null() {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        if self.is_null() {
+            visitor.visit_unit()
+        } else {
+            self.type_error("nil")
+        }
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(self, _: &'static str, visitor: V)
+        -> Result<V::Value>
+    {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _: &'static str, visitor: V)
+        -> Result<V::Value>
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value {
+            SyxValue::Table(table) => {
+                let items = self.items(table).into_iter().map(|item| self.with(item));
+                visitor.visit_seq(de::value::SeqDeserializer::new(items))
+            }
+            _ => self.type_error("table"),
+        }
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _: usize, visitor: V) -> Result<V::Value> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(self, _: &'static str, _: usize, visitor: V)
+        -> Result<V::Value>
+    {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value {
+            SyxValue::Table(table) => {
+                let pairs = self.pairs(table)?.into_iter()
+                    .map(|(key, value)| (self.with(key), self.with(value)));
+                visitor.visit_map(de::value::MapDeserializer::new(pairs))
+            }
+            _ => self.type_error("table"),
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(self, _: &'static str, _: &'static [&'static str],
+                                           visitor: V) -> Result<V::Value>
+    {
+        self.deserialize_map(visitor)
+    }
+
+    // a variant name, or a table with one as its only key
+    fn deserialize_enum<V: Visitor<'de>>(self, _: &'static str, _: &'static [&'static str],
+                                         visitor: V) -> Result<V::Value>
+    {
+        let (variant, value) = match self.value {
+            SyxValue::String(_) => (self.value.clone(), None),
+            SyxValue::Table(table) => {
+                let mut pairs = self.pairs(table)?;
+                if pairs.len() != 1 {
+                    return self.type_error("table with a single key");
+                }
+                let (variant, value) = pairs.remove(0);
+                (variant, Some(value))
+            }
+            _ => return self.type_error("string or table"),
+        };
+        visitor.visit_enum(EnumDeserializer {
+            variant: self.with(variant),
+            value: value.map(|value| self.with(value)),
+        })
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool f32 f64 char str string bytes byte_buf identifier i128 u128
+    }
+}
+
+impl<'de, 's> IntoDeserializer<'de, Error> for ValueDeserializer<'s> {
+    type Deserializer = ValueDeserializer<'s>;
+
+    fn into_deserializer(self) -> ValueDeserializer<'s> {
+        self
+    }
+}
+
+struct EnumDeserializer<'s> {
+    variant: ValueDeserializer<'s>,
+    value: Option<ValueDeserializer<'s>>,
+}
+
+impl<'de, 's> de::EnumAccess<'de> for EnumDeserializer<'s> {
+    type Error = Error;
+    type Variant = VariantDeserializer<'s>;
+
+    fn variant_seed<T: de::DeserializeSeed<'de>>(self, seed: T)
+        -> Result<(T::Value, VariantDeserializer<'s>)>
+    {
+        Ok((seed.deserialize(self.variant)?, VariantDeserializer(self.value)))
+    }
+}
+
+// the value of a variant, None for a unit variant given by its name
+struct VariantDeserializer<'s>(Option<ValueDeserializer<'s>>);
+
+impl<'de, 's> de::VariantAccess<'de> for VariantDeserializer<'s> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        match self.0 {
+            None => Ok(()),
+            Some(value) => de::Deserialize::deserialize(value),
+        }
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value> {
+        match self.0 {
+            Some(value) => seed.deserialize(value),
+            None => Err(de::Error::invalid_type(de::Unexpected::UnitVariant, &"newtype variant")),
+        }
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _: usize, visitor: V) -> Result<V::Value> {
+        match self.0 {
+            Some(value) => de::Deserializer::deserialize_seq(value, visitor),
+            None => Err(de::Error::invalid_type(de::Unexpected::UnitVariant, &"tuple variant")),
+        }
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, _: &'static [&'static str], visitor: V)
+        -> Result<V::Value>
+    {
+        match self.0 {
+            Some(value) => de::Deserializer::deserialize_map(value, visitor),
+            None => Err(de::Error::invalid_type(de::Unexpected::UnitVariant, &"struct variant")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use super::super::testing::run_in;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Shape {
+        Point,
+        Circle(f64),
+        Rect { w: u32, h: u32 },
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Scene {
+        name: String,
+        shapes: Vec<Shape>,
+        tags: HashMap<String, bool>,
+        parent: Option<Box<Scene>>,
+    }
+
+    fn eval(state: &mut SyxState, source: &str) -> SyxValue {
+        run_in(state, source).unwrap().into_first()
+    }
+
+    #[test]
+    fn test_from_script() {
+        let mut state = SyxState::new();
+        let value = eval(&mut state, "return { name = 'main', tags = { big = true }, \
+                                      shapes = { 'Point', { Circle = 2 }, \
+                                                 { Rect = { w = 3, h = 4.0 } } } }");
+        let scene: Scene = state.from_value(value).unwrap();
+        assert_eq!(scene, Scene {
+            name: "main".to_owned(),
+            shapes: vec![Shape::Point, Shape::Circle(2.0), Shape::Rect { w: 3, h: 4 }],
+            tags: vec![("big".to_owned(), true)].into_iter().collect(),
+            parent: None,
+        });
+        let value = eval(&mut state, "return { name = 1 }");
+        assert!(state.from_value::<Scene>(value).is_err());
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let mut state = SyxState::new();
+        let scene = Scene {
+            name: "child".to_owned(),
+            shapes: vec![Shape::Rect { w: 1, h: 2 }],
+            tags: HashMap::new(),
+            parent: Some(Box::new(Scene {
+                name: "root".to_owned(),
+                shapes: vec![],
+                tags: HashMap::new(),
+                parent: None,
+            })),
+        };
+        let value = state.to_value(&scene).unwrap();
+        let back: Scene = state.from_value(value).unwrap();
+        assert_eq!(back, scene);
+    }
+
+    #[test]
+    fn test_options() {
+        let mut state = SyxState::new();
+        let null = SyxValue::Table(state.new_table(0, 0));
+        let options = SerdeOptions { non_string_keys: NonStringKeys::Stringify, null };
+        let value = state.to_value_with(&vec![Some(1), None, Some(3)], &options).unwrap();
+        let table = match value {
+            SyxValue::Table(table) => table,
+            _ => unreachable!(),
+        };
+        assert_eq!(state.table(table).length(), 3);
+        let back: Vec<Option<i32>> = state.from_value_with(value.clone(), &options).unwrap();
+        assert_eq!(back, vec![Some(1), None, Some(3)]);
+        // the default nil leaves a hole
+        let value = state.to_value(&vec![Some(1), None]).unwrap();
+        assert_eq!(state.from_value::<Vec<Option<i32>>>(value).unwrap(), vec![Some(1)]);
+
+        let value = eval(&mut state, "return { [1] = true, [2.5] = false, x = true }");
+        let map: HashMap<String, bool> = state.from_value_with(value.clone(), &options).unwrap();
+        assert_eq!(map.len(), 3);
+        assert!(!map["2.5"]);
+        assert!(state.from_value::<HashMap<String, bool>>(value.clone()).is_err());
+        let options = SerdeOptions { non_string_keys: NonStringKeys::Skip, ..options };
+        let map: HashMap<String, bool> = state.from_value_with(value, &options).unwrap();
+        assert_eq!(map.keys().collect::<Vec<_>>(), vec!["x"]);
+    }
+}
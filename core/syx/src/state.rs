@@ -1,8 +1,186 @@
-pub struct SyxState {}
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+use super::conf::{SYX_MAXSTACK, SYX_MAXTAGLOOP, SYX_RANDOMSEED};
+use super::gc::Heap;
+use super::hook::{Hook, HookMask};
+use super::object::{
+    FunctionRef, Proto, SyxInteger, SyxString, SyxTable, SyxValue, TableRef, ThreadRef, UpvalRef,
+};
+use super::string::StringTable;
+use super::stdlib::io::IoState;
+use super::task::Tasks;
+use super::tm::TagMethod;
+use super::trace::Recorder;
+
+// Activation record of a function running on the state's stack
+pub(crate) struct CallInfo {
+    pub closure: FunctionRef,     // function being run
+    pub proto: Arc<Proto>,        // its prototype
+    pub base: usize,              // stack index of register 0
+    pub pc: usize,                // next instruction, saved across calls
+    pub top: usize,               // end of open results, saved across calls
+    pub ret: usize,               // where the caller wants the results
+    pub nresults: Option<usize>,  // how many, None for all of them
+    pub from_lua: bool,           // called by the frame below, not from Rust
+    pub tailcall: bool,           // took over the frame of a function tail calling it
+    pub varargs: Vec<SyxValue>,   // arguments past `numparams`
+}
+
+// The stack, frames and open upvalues are those of the running thread, see
+// coroutine.rs for where the others keep theirs.
+//
+// A state is Send: whatever the host hands it (native closures, userdata,
+// hooks and the io backend) must be Send too, so a state can be set up on one
+// thread and moved to another to run. It is not Sync, and is only ever used
+// by one thread at a time. A Proto is both, so one loaded chunk can be run by
+// states on several threads.
+pub struct SyxState {
+    pub(crate) stack: Vec<SyxValue>,    // registers of every active frame
+    pub(crate) frames: Vec<CallInfo>,   // innermost call last
+    pub(crate) open_upvalues: Vec<UpvalRef>, // by stack index, innermost last
+    pub(crate) nny: usize,              // number of non-yieldable calls running
+    pub(crate) ccalls: usize,           // calls from Rust into Lua running, of any thread
+    pub(crate) current: Option<ThreadRef>, // running coroutine, None for main
+    pub(crate) main_thread: Option<ThreadRef>, // what scripts see of main, see coroutine.rs
+    pub(crate) resumers: Vec<Option<ThreadRef>>, // threads waiting on a resume
+    pub(crate) yielded: Option<Vec<SyxValue>>, // values of a pending yield
+    pub(crate) api_stack: Vec<SyxValue>, // see api.rs
+    pub(crate) api_base: usize,         // bottom of the running native's values
+    pub(crate) tasks: Tasks,            // see task.rs
+    pub(crate) heap: Heap,              // every collectable object
+    pub(crate) globals: TableRef,       // _ENV of loaded chunks
+    pub(crate) registry: TableRef,      // see registry.rs
+    pub(crate) free_refs: Vec<SyxInteger>, // registry keys removed, for reuse
+    pub(crate) last_ref: SyxInteger,    // highest registry key handed out
+    pub(crate) string_metatable: Option<TableRef>, // shared by every string
+    pub(crate) userdata_metatables: HashMap<TypeId, TableRef>, // by userdata type
+    pub(crate) random: u64,             // state of math.random
+    pub(crate) io: IoState,             // backend and open files of the io library
+    pub(crate) unsafe_os: bool,         // os.getenv, os.remove and os.exit allowed
+    pub(crate) load_mode: String,       // chunks scripts may load, see `set_load_mode`
+    pub(crate) max_tag_loop: usize,     // longest __index/__newindex/__call chain
+    pub(crate) max_stack: usize,        // most slots the stack of a thread grows to
+    pub(crate) hook: Option<Hook>,      // see hook.rs
+    pub(crate) hook_mask: HookMask,
+    pub(crate) hook_count: usize,       // instructions left until the next count event
+    pub(crate) oldpc: usize,            // last instruction traced, for line events
+    pub(crate) lua_hook: SyxValue,      // function set by debug.sethook
+    pub(crate) recorder: Option<Box<Recorder>>, // see trace.rs
+    pub(crate) preempt: Arc<AtomicBool>, // see preempt.rs
+    pub(crate) strings: StringTable,    // interned short strings
+    pub(crate) tm_names: Vec<SyxString>, // metamethod names, by TagMethod
+}
 
 impl SyxState {
+    // A state interns its metamethod names, hashing them, so the hash seed
+    // is chosen by the first one created if `string::set_hash_seed` has not
+    // fixed it before (see string.rs)
     pub fn new() -> SyxState {
-        SyxState {}
+        let mut strings = StringTable::new();
+        let tm_names = TagMethod::ALL.iter()
+            .map(|event| strings.intern(event.name().as_bytes()))
+            .collect();
+        let mut state = SyxState {
+            stack: Vec::new(),
+            frames: Vec::new(),
+            open_upvalues: Vec::new(),
+            nny: 0,
+            ccalls: 0,
+            current: None,
+            main_thread: None,
+            resumers: Vec::new(),
+            yielded: None,
+            api_stack: Vec::new(),
+            api_base: 0,
+            tasks: Tasks::default(),
+            heap: Heap::new(),
+            globals: TableRef(0), // allocated below
+            registry: TableRef(0),
+            free_refs: Vec::new(),
+            last_ref: 0,
+            string_metatable: None,
+            userdata_metatables: HashMap::new(),
+            random: SYX_RANDOMSEED,
+            io: IoState::new(),
+            unsafe_os: false,
+            load_mode: "bt".to_owned(),
+            max_tag_loop: SYX_MAXTAGLOOP,
+            max_stack: SYX_MAXSTACK,
+            hook: None,
+            hook_mask: HookMask::default(),
+            hook_count: 0,
+            oldpc: 0,
+            lua_hook: SyxValue::Nil,
+            recorder: None,
+            preempt: Arc::default(),
+            strings,
+            tm_names,
+        };
+        let size = state.strings.size();
+        state.heap.allocate_strings(size);
+        state.globals = state.new_table(0, 0);
+        state.registry = state.new_table(0, 0);
+        state
+    }
+
+    // string with the given contents, shared with any equal short string
+    pub fn intern(&mut self, bytes: &[u8]) -> SyxString {
+        let size = self.strings.size();
+        let string = self.strings.intern(bytes);
+        let grown = self.strings.size() - size;
+        self.heap.allocate_strings(grown);
+        string
+    }
+
+    // Limit how many metamethod hops a single index or call may take before
+    // it is reported as a loop.
+    pub fn set_max_tag_loop(&mut self, limit: usize) {
+        self.max_tag_loop = limit;
+    }
+
+    // Limit how many stack slots the frames of a thread may use together,
+    // past which a call fails with "stack overflow" rather than recursing on.
+    pub fn set_max_stack(&mut self, limit: usize) {
+        self.max_stack = limit;
+    }
+
+    // Restrict the chunks scripts can load with load, loadfile, dofile and
+    // require to the kinds in `mode`, as its namesake argument to load: "t"
+    // for source text only, "b" for binary chunks only, "bt" for both.
+    pub fn set_load_mode(&mut self, mode: &str) {
+        self.load_mode = mode.to_owned();
+    }
+
+    // table holding the global variables, the _ENV of loaded chunks
+    pub fn globals(&self) -> TableRef {
+        self.globals
+    }
+
+    pub fn new_table(&mut self, narray: usize, nhash: usize) -> TableRef {
+        self.alloc_table(SyxTable::new(narray, nhash))
+    }
+
+    pub fn table(&self, table: TableRef) -> &SyxTable {
+        self.heap.tables.get(table.0)
+    }
+
+    // The table, to be changed: it gets a new version, so field caches
+    // holding its old contents are not used again
+    pub fn table_mut(&mut self, table: TableRef) -> &mut SyxTable {
+        self.barrier(table);
+        let version = self.heap.next_version();
+        let table = self.heap.tables.get_mut(table.0);
+        table.version = version;
+        table
+    }
+}
+
+impl Default for SyxState {
+    fn default() -> SyxState {
+        SyxState::new()
     }
 }
 
@@ -31,3 +209,41 @@ impl SyxState {
 //   l_signalT hookmask;
 //   lu_byte allowhook;
 // };
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    use super::*;
+    use super::super::compiler::parse;
+    use super::super::stdlib;
+
+    fn assert_send<T: Send>() {}
+    fn assert_sync<T: Sync>() {}
+
+    #[test]
+    fn test_send() {
+        assert_send::<SyxState>();
+        assert_send::<Proto>();
+        assert_sync::<Proto>();
+
+        let proto = Arc::new(parse(b"return add(20, 1) * 2", "=test").unwrap());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let workers: Vec<_> = (0..2).map(|_| {
+            let mut state = SyxState::new();
+            stdlib::open_libs(&mut state);
+            let seen = calls.clone();
+            state.register("add", move |_, args| {
+                seen.fetch_add(1, Ordering::Relaxed);
+                Ok(vec![SyxValue::Integer(args.check_integer(1)? + args.check_integer(2)?)].into())
+            });
+            let proto = proto.clone();
+            thread::spawn(move || state.call(proto, Vec::new()).unwrap().into_first())
+        }).collect();
+        for worker in workers {
+            assert_eq!(worker.join().unwrap(), SyxValue::Integer(42));
+        }
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,575 @@
+// Base library
+//
+// The functions every chunk expects to find as globals, along with `_G` and
+// `_VERSION`. print writes to the standard output of the io backend, so an
+// embedder that replaces the backend also decides where it goes, and
+// loadfile and dofile read files through it. The chunks load, loadfile and
+// dofile accept are further limited to the kinds `set_load_mode` allows.
+//
+// Consult the versioned lbaselib.c for more information.
+
+use std::sync::Arc;
+
+use super::super::compiler::{load_with_mode, skip_comment};
+use super::super::conf::{SYX_VERSION_MAJOR, SYX_VERSION_MINOR};
+use super::super::errors::*;
+use super::super::object::{NativeFunction, Proto, SyxInteger, SyxValue};
+use super::super::protect;
+use super::super::state::SyxState;
+use super::super::vm::{bad_argument, runtime_error};
+use super::io::error_message;
+use super::{
+    check_any, check_integer, check_string, check_table, opt_string, set_field, tostring,
+    type_error,
+};
+
+pub fn open(state: &mut SyxState) {
+    let globals = state.globals();
+    let functions: &[(&str, NativeFunction)] = &[
+        ("assert", assert),
+        ("dofile", dofile),
+        ("error", protect::error),
+        ("getmetatable", getmetatable),
+        ("ipairs", ipairs),
+        ("load", load),
+        ("loadfile", loadfile),
+        ("loadstring", loadstring),
+        ("next", next),
+        ("pairs", pairs),
+        ("pcall", protect::pcall),
+        ("print", print),
+        ("rawequal", rawequal),
+        ("rawget", rawget),
+        ("rawlen", rawlen),
+        ("rawset", rawset),
+        ("select", select),
+        ("setmetatable", setmetatable),
+        ("tonumber", tonumber),
+        ("tostring", base_tostring),
+        ("type", type_),
+        ("xpcall", protect::xpcall),
+    ];
+    for &(name, function) in functions {
+        set_field(state, globals, name, SyxValue::Native(function));
+    }
+    set_field(state, globals, "_G", SyxValue::Table(globals));
+    let version = format!("Lua {}.{}", SYX_VERSION_MAJOR, SYX_VERSION_MINOR);
+    let version = SyxValue::String(state.intern(version.as_bytes()));
+    set_field(state, globals, "_VERSION", version);
+}
+
+// print(...), each value through tostring, separated by tabs
+fn print(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let mut line = Vec::new();
+    for (i, value) in args.iter().enumerate() {
+        if i > 0 {
+            line.push(b'\t');
+        }
+        line.extend_from_slice(&tostring(state, value)?);
+    }
+    line.push(b'\n');
+    state.io.print(&line);
+    Ok(vec![])
+}
+
+// load_with_mode, for only the kinds of chunk in `mode` that the host allows
+// too, though a refused chunk is reported against `mode` as the caller gave it
+fn load_allowed(state: &SyxState, chunk: Vec<u8>, chunkname: &str, mode: &str) -> Result<Proto> {
+    let allowed: String = mode.chars().filter(|&kind| state.load_mode.contains(kind)).collect();
+    load_with_mode(chunk, chunkname, &allowed).map_err(|error| match *error.kind() {
+        ErrorKind::ChunkModeError(kind, _) => {
+            ErrorKind::ChunkModeError(kind, mode.to_owned()).into()
+        }
+        _ => error,
+    })
+}
+
+// results of load: the main function of `proto`, its _ENV being `env` if
+// there is one, or nil and the error
+fn load_result(state: &mut SyxState, proto: Result<Proto>, env: Option<SyxValue>)
+    -> Vec<SyxValue>
+{
+    let proto = match proto {
+        Ok(proto) => Arc::new(proto),
+        Err(error) => return vec![SyxValue::Nil, state.error_value(&error)],
+    };
+    let closure = match env {
+        Some(env) => state.new_env_closure(proto, env),
+        None => state.new_main_closure(proto),
+    };
+    vec![SyxValue::Function(closure)]
+}
+
+// pieces returned by `reader` until it returns nil or an empty string
+fn read_chunk(state: &mut SyxState, reader: SyxValue) -> Result<Vec<u8>> {
+    let mut chunk = Vec::new();
+    loop {
+        match state.call_value(reader.clone(), vec![])?.into_first() {
+            SyxValue::Nil => return Ok(chunk),
+            SyxValue::String(ref piece) if piece.is_empty() => return Ok(chunk),
+            SyxValue::String(piece) => chunk.extend_from_slice(&piece),
+            _ => return runtime_error("reader function must return a string".to_owned()),
+        }
+    }
+}
+
+// load(chunk [, chunkname [, mode [, env]]]), where `chunk` is a string or a
+// function returning its pieces. An `env` given, even nil, is its _ENV.
+fn load(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let (chunk, default_name) = match args.first() {
+        Some(reader) if reader.is_function() => match read_chunk(state, reader.clone()) {
+            Ok(chunk) => (chunk, "=(load)".to_owned()),
+            Err(error) => return Ok(vec![SyxValue::Nil, state.error_value(&error)]),
+        },
+        _ => {
+            let chunk = check_string(&args, 1, "load")?;
+            (chunk.to_vec(), chunk.to_string())
+        }
+    };
+    let chunkname = match args.get(1) {
+        None | Some(SyxValue::Nil) => default_name,
+        _ => check_string(&args, 2, "load")?.to_string(),
+    };
+    let mode = opt_string(&args, 3, "load", "bt")?;
+    let proto = load_allowed(state, chunk, &chunkname, &String::from_utf8_lossy(&mode));
+    Ok(load_result(state, proto, args.get(3).cloned()))
+}
+
+// loadstring(s [, chunkname]), load for strings only, as in Lua 5.1
+fn loadstring(state: &mut SyxState, mut args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    check_string(&args, 1, "loadstring")?;
+    args.truncate(2);
+    load(state, args)
+}
+
+// The main function of the file `filename`, or of the standard input
+fn load_file(state: &mut SyxState, filename: Option<&str>, mode: &str) -> Result<Proto> {
+    match filename {
+        Some(filename) => match state.io.read_file(filename) {
+            Ok(contents) => {
+                load_allowed(state, skip_comment(contents), &format!("@{}", filename), mode)
+            }
+            Err(error) => runtime_error(format!("cannot open {}",
+                                                error_message(&error, Some(filename)))),
+        },
+        None => match state.io.read_stdin() {
+            Ok(contents) => load_allowed(state, skip_comment(contents), "=stdin", mode),
+            Err(error) => runtime_error(format!("cannot read stdin: {}",
+                                                error_message(&error, None))),
+        },
+    }
+}
+
+fn opt_filename(args: &[SyxValue], name: &str) -> Result<Option<String>> {
+    match args.first() {
+        None | Some(SyxValue::Nil) => Ok(None),
+        _ => Ok(Some(check_string(args, 1, name)?.to_string())),
+    }
+}
+
+// loadfile([filename [, mode [, env]]]), load for the contents of a file
+fn loadfile(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let filename = opt_filename(&args, "loadfile")?;
+    let mode = opt_string(&args, 2, "loadfile", "bt")?.to_string();
+    let proto = load_file(state, filename.as_deref(), &mode);
+    Ok(load_result(state, proto, args.get(2).cloned()))
+}
+
+// dofile([filename]), every result of running a file, whose errors it raises
+fn dofile(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let filename = opt_filename(&args, "dofile")?;
+    let proto = load_file(state, filename.as_deref(), "bt")?;
+    let closure = state.new_main_closure(Arc::new(proto));
+    state.call_value(SyxValue::Function(closure), vec![]).map(Vec::from)
+}
+
+fn type_(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let value = check_any(&args, 1, "type")?;
+    Ok(vec![SyxValue::String(state.intern(value.type_name().as_bytes()))])
+}
+
+fn base_tostring(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let value = check_any(&args, 1, "tostring")?;
+    Ok(vec![SyxValue::String(tostring(state, &value)?)])
+}
+
+// tonumber(e [, base]), nil when e is not a numeral
+fn tonumber(_: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    match args.get(1) {
+        None | Some(SyxValue::Nil) => {
+            let value = check_any(&args, 1, "tonumber")?;
+            Ok(vec![value.coerce_to_number(None).unwrap_or(SyxValue::Nil)])
+        }
+        _ => {
+            let base = check_integer(&args, 2, "tonumber")?;
+            if !matches!(args[0], SyxValue::String(_)) {
+                return type_error(&args, 1, "tonumber", "string");
+            }
+            if !(2..=36).contains(&base) {
+                return bad_argument(2, "tonumber", "base out of range");
+            }
+            Ok(vec![args[0].coerce_to_number(Some(base as u32)).unwrap_or(SyxValue::Nil)])
+        }
+    }
+}
+
+// assert(v [, message, ...]), all its arguments when v is true
+fn assert(_: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let value = check_any(&args, 1, "assert")?;
+    if !value.is_falsy() {
+        return Ok(args);
+    }
+    let message = match args.get(1) {
+        Some(message) => message.clone(),
+        None => SyxValue::from("assertion failed!"),
+    };
+    bail!(ErrorKind::LuaError(message))
+}
+
+// select(n, ...), the arguments from the nth on, or their count for "#"
+fn select(_: &mut SyxState, mut args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let count = args.len().saturating_sub(1) as SyxInteger;
+    if let Some(SyxValue::String(s)) = args.first() {
+        if s.first() == Some(&b'#') {
+            return Ok(vec![SyxValue::Integer(count)]);
+        }
+    }
+    let n = check_integer(&args, 1, "select")?;
+    let n = if n < 0 {
+        n + count
+    } else if n == 0 {
+        return bad_argument(1, "select", "index out of range");
+    } else {
+        n.min(count + 1) - 1
+    };
+    if n < 0 {
+        return bad_argument(1, "select", "index out of range");
+    }
+    Ok(args.split_off(n as usize + 1))
+}
+
+fn rawequal(_: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let a = check_any(&args, 1, "rawequal")?;
+    let b = check_any(&args, 2, "rawequal")?;
+    Ok(vec![SyxValue::Bool(a == b)])
+}
+
+fn rawlen(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let length = match args.first() {
+        Some(&SyxValue::Table(t)) => state.table(t).length(),
+        Some(SyxValue::String(s)) => s.len() as SyxInteger,
+        _ => return bad_argument(1, "rawlen", "table or string expected"),
+    };
+    Ok(vec![SyxValue::Integer(length)])
+}
+
+fn rawget(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let table = check_table(&args, 1, "rawget")?;
+    let key = check_any(&args, 2, "rawget")?;
+    Ok(vec![state.table(table).get(&key)])
+}
+
+fn rawset(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let table = check_table(&args, 1, "rawset")?;
+    let key = check_any(&args, 2, "rawset")?;
+    let value = check_any(&args, 3, "rawset")?;
+    state.table_mut(table).set(key, value)?;
+    Ok(vec![SyxValue::Table(table)])
+}
+
+// getmetatable(object), or its __metatable field when there is one
+fn getmetatable(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let value = check_any(&args, 1, "getmetatable")?;
+    let metatable = match state.metatable(&value) {
+        Some(metatable) => metatable,
+        None => return Ok(vec![SyxValue::Nil]),
+    };
+    let field = SyxValue::String(state.intern(b"__metatable"));
+    Ok(vec![match state.table(metatable).get(&field) {
+        SyxValue::Nil => SyxValue::Table(metatable),
+        protected => protected,
+    }])
+}
+
+// setmetatable(table, metatable), unless it has a protected one already
+fn setmetatable(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let table = check_table(&args, 1, "setmetatable")?;
+    let metatable = match args.get(1) {
+        Some(SyxValue::Nil) => None,
+        Some(&SyxValue::Table(metatable)) => Some(metatable),
+        _ => return type_error(&args, 2, "setmetatable", "nil or table"),
+    };
+    if let Some(current) = state.table(table).metatable() {
+        let field = SyxValue::String(state.intern(b"__metatable"));
+        if !state.table(current).get(&field).is_nil() {
+            return Err(ErrorKind::RuntimeError(
+                "cannot change a protected metatable".to_owned()).into());
+        }
+    }
+    state.table_mut(table).set_metatable(metatable);
+    state.check_finalizer(&SyxValue::Table(table));
+    Ok(vec![SyxValue::Table(table)])
+}
+
+// next(table [, key])
+fn next(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let table = check_table(&args, 1, "next")?;
+    let key = args.get(1).cloned().unwrap_or(SyxValue::Nil);
+    Ok(match state.table(table).next(&key)? {
+        Some((key, value)) => vec![key, value],
+        None => vec![SyxValue::Nil],
+    })
+}
+
+// The first three results of the `event` handler of `value` (__pairs or
+// __ipairs), which stand in for what pairs or ipairs would return
+fn iteration_handler(state: &mut SyxState, value: &SyxValue, event: &[u8])
+    -> Result<Option<Vec<SyxValue>>>
+{
+    let metatable = match state.metatable(value) {
+        Some(metatable) => metatable,
+        None => return Ok(None),
+    };
+    let name = SyxValue::String(state.intern(event));
+    let handler = state.table(metatable).get(&name);
+    if handler.is_nil() {
+        return Ok(None);
+    }
+    let mut results = state.call_value(handler, vec![value.clone()])?;
+    results.adjust(3);
+    Ok(Some(results.into_vec()))
+}
+
+// pairs(t), for use as `for k, v in pairs(t)`, through __pairs
+fn pairs(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let value = check_any(&args, 1, "pairs")?;
+    if let Some(results) = iteration_handler(state, &value, b"__pairs")? {
+        return Ok(results);
+    }
+    let table = check_table(&args, 1, "pairs")?;
+    Ok(vec![SyxValue::Native(next), SyxValue::Table(table), SyxValue::Nil])
+}
+
+fn ipairs_next(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let i = check_integer(&args, 2, "ipairs")?.wrapping_add(1);
+    let value = state.get_index(&args[0], &SyxValue::Integer(i))?;
+    Ok(if value.is_nil() { vec![SyxValue::Nil] } else { vec![SyxValue::Integer(i), value] })
+}
+
+// ipairs(t), t[1], t[2], ... up to the first nil, following __index, or
+// through __ipairs
+fn ipairs(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let value = check_any(&args, 1, "ipairs")?;
+    if let Some(results) = iteration_handler(state, &value, b"__ipairs")? {
+        return Ok(results);
+    }
+    Ok(vec![SyxValue::Native(ipairs_next), value, SyxValue::Integer(0)])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::*;
+    use super::super::super::compiler::parse;
+
+    fn strings(values: &[&str]) -> Vec<SyxValue> {
+        values.iter().map(|&s| SyxValue::from(s)).collect()
+    }
+
+    #[test]
+    fn test_tonumber() {
+        let mut state = SyxState::new();
+        let mut convert = |args: Vec<SyxValue>| tonumber(&mut state, args).unwrap().remove(0);
+        assert_eq!(convert(strings(&["  0x10  "])), SyxValue::Integer(16));
+        assert_eq!(convert(strings(&["-7"])), SyxValue::Integer(-7));
+        assert_eq!(convert(strings(&["1e2"])), SyxValue::Number(100.0));
+        assert_eq!(convert(strings(&["0x1.8p1"])), SyxValue::Number(3.0));
+        assert_eq!(convert(strings(&["9223372036854775808"])),
+                   SyxValue::Number(9_223_372_036_854_775_808.0));
+        assert_eq!(convert(strings(&["0xffffffffffffffff"])), SyxValue::Integer(-1));
+        for bad in &["", "1e", "inf", "nan", "0x", "1 2", "- 1"] {
+            assert_eq!(convert(strings(&[bad])), SyxValue::Nil, "{:?}", bad);
+        }
+        assert_eq!(convert(vec![SyxValue::Bool(true)]), SyxValue::Nil);
+        let with_base = |digits: &str, base| vec![SyxValue::from(digits), SyxValue::Integer(base)];
+        assert_eq!(convert(with_base("ff", 16)), SyxValue::Integer(255));
+        assert_eq!(convert(with_base(" -zz ", 36)), SyxValue::Integer(-1295));
+        assert_eq!(convert(with_base("12", 2)), SyxValue::Nil);
+
+        let error = tonumber(&mut state, vec![SyxValue::Integer(1), SyxValue::Integer(10)]);
+        assert_eq!(error.unwrap_err().to_string(),
+                   "bad argument #1 to 'tonumber' (string expected, got number)");
+        let error = tonumber(&mut state, vec![SyxValue::from("1"), SyxValue::Integer(37)]);
+        assert_eq!(error.unwrap_err().to_string(),
+                   "bad argument #2 to 'tonumber' (base out of range)");
+    }
+
+    #[test]
+    fn test_select_and_assert() {
+        let mut state = SyxState::new();
+        let args = |n: SyxValue| vec![n, SyxValue::from("a"), SyxValue::from("b")];
+        assert_eq!(select(&mut state, args(SyxValue::from("#"))).unwrap(),
+                   vec![SyxValue::Integer(2)]);
+        assert_eq!(select(&mut state, args(SyxValue::Integer(2))).unwrap(), strings(&["b"]));
+        assert_eq!(select(&mut state, args(SyxValue::Integer(5))).unwrap(), vec![]);
+        assert_eq!(select(&mut state, args(SyxValue::Integer(-2))).unwrap(),
+                   strings(&["a", "b"]));
+        let error = select(&mut state, args(SyxValue::Integer(-3))).unwrap_err();
+        assert_eq!(error.to_string(), "bad argument #1 to 'select' (index out of range)");
+
+        assert_eq!(assert(&mut state, strings(&["ok", "unused"])).unwrap(),
+                   strings(&["ok", "unused"]));
+        let error = assert(&mut state, vec![SyxValue::Bool(false)]).unwrap_err();
+        assert_eq!(error.to_string(), "assertion failed!");
+        let error = assert(&mut state, vec![SyxValue::Nil, SyxValue::Integer(42)]).unwrap_err();
+        assert_eq!(state.error_value(&error), SyxValue::Integer(42));
+    }
+
+    #[test]
+    fn test_metatables() {
+        let mut state = SyxState::new();
+        let t = SyxValue::Table(state.new_table(0, 0));
+        let mt = state.new_table(0, 0);
+        setmetatable(&mut state, vec![t.clone(), SyxValue::Table(mt)]).unwrap();
+        assert_eq!(getmetatable(&mut state, vec![t.clone()]).unwrap(),
+                   vec![SyxValue::Table(mt)]);
+        set_field(&mut state, mt, "__metatable", SyxValue::from("locked"));
+        assert_eq!(getmetatable(&mut state, vec![t.clone()]).unwrap(), strings(&["locked"]));
+        let error = setmetatable(&mut state, vec![t.clone(), SyxValue::Nil]).unwrap_err();
+        assert_eq!(error.to_string(), "cannot change a protected metatable");
+        let error = setmetatable(&mut state, vec![t.clone(), SyxValue::Bool(true)]).unwrap_err();
+        assert_eq!(error.to_string(),
+                   "bad argument #2 to 'setmetatable' (nil or table expected, got boolean)");
+
+        // raw access skips __index
+        let fallback = state.new_table(0, 0);
+        set_field(&mut state, fallback, "x", SyxValue::Integer(1));
+        set_field(&mut state, mt, "__index", SyxValue::Table(fallback));
+        let key = SyxValue::from("x");
+        assert_eq!(rawget(&mut state, vec![t.clone(), key.clone()]).unwrap(),
+                   vec![SyxValue::Nil]);
+        rawset(&mut state, vec![t.clone(), key.clone(), SyxValue::Integer(2)]).unwrap();
+        assert_eq!(rawget(&mut state, vec![t.clone(), key]).unwrap(),
+                   vec![SyxValue::Integer(2)]);
+        let error = rawset(&mut state, vec![t, SyxValue::Nil, SyxValue::Nil]).unwrap_err();
+        assert_eq!(error.to_string(), "table index is nil");
+    }
+
+    #[test]
+    fn test_iteration() {
+        let mut state = SyxState::new();
+        let t = state.new_table(0, 0);
+        for i in 1..=3 {
+            state.table_mut(t).set_int(i, SyxValue::Integer(i * 10));
+        }
+        for key in &["a", "b", "c"] {
+            set_field(&mut state, t, key, SyxValue::Bool(true));
+        }
+        // clearing entries during a traversal is allowed
+        let mut seen = 0;
+        let mut key = SyxValue::Nil;
+        loop {
+            let args = vec![SyxValue::Table(t), key.clone()];
+            let mut entry = next(&mut state, args).unwrap();
+            if entry[0].is_nil() {
+                break;
+            }
+            key = entry.remove(0);
+            state.table_mut(t).set(key.clone(), SyxValue::Nil).unwrap();
+            seen += 1;
+        }
+        assert_eq!(seen, 6);
+        assert_eq!(next(&mut state, vec![SyxValue::Table(t)]).unwrap(), vec![SyxValue::Nil]);
+        let error = next(&mut state, vec![SyxValue::Table(t), SyxValue::from("z")]).unwrap_err();
+        assert_eq!(error.to_string(), "invalid key to 'next'");
+
+        // ipairs stops at the first nil
+        let list = state.new_table(0, 0);
+        state.table_mut(list).set_int(1, SyxValue::from("one"));
+        state.table_mut(list).set_int(3, SyxValue::from("three"));
+        let mut results = ipairs(&mut state, vec![SyxValue::Table(list)]).unwrap();
+        let state_args = vec![results.remove(1), results.remove(1)];
+        let step = ipairs_next(&mut state, state_args).unwrap();
+        assert_eq!(step, vec![SyxValue::Integer(1), SyxValue::from("one")]);
+        let step = ipairs_next(&mut state, vec![SyxValue::Table(list), step[0].clone()]).unwrap();
+        assert_eq!(step, vec![SyxValue::Nil]);
+    }
+
+    #[test]
+    fn test_iteration_metamethods() {
+        let mut state = SyxState::new();
+        super::super::open_libs(&mut state);
+        let source = b"
+            local backing = {10, 20, x = 30}
+            local proxy = setmetatable({}, {
+                __pairs = function(t) return next, backing, nil end,
+                __ipairs = function(t) return ipairs(backing) end,
+            })
+            local sum, count = 0, 0
+            for k, v in pairs(proxy) do sum = sum + v end
+            for i, v in ipairs(proxy) do count = count + i end
+            return sum, count, pcall(pairs, 1)";
+        let proto = parse(source, "=test").unwrap();
+        let results = state.call(Arc::new(proto), vec![]).unwrap();
+        assert!(results == vec![SyxValue::Integer(60), SyxValue::Integer(3), SyxValue::Bool(false),
+                                SyxValue::from("bad argument #1 to 'pairs' \
+                                                (table expected, got number)")]);
+    }
+
+    #[test]
+    fn test_load() {
+        let mut state = SyxState::new();
+        super::super::open_libs(&mut state);
+        let path = env::temp_dir().join(format!("syx-load-{}.lua", process::id()));
+        fs::write(&path, "#!shebang\nlocal n = ... return (n or 1) + 1, x").unwrap();
+        let proto = parse(b"return 7", "=b").unwrap();
+        let binary = super::super::super::dump::DumpState::to_u8(&proto);
+        let globals = state.globals();
+        let path = path.to_str().unwrap().to_owned();
+        set_field(&mut state, globals, "path", SyxValue::from(path.as_str()));
+        set_field(&mut state, globals, "binary", SyxValue::String(binary.unwrap().into()));
+        let run = |state: &mut SyxState, source: &[u8]| {
+            let proto = parse(source, "=test").unwrap();
+            state.call(Arc::new(proto), vec![]).unwrap()
+        };
+
+        let results = run(&mut state, b"
+            local pieces = {'return ', '1 + ', '...'}
+            local i = 0
+            local sum = load(function() i = i + 1 return pieces[i] end)
+            local env = {}
+            load('x = 2', '=set', 't', env)()
+            local f, message = load('x =')
+            local g, message2 = load('return 1', '=text', 'b')
+            return sum(2), env.x, x, f, message, g, message2, load(binary)()");
+        assert!(results == vec![
+            SyxValue::Integer(3), SyxValue::Integer(2), SyxValue::Nil, SyxValue::Nil,
+            SyxValue::from("[string \"x =\"]:1: unexpected symbol near <eof>"), SyxValue::Nil,
+            SyxValue::from("attempt to load a text chunk (mode is 'b')"), SyxValue::Integer(7),
+        ]);
+        let results = run(&mut state, b"
+            local f = loadfile(path, 't', {x = 'env'})
+            local a, b = f(10)
+            local c, d = dofile(path)
+            return a, b, c, d, select(2, loadfile(path .. '.missing'))");
+        let missing = format!("cannot open {}.missing: No such file or directory", path);
+        assert!(results == vec![
+            SyxValue::Integer(11), SyxValue::from("env"), SyxValue::Integer(2), SyxValue::Nil,
+            SyxValue::from(missing.as_str()),
+        ]);
+
+        // the host's mode applies on top of the script's, which errors name
+        state.set_load_mode("t");
+        let results = run(&mut state, b"local f, message = load(binary) \
+                                         return f, message, loadstring('return 1')(), \
+                                             select(2, load('return 1', 'n', 'x'))");
+        assert!(results == vec![
+            SyxValue::Nil, SyxValue::from("attempt to load a binary chunk (mode is 'bt')"),
+            SyxValue::Integer(1), SyxValue::from("attempt to load a text chunk (mode is 'x')"),
+        ]);
+        fs::remove_file(&path).unwrap();
+    }
+}
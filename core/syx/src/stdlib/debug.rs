@@ -0,0 +1,406 @@
+// Debug library
+//
+// Introspection of running functions through the debug tables of their
+// Protos (`lineinfo`, `locvars` and upvalue names), and hooks through the
+// state's hook (see hook.rs). Stack levels count Lua functions only, level 1
+// being the one that called into the library: native functions have no
+// frames of their own. There is only ever the running thread to look at, so
+// the optional thread argument of the Lua versions is not taken.
+//
+// Consult the versioned ldblib.c for more information.
+
+use std::sync::Arc;
+
+use super::super::debug::{current_line, local_name, short_source, LocalSlot};
+use super::super::errors::*;
+use super::super::hook::{HookEvent, HookMask};
+use super::super::object::{FunctionRef, Proto, SyxInteger, SyxValue, TableRef, UpvalRef};
+use super::super::state::SyxState;
+use super::super::vm::bad_argument;
+use super::{check_any, check_integer, new_lib, opt_integer, opt_string, set_field, type_error};
+
+pub fn open(state: &mut SyxState) {
+    new_lib(state, "debug", &[
+        ("getinfo", debug_getinfo),
+        ("getlocal", debug_getlocal),
+        ("setlocal", debug_setlocal),
+        ("getupvalue", debug_getupvalue),
+        ("setupvalue", debug_setupvalue),
+        ("traceback", debug_traceback),
+        ("sethook", debug_sethook),
+    ]);
+}
+
+// What getinfo describes: a Lua function, running in frame `frame` if it is
+// on the stack, or a native one
+enum Subject {
+    Lua { closure: FunctionRef, proto: Arc<Proto>, frame: Option<usize> },
+    Native(Option<SyxValue>),
+}
+
+fn info_lua(state: &mut SyxState, info: TableRef, option: u8, closure: FunctionRef,
+            proto: &Proto, frame: Option<usize>) {
+    match option {
+        b'S' => {
+            let source = SyxValue::String(state.intern(proto.source.as_bytes()));
+            set_field(state, info, "source", source);
+            let short_src = SyxValue::String(state.intern(short_source(&proto.source).as_bytes()));
+            set_field(state, info, "short_src", short_src);
+            set_field(state, info, "linedefined", SyxValue::Integer(proto.linedefined as SyxInteger));
+            set_field(state, info, "lastlinedefined",
+                      SyxValue::Integer(proto.lastlinedefined as SyxInteger));
+            let what = if proto.linedefined == 0 { "main" } else { "Lua" };
+            set_field(state, info, "what", SyxValue::from(what));
+        }
+        b'l' => {
+            let line = frame
+                .and_then(|index| current_line(proto, state.frames[index].pc))
+                .unwrap_or(-1);
+            set_field(state, info, "currentline", SyxValue::Integer(line as SyxInteger));
+        }
+        b'u' => {
+            let nups = state.closure(closure).upvalues.len();
+            set_field(state, info, "nups", SyxValue::Integer(nups as SyxInteger));
+            set_field(state, info, "nparams", SyxValue::Integer(proto.numparams as SyxInteger));
+            set_field(state, info, "isvararg", SyxValue::Bool(proto.is_vararg != 0));
+        }
+        b'n' => {
+            if let Some((kind, name)) = frame.and_then(|index| state.frame_name(index)) {
+                let name = SyxValue::String(state.intern(name.as_bytes()));
+                set_field(state, info, "name", name);
+                set_field(state, info, "namewhat", SyxValue::from(kind));
+            } else {
+                set_field(state, info, "namewhat", SyxValue::from(""));
+            }
+        }
+        b't' => {
+            let tailcall = frame.is_some_and(|index| state.frames[index].tailcall);
+            set_field(state, info, "istailcall", SyxValue::Bool(tailcall));
+        }
+        b'L' => {
+            let lines = state.new_table(0, proto.lineinfo.len());
+            for &line in &proto.lineinfo {
+                state.table_mut(lines).set_int(line as SyxInteger, SyxValue::Bool(true));
+            }
+            set_field(state, info, "activelines", SyxValue::Table(lines));
+        }
+        b'f' => set_field(state, info, "func", SyxValue::Function(closure)),
+        _ => {}
+    }
+}
+
+fn info_native(state: &mut SyxState, info: TableRef, option: u8, function: &Option<SyxValue>) {
+    match option {
+        b'S' => {
+            set_field(state, info, "source", SyxValue::from("=[C]"));
+            set_field(state, info, "short_src", SyxValue::from("[C]"));
+            set_field(state, info, "linedefined", SyxValue::Integer(-1));
+            set_field(state, info, "lastlinedefined", SyxValue::Integer(-1));
+            set_field(state, info, "what", SyxValue::from("C"));
+        }
+        b'l' => set_field(state, info, "currentline", SyxValue::Integer(-1)),
+        b'u' => {
+            set_field(state, info, "nups", SyxValue::Integer(0));
+            set_field(state, info, "nparams", SyxValue::Integer(0));
+            set_field(state, info, "isvararg", SyxValue::Bool(true));
+        }
+        b'n' => set_field(state, info, "namewhat", SyxValue::from("")),
+        b't' => set_field(state, info, "istailcall", SyxValue::Bool(false)),
+        b'f' => {
+            if let Some(ref function) = *function {
+                set_field(state, info, "func", function.clone());
+            }
+        }
+        _ => {}
+    }
+}
+
+// debug.getinfo(f [, what]), f being a function or a stack level
+fn debug_getinfo(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let what = opt_string(&args, 2, "getinfo", "flnStu")?;
+    if what.iter().any(|c| !b"SlnutLf".contains(c)) {
+        return bad_argument(2, "getinfo", "invalid option");
+    }
+    let subject = match args.first() {
+        Some(&SyxValue::Function(closure)) => {
+            let proto = state.closure(closure).proto.clone();
+            Subject::Lua { closure, proto, frame: None }
+        }
+        Some(function @ SyxValue::Native(_)) | Some(function @ SyxValue::NativeClosure(_)) => {
+            Subject::Native(Some(function.clone()))
+        }
+        Some(SyxValue::Integer(_)) | Some(SyxValue::Number(_)) => {
+            let level = check_integer(&args, 1, "getinfo")?;
+            if level == 0 {
+                // getinfo itself
+                Subject::Native(None)
+            } else {
+                match state.frame_at(level.max(0) as usize) {
+                    Some(index) => {
+                        let ci = &state.frames[index];
+                        Subject::Lua { closure: ci.closure, proto: ci.proto.clone(),
+                                       frame: Some(index) }
+                    }
+                    None => return Ok(vec![SyxValue::Nil]),
+                }
+            }
+        }
+        _ => return bad_argument(1, "getinfo", "function or level expected"),
+    };
+    let info = state.new_table(0, 16);
+    for &option in what.iter() {
+        match subject {
+            Subject::Lua { closure, ref proto, frame } => {
+                info_lua(state, info, option, closure, proto, frame)
+            }
+            Subject::Native(ref function) => info_native(state, info, option, function),
+        }
+    }
+    Ok(vec![SyxValue::Table(info)])
+}
+
+// frame of stack level argument `n`, None for level 0 (the library function
+// itself, which has no locals)
+fn check_level(state: &SyxState, args: &[SyxValue], n: usize, name: &str)
+    -> Result<Option<usize>>
+{
+    let level = check_integer(args, n, name)?;
+    if level == 0 {
+        return Ok(None);
+    }
+    match state.frame_at(level.max(0) as usize) {
+        Some(index) => Ok(Some(index)),
+        None => bad_argument(n, name, "level out of range"),
+    }
+}
+
+// debug.getlocal(level, n) or debug.getlocal(f, n) for parameter names
+fn debug_getlocal(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let n = check_integer(&args, 2, "getlocal")?;
+    match args.first() {
+        Some(&SyxValue::Function(closure)) => {
+            let proto = state.closure(closure).proto.clone();
+            let name = match (n as usize).checked_sub(1) {
+                Some(register) if n > 0 => local_name(&proto, register, 0),
+                _ => None,
+            };
+            return Ok(vec![match name {
+                Some(name) => SyxValue::String(state.intern(name.as_bytes())),
+                None => SyxValue::Nil,
+            }]);
+        }
+        Some(SyxValue::Native(_)) | Some(SyxValue::NativeClosure(_)) => {
+            return Ok(vec![SyxValue::Nil]);
+        }
+        _ => {}
+    }
+    let local = match check_level(state, &args, 1, "getlocal")? {
+        Some(index) => state.find_local(index, n).map(|local| (index, local)),
+        None => None,
+    };
+    match local {
+        Some((index, (name, slot))) => {
+            let value = match slot {
+                LocalSlot::Stack(i) => state.stack[i].clone(),
+                LocalSlot::Vararg(i) => state.frames[index].varargs[i].clone(),
+            };
+            Ok(vec![SyxValue::String(state.intern(name.as_bytes())), value])
+        }
+        None => Ok(vec![SyxValue::Nil]),
+    }
+}
+
+// debug.setlocal(level, n, value)
+fn debug_setlocal(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let n = check_integer(&args, 2, "setlocal")?;
+    let value = check_any(&args, 3, "setlocal")?;
+    let local = match check_level(state, &args, 1, "setlocal")? {
+        Some(index) => state.find_local(index, n).map(|local| (index, local)),
+        None => None,
+    };
+    match local {
+        Some((index, (name, slot))) => {
+            match slot {
+                LocalSlot::Stack(i) => state.stack[i] = value,
+                LocalSlot::Vararg(i) => state.frames[index].varargs[i] = value,
+            }
+            Ok(vec![SyxValue::String(state.intern(name.as_bytes()))])
+        }
+        None => Ok(vec![SyxValue::Nil]),
+    }
+}
+
+// name of upvalue `n` of function argument 1 and the upvalue itself, if it
+// has one
+fn find_upvalue(state: &SyxState, args: &[SyxValue], name: &str)
+    -> Result<Option<(String, UpvalRef)>>
+{
+    let n = check_integer(args, 2, name)?;
+    let closure = match args.first() {
+        Some(&SyxValue::Function(closure)) => closure,
+        Some(SyxValue::Native(_)) | Some(SyxValue::NativeClosure(_)) => return Ok(None),
+        _ => return type_error(args, 1, name, "function"),
+    };
+    let function = state.closure(closure);
+    let i = match (n as usize).checked_sub(1) {
+        Some(i) if n > 0 && i < function.upvalues.len() => i,
+        _ => return Ok(None),
+    };
+    let upvalue_name = match function.proto.upvalues.get(i) {
+        Some(upvalue) if !upvalue.name.is_empty() => upvalue.name.to_string(),
+        _ => "(*no name)".to_owned(),
+    };
+    Ok(Some((upvalue_name, function.upvalues[i])))
+}
+
+// debug.getupvalue(f, n)
+fn debug_getupvalue(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    match find_upvalue(state, &args, "getupvalue")? {
+        Some((name, upvalue)) => {
+            let value = state.get_upvalue(upvalue);
+            Ok(vec![SyxValue::String(state.intern(name.as_bytes())), value])
+        }
+        None => Ok(vec![]),
+    }
+}
+
+// debug.setupvalue(f, n, value)
+fn debug_setupvalue(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let value = check_any(&args, 3, "setupvalue")?;
+    match find_upvalue(state, &args, "setupvalue")? {
+        Some((name, upvalue)) => {
+            state.set_upvalue(upvalue, value);
+            Ok(vec![SyxValue::String(state.intern(name.as_bytes()))])
+        }
+        None => Ok(vec![]),
+    }
+}
+
+// debug.traceback([message [, level]]); a message that is not a string is
+// returned untouched
+fn debug_traceback(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let message = match args.first() {
+        None | Some(SyxValue::Nil) => None,
+        Some(SyxValue::String(s)) => Some(s.to_string()),
+        Some(&SyxValue::Integer(i)) => Some(i.to_string()),
+        Some(&SyxValue::Number(x)) => Some(x.to_string()),
+        Some(value) => return Ok(vec![value.clone()]),
+    };
+    let level = opt_integer(&args, 2, "traceback", 1)?;
+    let traceback = state.traceback(message.as_deref(), (level - 1).max(0) as usize);
+    Ok(vec![SyxValue::String(state.intern(traceback.as_bytes()))])
+}
+
+// debug.sethook([hook, mask [, count]]), mask holding "c" for calls, "r"
+// for returns and "l" for lines; without a hook any hook is removed
+fn debug_sethook(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let hook = match args.first() {
+        None | Some(SyxValue::Nil) => {
+            state.remove_hook();
+            state.lua_hook = SyxValue::Nil;
+            return Ok(vec![]);
+        }
+        Some(hook) if hook.is_function() => hook.clone(),
+        _ => return type_error(&args, 1, "sethook", "function"),
+    };
+    let events = opt_string(&args, 2, "sethook", "")?;
+    let count = opt_integer(&args, 3, "sethook", 0)?;
+    let mask = HookMask {
+        call: events.contains(&b'c'),
+        ret: events.contains(&b'r'),
+        line: events.contains(&b'l'),
+        count: count.max(0) as usize,
+    };
+    state.lua_hook = hook;
+    state.set_hook(mask, |state, event| {
+        let (name, line) = match event {
+            HookEvent::Call => ("call", None),
+            HookEvent::TailCall => ("tail call", None),
+            HookEvent::Return => ("return", None),
+            HookEvent::Line(line) => ("line", Some(line)),
+            HookEvent::Count => ("count", None),
+        };
+        let mut args = vec![SyxValue::from(name)];
+        args.extend(line.map(|line| SyxValue::Integer(line as SyxInteger)));
+        let hook = state.lua_hook.clone();
+        state.call_value(hook, args).map(|_| ())
+    });
+    if mask.is_empty() {
+        state.lua_hook = SyxValue::Nil;
+    }
+    Ok(vec![])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::super::super::compiler::parse;
+    use super::super::super::object::{MultiValue, SyxValue};
+    use super::super::super::state::SyxState;
+    use super::super::open_libs;
+
+    fn run(source: &str) -> MultiValue {
+        let mut state = SyxState::new();
+        open_libs(&mut state);
+        let proto = parse(source.as_bytes(), "@test.lua").unwrap();
+        state.call(Arc::new(proto), Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn test_getinfo() {
+        let results = run("
+            local function f()
+                local info = debug.getinfo(1)
+                return info.currentline, info.short_src, info.what, info.name, info.namewhat
+            end
+            local line, src, what, name, namewhat = f()
+            local main = debug.getinfo(1, 'S')
+            return line, src, what, name, namewhat, main.what, debug.getinfo(print).what,
+                   debug.getinfo(50)
+        ");
+        assert!(results[0] == SyxValue::Integer(3));
+        assert!(results[1] == SyxValue::from("test.lua"));
+        assert!(results[2] == SyxValue::from("Lua"));
+        assert!(results[3] == SyxValue::from("f"));
+        assert!(results[4] == SyxValue::from("local"));
+        assert!(results[5] == SyxValue::from("main"));
+        assert!(results[6] == SyxValue::from("C"));
+        assert!(results[7] == SyxValue::Nil);
+    }
+
+    #[test]
+    fn test_locals_and_upvalues() {
+        let results = run("
+            local a, b = 1, 2
+            debug.setlocal(1, 2, 20)
+            local name, value = debug.getlocal(1, 1)
+            local function f(x, y) return a end
+            debug.setupvalue(f, 1, 10)
+            local upname, upvalue = debug.getupvalue(f, 1)
+            return name, value, b, debug.getlocal(f, 2), upname, upvalue, a
+        ");
+        assert!(results[0] == SyxValue::from("a"));
+        assert!(results[1] == SyxValue::Integer(1));
+        assert!(results[2] == SyxValue::Integer(20));
+        assert!(results[3] == SyxValue::from("y"));
+        assert!(results[4] == SyxValue::from("a"));
+        assert!(results[5] == SyxValue::Integer(10));
+        assert!(results[6] == SyxValue::Integer(10));
+    }
+
+    #[test]
+    fn test_traceback_and_sethook() {
+        let results = run("
+            local lines = {}
+            debug.sethook(function(event, line) lines[#lines + 1] = line end, 'l')
+            local x = 1
+            debug.sethook()
+            local function f() return debug.traceback('oops') end
+            return #lines, f()
+        ");
+        assert!(results[0] == SyxValue::Integer(2));
+        assert!(results[1] == SyxValue::from(
+            "oops\nstack traceback:\n\ttest.lua:6: in local 'f'\n\ttest.lua:7: in main chunk"));
+    }
+}
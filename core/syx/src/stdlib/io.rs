@@ -0,0 +1,922 @@
+// IO library
+//
+// Every file the library touches comes from the IoBackend of the state, so an
+// embedder can hand scripts the real filesystem (NativeIo, the default),
+// nothing at all (NoIo, the default on wasm32-unknown-unknown, which has no
+// filesystem), or a virtual one of its own. Files are tables with the FILE*
+// metatable; their streams are kept by the state, keyed by that table, until
+// they are closed. Open files are never collected, so a script should close
+// what it opens.
+//
+// Consult the versioned liolib.c for more information.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+
+use super::super::errors::*;
+use super::super::object::{string_to_number, SyxInteger, SyxValue, TableRef};
+use super::super::state::SyxState;
+use super::super::vm::{bad_argument, runtime_error};
+use super::{check_any, check_string, new_lib, set_field, tostring, type_error};
+
+const BUFFER_SIZE: usize = 8 * 1024;
+const MAXNUMERAL: usize = 200; // longest numeral read with "n"
+
+// An open file, as the backend sees it. Streams opened for one direction
+// only should fail the other.
+pub trait IoStream: Send {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize>;
+    fn write(&mut self, data: &[u8]) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Where files come from. `mode` is as for C's fopen: "r", "w" or "a",
+// maybe followed by "+", and any number of "b"s.
+pub trait IoBackend: Send {
+    fn open(&mut self, path: &str, mode: &str) -> io::Result<Box<dyn IoStream>>;
+    fn stdin(&mut self) -> io::Result<Box<dyn IoStream>>;
+    fn stdout(&mut self) -> io::Result<Box<dyn IoStream>>;
+    fn stderr(&mut self) -> io::Result<Box<dyn IoStream>>;
+}
+
+fn bad_descriptor<T>() -> io::Result<T> {
+    Err(io::Error::other("Bad file descriptor"))
+}
+
+impl IoStream for fs::File {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        Read::read(self, buffer)
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.write_all(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Write::flush(self)
+    }
+}
+
+struct Stdin;
+struct Stdout;
+struct Stderr;
+
+impl IoStream for Stdin {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        io::stdin().read(buffer)
+    }
+
+    fn write(&mut self, _: &[u8]) -> io::Result<()> {
+        bad_descriptor()
+    }
+}
+
+impl IoStream for Stdout {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        bad_descriptor()
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        io::stdout().write_all(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+impl IoStream for Stderr {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        bad_descriptor()
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        io::stderr().write_all(data)
+    }
+}
+
+// The process's own filesystem and standard streams
+pub struct NativeIo;
+
+impl IoBackend for NativeIo {
+    fn open(&mut self, path: &str, mode: &str) -> io::Result<Box<dyn IoStream>> {
+        let mut options = fs::OpenOptions::new();
+        let update = mode.contains('+');
+        match mode.as_bytes()[0] {
+            b'r' => options.read(true).write(update),
+            b'w' => options.write(true).create(true).truncate(true).read(update),
+            _ => options.append(true).create(true).read(update),
+        };
+        Ok(Box::new(options.open(path)?))
+    }
+
+    fn stdin(&mut self) -> io::Result<Box<dyn IoStream>> {
+        Ok(Box::new(Stdin))
+    }
+
+    fn stdout(&mut self) -> io::Result<Box<dyn IoStream>> {
+        Ok(Box::new(Stdout))
+    }
+
+    fn stderr(&mut self) -> io::Result<Box<dyn IoStream>> {
+        Ok(Box::new(Stderr))
+    }
+}
+
+// No files at all, not even the standard streams
+pub struct NoIo;
+
+impl NoIo {
+    fn denied<T>() -> io::Result<T> {
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, "Permission denied"))
+    }
+}
+
+impl IoBackend for NoIo {
+    fn open(&mut self, _: &str, _: &str) -> io::Result<Box<dyn IoStream>> {
+        NoIo::denied()
+    }
+
+    fn stdin(&mut self) -> io::Result<Box<dyn IoStream>> {
+        NoIo::denied()
+    }
+
+    fn stdout(&mut self) -> io::Result<Box<dyn IoStream>> {
+        NoIo::denied()
+    }
+
+    fn stderr(&mut self) -> io::Result<Box<dyn IoStream>> {
+        NoIo::denied()
+    }
+}
+
+// An open file and what has been read ahead from it
+struct File {
+    stream: Box<dyn IoStream>,
+    buffer: Vec<u8>,
+    start: usize,   // first unread byte of the buffer
+    standard: bool, // stdin, stdout or stderr, which stay open
+}
+
+impl File {
+    fn new(stream: Box<dyn IoStream>, standard: bool) -> File {
+        File { stream, buffer: Vec::new(), start: 0, standard }
+    }
+
+    // make sure there is something unread in the buffer, false at the end
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.start < self.buffer.len() {
+            return Ok(true);
+        }
+        self.buffer.resize(BUFFER_SIZE, 0);
+        let count = self.stream.read(&mut self.buffer)?;
+        self.buffer.truncate(count);
+        self.start = 0;
+        Ok(count > 0)
+    }
+
+    fn peek(&mut self) -> io::Result<Option<u8>> {
+        Ok(if self.fill()? { Some(self.buffer[self.start]) } else { None })
+    }
+
+    fn take(&mut self, count: usize, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.buffer[self.start..self.start + count]);
+        self.start += count;
+    }
+
+    // the next line, None at the end of the file
+    fn read_line(&mut self, keep_newline: bool) -> io::Result<Option<Vec<u8>>> {
+        let mut line = Vec::new();
+        let mut any = false;
+        while self.fill()? {
+            any = true;
+            let unread = &self.buffer[self.start..];
+            match unread.iter().position(|&b| b == b'\n') {
+                Some(i) => {
+                    self.take(i, &mut line);
+                    self.start += 1;
+                    if keep_newline {
+                        line.push(b'\n');
+                    }
+                    return Ok(Some(line));
+                }
+                None => {
+                    let count = unread.len();
+                    self.take(count, &mut line);
+                }
+            }
+        }
+        Ok(if any { Some(line) } else { None })
+    }
+
+    // up to `count` bytes, None at the end of the file
+    fn read_count(&mut self, count: usize) -> io::Result<Option<Vec<u8>>> {
+        if count == 0 {
+            // tests for the end of the file
+            return Ok(if self.fill()? { Some(Vec::new()) } else { None });
+        }
+        let mut out = Vec::new();
+        while out.len() < count && self.fill()? {
+            let available = (self.buffer.len() - self.start).min(count - out.len());
+            self.take(available, &mut out);
+        }
+        Ok(if out.is_empty() { None } else { Some(out) })
+    }
+
+    fn read_all(&mut self) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        while self.fill()? {
+            let count = self.buffer.len() - self.start;
+            self.take(count, &mut out);
+        }
+        Ok(out)
+    }
+
+    // accept the next byte if it is one of `set`
+    fn accept(&mut self, set: &[u8], numeral: &mut Vec<u8>) -> io::Result<bool> {
+        match self.peek()? {
+            Some(b) if set.contains(&b) && numeral.len() < MAXNUMERAL => {
+                numeral.push(b);
+                self.start += 1;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn accept_digits(&mut self, hex: bool, numeral: &mut Vec<u8>) -> io::Result<usize> {
+        let digits: &[u8] = if hex { b"0123456789abcdefABCDEF" } else { b"0123456789" };
+        let mut count = 0;
+        while self.accept(digits, numeral)? {
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    // The longest prefix of the input that looks like a numeral, as
+    // converted to a number, or None if it is not one
+    fn read_number(&mut self) -> io::Result<Option<SyxValue>> {
+        while let Some(b) = self.peek()? {
+            if !b.is_ascii_whitespace() && b != b'\x0b' {
+                break;
+            }
+            self.start += 1;
+        }
+        let mut numeral = Vec::new();
+        self.accept(b"+-", &mut numeral)?;
+        let mut hex = false;
+        let mut count = 0;
+        if self.accept(b"0", &mut numeral)? {
+            hex = self.accept(b"xX", &mut numeral)?;
+            count = if hex { 0 } else { 1 };
+        }
+        count += self.accept_digits(hex, &mut numeral)?;
+        if self.accept(b".", &mut numeral)? {
+            count += self.accept_digits(hex, &mut numeral)?;
+        }
+        let exponent: &[u8] = if hex { b"pP" } else { b"eE" };
+        if count > 0 && self.accept(exponent, &mut numeral)? {
+            self.accept(b"+-", &mut numeral)?;
+            self.accept_digits(false, &mut numeral)?;
+        }
+        Ok(string_to_number(&numeral))
+    }
+}
+
+// Everything the library keeps per state
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+fn default_backend() -> Box<dyn IoBackend> {
+    Box::new(NativeIo)
+}
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+fn default_backend() -> Box<dyn IoBackend> {
+    Box::new(NoIo)
+}
+
+pub(crate) struct IoState {
+    backend: Box<dyn IoBackend>,
+    files: HashMap<TableRef, File>, // open files by their handle
+    metatable: Option<TableRef>,    // of every file handle
+    input: Option<TableRef>,        // default input and output files
+    output: Option<TableRef>,
+}
+
+impl IoState {
+    pub(crate) fn new() -> IoState {
+        IoState {
+            backend: default_backend(),
+            files: HashMap::new(),
+            metatable: None,
+            input: None,
+            output: None,
+        }
+    }
+
+    // Write `data` to the standard output of the backend, as print does,
+    // ignoring any error
+    pub(crate) fn print(&mut self, data: &[u8]) {
+        if let Ok(mut stream) = self.backend.stdout() {
+            let _ = stream.write(data).and_then(|_| stream.flush());
+        }
+    }
+
+    // Whether the backend lets `path` be opened for reading
+    pub(crate) fn readable(&mut self, path: &str) -> bool {
+        self.backend.open(path, "r").is_ok()
+    }
+
+    // The whole contents of the file at `path`, read through the backend
+    pub(crate) fn read_file(&mut self, path: &str) -> io::Result<Vec<u8>> {
+        let stream = self.backend.open(path, "r")?;
+        read_all(stream)
+    }
+
+    // everything left on the standard input of the backend
+    pub(crate) fn read_stdin(&mut self) -> io::Result<Vec<u8>> {
+        let stream = self.backend.stdin()?;
+        read_all(stream)
+    }
+
+    // tables the collector must keep: open files and the file metatable
+    pub(crate) fn roots(&self) -> impl Iterator<Item = TableRef> + '_ {
+        self.files.keys().cloned()
+            .chain(self.metatable)
+            .chain(self.input)
+            .chain(self.output)
+    }
+}
+
+impl SyxState {
+    // Replace where the io library gets files from. Files already open
+    // keep working.
+    pub fn set_io_backend(&mut self, backend: Box<dyn IoBackend>) {
+        self.io.backend = backend;
+    }
+
+    // a handle for `stream`
+    fn new_file(&mut self, stream: Box<dyn IoStream>, standard: bool) -> TableRef {
+        let handle = self.new_table(0, 0);
+        let metatable = self.io.metatable;
+        self.table_mut(handle).set_metatable(metatable);
+        self.io.files.insert(handle, File::new(stream, standard));
+        handle
+    }
+}
+
+pub fn open(state: &mut SyxState) {
+    let lib = new_lib(state, "io", &[
+        ("open", io_open),
+        ("close", io_close),
+        ("read", io_read),
+        ("write", io_write),
+        ("lines", io_lines),
+        ("input", io_input),
+        ("output", io_output),
+        ("type", io_type),
+    ]);
+    let methods = state.new_table(0, 5);
+    for &(name, function) in &[
+        ("read", file_read as super::super::object::NativeFunction),
+        ("write", file_write),
+        ("lines", file_lines),
+        ("close", file_close),
+        ("flush", file_flush),
+    ] {
+        set_field(state, methods, name, SyxValue::Native(function));
+    }
+    let metatable = state.new_table(0, 3);
+    set_field(state, metatable, "__index", SyxValue::Table(methods));
+    set_field(state, metatable, "__name", SyxValue::from("FILE*"));
+    set_field(state, metatable, "__tostring", SyxValue::Native(file_tostring));
+    state.io.metatable = Some(metatable);
+
+    // the standard streams the backend allows
+    if let Ok(stream) = state.io.backend.stdin() {
+        let handle = state.new_file(stream, true);
+        set_field(state, lib, "stdin", SyxValue::Table(handle));
+        state.io.input = Some(handle);
+    }
+    if let Ok(stream) = state.io.backend.stdout() {
+        let handle = state.new_file(stream, true);
+        set_field(state, lib, "stdout", SyxValue::Table(handle));
+        state.io.output = Some(handle);
+    }
+    if let Ok(stream) = state.io.backend.stderr() {
+        let handle = state.new_file(stream, true);
+        set_field(state, lib, "stderr", SyxValue::Table(handle));
+    }
+}
+
+// "path: message" for `error`, as strerror words it
+// whatever is left to read from `stream`
+fn read_all(mut stream: Box<dyn IoStream>) -> io::Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    let mut buffer = [0; BUFFER_SIZE];
+    loop {
+        match stream.read(&mut buffer)? {
+            0 => return Ok(contents),
+            n => contents.extend_from_slice(&buffer[..n]),
+        }
+    }
+}
+
+pub(crate) fn error_message(error: &io::Error, path: Option<&str>) -> String {
+    let mut message = error.to_string();
+    // "No such file or directory (os error 2)" reads better without the code
+    if let Some(i) = message.find(" (os error") {
+        message.truncate(i);
+    }
+    match path {
+        Some(path) => format!("{}: {}", path, message),
+        None => message,
+    }
+}
+
+// results of a failed operation: nil, a message, and the error number
+pub(crate) fn failure(state: &mut SyxState, error: &io::Error, path: Option<&str>)
+    -> Result<Vec<SyxValue>>
+{
+    let message = error_message(error, path);
+    Ok(vec![
+        SyxValue::Nil,
+        SyxValue::String(state.intern(message.as_bytes())),
+        SyxValue::Integer(error.raw_os_error().unwrap_or(0) as SyxInteger),
+    ])
+}
+
+// argument `n` as an open file handle
+fn check_file(state: &SyxState, args: &[SyxValue], n: usize, name: &str) -> Result<TableRef> {
+    match args.get(n - 1) {
+        Some(&SyxValue::Table(t)) if state.table(t).metatable() == state.io.metatable => {
+            if state.io.files.contains_key(&t) {
+                Ok(t)
+            } else {
+                runtime_error("attempt to use a closed file".to_owned())
+            }
+        }
+        _ => type_error(args, n, name, "FILE*"),
+    }
+}
+
+fn file_mut(state: &mut SyxState, handle: TableRef) -> &mut File {
+    state.io.files.get_mut(&handle).expect("file handles are checked first")
+}
+
+// default input or output file, which must still be open
+fn default_file(state: &SyxState, output: bool) -> Result<TableRef> {
+    let (handle, kind) = if output {
+        (state.io.output, "output")
+    } else {
+        (state.io.input, "input")
+    };
+    match handle {
+        Some(handle) if state.io.files.contains_key(&handle) => Ok(handle),
+        _ => runtime_error(format!("default {} file is closed", kind)),
+    }
+}
+
+// open(filename [, mode])
+fn io_open(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let path = check_string(&args, 1, "open")?.to_string();
+    let mode = match args.get(1) {
+        None | Some(SyxValue::Nil) => "r".to_owned(),
+        _ => check_string(&args, 2, "open")?.to_string(),
+    };
+    if !valid_mode(&mode) {
+        return bad_argument(2, "open", "invalid mode");
+    }
+    match state.io.backend.open(&path, &mode) {
+        Ok(stream) => Ok(vec![SyxValue::Table(state.new_file(stream, false))]),
+        Err(error) => failure(state, &error, Some(&path)),
+    }
+}
+
+// "r", "w" or "a", then maybe "+", then any number of "b"s
+fn valid_mode(mode: &str) -> bool {
+    if !mode.starts_with(['r', 'w', 'a']) {
+        return false;
+    }
+    let rest = &mode[1..];
+    rest.strip_prefix('+').unwrap_or(rest).bytes().all(|b| b == b'b')
+}
+
+fn close_file(state: &mut SyxState, handle: TableRef) -> Result<Vec<SyxValue>> {
+    if state.io.files[&handle].standard {
+        let message = SyxValue::from("cannot close standard file");
+        return Ok(vec![SyxValue::Nil, message]);
+    }
+    let mut file = state.io.files.remove(&handle).expect("file handles are checked first");
+    match file.stream.flush() {
+        Ok(()) => Ok(vec![SyxValue::Bool(true)]),
+        Err(error) => failure(state, &error, None),
+    }
+}
+
+// close([file]), the default output file if there is none
+fn io_close(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let handle = match args.first() {
+        None | Some(SyxValue::Nil) => default_file(state, true)?,
+        _ => check_file(state, &args, 1, "close")?,
+    };
+    close_file(state, handle)
+}
+
+fn file_close(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let handle = check_file(state, &args, 1, "close")?;
+    close_file(state, handle)
+}
+
+// Read from `handle` for each of `formats`, stopping at the first that
+// fails. An empty list of formats reads a line.
+fn read_formats(state: &mut SyxState, handle: TableRef, formats: &[SyxValue], first: usize,
+                name: &str) -> Result<Vec<SyxValue>> {
+    let line = [SyxValue::from("l")];
+    let formats = if formats.is_empty() { &line[..] } else { formats };
+    let mut results = Vec::with_capacity(formats.len());
+    for (i, format) in formats.iter().enumerate() {
+        let n = first + i;
+        let file = file_mut(state, handle);
+        let result = match *format {
+            SyxValue::Integer(_) | SyxValue::Number(_) => match format.coerce_to_integer() {
+                Some(count) => file.read_count(count.max(0) as usize),
+                None => return bad_argument(n, name, "number has no integer representation"),
+            },
+            SyxValue::String(ref s) => match s.iter().find(|&&b| b != b'*') {
+                Some(b'n') => match file.read_number() {
+                    Ok(Some(number)) => {
+                        results.push(number);
+                        continue;
+                    }
+                    Ok(None) => Ok(None),
+                    Err(error) => Err(error),
+                },
+                Some(b'l') => file.read_line(false),
+                Some(b'L') => file.read_line(true),
+                Some(b'a') => file.read_all().map(Some),
+                _ => return bad_argument(n, name, "invalid format"),
+            },
+            _ => return bad_argument(n, name, "invalid format"),
+        };
+        match result {
+            Ok(Some(bytes)) => results.push(SyxValue::String(state.intern(&bytes))),
+            Ok(None) => {
+                results.push(SyxValue::Nil);
+                break;
+            }
+            Err(error) => return failure(state, &error, None),
+        }
+    }
+    Ok(results)
+}
+
+fn io_read(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let handle = default_file(state, false)?;
+    read_formats(state, handle, &args, 1, "read")
+}
+
+fn file_read(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let handle = check_file(state, &args, 1, "read")?;
+    read_formats(state, handle, &args[1..], 2, "read")
+}
+
+// write `values` (strings or numbers) to `handle`, returning the file
+fn write_values(state: &mut SyxState, handle: TableRef, values: &[SyxValue], first: usize)
+    -> Result<Vec<SyxValue>>
+{
+    for (i, value) in values.iter().enumerate() {
+        match *value {
+            SyxValue::String(_) | SyxValue::Integer(_) | SyxValue::Number(_) => {
+                let data = tostring(state, value)?;
+                if let Err(error) = file_mut(state, handle).stream.write(&data) {
+                    return failure(state, &error, None);
+                }
+            }
+            _ => {
+                let message = format!("string expected, got {}", value.type_name());
+                return bad_argument(first + i, "write", &message);
+            }
+        }
+    }
+    Ok(vec![SyxValue::Table(handle)])
+}
+
+fn io_write(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let handle = default_file(state, true)?;
+    write_values(state, handle, &args, 1)
+}
+
+fn file_write(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let handle = check_file(state, &args, 1, "write")?;
+    write_values(state, handle, &args[1..], 2)
+}
+
+fn file_flush(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let handle = check_file(state, &args, 1, "flush")?;
+    match file_mut(state, handle).stream.flush() {
+        Ok(()) => Ok(vec![SyxValue::Table(handle)]),
+        Err(error) => failure(state, &error, None),
+    }
+}
+
+// An iterator reading `formats` from `handle` on each call, closing the file
+// at its end when `close` is set. Like the gmatch iterator, it is a table
+// called through its __call: [1] is the file, [2] whether to close it, and
+// the formats follow.
+fn lines_iterator(state: &mut SyxState, handle: TableRef, formats: &[SyxValue], close: bool)
+    -> Vec<SyxValue>
+{
+    let iterator = state.new_table(formats.len() + 2, 0);
+    let metatable = state.new_table(0, 1);
+    set_field(state, metatable, "__call", SyxValue::Native(lines_next));
+    let table = state.table_mut(iterator);
+    table.set_int(1, SyxValue::Table(handle));
+    table.set_int(2, SyxValue::Bool(close));
+    for (i, format) in formats.iter().enumerate() {
+        table.set_int(i as SyxInteger + 3, format.clone());
+    }
+    table.set_metatable(Some(metatable));
+    vec![SyxValue::Table(iterator)]
+}
+
+fn lines_next(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let (handle, close, formats) = match args.first() {
+        Some(&SyxValue::Table(iterator)) => {
+            let table = state.table(iterator);
+            let formats: Vec<SyxValue> = (3..=table.length()).map(|i| table.get_int(i)).collect();
+            match (table.get_int(1), table.get_int(2)) {
+                (SyxValue::Table(handle), SyxValue::Bool(close)) => (handle, close, formats),
+                _ => return runtime_error("invalid lines state".to_owned()),
+            }
+        }
+        _ => return runtime_error("invalid lines state".to_owned()),
+    };
+    if !state.io.files.contains_key(&handle) {
+        return runtime_error("file is already closed".to_owned());
+    }
+    let results = read_formats(state, handle, &formats, 2, "lines")?;
+    if results.first().is_none_or(SyxValue::is_nil) && close {
+        close_file(state, handle)?;
+    }
+    Ok(results)
+}
+
+// lines([filename, ...]), the lines of a file, or of the default input
+fn io_lines(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    match args.first() {
+        None | Some(SyxValue::Nil) => {
+            let handle = default_file(state, false)?;
+            Ok(lines_iterator(state, handle, args.get(1..).unwrap_or(&[]), false))
+        }
+        _ => {
+            let path = check_string(&args, 1, "lines")?.to_string();
+            let stream = match state.io.backend.open(&path, "r") {
+                Ok(stream) => stream,
+                Err(error) => return runtime_error(error_message(&error, Some(&path))),
+            };
+            let handle = state.new_file(stream, false);
+            Ok(lines_iterator(state, handle, &args[1..], true))
+        }
+    }
+}
+
+fn file_lines(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let handle = check_file(state, &args, 1, "lines")?;
+    Ok(lines_iterator(state, handle, &args[1..], false))
+}
+
+// input([file]) and output([file]): set the default file from a handle or a
+// file name, and return the current one
+fn default_aux(state: &mut SyxState, args: Vec<SyxValue>, output: bool)
+    -> Result<Vec<SyxValue>>
+{
+    let name = if output { "output" } else { "input" };
+    let handle = match args.first() {
+        None | Some(SyxValue::Nil) => None,
+        Some(SyxValue::String(path)) => {
+            let path = path.to_string();
+            let mode = if output { "w" } else { "r" };
+            match state.io.backend.open(&path, mode) {
+                Ok(stream) => Some(state.new_file(stream, false)),
+                Err(error) => return runtime_error(error_message(&error, Some(&path))),
+            }
+        }
+        _ => Some(check_file(state, &args, 1, name)?),
+    };
+    if let Some(handle) = handle {
+        if output {
+            state.io.output = Some(handle);
+        } else {
+            state.io.input = Some(handle);
+        }
+    }
+    let current = if output { state.io.output } else { state.io.input };
+    Ok(vec![current.map_or(SyxValue::Nil, SyxValue::Table)])
+}
+
+fn io_input(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    default_aux(state, args, false)
+}
+
+fn io_output(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    default_aux(state, args, true)
+}
+
+// type(obj), "file", "closed file", or nil for anything else
+fn io_type(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let name = match check_any(&args, 1, "type")? {
+        SyxValue::Table(t) if state.table(t).metatable() == state.io.metatable => {
+            if state.io.files.contains_key(&t) { "file" } else { "closed file" }
+        }
+        _ => return Ok(vec![SyxValue::Nil]),
+    };
+    Ok(vec![SyxValue::String(state.intern(name.as_bytes()))])
+}
+
+fn file_tostring(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let text = match args.first() {
+        Some(&SyxValue::Table(t)) if state.io.files.contains_key(&t) => {
+            format!("file (0x{:08x})", t.0)
+        }
+        _ => "file (closed)".to_owned(),
+    };
+    Ok(vec![SyxValue::String(state.intern(text.as_bytes()))])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    type Files = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+    // files in memory, shared with the test through `files`
+    struct MemoryIo {
+        files: Files,
+    }
+
+    struct MemoryFile {
+        files: Files,
+        path: String,
+        position: usize,
+    }
+
+    impl IoStream for MemoryFile {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            let files = self.files.lock().unwrap();
+            let contents = &files[&self.path][self.position..];
+            let count = contents.len().min(buffer.len());
+            buffer[..count].copy_from_slice(&contents[..count]);
+            self.position += count;
+            Ok(count)
+        }
+
+        fn write(&mut self, data: &[u8]) -> io::Result<()> {
+            self.files.lock().unwrap().get_mut(&self.path).unwrap().extend_from_slice(data);
+            Ok(())
+        }
+    }
+
+    impl IoBackend for MemoryIo {
+        fn open(&mut self, path: &str, mode: &str) -> io::Result<Box<dyn IoStream>> {
+            let mut files = self.files.lock().unwrap();
+            if mode.starts_with('w') {
+                files.insert(path.to_owned(), Vec::new());
+            } else if !files.contains_key(path) {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "No such file or directory"));
+            }
+            let files = self.files.clone();
+            Ok(Box::new(MemoryFile { files, path: path.to_owned(), position: 0 }))
+        }
+
+        fn stdin(&mut self) -> io::Result<Box<dyn IoStream>> {
+            self.open("stdin", "r")
+        }
+
+        fn stdout(&mut self) -> io::Result<Box<dyn IoStream>> {
+            self.open("stdout", "a")
+        }
+
+        fn stderr(&mut self) -> io::Result<Box<dyn IoStream>> {
+            NoIo.stderr()
+        }
+    }
+
+    fn memory_state(files: &[(&str, &str)]) -> (SyxState, Files) {
+        let files: Files = Arc::new(Mutex::new(files.iter()
+            .map(|&(path, contents)| (path.to_owned(), contents.as_bytes().to_vec()))
+            .collect()));
+        let mut state = SyxState::new();
+        state.set_io_backend(Box::new(MemoryIo { files: files.clone() }));
+        open(&mut state);
+        (state, files)
+    }
+
+    fn strings(values: &[&str]) -> Vec<SyxValue> {
+        values.iter().map(|&s| SyxValue::from(s)).collect()
+    }
+
+    #[test]
+    fn test_read_formats() {
+        let (mut state, _) = memory_state(&[
+            ("stdin", ""),
+            ("data", "first line\nsecond\n 42 -0x10 2.5e1 nope\nrest"),
+        ]);
+        let file = io_open(&mut state, strings(&["data"])).unwrap().remove(0);
+        let read = |state: &mut SyxState, formats: Vec<SyxValue>| {
+            let mut args = vec![file.clone()];
+            args.extend(formats);
+            file_read(state, args).unwrap()
+        };
+        assert_eq!(read(&mut state, vec![]), strings(&["first line"]));
+        assert_eq!(read(&mut state, strings(&["L"])), strings(&["second\n"]));
+        assert_eq!(read(&mut state, strings(&["n", "*n", "n"])),
+                   vec![SyxValue::Integer(42), SyxValue::Integer(-16), SyxValue::Number(25.0)]);
+        assert_eq!(read(&mut state, strings(&["n", "l"])), vec![SyxValue::Nil]);
+        assert_eq!(read(&mut state, vec![SyxValue::Integer(3), SyxValue::Integer(0)]),
+                   strings(&["nop", ""]));
+        assert_eq!(read(&mut state, strings(&["a"])), strings(&["e\nrest"]));
+        assert_eq!(read(&mut state, strings(&["a", "l"])), vec![SyxValue::from(""), SyxValue::Nil]);
+        assert_eq!(read(&mut state, vec![SyxValue::Integer(0)]), vec![SyxValue::Nil]);
+        let error = file_read(&mut state, vec![file.clone(), SyxValue::from("x")]).unwrap_err();
+        assert_eq!(error.to_string(), "bad argument #2 to 'read' (invalid format)");
+    }
+
+    #[test]
+    fn test_write_and_close() {
+        let (mut state, files) = memory_state(&[("stdin", ""), ("stdout", "")]);
+        io_write(&mut state, vec![SyxValue::from("x = "), SyxValue::Integer(1)]).unwrap();
+        assert_eq!(files.lock().unwrap()["stdout"], b"x = 1");
+        let file = io_open(&mut state, strings(&["out", "w"])).unwrap().remove(0);
+        let result = file_write(&mut state, vec![file.clone(), SyxValue::from("a\nb")]).unwrap();
+        assert_eq!(result, vec![file.clone()]);
+        assert_eq!(files.lock().unwrap()["out"], b"a\nb");
+        let error = file_write(&mut state, vec![file.clone(), SyxValue::Bool(true)]).unwrap_err();
+        assert_eq!(error.to_string(), "bad argument #2 to 'write' (string expected, got boolean)");
+
+        assert_eq!(io_type(&mut state, vec![file.clone()]).unwrap(), strings(&["file"]));
+        assert_eq!(file_close(&mut state, vec![file.clone()]).unwrap(), vec![SyxValue::Bool(true)]);
+        assert_eq!(io_type(&mut state, vec![file.clone()]).unwrap(), strings(&["closed file"]));
+        let error = file_read(&mut state, vec![file.clone()]).unwrap_err();
+        assert_eq!(error.to_string(), "attempt to use a closed file");
+        let text = tostring(&mut state, &file).unwrap();
+        assert_eq!(text, *b"file (closed)");
+
+        // the standard files stay open
+        let result = io_close(&mut state, vec![]).unwrap();
+        assert_eq!(result, vec![SyxValue::Nil, SyxValue::from("cannot close standard file")]);
+    }
+
+    #[test]
+    fn test_open_errors() {
+        let (mut state, _) = memory_state(&[]);
+        let result = io_open(&mut state, strings(&["missing"])).unwrap();
+        assert_eq!(result, vec![SyxValue::Nil, SyxValue::from("missing: No such file or directory"),
+                                SyxValue::Integer(0)]);
+        let error = io_open(&mut state, strings(&["missing", "rw"])).unwrap_err();
+        assert_eq!(error.to_string(), "bad argument #2 to 'open' (invalid mode)");
+        assert!(valid_mode("r+b") && valid_mode("abb") && !valid_mode("+r") && !valid_mode(""));
+
+        // no standard streams from this backend, so no default files
+        let error = io_read(&mut state, vec![]).unwrap_err();
+        assert_eq!(error.to_string(), "default input file is closed");
+
+        let mut state = SyxState::new();
+        state.set_io_backend(Box::new(NoIo));
+        open(&mut state);
+        let result = io_open(&mut state, strings(&["/etc/passwd"])).unwrap();
+        assert_eq!(result[1], SyxValue::from("/etc/passwd: Permission denied"));
+        let error = io_write(&mut state, strings(&["hi"])).unwrap_err();
+        assert_eq!(error.to_string(), "default output file is closed");
+    }
+
+    #[test]
+    fn test_lines() {
+        let (mut state, _) = memory_state(&[("stdin", "one\ntwo"), ("numbers", "1 2\n3")]);
+        let iterator = io_lines(&mut state, vec![]).unwrap().remove(0);
+        let mut lines = Vec::new();
+        loop {
+            let line = lines_next(&mut state, vec![iterator.clone()]).unwrap().remove(0);
+            if line.is_nil() {
+                break;
+            }
+            lines.push(line);
+        }
+        assert_eq!(lines, strings(&["one", "two"]));
+
+        // a named file is closed once its end is reached
+        let iterator = io_lines(&mut state, strings(&["numbers", "n"])).unwrap().remove(0);
+        let next = |state: &mut SyxState| lines_next(state, vec![iterator.clone()]);
+        for i in 1..4 {
+            assert_eq!(next(&mut state).unwrap(), vec![SyxValue::Integer(i)]);
+        }
+        assert_eq!(next(&mut state).unwrap(), vec![SyxValue::Nil]);
+        assert_eq!(next(&mut state).unwrap_err().to_string(), "file is already closed");
+
+        let error = io_lines(&mut state, strings(&["missing"])).unwrap_err();
+        assert_eq!(error.to_string(), "missing: No such file or directory");
+    }
+}
@@ -0,0 +1,631 @@
+// JSON library
+//
+// json.encode turns a value into JSON text and json.decode reads it back,
+// with `SyxValue::to_json` and `SyxValue::from_json` doing the same from
+// Rust. Tables whose keys are the integers 1 to n become arrays, other
+// tables objects, with number keys written as strings; the empty table is
+// an empty object. Metatables are not consulted. Functions, threads and
+// userdata can't be encoded, nor can NaN and the infinities, tables
+// referring back to themselves, or strings that aren't valid UTF-8.
+// Floats are written as tostring shows them, so with 14 significant digits.
+//
+// Decoding gives arrays as tables counting from 1 and objects as tables
+// with string keys. Numbers without a fraction or exponent become integers
+// when they fit, as numerals do. JSON null becomes `JsonOptions::null`,
+// nil by default, which leaves holes in arrays and drops object fields;
+// scripts get json.null instead, a sentinel table that also encodes as null.
+//
+// `JsonOptions` also decide how arrays with holes are written, and whether
+// object keys are sorted, for output that is the same from run to run.
+//
+// Lua has no json library, so `open_libs` leaves it out, and a script's own
+// global `json` is never shadowed; a host opens it with `open` to offer it.
+//
+// Consult RFC 8259 and lua-cjson's manual for more information.
+
+use super::super::errors::*;
+use super::super::numfmt::{integer_to_string, number_to_string};
+use super::super::object::{string_to_number, SyxInteger, SyxValue, TableRef};
+use super::super::state::SyxState;
+use super::super::vm::bad_argument;
+use super::{new_lib, set_field, type_error};
+
+const MAX_DEPTH: usize = 200; // arrays and objects nested in one another
+
+// past this index, an array more than half holes is excessively sparse
+const SPARSE_SAFE: SyxInteger = 10;
+
+// How tables with positive integer keys but holes between them are written
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SparseArrays {
+    Null,   // as arrays with null in the holes, unless excessively sparse
+    Object, // as objects, with the indices as string keys
+    Error,  // not at all
+}
+
+#[derive(Clone, Debug)]
+pub struct JsonOptions {
+    pub sparse_arrays: SparseArrays,
+    pub sort_keys: bool,
+    pub null: SyxValue, // what null decodes to, and encodes as besides nil
+}
+
+impl Default for JsonOptions {
+    fn default() -> JsonOptions {
+        JsonOptions {
+            sparse_arrays: SparseArrays::Null,
+            sort_keys: false,
+            null: SyxValue::Nil,
+        }
+    }
+}
+
+fn json_error<T>(message: String) -> Result<T> {
+    Err(ErrorKind::JsonError(message).into())
+}
+
+impl SyxValue {
+    pub fn to_json(&self, state: &SyxState, options: &JsonOptions) -> Result<String> {
+        let mut encoder = Encoder { state, options, out: Vec::new(), tables: Vec::new() };
+        encoder.value(self)?;
+        // only valid strings are copied in, everything else is ASCII
+        Ok(String::from_utf8(encoder.out).expect("JSON output is UTF-8"))
+    }
+
+    pub fn from_json(state: &mut SyxState, text: &str, options: &JsonOptions)
+        -> Result<SyxValue>
+    {
+        decode(state, text.as_bytes(), options)
+    }
+}
+
+struct Encoder<'s> {
+    state: &'s SyxState,
+    options: &'s JsonOptions,
+    out: Vec<u8>,
+    tables: Vec<TableRef>, // being written, to catch cycles
+}
+
+impl<'s> Encoder<'s> {
+    fn value(&mut self, value: &SyxValue) -> Result<()> {
+        if value.is_nil() || *value == self.options.null {
+            self.out.extend_from_slice(b"null");
+            return Ok(());
+        }
+        match *value {
+            SyxValue::Bool(b) => self.out.extend_from_slice(if b { b"true" } else { b"false" }),
+            SyxValue::Integer(i) => self.out.extend(integer_to_string(i).into_bytes()),
+            SyxValue::Number(n) if n.is_finite() => {
+                self.out.extend(number_to_string(n).into_bytes())
+            }
+            SyxValue::Number(_) => return json_error("cannot encode NaN or infinity".to_owned()),
+            SyxValue::String(ref s) => self.string(s)?,
+            SyxValue::Table(table) => self.table(table)?,
+            _ => return json_error(format!("cannot encode a {} value", value.type_name())),
+        }
+        Ok(())
+    }
+
+    fn string(&mut self, s: &[u8]) -> Result<()> {
+        if std::str::from_utf8(s).is_err() {
+            return json_error("cannot encode a string that is not valid UTF-8".to_owned());
+        }
+        self.out.push(b'"');
+        for &byte in s {
+            match byte {
+                b'"' => self.out.extend_from_slice(b"\\\""),
+                b'\\' => self.out.extend_from_slice(b"\\\\"),
+                b'\n' => self.out.extend_from_slice(b"\\n"),
+                b'\r' => self.out.extend_from_slice(b"\\r"),
+                b'\t' => self.out.extend_from_slice(b"\\t"),
+                0x08 => self.out.extend_from_slice(b"\\b"),
+                0x0c => self.out.extend_from_slice(b"\\f"),
+                0..=0x1f | 0x7f => self.out.extend(format!("\\u{:04x}", byte).into_bytes()),
+                _ => self.out.push(byte),
+            }
+        }
+        self.out.push(b'"');
+        Ok(())
+    }
+
+    fn table(&mut self, table: TableRef) -> Result<()> {
+        if self.tables.contains(&table) {
+            return json_error("cannot encode a table with a cycle".to_owned());
+        }
+        if self.tables.len() >= MAX_DEPTH {
+            return json_error("cannot encode a table nested this deep".to_owned());
+        }
+        self.tables.push(table);
+        let entries: Vec<(SyxValue, SyxValue)> = self.state.table(table).iter().collect();
+        match self.array_length(&entries)? {
+            Some(len) => self.array(table, len)?,
+            None => self.object(entries)?,
+        }
+        self.tables.pop();
+        Ok(())
+    }
+
+    // The length of the array `entries` are written as, None for an object
+    fn array_length(&self, entries: &[(SyxValue, SyxValue)]) -> Result<Option<SyxInteger>> {
+        let mut max = 0;
+        for (key, _) in entries {
+            match *key {
+                SyxValue::Integer(i) if i >= 1 => max = max.max(i),
+                _ => return Ok(None),
+            }
+        }
+        let count = entries.len() as SyxInteger;
+        if count == 0 {
+            return Ok(None);
+        } else if max == count {
+            return Ok(Some(max));
+        }
+        match self.options.sparse_arrays {
+            SparseArrays::Null if max > SPARSE_SAFE && max / 2 > count => {
+                json_error("cannot encode an excessively sparse array".to_owned())
+            }
+            SparseArrays::Null => Ok(Some(max)),
+            SparseArrays::Object => Ok(None),
+            SparseArrays::Error => json_error("cannot encode a sparse array".to_owned()),
+        }
+    }
+
+    fn array(&mut self, table: TableRef, len: SyxInteger) -> Result<()> {
+        self.out.push(b'[');
+        for i in 1..=len {
+            if i > 1 {
+                self.out.push(b',');
+            }
+            let value = self.state.table(table).get_int(i);
+            self.value(&value)?;
+        }
+        self.out.push(b']');
+        Ok(())
+    }
+
+    fn object(&mut self, entries: Vec<(SyxValue, SyxValue)>) -> Result<()> {
+        let mut fields = Vec::with_capacity(entries.len());
+        for (key, value) in entries {
+            let key = match key {
+                SyxValue::String(ref s) => s.to_vec(),
+                SyxValue::Integer(i) => integer_to_string(i).into_bytes(),
+                SyxValue::Number(n) if n.is_finite() => number_to_string(n).into_bytes(),
+                _ => return json_error(format!("cannot encode a {} key", key.type_name())),
+            };
+            fields.push((key, value));
+        }
+        if self.options.sort_keys {
+            fields.sort_by(|a, b| a.0.cmp(&b.0));
+        }
+        self.out.push(b'{');
+        for (i, (key, value)) in fields.iter().enumerate() {
+            if i > 0 {
+                self.out.push(b',');
+            }
+            self.string(key)?;
+            self.out.push(b':');
+            self.value(value)?;
+        }
+        self.out.push(b'}');
+        Ok(())
+    }
+}
+
+struct Decoder<'a> {
+    state: &'a mut SyxState,
+    options: &'a JsonOptions,
+    text: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+fn decode(state: &mut SyxState, text: &[u8], options: &JsonOptions) -> Result<SyxValue> {
+    let mut decoder = Decoder { state, options, text, pos: 0, depth: 0 };
+    let value = decoder.value()?;
+    decoder.skip_whitespace();
+    if decoder.pos < text.len() {
+        return decoder.unexpected();
+    }
+    Ok(value)
+}
+
+impl<'a> Decoder<'a> {
+    fn error<T>(&self, message: &str) -> Result<T> {
+        json_error(format!("{} at position {}", message, self.pos + 1))
+    }
+
+    fn unexpected<T>(&self) -> Result<T> {
+        match self.text.get(self.pos) {
+            Some(&c) if c.is_ascii_graphic() => {
+                self.error(&format!("unexpected character '{}'", c as char))
+            }
+            Some(_) => self.error("unexpected character"),
+            None => self.error("unexpected end of text"),
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.get(self.pos).cloned()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    // skip `c` after any whitespace, if it is there
+    fn accept(&mut self, c: u8) -> bool {
+        self.skip_whitespace();
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> Result<()> {
+        if self.accept(c) {
+            Ok(())
+        } else {
+            self.unexpected()
+        }
+    }
+
+    fn value(&mut self) -> Result<SyxValue> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{') => self.object(),
+            Some(b'[') => self.array(),
+            Some(b'"') => {
+                let s = self.string()?;
+                Ok(SyxValue::String(self.state.intern(&s)))
+            }
+            Some(b'-') | Some(b'0'..=b'9') => self.number(),
+            Some(b't') => self.literal("true", SyxValue::Bool(true)),
+            Some(b'f') => self.literal("false", SyxValue::Bool(false)),
+            Some(b'n') => self.literal("null", self.options.null.clone()),
+            _ => self.unexpected(),
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: SyxValue) -> Result<SyxValue> {
+        if self.text[self.pos..].starts_with(word.as_bytes()) {
+            self.pos += word.len();
+            Ok(value)
+        } else {
+            self.unexpected()
+        }
+    }
+
+    // -?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][+-]?[0-9]+)?, read as a numeral
+    fn number(&mut self) -> Result<SyxValue> {
+        let start = self.pos;
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+        }
+        if self.peek() == Some(b'0') {
+            self.pos += 1;
+        } else {
+            self.digits()?;
+        }
+        if self.peek() == Some(b'.') {
+            self.pos += 1;
+            self.digits()?;
+        }
+        if let Some(b'e') | Some(b'E') = self.peek() {
+            self.pos += 1;
+            if let Some(b'+') | Some(b'-') = self.peek() {
+                self.pos += 1;
+            }
+            self.digits()?;
+        }
+        Ok(string_to_number(&self.text[start..self.pos]).expect("JSON numbers are numerals"))
+    }
+
+    fn digits(&mut self) -> Result<()> {
+        if !self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            return self.unexpected();
+        }
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        Ok(())
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>> {
+        self.pos += 1; // the opening quote
+        let mut s = Vec::new();
+        loop {
+            match self.peek() {
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    self.pos += 1;
+                    let escaped = match self.peek() {
+                        Some(b'"') => b'"',
+                        Some(b'\\') => b'\\',
+                        Some(b'/') => b'/',
+                        Some(b'b') => 0x08,
+                        Some(b'f') => 0x0c,
+                        Some(b'n') => b'\n',
+                        Some(b'r') => b'\r',
+                        Some(b't') => b'\t',
+                        Some(b'u') => {
+                            let c = self.unicode_escape()?;
+                            s.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                            continue;
+                        }
+                        _ => return self.error("invalid escape"),
+                    };
+                    s.push(escaped);
+                }
+                Some(c) if c >= 0x20 => s.push(c),
+                _ => return self.unexpected(),
+            }
+            self.pos += 1;
+        }
+        self.pos += 1;
+        Ok(s)
+    }
+
+    // \uXXXX at `pos` - 1, two of them for a surrogate pair, leaving `pos`
+    // after the last
+    fn unicode_escape(&mut self) -> Result<char> {
+        let high = self.hex4()?;
+        let code = match high {
+            0xd800..=0xdbff => {
+                if !self.text[self.pos..].starts_with(b"\\u") {
+                    return self.error("unpaired surrogate");
+                }
+                self.pos += 1;
+                let low = self.hex4()?;
+                if !(0xdc00..=0xdfff).contains(&low) {
+                    return self.error("unpaired surrogate");
+                }
+                0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+            }
+            0xdc00..=0xdfff => return self.error("unpaired surrogate"),
+            code => code,
+        };
+        Ok(std::char::from_u32(code).expect("surrogates are paired"))
+    }
+
+    // the four hex digits after the `u` at `pos`
+    fn hex4(&mut self) -> Result<u32> {
+        let digits = self.text.get(self.pos + 1..self.pos + 5)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .filter(|digits| digits.bytes().all(|c| c.is_ascii_hexdigit()));
+        match digits {
+            Some(digits) => {
+                self.pos += 5;
+                Ok(u32::from_str_radix(digits, 16).expect("hex digits"))
+            }
+            None => self.error("invalid unicode escape"),
+        }
+    }
+
+    fn enter(&mut self) -> Result<TableRef> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return self.error("too many nested arrays and objects");
+        }
+        self.pos += 1; // the opening bracket
+        Ok(self.state.new_table(0, 0))
+    }
+
+    fn array(&mut self) -> Result<SyxValue> {
+        let table = self.enter()?;
+        if !self.accept(b']') {
+            let mut i = 1;
+            loop {
+                let value = self.value()?;
+                self.state.table_set(table, SyxValue::Integer(i), value)?;
+                i += 1;
+                if !self.accept(b',') {
+                    break;
+                }
+            }
+            self.expect(b']')?;
+        }
+        self.depth -= 1;
+        Ok(SyxValue::Table(table))
+    }
+
+    fn object(&mut self) -> Result<SyxValue> {
+        let table = self.enter()?;
+        if !self.accept(b'}') {
+            loop {
+                self.skip_whitespace();
+                if self.peek() != Some(b'"') {
+                    return self.unexpected();
+                }
+                let key = self.string()?;
+                let key = SyxValue::String(self.state.intern(&key));
+                self.expect(b':')?;
+                let value = self.value()?;
+                self.state.table_set(table, key, value)?;
+                if !self.accept(b',') {
+                    break;
+                }
+            }
+            self.expect(b'}')?;
+        }
+        self.depth -= 1;
+        Ok(SyxValue::Table(table))
+    }
+}
+
+// Store the library as global `json`, which `open_libs` does not
+pub fn open(state: &mut SyxState) {
+    let lib = new_lib(state, "json", &[]);
+    let null = SyxValue::Table(state.new_table(0, 0));
+    let encode = state.create_closure("encode", vec![null.clone()], {
+        let null = null.clone();
+        move |state, args| {
+            let value = args.check_any(1)?;
+            let options = script_options(state, args.values(), null.clone())?;
+            let json = value.to_json(state, &options)?;
+            Ok(vec![SyxValue::String(state.intern(json.as_bytes()))].into())
+        }
+    });
+    let decode = state.create_closure("decode", vec![null.clone()], {
+        let null = null.clone();
+        move |state, args| {
+            let text = args.check_string(1)?;
+            let options = JsonOptions { null: null.clone(), ..JsonOptions::default() };
+            Ok(vec![self::decode(state, &text, &options)?].into())
+        }
+    });
+    set_field(state, lib, "encode", encode);
+    set_field(state, lib, "decode", decode);
+    set_field(state, lib, "null", null);
+}
+
+// json.encode's options table: sort_keys, and sparse_arrays as one of
+// "null", "object" or "error"
+fn script_options(state: &mut SyxState, args: &[SyxValue], null: SyxValue)
+    -> Result<JsonOptions>
+{
+    let mut options = JsonOptions { null, ..JsonOptions::default() };
+    let table = match args.get(1) {
+        None | Some(SyxValue::Nil) => return Ok(options),
+        Some(&SyxValue::Table(table)) => table,
+        Some(_) => return type_error(args, 2, "encode", "table"),
+    };
+    let field = |state: &mut SyxState, name: &str| {
+        let key = SyxValue::String(state.intern(name.as_bytes()));
+        state.table(table).get(&key)
+    };
+    options.sort_keys = !field(state, "sort_keys").is_falsy();
+    let sparse = field(state, "sparse_arrays");
+    options.sparse_arrays = match sparse {
+        SyxValue::Nil => SparseArrays::Null,
+        SyxValue::String(ref s) if &s[..] == b"null" => SparseArrays::Null,
+        SyxValue::String(ref s) if &s[..] == b"object" => SparseArrays::Object,
+        SyxValue::String(ref s) if &s[..] == b"error" => SparseArrays::Error,
+        _ => return bad_argument(2, "encode", "invalid option 'sparse_arrays'"),
+    };
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::super::testing::run_in;
+    use super::super::open_libs;
+
+    #[test]
+    fn test_encode() {
+        let mut state = SyxState::new();
+        let sorted = JsonOptions { sort_keys: true, ..JsonOptions::default() };
+        let table = state.new_table(0, 0);
+        for (key, value) in [
+            (SyxValue::from("b"), SyxValue::Bool(true)),
+            (SyxValue::from("a"), SyxValue::from("say \"hi\"\n\u{1}é")),
+            (SyxValue::Integer(3), SyxValue::Number(0.5)),
+        ] {
+            state.table_set(table, key, value).unwrap();
+        }
+        assert_eq!(SyxValue::Table(table).to_json(&state, &sorted).unwrap(),
+                   r#"{"3":0.5,"a":"say \"hi\"\n\u0001é","b":true}"#);
+
+        let list = state.new_table(0, 0);
+        for i in 1..4 {
+            state.table_set(list, SyxValue::Integer(i), SyxValue::Integer(i * 10)).unwrap();
+        }
+        state.table_set(table, SyxValue::from("list"), SyxValue::Table(list)).unwrap();
+        state.table_set(list, SyxValue::Integer(2), SyxValue::Nil).unwrap();
+        let encode = |state: &SyxState, sparse_arrays| {
+            let options = JsonOptions { sparse_arrays, sort_keys: true, ..JsonOptions::default() };
+            SyxValue::Table(list).to_json(state, &options).map_err(|e| e.to_string())
+        };
+        assert_eq!(encode(&state, SparseArrays::Null).unwrap(), "[10,null,30]");
+        assert_eq!(encode(&state, SparseArrays::Object).unwrap(), r#"{"1":10,"3":30}"#);
+        assert_eq!(encode(&state, SparseArrays::Error).unwrap_err(),
+                   "cannot encode a sparse array");
+        state.table_set(list, SyxValue::Integer(100), SyxValue::Integer(1)).unwrap();
+        assert_eq!(encode(&state, SparseArrays::Null).unwrap_err(),
+                   "cannot encode an excessively sparse array");
+
+        // the same output every time, whatever order the fields are in
+        state.table_set(list, SyxValue::Integer(100), SyxValue::Nil).unwrap();
+        let empty = SyxValue::Table(state.new_table(0, 0));
+        state.table_set(table, SyxValue::from("empty"), empty).unwrap();
+        assert_eq!(SyxValue::Table(table).to_json(&state, &sorted).unwrap(),
+                   concat!(r#"{"3":0.5,"a":"say \"hi\"\n\u0001é","b":true,"#,
+                           r#""empty":{},"list":[10,null,30]}"#));
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut state = SyxState::new();
+        let options = JsonOptions::default();
+        let text = r#" {"a": [1, -2.5e-1, true, null, "\u00e9\ud83d\ude00\/"],
+                        "b": {}, "c": null} "#;
+        let value = SyxValue::from_json(&mut state, text, &options).unwrap();
+        let table = match value {
+            SyxValue::Table(table) => table,
+            _ => panic!("{:?}", value),
+        };
+        assert_eq!(state.table(table).get(&SyxValue::from("c")), SyxValue::Nil);
+        let a = match state.table(table).get(&SyxValue::from("a")) {
+            SyxValue::Table(a) => a,
+            value => panic!("{:?}", value),
+        };
+        let a = state.table(a);
+        assert_eq!(a.get_int(1), SyxValue::Integer(1));
+        assert_eq!(a.get_int(2), SyxValue::Number(-0.25));
+        assert_eq!(a.get_int(3), SyxValue::Bool(true));
+        assert_eq!(a.get_int(4), SyxValue::Nil);
+        assert_eq!(a.get_int(5), SyxValue::from("é😀/"));
+        // and back again
+        let sorted = JsonOptions { sort_keys: true, ..options.clone() };
+        assert_eq!(value.to_json(&state, &sorted).unwrap(),
+                   r#"{"a":[1,-0.25,true,null,"é😀/"],"b":{}}"#);
+
+        let error = |text: &str| {
+            let mut state = SyxState::new();
+            SyxValue::from_json(&mut state, text, &options).unwrap_err().to_string()
+        };
+        assert_eq!(error("[1, 2"), "unexpected end of text at position 6");
+        assert_eq!(error("[1,]"), "unexpected character ']' at position 4");
+        assert_eq!(error("{a: 1}"), "unexpected character 'a' at position 2");
+        assert_eq!(error("01"), "unexpected character '1' at position 2");
+        assert_eq!(error("1."), "unexpected end of text at position 3");
+        assert_eq!(error("\"\\x\""), "invalid escape at position 3");
+        assert_eq!(error("\"\\ud800\""), "unpaired surrogate at position 8");
+        assert_eq!(error("\"a\nb\""), "unexpected character at position 3");
+        assert_eq!(error("nul"), "unexpected character 'n' at position 1");
+        assert_eq!(error(&"[".repeat(MAX_DEPTH + 1)),
+                   format!("too many nested arrays and objects at position {}", MAX_DEPTH + 1));
+    }
+
+    #[test]
+    fn test_json_library() {
+        let mut state = SyxState::new();
+        open_libs(&mut state);
+        assert!(run_in(&mut state, "return json").unwrap()[0] == SyxValue::Nil);
+        open(&mut state);
+        let results = run_in(&mut state, r#"
+            local t = json.decode('{"list": [1, null, 3], "name": "x"}')
+            local cycle = {}
+            cycle.self = cycle
+            return t.list[2] == json.null, #t.list, t.name,
+                   json.encode({z = 1, a = {json.null, false}}, {sort_keys = true}),
+                   json.encode({[1] = 1, [3] = 3}, {sparse_arrays = 'object', sort_keys = true}),
+                   json.encode('\0'), select(2, pcall(json.encode, cycle)),
+                   select(2, pcall(json.encode, print)),
+                   select(2, pcall(json.encode, 1, {sparse_arrays = 'maybe'}))
+        "#).unwrap();
+        assert_eq!(results, vec![
+            SyxValue::Bool(true), SyxValue::Integer(3), SyxValue::from("x"),
+            SyxValue::from(r#"{"a":[null,false],"z":1}"#),
+            SyxValue::from(r#"{"1":1,"3":3}"#),
+            SyxValue::from(r#""\u0000""#),
+            SyxValue::from("cannot encode a table with a cycle"),
+            SyxValue::from("cannot encode a function value"),
+            SyxValue::from("bad argument #2 to 'encode' (invalid option 'sparse_arrays')"),
+        ]);
+    }
+}
@@ -0,0 +1,369 @@
+// Math library
+//
+// Functions taking integers or floats keep integers as integers where Lua
+// 5.3 does (abs, max, min, fmod, floor and ceil of integral results), and
+// return floats otherwise.
+//
+// Consult the versioned lmathlib.c for more information.
+
+use std::f64::consts::PI;
+
+use super::super::conf::SYX_RANDOMSEED;
+use super::super::errors::*;
+use super::super::object::{
+    float_to_integer, number_to_f64, SyxInteger, SyxNumber, SyxUnsigned, SyxValue,
+};
+use super::super::state::SyxState;
+use super::super::vm::{bad_argument, less_than, runtime_error};
+use super::{check_any, check_integer, check_number, new_lib, set_field, type_error};
+
+pub fn open(state: &mut SyxState) {
+    let lib = new_lib(state, "math", &[
+        ("abs", abs),
+        ("ceil", ceil),
+        ("floor", floor),
+        ("sqrt", sqrt),
+        ("exp", exp),
+        ("log", log),
+        ("sin", sin),
+        ("cos", cos),
+        ("tan", tan),
+        ("asin", asin),
+        ("acos", acos),
+        ("atan", atan),
+        ("deg", deg),
+        ("rad", rad),
+        ("fmod", fmod),
+        ("modf", modf),
+        ("max", max),
+        ("min", min),
+        ("tointeger", tointeger),
+        ("type", math_type),
+        ("ult", ult),
+        ("random", random),
+        ("randomseed", randomseed),
+    ]);
+    set_field(state, lib, "pi", SyxValue::Number(PI as SyxNumber));
+    set_field(state, lib, "huge", SyxValue::Number(SyxNumber::INFINITY));
+    set_field(state, lib, "maxinteger", SyxValue::Integer(SyxInteger::MAX));
+    set_field(state, lib, "mininteger", SyxValue::Integer(SyxInteger::MIN));
+}
+
+// float result as an integer when it has an integer representation
+fn integral(x: SyxNumber) -> SyxValue {
+    match float_to_integer(x) {
+        Some(i) => SyxValue::Integer(i),
+        None => SyxValue::Number(x),
+    }
+}
+
+fn float_fn(args: &[SyxValue], name: &str, f: fn(SyxNumber) -> SyxNumber)
+    -> Result<Vec<SyxValue>>
+{
+    Ok(vec![SyxValue::Number(f(check_number(args, 1, name)?))])
+}
+
+fn abs(_: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    match args.first() {
+        Some(&SyxValue::Integer(i)) => Ok(vec![SyxValue::Integer(i.wrapping_abs())]),
+        _ => float_fn(&args, "abs", SyxNumber::abs),
+    }
+}
+
+fn ceil(_: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    match args.first() {
+        Some(&SyxValue::Integer(i)) => Ok(vec![SyxValue::Integer(i)]),
+        _ => Ok(vec![integral(check_number(&args, 1, "ceil")?.ceil())]),
+    }
+}
+
+fn floor(_: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    match args.first() {
+        Some(&SyxValue::Integer(i)) => Ok(vec![SyxValue::Integer(i)]),
+        _ => Ok(vec![integral(check_number(&args, 1, "floor")?.floor())]),
+    }
+}
+
+fn sqrt(_: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    float_fn(&args, "sqrt", SyxNumber::sqrt)
+}
+
+fn exp(_: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    float_fn(&args, "exp", SyxNumber::exp)
+}
+
+// log(x [, base])
+fn log(_: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let x = check_number(&args, 1, "log")?;
+    let result = match args.get(1) {
+        None | Some(SyxValue::Nil) => x.ln(),
+        _ => {
+            let base = check_number(&args, 2, "log")?;
+            if base == 2.0 {
+                x.log2()
+            } else if base == 10.0 {
+                x.log10()
+            } else {
+                x.ln() / base.ln()
+            }
+        }
+    };
+    Ok(vec![SyxValue::Number(result)])
+}
+
+fn sin(_: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    float_fn(&args, "sin", SyxNumber::sin)
+}
+
+fn cos(_: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    float_fn(&args, "cos", SyxNumber::cos)
+}
+
+fn tan(_: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    float_fn(&args, "tan", SyxNumber::tan)
+}
+
+fn asin(_: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    float_fn(&args, "asin", SyxNumber::asin)
+}
+
+fn acos(_: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    float_fn(&args, "acos", SyxNumber::acos)
+}
+
+// atan(y [, x])
+fn atan(_: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let y = check_number(&args, 1, "atan")?;
+    let x = match args.get(1) {
+        None | Some(SyxValue::Nil) => 1.0,
+        _ => check_number(&args, 2, "atan")?,
+    };
+    Ok(vec![SyxValue::Number(y.atan2(x))])
+}
+
+fn deg(_: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    float_fn(&args, "deg", SyxNumber::to_degrees)
+}
+
+fn rad(_: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    float_fn(&args, "rad", SyxNumber::to_radians)
+}
+
+// remainder of a division rounding towards zero, unlike `%`
+fn fmod(_: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    if let (Some(&SyxValue::Integer(a)), Some(&SyxValue::Integer(b))) = (args.first(), args.get(1)) {
+        return match b {
+            0 => bad_argument(2, "fmod", "zero"),
+            // avoids the overflow of mininteger % -1
+            -1 => Ok(vec![SyxValue::Integer(0)]),
+            b => Ok(vec![SyxValue::Integer(a % b)]),
+        };
+    }
+    let a = check_number(&args, 1, "fmod")?;
+    let b = check_number(&args, 2, "fmod")?;
+    Ok(vec![SyxValue::Number(a % b)])
+}
+
+// integral and fractional parts, both floats
+fn modf(_: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    if let Some(&SyxValue::Integer(i)) = args.first() {
+        return Ok(vec![SyxValue::Number(i as SyxNumber), SyxValue::Number(0.0)]);
+    }
+    let x = check_number(&args, 1, "modf")?;
+    let whole = x.trunc();
+    // infinities have no fractional part, not inf - inf
+    let fraction = if x == whole { 0.0 } else { x - whole };
+    Ok(vec![SyxValue::Number(whole), SyxValue::Number(fraction)])
+}
+
+// the first of `args` for which `before(it, every other)` holds
+fn pick(args: &[SyxValue], name: &str, before: fn(&SyxValue, &SyxValue) -> Result<bool>)
+    -> Result<Vec<SyxValue>>
+{
+    check_number(args, 1, name)?;
+    let mut best = 0;
+    for i in 1..args.len() {
+        check_number(args, i + 1, name)?;
+        if before(&args[i], &args[best])? {
+            best = i;
+        }
+    }
+    Ok(vec![args[best].clone()])
+}
+
+fn max(_: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    pick(&args, "max", |x, y| less_than(y, x))
+}
+
+fn min(_: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    pick(&args, "min", less_than)
+}
+
+fn tointeger(_: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let value = check_any(&args, 1, "tointeger")?;
+    Ok(vec![value.coerce_to_integer().map_or(SyxValue::Nil, SyxValue::Integer)])
+}
+
+// math.type(x), "integer", "float", or nil for anything else
+fn math_type(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let name = match check_any(&args, 1, "type")? {
+        SyxValue::Integer(_) => "integer",
+        SyxValue::Number(_) => "float",
+        _ => return Ok(vec![SyxValue::Nil]),
+    };
+    Ok(vec![SyxValue::String(state.intern(name.as_bytes()))])
+}
+
+// unsigned comparison of two integers
+fn ult(_: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let a = check_integer(&args, 1, "ult")?;
+    let b = check_integer(&args, 2, "ult")?;
+    Ok(vec![SyxValue::Bool((a as SyxUnsigned) < (b as SyxUnsigned))])
+}
+
+// xorshift64*, uniform in [0, 1)
+fn next_random(state: &mut SyxState) -> SyxNumber {
+    let mut x = state.random;
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    state.random = x;
+    // as many bits as a float holds exactly
+    let digits = SyxNumber::MANTISSA_DIGITS;
+    (x.wrapping_mul(SYX_RANDOMSEED) >> (64 - digits)) as SyxNumber / (1u64 << digits) as SyxNumber
+}
+
+// random(), random(m) or random(m, n)
+fn random(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let r = next_random(state);
+    let (low, up) = match args.len() {
+        0 => return Ok(vec![SyxValue::Number(r)]),
+        1 => (1, check_integer(&args, 1, "random")?),
+        2 => (check_integer(&args, 1, "random")?, check_integer(&args, 2, "random")?),
+        _ => return runtime_error("wrong number of arguments".to_owned()),
+    };
+    if low > up {
+        return bad_argument(args.len(), "random", "interval is empty");
+    }
+    if low < 0 && up > SyxInteger::MAX + low {
+        return bad_argument(1, "random", "interval too large");
+    }
+    let offset = (r * ((up - low) as SyxNumber + 1.0)) as SyxInteger;
+    Ok(vec![SyxValue::Integer(low + offset)])
+}
+
+fn randomseed(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let seed = match args.first() {
+        Some(&SyxValue::Integer(i)) => i as u64,
+        Some(&SyxValue::Number(x)) => number_to_f64(x).to_bits(),
+        _ => return type_error(&args, 1, "randomseed", "number"),
+    };
+    // xorshift never leaves a zero state
+    state.random = match seed ^ SYX_RANDOMSEED {
+        0 => SYX_RANDOMSEED,
+        seed => seed,
+    };
+    next_random(state);
+    Ok(vec![])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use super::super::super::object::{NativeFunction, Proto, Upvalue};
+    use super::super::super::opcodes::OpCode;
+    use super::super::super::testing::{abc, abx};
+
+    fn call(function: NativeFunction, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+        function(&mut SyxState::new(), args)
+    }
+
+    #[test]
+    fn test_rounding() {
+        assert_eq!(call(floor, vec![SyxValue::Number(3.7)]).unwrap(),
+                   vec![SyxValue::Integer(3)]);
+        assert_eq!(call(ceil, vec![SyxValue::Number(-3.7)]).unwrap(),
+                   vec![SyxValue::Integer(-3)]);
+        assert_eq!(call(floor, vec![SyxValue::Number(1e30)]).unwrap(),
+                   vec![SyxValue::Number(1e30)]);
+        assert_eq!(call(modf, vec![SyxValue::Number(-3.5)]).unwrap(),
+                   vec![SyxValue::Number(-3.0), SyxValue::Number(-0.5)]);
+        assert_eq!(call(modf, vec![SyxValue::Number(SyxNumber::INFINITY)]).unwrap(),
+                   vec![SyxValue::Number(SyxNumber::INFINITY), SyxValue::Number(0.0)]);
+        assert_eq!(call(fmod, vec![SyxValue::Integer(-7), SyxValue::Integer(3)]).unwrap(),
+                   vec![SyxValue::Integer(-1)]);
+        assert_eq!(call(fmod, vec![SyxValue::Number(7.5), SyxValue::Integer(2)]).unwrap(),
+                   vec![SyxValue::Number(1.5)]);
+        assert_eq!(call(fmod, vec![SyxValue::Integer(1), SyxValue::Integer(0)])
+                       .unwrap_err().to_string(),
+                   "bad argument #2 to 'fmod' (zero)");
+        assert_eq!(call(tointeger, vec![SyxValue::Number(4.0)]).unwrap(),
+                   vec![SyxValue::Integer(4)]);
+        assert_eq!(call(tointeger, vec![SyxValue::Number(4.5)]).unwrap(),
+                   vec![SyxValue::Nil]);
+        // numerals convert, as they do for every integer argument
+        assert_eq!(call(tointeger, vec![SyxValue::from("0x10")]).unwrap(),
+                   vec![SyxValue::Integer(16)]);
+        assert_eq!(call(tointeger, vec![SyxValue::from("8.5")]).unwrap(), vec![SyxValue::Nil]);
+        assert_eq!(call(tointeger, vec![SyxValue::Bool(true)]).unwrap(), vec![SyxValue::Nil]);
+    }
+
+    #[test]
+    fn test_arguments() {
+        assert_eq!(call(max, vec![SyxValue::Integer(2), SyxValue::Number(2.5),
+                                  SyxValue::Integer(-1)]).unwrap(),
+                   vec![SyxValue::Number(2.5)]);
+        assert_eq!(call(min, vec![SyxValue::Integer(2), SyxValue::Number(2.5)]).unwrap(),
+                   vec![SyxValue::Integer(2)]);
+        assert_eq!(call(max, vec![]).unwrap_err().to_string(),
+                   "bad argument #1 to 'max' (number expected, got no value)");
+        // numerals are numbers too
+        assert_eq!(call(sqrt, vec![SyxValue::from(" 0x4 ")]).unwrap(), vec![SyxValue::Number(2.0)]);
+        assert_eq!(call(sqrt, vec![SyxValue::from("four")]).unwrap_err().to_string(),
+                   "bad argument #1 to 'sqrt' (number expected, got string)");
+        assert_eq!(call(ult, vec![SyxValue::Integer(1), SyxValue::Number(0.5)])
+                       .unwrap_err().to_string(),
+                   "bad argument #2 to 'ult' (number has no integer representation)");
+        assert_eq!(call(math_type, vec![SyxValue::Number(1.0)]).unwrap(),
+                   vec![SyxValue::from("float")]);
+        assert_eq!(call(math_type, vec![SyxValue::Nil]).unwrap(), vec![SyxValue::Nil]);
+
+        let mut state = SyxState::new();
+        for _ in 0..100 {
+            match random(&mut state, vec![SyxValue::Integer(3), SyxValue::Integer(5)]) {
+                Ok(ref v) if v[0] == SyxValue::Integer(3) || v[0] == SyxValue::Integer(4) ||
+                             v[0] == SyxValue::Integer(5) => {}
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        let error = random(&mut state, vec![SyxValue::Integer(0)]).unwrap_err();
+        assert_eq!(error.to_string(), "bad argument #1 to 'random' (interval is empty)");
+    }
+
+    #[test]
+    fn test_from_bytecode() {
+        // return math.floor(3.7), math.maxinteger
+        let mut proto = Proto::new();
+        proto.maxstacksize = 3;
+        proto.upvalues = vec![Upvalue { name: "_ENV".into(), instack: 1, idx: 0 }];
+        proto.constants = vec![
+            SyxValue::from("math"), SyxValue::from("floor"), SyxValue::Number(3.7),
+            SyxValue::from("maxinteger"),
+        ];
+        proto.instructions = vec![
+            abc(OpCode::GetTabUp, 0, 0, 256),
+            abc(OpCode::GetTable, 0, 0, 257),
+            abx(OpCode::LoadK, 1, 2),
+            abc(OpCode::Call, 0, 2, 2),
+            abc(OpCode::GetTabUp, 1, 0, 256),
+            abc(OpCode::GetTable, 1, 1, 259),
+            abc(OpCode::Return, 0, 3, 0),
+        ];
+        let mut state = SyxState::new();
+        open(&mut state);
+        let results = state.call(Arc::new(proto), vec![]).unwrap();
+        assert_eq!(results, vec![SyxValue::Integer(3), SyxValue::Integer(SyxInteger::MAX)]);
+    }
+}
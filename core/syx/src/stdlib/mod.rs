@@ -0,0 +1,176 @@
+// Standard library
+//
+// Each library is a table of native functions stored in the globals under
+// its name, and is opened into a state with its `open` function, or those of
+// Lua all at once with `open_libs`; json is only opened on request. Arguments
+// are checked as luaL_check* does, so a bad one is reported as "bad argument
+// #n to 'name' (...)".
+//
+// Consult the versioned linit.c and lauxlib.c for more information.
+
+pub mod base;
+pub mod debug;
+pub mod io;
+pub mod json;
+pub mod math;
+pub mod os;
+pub mod pack;
+pub mod package;
+pub mod pattern;
+pub mod string;
+pub mod table;
+pub mod utf8;
+
+use super::coroutine;
+use super::errors::*;
+use super::object::{NativeFunction, SyxInteger, SyxNumber, SyxString, SyxValue, TableRef};
+use super::state::SyxState;
+use super::vm::{append_string, bad_argument, runtime_error};
+
+pub fn open_libs(state: &mut SyxState) {
+    base::open(state);
+    coroutine::open(state);
+    debug::open(state);
+    io::open(state);
+    math::open(state);
+    os::open(state);
+    string::open(state);
+    table::open(state);
+    utf8::open(state);
+    package::open(state);
+}
+
+// Create a library table holding `functions`, and store it as global `name`
+pub(crate) fn new_lib(state: &mut SyxState, name: &str, functions: &[(&str, NativeFunction)])
+    -> TableRef
+{
+    let lib = state.new_table(0, functions.len());
+    for &(field, function) in functions {
+        set_field(state, lib, field, SyxValue::Native(function));
+    }
+    let globals = state.globals();
+    set_field(state, globals, name, SyxValue::Table(lib));
+    lib
+}
+
+pub(crate) fn set_field(state: &mut SyxState, table: TableRef, field: &str, value: SyxValue) {
+    let key = SyxValue::String(state.intern(field.as_bytes()));
+    state.table_mut(table).set(key, value).expect("string keys are always valid");
+}
+
+// error for argument `n` of `name` not being an `expected`
+pub(crate) fn type_error<T>(args: &[SyxValue], n: usize, name: &str, expected: &str)
+    -> Result<T>
+{
+    let got = match args.get(n - 1) {
+        Some(value) => value.type_name(),
+        None => "no value",
+    };
+    bad_argument(n, name, &format!("{} expected, got {}", expected, got))
+}
+
+// argument `n` (counting from 1), of any type
+pub(crate) fn check_any(args: &[SyxValue], n: usize, name: &str) -> Result<SyxValue> {
+    match args.get(n - 1) {
+        Some(value) => Ok(value.clone()),
+        None => bad_argument(n, name, "value expected"),
+    }
+}
+
+// argument `n` as a number, strings being converted as tonumber does
+pub(crate) fn check_number(args: &[SyxValue], n: usize, name: &str) -> Result<SyxNumber> {
+    match args.get(n - 1).and_then(|value| value.coerce_to_number(None)) {
+        Some(SyxValue::Integer(i)) => Ok(i as SyxNumber),
+        Some(SyxValue::Number(x)) => Ok(x),
+        _ => type_error(args, n, name, "number"),
+    }
+}
+
+// argument `n` as an integer, converted as `SyxValue::coerce_to_integer` does
+pub(crate) fn check_integer(args: &[SyxValue], n: usize, name: &str) -> Result<SyxInteger> {
+    match args.get(n - 1) {
+        Some(value) => match value.coerce_to_integer() {
+            Some(i) => Ok(i),
+            None if value.coerce_to_number(None).is_some() => {
+                bad_argument(n, name, "number has no integer representation")
+            }
+            None => type_error(args, n, name, "number"),
+        },
+        None => type_error(args, n, name, "number"),
+    }
+}
+
+// argument `n` as an integer, or `default` when it is nil or absent
+pub(crate) fn opt_integer(args: &[SyxValue], n: usize, name: &str, default: SyxInteger)
+    -> Result<SyxInteger>
+{
+    match args.get(n - 1) {
+        None | Some(SyxValue::Nil) => Ok(default),
+        _ => check_integer(args, n, name),
+    }
+}
+
+// argument `n` as a string, numbers being converted as CONCAT does
+pub(crate) fn check_string(args: &[SyxValue], n: usize, name: &str) -> Result<SyxString> {
+    match args.get(n - 1) {
+        Some(SyxValue::String(s)) => Ok(s.clone()),
+        Some(value @ SyxValue::Integer(_)) | Some(value @ SyxValue::Number(_)) => {
+            let mut buffer = Vec::new();
+            append_string(&mut buffer, value);
+            Ok(SyxString::new(buffer))
+        }
+        _ => type_error(args, n, name, "string"),
+    }
+}
+
+pub(crate) fn opt_string(args: &[SyxValue], n: usize, name: &str, default: &str)
+    -> Result<SyxString>
+{
+    match args.get(n - 1) {
+        None | Some(SyxValue::Nil) => Ok(SyxString::from(default)),
+        _ => check_string(args, n, name),
+    }
+}
+
+pub(crate) fn check_table(args: &[SyxValue], n: usize, name: &str) -> Result<TableRef> {
+    match args.get(n - 1) {
+        Some(&SyxValue::Table(t)) => Ok(t),
+        _ => type_error(args, n, name, "table"),
+    }
+}
+
+// Any value as a string, through its __tostring handler if it has one.
+// Values without a readable form are shown by type and identity.
+pub(crate) fn tostring(state: &mut SyxState, value: &SyxValue) -> Result<SyxString> {
+    if let Some(metatable) = state.metatable(value) {
+        let name = SyxValue::String(state.intern(b"__tostring"));
+        let handler = state.table(metatable).get(&name);
+        if !handler.is_nil() {
+            let results = state.call_value(handler, vec![value.clone()])?;
+            return match results.into_iter().next() {
+                Some(SyxValue::String(s)) => Ok(s),
+                _ => runtime_error("'__tostring' must return a string".to_owned()),
+            };
+        }
+    }
+    let text = match *value {
+        SyxValue::Nil => "nil".to_owned(),
+        SyxValue::Bool(b) => b.to_string(),
+        SyxValue::Table(t) => format!("table: 0x{:08x}", t.0),
+        SyxValue::Function(f) => format!("function: 0x{:08x}", f.0),
+        SyxValue::Native(f) => format!("function: builtin: 0x{:08x}", f as usize),
+        SyxValue::NativeClosure(f) => format!("function: builtin: 0x{:08x}", f.0),
+        SyxValue::Thread(t) => format!("thread: 0x{:08x}", t.0),
+        SyxValue::UserData(u) => {
+            let name = state.userdata_name(u).unwrap_or_else(|| "userdata".to_owned());
+            format!("{}: 0x{:08x}", name, u.0)
+        }
+        SyxValue::String(ref s) => return Ok(s.clone()),
+        SyxValue::Integer(_) | SyxValue::Number(_) => {
+            let mut buffer = Vec::new();
+            append_string(&mut buffer, value);
+            return Ok(state.intern(&buffer));
+        }
+    };
+    Ok(state.intern(text.as_bytes()))
+}
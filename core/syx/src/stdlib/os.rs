@@ -0,0 +1,427 @@
+// OS library
+//
+// time, clock and date are always available. getenv, remove and exit reach
+// outside the state, to the environment, the filesystem and the process, so
+// they fail unless the embedder opts in with `allow_unsafe_os`.
+//
+// There is no notion of a time zone: dates are always in UTC, and "!" at
+// the start of a date format changes nothing. clock measures wall time since
+// the process first asked for it, as the processor time is not available.
+// On wasm32-unknown-unknown there is no clock and no process to exit, so
+// time, clock and exit raise errors there instead.
+//
+// Consult the versioned loslib.c for more information.
+
+use std::env;
+use std::fs;
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+use std::process;
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+use std::sync::OnceLock;
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use super::super::errors::*;
+use super::super::object::{SyxInteger, SyxNumber, SyxValue, TableRef};
+use super::super::state::SyxState;
+use super::super::vm::{bad_argument, runtime_error};
+use super::io::failure;
+use super::{check_integer, check_string, check_table, new_lib, opt_string, set_field};
+
+const SECONDS_PER_DAY: SyxInteger = 24 * 60 * 60;
+
+const DAY_NAMES: [&str; 7] = [
+    "Sunday", "Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday",
+];
+const MONTH_NAMES: [&str; 12] = [
+    "January", "February", "March", "April", "May", "June",
+    "July", "August", "September", "October", "November", "December",
+];
+
+impl SyxState {
+    // Let scripts use os.getenv, os.remove and os.exit
+    pub fn allow_unsafe_os(&mut self, allow: bool) {
+        self.unsafe_os = allow;
+    }
+}
+
+pub fn open(state: &mut SyxState) {
+    new_lib(state, "os", &[
+        ("time", os_time),
+        ("clock", os_clock),
+        ("date", os_date),
+        ("getenv", os_getenv),
+        ("remove", os_remove),
+        ("exit", os_exit),
+    ]);
+}
+
+fn check_unsafe(state: &SyxState, name: &str) -> Result<()> {
+    if state.unsafe_os {
+        Ok(())
+    } else {
+        runtime_error(format!("'{}' is not allowed in this state", name))
+    }
+}
+
+// Days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+// `month` is 1 to 12, `day` may be out of its month.
+fn days_from_civil(year: SyxInteger, month: SyxInteger, day: SyxInteger) -> SyxInteger {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// (year, month, day) of a day counted from 1970-01-01
+fn civil_from_days(days: SyxInteger) -> (SyxInteger, SyxInteger, SyxInteger) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// A moment broken down as in C's struct tm, with a 1-based month and a
+// full year
+struct Date {
+    year: SyxInteger,
+    month: SyxInteger,   // 1 to 12
+    day: SyxInteger,     // 1 to 31
+    hour: SyxInteger,
+    min: SyxInteger,
+    sec: SyxInteger,
+    weekday: SyxInteger, // 0 to 6, from Sunday
+    yearday: SyxInteger, // 1 to 366
+}
+
+impl Date {
+    fn from_time(time: SyxInteger) -> Date {
+        let days = time.div_euclid(SECONDS_PER_DAY);
+        let seconds = time.rem_euclid(SECONDS_PER_DAY);
+        let (year, month, day) = civil_from_days(days);
+        Date {
+            year,
+            month,
+            day,
+            hour: seconds / 3600,
+            min: seconds / 60 % 60,
+            sec: seconds % 60,
+            weekday: (days + 4).rem_euclid(7), // 1970-01-01 was a Thursday
+            yearday: days - days_from_civil(year, 1, 1) + 1,
+        }
+    }
+}
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+fn now() -> Result<SyxInteger> {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => Ok(elapsed.as_secs() as SyxInteger),
+        Err(before) => Ok(-(before.duration().as_secs() as SyxInteger)),
+    }
+}
+
+// wasm32-unknown-unknown has no clock, and std panics when asked for one
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+fn now() -> Result<SyxInteger> {
+    runtime_error("time is not available on this platform".to_owned())
+}
+
+// field `key` of a date table, `default` if it is absent
+fn date_field(state: &mut SyxState, table: TableRef, key: &str, default: Option<SyxInteger>)
+    -> Result<SyxInteger>
+{
+    let name = SyxValue::String(state.intern(key.as_bytes()));
+    match state.table(table).get(&name) {
+        SyxValue::Integer(i) => Ok(i),
+        SyxValue::Number(n) if n.floor() == n && n.abs() < (1u64 << 53) as SyxNumber => {
+            Ok(n as SyxInteger)
+        }
+        SyxValue::Nil => match default {
+            Some(default) => Ok(default),
+            None => runtime_error(format!("field '{}' missing in date table", key)),
+        },
+        SyxValue::Number(_) | SyxValue::String(_) => {
+            runtime_error(format!("field '{}' is out-of-bound", key))
+        }
+        _ => runtime_error(format!("field '{}' is not an integer", key)),
+    }
+}
+
+// store `date` in the fields of `table`, as os.date("*t") returns it
+fn set_all_fields(state: &mut SyxState, table: TableRef, date: &Date) {
+    for &(key, value) in &[
+        ("year", date.year),
+        ("month", date.month),
+        ("day", date.day),
+        ("hour", date.hour),
+        ("min", date.min),
+        ("sec", date.sec),
+        ("wday", date.weekday + 1),
+        ("yday", date.yearday),
+    ] {
+        set_field(state, table, key, SyxValue::Integer(value));
+    }
+    set_field(state, table, "isdst", SyxValue::Bool(false));
+}
+
+// time([table]), the current time, or the time a date table describes, whose
+// fields are then normalized
+fn os_time(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let table = match args.first() {
+        None | Some(SyxValue::Nil) => return Ok(vec![SyxValue::Integer(now()?)]),
+        _ => check_table(&args, 1, "time")?,
+    };
+    let year = date_field(state, table, "year", None)?;
+    let month = date_field(state, table, "month", None)?;
+    let day = date_field(state, table, "day", None)?;
+    let hour = date_field(state, table, "hour", Some(12))?;
+    let min = date_field(state, table, "min", Some(0))?;
+    let sec = date_field(state, table, "sec", Some(0))?;
+    let year = year.checked_add((month - 1).div_euclid(12));
+    // small enough years for days_from_civil not to overflow
+    let time = year.filter(|year| year.abs() < SyxInteger::MAX / 1024).and_then(|year| {
+        let days = days_from_civil(year, (month - 1).rem_euclid(12) + 1, 1)
+            .checked_add(day)?.checked_sub(1)?;
+        days.checked_mul(SECONDS_PER_DAY)?
+            .checked_add(hour.checked_mul(3600)?)?
+            .checked_add(min.checked_mul(60)?)?
+            .checked_add(sec)
+    });
+    let time = match time {
+        Some(time) => time,
+        None => {
+            return runtime_error(
+                "time result cannot be represented in this installation".to_owned())
+        }
+    };
+    set_all_fields(state, table, &Date::from_time(time));
+    Ok(vec![SyxValue::Integer(time)])
+}
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+fn os_clock(_: &mut SyxState, _: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    static START: OnceLock<Instant> = OnceLock::new();
+    let elapsed = START.get_or_init(Instant::now).elapsed();
+    Ok(vec![SyxValue::Number(elapsed.as_secs_f64() as SyxNumber)])
+}
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+fn os_clock(_: &mut SyxState, _: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    runtime_error("clock is not available on this platform".to_owned())
+}
+
+// Append `date` formatted by the strftime conversion `spec` to `out`
+fn convert(out: &mut String, spec: char, date: &Date) -> bool {
+    let hour12 = if date.hour % 12 == 0 { 12 } else { date.hour % 12 };
+    let weekday_name = DAY_NAMES[date.weekday as usize];
+    let month_name = MONTH_NAMES[date.month as usize - 1];
+    let text = match spec {
+        'a' => weekday_name[..3].to_owned(),
+        'A' => weekday_name.to_owned(),
+        'b' | 'h' => month_name[..3].to_owned(),
+        'B' => month_name.to_owned(),
+        'c' => format!("{} {} {:2} {:02}:{:02}:{:02} {}", &weekday_name[..3],
+                       &month_name[..3], date.day, date.hour, date.min, date.sec, date.year),
+        'C' => format!("{:02}", date.year.div_euclid(100)),
+        'd' => format!("{:02}", date.day),
+        'D' | 'x' => format!("{:02}/{:02}/{:02}", date.month, date.day,
+                             date.year.rem_euclid(100)),
+        'e' => format!("{:2}", date.day),
+        'F' => format!("{}-{:02}-{:02}", date.year, date.month, date.day),
+        'H' => format!("{:02}", date.hour),
+        'I' => format!("{:02}", hour12),
+        'j' => format!("{:03}", date.yearday),
+        'm' => format!("{:02}", date.month),
+        'M' => format!("{:02}", date.min),
+        'n' => "\n".to_owned(),
+        'p' => (if date.hour < 12 { "AM" } else { "PM" }).to_owned(),
+        'R' => format!("{:02}:{:02}", date.hour, date.min),
+        'S' => format!("{:02}", date.sec),
+        't' => "\t".to_owned(),
+        'T' | 'X' => format!("{:02}:{:02}:{:02}", date.hour, date.min, date.sec),
+        'u' => (if date.weekday == 0 { 7 } else { date.weekday }).to_string(),
+        'w' => date.weekday.to_string(),
+        'y' => format!("{:02}", date.year.rem_euclid(100)),
+        'Y' => date.year.to_string(),
+        'z' => "+0000".to_owned(),
+        'Z' => "UTC".to_owned(),
+        '%' => "%".to_owned(),
+        _ => return false,
+    };
+    out.push_str(&text);
+    true
+}
+
+// date([format [, time]])
+fn os_date(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let format = opt_string(&args, 1, "date", "%c")?;
+    let time = match args.get(1) {
+        None | Some(SyxValue::Nil) => now()?,
+        _ => check_integer(&args, 2, "date")?,
+    };
+    let date = Date::from_time(time);
+    let format = format.strip_prefix(b"!").unwrap_or(&format);
+    if format.starts_with(b"*t") {
+        let table = state.new_table(0, 9);
+        set_all_fields(state, table, &date);
+        return Ok(vec![SyxValue::Table(table)]);
+    }
+    let format = String::from_utf8_lossy(format);
+    let mut out = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        let spec = chars.next();
+        if !spec.is_some_and(|spec| convert(&mut out, spec, &date)) {
+            let spec = spec.map_or(String::new(), |spec| spec.to_string());
+            let message = format!("invalid conversion specifier '%{}'", spec);
+            return bad_argument(1, "date", &message);
+        }
+    }
+    Ok(vec![SyxValue::String(state.intern(out.as_bytes()))])
+}
+
+// getenv(varname)
+fn os_getenv(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let name = check_string(&args, 1, "getenv")?.to_string();
+    check_unsafe(state, "getenv")?;
+    Ok(vec![match env::var_os(name) {
+        Some(value) => SyxValue::String(state.intern(value.to_string_lossy().as_bytes())),
+        None => SyxValue::Nil,
+    }])
+}
+
+// remove(filename)
+fn os_remove(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let path = check_string(&args, 1, "remove")?.to_string();
+    check_unsafe(state, "remove")?;
+    let result = fs::metadata(&path).and_then(|metadata| {
+        if metadata.is_dir() {
+            fs::remove_dir(&path)
+        } else {
+            fs::remove_file(&path)
+        }
+    });
+    match result {
+        Ok(()) => Ok(vec![SyxValue::Bool(true)]),
+        Err(error) => failure(state, &error, Some(&path)),
+    }
+}
+
+// exit([code [, close]]), true meaning success and false failure
+fn os_exit(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let code = match args.first() {
+        None | Some(SyxValue::Nil) | Some(SyxValue::Bool(true)) => 0,
+        Some(SyxValue::Bool(false)) => 1,
+        _ => check_integer(&args, 1, "exit")? as i32,
+    };
+    check_unsafe(state, "exit")?;
+    exit(code)
+}
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+fn exit(code: i32) -> Result<Vec<SyxValue>> {
+    process::exit(code)
+}
+
+// std aborts rather than failing when a wasm32-unknown-unknown module exits
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+fn exit(_: i32) -> Result<Vec<SyxValue>> {
+    runtime_error("exit is not available on this platform".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+
+    fn date(state: &mut SyxState, format: &str, time: SyxInteger) -> Result<Vec<SyxValue>> {
+        os_date(state, vec![SyxValue::from(format), SyxValue::Integer(time)])
+    }
+
+    #[test]
+    fn test_date() {
+        let mut state = SyxState::new();
+        // 2000-02-29 13:05:09 UTC, a Tuesday
+        let time = 951_829_509;
+        let text = date(&mut state, "%Y-%m-%d %H:%M:%S %a %j %I%p %%", time).unwrap();
+        assert_eq!(text, vec![SyxValue::from("2000-02-29 13:05:09 Tue 060 01PM %")]);
+        let text = date(&mut state, "!%c", time).unwrap();
+        assert_eq!(text, vec![SyxValue::from("Tue Feb 29 13:05:09 2000")]);
+        assert_eq!(date(&mut state, "%x", -1).unwrap(), vec![SyxValue::from("12/31/69")]);
+
+        let error = date(&mut state, "%Q", time).unwrap_err();
+        assert_eq!(error.to_string(),
+                   "bad argument #1 to 'date' (invalid conversion specifier '%Q')");
+        let error = date(&mut state, "100%", time).unwrap_err();
+        assert_eq!(error.to_string(),
+                   "bad argument #1 to 'date' (invalid conversion specifier '%')");
+
+        // a date table turns back into the same time
+        let table = date(&mut state, "*t", time).unwrap().remove(0);
+        let t = match table {
+            SyxValue::Table(t) => t,
+            _ => panic!("date table expected"),
+        };
+        assert_eq!(date_field(&mut state, t, "wday", None).unwrap(), 3);
+        assert_eq!(date_field(&mut state, t, "yday", None).unwrap(), 60);
+        assert_eq!(os_time(&mut state, vec![table]).unwrap(), vec![SyxValue::Integer(time)]);
+    }
+
+    #[test]
+    fn test_time_normalizes() {
+        let mut state = SyxState::new();
+        let t = state.new_table(0, 0);
+        set_field(&mut state, t, "year", SyxValue::Integer(1999));
+        set_field(&mut state, t, "month", SyxValue::Integer(14));
+        set_field(&mut state, t, "day", SyxValue::Integer(0));
+        let time = os_time(&mut state, vec![SyxValue::Table(t)]).unwrap();
+        // the day before 2000-02-01, at noon
+        assert_eq!(time, vec![SyxValue::Integer(949_320_000)]);
+        assert_eq!(date_field(&mut state, t, "year", None).unwrap(), 2000);
+        assert_eq!(date_field(&mut state, t, "month", None).unwrap(), 1);
+        assert_eq!(date_field(&mut state, t, "day", None).unwrap(), 31);
+
+        let t = state.new_table(0, 0);
+        set_field(&mut state, t, "year", SyxValue::Integer(2000));
+        let error = os_time(&mut state, vec![SyxValue::Table(t)]).unwrap_err();
+        assert_eq!(error.to_string(), "field 'month' missing in date table");
+        set_field(&mut state, t, "month", SyxValue::Number(1.5));
+        let error = os_time(&mut state, vec![SyxValue::Table(t)]).unwrap_err();
+        assert_eq!(error.to_string(), "field 'month' is out-of-bound");
+    }
+
+    #[test]
+    fn test_unsafe_functions() {
+        let mut state = SyxState::new();
+        let args = vec![SyxValue::from("PATH")];
+        let error = os_getenv(&mut state, args.clone()).unwrap_err();
+        assert_eq!(error.to_string(), "'getenv' is not allowed in this state");
+        let error = os_exit(&mut state, vec![]).unwrap_err();
+        assert_eq!(error.to_string(), "'exit' is not allowed in this state");
+
+        state.allow_unsafe_os(true);
+        let missing = vec![SyxValue::from("SYX_SURELY_UNSET_VARIABLE")];
+        assert_eq!(os_getenv(&mut state, missing).unwrap(), vec![SyxValue::Nil]);
+        let path = env::temp_dir().join(format!("syx-os-remove-{}", process::id()));
+        fs::write(&path, b"").unwrap();
+        let args = vec![SyxValue::from(path.to_str().unwrap())];
+        assert_eq!(os_remove(&mut state, args.clone()).unwrap(), vec![SyxValue::Bool(true)]);
+        let result = os_remove(&mut state, args).unwrap();
+        assert!(result[0].is_nil());
+        assert_eq!(result[2], SyxValue::Integer(2)); // ENOENT
+    }
+}
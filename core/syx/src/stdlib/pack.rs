@@ -0,0 +1,410 @@
+// Binary packing
+//
+// string.pack, unpack and packsize, which convert values to and from binary
+// strings laid out by a format: `<`, `>` and `=` set the byte order, `![n]`
+// the maximum alignment, and every other option stands for one value.
+// Integers of up to 16 bytes can be packed, those wider than lua_Integer
+// being sign extended, and unpacked as long as they fit. Sizes and the
+// native byte order are those of a 64-bit C build; `j` and `n` follow the
+// widths of SyxInteger and SyxNumber.
+//
+// Consult the versioned lstrlib.c (str_pack, str_unpack) for more
+// information.
+
+use std::mem::size_of;
+
+use super::super::errors::*;
+use super::super::object::{SyxInteger, SyxNumber, SyxUnsigned, SyxValue};
+use super::super::state::SyxState;
+use super::super::vm::{bad_argument, runtime_error};
+use super::string::position;
+use super::{check_integer, check_number, check_string, opt_integer};
+
+const MAXINTSIZE: usize = 16; // widest integer option
+const MAXALIGN: usize = 8; // default for `!`
+const SZINT: usize = size_of::<SyxInteger>();
+
+#[derive(Clone, Copy, PartialEq)]
+enum KOption {
+    Int,
+    Uint,
+    Float,
+    Double,
+    Number,
+    Char,      // fixed-size string
+    String,    // string preceded by its length
+    Zstr,      // zero-terminated string
+    Padding,   // a zero byte
+    PaddAlign, // alignment to the next option
+    Nop,
+}
+
+struct Format<'a> {
+    fmt: &'a [u8],
+    pos: usize,
+    name: &'static str,
+    little: bool,
+    maxalign: usize,
+}
+
+impl<'a> Format<'a> {
+    fn new(fmt: &'a [u8], name: &'static str) -> Format<'a> {
+        Format { fmt, pos: 0, name, little: cfg!(target_endian = "little"), maxalign: 1 }
+    }
+
+    fn digit(&self) -> bool {
+        self.fmt.get(self.pos).is_some_and(u8::is_ascii_digit)
+    }
+
+    // the number at the current position, `default` if there is none
+    fn number(&mut self, default: usize) -> usize {
+        if !self.digit() {
+            return default;
+        }
+        let mut n = 0usize;
+        while self.digit() && n <= (isize::MAX as usize - 9) / 10 {
+            n = n * 10 + (self.fmt[self.pos] - b'0') as usize;
+            self.pos += 1;
+        }
+        n
+    }
+
+    fn size_limit(&mut self, default: usize) -> Result<usize> {
+        let n = self.number(default);
+        if !(1..=MAXINTSIZE).contains(&n) {
+            return runtime_error(format!("integral size ({}) out of limits [1,{}]",
+                                         n, MAXINTSIZE));
+        }
+        Ok(n)
+    }
+
+    // the next option and its size, None at the end of the format
+    fn option(&mut self) -> Result<Option<(KOption, usize)>> {
+        let opt = match self.fmt.get(self.pos) {
+            Some(&opt) => opt,
+            None => return Ok(None),
+        };
+        self.pos += 1;
+        Ok(Some(match opt {
+            b'b' => (KOption::Int, 1),
+            b'B' => (KOption::Uint, 1),
+            b'h' => (KOption::Int, 2),
+            b'H' => (KOption::Uint, 2),
+            b'l' => (KOption::Int, 8),
+            b'L' => (KOption::Uint, 8),
+            b'j' => (KOption::Int, SZINT),
+            b'J' => (KOption::Uint, SZINT),
+            b'T' => (KOption::Uint, size_of::<usize>()),
+            b'f' => (KOption::Float, 4),
+            b'd' => (KOption::Double, 8),
+            b'n' => (KOption::Number, size_of::<SyxNumber>()),
+            b'i' => (KOption::Int, self.size_limit(4)?),
+            b'I' => (KOption::Uint, self.size_limit(4)?),
+            b's' => (KOption::String, self.size_limit(size_of::<usize>())?),
+            b'c' => match self.number(usize::MAX) {
+                usize::MAX => return runtime_error(
+                    "missing size for format option 'c'".to_owned()),
+                size => (KOption::Char, size),
+            },
+            b'z' => (KOption::Zstr, 0),
+            b'x' => (KOption::Padding, 1),
+            b'X' => (KOption::PaddAlign, 0),
+            b' ' => (KOption::Nop, 0),
+            b'<' | b'>' | b'=' => {
+                self.little = match opt {
+                    b'<' => true,
+                    b'>' => false,
+                    _ => cfg!(target_endian = "little"),
+                };
+                (KOption::Nop, 0)
+            }
+            b'!' => {
+                self.maxalign = self.size_limit(MAXALIGN)?;
+                (KOption::Nop, 0)
+            }
+            _ => return runtime_error(format!("invalid format option '{}'", opt as char)),
+        }))
+    }
+
+    // The next option, its size and the padding needed before it to align
+    // it when `total` bytes come before
+    fn details(&mut self, total: usize) -> Result<Option<(KOption, usize, usize)>> {
+        let (opt, size) = match self.option()? {
+            Some(option) => option,
+            None => return Ok(None),
+        };
+        let mut align = size;
+        if opt == KOption::PaddAlign {
+            match self.option()? {
+                Some((next, size)) if next != KOption::Char && size != 0 => align = size,
+                _ => return bad_argument(1, self.name, "invalid next option for option 'X'"),
+            }
+        }
+        if align <= 1 || opt == KOption::Char {
+            return Ok(Some((opt, size, 0)));
+        }
+        let align = align.min(self.maxalign);
+        if !align.is_power_of_two() {
+            return bad_argument(1, self.name, "format asks for alignment not power of 2");
+        }
+        Ok(Some((opt, size, (align - (total & (align - 1))) & (align - 1))))
+    }
+}
+
+// `bytes`, least significant first, in the order of the format
+fn push_ordered(buffer: &mut Vec<u8>, bytes: &[u8], little: bool) {
+    if little {
+        buffer.extend_from_slice(bytes);
+    } else {
+        buffer.extend(bytes.iter().rev());
+    }
+}
+
+// `bytes` as laid out by the format, reordered least significant first
+fn read_ordered(bytes: &[u8], little: bool) -> Vec<u8> {
+    let mut bytes = bytes.to_vec();
+    if !little {
+        bytes.reverse();
+    }
+    bytes
+}
+
+fn unpack_int(bytes: &[u8], little: bool, signed: bool) -> Result<SyxInteger> {
+    let bytes = read_ordered(bytes, little);
+    let negative = signed && bytes[bytes.len() - 1] & 0x80 != 0;
+    let mut wide = [if negative { 0xff } else { 0 }; MAXINTSIZE];
+    wide[..bytes.len()].copy_from_slice(&bytes);
+    let value = i128::from_le_bytes(wide) as SyxInteger;
+    // bytes that do not fit must only extend the sign
+    if bytes.len() > SZINT {
+        let extension = if signed && value < 0 { 0xff } else { 0 };
+        if bytes[SZINT..].iter().any(|&byte| byte != extension) {
+            return runtime_error(format!("{}-byte integer does not fit into Lua Integer",
+                                         bytes.len()));
+        }
+    }
+    Ok(value)
+}
+
+// pack(fmt, v1, v2, ...)
+pub(super) fn str_pack(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let fmt = check_string(&args, 1, "pack")?;
+    let mut format = Format::new(&fmt, "pack");
+    let mut buffer = Vec::new();
+    let mut arg = 1;
+    while let Some((opt, size, padding)) = format.details(buffer.len())? {
+        let len = buffer.len();
+        buffer.resize(len + padding, 0);
+        match opt {
+            KOption::Int | KOption::Uint => {
+                arg += 1;
+                let n = check_integer(&args, arg, "pack")?;
+                if size < SZINT {
+                    let bits = size * 8;
+                    if opt == KOption::Int {
+                        let limit = 1i128 << (bits - 1);
+                        if (n as i128) < -limit || n as i128 >= limit {
+                            return bad_argument(arg, "pack", "integer overflow");
+                        }
+                    } else if (n as SyxUnsigned) as u128 >= 1u128 << bits {
+                        return bad_argument(arg, "pack", "unsigned overflow");
+                    }
+                }
+                // sign extended past the width of SyxInteger
+                push_ordered(&mut buffer, &(n as i128).to_le_bytes()[..size], format.little);
+            }
+            KOption::Float | KOption::Double | KOption::Number => {
+                arg += 1;
+                let x = check_number(&args, arg, "pack")?;
+                match opt {
+                    KOption::Float => {
+                        push_ordered(&mut buffer, &(x as f32).to_le_bytes(), format.little)
+                    }
+                    KOption::Double => {
+                        push_ordered(&mut buffer, &(x as f64).to_le_bytes(), format.little)
+                    }
+                    _ => push_ordered(&mut buffer, &x.to_le_bytes(), format.little),
+                }
+            }
+            KOption::Char => {
+                arg += 1;
+                let s = check_string(&args, arg, "pack")?;
+                if s.len() > size {
+                    return bad_argument(arg, "pack", "string longer than given size");
+                }
+                buffer.extend_from_slice(&s);
+                let len = buffer.len();
+                buffer.resize(len + size - s.len(), 0);
+            }
+            KOption::String => {
+                arg += 1;
+                let s = check_string(&args, arg, "pack")?;
+                if size < size_of::<usize>() && s.len() as u128 >= 1u128 << (size * 8) {
+                    return bad_argument(arg, "pack", "string length does not fit in given size");
+                }
+                push_ordered(&mut buffer, &(s.len() as i128).to_le_bytes()[..size],
+                             format.little);
+                buffer.extend_from_slice(&s);
+            }
+            KOption::Zstr => {
+                arg += 1;
+                let s = check_string(&args, arg, "pack")?;
+                if s.contains(&0) {
+                    return bad_argument(arg, "pack", "string contains zeros");
+                }
+                buffer.extend_from_slice(&s);
+                buffer.push(0);
+            }
+            KOption::Padding => buffer.push(0),
+            KOption::PaddAlign | KOption::Nop => {}
+        }
+    }
+    Ok(vec![SyxValue::String(state.intern(&buffer))])
+}
+
+// packsize(fmt), for formats without variable-length strings
+pub(super) fn str_packsize(_: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let fmt = check_string(&args, 1, "packsize")?;
+    let mut format = Format::new(&fmt, "packsize");
+    let mut total = 0usize;
+    while let Some((opt, size, padding)) = format.details(total)? {
+        if opt == KOption::String || opt == KOption::Zstr {
+            return bad_argument(1, "packsize", "variable-length format");
+        }
+        let size = size + padding;
+        if total > SyxInteger::MAX as usize - size {
+            return bad_argument(1, "packsize", "format result too large");
+        }
+        total += size;
+    }
+    Ok(vec![SyxValue::Integer(total as SyxInteger)])
+}
+
+// unpack(fmt, s [, pos]), the values followed by the position after them
+pub(super) fn str_unpack(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let fmt = check_string(&args, 1, "unpack")?;
+    let data = check_string(&args, 2, "unpack")?;
+    let len = data.len();
+    let pos = position(opt_integer(&args, 3, "unpack", 1)?, len) - 1;
+    if pos < 0 || pos as u64 > len as u64 {
+        return bad_argument(3, "unpack", "initial position out of string");
+    }
+    let mut pos = pos as usize;
+    let mut format = Format::new(&fmt, "unpack");
+    let mut results = Vec::new();
+    while let Some((opt, size, padding)) = format.details(pos)? {
+        if padding + size > len - pos {
+            return bad_argument(2, "unpack", "data string too short");
+        }
+        pos += padding;
+        let bytes = &data[pos..pos + size];
+        match opt {
+            KOption::Int | KOption::Uint => {
+                let n = unpack_int(bytes, format.little, opt == KOption::Int)?;
+                results.push(SyxValue::Integer(n));
+            }
+            KOption::Float => {
+                let mut le = [0; 4];
+                le.copy_from_slice(&read_ordered(bytes, format.little));
+                results.push(SyxValue::Number(f32::from_le_bytes(le) as SyxNumber));
+            }
+            KOption::Double => {
+                let mut le = [0; 8];
+                le.copy_from_slice(&read_ordered(bytes, format.little));
+                results.push(SyxValue::Number(f64::from_le_bytes(le) as SyxNumber));
+            }
+            KOption::Number => {
+                let mut le = [0; size_of::<SyxNumber>()];
+                le.copy_from_slice(&read_ordered(bytes, format.little));
+                results.push(SyxValue::Number(SyxNumber::from_le_bytes(le)));
+            }
+            KOption::Char => results.push(SyxValue::String(state.intern(bytes))),
+            KOption::String => {
+                let n = unpack_int(bytes, format.little, false)? as SyxUnsigned as u64;
+                if n > (len - pos - size) as u64 {
+                    return bad_argument(2, "unpack", "data string too short");
+                }
+                let start = pos + size;
+                let s = state.intern(&data[start..start + n as usize]);
+                results.push(SyxValue::String(s));
+                pos += n as usize;
+            }
+            KOption::Zstr => {
+                let n = match data[pos..].iter().position(|&byte| byte == 0) {
+                    Some(n) => n,
+                    None => return bad_argument(2, "unpack", "unfinished string for format 'z'"),
+                };
+                results.push(SyxValue::String(state.intern(&data[pos..pos + n])));
+                pos += n + 1;
+            }
+            KOption::Padding | KOption::PaddAlign | KOption::Nop => {}
+        }
+        pos += size;
+    }
+    results.push(SyxValue::Integer(pos as SyxInteger + 1));
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::super::object::SyxString;
+    use super::super::super::testing::{ints, run_source};
+
+    fn bytes(bytes: &[u8]) -> SyxValue {
+        SyxValue::String(SyxString::from(bytes))
+    }
+
+    #[test]
+    fn test_pack() {
+        let results = run_source("return string.pack('<i2 >I3 b', -2, 0x010203, 127)").unwrap();
+        assert_eq!(results, vec![bytes(b"\xfe\xff\x01\x02\x03\x7f")]);
+        let results = run_source("return string.pack('>i16', -1) == string.rep('\\xff', 16), \
+                                  string.pack('!4 b i4', 1, 2):byte(1, -1)").unwrap();
+        let mut expected = vec![SyxValue::Bool(true)];
+        expected.extend(ints(&[1, 0, 0, 0, 2, 0, 0, 0]));
+        assert!(results == expected);
+        let results = run_source("return string.pack('z s1 c3', 'ab', 'cd', 'e')").unwrap();
+        assert_eq!(results, vec![bytes(b"ab\0\x02cde\0\0")]);
+        let results = run_source("return string.packsize('i4 !8 d c5'), \
+                                  string.packsize('! b Xi8 h')").unwrap();
+        assert!(results == ints(&[21, 10]));
+    }
+
+    #[test]
+    fn test_unpack() {
+        let results = run_source("local s = string.pack('<i3 >H z s2 d', -5, 513, 'hi', 'there', \
+                                  0.5) return string.unpack('<i3 >H z s2 d', s)").unwrap();
+        assert!(results == vec![
+            SyxValue::Integer(-5), SyxValue::Integer(513), SyxValue::from("hi"),
+            SyxValue::from("there"), SyxValue::Number(0.5), SyxValue::Integer(24),
+        ]);
+        let results = run_source("return string.unpack('B', 'abc', -1), \
+                                  string.unpack('>I9', string.rep('\\0', 8) .. '\\1')").unwrap();
+        assert!(results == ints(&[99, 1, 10]));
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |source: &str| run_source(source).unwrap_err().to_string();
+        assert_eq!(error("string.pack('i17', 1)"),
+                   "test:1: integral size (17) out of limits [1,16]");
+        assert_eq!(error("string.pack('y', 1)"), "test:1: invalid format option 'y'");
+        assert_eq!(error("string.pack('c', 'x')"),
+                   "test:1: missing size for format option 'c'");
+        assert_eq!(error("string.pack('i1', 128)"),
+                   "test:1: bad argument #2 to 'pack' (integer overflow)");
+        assert_eq!(error("string.pack('z', 'a\\0')"),
+                   "test:1: bad argument #2 to 'pack' (string contains zeros)");
+        assert_eq!(error("string.pack('!4 Xc1')"),
+                   "test:1: bad argument #1 to 'pack' (invalid next option for option 'X')");
+        assert_eq!(error("string.packsize('s')"),
+                   "test:1: bad argument #1 to 'packsize' (variable-length format)");
+        assert_eq!(error("string.unpack('i4', 'abc')"),
+                   "test:1: bad argument #2 to 'unpack' (data string too short)");
+        assert_eq!(error("string.unpack('b', 'a', 3)"),
+                   "test:1: bad argument #3 to 'unpack' (initial position out of string)");
+        assert_eq!(error("string.unpack('i9', string.rep('\\0', 8) .. '\\1')"),
+                   "test:1: 9-byte integer does not fit into Lua Integer");
+    }
+}
@@ -0,0 +1,385 @@
+// Package library
+//
+// require finds a module by calling each function in package.searchers in
+// turn until one returns a loader: the first looks in package.preload, the
+// second for a Lua file along package.path, and the third for a C library
+// along package.cpath, only to report that such libraries cannot be loaded.
+// Files are read through the io backend (see io.rs), so a state given NoIo
+// finds no modules in them. A host can append searchers of its own with
+// `add_searcher`, to load modules from wherever it keeps them, and provide
+// modules of its own with `preload_module`.
+//
+// Neither path is taken from the environment: they start out as
+// SYX_PATH_DEFAULT and SYX_CPATH_DEFAULT, and scripts or the host may change
+// them. The all-in-one C searcher is left out.
+//
+// Consult the versioned loadlib.c for more information.
+
+use std::sync::Arc;
+
+use super::super::compiler::load_contents;
+use super::super::conf::{SYX_CPATH_DEFAULT, SYX_PATH_DEFAULT};
+use super::super::errors::*;
+use super::super::native::Args;
+use super::super::object::{MultiValue, NativeFunction, SyxInteger, SyxValue, TableRef};
+use super::super::state::SyxState;
+use super::super::vm::runtime_error;
+use super::io::error_message;
+use super::{check_string, new_lib, opt_string, set_field};
+
+// registry fields
+const LOADED: &str = "_LOADED";
+const PRELOAD: &str = "_PRELOAD";
+const PACKAGE: &str = "_PACKAGE";
+
+// libraries loaded already when package is opened
+const LIBRARIES: &[&str] = &["debug", "io", "math", "os", "string", "table", "utf8"];
+
+// What a searcher added with `add_searcher` found for a module name
+pub enum Search {
+    // the function loading the module, and the value passed to it after the
+    // name (a file name, say)
+    Found(SyxValue, SyxValue),
+    // why the module is not there, one line of require's error message
+    NotFound(String),
+}
+
+pub fn open(state: &mut SyxState) {
+    let lib = new_lib(state, "package", &[("searchpath", searchpath)]);
+    set_field(state, lib, "path", SyxValue::from(SYX_PATH_DEFAULT));
+    set_field(state, lib, "cpath", SyxValue::from(SYX_CPATH_DEFAULT));
+    set_field(state, lib, "config", SyxValue::from("/\n;\n?\n!\n-\n"));
+
+    let loaded = registry_table(state, LOADED);
+    let globals = state.globals();
+    set_field(state, loaded, "_G", SyxValue::Table(globals));
+    for &name in LIBRARIES.iter().chain(&["package"]) {
+        if let SyxValue::Table(library) = state.table(globals).get(&SyxValue::from(name)) {
+            set_field(state, loaded, name, SyxValue::Table(library));
+        }
+    }
+    set_field(state, lib, "loaded", SyxValue::Table(loaded));
+    let preload = registry_table(state, PRELOAD);
+    set_field(state, lib, "preload", SyxValue::Table(preload));
+
+    let searchers = state.new_table(3, 0);
+    let functions: [NativeFunction; 3] = [search_preload, search_lua, search_c];
+    for (i, &searcher) in functions.iter().enumerate() {
+        state.table_mut(searchers).set_int(i as SyxInteger + 1, SyxValue::Native(searcher));
+    }
+    set_field(state, lib, "searchers", SyxValue::Table(searchers));
+
+    let registry = state.registry();
+    set_field(state, registry, PACKAGE, SyxValue::Table(lib));
+    set_field(state, globals, "require", SyxValue::Native(require));
+}
+
+// Registry field `name`, created as an empty table if it is not one
+fn registry_table(state: &mut SyxState, name: &str) -> TableRef {
+    let registry = state.registry();
+    if let SyxValue::Table(table) = state.table(registry).get(&SyxValue::from(name)) {
+        return table;
+    }
+    let table = state.new_table(0, 0);
+    set_field(state, registry, name, SyxValue::Table(table));
+    table
+}
+
+// package.`field`, which must be a `expected`
+fn package_field(state: &mut SyxState, field: &str, expected: &str) -> Result<SyxValue> {
+    let package = match state.table(state.registry()).get(&SyxValue::from(PACKAGE)) {
+        SyxValue::Table(package) => package,
+        _ => return runtime_error("package library not open".to_owned()),
+    };
+    let value = state.table(package).get(&SyxValue::from(field));
+    if value.type_name() != expected {
+        return runtime_error(format!("'package.{}' must be a {}", field, expected));
+    }
+    Ok(value)
+}
+
+// require(modname)
+fn require(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let name = SyxValue::String(check_string(&args, 1, "require")?);
+    let loaded = registry_table(state, LOADED);
+    let module = state.table(loaded).get(&name);
+    if !module.is_falsy() {
+        return Ok(vec![module]);
+    }
+    let (loader, extra) = find_loader(state, &name)?;
+    let module = state.call_value(loader, vec![name.clone(), extra])?.into_first();
+    if !module.is_nil() {
+        state.table_set(loaded, name.clone(), module)?;
+    }
+    // a module returning nothing, and not storing itself, is loaded as true
+    if state.table(loaded).get(&name).is_nil() {
+        state.table_set(loaded, name.clone(), SyxValue::Bool(true))?;
+    }
+    Ok(vec![state.table(loaded).get(&name)])
+}
+
+// The loader the first searcher finding `name` returns, with its extra value
+fn find_loader(state: &mut SyxState, name: &SyxValue) -> Result<(SyxValue, SyxValue)> {
+    let searchers = match package_field(state, "searchers", "table")? {
+        SyxValue::Table(searchers) => searchers,
+        _ => unreachable!(),
+    };
+    let mut message = format!("module '{}' not found:", name_text(name));
+    for i in 1.. {
+        let searcher = state.table(searchers).get_int(i);
+        if searcher.is_nil() {
+            break;
+        }
+        let mut results = state.call_value(searcher, vec![name.clone()])?.into_vec();
+        results.resize(2, SyxValue::Nil);
+        if results[0].is_function() {
+            let extra = results.pop().unwrap();
+            return Ok((results.pop().unwrap(), extra));
+        }
+        if let SyxValue::String(ref reason) = results[0] {
+            message.push_str(&reason.to_string());
+        }
+    }
+    runtime_error(message)
+}
+
+fn name_text(name: &SyxValue) -> String {
+    match *name {
+        SyxValue::String(ref name) => name.to_string(),
+        _ => unreachable!(),
+    }
+}
+
+// The first file along `path` that can be read, with `sep` in `name`
+// replaced by `rep` and the result put in place of each '?'. Fails with a
+// line for each file tried.
+fn search_path(state: &mut SyxState, name: &str, path: &str, sep: &str, rep: &str)
+    -> ::std::result::Result<String, String>
+{
+    let name = if sep.is_empty() { name.to_owned() } else { name.replace(sep, rep) };
+    let mut message = String::new();
+    for template in path.split(';').filter(|template| !template.is_empty()) {
+        let filename = template.replace('?', &name);
+        if state.io.readable(&filename) {
+            return Ok(filename);
+        }
+        message.push_str(&format!("\n\tno file '{}'", filename));
+    }
+    Err(message)
+}
+
+// searchpath(name, path [, sep [, rep]])
+fn searchpath(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let name = check_string(&args, 1, "searchpath")?.to_string();
+    let path = check_string(&args, 2, "searchpath")?.to_string();
+    let sep = opt_string(&args, 3, "searchpath", ".")?.to_string();
+    let rep = opt_string(&args, 4, "searchpath", "/")?.to_string();
+    match search_path(state, &name, &path, &sep, &rep) {
+        Ok(filename) => Ok(vec![SyxValue::from(filename.as_str())]),
+        Err(message) => Ok(vec![SyxValue::Nil, SyxValue::from(message.as_str())]),
+    }
+}
+
+// The file along package.`field` for module `name`, or why there is none
+fn search_file(state: &mut SyxState, args: &[SyxValue], field: &str)
+    -> Result<::std::result::Result<String, String>>
+{
+    let name = check_string(args, 1, "searcher")?.to_string();
+    let path = match package_field(state, field, "string")? {
+        SyxValue::String(path) => path.to_string(),
+        _ => unreachable!(),
+    };
+    Ok(search_path(state, &name, &path, ".", "/"))
+}
+
+fn load_error<T>(name: &SyxValue, filename: &str, message: &str) -> Result<T> {
+    runtime_error(format!("error loading module '{}' from file '{}':\n\t{}",
+                          name_text(name), filename, message))
+}
+
+fn search_preload(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let name = check_string(&args, 1, "searcher")?;
+    let preload = registry_table(state, PRELOAD);
+    let loader = state.table(preload).get(&SyxValue::String(name.clone()));
+    if loader.is_nil() {
+        let message = format!("\n\tno field package.preload['{}']", name);
+        return Ok(vec![SyxValue::from(message.as_str())]);
+    }
+    Ok(vec![loader])
+}
+
+// a Lua file, source or binary, along package.path
+fn search_lua(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let filename = match search_file(state, &args, "path")? {
+        Ok(filename) => filename,
+        Err(message) => return Ok(vec![SyxValue::from(message.as_str())]),
+    };
+    let contents = match state.io.read_file(&filename) {
+        Ok(contents) => contents,
+        Err(error) => return load_error(&args[0], &filename, &error_message(&error, None)),
+    };
+    let mode = state.load_mode.clone();
+    let proto = match load_contents(contents, &filename, &mode) {
+        Ok(proto) => proto,
+        Err(error) => return load_error(&args[0], &filename, &error.to_string()),
+    };
+    let loader = state.new_main_closure(Arc::new(proto));
+    Ok(vec![SyxValue::Function(loader), SyxValue::from(filename.as_str())])
+}
+
+// a C library along package.cpath, which there is no loading
+fn search_c(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    match search_file(state, &args, "cpath")? {
+        Ok(filename) => load_error(&args[0], &filename,
+                                   "dynamic libraries not enabled; check your Lua installation"),
+        Err(message) => Ok(vec![SyxValue::from(message.as_str())]),
+    }
+}
+
+impl SyxState {
+    // Make `require(name)` call `loader` the first time, with the name as its
+    // argument, without looking any further. Its first result is the module.
+    // The package library need not be open yet.
+    pub fn preload_module(&mut self, name: &str,
+                          loader: impl Fn(&mut SyxState, Args) -> Result<MultiValue>
+                                      + Send + Sync + 'static)
+    {
+        let loader = self.create_function(name, loader);
+        let preload = registry_table(self, PRELOAD);
+        set_field(self, preload, name, loader);
+    }
+
+    // Append `searcher` to package.searchers. It is called with the name of
+    // a module no earlier searcher found.
+    pub fn add_searcher(&mut self,
+                        searcher: impl Fn(&mut SyxState, &str) -> Result<Search>
+                                       + Send + Sync + 'static)
+        -> Result<()>
+    {
+        let searchers = match package_field(self, "searchers", "table")? {
+            SyxValue::Table(searchers) => searchers,
+            _ => unreachable!(),
+        };
+        let function = self.create_function("searcher", move |state, args| {
+            let name = args.check_string(1)?.to_string();
+            let results = match searcher(state, &name)? {
+                Search::Found(loader, extra) => vec![loader, extra],
+                Search::NotFound(message) => {
+                    vec![SyxValue::from(format!("\n\t{}", message).as_str())]
+                }
+            };
+            Ok(results.into())
+        });
+        let n = self.table(searchers).length();
+        self.table_set(searchers, SyxValue::Integer(n + 1), function)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::*;
+    use super::super::super::compiler::parse;
+    use super::super::open_libs;
+    use super::super::io::NoIo;
+    use super::super::super::testing::run_in;
+
+    fn error(state: &mut SyxState, source: &str) -> String {
+        run_in(state, source).expect_err("should fail").to_string()
+    }
+
+    #[test]
+    fn test_require_file() {
+        let dir = env::temp_dir().join(format!("syx-package-{}", process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("lib/counter.lua"),
+                  "#!shebang\nloads = (loads or 0) + 1 return {name = ..., file = select(2, ...)}")
+            .unwrap();
+        fs::write(dir.join("broken.lua"), "return +").unwrap();
+        let mut state = SyxState::new();
+        open_libs(&mut state);
+        let path = format!("{}/?.lua", dir.display());
+        let globals = state.globals();
+        set_field(&mut state, globals, "dir", SyxValue::from(path.as_str()));
+
+        let results = run_in(&mut state, "
+            package.path = dir
+            local a = require 'lib.counter'
+            local b = require 'lib.counter'
+            return a == b, loads, a.name, a.file == package.searchpath('lib.counter', dir),
+                   package.loaded['lib.counter'] == a, require 'string' == string
+        ").unwrap();
+        assert!(results == vec![SyxValue::Bool(true), SyxValue::Integer(1),
+                                SyxValue::from("lib.counter"), SyxValue::Bool(true),
+                                SyxValue::Bool(true), SyxValue::Bool(true)]);
+        let message = error(&mut state, "require 'broken'");
+        let expected = "test:1: error loading module 'broken' from file '";
+        assert!(message.starts_with(expected), "{}", message);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_not_found() {
+        let mut state = SyxState::new();
+        state.set_io_backend(Box::new(NoIo));
+        open_libs(&mut state);
+        assert_eq!(error(&mut state, "
+            package.path = './?.lua;./?/init.lua' package.cpath = './?.so'
+            require 'a.b'
+        "), "test:3: module 'a.b' not found:\n\tno field package.preload['a.b']\
+             \n\tno file './a/b.lua'\n\tno file './a/b/init.lua'\n\tno file './a/b.so'");
+        assert_eq!(error(&mut state, "package.path = nil require 'x'"),
+                   "test:1: 'package.path' must be a string");
+
+        let results = run_in(&mut state, "
+            package.preload.answer = function(name) return 42 end
+            package.preload.empty = function() end
+            return require 'answer', require 'empty', package.searchpath('a', 'x/?;?.y')
+        ").unwrap();
+        assert!(results == vec![SyxValue::Integer(42), SyxValue::Bool(true), SyxValue::Nil,
+                                SyxValue::from("\n\tno file 'x/a'\n\tno file 'a.y'")]);
+    }
+
+    #[test]
+    fn test_add_searcher() {
+        let mut state = SyxState::new();
+        assert!(state.add_searcher(|_, _| Ok(Search::NotFound(String::new()))).is_err());
+        state.set_io_backend(Box::new(NoIo));
+        open_libs(&mut state);
+        state.add_searcher(|state, name| {
+            if name != "bundled" {
+                return Ok(Search::NotFound(format!("no asset '{}'", name)));
+            }
+            let proto = parse(b"return ... .. '!'", "=bundled")?;
+            let loader = state.new_main_closure(Arc::new(proto));
+            Ok(Search::Found(SyxValue::Function(loader), SyxValue::Nil))
+        }).unwrap();
+        let results = run_in(&mut state, "return require 'bundled'").unwrap();
+        assert!(results == vec![SyxValue::from("bundled!")]);
+        let message = error(&mut state, "package.path = '' package.cpath = '' require 'other'");
+        assert_eq!(message, "test:1: module 'other' not found:\
+                             \n\tno field package.preload['other']\n\tno asset 'other'");
+    }
+
+    #[test]
+    fn test_preload_module() {
+        let mut state = SyxState::new();
+        state.set_io_backend(Box::new(NoIo));
+        state.preload_module("vec", |state, args| {
+            let lib = state.new_table(0, 1);
+            let name = args.check_any(1)?;
+            set_field(state, lib, "name", name);
+            Ok(vec![SyxValue::Table(lib)].into())
+        });
+        open_libs(&mut state);
+        let results = run_in(&mut state, "
+            local vec = require 'vec'
+            return vec.name, require 'vec' == vec, package.loaded.vec == vec
+        ").unwrap();
+        assert!(results == vec![SyxValue::from("vec"), SyxValue::Bool(true),
+                                SyxValue::Bool(true)]);
+    }
+}
@@ -0,0 +1,443 @@
+// Lua patterns
+//
+// A backtracking matcher for the pattern language of string.find, match,
+// gmatch and gsub: single character classes with the `*`, `+`, `-` and `?`
+// repetitions, `%b` balanced pairs, `%f` frontiers, anchors, back references
+// and captures, which are either substrings or (for `()`) positions.
+// Positions are byte offsets into the subject. Errors in a pattern are only
+// found as far as matching gets, as in PUC-Rio.
+//
+// Consult the versioned lstrlib.c for more information.
+
+use super::super::errors::*;
+use super::super::object::{SyxInteger, SyxValue};
+use super::super::state::SyxState;
+use super::super::vm::runtime_error;
+
+const L_ESC: u8 = b'%';
+const SPECIALS: &[u8] = b"^$*+?.([%-";
+const MAXCCALLS: usize = 200; // nesting of the matcher before giving up
+const MAXCAPTURES: usize = 32;
+
+#[derive(Clone, Copy, PartialEq)]
+enum CaptureLen {
+    Unfinished,
+    Position,
+    Len(usize),
+}
+
+// true when `pattern` has nothing but plain characters
+pub(crate) fn no_specials(pattern: &[u8]) -> bool {
+    !pattern.iter().any(|byte| SPECIALS.contains(byte))
+}
+
+// C's <ctype.h> classes, in the C locale
+fn match_class(c: u8, class: u8) -> bool {
+    let matches = match class.to_ascii_lowercase() {
+        b'a' => c.is_ascii_alphabetic(),
+        b'c' => c.is_ascii_control(),
+        b'd' => c.is_ascii_digit(),
+        b'g' => c.is_ascii_graphic(),
+        b'l' => c.is_ascii_lowercase(),
+        b'p' => c.is_ascii_punctuation(),
+        b's' => matches!(c, b' ' | b'\t' | b'\n' | b'\x0b' | b'\x0c' | b'\r'),
+        b'u' => c.is_ascii_uppercase(),
+        b'w' => c.is_ascii_alphanumeric(),
+        b'x' => c.is_ascii_hexdigit(),
+        _ => return class == c,
+    };
+    // upper case letters are the complement of the class
+    matches != class.is_ascii_uppercase()
+}
+
+pub(crate) struct MatchState<'a> {
+    src: &'a [u8],
+    pattern: &'a [u8],
+    depth: usize,
+    level: usize, // number of captures, finished or not
+    captures: [(usize, CaptureLen); MAXCAPTURES],
+}
+
+impl<'a> MatchState<'a> {
+    pub(crate) fn new(src: &'a [u8], pattern: &'a [u8]) -> MatchState<'a> {
+        MatchState {
+            src,
+            pattern,
+            depth: MAXCCALLS,
+            level: 0,
+            captures: [(0, CaptureLen::Unfinished); MAXCAPTURES],
+        }
+    }
+
+    // Try to match the pattern from index `p` against the subject at `s`,
+    // returning the end of the match. Clears the captures of any earlier
+    // attempt.
+    pub(crate) fn find_at(&mut self, s: usize, p: usize) -> Result<Option<usize>> {
+        self.level = 0;
+        self.depth = MAXCCALLS;
+        self.do_match(s, p)
+    }
+
+    // end of the single character class at `p`
+    fn class_end(&self, mut p: usize) -> Result<usize> {
+        let c = self.pattern[p];
+        p += 1;
+        if c == L_ESC {
+            if p >= self.pattern.len() {
+                return runtime_error("malformed pattern (ends with '%')".to_owned());
+            }
+            return Ok(p + 1);
+        }
+        if c == b'[' {
+            if self.pattern.get(p) == Some(&b'^') {
+                p += 1;
+            }
+            // the first character is never the closing ']'
+            loop {
+                if p >= self.pattern.len() {
+                    return runtime_error("malformed pattern (missing ']')".to_owned());
+                }
+                let c = self.pattern[p];
+                p += 1;
+                if c == L_ESC && p < self.pattern.len() {
+                    p += 1; // skip escapes, such as '%]'
+                }
+                if self.pattern.get(p) == Some(&b']') {
+                    return Ok(p + 1);
+                }
+            }
+        }
+        Ok(p)
+    }
+
+    // whether `c` is in the set from the '[' at `p` to the ']' at `end`
+    fn match_bracket_class(&self, c: u8, mut p: usize, end: usize) -> bool {
+        let mut sig = true;
+        if self.pattern[p + 1] == b'^' {
+            sig = false;
+            p += 1;
+        }
+        p += 1;
+        while p < end {
+            let pc = self.pattern[p];
+            if pc == L_ESC {
+                p += 1;
+                if match_class(c, self.pattern[p]) {
+                    return sig;
+                }
+            } else if self.pattern[p + 1] == b'-' && p + 2 < end {
+                p += 2;
+                if pc <= c && c <= self.pattern[p] {
+                    return sig;
+                }
+            } else if pc == c {
+                return sig;
+            }
+            p += 1;
+        }
+        !sig
+    }
+
+    // whether the subject at `s` matches the class from `p` to `ep`
+    fn single_match(&self, s: usize, p: usize, ep: usize) -> bool {
+        let c = match self.src.get(s) {
+            Some(&c) => c,
+            None => return false,
+        };
+        match self.pattern[p] {
+            b'.' => true,
+            L_ESC => match_class(c, self.pattern[p + 1]),
+            b'[' => self.match_bracket_class(c, p, ep - 1),
+            pc => pc == c,
+        }
+    }
+
+    fn do_match(&mut self, s: usize, p: usize) -> Result<Option<usize>> {
+        if self.depth == 0 {
+            return runtime_error("pattern too complex".to_owned());
+        }
+        self.depth -= 1;
+        let result = self.match_here(s, p);
+        self.depth += 1;
+        result
+    }
+
+    fn match_here(&mut self, mut s: usize, mut p: usize) -> Result<Option<usize>> {
+        loop {
+            if p == self.pattern.len() {
+                return Ok(Some(s));
+            }
+            match self.pattern[p] {
+                b'(' => {
+                    return if self.pattern.get(p + 1) == Some(&b')') {
+                        self.start_capture(s, p + 2, CaptureLen::Position)
+                    } else {
+                        self.start_capture(s, p + 1, CaptureLen::Unfinished)
+                    };
+                }
+                b')' => return self.end_capture(s, p + 1),
+                b'$' if p + 1 == self.pattern.len() => {
+                    return Ok(if s == self.src.len() { Some(s) } else { None });
+                }
+                L_ESC if self.pattern.get(p + 1) == Some(&b'b') => {
+                    match self.match_balance(s, p + 2)? {
+                        Some(end) => {
+                            s = end;
+                            p += 4;
+                            continue;
+                        }
+                        None => return Ok(None),
+                    }
+                }
+                L_ESC if self.pattern.get(p + 1) == Some(&b'f') => {
+                    p += 2;
+                    if self.pattern.get(p) != Some(&b'[') {
+                        return runtime_error("missing '[' after '%f' in pattern".to_owned());
+                    }
+                    let ep = self.class_end(p)?;
+                    let previous = if s == 0 { 0 } else { self.src[s - 1] };
+                    let current = self.src.get(s).cloned().unwrap_or(0);
+                    if !self.match_bracket_class(previous, p, ep - 1) &&
+                        self.match_bracket_class(current, p, ep - 1) {
+                        p = ep;
+                        continue;
+                    }
+                    return Ok(None);
+                }
+                L_ESC if self.pattern.get(p + 1).is_some_and(u8::is_ascii_digit) => {
+                    match self.match_capture(s, self.pattern[p + 1])? {
+                        Some(end) => {
+                            s = end;
+                            p += 2;
+                            continue;
+                        }
+                        None => return Ok(None),
+                    }
+                }
+                _ => {}
+            }
+            let ep = self.class_end(p)?;
+            let repetition = self.pattern.get(ep).cloned();
+            if !self.single_match(s, p, ep) {
+                // '*', '?' and '-' accept zero repetitions
+                if let Some(b'*') | Some(b'?') | Some(b'-') = repetition {
+                    p = ep + 1;
+                    continue;
+                }
+                return Ok(None);
+            }
+            match repetition {
+                Some(b'?') => {
+                    if let Some(end) = self.do_match(s + 1, ep + 1)? {
+                        return Ok(Some(end));
+                    }
+                    p = ep + 1;
+                }
+                Some(b'+') => return self.max_expand(s + 1, p, ep),
+                Some(b'*') => return self.max_expand(s, p, ep),
+                Some(b'-') => return self.min_expand(s, p, ep),
+                _ => {
+                    s += 1;
+                    p = ep;
+                }
+            }
+        }
+    }
+
+    // the longest run of the class that lets the rest of the pattern match
+    fn max_expand(&mut self, s: usize, p: usize, ep: usize) -> Result<Option<usize>> {
+        let mut count = 0;
+        while self.single_match(s + count, p, ep) {
+            count += 1;
+        }
+        loop {
+            if let Some(end) = self.do_match(s + count, ep + 1)? {
+                return Ok(Some(end));
+            }
+            if count == 0 {
+                return Ok(None);
+            }
+            count -= 1;
+        }
+    }
+
+    // the shortest run of the class that lets the rest of the pattern match
+    fn min_expand(&mut self, mut s: usize, p: usize, ep: usize) -> Result<Option<usize>> {
+        loop {
+            if let Some(end) = self.do_match(s, ep + 1)? {
+                return Ok(Some(end));
+            }
+            if !self.single_match(s, p, ep) {
+                return Ok(None);
+            }
+            s += 1;
+        }
+    }
+
+    fn match_balance(&self, s: usize, p: usize) -> Result<Option<usize>> {
+        if p + 1 >= self.pattern.len() {
+            return runtime_error("malformed pattern (missing arguments to '%b')".to_owned());
+        }
+        let (open, close) = (self.pattern[p], self.pattern[p + 1]);
+        if self.src.get(s) != Some(&open) {
+            return Ok(None);
+        }
+        let mut depth = 1;
+        for (i, &c) in self.src.iter().enumerate().skip(s + 1) {
+            if c == close {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(Some(i + 1));
+                }
+            } else if c == open {
+                depth += 1;
+            }
+        }
+        Ok(None)
+    }
+
+    fn start_capture(&mut self, s: usize, p: usize, what: CaptureLen) -> Result<Option<usize>> {
+        if self.level >= MAXCAPTURES {
+            return runtime_error("too many captures".to_owned());
+        }
+        self.captures[self.level] = (s, what);
+        self.level += 1;
+        let result = self.do_match(s, p)?;
+        if result.is_none() {
+            self.level -= 1; // undo the capture
+        }
+        Ok(result)
+    }
+
+    fn end_capture(&mut self, s: usize, p: usize) -> Result<Option<usize>> {
+        let open = (0..self.level).rev()
+            .find(|&l| self.captures[l].1 == CaptureLen::Unfinished);
+        let l = match open {
+            Some(l) => l,
+            None => return runtime_error("invalid pattern capture".to_owned()),
+        };
+        self.captures[l].1 = CaptureLen::Len(s - self.captures[l].0);
+        let result = self.do_match(s, p)?;
+        if result.is_none() {
+            self.captures[l].1 = CaptureLen::Unfinished; // undo the capture
+        }
+        Ok(result)
+    }
+
+    // back reference to capture `digit`, as in "%1"
+    fn match_capture(&self, s: usize, digit: u8) -> Result<Option<usize>> {
+        let l = (digit - b'0') as usize;
+        if l == 0 || l > self.level || self.captures[l - 1].1 == CaptureLen::Unfinished {
+            return runtime_error(format!("invalid capture index %{}", l));
+        }
+        match self.captures[l - 1] {
+            (start, CaptureLen::Len(len)) if self.src[s..].starts_with(
+                &self.src[start..start + len]) => Ok(Some(s + len)),
+            _ => Ok(None),
+        }
+    }
+
+    // Value of capture `i`, or of the whole match from `s` to `e` when the
+    // pattern has no captures and `i` is 0
+    pub(crate) fn capture(&self, state: &mut SyxState, i: usize, s: usize, e: usize)
+        -> Result<SyxValue>
+    {
+        if i >= self.level {
+            if i != 0 {
+                return runtime_error(format!("invalid capture index %{}", i + 1));
+            }
+            return Ok(SyxValue::String(state.intern(&self.src[s..e])));
+        }
+        match self.captures[i] {
+            (_, CaptureLen::Unfinished) => runtime_error("unfinished capture".to_owned()),
+            (start, CaptureLen::Position) => Ok(SyxValue::Integer(start as SyxInteger + 1)),
+            (start, CaptureLen::Len(len)) => {
+                Ok(SyxValue::String(state.intern(&self.src[start..start + len])))
+            }
+        }
+    }
+
+    // Every capture of a match from `s` to `e`, or the whole match if there
+    // are none and `whole` is set
+    pub(crate) fn captures(&self, state: &mut SyxState, s: usize, e: usize, whole: bool)
+        -> Result<Vec<SyxValue>>
+    {
+        let count = if self.level == 0 && whole { 1 } else { self.level };
+        (0..count).map(|i| self.capture(state, i, s, e)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // start and end of the first match, and the captures as strings
+    fn find(src: &str, pattern: &str) -> Option<(usize, usize, Vec<String>)> {
+        let mut state = SyxState::new();
+        let mut ms = MatchState::new(src.as_bytes(), pattern.as_bytes());
+        for s in 0..=src.len() {
+            if let Some(e) = ms.find_at(s, 0).unwrap() {
+                let captures = ms.captures(&mut state, s, e, false).unwrap().iter()
+                    .map(|value| match *value {
+                        SyxValue::String(ref s) => s.to_string(),
+                        ref other => format!("{:?}", other),
+                    })
+                    .collect();
+                return Some((s, e, captures));
+            }
+        }
+        None
+    }
+
+    fn error(src: &str, pattern: &str) -> String {
+        let mut ms = MatchState::new(src.as_bytes(), pattern.as_bytes());
+        ms.find_at(0, 0).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_classes() {
+        assert_eq!(find("hello world", "o w"), Some((4, 7, vec![])));
+        assert_eq!(find("x = 42;", "%d+"), Some((4, 6, vec![])));
+        assert_eq!(find("abc", "%A"), None);
+        assert_eq!(find("a.b", "%."), Some((1, 2, vec![])));
+        assert_eq!(find("x]y", "[]]"), Some((1, 2, vec![])));
+        assert_eq!(find("key_1=", "[%w_]+"), Some((0, 5, vec![])));
+        assert_eq!(find("abc-def", "[^%a]"), Some((3, 4, vec![])));
+        assert_eq!(find("0x1F", "[0-9a-fA-F]+$"), Some((2, 4, vec![])));
+        assert_eq!(find("\x0b", "%s"), Some((0, 1, vec![])));
+    }
+
+    #[test]
+    fn test_repetitions() {
+        assert_eq!(find("<a><b>", "<.*>"), Some((0, 6, vec![])));
+        assert_eq!(find("<a><b>", "<.->"), Some((0, 3, vec![])));
+        assert_eq!(find("color", "colou?r"), Some((0, 5, vec![])));
+        assert_eq!(find("aaa", "a-$"), Some((0, 3, vec![])));
+        assert_eq!(find("", "x*"), Some((0, 0, vec![])));
+    }
+
+    #[test]
+    fn test_captures() {
+        assert_eq!(find("key = value", "(%w+)%s*=%s*(%w+)"),
+                   Some((0, 11, vec!["key".to_owned(), "value".to_owned()])));
+        assert_eq!(find("hello", "()ll()"),
+                   Some((2, 4, vec!["Integer(3)".to_owned(), "Integer(5)".to_owned()])));
+        assert_eq!(find("say \"hi\" now", "([\"'])(.-)%1"),
+                   Some((4, 8, vec!["\"".to_owned(), "hi".to_owned()])));
+        assert_eq!(find("f(a(b)c) d", "%b()"), Some((1, 8, vec![])));
+        assert_eq!(find("THE (quick) fox", "%f[%a]%a+%f[%A]"), Some((0, 3, vec![])));
+        assert_eq!(find("THE (quick) fox", "%f[%l]%a+"), Some((5, 10, vec![])));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(error("a", "%"), "malformed pattern (ends with '%')");
+        assert_eq!(error("a", "[a"), "malformed pattern (missing ']')");
+        assert_eq!(error("a", "a)"), "invalid pattern capture");
+        assert_eq!(error("a", "%1"), "invalid capture index %1");
+        assert_eq!(error("a", "%b"), "malformed pattern (missing arguments to '%b')");
+        assert_eq!(error("a", "%fa"), "missing '[' after '%f' in pattern");
+        assert_eq!(error("a", &"(".repeat(33)), "too many captures");
+        assert_eq!(error(&"a".repeat(300), &"a?".repeat(300)), "pattern too complex");
+    }
+}
@@ -0,0 +1,791 @@
+// String library
+//
+// Strings are byte strings, and every function here works on bytes: lengths
+// and positions count bytes, and upper/lower only map ASCII letters, as the
+// C locale does. Negative positions count back from the end of the string.
+// The library table is also the __index of the string metatable, so its
+// functions can be called as methods on any string. Patterns are matched by
+// pattern.rs, and binary strings packed and unpacked by pack.rs.
+//
+// Consult the versioned lstrlib.c for more information.
+
+use super::super::errors::*;
+use super::super::numfmt::{exponent_form, general_form, hex_form};
+use super::super::object::{SyxInteger, SyxNumber, SyxUnsigned, SyxValue};
+use super::super::state::SyxState;
+use super::super::vm::{bad_argument, runtime_error};
+use super::pack::{str_pack, str_packsize, str_unpack};
+use super::pattern::{no_specials, MatchState};
+use super::{
+    check_any, check_integer, check_number, check_string, new_lib, opt_integer, opt_string,
+    set_field, tostring, type_error,
+};
+
+pub fn open(state: &mut SyxState) {
+    let lib = new_lib(state, "string", &[
+        ("len", str_len),
+        ("sub", str_sub),
+        ("upper", str_upper),
+        ("lower", str_lower),
+        ("rep", str_rep),
+        ("reverse", str_reverse),
+        ("byte", str_byte),
+        ("char", str_char),
+        ("format", str_format),
+        ("find", str_find),
+        ("match", str_match),
+        ("gmatch", str_gmatch),
+        ("gsub", str_gsub),
+        ("pack", str_pack),
+        ("packsize", str_packsize),
+        ("unpack", str_unpack),
+    ]);
+    let metatable = state.new_table(0, 1);
+    set_field(state, metatable, "__index", SyxValue::Table(lib));
+    state.string_metatable = Some(metatable);
+}
+
+// Translate a relative position to an absolute one, where -1 is the last
+// byte. Positions before the start come out as 0.
+pub(super) fn position(pos: SyxInteger, len: usize) -> SyxInteger {
+    if pos >= 0 {
+        pos
+    } else {
+        (len as SyxInteger + pos + 1).max(0)
+    }
+}
+
+// bytes `i` to `j` of a string of `len` bytes, as a range of indices
+fn byte_range(i: SyxInteger, j: SyxInteger, len: usize) -> std::ops::Range<usize> {
+    let start = position(i, len).max(1);
+    let end = position(j, len).min(len as SyxInteger);
+    if start > end {
+        0..0
+    } else {
+        start as usize - 1..end as usize
+    }
+}
+
+fn string_result(state: &mut SyxState, bytes: &[u8]) -> Result<Vec<SyxValue>> {
+    Ok(vec![SyxValue::String(state.intern(bytes))])
+}
+
+// append `bytes` to `out`, within the memory limit of the state
+fn append(state: &mut SyxState, out: &mut Vec<u8>, bytes: &[u8]) -> Result<()> {
+    state.reserve(out, bytes.len())?;
+    out.extend_from_slice(bytes);
+    Ok(())
+}
+
+fn str_len(_: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let s = check_string(&args, 1, "len")?;
+    Ok(vec![SyxValue::Integer(s.len() as SyxInteger)])
+}
+
+// sub(s, i [, j])
+fn str_sub(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let s = check_string(&args, 1, "sub")?;
+    let i = check_integer(&args, 2, "sub")?;
+    let j = opt_integer(&args, 3, "sub", -1)?;
+    string_result(state, &s[byte_range(i, j, s.len())])
+}
+
+fn str_upper(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let s = check_string(&args, 1, "upper")?;
+    string_result(state, &s.to_ascii_uppercase())
+}
+
+fn str_lower(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let s = check_string(&args, 1, "lower")?;
+    string_result(state, &s.to_ascii_lowercase())
+}
+
+// rep(s, n [, sep])
+fn str_rep(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let s = check_string(&args, 1, "rep")?;
+    let n = check_integer(&args, 2, "rep")?;
+    let sep = opt_string(&args, 3, "rep", "")?;
+    if n <= 0 {
+        return string_result(state, b"");
+    }
+    let n = n as usize;
+    let size = s.len().checked_mul(n)
+        .and_then(|size| size.checked_add(sep.len() * (n - 1)))
+        .filter(|&size| size < isize::MAX as usize);
+    let mut buffer = Vec::new();
+    match size {
+        Some(size) => state.reserve(&mut buffer, size)?,
+        None => return runtime_error("resulting string too large".to_owned()),
+    }
+    for i in 0..n {
+        if i > 0 {
+            buffer.extend_from_slice(&sep);
+        }
+        buffer.extend_from_slice(&s);
+    }
+    string_result(state, &buffer)
+}
+
+fn str_reverse(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let s = check_string(&args, 1, "reverse")?;
+    let reversed: Vec<u8> = s.iter().rev().cloned().collect();
+    string_result(state, &reversed)
+}
+
+// byte(s [, i [, j]]), the bytes from i to j as integers
+fn str_byte(_: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let s = check_string(&args, 1, "byte")?;
+    let i = opt_integer(&args, 2, "byte", 1)?;
+    let j = opt_integer(&args, 3, "byte", position(i, s.len()))?;
+    Ok(s[byte_range(i, j, s.len())].iter()
+        .map(|&b| SyxValue::Integer(b as SyxInteger))
+        .collect())
+}
+
+// char(...), the string made of the given bytes
+fn str_char(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let mut buffer = Vec::with_capacity(args.len());
+    for n in 1..=args.len() {
+        match check_integer(&args, n, "char")? {
+            c @ 0..=255 => buffer.push(c as u8),
+            _ => return bad_argument(n, "char", "value out of range"),
+        }
+    }
+    string_result(state, &buffer)
+}
+
+// Flags, width and precision of a format directive. Width and precision
+// are at most two digits, as in PUC-Rio.
+#[derive(Default)]
+struct Spec {
+    left: bool,   // '-'
+    plus: bool,   // '+'
+    space: bool,  // ' '
+    alt: bool,    // '#'
+    zero: bool,   // '0'
+    width: usize,
+    precision: Option<usize>,
+}
+
+impl Spec {
+    fn is_plain(&self) -> bool {
+        !(self.left || self.plus || self.space || self.alt || self.zero) &&
+            self.width == 0 && self.precision.is_none()
+    }
+
+    // sign of a number, as the flags ask for it
+    fn sign(&self, negative: bool) -> &'static str {
+        if negative {
+            "-"
+        } else if self.plus {
+            "+"
+        } else if self.space {
+            " "
+        } else {
+            ""
+        }
+    }
+
+    // Append `prefix` and `body` to `out`, padded to the width. With the '0'
+    // flag, numbers are padded with zeros between the two.
+    fn pad(&self, out: &mut Vec<u8>, prefix: &str, body: &[u8], numeric: bool) {
+        let fill = self.width.saturating_sub(prefix.len() + body.len());
+        if self.left {
+            out.extend_from_slice(prefix.as_bytes());
+            out.extend_from_slice(body);
+            out.resize(out.len() + fill, b' ');
+        } else if self.zero && numeric {
+            out.extend_from_slice(prefix.as_bytes());
+            out.resize(out.len() + fill, b'0');
+            out.extend_from_slice(body);
+        } else {
+            out.resize(out.len() + fill, b' ');
+            out.extend_from_slice(prefix.as_bytes());
+            out.extend_from_slice(body);
+        }
+    }
+}
+
+// up to two digits starting at `*i`
+fn parse_digits(format: &[u8], i: &mut usize) -> usize {
+    let mut value = 0;
+    for _ in 0..2 {
+        match format.get(*i) {
+            Some(&d) if d.is_ascii_digit() => {
+                value = value * 10 + (d - b'0') as usize;
+                *i += 1;
+            }
+            _ => break,
+        }
+    }
+    value
+}
+
+// directive starting after the '%' at `*i`, and its conversion character
+fn parse_spec(format: &[u8], i: &mut usize) -> Result<(Spec, u8)> {
+    let mut spec = Spec::default();
+    let start = *i;
+    while let Some(&flag) = format.get(*i) {
+        match flag {
+            b'-' => spec.left = true,
+            b'+' => spec.plus = true,
+            b' ' => spec.space = true,
+            b'#' => spec.alt = true,
+            b'0' => spec.zero = true,
+            _ => break,
+        }
+        *i += 1;
+    }
+    if *i - start > 5 {
+        return runtime_error("invalid format (repeated flags)".to_owned());
+    }
+    spec.width = parse_digits(format, i);
+    if format.get(*i) == Some(&b'.') {
+        *i += 1;
+        spec.precision = Some(parse_digits(format, i));
+    }
+    if format.get(*i).is_some_and(u8::is_ascii_digit) {
+        return runtime_error("invalid format (width or precision too long)".to_owned());
+    }
+    let conversion = format.get(*i).cloned().unwrap_or(b'\0');
+    *i += 1;
+    Ok((spec, conversion))
+}
+
+fn format_integer(out: &mut Vec<u8>, spec: &Spec, conversion: u8, n: SyxInteger) {
+    let mut digits = match conversion {
+        b'd' | b'i' => n.unsigned_abs().to_string(),
+        b'o' => format!("{:o}", n as SyxUnsigned),
+        b'u' => format!("{}", n as SyxUnsigned),
+        b'x' => format!("{:x}", n as SyxUnsigned),
+        _ => format!("{:X}", n as SyxUnsigned),
+    };
+    if let Some(precision) = spec.precision {
+        if precision == 0 && n == 0 {
+            digits.clear();
+        } else if digits.len() < precision {
+            digits.insert_str(0, &"0".repeat(precision - digits.len()));
+        }
+    }
+    let prefix = match conversion {
+        b'd' | b'i' => spec.sign(n < 0),
+        b'o' if spec.alt && !digits.starts_with('0') => "0",
+        b'x' if spec.alt && n != 0 => "0x",
+        b'X' if spec.alt && n != 0 => "0X",
+        _ => "",
+    };
+    // as in C, a precision turns off zero padding
+    spec.pad(out, prefix, digits.as_bytes(), spec.precision.is_none());
+}
+
+fn format_float(out: &mut Vec<u8>, spec: &Spec, conversion: u8, x: SyxNumber) {
+    // NaN has a sign as well, "-nan"
+    let sign = spec.sign(x.is_sign_negative());
+    let upper = conversion.is_ascii_uppercase();
+    let magnitude = x.abs();
+    if !x.is_finite() {
+        let body = if x.is_nan() { "nan" } else { "inf" };
+        let body = if upper { body.to_ascii_uppercase() } else { body.to_owned() };
+        return spec.pad(out, sign, body.as_bytes(), false);
+    }
+    let precision = spec.precision.unwrap_or(6);
+    let (prefix, body) = match conversion.to_ascii_lowercase() {
+        b'f' => {
+            let mut fixed = format!("{:.*}", precision, magnitude);
+            if spec.alt && precision == 0 {
+                fixed.push('.');
+            }
+            (sign.to_owned(), fixed)
+        }
+        b'e' => (sign.to_owned(), exponent_form(magnitude, precision, spec.alt)),
+        b'g' => (sign.to_owned(), general_form(magnitude, precision, spec.alt)),
+        _ => (format!("{}0x", sign), hex_form(magnitude, spec.precision, spec.alt)),
+    };
+    let (prefix, body) = if upper {
+        (prefix.to_ascii_uppercase(), body.to_ascii_uppercase())
+    } else {
+        (prefix, body)
+    };
+    spec.pad(out, &prefix, body.as_bytes(), true);
+}
+
+// `value` as Lua source that reads back as the same value, for %q
+fn format_literal(state: &mut SyxState, out: &mut Vec<u8>, value: &SyxValue, n: usize)
+    -> Result<()>
+{
+    match *value {
+        SyxValue::String(ref s) => {
+            state.reserve(out, s.len() + 2)?;
+            out.push(b'"');
+            for (i, &byte) in s.iter().enumerate() {
+                match byte {
+                    b'"' | b'\\' | b'\n' => out.extend_from_slice(&[b'\\', byte]),
+                    // escapes run up to three digits, so pad when one follows
+                    byte if byte == 0 || byte.is_ascii_control() => {
+                        let escape = if s.get(i + 1).is_some_and(u8::is_ascii_digit) {
+                            format!("\\{:03}", byte)
+                        } else {
+                            format!("\\{}", byte)
+                        };
+                        out.extend(escape.bytes());
+                    }
+                    byte => out.push(byte),
+                }
+            }
+            out.push(b'"');
+        }
+        // the minimum integer has no decimal literal, "-9223372036854775808"
+        // reads as a float
+        SyxValue::Integer(SyxInteger::MIN) => {
+            out.extend(format!("0x{:x}", SyxInteger::MIN as SyxUnsigned).bytes());
+        }
+        SyxValue::Integer(i) => out.extend(i.to_string().bytes()),
+        SyxValue::Number(x) => {
+            let literal = if x.is_nan() {
+                "(0/0)".to_owned()
+            } else if x.is_infinite() {
+                if x < 0.0 { "-1e9999" } else { "1e9999" }.to_owned()
+            } else {
+                let sign = if x.is_sign_negative() { "-" } else { "" };
+                format!("{}0x{}", sign, hex_form(x.abs(), None, false))
+            };
+            out.extend(literal.bytes());
+        }
+        SyxValue::Nil | SyxValue::Bool(_) => out.extend_from_slice(&tostring(state, value)?),
+        _ => return bad_argument(n, "format", "value has no literal form"),
+    }
+    Ok(())
+}
+
+// format(fmt, ...), as C's sprintf with Lua values
+fn str_format(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let format = check_string(&args, 1, "format")?;
+    let mut out = Vec::with_capacity(format.len());
+    let mut n = 1; // last argument used
+    let mut i = 0;
+    while i < format.len() {
+        let byte = format[i];
+        i += 1;
+        if byte != b'%' {
+            out.push(byte);
+            continue;
+        }
+        if format.get(i) == Some(&b'%') {
+            out.push(b'%');
+            i += 1;
+            continue;
+        }
+        let (spec, conversion) = parse_spec(&format, &mut i)?;
+        n += 1;
+        if n > args.len() {
+            return bad_argument(n, "format", "no value");
+        }
+        match conversion {
+            b'c' => {
+                let c = check_integer(&args, n, "format")?;
+                spec.pad(&mut out, "", &[c as u8], false);
+            }
+            b'd' | b'i' | b'o' | b'u' | b'x' | b'X' => {
+                let value = check_integer(&args, n, "format")?;
+                format_integer(&mut out, &spec, conversion, value);
+            }
+            b'a' | b'A' | b'e' | b'E' | b'f' | b'F' | b'g' | b'G' => {
+                let value = check_number(&args, n, "format")?;
+                format_float(&mut out, &spec, conversion, value);
+            }
+            b'q' => format_literal(state, &mut out, &args[n - 1], n)?,
+            b's' => {
+                let s = tostring(state, &args[n - 1])?;
+                if spec.is_plain() {
+                    append(state, &mut out, &s)?;
+                } else if s.contains(&0) {
+                    return bad_argument(n, "format", "string contains zeros");
+                } else {
+                    let end = spec.precision.map_or(s.len(), |p| p.min(s.len()));
+                    state.reserve(&mut out, end + spec.width)?;
+                    spec.pad(&mut out, "", &s[..end], false);
+                }
+            }
+            _ => return runtime_error(format!("invalid option '%{}' to 'format'",
+                                              conversion as char)),
+        }
+    }
+    string_result(state, &out)
+}
+
+// find and match: the position or captures of the first match at or after
+// `init`, optionally anchored to it with "^"
+fn find_aux(state: &mut SyxState, args: Vec<SyxValue>, find: bool) -> Result<Vec<SyxValue>> {
+    let name = if find { "find" } else { "match" };
+    let s = check_string(&args, 1, name)?;
+    let pattern = check_string(&args, 2, name)?;
+    let init = position(opt_integer(&args, 3, name, 1)?, s.len()).max(1) as usize - 1;
+    if init > s.len() {
+        return Ok(vec![SyxValue::Nil]);
+    }
+    let plain = args.get(3).is_some_and(|value| !value.is_falsy());
+    if find && (plain || no_specials(&pattern)) {
+        let found = if pattern.is_empty() {
+            Some(0)
+        } else {
+            s[init..].windows(pattern.len()).position(|window| window == &pattern[..])
+        };
+        return Ok(match found {
+            Some(i) => vec![
+                SyxValue::Integer((init + i + 1) as SyxInteger),
+                SyxValue::Integer((init + i + pattern.len()) as SyxInteger),
+            ],
+            None => vec![SyxValue::Nil],
+        });
+    }
+    let anchor = pattern.first() == Some(&b'^');
+    let mut ms = MatchState::new(&s, &pattern);
+    for start in init..=s.len() {
+        if let Some(end) = ms.find_at(start, anchor as usize)? {
+            return if find {
+                let mut results = vec![
+                    SyxValue::Integer(start as SyxInteger + 1),
+                    SyxValue::Integer(end as SyxInteger),
+                ];
+                results.extend(ms.captures(state, start, end, false)?);
+                Ok(results)
+            } else {
+                ms.captures(state, start, end, true)
+            };
+        }
+        if anchor {
+            break;
+        }
+    }
+    Ok(vec![SyxValue::Nil])
+}
+
+// find(s, pattern [, init [, plain]])
+fn str_find(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    find_aux(state, args, true)
+}
+
+// match(s, pattern [, init])
+fn str_match(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    find_aux(state, args, false)
+}
+
+// gmatch(s, pattern). The iterator is a table holding the subject, the
+// pattern and where to go on from, called through its __call.
+fn str_gmatch(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let s = check_string(&args, 1, "gmatch")?;
+    let pattern = check_string(&args, 2, "gmatch")?;
+    let iterator = state.new_table(4, 0);
+    let metatable = state.new_table(0, 1);
+    set_field(state, metatable, "__call", SyxValue::Native(gmatch_next));
+    let table = state.table_mut(iterator);
+    table.set_int(1, SyxValue::String(s));
+    table.set_int(2, SyxValue::String(pattern));
+    table.set_int(3, SyxValue::Integer(0)); // where the next match may start
+    table.set_int(4, SyxValue::Integer(-1)); // end of the last match
+    table.set_metatable(Some(metatable));
+    Ok(vec![SyxValue::Table(iterator)])
+}
+
+fn gmatch_next(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let iterator = match args.first() {
+        Some(&SyxValue::Table(t)) => t,
+        _ => return runtime_error("invalid gmatch state".to_owned()),
+    };
+    let (s, pattern, start, last) = {
+        let table = state.table(iterator);
+        match (table.get_int(1), table.get_int(2), table.get_int(3), table.get_int(4)) {
+            (SyxValue::String(s), SyxValue::String(p), SyxValue::Integer(start),
+             SyxValue::Integer(last)) => (s, p, start as usize, last),
+            _ => return runtime_error("invalid gmatch state".to_owned()),
+        }
+    };
+    let mut ms = MatchState::new(&s, &pattern);
+    for start in start..=s.len() {
+        match ms.find_at(start, 0)? {
+            // an empty match right after the last one is skipped
+            Some(end) if end as SyxInteger != last => {
+                let table = state.table_mut(iterator);
+                table.set_int(3, SyxValue::Integer(end as SyxInteger));
+                table.set_int(4, SyxValue::Integer(end as SyxInteger));
+                return ms.captures(state, start, end, true);
+            }
+            _ => {}
+        }
+    }
+    state.table_mut(iterator).set_int(3, SyxValue::Integer(s.len() as SyxInteger + 1));
+    Ok(vec![SyxValue::Nil])
+}
+
+// append the replacement for the match from `start` to `end` to `out`
+fn add_value(state: &mut SyxState, ms: &MatchState, out: &mut Vec<u8>, start: usize,
+             end: usize, replacement: &SyxValue, whole: &[u8]) -> Result<()> {
+    let value = match *replacement {
+        SyxValue::Table(_) => {
+            let key = ms.capture(state, 0, start, end)?;
+            state.get_index(replacement, &key)?
+        }
+        SyxValue::String(_) | SyxValue::Integer(_) | SyxValue::Number(_) => {
+            let template = tostring(state, replacement)?;
+            let mut i = 0;
+            while i < template.len() {
+                let byte = template[i];
+                i += 1;
+                if byte != b'%' {
+                    append(state, out, &[byte])?;
+                    continue;
+                }
+                match template.get(i) {
+                    Some(b'%') => append(state, out, b"%")?,
+                    Some(b'0') => append(state, out, whole)?,
+                    Some(&digit) if digit.is_ascii_digit() => {
+                        let capture = ms.capture(state, (digit - b'1') as usize, start, end)?;
+                        let capture = tostring(state, &capture)?;
+                        append(state, out, &capture)?;
+                    }
+                    _ => return runtime_error(
+                        "invalid use of '%' in replacement string".to_owned()),
+                }
+                i += 1;
+            }
+            return Ok(());
+        }
+        _ => {
+            let captures = ms.captures(state, start, end, true)?;
+            let results = state.call_value(replacement.clone(), captures)?;
+            results.into_iter().next().unwrap_or(SyxValue::Nil)
+        }
+    };
+    match value {
+        // false or nil keeps the original match
+        SyxValue::Nil | SyxValue::Bool(false) => append(state, out, whole)?,
+        SyxValue::String(ref s) => append(state, out, s)?,
+        SyxValue::Integer(_) | SyxValue::Number(_) => {
+            let s = tostring(state, &value)?;
+            append(state, out, &s)?
+        }
+        _ => return runtime_error(format!("invalid replacement value (a {})",
+                                          value.type_name())),
+    }
+    Ok(())
+}
+
+// gsub(s, pattern, repl [, n]), the string with up to n matches replaced,
+// and how many were
+fn str_gsub(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let s = check_string(&args, 1, "gsub")?;
+    let pattern = check_string(&args, 2, "gsub")?;
+    let replacement = check_any(&args, 3, "gsub")?;
+    match replacement {
+        SyxValue::String(_) | SyxValue::Integer(_) | SyxValue::Number(_) |
+        SyxValue::Table(_) | SyxValue::Function(_) | SyxValue::Native(_) |
+        SyxValue::NativeClosure(_) => {}
+        _ => return type_error(&args, 3, "gsub", "string/function/table"),
+    }
+    let max = opt_integer(&args, 4, "gsub", s.len() as SyxInteger + 1)?;
+    let anchor = pattern.first() == Some(&b'^');
+    let mut ms = MatchState::new(&s, &pattern);
+    let mut out = Vec::new();
+    state.reserve(&mut out, s.len())?;
+    let mut count = 0;
+    let mut src = 0;
+    let mut last = None;
+    while count < max {
+        match ms.find_at(src, anchor as usize)? {
+            Some(end) if Some(end) != last => {
+                count += 1;
+                add_value(state, &ms, &mut out, src, end, &replacement, &s[src..end])?;
+                src = end;
+                last = Some(end);
+            }
+            _ if src < s.len() => {
+                append(state, &mut out, &s[src..src + 1])?;
+                src += 1;
+            }
+            _ => break,
+        }
+        if anchor {
+            break;
+        }
+    }
+    append(state, &mut out, &s[src..])?;
+    Ok(vec![SyxValue::String(state.intern(&out)), SyxValue::Integer(count)])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use super::super::super::object::{NativeFunction, Proto};
+    use super::super::super::opcodes::{Instruction, OpCode};
+
+    fn string(function: NativeFunction, args: Vec<SyxValue>) -> String {
+        let mut state = SyxState::new();
+        match function(&mut state, args) {
+            Ok(ref results) => match results[0] {
+                SyxValue::String(ref s) => s.to_string(),
+                ref other => panic!("expected a string, got {:?}", other),
+            },
+            Err(error) => error.to_string(),
+        }
+    }
+
+    fn format(format: &str, value: SyxValue) -> String {
+        string(str_format, vec![SyxValue::from(format), value])
+    }
+
+    #[test]
+    fn test_bytes() {
+        let s = || SyxValue::from("hello");
+        let int = SyxValue::Integer;
+        assert_eq!(string(str_sub, vec![s(), int(2), int(-2)]), "ell");
+        assert_eq!(string(str_sub, vec![s(), int(-3)]), "llo");
+        assert_eq!(string(str_sub, vec![s(), int(-100), int(100)]), "hello");
+        assert_eq!(string(str_sub, vec![s(), int(4), int(2)]), "");
+        assert_eq!(string(str_upper, vec![s()]), "HELLO");
+        assert_eq!(string(str_reverse, vec![s()]), "olleh");
+        assert_eq!(string(str_rep, vec![s(), int(3), SyxValue::from(", ")]),
+                   "hello, hello, hello");
+        assert_eq!(string(str_rep, vec![s(), int(0)]), "");
+        // more than the global allocator can give is an error, not an abort
+        #[cfg(not(feature = "int32"))]
+        assert_eq!(string(str_rep, vec![s(), int(1 << 45)]), "not enough memory");
+        assert_eq!(string(str_char, vec![int(72), int(105)]), "Hi");
+        assert_eq!(string(str_char, vec![int(256)]),
+                   "bad argument #1 to 'char' (value out of range)");
+        assert_eq!(string(str_upper, vec![SyxValue::Nil]),
+                   "bad argument #1 to 'upper' (string expected, got nil)");
+
+        let mut state = SyxState::new();
+        let bytes = str_byte(&mut state, vec![s(), int(-2), int(-1)]).unwrap();
+        assert_eq!(bytes, vec![int(108), int(111)]);
+        let bytes = str_byte(&mut state, vec![s()]).unwrap();
+        assert_eq!(bytes, vec![int(104)]);
+        let len = str_len(&mut state, vec![int(-12)]).unwrap();
+        assert_eq!(len, vec![int(3)]);
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(format("%5.2f|", SyxValue::Number(1.23456)), " 1.23|");
+        assert_eq!(format("%d", SyxValue::Number(3.0)), "3");
+        assert_eq!(format("%d", SyxValue::Number(3.5)),
+                   "bad argument #2 to 'format' (number has no integer representation)");
+        assert_eq!(format("%05d", SyxValue::Integer(-42)), "-0042");
+        assert_eq!(format("%-5d|", SyxValue::Integer(42)), "42   |");
+        assert_eq!(format("%+.3d", SyxValue::Integer(7)), "+007");
+        assert_eq!(format("%#x %X", SyxValue::Integer(255)),
+                   "bad argument #3 to 'format' (no value)");
+        assert_eq!(format("%#x", SyxValue::Integer(255)), "0xff");
+        let digits = SyxInteger::BITS as usize / 4;
+        assert_eq!(format("%x", SyxValue::Integer(-1)), "f".repeat(digits));
+        assert_eq!(format("%e", SyxValue::Number(12345.678)), "1.234568e+04");
+        assert_eq!(format("%.0E", SyxValue::Number(0.000125)), "1E-04");
+        assert_eq!(format("%g", SyxValue::Number(0.0001)), "0.0001");
+        assert_eq!(format("%g", SyxValue::Number(100000.0)), "100000");
+        assert_eq!(format("%g", SyxValue::Number(1e6)), "1e+06");
+        assert_eq!(format("%g", SyxValue::Number(2.5)), "2.5");
+        assert_eq!(format("%#g", SyxValue::Number(2.5)), "2.50000");
+        assert_eq!(format("%5.1f", SyxValue::Number(-SyxNumber::INFINITY)), " -inf");
+        assert_eq!(format("%f", SyxValue::Number(-SyxNumber::NAN.abs())), "-nan");
+        assert_eq!(format("%s", SyxValue::Number(1e15)), "1e+15");
+        assert_eq!(format("%a", SyxValue::Number(1.0)), "0x1p+0");
+        #[cfg(not(feature = "float32"))]
+        assert_eq!(format("%a", SyxValue::Number(-0.1)), "-0x1.999999999999ap-4");
+        assert_eq!(format("%.1a", SyxValue::Number(1.96875)), "0x2.0p+0");
+        assert_eq!(format("%c%c", SyxValue::Integer(65)),
+                   "bad argument #3 to 'format' (no value)");
+        assert_eq!(format("%3c|", SyxValue::Integer(65)), "  A|");
+        assert_eq!(format("%10.3s|", SyxValue::from("abcdef")), "       abc|");
+        assert_eq!(format("%s %%", SyxValue::Bool(true)), "true %");
+        assert_eq!(format("%q", SyxValue::from("a\"\n\0b\x011")), "\"a\\\"\\\n\\0b\\0011\"");
+        assert_eq!(format("%q", SyxValue::Integer(SyxInteger::MIN)),
+                   format!("0x8{}", "0".repeat(digits - 1)));
+        assert_eq!(format("%q", SyxValue::Number(0.5)), "0x1p-1");
+        assert_eq!(format("%y", SyxValue::Nil), "invalid option '%y' to 'format'");
+        assert_eq!(format("%100d", SyxValue::Nil),
+                   "invalid format (width or precision too long)");
+    }
+
+    fn call(state: &mut SyxState, function: NativeFunction, args: &[SyxValue]) -> Vec<SyxValue> {
+        function(state, args.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_patterns() {
+        let mut state = SyxState::new();
+        let s = SyxValue::from;
+        let int = SyxValue::Integer;
+        assert_eq!(call(&mut state, str_find, &[s("hello world"), s("o"), int(6)]),
+                   vec![int(8), int(8)]);
+        assert_eq!(call(&mut state, str_find, &[s("a+b"), s("+"), int(1), SyxValue::Bool(true)]),
+                   vec![int(2), int(2)]);
+        assert_eq!(call(&mut state, str_find, &[s("abc"), s("")]), vec![int(1), int(0)]);
+        assert_eq!(call(&mut state, str_find, &[s("abc"), s(""), int(10)]), vec![SyxValue::Nil]);
+        assert_eq!(call(&mut state, str_find, &[s("key=val"), s("(%w+)=(%w+)")]),
+                   vec![int(1), int(7), s("key"), s("val")]);
+        assert_eq!(call(&mut state, str_find, &[s("aab"), s("^b")]), vec![SyxValue::Nil]);
+        assert_eq!(call(&mut state, str_match, &[s("  trim  "), s("^%s*(.-)%s*$")]),
+                   vec![s("trim")]);
+        assert_eq!(call(&mut state, str_match, &[s("x = 10"), s("%d+")]), vec![s("10")]);
+
+        assert_eq!(call(&mut state, str_gsub, &[s("hello world"), s("o"), s("0")]),
+                   vec![s("hell0 w0rld"), int(2)]);
+        assert_eq!(call(&mut state, str_gsub, &[s("abc"), s("%w"), s("%0%%"), int(2)]),
+                   vec![s("a%b%c"), int(2)]);
+        assert_eq!(call(&mut state, str_gsub, &[s("hello"), s(""), s("-")]),
+                   vec![s("-h-e-l-l-o-"), int(6)]);
+        assert_eq!(call(&mut state, str_gsub, &[s("abc"), s("b"), SyxValue::Native(str_upper)]),
+                   vec![s("aBc"), int(1)]);
+        let table = state.new_table(0, 1);
+        state.table_mut(table).set(s("name"), s("syx")).unwrap();
+        assert_eq!(call(&mut state, str_gsub, &[s("$name $age"), s("%$(%w+)"),
+                                                SyxValue::Table(table)]),
+                   vec![s("syx $age"), int(2)]);
+        let error = str_gsub(&mut state, vec![s("abc"), s("%w"), s("%2")]).unwrap_err();
+        assert_eq!(error.to_string(), "invalid capture index %2");
+        let error = str_gsub(&mut state, vec![s("abc"), s("%w"), s("%x")]).unwrap_err();
+        assert_eq!(error.to_string(), "invalid use of '%' in replacement string");
+
+        // the iterator works both called directly and from a generic for
+        let iterator = call(&mut state, str_gmatch, &[s("one two  three"), s("%a+")]).remove(0);
+        let mut words = vec![];
+        loop {
+            match state.call_value(iterator.clone(), vec![]).unwrap().into_first() {
+                SyxValue::Nil => break,
+                word => words.push(word),
+            }
+        }
+        assert_eq!(words, vec![s("one"), s("two"), s("three")]);
+        let iterator = call(&mut state, str_gmatch, &[s("k=v, a=b"), s("(%w+)=(%w+)")]).remove(0);
+        let pairs = state.call_value(iterator, vec![SyxValue::Nil, SyxValue::Nil]).unwrap();
+        assert_eq!(pairs, vec![s("k"), s("v")]);
+    }
+
+    #[test]
+    fn test_methods() {
+        // return ("abc"):upper(), #("abc"):rep(2)
+        let mut proto = Proto::new();
+        proto.maxstacksize = 4;
+        proto.constants = vec![
+            SyxValue::from("abc"), SyxValue::from("upper"), SyxValue::from("rep"),
+            SyxValue::Integer(2),
+        ];
+        proto.instructions = vec![
+            Instruction::ABx { instruction: OpCode::LoadK, a: 1, bx: 0 },
+            Instruction::ABC { instruction: OpCode::SelfLoad, a: 0, b: 1, c: 257 },
+            Instruction::ABC { instruction: OpCode::Call, a: 0, b: 2, c: 2 },
+            Instruction::ABx { instruction: OpCode::LoadK, a: 2, bx: 0 },
+            Instruction::ABC { instruction: OpCode::SelfLoad, a: 1, b: 2, c: 258 },
+            Instruction::ABx { instruction: OpCode::LoadK, a: 3, bx: 3 },
+            Instruction::ABC { instruction: OpCode::Call, a: 1, b: 3, c: 2 },
+            Instruction::ABC { instruction: OpCode::Len, a: 1, b: 1, c: 0 },
+            Instruction::ABC { instruction: OpCode::Return, a: 0, b: 3, c: 0 },
+        ];
+        let mut state = SyxState::new();
+        open(&mut state);
+        let results = state.call(Arc::new(proto), vec![]).unwrap();
+        assert_eq!(results, vec![SyxValue::from("ABC"), SyxValue::Integer(6)]);
+    }
+}
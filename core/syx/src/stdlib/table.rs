@@ -0,0 +1,342 @@
+// Table library
+//
+// Elements are read and written through __index and __newindex, and the
+// length of a table through __len, as in Lua 5.3.
+//
+// table.sort is a merge sort rather than PUC-Rio's quicksort, so it is
+// stable: elements the comparator considers equal keep their order. Lua
+// does not promise that, so scripts should not count on it either. An
+// inconsistent comparator (one for which a < b and b < a may both hold)
+// leaves the elements in some unspecified order instead of raising
+// "invalid order function for sorting".
+//
+// Consult the versioned ltablib.c for more information.
+
+use super::super::errors::*;
+use super::super::object::{SyxInteger, SyxValue, TableRef};
+use super::super::state::SyxState;
+use super::super::tm::TagMethod;
+use super::super::vm::{bad_argument, runtime_error};
+use super::{
+    check_integer, check_table, new_lib, opt_integer, opt_string, set_field, tostring, type_error,
+};
+
+const MAXUNPACK: SyxInteger = 1_000_000; // most values unpack returns
+
+pub fn open(state: &mut SyxState) {
+    new_lib(state, "table", &[
+        ("insert", insert),
+        ("remove", remove),
+        ("concat", concat),
+        ("sort", sort),
+        ("unpack", unpack),
+        ("pack", pack),
+    ]);
+}
+
+// #t, through __len
+fn length(state: &mut SyxState, table: TableRef) -> Result<SyxInteger> {
+    let value = SyxValue::Table(table);
+    let handler = match state.metamethod(&value, TagMethod::Len) {
+        Some(handler) => handler,
+        None => return Ok(state.table(table).length()),
+    };
+    let result = state.call_value(handler, vec![value.clone(), value])?.into_first();
+    match result.coerce_to_integer() {
+        Some(n) => Ok(n),
+        None => runtime_error("object length is not an integer".to_owned()),
+    }
+}
+
+fn get(state: &mut SyxState, table: TableRef, i: SyxInteger) -> Result<SyxValue> {
+    state.get_index(&SyxValue::Table(table), &SyxValue::Integer(i))
+}
+
+fn set(state: &mut SyxState, table: TableRef, i: SyxInteger, value: SyxValue) -> Result<()> {
+    state.set_index(&SyxValue::Table(table), SyxValue::Integer(i), value)
+}
+
+// insert(t, [pos,] value)
+fn insert(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let table = check_table(&args, 1, "insert")?;
+    let end = length(state, table)?.wrapping_add(1); // first empty slot
+    let (pos, value) = match args.len() {
+        2 => (end, args[1].clone()),
+        3 => {
+            let pos = check_integer(&args, 2, "insert")?;
+            // 1 <= pos <= end, compared unsigned to catch pos < 1 too
+            if (pos as u64).wrapping_sub(1) >= end as u64 {
+                return bad_argument(2, "insert", "position out of bounds");
+            }
+            for i in (pos + 1..=end).rev() {
+                let value = get(state, table, i - 1)?;
+                set(state, table, i, value)?;
+            }
+            (pos, args[2].clone())
+        }
+        _ => return runtime_error("wrong number of arguments to 'insert'".to_owned()),
+    };
+    set(state, table, pos, value)?;
+    Ok(vec![])
+}
+
+// remove(t [, pos]), returning the removed element
+fn remove(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let table = check_table(&args, 1, "remove")?;
+    let size = length(state, table)?;
+    let mut pos = opt_integer(&args, 2, "remove", size)?;
+    // removing from an empty list may ask for position 0 or size + 1
+    if pos != size && (pos as u64).wrapping_sub(1) > size as u64 {
+        return bad_argument(1, "remove", "position out of bounds");
+    }
+    let removed = get(state, table, pos)?;
+    while pos < size {
+        let next = get(state, table, pos + 1)?;
+        set(state, table, pos, next)?;
+        pos += 1;
+    }
+    set(state, table, pos, SyxValue::Nil)?;
+    Ok(vec![removed])
+}
+
+// concat(t [, sep [, i [, j]]])
+fn concat(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let table = check_table(&args, 1, "concat")?;
+    let sep = opt_string(&args, 2, "concat", "")?;
+    let i = opt_integer(&args, 3, "concat", 1)?;
+    let j = match args.get(3) {
+        None | Some(SyxValue::Nil) => length(state, table)?,
+        _ => check_integer(&args, 4, "concat")?,
+    };
+    let mut buffer = Vec::new();
+    let mut k = i;
+    while k <= j {
+        match get(state, table, k)? {
+            value @ SyxValue::String(_) | value @ SyxValue::Integer(_) |
+            value @ SyxValue::Number(_) => {
+                let s = tostring(state, &value)?;
+                state.reserve(&mut buffer, s.len())?;
+                buffer.extend_from_slice(&s);
+            }
+            _ => return runtime_error(format!(
+                "invalid value (at index {}) in table for 'concat'", k)),
+        }
+        if k == j {
+            break; // j may be the maximum integer
+        }
+        state.reserve(&mut buffer, sep.len())?;
+        buffer.extend_from_slice(&sep);
+        k += 1;
+    }
+    Ok(vec![SyxValue::String(state.intern(&buffer))])
+}
+
+// unpack(t [, i [, j]]), the elements from i to j
+fn unpack(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let table = check_table(&args, 1, "unpack")?;
+    let i = opt_integer(&args, 2, "unpack", 1)?;
+    let j = match args.get(2) {
+        None | Some(SyxValue::Nil) => length(state, table)?,
+        _ => check_integer(&args, 3, "unpack")?,
+    };
+    if i > j {
+        return Ok(vec![]);
+    }
+    let count = (j as u64).wrapping_sub(i as u64);
+    if count >= MAXUNPACK as u64 {
+        return runtime_error("too many results to unpack".to_owned());
+    }
+    (0..=count as SyxInteger).map(|k| get(state, table, i + k)).collect()
+}
+
+// pack(...), a table of the arguments with their count in field n
+fn pack(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let count = args.len();
+    let table = state.new_table(count, 1);
+    for (i, value) in args.into_iter().enumerate() {
+        state.table_mut(table).set_int(i as SyxInteger + 1, value);
+    }
+    set_field(state, table, "n", SyxValue::Integer(count as SyxInteger));
+    Ok(vec![SyxValue::Table(table)])
+}
+
+// `a < b` for sort, by the comparator if there is one
+fn sort_less(state: &mut SyxState, comparator: &SyxValue, a: &SyxValue, b: &SyxValue)
+    -> Result<bool>
+{
+    if comparator.is_nil() {
+        return state.less_than(a, b);
+    }
+    let results = state.call_value(comparator.clone(), vec![a.clone(), b.clone()])?;
+    Ok(results.first().is_some_and(|result| !result.is_falsy()))
+}
+
+// Stable merge sort of `values` that stops at the first comparator error.
+// `scratch` is as long as `values`.
+fn merge_sort(state: &mut SyxState, comparator: &SyxValue, values: &mut [SyxValue],
+              scratch: &mut [SyxValue]) -> Result<()> {
+    let len = values.len();
+    if len <= 1 {
+        return Ok(());
+    }
+    let mid = len / 2;
+    merge_sort(state, comparator, &mut values[..mid], &mut scratch[..mid])?;
+    merge_sort(state, comparator, &mut values[mid..], &mut scratch[mid..])?;
+    scratch.clone_from_slice(values);
+    let (mut i, mut j) = (0, mid);
+    for slot in values.iter_mut() {
+        // take from the right run only when it is strictly smaller
+        let right = j < len &&
+            (i == mid || sort_less(state, comparator, &scratch[j], &scratch[i])?);
+        if right {
+            *slot = scratch[j].clone();
+            j += 1;
+        } else {
+            *slot = scratch[i].clone();
+            i += 1;
+        }
+    }
+    Ok(())
+}
+
+// sort(t [, comp])
+fn sort(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let table = check_table(&args, 1, "sort")?;
+    let n = length(state, table)?;
+    if n <= 1 {
+        return Ok(vec![]);
+    }
+    if n >= i32::MAX as SyxInteger {
+        return bad_argument(1, "sort", "array too big");
+    }
+    let comparator = match args.get(1) {
+        None | Some(SyxValue::Nil) => SyxValue::Nil,
+        Some(f) if f.is_function() => f.clone(),
+        _ => return type_error(&args, 2, "sort", "function"),
+    };
+    let mut values = (1..=n).map(|i| get(state, table, i)).collect::<Result<Vec<_>>>()?;
+    let mut scratch = values.clone();
+    merge_sort(state, &comparator, &mut values, &mut scratch)?;
+    for (i, value) in values.into_iter().enumerate() {
+        set(state, table, i as SyxInteger + 1, value)?;
+    }
+    Ok(vec![])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::super::object::NativeFunction;
+    use super::super::super::vm::less_than;
+    use super::super::super::testing::ints;
+
+    fn list(state: &mut SyxState, values: &[SyxInteger]) -> TableRef {
+        let table = state.new_table(values.len(), 0);
+        for (i, &value) in values.iter().enumerate() {
+            state.table_mut(table).set_int(i as SyxInteger + 1, SyxValue::Integer(value));
+        }
+        table
+    }
+
+    fn contents(state: &SyxState, table: TableRef) -> Vec<SyxValue> {
+        let table = state.table(table);
+        (1..=table.length()).map(|i| table.get_int(i)).collect()
+    }
+
+    fn call(state: &mut SyxState, function: NativeFunction, args: Vec<SyxValue>)
+        -> Result<Vec<SyxValue>>
+    {
+        function(state, args)
+    }
+
+    #[test]
+    fn test_insert_remove() {
+        let mut state = SyxState::new();
+        let t = list(&mut state, &[1, 2, 3]);
+        let table = SyxValue::Table(t);
+        call(&mut state, insert, vec![table.clone(), SyxValue::Integer(4)]).unwrap();
+        call(&mut state, insert, vec![table.clone(), SyxValue::Integer(1), SyxValue::Integer(0)])
+            .unwrap();
+        assert_eq!(contents(&state, t), ints(&[0, 1, 2, 3, 4]));
+        let error = call(&mut state, insert, vec![table.clone(), SyxValue::Integer(7),
+                                                  SyxValue::Integer(0)]).unwrap_err();
+        assert_eq!(error.to_string(), "bad argument #2 to 'insert' (position out of bounds)");
+        let error = call(&mut state, insert, vec![table.clone()]).unwrap_err();
+        assert_eq!(error.to_string(), "wrong number of arguments to 'insert'");
+
+        assert_eq!(call(&mut state, remove, vec![table.clone()]).unwrap(), ints(&[4]));
+        assert_eq!(call(&mut state, remove, vec![table.clone(), SyxValue::Integer(1)]).unwrap(),
+                   ints(&[0]));
+        assert_eq!(contents(&state, t), ints(&[1, 2, 3]));
+        let empty = SyxValue::Table(state.new_table(0, 0));
+        assert_eq!(call(&mut state, remove, vec![empty]).unwrap(), vec![SyxValue::Nil]);
+    }
+
+    #[test]
+    fn test_concat_unpack_pack() {
+        let mut state = SyxState::new();
+        let t = list(&mut state, &[1, 2, 3]);
+        let table = SyxValue::Table(t);
+        assert_eq!(call(&mut state, concat, vec![table.clone(), SyxValue::from(", ")]).unwrap(),
+                   vec![SyxValue::from("1, 2, 3")]);
+        assert_eq!(call(&mut state, concat, vec![table.clone(), SyxValue::Nil,
+                                                 SyxValue::Integer(2)]).unwrap(),
+                   vec![SyxValue::from("23")]);
+        state.table_mut(t).set_int(2, SyxValue::Bool(true));
+        let error = call(&mut state, concat, vec![table.clone()]).unwrap_err();
+        assert_eq!(error.to_string(), "invalid value (at index 2) in table for 'concat'");
+
+        assert_eq!(call(&mut state, unpack, vec![table.clone(), SyxValue::Integer(2),
+                                                 SyxValue::Integer(4)]).unwrap(),
+                   vec![SyxValue::Bool(true), SyxValue::Integer(3), SyxValue::Nil]);
+        let error = call(&mut state, unpack, vec![table, SyxValue::Integer(1),
+                                                  SyxValue::Integer(SyxInteger::MAX)]);
+        assert_eq!(error.unwrap_err().to_string(), "too many results to unpack");
+
+        let packed = call(&mut state, pack, vec![SyxValue::Nil, SyxValue::Integer(2)]).unwrap();
+        let packed = match packed[0] {
+            SyxValue::Table(t) => state.table(t),
+            _ => panic!("expected a table"),
+        };
+        assert_eq!(packed.get(&SyxValue::from("n")), SyxValue::Integer(2));
+        assert_eq!(packed.get_int(2), SyxValue::Integer(2));
+    }
+
+    // sorts by the first element of {key, tag} pairs
+    fn by_key(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+        let key = |value: &SyxValue| match *value {
+            SyxValue::Table(t) => state.table(t).get_int(1),
+            _ => SyxValue::Nil,
+        };
+        Ok(vec![SyxValue::Bool(less_than(&key(&args[0]), &key(&args[1]))?)])
+    }
+
+    #[test]
+    fn test_sort() {
+        let mut state = SyxState::new();
+        let t = list(&mut state, &[5, 2, 8, 1, 9, 3]);
+        call(&mut state, sort, vec![SyxValue::Table(t)]).unwrap();
+        assert_eq!(contents(&state, t), ints(&[1, 2, 3, 5, 8, 9]));
+
+        // equal keys keep their order
+        let pairs = state.new_table(4, 0);
+        for (i, &(key, tag)) in [(2, 1), (1, 2), (2, 3), (1, 4)].iter().enumerate() {
+            let pair = list(&mut state, &[key, tag]);
+            state.table_mut(pairs).set_int(i as SyxInteger + 1, SyxValue::Table(pair));
+        }
+        call(&mut state, sort, vec![SyxValue::Table(pairs), SyxValue::Native(by_key)]).unwrap();
+        let tags: Vec<SyxValue> = contents(&state, pairs).iter()
+            .map(|pair| match *pair {
+                SyxValue::Table(p) => state.table(p).get_int(2),
+                _ => SyxValue::Nil,
+            })
+            .collect();
+        assert_eq!(tags, ints(&[2, 4, 1, 3]));
+
+        let mixed = state.new_table(2, 0);
+        state.table_mut(mixed).set_int(1, SyxValue::Integer(1));
+        state.table_mut(mixed).set_int(2, SyxValue::from("x"));
+        let error = call(&mut state, sort, vec![SyxValue::Table(mixed)]).unwrap_err();
+        assert_eq!(error.to_string(), "attempt to compare string with number");
+    }
+}
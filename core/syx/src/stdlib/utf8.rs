@@ -0,0 +1,262 @@
+// UTF-8 library
+//
+// Strings stay byte strings: these functions read UTF-8 sequences out of
+// them and write sequences into them, and positions count bytes as they do
+// everywhere else. Code points go up to 0x10FFFF as in Lua 5.3, surrogates
+// included, while overlong sequences are invalid. utf8.len reports the
+// position of the first invalid sequence; the other functions raise an
+// error on one.
+//
+// Consult the versioned lutf8lib.c for more information.
+
+use super::super::compiler::lexer::utf8_encode;
+use super::super::errors::*;
+use super::super::object::{SyxInteger, SyxValue};
+use super::super::state::SyxState;
+use super::super::vm::{bad_argument, runtime_error};
+use super::string::position;
+use super::{check_integer, check_string, new_lib, opt_integer, set_field};
+
+const MAXUNICODE: u32 = 0x10ffff;
+
+// exactly one sequence, in a valid string
+const CHARPATTERN: &[u8] = b"[\0-\x7F\xC2-\xF4][\x80-\xBF]*";
+
+pub fn open(state: &mut SyxState) {
+    let lib = new_lib(state, "utf8", &[
+        ("char", utf8_char),
+        ("codepoint", utf8_codepoint),
+        ("len", utf8_len),
+        ("offset", utf8_offset),
+        ("codes", utf8_codes),
+    ]);
+    let pattern = SyxValue::String(state.intern(CHARPATTERN));
+    set_field(state, lib, "charpattern", pattern);
+}
+
+// whether byte `i` of `s` continues a sequence, false past the end
+fn continues(s: &[u8], i: usize) -> bool {
+    s.get(i).is_some_and(|&byte| byte & 0xc0 == 0x80)
+}
+
+// The code point of the sequence `s` starts with and the sequence's length,
+// None if it is invalid
+fn decode(s: &[u8]) -> Option<(u32, usize)> {
+    // largest code point each length is too long for
+    const LIMITS: [u32; 4] = [0xff, 0x7f, 0x7ff, 0xffff];
+    let mut c = u32::from(*s.first()?);
+    if c < 0x80 {
+        return Some((c, 1));
+    }
+    let mut count = 0;
+    let mut code = 0;
+    while c & 0x40 != 0 {
+        count += 1;
+        if count > 3 || !continues(s, count) {
+            return None;
+        }
+        code = (code << 6) | u32::from(s[count] & 0x3f);
+        c <<= 1;
+    }
+    code |= (c & 0x7f) << (count * 5);
+    if code > MAXUNICODE || code <= LIMITS[count] {
+        return None;
+    }
+    Some((code, count + 1))
+}
+
+// char(...), the sequences of the code points given, one after the other
+fn utf8_char(state: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let mut out = Vec::with_capacity(args.len());
+    for n in 1..=args.len() {
+        let code = check_integer(&args, n, "char")?;
+        if !(0..=MAXUNICODE as SyxInteger).contains(&code) {
+            return bad_argument(n, "char", "value out of range");
+        }
+        utf8_encode(code as u32, &mut out);
+    }
+    Ok(vec![SyxValue::String(state.intern(&out))])
+}
+
+// codepoint(s [, i [, j]]), the code points of the sequences starting
+// between bytes i and j
+fn utf8_codepoint(_: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let s = check_string(&args, 1, "codepoint")?;
+    let i = position(opt_integer(&args, 2, "codepoint", 1)?, s.len());
+    let j = position(opt_integer(&args, 3, "codepoint", i)?, s.len());
+    if i < 1 {
+        return bad_argument(2, "codepoint", "out of range");
+    }
+    if j > s.len() as SyxInteger {
+        return bad_argument(3, "codepoint", "out of range");
+    }
+    let mut codes = Vec::new();
+    let mut pos = i as usize - 1;
+    while pos < j as usize {
+        match decode(&s[pos..]) {
+            Some((code, length)) => {
+                codes.push(SyxValue::Integer(code as SyxInteger));
+                pos += length;
+            }
+            None => return runtime_error("invalid UTF-8 code".to_owned()),
+        }
+    }
+    Ok(codes)
+}
+
+// len(s [, i [, j]]), the number of sequences starting between bytes i and
+// j, or nil and the position of the first invalid one
+fn utf8_len(_: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let s = check_string(&args, 1, "len")?;
+    let len = s.len() as SyxInteger;
+    let i = position(opt_integer(&args, 2, "len", 1)?, s.len());
+    let j = position(opt_integer(&args, 3, "len", -1)?, s.len());
+    if !(1..=len + 1).contains(&i) {
+        return bad_argument(2, "len", "initial position out of string");
+    }
+    if j > len {
+        return bad_argument(3, "len", "final position out of string");
+    }
+    let mut count = 0;
+    let mut pos = i as usize - 1;
+    while pos < j as usize {
+        match decode(&s[pos..]) {
+            Some((_, length)) => pos += length,
+            None => return Ok(vec![SyxValue::Nil, SyxValue::Integer(pos as SyxInteger + 1)]),
+        }
+        count += 1;
+    }
+    Ok(vec![SyxValue::Integer(count)])
+}
+
+// offset(s, n [, i]), the position of the nth sequence counting from the
+// one at byte i (backwards for a negative n), or of the start of the one
+// byte i is in for n = 0
+fn utf8_offset(_: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let s = check_string(&args, 1, "offset")?;
+    let len = s.len() as SyxInteger;
+    let mut n = check_integer(&args, 2, "offset")?;
+    let default = if n >= 0 { 1 } else { len + 1 };
+    let i = position(opt_integer(&args, 3, "offset", default)?, s.len());
+    if !(1..=len + 1).contains(&i) {
+        return bad_argument(3, "offset", "position out of range");
+    }
+    let mut pos = i as usize - 1;
+    if n == 0 {
+        while pos > 0 && continues(&s, pos) {
+            pos -= 1;
+        }
+        return Ok(vec![SyxValue::Integer(pos as SyxInteger + 1)]);
+    }
+    if continues(&s, pos) {
+        return runtime_error("initial position is a continuation byte".to_owned());
+    }
+    if n < 0 {
+        while n < 0 && pos > 0 {
+            pos -= 1;
+            while pos > 0 && continues(&s, pos) {
+                pos -= 1;
+            }
+            n += 1;
+        }
+    } else {
+        n -= 1;
+        while n > 0 && pos < s.len() {
+            pos += 1;
+            while continues(&s, pos) {
+                pos += 1;
+            }
+            n -= 1;
+        }
+    }
+    Ok(vec![if n == 0 { SyxValue::Integer(pos as SyxInteger + 1) } else { SyxValue::Nil }])
+}
+
+// the position and code point of the sequence after the one at byte i
+fn utf8_next(_: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let s = check_string(&args, 1, "codes")?;
+    let mut pos = check_integer(&args, 2, "codes")?.wrapping_sub(1);
+    if pos < 0 {
+        pos = 0;
+    } else if pos < s.len() as SyxInteger {
+        pos += 1;
+        while continues(&s, pos as usize) {
+            pos += 1;
+        }
+    }
+    if pos >= s.len() as SyxInteger {
+        return Ok(vec![SyxValue::Nil]);
+    }
+    match decode(&s[pos as usize..]) {
+        Some((code, length)) if !continues(&s, pos as usize + length) => {
+            Ok(vec![SyxValue::Integer(pos + 1), SyxValue::Integer(code as SyxInteger)])
+        }
+        _ => runtime_error("invalid UTF-8 code".to_owned()),
+    }
+}
+
+// codes(s), for iterating over the positions and code points of s
+fn utf8_codes(_: &mut SyxState, args: Vec<SyxValue>) -> Result<Vec<SyxValue>> {
+    let s = check_string(&args, 1, "codes")?;
+    Ok(vec![SyxValue::Native(utf8_next), SyxValue::String(s), SyxValue::Integer(0)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::super::testing::{ints, run_source};
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode(b"a"), Some((0x61, 1)));
+        assert_eq!(decode("é".as_bytes()), Some((0xe9, 2)));
+        assert_eq!(decode("\u{10ffff}".as_bytes()), Some((0x10ffff, 4)));
+        assert_eq!(decode(b"\xed\xa0\x80"), Some((0xd800, 3))); // surrogates are allowed
+        assert_eq!(decode(b"\xc0\x80"), None); // overlong
+        assert_eq!(decode(b"\xf4\x90\x80\x80"), None); // past 0x10FFFF
+        assert_eq!(decode(b"\x80"), None);
+        assert_eq!(decode(b"\xe9x"), None);
+        assert_eq!(decode(b"\xff\xbf\xbf\xbf\xbf"), None);
+        assert_eq!(decode(b""), None);
+    }
+
+    #[test]
+    fn test_utf8() {
+        let results = run_source("
+            local s = utf8.char(72, 0xe9, 0x20ac, 0x1f600)
+            local positions, codes = {}, {}
+            for p, c in utf8.codes(s) do
+                positions[#positions + 1] = p
+                codes[#codes + 1] = c
+            end
+            return s == 'H\\u{e9}\\u{20ac}\\u{1f600}', utf8.len(s), utf8.len(s, 4),
+                utf8.offset(s, 3), utf8.offset(s, -1), utf8.offset(s, 0, 3), utf8.offset(s, 6),
+                table.concat(positions, ','), table.concat(codes, ','),
+                utf8.codepoint(s, 2, -1)
+        ").unwrap();
+        assert_eq!(results[0], SyxValue::Bool(true));
+        assert_eq!(results[1..6], ints(&[4, 2, 4, 7, 2])[..]);
+        assert_eq!(results[6], SyxValue::Nil);
+        assert_eq!(results[7], SyxValue::from("1,2,4,7"));
+        assert_eq!(results[8], SyxValue::from("72,233,8364,128512"));
+        assert_eq!(results[9..], ints(&[0xe9, 0x20ac, 0x1f600])[..]);
+
+        // the position of the first invalid sequence
+        assert_eq!(run_source("return utf8.len('ab\\xffc')").unwrap(),
+                   vec![SyxValue::Nil, SyxValue::Integer(3)]);
+        assert_eq!(run_source("return ('x\\u{e9}'):match(utf8.charpattern, 2)").unwrap(),
+                   vec![SyxValue::from("\u{e9}")]);
+
+        let error = |source: &str| run_source(source).unwrap_err().to_string();
+        assert_eq!(error("return utf8.codepoint('\\xe9x')"), "test:1: invalid UTF-8 code");
+        assert_eq!(error("for _ in utf8.codes('a\\x80') do end"), "test:1: invalid UTF-8 code");
+        assert_eq!(error("return utf8.offset('\\u{e9}', 1, 2)"),
+                   "test:1: initial position is a continuation byte");
+        assert_eq!(error("return utf8.char(0x110000)"),
+                   "test:1: bad argument #1 to 'char' (value out of range)");
+        assert_eq!(error("return utf8.len('abc', 5)"),
+                   "test:1: bad argument #2 to 'len' (initial position out of string)");
+        assert_eq!(error("return utf8.codepoint('abc', 1, 4)"),
+                   "test:1: bad argument #3 to 'codepoint' (out of range)");
+    }
+}
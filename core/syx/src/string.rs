@@ -0,0 +1,418 @@
+// Strings
+//
+// Strings are immutable byte strings shared by reference. The hash is worked
+// out once, when the string is created, so table lookups never rehash the
+// contents. Short strings (see limits::SYX_MAXSHORTLEN) are interned in the
+// StringTable of a state, so two equal short strings from the same state are
+// usually the same allocation and compare by pointer.
+//
+// A string can also be a range of a buffer shared with other strings, rather
+// than bytes of its own: long string constants of a chunk loaded with
+// `LoadState::from_shared` keep the chunk alive instead of copying out of it.
+//
+// String hashes, and the hashes of table keys built on them, are seeded so
+// scripts can not pick keys that all land in one bucket and slow a host down
+// (hash flooding). Lua keeps a seed per state, but strings here go from state
+// to state (as constants of a shared Proto, say) with their hashes, so there
+// is one seed for the process. It is random unless fixed for runs that
+// iterate tables in the same order every time: by a host calling
+// `set_hash_seed` first thing, or, where that cannot be first, such as in
+// tests run in parallel, by the SYX_HASHSEED environment variable (decimal,
+// or hexadecimal after "0x"). Creating a SyxState hashes strings, so a later
+// `set_hash_seed` is an error rather than a seed that silently does not apply.
+//
+// Consult the versioned lstring.c for more information.
+
+use std::cmp::Ordering;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::mem::size_of;
+use std::ops::Deref;
+use std::sync::{Arc, OnceLock};
+
+use super::errors::*;
+use super::limits::SYX_MAXSHORTLEN;
+
+// Bytes held in memory, such as a chunk mapped from a file (see mmap.rs)
+pub type SharedBuffer = Arc<dyn AsRef<[u8]> + Send + Sync>;
+
+enum Storage {
+    Owned(Box<[u8]>),
+    Shared(SharedBuffer, usize, usize), // start and end in the buffer
+}
+
+struct StringData {
+    hash: u64,
+    storage: Storage,
+}
+
+impl StringData {
+    fn bytes(&self) -> &[u8] {
+        match self.storage {
+            Storage::Owned(ref bytes) => bytes,
+            Storage::Shared(ref buffer, start, end) => &(**buffer).as_ref()[start..end],
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SyxString(Arc<StringData>);
+
+static HASH_SEED: OnceLock<u64> = OnceLock::new();
+
+// Seed of every hash from now on, chosen the first time it is needed: from
+// SYX_HASHSEED if that is set and valid, at random otherwise (luai_makeseed)
+pub fn hash_seed() -> u64 {
+    *HASH_SEED.get_or_init(|| {
+        env::var("SYX_HASHSEED").ok()
+            .and_then(|seed| parse_seed(&seed))
+            .unwrap_or_else(|| RandomState::new().hash_one(0u8))
+    })
+}
+
+fn parse_seed(seed: &str) -> Option<u64> {
+    match seed.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => seed.parse().ok(),
+    }
+}
+
+// Fix the seed, for reproducible runs. Only works before anything has been
+// hashed with a random one, such as before the first SyxState is created; an
+// error after that, unless the seed is already the same.
+pub fn set_hash_seed(seed: u64) -> Result<()> {
+    match HASH_SEED.set(seed) {
+        Ok(()) => Ok(()),
+        Err(_) if hash_seed() == seed => Ok(()),
+        Err(_) => Err(ErrorKind::HashSeedChosen(hash_seed()).into()),
+    }
+}
+
+// FNV-1a from the seed, cheap and good enough for the short keys tables
+// mostly see
+fn hash_bytes(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325 ^ hash_seed();
+    for &byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+impl SyxString {
+    pub fn new(bytes: impl Into<Vec<u8>>) -> SyxString {
+        let bytes = bytes.into().into_boxed_slice();
+        SyxString(Arc::new(StringData {
+            hash: hash_bytes(&bytes),
+            storage: Storage::Owned(bytes),
+        }))
+    }
+
+    // String of the bytes from `start` to `end` of `buffer`, sharing it.
+    // Panics if they are not in the buffer.
+    pub fn shared(buffer: &SharedBuffer, start: usize, end: usize) -> SyxString {
+        let hash = hash_bytes(&(**buffer).as_ref()[start..end]);
+        SyxString(Arc::new(StringData {
+            hash,
+            storage: Storage::Shared(buffer.clone(), start, end),
+        }))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.bytes()
+    }
+
+    // bytes the string takes, as allocators are told (see alloc.rs)
+    pub(crate) fn size(&self) -> usize {
+        size_of::<StringData>() + self.0.bytes().len()
+    }
+
+    pub fn cached_hash(&self) -> u64 {
+        self.0.hash
+    }
+
+    pub fn is_short(&self) -> bool {
+        self.0.bytes().len() <= SYX_MAXSHORTLEN
+    }
+
+    pub fn ptr_eq(&self, other: &SyxString) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+
+    // the string as the address of its shared data, see nanbox.rs
+    #[cfg(feature = "nanbox")]
+    pub(crate) fn into_raw(self) -> usize {
+        Arc::into_raw(self.0) as usize
+    }
+
+    // Safety: `address` comes from `into_raw`, whose reference this takes over
+    #[cfg(feature = "nanbox")]
+    pub(crate) unsafe fn from_raw(address: usize) -> SyxString {
+        SyxString(Arc::from_raw(address as *const StringData))
+    }
+}
+
+impl Deref for SyxString {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.0.bytes()
+    }
+}
+
+impl PartialEq for SyxString {
+    fn eq(&self, other: &SyxString) -> bool {
+        self.ptr_eq(other) ||
+            (self.0.hash == other.0.hash && self.0.bytes() == other.0.bytes())
+    }
+}
+
+impl Eq for SyxString {}
+
+impl<'a> PartialEq<&'a [u8]> for SyxString {
+    fn eq(&self, other: &&'a [u8]) -> bool {
+        self.0.bytes() == *other
+    }
+}
+
+impl<const N: usize> PartialEq<[u8; N]> for SyxString {
+    fn eq(&self, other: &[u8; N]) -> bool {
+        self.0.bytes() == other
+    }
+}
+
+impl<'a, const N: usize> PartialEq<&'a [u8; N]> for SyxString {
+    fn eq(&self, other: &&'a [u8; N]) -> bool {
+        self.0.bytes() == *other
+    }
+}
+
+impl PartialOrd for SyxString {
+    fn partial_cmp(&self, other: &SyxString) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SyxString {
+    fn cmp(&self, other: &SyxString) -> Ordering {
+        self.0.bytes().cmp(other.0.bytes())
+    }
+}
+
+impl Hash for SyxString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.0.hash);
+    }
+}
+
+impl fmt::Debug for SyxString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", String::from_utf8_lossy(self.0.bytes()))
+    }
+}
+
+impl fmt::Display for SyxString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(self.0.bytes()))
+    }
+}
+
+impl From<Vec<u8>> for SyxString {
+    fn from(bytes: Vec<u8>) -> SyxString {
+        SyxString::new(bytes)
+    }
+}
+
+impl<'a> From<&'a [u8]> for SyxString {
+    fn from(bytes: &'a [u8]) -> SyxString {
+        SyxString::new(bytes)
+    }
+}
+
+impl<'a> From<&'a str> for SyxString {
+    fn from(string: &'a str) -> SyxString {
+        SyxString::new(string.as_bytes())
+    }
+}
+
+// Hashes table keys from what `Hash for SyxValue` writes (the cached hash of
+// a string, an integer or the index of an object) mixed with the seed
+#[derive(Clone, Copy)]
+pub struct SeededState(u64);
+
+impl Default for SeededState {
+    fn default() -> SeededState {
+        SeededState(hash_seed())
+    }
+}
+
+impl BuildHasher for SeededState {
+    type Hasher = SeededHasher;
+
+    fn build_hasher(&self) -> SeededHasher {
+        SeededHasher(self.0)
+    }
+}
+
+pub struct SeededHasher(u64);
+
+impl Hasher for SeededHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_u64(u64::from(byte));
+        }
+    }
+
+    // folded multiply, as foldhash
+    fn write_u64(&mut self, value: u64) {
+        let product = u128::from(self.0 ^ value) * 0x5851_f42d_4c95_7f2d;
+        self.0 = (product as u64) ^ (product >> 64) as u64;
+    }
+
+    fn write_u8(&mut self, value: u8) {
+        self.write_u64(u64::from(value));
+    }
+
+    fn write_i32(&mut self, value: i32) {
+        self.write_u64(value as u64);
+    }
+
+    fn write_i64(&mut self, value: i64) {
+        self.write_u64(value as u64);
+    }
+
+    fn write_usize(&mut self, value: usize) {
+        self.write_u64(value as u64);
+    }
+}
+
+// Interning table for short strings, keyed by the cached hash. The long
+// strings it makes are held too, so that both kinds are freed from it when
+// nothing else refers to them and their size can be accounted for.
+#[derive(Default)]
+pub struct StringTable {
+    buckets: HashMap<u64, Vec<SyxString>, SeededState>,
+    long: Vec<SyxString>,
+    count: usize,
+    size: usize, // bytes of the strings held, short and long
+}
+
+impl StringTable {
+    pub fn new() -> StringTable {
+        StringTable::default()
+    }
+
+    // Returns the interned copy of a short string, creating it if needed.
+    // Long strings are not interned and always get a new allocation.
+    pub fn intern(&mut self, bytes: &[u8]) -> SyxString {
+        if bytes.len() > SYX_MAXSHORTLEN {
+            let string = SyxString::new(bytes);
+            self.size += string.size();
+            self.long.push(string.clone());
+            return string;
+        }
+        let hash = hash_bytes(bytes);
+        let bucket = self.buckets.entry(hash).or_default();
+        if let Some(string) = bucket.iter().find(|s| s.0.bytes() == bytes) {
+            return string.clone();
+        }
+        let string = SyxString(Arc::new(StringData {
+            hash,
+            storage: Storage::Owned(bytes.into()),
+        }));
+        bucket.push(string.clone());
+        self.count += 1;
+        self.size += string.size();
+        string
+    }
+
+    // Drop strings only the table still refers to, returning the bytes freed
+    pub(crate) fn sweep(&mut self) -> usize {
+        let mut count = 0;
+        let mut freed = 0;
+        let mut alive = |s: &SyxString| {
+            let alive = Arc::strong_count(&s.0) > 1;
+            if !alive {
+                freed += s.size();
+            }
+            alive
+        };
+        self.buckets.retain(|_, bucket| {
+            bucket.retain(&mut alive);
+            count += bucket.len();
+            !bucket.is_empty()
+        });
+        self.long.retain(alive);
+        self.count = count;
+        self.size -= freed;
+        freed
+    }
+
+    // bytes of the strings held
+    pub(crate) fn size(&self) -> usize {
+        self.size
+    }
+
+    // number of interned strings
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interning() {
+        let mut table = StringTable::new();
+        let a = table.intern(b"hello");
+        let b = table.intern(b"hello");
+        assert!(a.ptr_eq(&b));
+        assert_eq!(table.len(), 1);
+
+        // equal contents compare equal without being interned
+        let c = SyxString::from("hello");
+        assert!(!a.ptr_eq(&c));
+        assert_eq!(a, c);
+        assert_eq!(a.cached_hash(), c.cached_hash());
+
+        let long = vec![b'x'; SYX_MAXSHORTLEN + 1];
+        let d = table.intern(&long);
+        let e = table.intern(&long);
+        assert!(!d.is_short() && !d.ptr_eq(&e));
+        assert_eq!(d, e);
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn test_hash_seed() {
+        // asking for the seed chooses it if nothing has yet, so it is fixed
+        // from here on whatever other tests ran first
+        let seed = hash_seed();
+        assert!(set_hash_seed(seed).is_ok());
+        let error = set_hash_seed(seed.wrapping_add(1)).unwrap_err();
+        assert_eq!(error.to_string(), format!("hash seed already chosen: {:#x}", seed));
+
+        let hash = |value: u64| SeededState(seed).hash_one(value);
+        assert_eq!(hash(1), hash(1));
+        assert!(hash(1) != hash(2));
+        assert!(SeededState(seed).hash_one(1) != SeededState(!seed).hash_one(1));
+        assert_eq!(SyxString::from("key").cached_hash(), hash_bytes(b"key"));
+
+        assert_eq!(parse_seed("1234"), Some(1234));
+        assert_eq!(parse_seed("0xff"), Some(0xff));
+        assert_eq!(parse_seed("random"), None);
+    }
+}
@@ -0,0 +1,292 @@
+// Async native functions and tasks
+//
+// An async native function returns a Future rather than its results, and can
+// only be called from a task: a coroutine started with `spawn` and driven by
+// the host through `poll_tasks`. Calling one suspends the task as a yield
+// does, and the future is kept with the task; once it resolves, the task is
+// resumed with what it produced as the results of the call. A future that
+// fails kills its task, as no pcall can be active across a yield.
+//
+// `poll_tasks` takes the Context of whatever runs it, so `drive_tasks` can be
+// awaited on any executor (tokio's included), and `run_tasks` is a blocking
+// executor of its own that parks the thread until a future wakes it. A task
+// that yields plainly is resumed again on the next poll. The results of a
+// finished task are kept until `task_result` takes them.
+
+use std::collections::HashMap;
+use std::future::{poll_fn, Future};
+use std::mem;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+use super::coroutine::Resumed;
+use super::errors::*;
+use super::native::Args;
+use super::object::{MultiValue, SyxValue, ThreadRef};
+use super::state::SyxState;
+use super::stdlib::set_field;
+use super::vm::runtime_error;
+
+pub type NativeFuture = Pin<Box<dyn Future<Output = Result<MultiValue>> + Send>>;
+
+struct Task {
+    thread: ThreadRef,
+    args: Vec<SyxValue>,          // to resume it with next, when it awaits nothing
+    future: Option<NativeFuture>, // what it is waiting on
+}
+
+#[derive(Default)]
+pub(crate) struct Tasks {
+    running: Vec<Task>,
+    finished: HashMap<ThreadRef, Result<MultiValue>>,
+    awaiting: Option<NativeFuture>, // left by an async function for its task
+}
+
+impl Tasks {
+    // threads and values the collector has to keep
+    pub(crate) fn threads(&self) -> impl Iterator<Item = ThreadRef> + '_ {
+        self.running.iter().map(|task| task.thread)
+    }
+
+    pub(crate) fn values(&self) -> impl Iterator<Item = &SyxValue> {
+        let results = self.finished.values().filter_map(|result| result.as_ref().ok());
+        self.running.iter().flat_map(|task| task.args.iter())
+            .chain(results.flat_map(|values| values.iter()))
+    }
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<ThreadWaker>) {
+        self.0.unpark();
+    }
+}
+
+impl SyxState {
+    // Function value calling `function` and suspending the task calling it
+    // until the future it returns resolves
+    pub fn create_async_function<F, T>(&mut self, name: &str, function: F) -> SyxValue
+        where F: Fn(&mut SyxState, Args) -> T + Send + Sync + 'static,
+              T: Future<Output = Result<MultiValue>> + Send + 'static
+    {
+        self.create_function(name, move |state, args| {
+            if !state.current.is_some_and(|thread| state.is_task(thread)) {
+                return runtime_error(format!("attempt to call async function '{}' outside a task",
+                                             args.name()));
+            }
+            if state.nny > 0 {
+                return runtime_error("attempt to yield across a C-call boundary".to_owned());
+            }
+            let future = function(state, args);
+            state.tasks.awaiting = Some(Box::pin(future));
+            state.yielded = Some(Vec::new());
+            Ok(MultiValue::new())
+        })
+    }
+
+    // Store an async `function` as global `name`
+    pub fn register_async<F, T>(&mut self, name: &str, function: F)
+        where F: Fn(&mut SyxState, Args) -> T + Send + Sync + 'static,
+              T: Future<Output = Result<MultiValue>> + Send + 'static
+    {
+        let function = self.create_async_function(name, function);
+        let globals = self.globals();
+        set_field(self, globals, name, function);
+    }
+
+    // Run `body` with `args` as a task, from the next poll on, returning its
+    // coroutine
+    pub fn spawn(&mut self, body: SyxValue, args: Vec<SyxValue>) -> ThreadRef {
+        let thread = self.new_thread(body);
+        self.tasks.running.push(Task { thread, args, future: None });
+        thread
+    }
+
+    fn is_task(&self, thread: ThreadRef) -> bool {
+        self.tasks.running.iter().any(|task| task.thread == thread)
+    }
+
+    pub fn has_tasks(&self) -> bool {
+        !self.tasks.running.is_empty()
+    }
+
+    // What a finished task returned or failed with, taken out of the state
+    pub fn task_result(&mut self, thread: ThreadRef) -> Option<Result<MultiValue>> {
+        self.tasks.finished.remove(&thread)
+    }
+
+    // Run every task that can go on until it waits again or finishes. Ready
+    // once no task is left.
+    pub fn poll_tasks(&mut self, cx: &mut Context) -> Poll<()> {
+        let mut i = 0;
+        while i < self.tasks.running.len() {
+            let args = match self.tasks.running[i].future.take() {
+                Some(mut future) => match future.as_mut().poll(cx) {
+                    Poll::Pending => {
+                        self.tasks.running[i].future = Some(future);
+                        i += 1;
+                        continue;
+                    }
+                    Poll::Ready(Ok(values)) => values.into_vec(),
+                    Poll::Ready(Err(error)) => {
+                        let task = self.tasks.running.remove(i);
+                        self.kill_thread(task.thread);
+                        self.tasks.finished.insert(task.thread, Err(error));
+                        continue;
+                    }
+                },
+                None => mem::take(&mut self.tasks.running[i].args),
+            };
+            let thread = self.tasks.running[i].thread;
+            match self.resume(thread, args) {
+                Ok(Resumed::Yield(_)) => match self.tasks.awaiting.take() {
+                    // polled straight away, it may be ready already
+                    Some(future) => self.tasks.running[i].future = Some(future),
+                    None => {
+                        cx.waker().wake_by_ref();
+                        i += 1;
+                    }
+                },
+                result => {
+                    self.tasks.running.remove(i);
+                    let result = result.map(|resumed| match resumed {
+                        Resumed::Return(values) | Resumed::Yield(values) => values,
+                    });
+                    self.tasks.finished.insert(thread, result);
+                }
+            }
+        }
+        if self.tasks.running.is_empty() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    // Future finishing every task, for a host executor to await
+    pub fn drive_tasks(&mut self) -> impl Future<Output = ()> + '_ {
+        poll_fn(move |cx| self.poll_tasks(cx))
+    }
+
+    // Finish every task, blocking the thread while they wait
+    pub fn run_tasks(&mut self) {
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        while self.poll_tasks(&mut cx).is_pending() {
+            thread::park();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use super::*;
+    use super::super::compiler::parse;
+    use super::super::coroutine::{coyield, CoStatus};
+    use super::super::stdlib;
+
+    // resolves to the value another thread sets
+    struct Delayed {
+        shared: Arc<Mutex<(Option<SyxValue>, Option<Waker>)>>,
+    }
+
+    impl Future for Delayed {
+        type Output = Result<MultiValue>;
+
+        fn poll(self: Pin<&mut Delayed>, cx: &mut Context) -> Poll<Result<MultiValue>> {
+            let mut shared = self.shared.lock().unwrap();
+            match shared.0.take() {
+                Some(value) => Poll::Ready(Ok(vec![value].into())),
+                None => {
+                    shared.1 = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        }
+    }
+
+    // delay(n) resolves to n * 2 from another thread
+    fn delay(_: &mut SyxState, args: Args) -> Delayed {
+        let shared: Arc<Mutex<(Option<SyxValue>, Option<Waker>)>> = Arc::default();
+        let n = args.check_integer(1).unwrap_or(0);
+        let setter = shared.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(5));
+            let mut shared = setter.lock().unwrap();
+            shared.0 = Some(SyxValue::Integer(n * 2));
+            if let Some(waker) = shared.1.take() {
+                waker.wake();
+            }
+        });
+        Delayed { shared }
+    }
+
+    fn task_state() -> SyxState {
+        let mut state = SyxState::new();
+        stdlib::open_libs(&mut state);
+        let globals = state.globals();
+        set_field(&mut state, globals, "pause", SyxValue::Native(coyield));
+        state.register_async("delay", delay);
+        state.register_async("ready", |_, args: Args| {
+            let values = args.into_values();
+            future::ready(match values.first() {
+                Some(&SyxValue::Bool(false)) => runtime_error("not ready".to_owned()),
+                _ => Ok(values.into()),
+            })
+        });
+        state
+    }
+
+    fn spawn_source(state: &mut SyxState, source: &str) -> ThreadRef {
+        let proto = Arc::new(parse(source.as_bytes(), "=test").unwrap());
+        let body = SyxValue::Function(state.new_main_closure(proto));
+        state.spawn(body, Vec::new())
+    }
+
+    #[test]
+    fn test_tasks() {
+        let mut state = task_state();
+        let first = spawn_source(&mut state, "log = '' local a = delay(1) log = log .. 'a' \
+                                              return a + delay(a), ready(1, 2)");
+        let second = spawn_source(&mut state, "pause() log = log .. 'b' \
+                                               return ready('x')");
+        state.collect_garbage();
+        state.run_tasks();
+        assert!(!state.has_tasks());
+        assert!(state.task_result(first).unwrap().unwrap() == vec![
+            SyxValue::Integer(6), SyxValue::Integer(1), SyxValue::Integer(2)]);
+        assert!(state.task_result(second).unwrap().unwrap() == vec![SyxValue::from("x")]);
+        assert!(state.task_result(second).is_none());
+        // the second task ran while the first one waited
+        let globals = state.globals();
+        assert_eq!(state.table(globals).get(&SyxValue::from("log")), SyxValue::from("ba"));
+    }
+
+    #[test]
+    fn test_task_errors() {
+        let mut state = task_state();
+        let failing = spawn_source(&mut state, "local x = ready(false) return x");
+        let raising = spawn_source(&mut state, "error('raised', 0)");
+        let nested = spawn_source(&mut state, "return pcall(delay, 1)");
+        state.run_tasks();
+        let error = state.task_result(failing).unwrap().unwrap_err();
+        assert_eq!(error.to_string(), "not ready");
+        assert_eq!(state.thread_status(failing), CoStatus::Dead);
+        let error = state.task_result(raising).unwrap().unwrap_err();
+        assert_eq!(state.error_value(&error), SyxValue::from("raised"));
+        assert!(state.task_result(nested).unwrap().unwrap() == vec![
+            SyxValue::Bool(false), SyxValue::from("attempt to yield across a C-call boundary")]);
+
+        let proto = Arc::new(parse(b"return delay(1)", "=test").unwrap());
+        let error = state.call(proto, Vec::new()).unwrap_err();
+        assert_eq!(error.to_string(),
+                   "test:1: attempt to call async function 'delay' outside a task");
+    }
+}
@@ -0,0 +1,40 @@
+// Helpers shared by the tests of several modules
+
+use std::sync::Arc;
+
+use super::compiler::parse;
+use super::errors::*;
+use super::object::{MultiValue, SyxInteger, SyxValue};
+use super::opcodes::{Instruction, OpCode};
+use super::state::SyxState;
+use super::stdlib::open_libs;
+
+// Run `source`, a chunk named "=test", in `state`
+pub(crate) fn run_in(state: &mut SyxState, source: &str) -> Result<MultiValue> {
+    let proto = parse(source.as_bytes(), "=test")?;
+    state.call(Arc::new(proto), Vec::new())
+}
+
+// Run `source` in a new state with the standard libraries open
+pub(crate) fn run_source(source: &str) -> Result<MultiValue> {
+    let mut state = SyxState::new();
+    open_libs(&mut state);
+    run_in(&mut state, source)
+}
+
+pub(crate) fn abc(op: OpCode, a: u8, b: u16, c: u16) -> Instruction {
+    Instruction::ABC { instruction: op, a, b, c }
+}
+
+pub(crate) fn abx(op: OpCode, a: u8, bx: u32) -> Instruction {
+    Instruction::ABx { instruction: op, a, bx }
+}
+
+pub(crate) fn asbx(op: OpCode, a: u8, sbx: i32) -> Instruction {
+    Instruction::AsBx { instruction: op, a, sbx }
+}
+
+// integer values, for comparing results with
+pub(crate) fn ints(values: &[SyxInteger]) -> Vec<SyxValue> {
+    values.iter().map(|&i| SyxValue::Integer(i)).collect()
+}
@@ -0,0 +1,99 @@
+// Tag methods (metamethods)
+//
+// Events are looked up by name in the metatable of the value that triggered
+// them. Tables carry their own metatable, and every string shares the one
+// the string library sets up.
+//
+// Consult the versioned ltm.c for more information.
+
+use super::object::{SyxValue, TableRef};
+use super::state::SyxState;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TagMethod {
+    Index,
+    NewIndex,
+    Gc,
+    Mode,
+    Len,
+    Eq,
+    Add,
+    Sub,
+    Mul,
+    Mod,
+    Pow,
+    Div,
+    IDiv,
+    BAnd,
+    BOr,
+    BXor,
+    Shl,
+    Shr,
+    Unm,
+    BNot,
+    Lt,
+    Le,
+    Concat,
+    Call,
+}
+
+impl TagMethod {
+    pub const ALL: [TagMethod; 24] = [
+        TagMethod::Index, TagMethod::NewIndex, TagMethod::Gc, TagMethod::Mode,
+        TagMethod::Len, TagMethod::Eq, TagMethod::Add, TagMethod::Sub,
+        TagMethod::Mul, TagMethod::Mod, TagMethod::Pow, TagMethod::Div,
+        TagMethod::IDiv, TagMethod::BAnd, TagMethod::BOr, TagMethod::BXor,
+        TagMethod::Shl, TagMethod::Shr, TagMethod::Unm, TagMethod::BNot,
+        TagMethod::Lt, TagMethod::Le, TagMethod::Concat, TagMethod::Call,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TagMethod::Index => "__index",
+            TagMethod::NewIndex => "__newindex",
+            TagMethod::Gc => "__gc",
+            TagMethod::Mode => "__mode",
+            TagMethod::Len => "__len",
+            TagMethod::Eq => "__eq",
+            TagMethod::Add => "__add",
+            TagMethod::Sub => "__sub",
+            TagMethod::Mul => "__mul",
+            TagMethod::Mod => "__mod",
+            TagMethod::Pow => "__pow",
+            TagMethod::Div => "__div",
+            TagMethod::IDiv => "__idiv",
+            TagMethod::BAnd => "__band",
+            TagMethod::BOr => "__bor",
+            TagMethod::BXor => "__bxor",
+            TagMethod::Shl => "__shl",
+            TagMethod::Shr => "__shr",
+            TagMethod::Unm => "__unm",
+            TagMethod::BNot => "__bnot",
+            TagMethod::Lt => "__lt",
+            TagMethod::Le => "__le",
+            TagMethod::Concat => "__concat",
+            TagMethod::Call => "__call",
+        }
+    }
+}
+
+impl SyxState {
+    pub fn metatable(&self, value: &SyxValue) -> Option<TableRef> {
+        match *value {
+            SyxValue::Table(t) => self.table(t).metatable(),
+            SyxValue::String(_) => self.string_metatable,
+            SyxValue::UserData(u) => self.userdata_metatable(u),
+            _ => None,
+        }
+    }
+
+    // handler for `event` in the metatable of `value`, if there is one
+    pub fn metamethod(&self, value: &SyxValue, event: TagMethod) -> Option<SyxValue> {
+        let metatable = self.metatable(value)?;
+        let name = SyxValue::String(self.tm_names[event as usize].clone());
+        match self.table(metatable).get(&name) {
+            SyxValue::Nil => None,
+            handler => Some(handler),
+        }
+    }
+}
@@ -0,0 +1,266 @@
+// Trace recorder
+//
+// An instrumentation mode for finding hot loops and seeing what they run. A
+// loop is found by its back edge: an instruction running at or before the
+// one before it, in the same function and frame, is a loop header. Every
+// header counts the iterations it starts, and once it reaches the threshold
+// the next iteration is recorded as a trace: each instruction the loop's
+// frame runs until it gets back to the header, with the types of the values
+// it reads. Calls appear as their CALL; what the callee runs is not part of
+// the trace. An iteration that leaves the frame or runs longer than
+// MAX_TRACE_LENGTH is dropped, and the next one is recorded instead.
+//
+// Each header is recorded once. Traces and counts are kept until the
+// recorder is stopped, along with the functions they are in.
+//
+// Loosely follows the recorder of LuaJIT (lj_record.c, lj_trace.c); Lua
+// itself has none.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::object::{Proto, SyxType, SyxValue};
+use super::opcodes::{Instruction, OpCode};
+use super::state::SyxState;
+
+const BITRK: u16 = 1 << 8; // see vm.rs
+const MAX_TRACE_LENGTH: usize = 1000; // instructions recorded before giving up
+
+// One instruction of a trace, and the types of the values it read
+#[derive(Debug)]
+pub struct TraceEntry {
+    pub pc: usize,
+    pub line: Option<i32>,
+    pub opcode: OpCode,
+    pub operands: Vec<SyxType>,
+}
+
+// One iteration of a hot loop, from its header back to it
+pub struct Trace {
+    pub proto: Arc<Proto>,
+    pub start: usize, // pc of the loop header
+    pub entries: Vec<TraceEntry>,
+}
+
+// Iterations started by a loop header
+pub struct LoopCount {
+    pub proto: Arc<Proto>,
+    pub start: usize,
+    pub iterations: u32,
+}
+
+pub(crate) struct Recorder {
+    threshold: u32,
+    // by address of the Proto, which `proto` keeps alive, and header
+    counts: HashMap<(usize, usize), LoopCount>,
+    last: Option<(usize, usize, usize)>, // Proto, frame count and pc last run
+    recording: Option<(Trace, usize)>,   // with the frame count of its loop
+    traces: Vec<Trace>,
+}
+
+fn opcode(i: &Instruction) -> OpCode {
+    match *i {
+        Instruction::ABC { instruction, .. } | Instruction::ABx { instruction, .. } |
+        Instruction::AsBx { instruction, .. } | Instruction::Ax { instruction, .. } => instruction,
+    }
+}
+
+fn address(proto: &Arc<Proto>) -> usize {
+    &**proto as *const Proto as usize
+}
+
+impl SyxState {
+    // Start counting loop iterations, recording a trace of each loop that
+    // starts `threshold` of them. Anything recorded so far is discarded.
+    pub fn start_trace_recording(&mut self, threshold: u32) {
+        self.recorder = Some(Box::new(Recorder {
+            threshold,
+            counts: HashMap::new(),
+            last: None,
+            recording: None,
+            traces: Vec::new(),
+        }));
+    }
+
+    // Stop recording, returning the complete traces in the order they were
+    // recorded
+    pub fn stop_trace_recording(&mut self) -> Vec<Trace> {
+        self.recorder.take().map(|recorder| recorder.traces).unwrap_or_default()
+    }
+
+    pub fn traces(&self) -> &[Trace] {
+        self.recorder.as_ref().map(|recorder| &recorder.traces[..]).unwrap_or(&[])
+    }
+
+    // Iterations counted for every loop header seen, most first
+    pub fn loop_counts(&self) -> Vec<&LoopCount> {
+        let mut counts: Vec<&LoopCount> = match self.recorder {
+            Some(ref recorder) => recorder.counts.values().collect(),
+            None => Vec::new(),
+        };
+        counts.sort_by(|a, b| b.iterations.cmp(&a.iterations).then(a.start.cmp(&b.start)));
+        counts
+    }
+
+    // Instruction `pc` of `proto`, in the frame at `base`, is about to run
+    pub(crate) fn record(&mut self, proto: &Arc<Proto>, base: usize, pc: usize) {
+        let mut recorder = match self.recorder.take() {
+            Some(recorder) => recorder,
+            None => return,
+        };
+        let here = (address(proto), self.frames.len(), pc);
+        let header = match recorder.last {
+            Some((p, depth, last)) => p == here.0 && depth == here.1 && pc <= last,
+            None => false,
+        };
+        recorder.last = Some(here);
+
+        if let Some((trace, depth)) = recorder.recording.take() {
+            if here.1 > depth {
+                // in a function the loop called
+                recorder.recording = Some((trace, depth));
+            } else if here.1 < depth || address(&trace.proto) != here.0 ||
+                      trace.entries.len() >= MAX_TRACE_LENGTH {
+                // left the loop
+            } else if pc == trace.start && !trace.entries.is_empty() {
+                recorder.traces.push(trace);
+            } else {
+                let mut trace = trace;
+                trace.entries.push(self.trace_entry(proto, base, pc));
+                recorder.recording = Some((trace, depth));
+            }
+        }
+
+        if header {
+            let entry = recorder.counts.entry((here.0, pc)).or_insert_with(|| LoopCount {
+                proto: proto.clone(),
+                start: pc,
+                iterations: 0,
+            });
+            entry.iterations = entry.iterations.saturating_add(1);
+            let recorded = recorder.traces.iter()
+                .any(|trace| trace.start == pc && address(&trace.proto) == here.0);
+            if entry.iterations >= recorder.threshold && !recorded &&
+               recorder.recording.is_none() {
+                let trace = Trace { proto: proto.clone(), start: pc, entries: Vec::new() };
+                let entry = self.trace_entry(proto, base, pc);
+                recorder.recording = Some((Trace { entries: vec![entry], ..trace }, here.1));
+            }
+        }
+        self.recorder = Some(recorder);
+    }
+
+    fn trace_entry(&self, proto: &Proto, base: usize, pc: usize) -> TraceEntry {
+        let instruction = &proto.instructions[pc];
+        TraceEntry {
+            pc,
+            line: proto.lineinfo.get(pc).cloned(),
+            opcode: opcode(instruction),
+            operands: self.operands(proto, base, instruction)
+                .iter()
+                .map(|value| value.syx_type())
+                .collect(),
+        }
+    }
+
+    // values an instruction reads from registers and constants
+    fn operands<'a>(&'a self, proto: &'a Proto, base: usize, instruction: &Instruction)
+        -> Vec<&'a SyxValue>
+    {
+        let register = |r: usize| &self.stack[base + r];
+        let rk = |x: u16| if x & BITRK != 0 {
+            &proto.constants[(x & !BITRK) as usize]
+        } else {
+            register(x as usize)
+        };
+        match *instruction {
+            Instruction::ABC { instruction: op, a, b, c } => {
+                let a = a as usize;
+                match op {
+                    OpCode::Move | OpCode::TestSet => vec![register(b as usize)],
+                    OpCode::GetTable | OpCode::SelfLoad => vec![register(b as usize), rk(c)],
+                    OpCode::GetTabUp => vec![rk(c)],
+                    OpCode::SetTable => vec![register(a), rk(b), rk(c)],
+                    OpCode::SetTabUp => vec![rk(b), rk(c)],
+                    OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::Mod | OpCode::Pow |
+                    OpCode::Div | OpCode::IDiv | OpCode::BAnd | OpCode::BOr | OpCode::BXOr |
+                    OpCode::Shl | OpCode::Shr | OpCode::Eq | OpCode::Lt | OpCode::Le => {
+                        vec![rk(b), rk(c)]
+                    }
+                    OpCode::Unm | OpCode::BNot | OpCode::Not | OpCode::Len => vec![rk(b)],
+                    OpCode::Concat => (b as usize..=c as usize).map(register).collect(),
+                    OpCode::Test | OpCode::SetUpval | OpCode::Call | OpCode::TailCall |
+                    OpCode::TForCall => vec![register(a)],
+                    _ => Vec::new(),
+                }
+            }
+            Instruction::AsBx { instruction: op, a, .. } => {
+                let a = a as usize;
+                match op {
+                    OpCode::ForLoop | OpCode::ForPrep => (a..a + 3).map(register).collect(),
+                    OpCode::TForLoop => vec![register(a + 1)],
+                    _ => Vec::new(),
+                }
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::compiler::parse;
+    use super::super::stdlib;
+
+    fn record(source: &str, threshold: u32) -> (SyxState, Vec<Trace>) {
+        let proto = parse(source.as_bytes(), "=test").unwrap();
+        let mut state = SyxState::new();
+        stdlib::open_libs(&mut state);
+        state.start_trace_recording(threshold);
+        state.call(Arc::new(proto), Vec::new()).unwrap();
+        assert!(!state.loop_counts().is_empty());
+        let traces = state.stop_trace_recording();
+        (state, traces)
+    }
+
+    #[test]
+    #[cfg(not(feature = "float_only"))] // integer loops
+    fn test_numeric_loop() {
+        let (_, traces) = record("local sum = 0 for i = 1, 100 do sum = sum + i * 0.5 end", 10);
+        assert_eq!(traces.len(), 1);
+        let trace = &traces[0];
+        let opcodes: Vec<OpCode> = trace.entries.iter().map(|entry| entry.opcode).collect();
+        assert_eq!(opcodes, vec![OpCode::Mul, OpCode::Add, OpCode::ForLoop]);
+        assert_eq!(trace.entries[0].pc, trace.start);
+        // i * 0.5 reads an integer and a float, sum was made a float already
+        assert!(matches!(trace.entries[0].operands[..], [SyxType::TNUMINT, SyxType::TNUMFLT]));
+        assert!(matches!(trace.entries[1].operands[..], [SyxType::TNUMFLT, SyxType::TNUMFLT]));
+        assert_eq!(trace.entries[2].line, Some(1));
+    }
+
+    #[test]
+    fn test_threshold() {
+        let source = "local t = {} for i = 1, 5 do t[i] = tostring(i) end \
+                      local n = 0 while n < 50 do n = n + 1 end";
+        let (state, traces) = record(source, 20);
+        // only the while loop gets hot enough
+        assert_eq!(traces.len(), 1);
+        assert!(traces[0].entries.iter().any(|entry| entry.opcode == OpCode::Lt));
+        assert!(state.traces().is_empty());
+        let proto = parse(source.as_bytes(), "=test").unwrap();
+        let mut state = SyxState::new();
+        stdlib::open_libs(&mut state);
+        state.start_trace_recording(1);
+        state.call(Arc::new(proto), Vec::new()).unwrap();
+        // a back edge per run of the body, but for the first run of the for
+        // loop's, which FORPREP falls through to
+        let counts = state.loop_counts();
+        assert_eq!(counts[0].iterations, 50);
+        assert_eq!(counts[1].iterations, 4);
+        // the call to tostring is recorded, not what it runs
+        assert!(state.traces()[0].entries.iter()
+            .any(|entry| entry.opcode == OpCode::Call &&
+                 entry.operands == [SyxType::TFUNCTION]));
+    }
+}
@@ -1,22 +1,185 @@
 use std::convert::{TryFrom, TryInto};
+use std::io::{BufReader, Cursor, Read};
+use std::sync::Arc;
 
-use super::conf::{SYX_HEADER, SYX_DATA, SYX_VERSION, SYX_FORMAT, SYX_INT, SYX_NUM};
+use super::conf::{
+    SYX_HEADER, SYX_DATA, SYX_VERSION, SYX_FORMAT, SYX_FORMAT_COLUMNS, SYX_INT, SYX_NUM,
+};
 
 use super::object::{
-    LocVar, Proto, SyxInt, SyxInteger, SyxNumber, SyxString,
+    integer_value, LocVar, Proto, SyxInt, SyxInteger, SyxNumber, SyxString,
     SyxType, SyxValue, Upvalue
 };
-use super::opcodes::{Instruction, Word};
-use super::{limits, state};
+use super::opcodes::Word;
+use super::state::SyxState;
+use super::limits::SYX_MAXSHORTLEN;
+use super::string::{SharedBuffer, StringTable};
+use super::format;
 use super::errors::*;
 
-pub struct LoadState {
-    input: Box<Iterator<Item = u8>>,
-    name: Box<::std::fmt::Display>,
-    state: Option<state::SyxState>,
+// Source of the bytes of a chunk, read a section at a time. A source held in
+// memory hands out slices of itself, and one that streams fills a buffer of
+// its own. Nothing else is needed from `std::io`, so chunks held in memory
+// load on targets with no filesystem, such as wasm32.
+pub trait ChunkReader {
+    // The next `n` bytes, or what is left if there are fewer
+    fn read(&mut self, n: usize) -> Result<&[u8]>;
+
+    // The same as a string sharing the memory they are in, None if that can
+    // not outlive the reader
+    fn read_shared(&mut self, _n: usize) -> Result<Option<SyxString>> {
+        Ok(None)
+    }
+
+    // Bytes left to read, None if that is not known in advance
+    fn remaining(&self) -> Option<usize> {
+        None
+    }
+}
+
+impl ChunkReader for &[u8] {
+    fn read(&mut self, n: usize) -> Result<&[u8]> {
+        let (head, tail) = self.split_at(n.min(self.len()));
+        *self = tail;
+        Ok(head)
+    }
+
+    fn remaining(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
+
+impl<R: ChunkReader + ?Sized> ChunkReader for &mut R {
+    fn read(&mut self, n: usize) -> Result<&[u8]> {
+        (**self).read(n)
+    }
+
+    fn read_shared(&mut self, n: usize) -> Result<Option<SyxString>> {
+        (**self).read_shared(n)
+    }
+
+    fn remaining(&self) -> Option<usize> {
+        (**self).remaining()
+    }
+}
+
+impl<T: AsRef<[u8]>> ChunkReader for Cursor<T> {
+    fn read(&mut self, n: usize) -> Result<&[u8]> {
+        let length = self.get_ref().as_ref().len();
+        let start = (self.position() as usize).min(length);
+        let end = start + n.min(length - start);
+        self.set_position(end as u64);
+        Ok(&self.get_ref().as_ref()[start..end])
+    }
+
+    fn remaining(&self) -> Option<usize> {
+        let length = self.get_ref().as_ref().len();
+        Some(length - (self.position() as usize).min(length))
+    }
+}
+
+// Chunk in a buffer its long strings can share, see `LoadState::from_shared`
+pub struct SharedReader {
+    buffer: SharedBuffer,
+    position: usize,
+}
+
+impl SharedReader {
+    pub fn new(buffer: SharedBuffer) -> SharedReader {
+        SharedReader { buffer, position: 0 }
+    }
+
+    fn advance(&mut self, n: usize) -> (usize, usize) {
+        let start = self.position;
+        self.position += n.min((*self.buffer).as_ref().len() - start);
+        (start, self.position)
+    }
+}
+
+impl ChunkReader for SharedReader {
+    fn read(&mut self, n: usize) -> Result<&[u8]> {
+        let (start, end) = self.advance(n);
+        Ok(&(*self.buffer).as_ref()[start..end])
+    }
+
+    fn read_shared(&mut self, n: usize) -> Result<Option<SyxString>> {
+        let (start, end) = self.advance(n);
+        Ok(Some(SyxString::shared(&self.buffer, start, end)))
+    }
+
+    fn remaining(&self) -> Option<usize> {
+        Some((*self.buffer).as_ref().len() - self.position)
+    }
 }
 
-trait Primitives {}
+// Chunk read from any `io::Read` as it is loaded
+pub struct StreamReader<R> {
+    input: BufReader<R>,
+    buffer: Vec<u8>,
+}
+
+impl<R: Read> StreamReader<R> {
+    pub fn new(input: R) -> StreamReader<R> {
+        StreamReader { input: BufReader::new(input), buffer: Vec::new() }
+    }
+}
+
+impl<R: Read> ChunkReader for StreamReader<R> {
+    fn read(&mut self, n: usize) -> Result<&[u8]> {
+        self.buffer.clear();
+        self.input.by_ref().take(n as u64).read_to_end(&mut self.buffer)
+            .chain_err(|| "read failed")?;
+        Ok(&self.buffer)
+    }
+}
+
+// Name of a chunk in undump errors, as luaU_undump has it: without the "@"
+// or "=" of file and literal names, and "binary string" for a chunk named
+// after its own bytes, as `load` names strings
+fn chunk_name(name: String) -> String {
+    if name.starts_with('@') || name.starts_with('=') {
+        name[1..].to_owned()
+    } else if name.as_bytes().first() == Some(&SYX_HEADER[0]) {
+        "binary string".to_owned()
+    } else {
+        name
+    }
+}
+
+pub struct LoadState<'s> {
+    input: &'s mut dyn ChunkReader,
+    name: String,
+    strings: &'s mut StringTable, // short strings are interned here
+    offset: usize, // bytes read so far
+    start: usize, // where the value being read starts
+    pub(crate) section: Section,
+    diagnostics: Option<Vec<UndumpError>>, // problems to go on past, when strict
+    pub(crate) swap: bool, // chunk was dumped with the opposite byte order
+    pub(crate) sizes: ChunkSizes,
+    columns: bool, // debug info ends with columns (SYX_FORMAT_COLUMNS)
+}
+
+// Widths of the C types the chunk was dumped with; values are widened or
+// narrowed (checked) to the native Syx types as they are loaded
+pub(crate) struct ChunkSizes {
+    pub(crate) int: usize,
+    pub(crate) size: usize,
+    pub(crate) integer: usize,
+    pub(crate) number: usize,
+}
+
+impl ChunkSizes {
+    fn native() -> ChunkSizes {
+        ChunkSizes {
+            int: ::std::mem::size_of::<SyxInt>(),
+            size: ::std::mem::size_of::<usize>(),
+            integer: ::std::mem::size_of::<SyxInteger>(),
+            number: ::std::mem::size_of::<SyxNumber>(),
+        }
+    }
+}
+
+pub(crate) trait Primitives {}
 
 macro_rules! primitive {
     ($($item:ty),*) => { $(impl Primitives for $item {})* }
@@ -34,73 +197,174 @@ macro_rules! expand {
 }
 
 #[allow(dead_code)]
-impl LoadState {
+impl<'s> LoadState<'s> {
     pub fn from_read(
         mut input: impl ::std::io::Read,
         name: impl Into<String>,
     ) -> Result<Proto> {
         let mut buffer: Vec<u8> = Vec::new();
         let into_name = name.into();
-        if input.read_to_end(&mut buffer).is_ok() {
-            LoadState::from_u8(buffer, into_name.clone())
-        } else {
-            Err(ErrorKind::BufferNotReadable(into_name).into())
+        match input.read_to_end(&mut buffer) {
+            Ok(_) => LoadState::from_u8(buffer, into_name),
+            Err(cause) => {
+                let error = UndumpError::new(&chunk_name(into_name), buffer.len(),
+                                             Section::Header, UndumpReason::Unreadable);
+                Err(cause).chain_err(|| ErrorKind::Undump(error))
+            }
         }
     }
 
     pub fn from_u8(buffer: Vec<u8>, name: impl Into<String>)
         -> Result<Proto>
     {
-        let mut state = LoadState {
-            input: Box::new(buffer.into_iter()),
-            name: Box::new(name.into()),
-            state: None,
-        };
-        let proto = state.load_chunk(state::SyxState::new())?;
-        match state.load::<u8>() {
-            Err(_) => Ok(proto),
-            Ok(_) => Err(ErrorKind::BufferNotEmpty.into()),
+        LoadState::from_reader(Cursor::new(buffer), name)
+    }
+
+    /// Load a chunk without buffering it first; sections are parsed as the
+    /// bytes come in from the reader, so memory use is limited to the `Proto`
+    pub fn from_stream(input: impl Read, name: impl Into<String>)
+        -> Result<Proto>
+    {
+        LoadState::from_reader(StreamReader::new(input), name)
+    }
+
+    /// Load a chunk without copying its long strings, which share `buffer`
+    /// instead (and keep it alive as long as they are), as a mapped file
+    /// would be shared (see mmap.rs)
+    pub fn from_shared(buffer: SharedBuffer, name: impl Into<String>) -> Result<Proto> {
+        LoadState::from_reader(SharedReader::new(buffer), name)
+    }
+
+    pub fn from_reader(mut input: impl ChunkReader, name: impl Into<String>) -> Result<Proto> {
+        LoadState::load_from(&mut input, name, &mut StringTable::new())
+    }
+
+    /// Loader reporting every problem it can find in a chunk rather than
+    /// only the first, see `StrictLoader`
+    pub fn strict() -> StrictLoader {
+        StrictLoader
+    }
+
+    fn new(input: &'s mut dyn ChunkReader, name: String, strings: &'s mut StringTable)
+        -> LoadState<'s>
+    {
+        LoadState {
+            input,
+            name: chunk_name(name),
+            strings,
+            offset: 0,
+            start: 0,
+            section: Section::Header,
+            diagnostics: None,
+            swap: false,
+            sizes: ChunkSizes::native(),
+            columns: false,
         }
     }
 
-    fn assert_verification(&mut self, val: bool, err: impl ::std::fmt::Display)
+    fn load_from(input: &mut dyn ChunkReader, name: impl Into<String>,
+                 strings: &mut StringTable) -> Result<Proto>
+    {
+        LoadState::new(input, name.into(), strings).load_all()
+    }
+
+    fn load_all(&mut self) -> Result<Proto> {
+        let proto = self.load_chunk().map_err(|error| self.locate(error))?;
+        self.section = Section::Trailer;
+        if self.load::<u8>().is_ok() {
+            let error = self.undump_error(UndumpReason::TrailingBytes);
+            self.diagnose(error)?;
+        }
+        Ok(proto)
+    }
+
+    // A problem loading can go on past: kept when strict, raised otherwise
+    pub(crate) fn diagnose(&mut self, error: Error) -> Result<()> {
+        match (error, self.diagnostics.as_mut()) {
+            (Error(ErrorKind::Undump(error), _), Some(diagnostics)) => {
+                diagnostics.push(error);
+                Ok(())
+            }
+            (error, _) => Err(error),
+        }
+    }
+
+    // Errors raised while loading that are not about the chunk, such as a bad
+    // opcode, are wrapped in one saying where they were raised
+    fn locate(&self, error: Error) -> Error {
+        if let ErrorKind::Undump(_) = *error.kind() {
+            return error;
+        }
+        let reason = UndumpReason::Invalid(error.to_string());
+        let located = UndumpError::new(&self.name, self.start, self.section, reason);
+        Error::with_chain(error, ErrorKind::Undump(located))
+    }
+
+    pub(crate) fn assert_verification(&mut self, val: bool, err: impl ::std::fmt::Display)
         -> Result<()>
     {
-        return if !val {
-            self.raise_from_verification(err)
+        if !val {
+            let error = self.verification_error(err);
+            self.diagnose(error)
         } else {
             Ok(())
         }
     }
 
-    fn raise_from_verification(&mut self, err: impl ::std::fmt::Display)
+    pub(crate) fn raise_from_verification(&mut self, err: impl ::std::fmt::Display)
         -> Result<()>
     {
-        Err(ErrorKind::InvalidVerification(self.name.to_string(),
-                                           err.to_string()).into())
+        Err(self.verification_error(err))
     }
 
-    fn load_range(&mut self, range: usize) -> Result<Vec<u8>> {
-        let v: Vec<u8> = self.input.by_ref().take(range).collect();
-        self.assert_verification(v.len() == range,
-                                 format!("Not enough bytes: {}", range))?;
-        Ok(v)
-        // made redundant by the above
-        /*
-        let mut ret: Vec<u8> = Vec::with_capacity(range);
-        for i in 0..range {
-            if let Some(mut ch) = self.input.next() {
-                ret.push(ch);
-            } else {
-                self.raise_from_verification(
-                    format!("Missing byte at pos: {}", i))?;
-            }
+    pub(crate) fn verification_error(&self, err: impl ::std::fmt::Display) -> Error {
+        self.undump_error(UndumpReason::Verification(err.to_string()))
+    }
+
+    // error at the start of the value being read
+    pub(crate) fn undump_error(&self, reason: UndumpReason) -> Error {
+        UndumpError::new(&self.name, self.start, self.section, reason).into()
+    }
+
+    // the next `range` bytes, all of them or an error
+    fn read_bytes(&mut self, range: usize) -> Result<&[u8]> {
+        self.start = self.offset;
+        let (name, start, section) = (&self.name, self.start, self.section);
+        let unreadable = || UndumpError::new(name, start, section, UndumpReason::Unreadable);
+        let bytes = self.input.read(range).chain_err(|| ErrorKind::Undump(unreadable()))?;
+        self.offset += bytes.len();
+        if bytes.len() != range {
+            let reason = UndumpReason::Truncated(range);
+            return Err(UndumpError::new(name, start, section, reason).into());
+        }
+        Ok(bytes)
+    }
+
+    // the same as a string, sharing the reader's memory if it can
+    fn read_string(&mut self, range: usize) -> Result<SyxString> {
+        self.start = self.offset;
+        let shared = self.input.read_shared(range)
+            .chain_err(|| ErrorKind::Undump(self.unreadable()))?;
+        let string = match shared {
+            Some(string) => string,
+            None => return Ok(SyxString::new(self.read_bytes(range)?)),
         };
-        Ok(ret)
-        */
+        self.offset += string.len();
+        if string.len() != range {
+            return Err(self.undump_error(UndumpReason::Truncated(range)));
+        }
+        Ok(string)
     }
 
-    fn load<T: Copy + Primitives>(&mut self) -> Result<T> {
+    fn unreadable(&self) -> UndumpError {
+        UndumpError::new(&self.name, self.start, self.section, UndumpReason::Unreadable)
+    }
+
+    pub(crate) fn load_range(&mut self, range: usize) -> Result<Vec<u8>> {
+        Ok(self.read_bytes(range)?.to_vec())
+    }
+
+    pub(crate) fn load<T: Copy + Primitives>(&mut self) -> Result<T> {
         /*
          * Safety of this method
          * ---
@@ -108,7 +372,7 @@ impl LoadState {
          * it will alwasy pass:
          *
          * 1. It will always transmute bytes directly to the size of T
-         * 2. The size of T is loaded from self.load_range, which either grabs
+         * 2. The size of T is loaded from self.read_bytes, which either grabs
          *    the whole thing or fails to load
          * 3. All values of type `Primitives` are defined at the top of this
          *    file and will always be Rust primitives.
@@ -117,49 +381,128 @@ impl LoadState {
         // https://github.com/rust-lang/rust/issues/31844
         // https://github.com/rust-lang/rfcs/blob/master/text/1210-impl-specialization.md
         let size = ::std::mem::size_of::<T>();
-        let bytes = self.load_range(size)?;
-        Ok(unsafe { *(&bytes[0] as *const u8 as *const T) })
+        let swap = self.swap;
+        let mut bytes = [0u8; 8]; // no primitive is wider
+        bytes[..size].copy_from_slice(self.read_bytes(size)?);
+        if swap {
+            bytes[..size].reverse();
+        }
+        Ok(unsafe { ::std::ptr::read_unaligned(bytes.as_ptr() as *const T) })
+    }
+
+    pub(crate) fn narrow<S, T>(&self, value: S, name: &str) -> Result<T>
+        where S: Copy + ::std::fmt::Display, T: TryFrom<S>
+    {
+        T::try_from(value).map_err(|_| {
+            self.verification_error(format!("{} out of range: {}", name, value))
+        })
+    }
+
+    pub(crate) fn load_int(&mut self) -> Result<SyxInt> {
+        match self.sizes.int {
+            2 => Ok(SyxInt::from(self.load::<i16>()?)),
+            4 => Ok(self.load::<i32>()? as SyxInt),
+            _ => {
+                let value = self.load::<i64>()?;
+                self.narrow(value, "int")
+            }
+        }
+    }
+
+    // the number of elements that follow, a negative one an error (none when
+    // strict)
+    pub(crate) fn load_count(&mut self, name: &str) -> Result<usize> {
+        let count = self.load_int()?;
+        if count < 0 {
+            let error = self.verification_error(format!("negative {} count: {}", name, count));
+            self.diagnose(error)?;
+        }
+        Ok(count.max(0) as usize)
+    }
+
+    // room to set aside for `count` elements: each takes at least a byte, so
+    // no more than the input has left
+    pub(crate) fn reservation(&self, count: usize) -> usize {
+        count.min(self.input.remaining().unwrap_or(0))
+    }
+
+    pub(crate) fn load_size(&mut self) -> Result<usize> {
+        match self.sizes.size {
+            4 => Ok(self.load::<u32>()? as usize),
+            _ => {
+                let value = self.load::<u64>()?;
+                self.narrow(value, "size_t")
+            }
+        }
+    }
+
+    // an integer of the chunk's width, widened
+    fn load_wide_integer(&mut self) -> Result<i64> {
+        match self.sizes.integer {
+            4 => Ok(i64::from(self.load::<i32>()?)),
+            _ => self.load::<i64>(),
+        }
+    }
+
+    pub(crate) fn load_integer(&mut self) -> Result<SyxInteger> {
+        let value = self.load_wide_integer()?;
+        self.narrow(value, "lua_Integer")
+    }
+
+    pub(crate) fn load_number(&mut self) -> Result<SyxNumber> {
+        match self.sizes.number {
+            4 => Ok(self.load::<f32>()? as SyxNumber),
+            _ => Ok(self.load::<f64>()? as SyxNumber),
+        }
     }
 
     fn load_string(&mut self) -> Result<SyxString> {
         let mut size: usize = self.load::<u8>()? as usize;
         if size == 0xFF {
-            size = self.load::<usize>()?;
+            size = self.load_size()?;
         }
         if size == 0 {
             // Turns out it can happen with stripped debug info. We'll just
             // return an empty string as it's not likely to be empty if it does
             // exist - wait, what happens in PUC-Rio Lua?..
-            Ok(vec![])
+            Ok(self.intern(&[]))
+        } else if size - 1 > SYX_MAXSHORTLEN {
+            // long strings are not interned
+            self.read_string(size - 1)
         } else {
-            // So, Lua has a concept of "short" and "long" strings. This can be
-            // optimized later in the future, as well as the SyxString type, to
-            // include a hash field.
-            size -= 1;
-            if size < limits::SYX_MAXSHORTLEN {
-                self.load_range(size)
-            } else {
-                self.load_range(size)
-            }
+            let bytes = self.load_range(size - 1)?;
+            Ok(self.intern(&bytes))
         }
     }
 
+    // short strings are shared with every other equal string in the table
+    pub(crate) fn intern(&mut self, bytes: &[u8]) -> SyxString {
+        self.strings.intern(bytes)
+    }
+
     fn load_constants(&mut self, proto: &mut Proto) -> Result<()> {
-        let constant_count: isize = self.load::<i32>()? as isize;
+        self.section = Section::Constants;
+        let constant_count = self.load_count("constant")?;
         proto.constants.clear();
+        proto.constants.reserve(self.reservation(constant_count));
         for _ in 0..constant_count {
             // get type from byte
-            proto.constants.push(match SyxType::try_from(self.load::<u8>()?)? {
-                SyxType::TNIL => SyxValue::Nil,
-                SyxType::TBOOLEAN => SyxValue::Bool(self.load::<u8>()? == 1),
+            let t = self.load::<u8>()?;
+            let bad_type = || UndumpReason::ConstantType(t);
+            proto.constants.push(match SyxType::try_from(t).map_err(|_| bad_type()) {
+                Ok(SyxType::TNIL) => SyxValue::Nil,
+                Ok(SyxType::TBOOLEAN) => SyxValue::Bool(self.load::<u8>()? == 1),
                 // these lines represent everything wrong with the world
                 // they take up more than 80 characters
-                SyxType::TNUMFLT => SyxValue::Number(self.load::<SyxNumber>()?),
-                SyxType::TNUMINT => SyxValue::Integer(self.load::<SyxInteger>()?),
-                | SyxType::TSHRSTR
-                | SyxType::TLNGSTR => SyxValue::String(self.load_string()?),
-                x => {
-                    return Err(ErrorKind::InvalidConstantType(x).into());
+                Ok(SyxType::TNUMFLT) => SyxValue::Number(self.load_number()?),
+                Ok(SyxType::TNUMINT) => integer_value(self.load_integer()?),
+                | Ok(SyxType::TSHRSTR)
+                | Ok(SyxType::TLNGSTR) => SyxValue::String(self.load_string()?),
+                _ => {
+                    // loaded as nil when strict
+                    let error = self.undump_error(bad_type());
+                    self.diagnose(error)?;
+                    SyxValue::Nil
                 }
             });
         }
@@ -167,34 +510,38 @@ impl LoadState {
     }
 
     fn load_code(&mut self, proto: &mut Proto) -> Result<()> {
-        let count = self.load::<SyxInt>()?;
+        self.section = Section::Code;
+        let count = self.load_count("instruction")?;
         proto.instructions.clear();
-        proto.instructions.reserve(count as usize);
-        for _ in 0..(count) {
+        proto.instructions.reserve(self.reservation(count));
+        for _ in 0..count {
             proto.instructions.push(self.load::<Word>()?.try_into()?);
         }
         Ok(())
     }
 
     fn load_protos(&mut self, proto: &mut Proto) -> Result<()> {
-        let count = self.load::<SyxInt>()?;
+        self.section = Section::Function;
+        let count = self.load_count("function")?;
         proto.protos.clear();
-        proto.protos.reserve(count as usize);
-        for _ in 0..(count) {
+        proto.protos.reserve(self.reservation(count));
+        for _ in 0..count {
             let mut new_proto = Proto::new();
-            self.load_function(&mut new_proto, vec![])?;
-            proto.protos.push(new_proto);
+            // nested functions with no source of their own inherit the parent
+            self.load_function(&mut new_proto, &proto.source)?;
+            proto.protos.push(Arc::new(new_proto));
         }
         Ok(())
     }
 
     fn load_upvalues(&mut self, proto: &mut Proto) -> Result<()> {
-        let upvalues_count = self.load::<SyxInt>()?;
+        self.section = Section::Upvalues;
+        let upvalues_count = self.load_count("upvalue")?;
         proto.upvalues.clear();
-        proto.upvalues.reserve(upvalues_count as usize);
+        proto.upvalues.reserve(self.reservation(upvalues_count));
         for _ in 0..upvalues_count {
             proto.upvalues.push(Upvalue {
-                name: vec![],
+                name: self.intern(&[]),
                 instack: self.load::<u8>()?,
                 idx: self.load::<u8>()?,
             })
@@ -203,49 +550,60 @@ impl LoadState {
     }
 
     fn load_debug(&mut self, proto: &mut Proto) -> Result<()> {
-        let lines = self.load::<SyxInt>()? as usize;
+        self.section = Section::Debug;
+        let lines = self.load_count("line")?;
         proto.lineinfo.clear();
-        proto.lineinfo.reserve(lines);
+        proto.lineinfo.reserve(self.reservation(lines));
         for _ in 0..lines {
-            proto.lineinfo.push(self.load::<SyxInt>()?);
+            proto.lineinfo.push(self.load_int()?);
         }
-        let size = self.load::<SyxInt>()? as usize;
+        let size = self.load_count("local")?;
         proto.locvars.clear();
-        proto.locvars.reserve(size);
+        proto.locvars.reserve(self.reservation(size));
         // load locvars
         for _ in 0..size {
             proto.locvars.push(LocVar {
                 varname: self.load_string()?,
-                startpc: self.load::<SyxInt>()?,
-                endpc: self.load::<SyxInt>()?,
+                startpc: self.load_int()?,
+                endpc: self.load_int()?,
             });
         }
         // end trash
-        let upvalue_count = self.load::<SyxInt>()? as usize;
+        let upvalue_count = self.load_count("upvalue name")?;
         for i in 0..upvalue_count {
             match proto.upvalues.get_mut(i) {
                 Some(value) => value.name = self.load_string()?,
-                None => return Err(ErrorKind::InvalidUpvalueIndex(i).into()),
+                None => {
+                    let error = self.undump_error(UndumpReason::UpvalueIndex(i));
+                    self.diagnose(error)?;
+                }
+            }
+        }
+        proto.columninfo.clear();
+        if self.columns {
+            let columns = self.load_count("column")?;
+            proto.columninfo.reserve(self.reservation(columns));
+            for _ in 0..columns {
+                proto.columninfo.push(self.load_int()?);
             }
         }
         Ok(())
     }
 
-    fn load_function(&mut self, proto: &mut Proto, source: SyxString)
+    fn load_function(&mut self, proto: &mut Proto, source: &str)
         -> Result<()>
     {
+        self.section = Section::Function;
         let loaded_source = self.load_string()?;
-        proto.source = String::from_utf8({
-            if !loaded_source.is_empty() {
-                loaded_source
-            } else {
-                source
-            }
-        }).chain_err(|| ErrorKind::InvalidSourceName)?;
-        proto.linedefined = self.load::<SyxInt>()?;
-        proto.lastlinedefined = self.load::<SyxInt>()?;
+        proto.source = if !loaded_source.is_empty() {
+            self.source_name(&loaded_source)?
+        } else {
+            source.to_owned()
+        };
+        proto.linedefined = self.load_int()?;
+        proto.lastlinedefined = self.load_int()?;
         proto.numparams = self.load::<u8>()?;
-        proto.is_vararg = self.load::<u8>()? != 0;
+        proto.is_vararg = self.load::<u8>()?;
         proto.maxstacksize = self.load::<u8>()?;
         self.load_code(proto)?;
         self.load_constants(proto)?;
@@ -255,6 +613,17 @@ impl LoadState {
         Ok(())
     }
 
+    pub(crate) fn source_name(&mut self, source: &[u8]) -> Result<String> {
+        match String::from_utf8(source.to_vec()) {
+            Ok(name) => Ok(name),
+            Err(_) => {
+                let error = self.undump_error(UndumpReason::SourceName);
+                self.diagnose(error)?;
+                Ok(String::from_utf8_lossy(source).into_owned())
+            }
+        }
+    }
+
     fn check_size(&mut self, size: (usize, &'static str)) -> Result<()> {
         if let Ok(bytecode_size) = self.load::<u8>() {
             self.assert_verification(
@@ -266,7 +635,14 @@ impl LoadState {
         }
     }
 
-    fn check_literal(
+    pub(crate) fn check_width(&mut self, name: &str, allowed: &[usize]) -> Result<usize> {
+        let width = self.load::<u8>()? as usize;
+        self.assert_verification(allowed.contains(&width),
+                                 format!("unsupported size: {}", name))?;
+        Ok(width)
+    }
+
+    pub(crate) fn check_literal(
         &mut self,
         value_impl: impl Into<Vec<u8>>,
         err: impl ::std::fmt::Display,
@@ -281,32 +657,440 @@ impl LoadState {
     }
 
     fn check_header(&mut self) -> Result<()> {
-        self.check_literal(SYX_HEADER, "header")?;
-        let bt = self.load::<u8>()?;
-        self.assert_verification(bt == SYX_VERSION, "version mismatch")?;
         let bt = self.load::<u8>()?;
+        self.columns = bt & SYX_FORMAT_COLUMNS != 0;
+        let bt = bt & !SYX_FORMAT_COLUMNS;
         self.assert_verification(bt == SYX_FORMAT, "format mismatch")?;
         self.check_literal(SYX_DATA, "load order verification")?;
-        self.check_size(expand!(i32))?;
-        self.check_size(expand!(usize))?;
+        self.sizes.int = self.check_width("int", &[2, 4, 8])?;
+        self.sizes.size = self.check_width("size_t", &[4, 8])?;
         self.check_size(expand!(Word))?;
-        self.check_size(expand!(SyxInteger))?;
-        self.check_size(expand!(SyxNumber))?;
-        let int: SyxInteger = self.load::<SyxInteger>()?;
-        self.assert_verification(int == SYX_INT, "endianness mismatch")?;
-        let float: SyxNumber = self.load::<SyxNumber>()?;
-        self.assert_verification(float == SYX_NUM, "float format mismatch")?;
+        self.sizes.integer = self.check_width("lua_Integer", &[4, 8])?;
+        self.sizes.number = self.check_width("lua_Number", &[4, 8])?;
+        self.check_numbers()
+    }
+
+    pub(crate) fn check_numbers(&mut self) -> Result<()> {
+        // SYX_INT doubles as the byte order marker: if it only matches once
+        // reversed, everything past this point has to be byte-swapped
+        let int = self.load_wide_integer()?;
+        let swapped = match self.sizes.integer {
+            4 => i64::from((int as i32).swap_bytes()),
+            _ => int.swap_bytes(),
+        };
+        if int != SYX_INT && swapped == SYX_INT {
+            self.swap = true;
+        } else {
+            self.assert_verification(int == SYX_INT, "endianness mismatch")?;
+        }
+        let float: SyxNumber = self.load_number()?;
+        self.assert_verification(float == SYX_NUM as SyxNumber, "float format mismatch")?;
         Ok(())
     }
 
-    fn load_chunk(&mut self, _lstate: state::SyxState) -> Result<Proto> {
-        self.state = Some(state::SyxState {});
+    fn load_chunk(&mut self) -> Result<Proto> {
         // ::TODO:: ::XXX:: here is where i left off
         // cl->p
+        self.check_literal(SYX_HEADER, "header")?;
+        let version = self.load::<u8>()?;
+        match version {
+            format::LUA51_VERSION => return format::lua51::load_chunk(self),
+            format::LUA52_VERSION => return format::lua52::load_chunk(self),
+            format::LUA54_VERSION => return format::lua54::load_chunk(self),
+            _ => (),
+        }
+        self.assert_verification(version == SYX_VERSION, "version mismatch")?;
         self.check_header()?;
         let mut proto = Proto::new();
         let _upvals = self.load::<u8>()?;
-        self.load_function(&mut proto, vec![])?;
+        self.load_function(&mut proto, "")?;
         Ok(proto)
     }
 }
+
+// Loads chunks as LoadState does, but goes on past every problem it can and
+// returns all of them: mismatched sizes and literals in the header, bad
+// constant tags (loaded as nil), upvalue names with no upvalue, source names
+// that are not UTF-8 and bytes left over. A problem it cannot go on past, such
+// as a truncated section, is the last one reported.
+pub struct StrictLoader;
+
+impl StrictLoader {
+    pub fn load_u8(&self, buffer: Vec<u8>, name: impl Into<String>)
+        -> ::std::result::Result<Proto, Vec<UndumpError>>
+    {
+        self.load_reader(Cursor::new(buffer), name)
+    }
+
+    pub fn load_reader(&self, mut input: impl ChunkReader, name: impl Into<String>)
+        -> ::std::result::Result<Proto, Vec<UndumpError>>
+    {
+        let mut strings = StringTable::new();
+        let mut state = LoadState::new(&mut input, name.into(), &mut strings);
+        state.diagnostics = Some(Vec::new());
+        let result = state.load_all();
+        let mut diagnostics = state.diagnostics.take().unwrap_or_default();
+        match result {
+            Ok(proto) if diagnostics.is_empty() => return Ok(proto),
+            Err(Error(ErrorKind::Undump(error), _)) => diagnostics.push(error),
+            _ => {}
+        }
+        Err(diagnostics)
+    }
+}
+
+impl SyxState {
+    // Load a binary chunk, sharing its short strings with this state
+    pub fn undump(&mut self, buffer: Vec<u8>, name: impl Into<String>) -> Result<Proto> {
+        let size = self.strings.size();
+        let result = LoadState::load_from(&mut Cursor::new(buffer), name, &mut self.strings);
+        let grown = self.strings.size() - size;
+        self.heap.allocate_strings(grown);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO_WORLD: &[u8] = include_bytes!("../luac.out");
+
+    fn undump_error(result: Result<Proto>) -> UndumpError {
+        match result {
+            Err(Error(ErrorKind::Undump(error), _)) => error,
+            _ => panic!("expected an undump error"),
+        }
+    }
+
+    struct FailingReader;
+
+    impl Read for FailingReader {
+        fn read(&mut self, _buf: &mut [u8]) -> ::std::io::Result<usize> {
+            Err(::std::io::Error::other("failed"))
+        }
+    }
+
+    // Writes values the way a host with the given byte order and type
+    // widths would have dumped them
+    struct ChunkBuilder {
+        chunk: Vec<u8>,
+        swap: bool,
+    }
+
+    impl ChunkBuilder {
+        fn bytes(&mut self, bytes: &[u8]) {
+            self.chunk.extend_from_slice(bytes);
+        }
+
+        fn value(&mut self, value: u64, width: usize) {
+            let mut bytes = match width {
+                2 => (value as u16).to_ne_bytes().to_vec(),
+                4 => (value as u32).to_ne_bytes().to_vec(),
+                _ => value.to_ne_bytes().to_vec(),
+            };
+            if self.swap {
+                bytes.reverse();
+            }
+            self.bytes(&bytes);
+        }
+
+        fn number(&mut self, value: f64, width: usize) {
+            match width {
+                4 => self.value(u64::from((value as f32).to_bits()), 4),
+                _ => self.value(value.to_bits(), 8),
+            }
+        }
+    }
+
+    fn build_chunk(swap: bool, int: usize, size: usize, integer: usize,
+                   number: usize) -> Vec<u8> {
+        // Minimal chunk: `return` with an integer, a float and a long string
+        // constant, the latter to exercise size_t
+        let mut b = ChunkBuilder { chunk: Vec::new(), swap };
+        b.bytes(SYX_HEADER);
+        b.bytes(&[SYX_VERSION, SYX_FORMAT]);
+        b.bytes(SYX_DATA);
+        b.bytes(&[int as u8, size as u8, 4, integer as u8, number as u8]);
+        b.value(SYX_INT as u64, integer);
+        b.number(SYX_NUM, number);
+        b.bytes(&[1, 0]); // upvalues, source
+        b.value(0, int);
+        b.value(0, int);
+        b.bytes(&[0, 2, 2]);
+        b.value(1, int);
+        b.value(0x0080_0026, 4);
+        b.value(3, int);
+        b.bytes(&[::object::SYX_TNUMINT]);
+        b.value(42, integer);
+        b.bytes(&[::object::SYX_TNUMFLT]);
+        b.number(1.5, number);
+        b.bytes(&[::object::SYX_TLNGSTR, 0xFF]);
+        b.value(301, size);
+        b.bytes(&[b'x'; 300]);
+        b.value(1, int);
+        b.bytes(&[1, 0]);
+        for _ in 0..4 {
+            // protos, lineinfo, locvars, upvalue names
+            b.value(0, int);
+        }
+        b.chunk
+    }
+
+    fn check_chunk(proto: Proto) {
+        assert_eq!(proto.maxstacksize, 2);
+        match proto.constants[0] {
+            SyxValue::Integer(n) => assert_eq!(n, 42),
+            // loaded as a float without integers
+            SyxValue::Number(n) if cfg!(feature = "float_only") => assert_eq!(n, 42.0),
+            _ => panic!("expected integer constant"),
+        }
+        match proto.constants[1] {
+            SyxValue::Number(n) => assert_eq!(n, 1.5),
+            _ => panic!("expected float constant"),
+        }
+        match proto.constants[2] {
+            SyxValue::String(ref s) => assert_eq!(s.len(), 300),
+            _ => panic!("expected string constant"),
+        }
+        assert_eq!(proto.upvalues.len(), 1);
+        assert_eq!(proto.instructions.len(), 1);
+        assert_eq!(Word::from(&proto.instructions[0]), 0x0080_0026);
+    }
+
+    #[test]
+    fn test_foreign_endianness() {
+        let chunk = build_chunk(true, 4, 8, 8, 8);
+        check_chunk(LoadState::from_u8(chunk, "foreign").unwrap());
+    }
+
+    #[test]
+    fn test_foreign_sizes() {
+        for &(int, size, integer, number) in &[(4, 4, 4, 4), (8, 8, 8, 8),
+                                               (2, 4, 8, 4), (4, 4, 4, 8)] {
+            for &swap in &[false, true] {
+                let chunk = build_chunk(swap, int, size, integer, number);
+                check_chunk(LoadState::from_u8(chunk, "sizes").unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_narrowing_overflow() {
+        let mut chunk = build_chunk(false, 8, 8, 8, 8);
+        // linedefined is the first int after the header, upvalue count and
+        // source; make it too large for SyxInt
+        let offset = SYX_HEADER.len() + 2 + SYX_DATA.len() + 5 + 8 + 8 + 2;
+        chunk[offset..offset + 8].copy_from_slice(&(1i64 << 40).to_ne_bytes());
+        let error = undump_error(LoadState::from_u8(chunk, "overflow"));
+        assert_eq!((error.offset, error.section), (offset, Section::Function));
+    }
+
+    #[test]
+    fn test_integer_width() {
+        // the integer constant 42 made one that only fits in 64 bits
+        let mut chunk = build_chunk(false, 4, 8, 8, 8);
+        let mut constant = vec![::object::SYX_TNUMINT];
+        constant.extend_from_slice(&42i64.to_ne_bytes());
+        let offset = chunk.windows(9).position(|w| w == &constant[..]).unwrap() + 1;
+        chunk[offset..offset + 8].copy_from_slice(&(1i64 << 40).to_ne_bytes());
+        let result = LoadState::from_u8(chunk, "wide");
+        #[cfg(not(feature = "int32"))]
+        assert!(result.unwrap().constants[0] == SyxValue::Integer(1 << 40));
+        #[cfg(feature = "int32")]
+        assert_eq!(undump_error(result).offset, offset);
+    }
+
+    #[test]
+    fn test_from_stream() {
+        let input = ::std::io::Cursor::new(HELLO_WORLD);
+        let proto = LoadState::from_stream(input, "luac.out").unwrap();
+        assert_eq!(proto.source, "=stdin");
+        assert_eq!(proto.instructions.len(), 4);
+        assert_eq!(proto.constants.len(), 2);
+    }
+
+    #[test]
+    fn test_chunk_readers() {
+        let proto = LoadState::from_reader(HELLO_WORLD, "luac.out").unwrap();
+        assert_eq!(proto.instructions.len(), 4);
+        let mut cursor = Cursor::new(HELLO_WORLD.to_vec());
+        LoadState::from_reader(&mut cursor, "luac.out").unwrap();
+        assert_eq!(cursor.position() as usize, HELLO_WORLD.len());
+        let error = undump_error(LoadState::from_reader(&HELLO_WORLD[..HELLO_WORLD.len() - 1],
+                                                        "short"));
+        assert_eq!(error.name, "short");
+        assert_eq!(error.section, Section::Debug);
+        assert_eq!(error.reason, UndumpReason::Truncated(4));
+        assert_eq!(error.offset, HELLO_WORLD.len() - 4);
+    }
+
+    #[test]
+    fn test_shared_strings() {
+        use super::super::compiler::parse;
+        use super::super::dump::DumpState;
+
+        let long = "y".repeat(64);
+        let source = format!("return '{}', 'short'", long);
+        let chunk = DumpState::to_u8(&parse(source.as_bytes(), "=test").unwrap()).unwrap();
+        let buffer: SharedBuffer = Arc::new(chunk);
+        let proto = LoadState::from_shared(buffer.clone(), "shared").unwrap();
+        // held by the long constant only
+        assert_eq!(Arc::strong_count(&buffer), 2);
+        match proto.constants[0] {
+            SyxValue::String(ref s) => assert_eq!(*s, long.as_bytes()),
+            _ => panic!("expected a string constant"),
+        }
+        drop(proto);
+        assert_eq!(Arc::strong_count(&buffer), 1);
+    }
+
+    #[test]
+    fn test_strict() {
+        let header = SYX_HEADER.len() + 2 + SYX_DATA.len() + 5 + 8 + 8;
+        let chunk = build_chunk(false, 8, 8, 8, 8);
+        check_chunk(LoadState::strict().load_u8(chunk.clone(), "strict").unwrap());
+
+        let mut broken = chunk.clone();
+        let word = SYX_HEADER.len() + 2 + SYX_DATA.len() + 2;
+        broken[word] = 5;
+        broken[header - 1] ^= 0xFF;
+        broken.push(0);
+        let diagnostics = LoadState::strict().load_u8(broken.clone(), "strict").err().unwrap();
+        let found: Vec<_> = diagnostics.iter()
+            .map(|error| (error.offset, error.section, error.reason.to_string()))
+            .collect();
+        assert_eq!(found, vec![
+            (word, Section::Header, "size mismatch: Word".to_owned()),
+            (header - 8, Section::Header, "float format mismatch".to_owned()),
+            (chunk.len(), Section::Trailer, "bytes left over from buffer".to_owned()),
+        ]);
+        // only the first without it
+        let error = undump_error(LoadState::from_u8(broken.clone(), "strict"));
+        assert_eq!(error, diagnostics[0]);
+
+        // nothing can be read past the end
+        broken.truncate(header + 10);
+        let diagnostics = LoadState::strict().load_u8(broken, "strict").err().unwrap();
+        assert_eq!(diagnostics.len(), 3);
+        assert_eq!(diagnostics[2].reason, UndumpReason::Truncated(8));
+    }
+
+    #[test]
+    fn test_corrupted_count() {
+        let header = SYX_HEADER.len() + 2 + SYX_DATA.len() + 5 + 8 + 8;
+        let code = header + 2 + 8 + 8 + 3;
+        let chunk = build_chunk(false, 8, 8, 8, 8);
+        let mut negative = chunk.clone();
+        negative[code..code + 8].copy_from_slice(&[0xFF; 8]);
+        let error = undump_error(LoadState::from_u8(negative.clone(), "corrupt"));
+        assert_eq!((error.offset, error.section), (code, Section::Code));
+        assert_eq!(error.reason.to_string(), "negative instruction count: -1");
+        // no instructions when strict, leaving the rest of the chunk unreadable
+        let diagnostics = LoadState::strict().load_u8(negative, "corrupt").err().unwrap();
+        assert_eq!(diagnostics[0], error);
+
+        // far more than the chunk could hold fails reading it, not reserving
+        let mut huge = chunk.clone();
+        huge[code..code + 8].copy_from_slice(&i64::from(i32::MAX).to_ne_bytes());
+        assert_eq!(undump_error(LoadState::from_u8(huge.clone(), "corrupt")).section,
+                   Section::Code);
+        let input = ::std::io::Cursor::new(huge);
+        assert!(LoadState::from_stream(input, "corrupt").is_err());
+    }
+
+    #[test]
+    fn test_from_stream_error() {
+        let result = LoadState::from_stream(FailingReader, "failing");
+        // the reader's error is kept as the cause
+        assert_eq!(result.as_ref().err().unwrap().iter().last().unwrap().to_string(), "failed");
+        let error = undump_error(result);
+        assert_eq!(error.name, "failing");
+        assert_eq!((error.offset, error.section), (0, Section::Header));
+        assert_eq!(error.reason, UndumpReason::Unreadable);
+
+        let result = LoadState::from_read(FailingReader, "@failing.out");
+        assert_eq!(result.as_ref().err().unwrap().iter().last().unwrap().to_string(), "failed");
+        let error = undump_error(result);
+        assert_eq!(error.name, "failing.out");
+        assert_eq!((error.offset, error.section), (0, Section::Header));
+        assert_eq!(error.reason, UndumpReason::Unreadable);
+    }
+
+    #[test]
+    fn test_error_locations() {
+        let header = SYX_HEADER.len() + 2 + SYX_DATA.len() + 5 + 8 + 8;
+        let chunk = build_chunk(false, 8, 8, 8, 8);
+
+        let mut bad_version = chunk.clone();
+        bad_version[SYX_HEADER.len()] = 0x99;
+        let error = undump_error(LoadState::from_u8(bad_version, "version"));
+        assert_eq!((error.offset, error.section), (SYX_HEADER.len(), Section::Header));
+        assert_eq!(error.to_string(),
+                   "error loading version: version mismatch (byte 4, header)");
+        let error = undump_error(LoadState::from_u8(chunk[..4].to_vec(), "@chunk.luac"));
+        assert_eq!(error.name, "chunk.luac");
+        let name = String::from_utf8(chunk[..4].to_vec()).unwrap();
+        let error = undump_error(LoadState::from_u8(chunk[..4].to_vec(), name));
+        assert_eq!(error.name, "binary string");
+
+        // type of the first constant, after the function header and code
+        let constant = header + 2 + 8 + 8 + 3 + 8 + 4 + 8;
+        let mut bad_constant = chunk.clone();
+        bad_constant[constant] = 5;
+        let error = undump_error(LoadState::from_u8(bad_constant, "constant"));
+        assert_eq!((error.offset, error.section), (constant, Section::Constants));
+        assert_eq!(error.reason, UndumpReason::ConstantType(5));
+
+        let mut bad_opcode = chunk.clone();
+        bad_opcode[header + 2 + 8 + 8 + 3 + 8] = 0x3F;
+        let error = undump_error(LoadState::from_u8(bad_opcode, "opcode"));
+        assert_eq!((error.offset, error.section), (header + 29, Section::Code));
+
+        let mut trailing = chunk.clone();
+        trailing.push(0);
+        let error = undump_error(LoadState::from_u8(trailing, "trailing"));
+        assert_eq!((error.offset, error.section), (chunk.len(), Section::Trailer));
+        assert_eq!(error.reason, UndumpReason::TrailingBytes);
+    }
+
+    #[test]
+    fn test_interned_constants() {
+        let mut state = SyxState::new();
+        let first = state.undump(HELLO_WORLD.to_vec(), "luac.out").unwrap();
+        let second = state.undump(HELLO_WORLD.to_vec(), "luac.out").unwrap();
+        match (&first.constants[0], &second.constants[0]) {
+            (SyxValue::String(a), SyxValue::String(b)) => {
+                assert_eq!(*a, b"print");
+                assert!(a.ptr_eq(b));
+            },
+            _ => panic!("expected string constants"),
+        }
+    }
+
+    #[test]
+    fn test_shared_proto() {
+        use std::thread;
+        use super::super::compiler::parse;
+        use super::super::dump::DumpState;
+
+        let source = parse(b"local s = 'shared' return s, #s", "=test").unwrap();
+        let chunk = DumpState::to_u8(&source).unwrap();
+        // loaded once, run by several states at the same time
+        let proto = Arc::new(LoadState::from_u8(chunk, "shared").unwrap());
+        let workers: Vec<_> = (0..4).map(|_| {
+            let proto = proto.clone();
+            thread::spawn(move || SyxState::new().call(proto, Vec::new()).unwrap().into_vec())
+        }).collect();
+        for worker in workers {
+            match (&worker.join().unwrap()[..], &proto.constants[0]) {
+                ([SyxValue::String(result), length], SyxValue::String(constant))
+                    if *length == SyxValue::Integer(6) => {
+                    assert!(result.ptr_eq(constant))
+                }
+                (results, _) => panic!("unexpected {:?}", results),
+            }
+        }
+        assert_eq!(Arc::strong_count(&proto), 1);
+    }
+}
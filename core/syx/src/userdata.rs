@@ -0,0 +1,206 @@
+// Userdata
+//
+// A userdata boxes a value of any Rust type for scripts to hold on to. It is
+// a collectable object, and the boxed value is dropped when it is collected.
+// Every userdata of one type shares the metatable registered for that type
+// with `register_userdata`, which is also where its methods go: the
+// metatable is its own __index, so `ud:method()` finds them, and methods
+// named after an event (`__add`, `__tostring`...) are its metamethods. A
+// `__gc` method has to be added before the userdata it finalizes are
+// created.
+//
+// The boxed value is reached with `borrow` and `borrow_mut`, which check
+// its type and, as a RefCell does, that it is not mutably borrowed twice.
+//
+// Consult the versioned lapi.c (lua_newuserdata) and lauxlib.c
+// (luaL_newmetatable, luaL_checkudata) for more information.
+
+use std::any::{Any, TypeId};
+use std::cell::{Ref, RefCell, RefMut};
+
+use super::errors::*;
+use super::native::Args;
+use super::object::{MultiValue, SyxValue, TableRef, UserDataRef};
+use super::state::SyxState;
+use super::stdlib::set_field;
+
+pub struct AnyUserData {
+    type_id: TypeId,
+    value: RefCell<Box<dyn Any + Send>>,
+}
+
+impl AnyUserData {
+    pub fn is<T: Any>(&self) -> bool {
+        self.type_id == TypeId::of::<T>()
+    }
+
+    pub fn borrow<T: Any>(&self) -> Result<Ref<'_, T>> {
+        self.check_type::<T>()?;
+        match self.value.try_borrow() {
+            Ok(value) => Ok(Ref::map(value, |value| value.downcast_ref().expect("type checked"))),
+            Err(_) => Err(ErrorKind::UserDataBorrowError.into()),
+        }
+    }
+
+    pub fn borrow_mut<T: Any>(&self) -> Result<RefMut<'_, T>> {
+        self.check_type::<T>()?;
+        match self.value.try_borrow_mut() {
+            Ok(value) => {
+                Ok(RefMut::map(value, |value| value.downcast_mut().expect("type checked")))
+            }
+            Err(_) => Err(ErrorKind::UserDataBorrowMutError.into()),
+        }
+    }
+
+    fn check_type<T: Any>(&self) -> Result<()> {
+        if self.is::<T>() {
+            Ok(())
+        } else {
+            Err(ErrorKind::UserDataTypeMismatch(::std::any::type_name::<T>()).into())
+        }
+    }
+}
+
+impl SyxState {
+    // Userdata holding `value`, with the metatable registered for its type
+    pub fn create_userdata<T: Any + Send>(&mut self, value: T) -> SyxValue {
+        let userdata = AnyUserData {
+            type_id: TypeId::of::<T>(),
+            value: RefCell::new(Box::new(value)),
+        };
+        let userdata = SyxValue::UserData(self.alloc_userdata(userdata));
+        self.check_finalizer(&userdata);
+        userdata
+    }
+
+    pub fn userdata(&self, userdata: UserDataRef) -> &AnyUserData {
+        self.heap.userdata.get(userdata.0)
+    }
+
+    // Metatable shared by every userdata of type T, created on first use
+    // with `name` as its __name, which error messages and `tostring` show
+    pub fn register_userdata<T: Any>(&mut self, name: &str) -> TableRef {
+        if let Some(&metatable) = self.userdata_metatables.get(&TypeId::of::<T>()) {
+            return metatable;
+        }
+        let metatable = self.new_table(0, 2);
+        set_field(self, metatable, "__index", SyxValue::Table(metatable));
+        let name = SyxValue::String(self.intern(name.as_bytes()));
+        set_field(self, metatable, "__name", name);
+        self.userdata_metatables.insert(TypeId::of::<T>(), metatable);
+        metatable
+    }
+
+    // Add method `name` to userdata of type T, which must be registered
+    pub fn add_method<T: Any>(&mut self, name: &str,
+                              method: impl Fn(&mut SyxState, Args) -> Result<MultiValue>
+                                      + Send + Sync + 'static)
+    {
+        let metatable = *self.userdata_metatables.get(&TypeId::of::<T>())
+            .expect("methods added to an unregistered userdata type");
+        let method = self.create_function(name, method);
+        set_field(self, metatable, name, method);
+    }
+
+    pub(crate) fn userdata_metatable(&self, userdata: UserDataRef) -> Option<TableRef> {
+        let type_id = self.userdata(userdata).type_id;
+        self.userdata_metatables.get(&type_id).cloned()
+    }
+
+    // __name of the type of a userdata, if it was registered
+    pub(crate) fn userdata_name(&self, userdata: UserDataRef) -> Option<String> {
+        self.type_name(self.userdata(userdata).type_id)
+    }
+
+    fn type_name(&self, type_id: TypeId) -> Option<String> {
+        let metatable = *self.userdata_metatables.get(&type_id)?;
+        match self.table(metatable).get(&SyxValue::from("__name")) {
+            SyxValue::String(name) => Some(name.to_string()),
+            _ => None,
+        }
+    }
+}
+
+impl Args {
+    // argument `n` as a userdata of type T, reported by its registered name
+    // otherwise
+    pub fn check_userdata<T: Any>(&self, state: &SyxState, n: usize) -> Result<UserDataRef> {
+        match self.get(n) {
+            Some(&SyxValue::UserData(userdata)) if state.userdata(userdata).is::<T>() => {
+                Ok(userdata)
+            }
+            _ => {
+                let expected = state.type_name(TypeId::of::<T>())
+                    .unwrap_or_else(|| "userdata".to_owned());
+                self.type_error(n, &expected)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::object::SyxInteger;
+    use super::super::stdlib;
+    use super::super::testing::run_in;
+
+    struct Counter {
+        count: SyxInteger,
+    }
+
+    fn counter_state() -> SyxState {
+        let mut state = SyxState::new();
+        stdlib::open_libs(&mut state);
+        state.register_userdata::<Counter>("Counter");
+        state.add_method::<Counter>("increment", |state, args| {
+            let counter = args.check_userdata::<Counter>(state, 1)?;
+            let by = args.opt_integer(2, 1)?;
+            let mut counter = state.userdata(counter).borrow_mut::<Counter>()?;
+            counter.count += by;
+            Ok(vec![SyxValue::Integer(counter.count)].into())
+        });
+        state.register("counter", |state, _| {
+            Ok(vec![state.create_userdata(Counter { count: 0 })].into())
+        });
+        state
+    }
+
+    #[test]
+    fn test_methods() {
+        let mut state = counter_state();
+        let results = run_in(&mut state, "local c = counter() c:increment() \
+                                       return c:increment(5), type(c), \
+                                       tostring(c):sub(1, 9), pcall(c.increment, 1)").unwrap();
+        assert!(results[..4] == [SyxValue::Integer(6), SyxValue::from("userdata"),
+                                 SyxValue::from("Counter: "), SyxValue::Bool(false)]);
+        assert!(results[4] == SyxValue::from(
+            "bad argument #1 to 'increment' (Counter expected, got number)"));
+    }
+
+    #[test]
+    fn test_borrow() {
+        let mut state = SyxState::new();
+        let value = match state.create_userdata(Counter { count: 1 }) {
+            SyxValue::UserData(userdata) => userdata,
+            _ => unreachable!(),
+        };
+        let userdata = state.userdata(value);
+        assert!(userdata.is::<Counter>() && !userdata.is::<String>());
+        {
+            let first = userdata.borrow::<Counter>().unwrap();
+            let second = userdata.borrow::<Counter>().unwrap();
+            assert_eq!(first.count + second.count, 2);
+            match *userdata.borrow_mut::<Counter>().err().unwrap().kind() {
+                ErrorKind::UserDataBorrowMutError => {}
+                ref error => panic!("unexpected error: {}", error),
+            }
+        }
+        userdata.borrow_mut::<Counter>().unwrap().count = 3;
+        assert_eq!(userdata.borrow::<Counter>().unwrap().count, 3);
+        match *userdata.borrow::<String>().err().unwrap().kind() {
+            ErrorKind::UserDataTypeMismatch(_) => {}
+            ref error => panic!("unexpected error: {}", error),
+        }
+    }
+}
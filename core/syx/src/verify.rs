@@ -0,0 +1,329 @@
+// Bytecode verification
+//
+// Binary chunks can come from anywhere, and the interpreter trusts the code
+// it runs: operands index the stack, the constants and the upvalues without
+// checking them. `Proto::verify` checks every function of a chunk before it
+// runs, so that registers stay below `maxstacksize`, constants, upvalues and
+// nested functions exist, jumps and skips land on an instruction, and
+// instructions that need a companion (a JMP after a test, an EXTRAARG after
+// LOADKX) have one.
+//
+// Consult the versioned ldebug.c (luaG_checkcode, from Lua 5.1) for more
+// information.
+
+use super::errors::*;
+use super::object::Proto;
+use super::opcodes::{Instruction, OpCode};
+
+const BITRK: u16 = 1 << 8; // see vm.rs
+
+impl Proto {
+    // Check this function and all the functions defined in it
+    pub fn verify(&self) -> Result<()> {
+        if self.instructions.last().map(opcode) != Some(OpCode::Return) {
+            return Err(ErrorKind::InvalidFunction("function does not end with a return").into());
+        }
+        if self.numparams > self.maxstacksize {
+            return Err(ErrorKind::InvalidFunction("parameters do not fit in the stack").into());
+        }
+        for (pc, instruction) in self.instructions.iter().enumerate() {
+            Verifier { proto: self, pc }.check(instruction)?;
+        }
+        for child in &self.protos {
+            child.verify()?;
+        }
+        Ok(())
+    }
+}
+
+fn opcode(i: &Instruction) -> OpCode {
+    match *i {
+        Instruction::ABC { instruction, .. } | Instruction::ABx { instruction, .. } |
+        Instruction::AsBx { instruction, .. } | Instruction::Ax { instruction, .. } => instruction,
+    }
+}
+
+struct Verifier<'p> {
+    proto: &'p Proto,
+    pc: usize,
+}
+
+impl<'p> Verifier<'p> {
+    fn register(&self, register: usize) -> Result<()> {
+        if register < self.proto.maxstacksize as usize {
+            Ok(())
+        } else {
+            Err(ErrorKind::InvalidRegister(self.pc, register).into())
+        }
+    }
+
+    fn constant(&self, index: usize) -> Result<()> {
+        if index < self.proto.constants.len() {
+            Ok(())
+        } else {
+            Err(ErrorKind::InvalidConstant(self.pc, index).into())
+        }
+    }
+
+    fn rk(&self, x: u16) -> Result<()> {
+        if x & BITRK != 0 {
+            self.constant((x & !BITRK) as usize)
+        } else {
+            self.register(x as usize)
+        }
+    }
+
+    fn upvalue(&self, index: usize) -> Result<()> {
+        if index < self.proto.upvalues.len() {
+            Ok(())
+        } else {
+            Err(ErrorKind::InvalidUpvalue(self.pc, index).into())
+        }
+    }
+
+    // `offset` instructions past the next one
+    fn jump(&self, offset: i64) -> Result<()> {
+        let target = self.pc as i64 + 1 + offset;
+        if target >= 0 && (target as usize) < self.proto.instructions.len() {
+            Ok(())
+        } else {
+            Err(ErrorKind::InvalidJump(self.pc, target).into())
+        }
+    }
+
+    fn next(&self) -> Option<&'p Instruction> {
+        self.proto.instructions.get(self.pc + 1)
+    }
+
+    fn followed_by(&self, op: OpCode) -> Result<()> {
+        match self.next() {
+            Some(next) if opcode(next) == op => Ok(()),
+            _ => Err(ErrorKind::MissingInstruction(self.pc, op).into()),
+        }
+    }
+
+    // the constant or count in the EXTRAARG after this instruction
+    fn extra_arg(&self) -> Result<u32> {
+        match self.next() {
+            Some(&Instruction::Ax { instruction: OpCode::ExtraArg, ax }) => Ok(ax),
+            _ => Err(ErrorKind::MissingInstruction(self.pc, OpCode::ExtraArg).into()),
+        }
+    }
+
+    fn check(&self, instruction: &Instruction) -> Result<()> {
+        match *instruction {
+            Instruction::ABC { instruction: op, a, b, c } => {
+                let (ra, rb, rc) = (a as usize, b as usize, c as usize);
+                match op {
+                    OpCode::Move => {
+                        self.register(ra)?;
+                        self.register(rb)
+                    }
+                    OpCode::LoadKX => {
+                        self.register(ra)?;
+                        self.constant(self.extra_arg()? as usize)
+                    }
+                    OpCode::LoadBool => {
+                        self.register(ra)?;
+                        if rc != 0 { self.jump(1) } else { Ok(()) }
+                    }
+                    OpCode::LoadNil => self.register(ra + rb),
+                    OpCode::GetUpval => {
+                        self.register(ra)?;
+                        self.upvalue(rb)
+                    }
+                    OpCode::GetTabUp => {
+                        self.register(ra)?;
+                        self.upvalue(rb)?;
+                        self.rk(c)
+                    }
+                    OpCode::GetTable => {
+                        self.register(ra)?;
+                        self.register(rb)?;
+                        self.rk(c)
+                    }
+                    OpCode::SetTabUp => {
+                        self.upvalue(ra)?;
+                        self.rk(b)?;
+                        self.rk(c)
+                    }
+                    OpCode::SetUpval => {
+                        self.register(ra)?;
+                        self.upvalue(rb)
+                    }
+                    OpCode::NewTable => self.register(ra),
+                    OpCode::SelfLoad => {
+                        self.register(ra + 1)?;
+                        self.register(rb)?;
+                        self.rk(c)
+                    }
+                    OpCode::SetTable | OpCode::Add | OpCode::Sub | OpCode::Mul |
+                    OpCode::Mod | OpCode::Pow | OpCode::Div | OpCode::IDiv | OpCode::BAnd |
+                    OpCode::BOr | OpCode::BXOr | OpCode::Shl | OpCode::Shr => {
+                        self.register(ra)?;
+                        self.rk(b)?;
+                        self.rk(c)
+                    }
+                    OpCode::Unm | OpCode::BNot | OpCode::Not | OpCode::Len => {
+                        self.register(ra)?;
+                        self.rk(b)
+                    }
+                    OpCode::Concat => {
+                        self.register(ra)?;
+                        if rb > rc {
+                            return Err(ErrorKind::InvalidRegister(self.pc, rb).into());
+                        }
+                        self.register(rc)
+                    }
+                    OpCode::Eq | OpCode::Lt | OpCode::Le => {
+                        self.rk(b)?;
+                        self.rk(c)?;
+                        self.followed_by(OpCode::Jmp)
+                    }
+                    OpCode::Test => {
+                        self.register(ra)?;
+                        self.followed_by(OpCode::Jmp)
+                    }
+                    OpCode::TestSet => {
+                        self.register(ra)?;
+                        self.register(rb)?;
+                        self.followed_by(OpCode::Jmp)
+                    }
+                    // with B or C 0 the arguments or results run up to the
+                    // top, set by the instruction before or after
+                    OpCode::Call | OpCode::TailCall => {
+                        self.register(ra)?;
+                        if rb > 0 {
+                            self.register(ra + rb - 1)?;
+                        }
+                        if rc > 1 {
+                            self.register(ra + rc - 2)?;
+                        }
+                        Ok(())
+                    }
+                    OpCode::Return | OpCode::VarArg => {
+                        if rb > 1 {
+                            self.register(ra + rb - 2)
+                        } else {
+                            self.register(ra)
+                        }
+                    }
+                    OpCode::TForCall => {
+                        self.register(ra + 2 + rc.max(1))?;
+                        self.followed_by(OpCode::TForLoop)
+                    }
+                    OpCode::SetList => {
+                        self.register(ra + rb)?;
+                        if rc == 0 {
+                            self.extra_arg()?;
+                        }
+                        Ok(())
+                    }
+                    _ => Ok(()),
+                }
+            }
+            Instruction::ABx { instruction: op, a, bx } => {
+                self.register(a as usize)?;
+                match op {
+                    OpCode::LoadK => self.constant(bx as usize),
+                    OpCode::Closure => {
+                        let child = match self.proto.protos.get(bx as usize) {
+                            Some(child) => child,
+                            None => return Err(ErrorKind::InvalidProto(self.pc, bx as usize).into()),
+                        };
+                        // the upvalues it captures, from the stack or our own
+                        for upvalue in &child.upvalues {
+                            if upvalue.instack != 0 {
+                                self.register(upvalue.idx as usize)?;
+                            } else {
+                                self.upvalue(upvalue.idx as usize)?;
+                            }
+                        }
+                        Ok(())
+                    }
+                    _ => Ok(()),
+                }
+            }
+            Instruction::AsBx { instruction: op, a, sbx } => {
+                let a = a as usize;
+                match op {
+                    OpCode::Jmp if a != 0 => self.register(a - 1)?,
+                    OpCode::ForLoop | OpCode::ForPrep => self.register(a + 3)?,
+                    OpCode::TForLoop => self.register(a + 1)?,
+                    _ => {}
+                }
+                // a loop that doesn't run skips past the ForLoop too
+                if op == OpCode::ForPrep {
+                    self.jump(sbx as i64 + 1)?;
+                }
+                self.jump(sbx as i64)
+            }
+            // only ever read by the instruction before, and skipped over
+            Instruction::Ax { .. } => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::compiler::parse;
+    use super::super::dump::DumpState;
+    use super::super::errors::ErrorKind;
+    use super::super::opcodes::{Instruction, OpCode};
+    use super::super::undump::LoadState;
+
+    const PROGRAM: &[u8] = b"
+        local t = {1, 2, 3, x = 'y'}
+        local function f(a, ...)
+            for i, v in ipairs(t) do a = a + v end
+            for i = 1, #t, 2 do if i > 1 and t[i] ~= nil then t.x = t.x .. i end end
+            while a > 100 do a = a // 2 end
+            return a, ...
+        end
+        print(f(1, 2, 3))
+    ";
+
+    #[test]
+    fn test_compiled_code_verifies() {
+        let proto = parse(PROGRAM, "=test").unwrap();
+        proto.verify().unwrap();
+        let chunk = DumpState::to_u8(&proto).unwrap();
+        LoadState::from_u8(chunk, "=test").unwrap().verify().unwrap();
+    }
+
+    #[test]
+    fn test_invalid_code() {
+        let proto = parse(b"local a, b = 1, 2 return a + b", "=test").unwrap();
+        let corrupt = |pc: usize, instruction: Instruction| {
+            let mut proto = parse(b"local a, b = 1, 2 return a + b", "=test").unwrap();
+            proto.instructions[pc] = instruction;
+            proto.verify().err().unwrap().0
+        };
+        proto.verify().unwrap();
+        match corrupt(0, Instruction::ABx { instruction: OpCode::LoadK, a: 0, bx: 7 }) {
+            ErrorKind::InvalidConstant(0, 7) => {}
+            error => panic!("unexpected error: {}", error),
+        }
+        match corrupt(2, Instruction::ABC { instruction: OpCode::Add, a: 200, b: 0, c: 1 }) {
+            ErrorKind::InvalidRegister(2, 200) => {}
+            error => panic!("unexpected error: {}", error),
+        }
+        match corrupt(1, Instruction::AsBx { instruction: OpCode::Jmp, a: 0, sbx: 10 }) {
+            ErrorKind::InvalidJump(1, 12) => {}
+            error => panic!("unexpected error: {}", error),
+        }
+        match corrupt(1, Instruction::ABC { instruction: OpCode::GetUpval, a: 0, b: 3, c: 0 }) {
+            ErrorKind::InvalidUpvalue(1, 3) => {}
+            error => panic!("unexpected error: {}", error),
+        }
+        match corrupt(1, Instruction::ABC { instruction: OpCode::Eq, a: 0, b: 0, c: 1 }) {
+            ErrorKind::MissingInstruction(1, OpCode::Jmp) => {}
+            error => panic!("unexpected error: {}", error),
+        }
+        let last = proto.instructions.len() - 1;
+        match corrupt(last, Instruction::ABC { instruction: OpCode::Move, a: 0, b: 1, c: 0 }) {
+            ErrorKind::InvalidFunction(_) => {}
+            error => panic!("unexpected error: {}", error),
+        }
+    }
+}
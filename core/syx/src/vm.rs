@@ -0,0 +1,1882 @@
+// Interpreter loop
+//
+// Registers of the running function live on `SyxState.stack`, starting at
+// the base of its CallInfo. Every frame reserves `maxstacksize` registers when
+// it is entered, so register access is a plain index off the base.
+//
+// Reading a field by a constant string key, as reading a global does, goes
+// through an inline cache: each closure remembers, per instruction, the
+// table it last read, the version of the table then and the value found.
+// Every write to a table gives it a new version (see `table_mut`), so the
+// cached value is used as long as the version matches.
+//
+// Consult the versioned lvm.c for more information.
+
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+use super::compiler::load_with_mode;
+use super::conf::{SYX_ERRORSTACK, SYX_MAXCCALLS, SYX_MAXNUMBER2STR};
+use super::coroutine::coresume;
+use super::errors::*;
+use super::func::FieldCache;
+use super::numfmt::{integer_to_string, number_to_string};
+use super::object::{
+    float_to_integer, integer_value, FunctionRef, MultiValue, Proto,
+    SyxInteger, SyxNumber, SyxUnsigned, SyxValue, UpvalRef,
+};
+use super::opcodes::{Instruction, OpCode};
+use super::state::{CallInfo, SyxState};
+use super::tm::TagMethod;
+
+const BITRK: usize = 1 << 8; // RK(x) refers to a constant when this is set
+const LFIELDS_PER_FLUSH: usize = 50; // SETLIST block size
+
+// table sizes in NEWTABLE are "floating point bytes", eeeeexxx
+fn fb2int(x: u16) -> usize {
+    let x = x as usize;
+    if x < 8 {
+        x
+    } else {
+        ((x & 7) + 8) << ((x >> 3) - 1)
+    }
+}
+
+pub(crate) fn runtime_error<T>(message: String) -> Result<T> {
+    Err(ErrorKind::RuntimeError(message).into())
+}
+
+pub(crate) fn bad_argument<T>(n: usize, name: &str, message: &str) -> Result<T> {
+    runtime_error(format!("bad argument #{} to '{}' ({})", n, name, message))
+}
+
+impl SyxState {
+    // Run `proto` as the main function of a chunk with `args` as its
+    // arguments, returning every value it returns. Its _ENV upvalue is the
+    // globals table.
+    pub fn call(&mut self, proto: Arc<Proto>, args: Vec<SyxValue>)
+        -> Result<MultiValue>
+    {
+        let closure = self.new_main_closure(proto);
+        self.call_closure(closure, args)
+    }
+
+    // `call` with `env` as the _ENV upvalue instead of the globals table, so
+    // every global the chunk reads or assigns, and those of the functions it
+    // defines, is a field of `env`. Whatever it does not put there is out of
+    // its reach, as in a sandbox.
+    pub fn call_with_env(&mut self, proto: Arc<Proto>, env: SyxValue, args: Vec<SyxValue>)
+        -> Result<MultiValue>
+    {
+        let closure = self.new_env_closure(proto, env);
+        self.call_closure(closure, args)
+    }
+
+    // Load `chunk` as `compiler::load_with_mode` does and call its main
+    // function with no arguments in a fresh environment: its _ENV is a new
+    // table, which reads what it lacks from the globals, so the chunk sees
+    // the libraries but its global assignments stay out of them. Errors
+    // loading it (a SyntaxError, ChunkModeError or Undump) are returned as
+    // they are, as are those it raises.
+    pub fn load_and_call(&mut self, chunk: Vec<u8>, chunkname: &str, mode: &str)
+        -> Result<MultiValue>
+    {
+        let proto = Arc::new(load_with_mode(chunk, chunkname, mode)?);
+        let env = self.new_table(0, 0);
+        let metatable = self.new_table(0, 1);
+        let index = SyxValue::String(self.tm_names[TagMethod::Index as usize].clone());
+        let globals = SyxValue::Table(self.globals);
+        self.table_set(metatable, index, globals)?;
+        self.table_mut(env).set_metatable(Some(metatable));
+        self.call_with_env(proto, SyxValue::Table(env), vec![])
+    }
+
+    fn call_closure(&mut self, closure: FunctionRef, args: Vec<SyxValue>)
+        -> Result<MultiValue>
+    {
+        self.protected_call(SyxValue::Function(closure), args, None)
+    }
+
+    // Call any value from Rust, and on failure drop whatever the call left
+    // on the stack. If there is a `handler`, it is called with the error
+    // value before that, and what it returns becomes the error. Nothing
+    // called this way can yield, as that would have to suspend the Rust
+    // caller too.
+    pub(crate) fn protected_call(&mut self, func: SyxValue, mut args: Vec<SyxValue>,
+                                 handler: Option<SyxValue>) -> Result<MultiValue> {
+        if self.ccalls >= SYX_MAXCCALLS {
+            return runtime_error("C stack overflow".to_owned());
+        }
+        let base = self.stack.len();
+        let depth = self.frames.len();
+        self.nny += 1;
+        self.ccalls += 1;
+        let mut results = match self.resolve_call(func, &mut args) {
+            Ok(SyxValue::Function(closure)) => {
+                self.enter(closure, base, args)
+                    .and_then(|_| self.hook_call())
+                    .and_then(|_| self.execute(depth))
+            }
+            Ok(native) => self.call_native(native, args),
+            Err(error) => Err(error),
+        };
+        if let (Err(error), Some(handler)) = (&results, handler) {
+            let value = self.error_value(error);
+            // room for the handler of a stack overflow
+            self.max_stack += SYX_ERRORSTACK;
+            let handled = match self.protected_call(handler, vec![value], None) {
+                Ok(values) => values.into_first(),
+                Err(_) => SyxValue::from("error in error handling"),
+            };
+            self.max_stack -= SYX_ERRORSTACK;
+            results = Err(ErrorKind::LuaError(handled).into());
+        }
+        self.nny -= 1;
+        self.ccalls -= 1;
+        self.close_upvalues(base);
+        self.frames.truncate(depth);
+        self.stack.truncate(base);
+        results
+    }
+
+    // Call a native function, with hook events around it. No return event
+    // is sent if it yields.
+    fn call_native(&mut self, function: SyxValue, args: Vec<SyxValue>)
+        -> Result<MultiValue>
+    {
+        self.hook_call()?;
+        let results = self.run_native(function, args)?;
+        if self.yielded.is_none() {
+            self.hook_return()?;
+        }
+        Ok(results)
+    }
+
+    pub(crate) fn enter(&mut self, closure: FunctionRef, base: usize, mut args: Vec<SyxValue>)
+        -> Result<()>
+    {
+        let proto = self.closure(closure).proto.clone();
+        let size = base + proto.maxstacksize as usize;
+        if size > self.max_stack {
+            return runtime_error("stack overflow".to_owned());
+        }
+        let numparams = proto.numparams as usize;
+        // extra arguments are kept for VARARG, or dropped by a function
+        // without "..."
+        let varargs = if args.len() > numparams && proto.is_vararg != 0 {
+            args.split_off(numparams)
+        } else {
+            args.truncate(numparams);
+            vec![]
+        };
+        self.stack.truncate(base);
+        self.stack.extend(args);
+        if self.stack.len() < size {
+            self.stack.resize(size, SyxValue::Nil);
+        }
+        self.frames.push(CallInfo {
+            closure,
+            proto,
+            base,
+            pc: 0,
+            top: base,
+            ret: base,
+            nresults: None,
+            from_lua: false,
+            tailcall: false,
+            varargs,
+        });
+        Ok(())
+    }
+
+    // upvalue `index` of the running closure
+    fn upvalue(&self, closure: FunctionRef, index: u16) -> Result<UpvalRef> {
+        match self.closure(closure).upvalues.get(index as usize) {
+            Some(&upvalue) => Ok(upvalue),
+            None => runtime_error(format!("upvalue {} is not bound", index)),
+        }
+    }
+
+    fn rk<'a>(&'a self, proto: &'a Proto, base: usize, x: u16) -> &'a SyxValue {
+        let x = x as usize;
+        if x & BITRK != 0 {
+            &proto.constants[x & !BITRK]
+        } else {
+            &self.stack[base + x]
+        }
+    }
+
+    // t[k], following __index
+    pub(crate) fn get_index(&mut self, table: &SyxValue, key: &SyxValue) -> Result<SyxValue> {
+        let mut table = table.clone();
+        for _ in 0..self.max_tag_loop {
+            let handler = match table {
+                SyxValue::Table(t) => {
+                    let value = self.table(t).get(key);
+                    if !value.is_nil() {
+                        return Ok(value);
+                    }
+                    match self.metamethod(&table, TagMethod::Index) {
+                        Some(handler) => handler,
+                        None => return Ok(SyxValue::Nil),
+                    }
+                }
+                _ => match self.metamethod(&table, TagMethod::Index) {
+                    Some(handler) => handler,
+                    None => return runtime_error(format!("attempt to index a {} value",
+                                                         table.type_name())),
+                },
+            };
+            if handler.is_function() {
+                return self.call_value(handler, vec![table, key.clone()])
+                    .map(MultiValue::into_first);
+            }
+            table = handler;
+        }
+        runtime_error("'__index' chain too long; possible loop".to_owned())
+    }
+
+    // t[K(x)] for the instruction at `pc`, which caches where a string key
+    // found its value: while the table is unchanged, the lookup is skipped.
+    // Only values found in the table itself are cached, as those of __index
+    // may change without it changing.
+    fn get_field(&mut self, closure: FunctionRef, proto: &Proto, pc: usize, table: &SyxValue,
+                 x: u16) -> Result<SyxValue> {
+        let key = &proto.constants[x as usize & !BITRK];
+        let t = match (table, key) {
+            (&SyxValue::Table(t), SyxValue::String(_)) => t,
+            _ => return self.get_index(table, key),
+        };
+        let version = self.table(t).version;
+        if let Some(cache) = self.closure(closure).caches.get(pc) {
+            if cache.table == t && cache.version == version {
+                return Ok(cache.value.clone());
+            }
+        }
+        let value = self.table(t).get(key);
+        if value.is_nil() {
+            return self.get_index(table, key);
+        }
+        let caches = &mut self.heap.functions.get_mut(closure.0).caches;
+        if caches.is_empty() {
+            caches.resize(proto.instructions.len(), FieldCache::default());
+        }
+        caches[pc] = FieldCache { table: t, version, value: value.clone() };
+        Ok(value)
+    }
+
+    // t[k] = v, following __newindex
+    pub(crate) fn set_index(&mut self, table: &SyxValue, key: SyxValue, value: SyxValue) -> Result<()> {
+        let mut table = table.clone();
+        for _ in 0..self.max_tag_loop {
+            let handler = match table {
+                SyxValue::Table(t) => {
+                    let handler = if self.table(t).get(&key).is_nil() {
+                        self.metamethod(&table, TagMethod::NewIndex)
+                    } else {
+                        None
+                    };
+                    match handler {
+                        Some(handler) => handler,
+                        None => return self.table_set(t, key, value),
+                    }
+                }
+                _ => match self.metamethod(&table, TagMethod::NewIndex) {
+                    Some(handler) => handler,
+                    None => return runtime_error(format!("attempt to index a {} value",
+                                                         table.type_name())),
+                },
+            };
+            if handler.is_function() {
+                return self.call_value(handler, vec![table, key, value]).map(|_| ());
+            }
+            table = handler;
+        }
+        runtime_error("'__newindex' chain too long; possible loop".to_owned())
+    }
+
+    // Call any value, going through __call for values that are not functions
+    pub(crate) fn call_value(&mut self, func: SyxValue, args: Vec<SyxValue>)
+        -> Result<MultiValue>
+    {
+        self.protected_call(func, args, None)
+    }
+
+    // The Lua or native function a call of `func` ends up in, after adding
+    // the objects with a __call handler to the front of `args`
+    pub(crate) fn resolve_call(&self, func: SyxValue, args: &mut Vec<SyxValue>) -> Result<SyxValue> {
+        let mut func = func;
+        for _ in 0..self.max_tag_loop {
+            if func.is_function() {
+                return Ok(func);
+            }
+            match self.metamethod(&func, TagMethod::Call) {
+                Some(handler) => {
+                    args.insert(0, func);
+                    func = handler;
+                }
+                None => return runtime_error(format!("attempt to call a {} value",
+                                                     func.type_name())),
+            }
+        }
+        runtime_error("'__call' chain too long; possible loop".to_owned())
+    }
+
+    // lhs == rhs, trying __eq for two tables or two userdata not the same
+    pub fn equal(&mut self, lhs: &SyxValue, rhs: &SyxValue) -> Result<bool> {
+        if lhs == rhs {
+            return Ok(true);
+        }
+        match (lhs, rhs) {
+            (SyxValue::Table(_), SyxValue::Table(_)) |
+            (SyxValue::UserData(_), SyxValue::UserData(_)) => {}
+            _ => return Ok(false),
+        }
+        match self.binary_metamethod(lhs.clone(), rhs.clone(), TagMethod::Eq) {
+            Some(result) => Ok(!result?.is_falsy()),
+            None => Ok(false),
+        }
+    }
+
+    // lhs < rhs, trying __lt unless both are numbers or strings
+    pub fn less_than(&mut self, lhs: &SyxValue, rhs: &SyxValue) -> Result<bool> {
+        match less_than(lhs, rhs) {
+            Err(error) => match self.binary_metamethod(lhs.clone(), rhs.clone(), TagMethod::Lt) {
+                Some(result) => Ok(!result?.is_falsy()),
+                None => Err(error),
+            },
+            result => result,
+        }
+    }
+
+    // lhs <= rhs, trying __le, then `not (rhs < lhs)` with __lt
+    pub fn less_equal(&mut self, lhs: &SyxValue, rhs: &SyxValue) -> Result<bool> {
+        match less_equal(lhs, rhs) {
+            Err(error) => {
+                if let Some(result) =
+                    self.binary_metamethod(lhs.clone(), rhs.clone(), TagMethod::Le) {
+                    return Ok(!result?.is_falsy());
+                }
+                match self.binary_metamethod(rhs.clone(), lhs.clone(), TagMethod::Lt) {
+                    Some(result) => Ok(result?.is_falsy()),
+                    None => Err(error),
+                }
+            }
+            result => result,
+        }
+    }
+
+    // Concatenate `values` right to left, as `a .. b .. c` is `a .. (b .. c)`:
+    // each run of strings and numbers at the end is joined in one buffer, and
+    // __concat is called for the last two values otherwise
+    pub(crate) fn concat(&mut self, mut values: Vec<SyxValue>) -> Result<SyxValue> {
+        while values.len() > 1 {
+            let count = values.iter().rev().take_while(|value| concatenable(value)).count();
+            if count >= 2 {
+                let start = values.len() - count;
+                let mut buffer = Vec::new();
+                let size = values[start..].iter().map(string_size).sum();
+                // results of __concat may be nowhere else should it collect
+                let mark = self.api_stack.len();
+                self.api_stack.extend_from_slice(&values[..start]);
+                let reserved = self.reserve(&mut buffer, size);
+                self.api_stack.truncate(mark);
+                reserved?;
+                for value in &values[start..] {
+                    append_string(&mut buffer, value);
+                }
+                values.truncate(start);
+                values.push(SyxValue::String(self.intern(&buffer)));
+                continue;
+            }
+            let rhs = values.pop().expect("two values");
+            let lhs = values.pop().expect("two values");
+            let bad = if concatenable(&lhs) { &rhs } else { &lhs };
+            let error = format!("attempt to concatenate a {} value", bad.type_name());
+            match self.binary_metamethod(lhs, rhs, TagMethod::Concat) {
+                Some(result) => values.push(result?),
+                None => return runtime_error(error),
+            }
+        }
+        Ok(values.pop().unwrap_or(SyxValue::Nil))
+    }
+
+    // first result of the handler for a binary event on `lhs` or `rhs`
+    fn binary_metamethod(&mut self, lhs: SyxValue, rhs: SyxValue, event: TagMethod)
+        -> Option<Result<SyxValue>>
+    {
+        let handler = self.metamethod(&lhs, event)
+            .or_else(|| self.metamethod(&rhs, event))?;
+        Some(self.call_value(handler, vec![lhs, rhs]).map(MultiValue::into_first))
+    }
+
+    // copy call results to R(A).., `wanted` of them or all if it is None,
+    // returning the new top
+    fn place_results(&mut self, ra: usize, mut results: MultiValue, wanted: Option<usize>)
+        -> usize
+    {
+        if let Some(wanted) = wanted {
+            results.adjust(wanted);
+        }
+        let count = results.len();
+        for (i, value) in results.into_iter().enumerate() {
+            self.set(ra + i, value);
+        }
+        ra + count
+    }
+
+    fn set(&mut self, index: usize, value: SyxValue) {
+        if index >= self.stack.len() {
+            self.stack.resize(index + 1, SyxValue::Nil);
+        }
+        self.stack[index] = value;
+    }
+
+    // registers of the running frame
+    fn frame_state(&self) -> (FunctionRef, Arc<Proto>, usize, usize, usize) {
+        let ci = self.frames.last().expect("no active frame");
+        (ci.closure, ci.proto.clone(), ci.base, ci.pc, ci.top)
+    }
+
+    // Complete the call a suspended frame yielded in, with `results` as
+    // what the call returned
+    pub(crate) fn finish_call(&mut self, results: MultiValue) {
+        let (_, proto, base, pc, _) = self.frame_state();
+        let (ra, wanted) = match proto.instructions[pc - 1] {
+            Instruction::ABC { instruction: OpCode::Call, a, c, .. } if c != 0 => {
+                (base + a as usize, Some(c as usize - 1))
+            }
+            // preempted, see preempt.rs
+            Instruction::ABC { instruction: OpCode::Check, .. } => return,
+            Instruction::ABC { a, .. } => (base + a as usize, None),
+            _ => unreachable!(),
+        };
+        let top = self.place_results(ra, results, wanted);
+        self.save_frame_top(top);
+    }
+
+    fn save_frame_top(&mut self, top: usize) {
+        self.frames.last_mut().expect("no active frame").top = top;
+    }
+
+    // Run Lua frames until the one at `depth` returns, and return its
+    // results. Calls between Lua functions push a frame and carry on in the
+    // same loop, as do resumes of coroutines by Lua code (see coroutine.rs),
+    // so neither takes any Rust stack. When a native function yields, the
+    // state of every frame is saved and the values it yields are returned
+    // instead.
+    //
+    // Runtime errors get the position of the instruction that raised them,
+    // and the frames are left as they were for tracebacks. One raised in a
+    // coroutine resumed here kills it and is returned by its resume.
+    pub(crate) fn execute(&mut self, depth: usize) -> Result<MultiValue> {
+        // coroutines running for a resume in this loop
+        let mut resumes = 0;
+        loop {
+            match self.run(depth, &mut resumes).map_err(|error| self.locate(error)) {
+                Err(error) if resumes > 0 => {
+                    resumes -= 1;
+                    let value = self.error_value(&error);
+                    self.switch_back(false);
+                    self.finish_resume(false, vec![value].into())?;
+                }
+                results => return results,
+            }
+        }
+    }
+
+    fn locate(&mut self, error: Error) -> Error {
+        if let ErrorKind::RuntimeError(ref message) = *error.kind() {
+            if let Some(location) = self.location() {
+                let message = format!("{} {}", location, message);
+                return ErrorKind::LuaError(SyxValue::String(self.intern(message.as_bytes())))
+                    .into();
+            }
+        }
+        error
+    }
+
+    // Dispatch is one match on the instruction. A table of handler functions
+    // indexed by opcode was tried and measured 10-20% slower on
+    // benches/interpreter.rs: without guaranteed tail calls every
+    // instruction becomes an indirect call that cannot be inlined.
+    fn run(&mut self, depth: usize, resumes: &mut usize) -> Result<MultiValue> {
+        // `top` is the end of the values left by the last open call or
+        // VARARG, see opcodes.rs
+        let (mut closure, mut proto, mut base, mut pc, mut top) = self.frame_state();
+
+        loop {
+            let instruction = match proto.instructions.get(pc) {
+                Some(instruction) => instruction,
+                None => return runtime_error("missing return".to_owned()),
+            };
+            pc += 1;
+            self.frames.last_mut().expect("no active frame").pc = pc;
+            if self.hook.is_some() {
+                self.trace_exec(&proto, pc - 1)?;
+            }
+            if self.recorder.is_some() {
+                self.record(&proto, base, pc - 1);
+            }
+
+            match *instruction {
+                Instruction::ABC { instruction: op, a, b, c } => {
+                    let ra = base + a as usize;
+                    match op {
+                        OpCode::Move => {
+                            self.stack[ra] = self.stack[base + b as usize].clone();
+                        }
+                        OpCode::LoadKX => {
+                            let index = match proto.instructions.get(pc) {
+                                Some(&Instruction::Ax { instruction: OpCode::ExtraArg, ax }) => ax,
+                                _ => return runtime_error("LOADKX without EXTRAARG".to_owned()),
+                            };
+                            pc += 1;
+                            self.stack[ra] = proto.constants[index as usize].clone();
+                        }
+                        OpCode::LoadBool => {
+                            self.stack[ra] = SyxValue::Bool(b != 0);
+                            if c != 0 {
+                                pc += 1;
+                            }
+                        }
+                        OpCode::LoadNil => {
+                            for value in &mut self.stack[ra..=ra + b as usize] {
+                                *value = SyxValue::Nil;
+                            }
+                        }
+                        OpCode::GetUpval => {
+                            let upvalue = self.upvalue(closure, b)?;
+                            self.stack[ra] = self.get_upvalue(upvalue);
+                        }
+                        OpCode::SetUpval => {
+                            let upvalue = self.upvalue(closure, b)?;
+                            let value = self.stack[ra].clone();
+                            self.set_upvalue(upvalue, value);
+                        }
+                        OpCode::GetTabUp if c as usize & BITRK != 0 => {
+                            let table = self.upvalue(closure, b).map(|u| self.get_upvalue(u))?;
+                            self.stack[ra] = self.get_field(closure, &proto, pc - 1, &table, c)?;
+                        }
+                        OpCode::GetTabUp => {
+                            let table = self.upvalue(closure, b).map(|u| self.get_upvalue(u))?;
+                            let key = self.rk(&proto, base, c).clone();
+                            self.stack[ra] = self.get_index(&table, &key)?;
+                        }
+                        OpCode::SetTabUp => {
+                            let table = self.upvalue(closure, a as u16).map(|u| self.get_upvalue(u))?;
+                            let key = self.rk(&proto, base, b).clone();
+                            let value = self.rk(&proto, base, c).clone();
+                            self.set_index(&table, key, value)?;
+                            self.check_gc()?;
+                        }
+                        OpCode::GetTable if c as usize & BITRK != 0 => {
+                            let table = self.stack[base + b as usize].clone();
+                            self.stack[ra] = self.get_field(closure, &proto, pc - 1, &table, c)?;
+                        }
+                        OpCode::GetTable => {
+                            let table = self.stack[base + b as usize].clone();
+                            let key = self.rk(&proto, base, c).clone();
+                            self.stack[ra] = self.get_index(&table, &key)?;
+                        }
+                        OpCode::SelfLoad => {
+                            let table = self.stack[base + b as usize].clone();
+                            let key = self.rk(&proto, base, c).clone();
+                            let value = self.get_index(&table, &key)?;
+                            self.stack[ra + 1] = table;
+                            self.stack[ra] = value;
+                        }
+                        OpCode::SetTable => {
+                            let table = self.stack[ra].clone();
+                            let key = self.rk(&proto, base, b).clone();
+                            let value = self.rk(&proto, base, c).clone();
+                            self.set_index(&table, key, value)?;
+                            self.check_gc()?;
+                        }
+                        OpCode::NewTable => {
+                            let table = self.new_table(fb2int(b), fb2int(c));
+                            self.stack[ra] = SyxValue::Table(table);
+                            self.check_gc()?;
+                        }
+                        OpCode::SetList => {
+                            let count = if b == 0 { top - ra - 1 } else { b as usize };
+                            let block = if c == 0 {
+                                match proto.instructions.get(pc) {
+                                    Some(&Instruction::Ax { instruction: OpCode::ExtraArg, ax }) => {
+                                        pc += 1;
+                                        ax as usize
+                                    }
+                                    _ => return runtime_error("SETLIST without EXTRAARG".to_owned()),
+                                }
+                            } else {
+                                c as usize
+                            };
+                            let table = match self.stack[ra] {
+                                SyxValue::Table(t) => t,
+                                _ => return runtime_error("SETLIST on a non-table".to_owned()),
+                            };
+                            let first = (block - 1) * LFIELDS_PER_FLUSH;
+                            for i in 1..=count {
+                                let value = self.stack[ra + i].clone();
+                                self.table_set(table, SyxValue::Integer((first + i) as SyxInteger),
+                                               value)?;
+                            }
+                            self.check_gc()?;
+                        }
+                        OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::Mod |
+                        OpCode::Pow | OpCode::Div | OpCode::IDiv | OpCode::BAnd |
+                        OpCode::BOr | OpCode::BXOr | OpCode::Shl | OpCode::Shr => {
+                            let value = match arith(op, self.rk(&proto, base, b),
+                                                    self.rk(&proto, base, c)) {
+                                Ok(value) => value,
+                                Err(error) => {
+                                    let lhs = self.rk(&proto, base, b).clone();
+                                    let rhs = self.rk(&proto, base, c).clone();
+                                    match self.binary_metamethod(lhs, rhs, arith_event(op)) {
+                                        Some(result) => result?,
+                                        None => return Err(error),
+                                    }
+                                }
+                            };
+                            self.stack[ra] = value;
+                        }
+                        OpCode::Unm | OpCode::Len | OpCode::BNot => {
+                            let operand = self.rk(&proto, base, b).clone();
+                            let event = match op {
+                                OpCode::Unm => TagMethod::Unm,
+                                OpCode::Len => TagMethod::Len,
+                                _ => TagMethod::BNot,
+                            };
+                            let handler = match operand {
+                                SyxValue::Integer(_) | SyxValue::Number(_) |
+                                SyxValue::String(_) => None,
+                                _ => self.metamethod(&operand, event),
+                            };
+                            let value = match handler {
+                                Some(handler) => {
+                                    let args = vec![operand.clone(), operand];
+                                    self.call_value(handler, args)?.into_first()
+                                }
+                                None => unary(self, op, &operand)?,
+                            };
+                            self.stack[ra] = value;
+                        }
+                        OpCode::Not => {
+                            let value = unary(self, op, self.rk(&proto, base, b))?;
+                            self.stack[ra] = value;
+                        }
+                        OpCode::Concat => {
+                            let values = self.stack[base + b as usize..=base + c as usize].to_vec();
+                            self.stack[ra] = self.concat(values)?;
+                            self.check_gc()?;
+                        }
+                        OpCode::Eq | OpCode::Lt | OpCode::Le => {
+                            let lhs = self.rk(&proto, base, b).clone();
+                            let rhs = self.rk(&proto, base, c).clone();
+                            let result = match op {
+                                OpCode::Eq => self.equal(&lhs, &rhs)?,
+                                OpCode::Lt => self.less_than(&lhs, &rhs)?,
+                                _ => self.less_equal(&lhs, &rhs)?,
+                            };
+                            if result != (a != 0) {
+                                pc += 1;
+                            }
+                        }
+                        OpCode::Test => {
+                            if self.stack[ra].is_falsy() == (c != 0) {
+                                pc += 1;
+                            }
+                        }
+                        OpCode::TestSet => {
+                            let value = self.stack[base + b as usize].clone();
+                            if value.is_falsy() == (c != 0) {
+                                pc += 1;
+                            } else {
+                                self.stack[ra] = value;
+                            }
+                        }
+                        OpCode::Call | OpCode::TailCall => {
+                            let end = if b == 0 { top } else { ra + b as usize };
+                            let func = self.stack[ra].clone();
+                            let mut args = self.stack[ra + 1..end].to_vec();
+                            // a tail call is followed by a RETURN of everything
+                            let wanted = if c == 0 || op == OpCode::TailCall {
+                                None
+                            } else {
+                                Some(c as usize - 1)
+                            };
+                            match self.resolve_call(func, &mut args)? {
+                                // the callee takes over the frame, so that tail
+                                // recursion runs in constant space
+                                SyxValue::Function(callee) if op == OpCode::TailCall => {
+                                    self.close_upvalues(base);
+                                    let ci = self.frames.pop().expect("no active frame");
+                                    if let Err(error) = self.enter(callee, ci.base, args) {
+                                        // the caller is still where it failed
+                                        self.frames.push(ci);
+                                        return Err(error);
+                                    }
+                                    let frame = self.frames.last_mut().expect("no active frame");
+                                    frame.ret = ci.ret;
+                                    frame.nresults = ci.nresults;
+                                    frame.from_lua = ci.from_lua;
+                                    frame.tailcall = true;
+                                    (closure, proto, base, pc, top) = self.frame_state();
+                                    self.hook_tail_call()?;
+                                }
+                                SyxValue::Function(callee) => {
+                                    self.save_frame_top(top);
+                                    let callee_base = self.stack.len();
+                                    self.enter(callee, callee_base, args)?;
+                                    let ci = self.frames.last_mut().expect("no active frame");
+                                    ci.ret = ra;
+                                    ci.nresults = wanted;
+                                    ci.from_lua = true;
+                                    (closure, proto, base, pc, top) = self.frame_state();
+                                    self.hook_call()?;
+                                }
+                                native if native == SyxValue::Native(coresume) => {
+                                    self.save_frame_top(top);
+                                    self.hook_call()?;
+                                    self.resume_from_loop(args, resumes)?;
+                                    (closure, proto, base, pc, top) = self.frame_state();
+                                }
+                                native => {
+                                    let results = self.call_native(native, args)?;
+                                    if let Some(values) = self.yielded.take() {
+                                        self.save_frame_top(top);
+                                        if *resumes == 0 {
+                                            return Ok(values.into());
+                                        }
+                                        // back to a resumer in this loop
+                                        *resumes -= 1;
+                                        self.switch_back(true);
+                                        self.finish_resume(true, values.into())?;
+                                        (closure, proto, base, pc, top) = self.frame_state();
+                                    } else {
+                                        top = self.place_results(ra, results, wanted);
+                                    }
+                                }
+                            }
+                        }
+                        OpCode::TForCall => {
+                            let func = self.stack[ra].clone();
+                            let args = self.stack[ra + 1..ra + 3].to_vec();
+                            let results = self.call_value(func, args)?;
+                            self.place_results(ra + 3, results, Some(c as usize));
+                        }
+                        OpCode::Return => {
+                            self.hook_return()?;
+                            let end = if b == 0 { top } else { ra + b as usize - 1 };
+                            let results: MultiValue = self.stack[ra..end].iter().cloned().collect();
+                            self.close_upvalues(base);
+                            let ci = self.frames.pop().expect("no active frame");
+                            self.stack.truncate(base);
+                            // the caller carries on at the line of its call
+                            if let Some(caller) = self.frames.last() {
+                                self.oldpc = caller.pc.saturating_sub(1);
+                            }
+                            if *resumes > 0 && self.frames.is_empty() {
+                                // a coroutine resumed in this loop returned
+                                *resumes -= 1;
+                                self.switch_back(false);
+                                self.finish_resume(true, results)?;
+                            } else if self.frames.len() == depth && *resumes == 0 {
+                                return Ok(results);
+                            } else {
+                                let caller_top = self.place_results(ci.ret, results, ci.nresults);
+                                self.save_frame_top(caller_top);
+                            }
+                            (closure, proto, base, pc, top) = self.frame_state();
+                        }
+                        OpCode::VarArg => {
+                            let varargs = &self.frames.last().expect("no active frame").varargs;
+                            let count = if b == 0 { varargs.len() } else { b as usize - 1 };
+                            if ra + count > self.max_stack {
+                                return runtime_error("stack overflow".to_owned());
+                            }
+                            let values: Vec<SyxValue> = (0..count)
+                                .map(|i| varargs.get(i).cloned().unwrap_or(SyxValue::Nil))
+                                .collect();
+                            for (i, value) in values.into_iter().enumerate() {
+                                self.set(ra + i, value);
+                            }
+                            top = ra + count;
+                        }
+                        OpCode::Check => {
+                            if self.check_preempt()? {
+                                self.save_frame_top(top);
+                                if *resumes == 0 {
+                                    return Ok(MultiValue::new());
+                                }
+                                *resumes -= 1;
+                                self.switch_back(true);
+                                self.finish_resume(true, MultiValue::new())?;
+                                (closure, proto, base, pc, top) = self.frame_state();
+                            }
+                        }
+                        _ => return runtime_error(format!("{:?} is not a valid ABC opcode", op)),
+                    }
+                }
+                Instruction::ABx { instruction: op, a, bx } => {
+                    let ra = base + a as usize;
+                    match op {
+                        OpCode::LoadK => {
+                            self.stack[ra] = proto.constants[bx as usize].clone();
+                        }
+                        OpCode::Closure => {
+                            let child = match proto.protos.get(bx as usize) {
+                                Some(child) => child.clone(),
+                                None => return runtime_error(format!("no function {}", bx)),
+                            };
+                            let mut upvalues = Vec::with_capacity(child.upvalues.len());
+                            for upvalue in &child.upvalues {
+                                upvalues.push(if upvalue.instack != 0 {
+                                    self.find_upvalue(base + upvalue.idx as usize)
+                                } else {
+                                    self.upvalue(closure, upvalue.idx as u16)?
+                                });
+                            }
+                            let function = self.new_closure(child, upvalues);
+                            self.stack[ra] = SyxValue::Function(function);
+                            self.check_gc()?;
+                        }
+                        _ => return runtime_error(format!("{:?} is not a valid ABx opcode", op)),
+                    }
+                }
+                Instruction::AsBx { instruction: op, a, sbx } => {
+                    let ra = base + a as usize;
+                    let jump = (pc as i64 + sbx as i64) as usize;
+                    match op {
+                        OpCode::Jmp => {
+                            if a != 0 {
+                                self.close_upvalues(ra - 1);
+                            }
+                            pc = jump;
+                        }
+                        OpCode::ForPrep => {
+                            // the body follows, and the ForLoop at `jump` ends it
+                            match for_prep(&mut self.stack[ra..ra + 3])? {
+                                Some(index) => self.stack[ra + 3] = index,
+                                None => pc = jump + 1,
+                            }
+                        }
+                        OpCode::ForLoop => {
+                            if let Some(index) = for_loop(&mut self.stack[ra..ra + 3]) {
+                                self.stack[ra + 3] = index;
+                                pc = jump;
+                            }
+                        }
+                        OpCode::TForLoop => {
+                            if let SyxValue::Nil = self.stack[ra + 1] {
+                            } else {
+                                self.stack[ra] = self.stack[ra + 1].clone();
+                                pc = jump;
+                            }
+                        }
+                        _ => return runtime_error(format!("{:?} is not a valid AsBx opcode", op)),
+                    }
+                }
+                Instruction::Ax { instruction: op, .. } => {
+                    return runtime_error(format!("unexpected {:?}", op));
+                }
+            }
+        }
+    }
+}
+
+fn arith_event(op: OpCode) -> TagMethod {
+    match op {
+        OpCode::Add => TagMethod::Add,
+        OpCode::Sub => TagMethod::Sub,
+        OpCode::Mul => TagMethod::Mul,
+        OpCode::Mod => TagMethod::Mod,
+        OpCode::Pow => TagMethod::Pow,
+        OpCode::Div => TagMethod::Div,
+        OpCode::IDiv => TagMethod::IDiv,
+        OpCode::BAnd => TagMethod::BAnd,
+        OpCode::BOr => TagMethod::BOr,
+        OpCode::BXOr => TagMethod::BXor,
+        OpCode::Shl => TagMethod::Shl,
+        _ => TagMethod::Shr,
+    }
+}
+
+fn tonumber(value: &SyxValue) -> Option<SyxNumber> {
+    match *value {
+        SyxValue::Integer(i) => Some(i as SyxNumber),
+        SyxValue::Number(n) => Some(n),
+        _ => None,
+    }
+}
+
+// The error for a bitwise operation on values without an integer value:
+// either one isn't a number at all, or it is a float with a fraction
+fn bitwise_error<T>(lhs: &SyxValue, rhs: &SyxValue) -> Result<T> {
+    match (lhs.coerce_to_number(None), rhs.coerce_to_number(None)) {
+        (Some(_), Some(_)) => runtime_error("number has no integer representation".to_owned()),
+        (None, _) => runtime_error(format!(
+            "attempt to perform bitwise operation on a {} value", lhs.type_name())),
+        (_, None) => runtime_error(format!(
+            "attempt to perform bitwise operation on a {} value", rhs.type_name())),
+    }
+}
+
+fn concatenable(value: &SyxValue) -> bool {
+    matches!(*value, SyxValue::String(_) | SyxValue::Integer(_) | SyxValue::Number(_))
+}
+
+// append strings and numbers as CONCAT sees them, false for anything else
+// bytes `value` takes when concatenated, at most for a number
+fn string_size(value: &SyxValue) -> usize {
+    match *value {
+        SyxValue::String(ref s) => s.len(),
+        _ => SYX_MAXNUMBER2STR,
+    }
+}
+
+pub(crate) fn append_string(buffer: &mut Vec<u8>, value: &SyxValue) -> bool {
+    match *value {
+        SyxValue::String(ref s) => buffer.extend_from_slice(s),
+        SyxValue::Integer(i) => buffer.extend(integer_to_string(i).into_bytes()),
+        SyxValue::Number(n) => buffer.extend(number_to_string(n).into_bytes()),
+        _ => return false,
+    }
+    true
+}
+
+fn shift_left(x: SyxInteger, y: SyxInteger) -> SyxInteger {
+    let bits = SyxInteger::BITS as SyxInteger;
+    if y <= -bits || y >= bits {
+        0
+    } else if y >= 0 {
+        ((x as SyxUnsigned) << y) as SyxInteger
+    } else {
+        ((x as SyxUnsigned) >> -y) as SyxInteger
+    }
+}
+
+pub(crate) fn arith(op: OpCode, lhs: &SyxValue, rhs: &SyxValue) -> Result<SyxValue> {
+    match op {
+        OpCode::BAnd | OpCode::BOr | OpCode::BXOr | OpCode::Shl | OpCode::Shr => {
+            // strings are converted too, and floats must have an integer value
+            let (x, y) = match (lhs.coerce_to_integer(), rhs.coerce_to_integer()) {
+                (Some(x), Some(y)) => (x, y),
+                _ => return bitwise_error(lhs, rhs),
+            };
+            return Ok(integer_value(match op {
+                OpCode::BAnd => x & y,
+                OpCode::BOr => x | y,
+                OpCode::BXOr => x ^ y,
+                OpCode::Shl => shift_left(x, y),
+                _ => shift_left(x, y.wrapping_neg()),
+            }));
+        }
+        _ => {}
+    }
+
+    // without integers, integers from the host are added as floats too
+    #[cfg(not(feature = "float_only"))]
+    if let (&SyxValue::Integer(x), &SyxValue::Integer(y)) = (lhs, rhs) {
+        match op {
+            OpCode::Add => return Ok(SyxValue::Integer(x.wrapping_add(y))),
+            OpCode::Sub => return Ok(SyxValue::Integer(x.wrapping_sub(y))),
+            OpCode::Mul => return Ok(SyxValue::Integer(x.wrapping_mul(y))),
+            OpCode::IDiv => {
+                if y == 0 {
+                    return runtime_error("attempt to perform 'n//0'".to_owned());
+                }
+                let q = x.wrapping_div(y);
+                let floor = x.wrapping_rem(y) != 0 && (x ^ y) < 0;
+                return Ok(SyxValue::Integer(if floor { q - 1 } else { q }));
+            }
+            OpCode::Mod => {
+                if y == 0 {
+                    return runtime_error("attempt to perform 'n%0'".to_owned());
+                }
+                let r = x.wrapping_rem(y);
+                let adjust = r != 0 && (r ^ y) < 0;
+                return Ok(SyxValue::Integer(if adjust { r + y } else { r }));
+            }
+            _ => {}
+        }
+    }
+
+    let (x, y) = match (tonumber(lhs), tonumber(rhs)) {
+        (Some(x), Some(y)) => (x, y),
+        // strings are converted following their syntax, so "10" + 1 is an
+        // integer and "10.0" + 1 a float
+        _ => match (lhs.coerce_to_number(None), rhs.coerce_to_number(None)) {
+            (Some(x), Some(y)) => return arith(op, &x, &y),
+            (None, _) => return runtime_error(format!(
+                "attempt to perform arithmetic on a {} value", lhs.type_name())),
+            (_, None) => return runtime_error(format!(
+                "attempt to perform arithmetic on a {} value", rhs.type_name())),
+        },
+    };
+    Ok(SyxValue::Number(match op {
+        OpCode::Add => x + y,
+        OpCode::Sub => x - y,
+        OpCode::Mul => x * y,
+        OpCode::Div => x / y,
+        OpCode::Pow => x.powf(y),
+        OpCode::IDiv => (x / y).floor(),
+        OpCode::Mod => {
+            let m = x % y;
+            if (m > 0.0 && y < 0.0) || (m < 0.0 && y > 0.0) { m + y } else { m }
+        }
+        _ => unreachable!("{:?} is not arithmetic", op),
+    }))
+}
+
+fn unary(state: &SyxState, op: OpCode, value: &SyxValue) -> Result<SyxValue> {
+    match (op, value) {
+        (OpCode::Not, _) => Ok(SyxValue::Bool(value.is_falsy())),
+        (OpCode::Unm, _) => match value.coerce_to_number(None) {
+            Some(SyxValue::Integer(i)) => Ok(integer_value(i.wrapping_neg())),
+            Some(SyxValue::Number(n)) => Ok(SyxValue::Number(-n)),
+            _ => runtime_error(format!(
+                "attempt to perform arithmetic on a {} value", value.type_name())),
+        },
+        (OpCode::BNot, _) => match value.coerce_to_integer() {
+            Some(i) => Ok(integer_value(!i)),
+            None => bitwise_error(value, value),
+        },
+        (OpCode::Len, SyxValue::String(s)) => Ok(integer_value(s.len() as SyxInteger)),
+        (OpCode::Len, &SyxValue::Table(t)) => Ok(integer_value(state.table(t).length())),
+        _ => runtime_error(format!("attempt to get length of a {} value", value.type_name())),
+    }
+}
+
+fn compare_error<T>(lhs: &SyxValue, rhs: &SyxValue) -> Result<T> {
+    let (a, b) = (lhs.type_name(), rhs.type_name());
+    if a == b {
+        runtime_error(format!("attempt to compare two {} values", a))
+    } else {
+        runtime_error(format!("attempt to compare {} with {}", a, b))
+    }
+}
+
+// How an integer compares to a float, exactly rather than by converting it,
+// None if the float is NaN
+fn compare_int_float(i: SyxInteger, f: SyxNumber) -> Option<Ordering> {
+    if f.is_nan() {
+        None
+    } else if f >= -(SyxInteger::MIN as SyxNumber) {
+        Some(Ordering::Less)
+    } else if f < SyxInteger::MIN as SyxNumber {
+        Some(Ordering::Greater)
+    } else {
+        let floor = f.floor();
+        match i.cmp(&(floor as SyxInteger)) {
+            Ordering::Equal if floor != f => Some(Ordering::Less),
+            ordering => Some(ordering),
+        }
+    }
+}
+
+// How two numbers or two strings compare, None for NaN
+fn compare(lhs: &SyxValue, rhs: &SyxValue) -> Result<Option<Ordering>> {
+    Ok(match (lhs, rhs) {
+        (&SyxValue::Integer(x), &SyxValue::Integer(y)) => Some(x.cmp(&y)),
+        (&SyxValue::Number(x), &SyxValue::Number(y)) => x.partial_cmp(&y),
+        (&SyxValue::Integer(x), &SyxValue::Number(y)) => compare_int_float(x, y),
+        (&SyxValue::Number(x), &SyxValue::Integer(y)) => {
+            compare_int_float(y, x).map(Ordering::reverse)
+        }
+        (SyxValue::String(x), SyxValue::String(y)) => Some(x.cmp(y)),
+        _ => return compare_error(lhs, rhs),
+    })
+}
+
+pub(crate) fn less_than(lhs: &SyxValue, rhs: &SyxValue) -> Result<bool> {
+    Ok(compare(lhs, rhs)? == Some(Ordering::Less))
+}
+
+fn less_equal(lhs: &SyxValue, rhs: &SyxValue) -> Result<bool> {
+    Ok(matches!(compare(lhs, rhs)?, Some(Ordering::Less | Ordering::Equal)))
+}
+
+impl SyxValue {
+    // Equality as `==` sees it, calling __eq if needed, whereas comparing
+    // with `==` in Rust is raw equality
+    pub fn lua_eq(&self, other: &SyxValue, state: &mut SyxState) -> Result<bool> {
+        state.equal(self, other)
+    }
+}
+
+// The integer limit of a loop with integer initial value and step, as
+// forlimit has it: a float limit is floored, or ceiled when counting down,
+// and one out of the integer range is clipped. None when the loop can't run
+// at all, such as counting up to a limit below the smallest integer.
+fn for_limit(limit: &SyxValue, step: SyxInteger) -> Result<Option<SyxInteger>> {
+    let n = match *limit {
+        SyxValue::Integer(limit) => return Ok(Some(limit)),
+        SyxValue::Number(n) => n,
+        _ => return runtime_error("'for' limit must be a number".to_owned()),
+    };
+    let n = if step < 0 { n.ceil() } else { n.floor() };
+    if n.is_nan() {
+        return Ok(None);
+    }
+    Ok(match float_to_integer(n) {
+        Some(limit) => Some(limit),
+        None if n > 0.0 => if step < 0 { None } else { Some(SyxInteger::MAX) },
+        None => if step < 0 { Some(SyxInteger::MIN) } else { None },
+    })
+}
+
+// Prepare R(A), R(A+1) and R(A+2) for the loop, returning the first value
+// of the control variable, or None when the loop doesn't run. The loop is
+// an integer one when the initial value and step are integers, and a float
+// one otherwise.
+fn for_prep(slots: &mut [SyxValue]) -> Result<Option<SyxValue>> {
+    if let (&SyxValue::Integer(init), &SyxValue::Integer(step)) = (&slots[0], &slots[2]) {
+        if step == 0 {
+            return runtime_error("'for' step is zero".to_owned());
+        }
+        let limit = match for_limit(&slots[1], step)? {
+            Some(limit) => limit,
+            None => return Ok(None),
+        };
+        if if step > 0 { init > limit } else { init < limit } {
+            return Ok(None);
+        }
+        slots[1] = SyxValue::Integer(limit);
+        return Ok(Some(SyxValue::Integer(init)));
+    }
+    let mut numbers = [0.0; 3];
+    for &(i, what) in &[(1, "limit"), (2, "step"), (0, "initial value")] {
+        numbers[i] = match tonumber(&slots[i]) {
+            Some(n) => n,
+            None => return runtime_error(format!("'for' {} must be a number", what)),
+        };
+    }
+    let [init, limit, step] = numbers;
+    if step == 0.0 {
+        return runtime_error("'for' step is zero".to_owned());
+    }
+    slots[0] = SyxValue::Number(init);
+    slots[1] = SyxValue::Number(limit);
+    slots[2] = SyxValue::Number(step);
+    let more = if step > 0.0 { init <= limit } else { limit <= init };
+    Ok(if more { Some(SyxValue::Number(init)) } else { None })
+}
+
+// R(A) += R(A+2), returning the new index if the loop continues. An integer
+// index that would overflow ends the loop instead of wrapping around.
+fn for_loop(slots: &mut [SyxValue]) -> Option<SyxValue> {
+    let index = match (&slots[0], &slots[1], &slots[2]) {
+        (&SyxValue::Integer(index), &SyxValue::Integer(limit), &SyxValue::Integer(step)) => {
+            let index = index.checked_add(step)?;
+            let more = if step > 0 { index <= limit } else { limit <= index };
+            if !more {
+                return None;
+            }
+            SyxValue::Integer(index)
+        }
+        (&SyxValue::Number(index), &SyxValue::Number(limit), &SyxValue::Number(step)) => {
+            let index = index + step;
+            let more = if step > 0.0 { index <= limit } else { limit <= index };
+            if !more {
+                return None;
+            }
+            SyxValue::Number(index)
+        }
+        _ => return None,
+    };
+    slots[0] = index.clone();
+    Some(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use super::super::object::Upvalue;
+    use super::super::stdlib::set_field;
+    use super::super::testing::{abc, abx, asbx, run_source};
+
+    fn run(instructions: Vec<Instruction>, constants: Vec<SyxValue>, args: Vec<SyxValue>)
+        -> Result<MultiValue>
+    {
+        let mut proto = Proto::new();
+        proto.maxstacksize = 8;
+        proto.is_vararg = 1;
+        proto.instructions = instructions;
+        proto.constants = constants;
+        SyxState::new().call(Arc::new(proto), args)
+    }
+
+    #[test]
+    #[cfg(not(feature = "int32"))] // 64-bit integers
+    fn test_arithmetic() {
+        // return 7 // 2, 7 / 2, -7 % 3, 1 << 62 >> 61
+        let results = run(vec![
+            abc(OpCode::IDiv, 0, 256, 257),
+            abc(OpCode::Div, 1, 256, 257),
+            abc(OpCode::Unm, 2, 256, 0),
+            abc(OpCode::Mod, 2, 2, 258),
+            abc(OpCode::Shl, 3, 259, 260),
+            abc(OpCode::Shr, 3, 3, 261),
+            abc(OpCode::Return, 0, 5, 0),
+        ], vec![
+            SyxValue::Integer(7), SyxValue::Integer(2), SyxValue::Integer(3),
+            SyxValue::Integer(1), SyxValue::Integer(62), SyxValue::Integer(61),
+        ], vec![]).unwrap();
+        assert_eq!(results, vec![
+            SyxValue::Integer(3), SyxValue::Number(3.5),
+            SyxValue::Integer(2), SyxValue::Integer(2),
+        ]);
+    }
+
+    #[test]
+    #[cfg(not(feature = "float_only"))] // integer arithmetic
+    fn test_arithmetic_semantics() {
+        let results = run_source("
+            local max, min, a, b, z = math.maxinteger, math.mininteger, 7, -2, 0
+            return max + 1 == min, min // -1, min % -1, a // b, a % b, -a % b,
+                   7.5 // -2, 7.5 % -2, a / b, 2 ^ 2, 1 + 1.0,
+                   '10' + 1, '0x10' * '2', '1e1' - 1, -'2', a % -math.huge,
+                   z / 0 ~= z / 0
+        ").unwrap();
+        assert_eq!(results, vec![
+            SyxValue::Bool(true), SyxValue::Integer(SyxInteger::MIN), SyxValue::Integer(0),
+            SyxValue::Integer(-4), SyxValue::Integer(-1), SyxValue::Integer(-1),
+            SyxValue::Number(-4.0), SyxValue::Number(-0.5), SyxValue::Number(-3.5),
+            SyxValue::Number(4.0), SyxValue::Number(2.0),
+            SyxValue::Integer(11), SyxValue::Integer(32), SyxValue::Number(9.0),
+            SyxValue::Integer(-2), SyxValue::Number(SyxNumber::NEG_INFINITY),
+            SyxValue::Bool(true),
+        ]);
+        let error = |source: &str| run_source(source).unwrap_err().to_string();
+        assert_eq!(error("local z = 0 return 1 % z"), "test:1: attempt to perform 'n%0'");
+        assert_eq!(error("local z = 0 return 1 // z"), "test:1: attempt to perform 'n//0'");
+        assert_eq!(error("return '10' + {}"),
+                   "test:1: attempt to perform arithmetic on a table value");
+        assert_eq!(error("return 'x' * 2"),
+                   "test:1: attempt to perform arithmetic on a string value");
+    }
+
+    #[test]
+    #[cfg(not(feature = "float_only"))] // integer loops
+    fn test_for_semantics() {
+        let results = run_source("
+            local max, min, count = math.maxinteger, math.mininteger, 0
+            for i = max - 2, max do count = count + 1 end
+            for i = min + 1, min, -1 do count = count + 1 end
+            for i = max - 1, 1e100 do count = count + 1 end
+            for i = 1, -1e100 do count = count + 1 end
+            for i = 1, 0/0 do count = count + 1 end
+            for i = 3, 1 do count = count + 1 end
+            local last, float
+            for i = 1, 3.5 do last = i end
+            for i = 1, 2, 0.5 do float = i end
+            return count, last, math.type(last), float, math.type(float)
+        ").unwrap();
+        assert_eq!(results, vec![
+            SyxValue::Integer(7), SyxValue::Integer(3), SyxValue::from("integer"),
+            SyxValue::Number(2.0), SyxValue::from("float"),
+        ]);
+        let error = |source: &str| run_source(source).unwrap_err().to_string();
+        assert_eq!(error("for i = 1, 10, 0 do end"), "test:1: 'for' step is zero");
+        assert_eq!(error("for i = 1.0, 10, 0 do end"), "test:1: 'for' step is zero");
+        assert_eq!(error("for i = 1, 'x' do end"), "test:1: 'for' limit must be a number");
+        assert_eq!(error("for i = 1, 2, {} do end"), "test:1: 'for' step must be a number");
+        assert_eq!(error("for i = {}, 2 do end"),
+                   "test:1: 'for' initial value must be a number");
+    }
+
+    #[test]
+    #[cfg(not(feature = "int32"))] // 64-bit integers
+    fn test_bitwise_semantics() {
+        let results = run_source("
+            local big, x, f = 1 << 63, 0xF0, 2.0
+            local mt = {__band = function(a, b) return 'band' end,
+                        __shl = function(a, b) return 'shl' end,
+                        __bnot = function(a) return 'bnot' end}
+            local t = setmetatable({}, mt)
+            return x & 0x3C, x | f, x ~ '0xFF', ~x, 1 << 64, big >> 63, -1 >> 60,
+                   1 << -1, 8 >> -1, t & 1.5, 1 << t, ~t
+        ").unwrap();
+        assert_eq!(results, vec![
+            SyxValue::Integer(0x30), SyxValue::Integer(0xF2), SyxValue::Integer(0x0F),
+            SyxValue::Integer(!0xF0), SyxValue::Integer(0), SyxValue::Integer(1),
+            SyxValue::Integer(0xF), SyxValue::Integer(0), SyxValue::Integer(16),
+            "band".into(), "shl".into(), "bnot".into(),
+        ]);
+        let error = |source: &str| run_source(source).unwrap_err().to_string();
+        assert_eq!(error("local f = 1.5 return f | 1"),
+                   "test:1: number has no integer representation");
+        assert_eq!(error("local f = 1.5 return ~f"),
+                   "test:1: number has no integer representation");
+        assert_eq!(error("local f = 1.5 return f & {}"),
+                   "test:1: attempt to perform bitwise operation on a table value");
+        assert_eq!(error("return 1 ~ 'x'"),
+                   "test:1: attempt to perform bitwise operation on a string value");
+    }
+
+    #[test]
+    fn test_integer_conversion() {
+        // floats with an integer value, and numerals, convert the same way
+        // for bitwise operators, integer arguments and table keys
+        let results = run_source("
+            local t = {}
+            t[2.0], t[3] = 'two', 'three'
+            return '3.0' | 0, ~-1.0, string.format('%d', 3.0), string.format('%x', '0x10'),
+                   math.tointeger('8'), ('x'):rep(2.0), t[2], t[3.0], next({[1.0] = true}),
+                   7 // 2.0, -7 // 2, 7 % -2.0, -7 % 2, 5.5 % -2, 1 // 0.0
+        ").unwrap();
+        assert_eq!(results, vec![
+            SyxValue::Integer(3), SyxValue::Integer(0), SyxValue::from("3"),
+            SyxValue::from("10"), SyxValue::Integer(8), SyxValue::from("xx"),
+            SyxValue::from("two"), SyxValue::from("three"), SyxValue::Integer(1),
+            SyxValue::Number(3.0), SyxValue::Integer(-4), SyxValue::Number(-1.0),
+            SyxValue::Integer(1), SyxValue::Number(-0.5), SyxValue::Number(SyxNumber::INFINITY),
+        ]);
+        let error = |source: &str| run_source(source).unwrap_err().to_string();
+        assert_eq!(error("return '1.5' | 0"), "test:1: number has no integer representation");
+        assert_eq!(error("return string.format('%d', 3.5)"),
+                   "test:1: bad argument #2 to 'format' (number has no integer representation)");
+        assert_eq!(error("return ('x'):rep('2.5')"),
+                   "test:1: bad argument #2 to 'rep' (number has no integer representation)");
+    }
+
+    #[test]
+    #[cfg(feature = "float_only")]
+    fn test_float_only() {
+        let results = run_source("
+            local t = {10, 20}
+            return 7 // 2, 5 | 2, #t, select('#', 1, 2), math.type(1), 1 .. '', 2^53 + 1 == 2^53,
+                string.format('%d', 3), tostring(-0.0), 1 / 2
+        ").unwrap().into_vec();
+        let numbers = [3.0, 7.0, 2.0, 2.0];
+        for (result, &n) in results.iter().zip(&numbers) {
+            assert!(matches!(*result, SyxValue::Number(x) if x == n), "{:?}", result);
+        }
+        assert_eq!(results[numbers.len()..], [
+            SyxValue::from("float"), SyxValue::from("1"), SyxValue::Bool(true),
+            SyxValue::from("3"), SyxValue::from("-0"), SyxValue::Number(0.5),
+        ]);
+    }
+
+    #[test]
+    #[cfg(not(any(feature = "int32", feature = "float_only")))] // 64-bit integers
+    fn test_comparison_semantics() {
+        let results = run_source("
+            local big, nan = 2^53, 0/0
+            local mt = {
+                __eq = function(a, b) return a.v == b.v end,
+                __lt = function(a, b) return a.v < b.v end,
+            }
+            local x, y = setmetatable({v = 1}, mt), setmetatable({v = 1}, mt)
+            local z = setmetatable({v = 2}, {__le = function() return 'yes' end})
+            return big + 1 == big, (1 << 53) + 1 > big, math.maxinteger < 2^63,
+                   math.mininteger <= -2^63, 1 < nan or nan <= 1, 1 == 1.0,
+                   x == y, x ~= y, x < y, x <= y, x <= z, 'a' < 'b', x == 1
+        ").unwrap();
+        let bools = [true, true, true, true, false, true,
+                     true, false, false, true, true, true, false];
+        assert_eq!(results, bools.iter().map(|&b| SyxValue::Bool(b)).collect::<Vec<_>>());
+        let error = |source: &str| run_source(source).unwrap_err().to_string();
+        assert_eq!(error("return {} < {}"), "test:1: attempt to compare two table values");
+        assert_eq!(error("return 1 <= '2'"), "test:1: attempt to compare number with string");
+
+        let mut state = SyxState::new();
+        let (a, b) = (SyxValue::Integer(3), SyxValue::Number(3.0));
+        assert!(a.lua_eq(&b, &mut state).unwrap());
+        assert!(!a.lua_eq(&SyxValue::from("3"), &mut state).unwrap());
+    }
+
+    #[test]
+    fn test_concat_semantics() {
+        let results = run_source("
+            local log = {}
+            local mt = {__concat = function(a, b)
+                local x = type(a) == 'table' and a.name or a
+                local y = type(b) == 'table' and b.name or b
+                log[#log + 1] = x .. '+' .. y
+                return x .. y
+            end}
+            local t = setmetatable({name = 'T'}, mt)
+            return 'a' .. 1 .. 2.5, 'a' .. 'b' .. t .. 'c' .. 'd', table.concat(log, ' ')
+        ").unwrap();
+        assert_eq!(results, vec!["a12.5".into(), "abTcd".into(), "T+cd".into()]);
+        assert_eq!(run_source("return 'a' .. {} .. 'b'").unwrap_err().to_string(),
+                   "test:1: attempt to concatenate a table value");
+        assert_eq!(run_source("return 1 .. nil").unwrap_err().to_string(),
+                   "test:1: attempt to concatenate a nil value");
+    }
+
+    #[test]
+    fn test_field_cache() {
+        let results = run_source("
+            x = 1
+            local t = {k = 'a'}
+            local seen = {}
+            for i = 1, 3 do
+                seen[#seen + 1] = x .. t.k
+                x = x + 1
+                if i == 2 then t.k = 'b' end
+            end
+            setmetatable(t, {__index = function() return 'index' end})
+            for i = 1, 2 do
+                seen[#seen + 1] = t.k
+                t.k = nil
+            end
+            return table.concat(seen, ' ')
+        ").unwrap();
+        assert_eq!(results, vec!["1a 2a 3b b index".into()]);
+
+        // entries cleared by the collector are not read from the cache
+        let mut state = SyxState::new();
+        super::super::stdlib::open_libs(&mut state);
+        let run = |state: &mut SyxState, source: &str| {
+            let proto = super::super::compiler::parse(source.as_bytes(), "=test").unwrap();
+            state.call(Arc::new(proto), vec![]).unwrap().into_first()
+        };
+        run(&mut state, "w = setmetatable({k = {}}, {__mode = 'v'})
+                         function get() return type(w.k) end");
+        assert_eq!(run(&mut state, "return get()"), "table".into());
+        state.collect_garbage();
+        assert_eq!(run(&mut state, "return get()"), "nil".into());
+    }
+
+    #[test]
+    fn test_numeric_for() {
+        // local s = 0; for i = 1, 10 do s = s + i end; return s
+        let results = run(vec![
+            Instruction::ABx { instruction: OpCode::LoadK, a: 0, bx: 0 },
+            Instruction::ABx { instruction: OpCode::LoadK, a: 1, bx: 1 },
+            Instruction::ABx { instruction: OpCode::LoadK, a: 2, bx: 2 },
+            Instruction::ABx { instruction: OpCode::LoadK, a: 3, bx: 1 },
+            asbx(OpCode::ForPrep, 1, 1),
+            abc(OpCode::Add, 0, 0, 4),
+            asbx(OpCode::ForLoop, 1, -2),
+            abc(OpCode::Return, 0, 2, 0),
+        ], vec![
+            SyxValue::Integer(0), SyxValue::Integer(1), SyxValue::Integer(10),
+        ], vec![]).unwrap();
+        assert_eq!(results, vec![SyxValue::Integer(55)]);
+    }
+
+    #[test]
+    fn test_compare_and_concat() {
+        // local a, b = ...; if a < b then return "x" .. a .. b end; return ...
+        let results = run(vec![
+            abc(OpCode::VarArg, 0, 3, 0),
+            abc(OpCode::Lt, 0, 0, 1),
+            asbx(OpCode::Jmp, 0, 5),
+            Instruction::ABx { instruction: OpCode::LoadK, a: 2, bx: 0 },
+            abc(OpCode::Move, 3, 0, 0),
+            abc(OpCode::Move, 4, 1, 0),
+            abc(OpCode::Concat, 2, 2, 4),
+            abc(OpCode::Return, 2, 2, 0),
+            abc(OpCode::VarArg, 2, 0, 0),
+            abc(OpCode::Return, 2, 0, 0),
+        ], vec![SyxValue::String("x".into())],
+           vec![SyxValue::Integer(1), SyxValue::Number(2.5)]).unwrap();
+        assert_eq!(results, vec![SyxValue::String("x12.5".into())]);
+
+        let results = run(vec![
+            abc(OpCode::VarArg, 0, 3, 0),
+            abc(OpCode::Lt, 0, 0, 1),
+            asbx(OpCode::Jmp, 0, 5),
+            Instruction::ABx { instruction: OpCode::LoadK, a: 2, bx: 0 },
+            abc(OpCode::Move, 3, 0, 0),
+            abc(OpCode::Move, 4, 1, 0),
+            abc(OpCode::Concat, 2, 2, 4),
+            abc(OpCode::Return, 2, 2, 0),
+            abc(OpCode::VarArg, 2, 0, 0),
+            abc(OpCode::Return, 2, 0, 0),
+        ], vec![SyxValue::String("x".into())],
+           vec![SyxValue::Integer(3), SyxValue::Integer(2), SyxValue::Bool(true)]).unwrap();
+        assert_eq!(results, vec![
+            SyxValue::Integer(3), SyxValue::Integer(2), SyxValue::Bool(true),
+        ]);
+    }
+
+    #[test]
+    fn test_varargs() {
+        let results = run_source("
+            local function count(...) return select('#', ...) end
+            local function pass(a, ...) return a, ... end
+            local function fixed(a, b) return b end
+            local function last(...) return select(-1, ...) end
+            return count(), count(nil, nil), select('#', pass(1, 2, nil)), fixed(1, 2, 3),
+                last(4, 5, 6), (pass(7, 8))
+        ").unwrap();
+        assert_eq!(results, vec![
+            SyxValue::Integer(0), SyxValue::Integer(2), SyxValue::Integer(3),
+            SyxValue::Integer(2), SyxValue::Integer(6), SyxValue::Integer(7),
+        ]);
+
+        // extra arguments to a function without "..." are dropped
+        let mut state = SyxState::new();
+        let mut proto = Proto::new();
+        proto.numparams = 1;
+        proto.maxstacksize = 2;
+        proto.instructions = vec![abc(OpCode::Return, 0, 2, 0)];
+        let closure = state.new_main_closure(Arc::new(proto));
+        state.enter(closure, 0, vec![SyxValue::Integer(1), SyxValue::Integer(2)]).unwrap();
+        assert!(state.frames.last().unwrap().varargs.is_empty());
+    }
+
+    #[test]
+    fn test_tail_calls() {
+        let source = "
+            local function even(n) if n == 0 then return depth() end return odd(n - 1) end
+            function odd(n) if n == 0 then return false end return even(n - 1) end
+            local function count(n, total)
+                if n == 0 then return total, depth() end
+                return count(n - 1, total + 1)
+            end
+            return even(1000000), count(1000000, 0)
+        ";
+        let proto = super::super::compiler::parse(source.as_bytes(), "=test").unwrap();
+        let mut state = SyxState::new();
+        let depth = state.create_function("depth", |state, _| {
+            Ok(vec![SyxValue::Integer(state.frames.len() as SyxInteger)].into())
+        });
+        let globals = state.globals();
+        super::super::stdlib::set_field(&mut state, globals, "depth", depth);
+        let results = state.call(Arc::new(proto), vec![]).unwrap();
+        // the main chunk and the last function called
+        assert_eq!(results, vec![
+            SyxValue::Integer(2), SyxValue::Integer(1000000), SyxValue::Integer(2),
+        ]);
+
+        let error = run_source("
+            local function fail() error('x') end
+            local function f() return fail() end
+            local ok, message = xpcall(f, debug.traceback)
+            return message
+        ").unwrap().into_vec();
+        assert_eq!(error, vec![SyxValue::from("test:2: x
+stack traceback:
+\ttest:2: in function <test:2>
+\t(...tail calls...)
+\ttest:4: in main chunk")]);
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        let error = run_source("local function f() return 1 + f() end return f()").unwrap_err();
+        assert_eq!(error.to_string(), "test:1: stack overflow");
+        // the message handler has room to run
+        let results = run_source("
+            local function f(...) return 1 + f(...) end
+            return xpcall(f, function(message) return 'handled: ' .. message end, 1, 2)
+        ").unwrap();
+        assert_eq!(results, vec![
+            SyxValue::Bool(false), SyxValue::from("handled: test:2: stack overflow"),
+        ]);
+
+        let proto = super::super::compiler::parse(
+            b"local function f(n) if n > 0 then return 1 + f(n - 1) end return 0 end \
+              return f(100)", "=test").unwrap();
+        let proto = Arc::new(proto);
+        let mut state = SyxState::new();
+        state.set_max_stack(100);
+        assert_eq!(state.call(proto.clone(), vec![]).unwrap_err().to_string(),
+                   "test:1: stack overflow");
+        assert!(state.stack.is_empty() && state.frames.is_empty());
+        state.set_max_stack(1000);
+        assert_eq!(state.call(proto, vec![]).unwrap(), vec![SyxValue::Integer(100)]);
+
+        // calls from Rust still nest on its stack, so they are limited too;
+        // unoptimized builds need room for all of them
+        let worker = thread::Builder::new().stack_size(64 << 20).spawn(|| {
+            run_source("
+                local depth = 0
+                local function f() depth = depth + 1; return select(2, pcall(f)) end
+                local t = setmetatable({}, {__index = function(t, k) return t[k] end})
+                return f(), depth, pcall(function() return t.x end)
+            ").unwrap()
+        }).unwrap();
+        assert_eq!(worker.join().unwrap(), vec![
+            SyxValue::from("C stack overflow"), SyxValue::Integer(200),
+            SyxValue::Bool(false), SyxValue::from("test:4: C stack overflow"),
+        ]);
+    }
+
+    #[test]
+    fn test_load_and_call() {
+        let mut state = SyxState::new();
+        super::super::stdlib::open_libs(&mut state);
+        let results = state.load_and_call(b"x = 1 return x, type(print)".to_vec(), "=test", "bt");
+        assert_eq!(results.unwrap(), vec![SyxValue::Integer(1), SyxValue::from("function")]);
+        let globals = state.globals();
+        assert!(state.table(globals).get(&SyxValue::from("x")).is_nil());
+
+        let proto = super::super::compiler::parse(b"return ...", "=test").unwrap();
+        let binary = super::super::dump::DumpState::to_u8(&proto).unwrap();
+        assert_eq!(state.load_and_call(binary.clone(), "=test", "b").unwrap(), vec![]);
+        match state.load_and_call(binary, "=test", "t") {
+            Err(Error(ErrorKind::ChunkModeError("binary", ref mode), _)) if mode == "t" => {}
+            result => panic!("unexpected {:?}", result.map(|_| ())),
+        }
+        let error = state.load_and_call(b"return".to_vec(), "=test", "b").unwrap_err();
+        assert_eq!(error.to_string(), "attempt to load a text chunk (mode is 'b')");
+        let error = state.load_and_call(b"return return".to_vec(), "=test", "t").unwrap_err();
+        assert!(matches!(*error.kind(), ErrorKind::SyntaxError(_)));
+        let error = state.load_and_call(b"error('boom')".to_vec(), "=test", "t").unwrap_err();
+        assert_eq!(error.to_string(), "test:1: boom");
+    }
+
+    #[test]
+    fn test_env() {
+        let mut state = SyxState::new();
+        super::super::stdlib::open_libs(&mut state);
+        let source = b"
+            count = (count or 0) + 1
+            function get() return count end
+            local function sandboxed() local _ENV = {}; x = 1; return x, print end
+            local sx, sprint = sandboxed()
+            return count, type(print), _ENV.count, sx, sprint";
+        let proto = Arc::new(super::super::compiler::parse(source, "=test").unwrap());
+        let (first, second) = (state.new_table(0, 0), state.new_table(0, 0));
+        let globals = state.globals();
+        let type_ = state.table(globals).get(&SyxValue::from("type"));
+        set_field(&mut state, second, "count", SyxValue::Integer(10));
+        set_field(&mut state, second, "type", type_);
+        state.push_value(SyxValue::Table(first));
+        state.push_value(SyxValue::Table(second));
+        for _ in 0..2 {
+            let results = state.call_with_env(proto.clone(), SyxValue::Table(first), vec![]);
+            assert_eq!(results.unwrap_err().to_string(), "test:6: attempt to call a nil value");
+        }
+        assert_eq!(state.table(first).get(&SyxValue::from("count")), SyxValue::Integer(2));
+        let results = state.call_with_env(proto.clone(), SyxValue::Table(second), vec![]);
+        assert_eq!(results.unwrap(), vec![
+            SyxValue::Integer(11), SyxValue::from("nil"), SyxValue::Integer(11),
+            SyxValue::Integer(1), SyxValue::Nil,
+        ]);
+        // functions keep the environment they were defined in
+        let get = state.table(first).get(&SyxValue::from("get"));
+        assert_eq!(state.call_value(get, vec![]).unwrap(), vec![SyxValue::Integer(2)]);
+        assert!(state.table(globals).get(&SyxValue::from("count")).is_nil());
+        assert!(state.table(globals).get(&SyxValue::from("x")).is_nil());
+    }
+
+    #[test]
+    fn test_table_constructor() {
+        // local t = {...}; t.n = #t; return t[2], t.n, t
+        let mut state = SyxState::new();
+        let mut proto = Proto::new();
+        proto.maxstacksize = 8;
+        proto.is_vararg = 1;
+        proto.constants = vec![SyxValue::String("n".into()), SyxValue::Integer(2)];
+        proto.instructions = vec![
+            abc(OpCode::NewTable, 0, 0, 0),
+            abc(OpCode::VarArg, 1, 0, 0),
+            abc(OpCode::SetList, 0, 0, 1),
+            abc(OpCode::Len, 1, 0, 0),
+            abc(OpCode::SetTable, 0, 256, 1),
+            abc(OpCode::GetTable, 1, 0, 257),
+            abc(OpCode::GetTable, 2, 0, 256),
+            abc(OpCode::Move, 3, 0, 0),
+            abc(OpCode::Return, 1, 4, 0),
+        ];
+        let args = vec![SyxValue::Integer(10), SyxValue::Integer(20), SyxValue::Integer(30)];
+        let results = state.call(Arc::new(proto), args).unwrap();
+        assert_eq!(results[..2], [SyxValue::Integer(20), SyxValue::Integer(3)]);
+        match results[2] {
+            SyxValue::Table(t) => assert_eq!(state.table(t).length(), 3),
+            _ => panic!("expected a table"),
+        }
+    }
+
+    fn key(name: &str) -> SyxValue {
+        SyxValue::String(name.into())
+    }
+
+    #[test]
+    fn test_metatables() {
+        // local p, t = ...; p.y = 2; return p.x, t.y
+        let mut state = SyxState::new();
+        let (proxy, target, meta) = (state.new_table(0, 0), state.new_table(0, 0),
+                                     state.new_table(0, 0));
+        state.table_mut(target).set(key("x"), SyxValue::Integer(1)).unwrap();
+        state.table_mut(meta).set(key("__index"), SyxValue::Table(target)).unwrap();
+        state.table_mut(meta).set(key("__newindex"), SyxValue::Table(target)).unwrap();
+        state.table_mut(proxy).set_metatable(Some(meta));
+
+        let mut proto = Proto::new();
+        proto.maxstacksize = 4;
+        proto.is_vararg = 1;
+        proto.constants = vec![key("x"), key("y"), SyxValue::Integer(2)];
+        proto.instructions = vec![
+            abc(OpCode::VarArg, 0, 3, 0),
+            abc(OpCode::SetTable, 0, 257, 258),
+            abc(OpCode::GetTable, 2, 0, 256),
+            abc(OpCode::GetTable, 3, 1, 257),
+            abc(OpCode::Return, 2, 3, 0),
+        ];
+        let proto = Arc::new(proto);
+        let args = vec![SyxValue::Table(proxy), SyxValue::Table(target)];
+        let results = state.call(proto.clone(), args).unwrap();
+        assert_eq!(results, vec![SyxValue::Integer(1), SyxValue::Integer(2)]);
+        assert!(state.table(proxy).get(&key("y")).is_nil());
+
+        // a table that is its own __index never finds anything
+        state.table_mut(meta).set(key("__index"), SyxValue::Table(proxy)).unwrap();
+        state.set_max_tag_loop(10);
+        let args = vec![SyxValue::Table(proxy), SyxValue::Table(target)];
+        let error = state.call(proto, args).unwrap_err();
+        assert_eq!(error.to_string(), "'__index' chain too long; possible loop");
+
+        // handlers that can't be called
+        state.table_mut(meta).set(key("__add"), SyxValue::Table(proxy)).unwrap();
+        let mut proto = Proto::new();
+        proto.maxstacksize = 2;
+        proto.is_vararg = 1;
+        proto.constants = vec![SyxValue::Integer(1)];
+        proto.instructions = vec![
+            abc(OpCode::VarArg, 0, 2, 0),
+            abc(OpCode::Add, 1, 256, 0),
+        ];
+        let error = state.call(Arc::new(proto), vec![SyxValue::Table(proxy)]).unwrap_err();
+        assert_eq!(error.to_string(), "attempt to call a table value");
+    }
+
+    fn capture(instack: u8, idx: u8) -> Upvalue {
+        Upvalue { name: "".into(), instack, idx }
+    }
+
+    #[test]
+    fn test_closures() {
+        // local function counter()
+        //     local n = 0
+        //     return function() n = n + 1; return n end
+        // end
+        // local c = counter(); c(); return c()
+        let mut inner = Proto::new();
+        inner.maxstacksize = 2;
+        inner.upvalues = vec![capture(1, 0)];
+        inner.constants = vec![SyxValue::Integer(1)];
+        inner.instructions = vec![
+            abc(OpCode::GetUpval, 0, 0, 0),
+            abc(OpCode::Add, 0, 0, 256),
+            abc(OpCode::SetUpval, 0, 0, 0),
+            abc(OpCode::Return, 0, 2, 0),
+        ];
+        let mut counter = Proto::new();
+        counter.maxstacksize = 2;
+        counter.constants = vec![SyxValue::Integer(0)];
+        counter.protos = vec![Arc::new(inner)];
+        counter.instructions = vec![
+            abx(OpCode::LoadK, 0, 0),
+            abx(OpCode::Closure, 1, 0),
+            abc(OpCode::Return, 1, 2, 0),
+        ];
+        let mut main = Proto::new();
+        main.maxstacksize = 4;
+        main.protos = vec![Arc::new(counter)];
+        main.instructions = vec![
+            abx(OpCode::Closure, 0, 0),
+            abc(OpCode::Call, 0, 1, 2),
+            abc(OpCode::Move, 1, 0, 0),
+            abc(OpCode::Call, 1, 1, 1),
+            abc(OpCode::Move, 1, 0, 0),
+            abc(OpCode::Call, 1, 1, 2),
+            abc(OpCode::Return, 1, 2, 0),
+        ];
+        let results = SyxState::new().call(Arc::new(main), vec![]).unwrap();
+        assert_eq!(results, vec![SyxValue::Integer(2)]);
+    }
+
+    #[test]
+    fn test_shared_upvalues() {
+        // local x = 10; local get = function() return x end
+        // x = 20; local a = get()
+        // do ... end closes x, later writes to its register are not seen
+        let mut getter = Proto::new();
+        getter.maxstacksize = 1;
+        getter.upvalues = vec![capture(1, 0)];
+        getter.instructions = vec![
+            abc(OpCode::GetUpval, 0, 0, 0),
+            abc(OpCode::Return, 0, 2, 0),
+        ];
+        let mut main = Proto::new();
+        main.maxstacksize = 5;
+        main.protos = vec![Arc::new(getter)];
+        main.constants = vec![
+            SyxValue::Integer(10), SyxValue::Integer(20), SyxValue::Integer(30),
+        ];
+        main.instructions = vec![
+            abx(OpCode::LoadK, 0, 0),
+            abx(OpCode::Closure, 1, 0),
+            abx(OpCode::LoadK, 0, 1),
+            abc(OpCode::Move, 2, 1, 0),
+            abc(OpCode::Call, 2, 1, 2),
+            asbx(OpCode::Jmp, 1, 0),
+            abx(OpCode::LoadK, 0, 2),
+            abc(OpCode::Move, 3, 1, 0),
+            abc(OpCode::Call, 3, 1, 2),
+            abc(OpCode::Return, 2, 3, 0),
+        ];
+        let mut state = SyxState::new();
+        let results = state.call(Arc::new(main), vec![]).unwrap();
+        assert_eq!(results, vec![SyxValue::Integer(20), SyxValue::Integer(20)]);
+        assert!(state.open_upvalues.is_empty());
+    }
+
+    #[test]
+    fn test_count_hook() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let proto = super::super::compiler::parse(b"while true do end", "=loop").unwrap();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let mut state = SyxState::new();
+        state.set_count_hook(100, move |_| {
+            if counted.fetch_add(1, Ordering::Relaxed) + 1 == 5 {
+                return runtime_error("instruction budget exceeded".to_owned());
+            }
+            Ok(())
+        });
+        let error = state.call(Arc::new(proto), Vec::new()).err().unwrap();
+        assert_eq!(error.to_string(), "loop:1: instruction budget exceeded");
+        assert_eq!(calls.load(Ordering::Relaxed), 5);
+
+        // a hook can remove itself and let the script finish
+        let mut proto = Proto::new();
+        proto.instructions = vec![abc(OpCode::LoadNil, 0, 0, 0), abc(OpCode::Return, 0, 1, 0)];
+        proto.maxstacksize = 1;
+        state.set_count_hook(1, |state| {
+            state.remove_hook();
+            Ok(())
+        });
+        state.call(Arc::new(proto), Vec::new()).unwrap();
+        assert!(state.hook.is_none());
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        let error = run(vec![
+            Instruction::ABx { instruction: OpCode::LoadK, a: 0, bx: 0 },
+            abc(OpCode::Call, 0, 1, 1),
+        ], vec![SyxValue::Integer(1)], vec![]).unwrap_err();
+        assert_eq!(error.to_string(), "attempt to call a number value");
+
+        // 1 // 0 is inf when both are floats
+        if cfg!(not(feature = "float_only")) {
+            let error = run(vec![
+                abc(OpCode::IDiv, 0, 256, 257),
+            ], vec![SyxValue::Integer(1), SyxValue::Integer(0)], vec![]).unwrap_err();
+            assert_eq!(error.to_string(), "attempt to perform 'n//0'");
+        }
+
+        let error = run(vec![
+            abc(OpCode::Lt, 0, 0, 256),
+        ], vec![SyxValue::Integer(0)], vec![]).unwrap_err();
+        assert_eq!(error.to_string(), "attempt to compare nil with number");
+
+        let error = run(vec![
+            abc(OpCode::GetTable, 0, 0, 256),
+        ], vec![SyxValue::Integer(0)], vec![]).unwrap_err();
+        assert_eq!(error.to_string(), "attempt to index a nil value");
+    }
+}
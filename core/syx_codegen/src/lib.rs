@@ -1,18 +1,22 @@
 #![recursion_limit="1024"]
+#![allow(clippy::upper_case_acronyms)]
 
 extern crate proc_macro;
 use proc_macro::TokenStream;
 
 extern crate syn;
 use syn::parse::{Parse, ParseStream, Result, Error};
-use syn::punctuated::{Punctuated, Pair, IntoIter};
-use syn::{parse_macro_input, Expr, Ident, Token, Type, Visibility};
+use syn::punctuated::{Punctuated, IntoIter};
+use syn::{parse_macro_input, Expr, Ident, Token};
 use syn::spanned::Spanned;
-use syn::export::Span;
+extern crate proc_macro2;
+use proc_macro2::Span;
 
 extern crate quote;
 use quote::quote;
 
+// Argument types are only validated for now, not consumed
+#[allow(dead_code)]
 #[derive(Clone)]
 enum OpCodeType {
     ABC(Ident, Ident, Ident),
@@ -23,12 +27,14 @@ enum OpCodeType {
     Ax(Ident),
 }
 
+#[allow(dead_code)]
 #[derive(Clone)]
 struct OpCodeContainer(Ident, OpCodeType);
 
 struct OpCodeParse {
     instruction_name: Ident, // Name of Instruction type
     opcode_name: Ident, // Name of the OpCode variants, usually OpCodes
+    decoded_name: Ident, // Name of the enum with a variant for each opcode
     error_name: Ident, // Name of the error type to use
     error_expr: Expr, // Name of the expression used to generate errors
 
@@ -72,6 +78,8 @@ impl Parse for OpCodeParse {
         input.parse::<Token![|]>()?;
         let opcode_name = input.parse::<Ident>()?;
         input.parse::<Token![|]>()?;
+        let decoded_name = input.parse::<Ident>()?;
+        input.parse::<Token![|]>()?;
         let error_name = input.parse::<Ident>()?;
         input.parse::<Token![=]>()?;
         let error_expr = input.parse::<Expr>()?;
@@ -102,7 +110,7 @@ impl Parse for OpCodeParse {
             let arg_types_span = arg_types_punct.span();
             let mut arg_types = arg_types_punct.into_iter();
             let arg_count = arg_types.len();
-            let mut expected_arg_count = 0;
+            let expected_arg_count;
 
             // Match over variant and append OpCodeContainer to output
             match format.to_string().as_str() {
@@ -162,16 +170,17 @@ impl Parse for OpCodeParse {
             input.parse::<Token![;]>()?;
         }
         Ok(OpCodeParse {
-            instruction_name: instruction_name,
-            opcode_name: opcode_name,
-            error_name: error_name,
-            error_expr: error_expr,
-            abc: abc,
-            ab: ab,
-            a: a,
-            abx: abx,
-            asbx: asbx,
-            ax: ax,
+            instruction_name,
+            opcode_name,
+            decoded_name,
+            error_name,
+            error_expr,
+            abc,
+            ab,
+            a,
+            abx,
+            asbx,
+            ax,
             list: output
         })
     }
@@ -180,16 +189,17 @@ impl Parse for OpCodeParse {
 #[proc_macro]
 pub fn bytecode(input: TokenStream) -> TokenStream {
     let OpCodeParse {
-        instruction_name: instruction_name,
-        opcode_name: opcode_name,
-        error_name: error_name,
-        error_expr: error_expr,
-        abc: abc,
-        ab: ab,
-        a: a,
-        abx: abx,
-        asbx: asbx,
-        ax: ax,
+        instruction_name,
+        opcode_name,
+        decoded_name,
+        error_name,
+        error_expr,
+        abc,
+        ab,
+        a,
+        abx,
+        asbx,
+        ax,
         list: opcode_list,
     } = parse_macro_input!(input as OpCodeParse);
 
@@ -210,33 +220,14 @@ pub fn bytecode(input: TokenStream) -> TokenStream {
     let result = quote! {
         pub type Word = u32;
 
-        const SIZE_OP: u32 = 6;
-
-        const SIZE_C: u32 = 9;
-        const SIZE_B: u32 = 9;
-        const SIZE_BX: u32 = SIZE_C + SIZE_B;
-        const SIZE_A: u32 = 8;
-        const SIZE_AX: u32 = SIZE_C + SIZE_B + SIZE_A;
-
-        const OFFSET_OP: u32 = 0;
-        const OFFSET_A: u32 = (OFFSET_OP + SIZE_OP);
-        const OFFSET_C: u32 = (OFFSET_A + SIZE_A);
-        const OFFSET_B: u32 = (OFFSET_C + SIZE_C);
-
-        const OFFSET_BX: u32 = OFFSET_C;
-        const OFFSET_AX: u32 = OFFSET_A;
+        // the layout itself is in limits.rs
+        use super::limits::{
+            OFFSET_OP, OFFSET_A, OFFSET_B, OFFSET_C, OFFSET_BX, OFFSET_AX,
+            MAXARG_OP, MAXARG_A, MAXARG_B, MAXARG_C, MAXARG_BX, MAXARG_AX, MAXARG_SBX, BITRK,
+        };
 
-        const BITMASK_OP: u32 = (1 << SIZE_OP) - 1;
-        const BITMASK_A: u32 = (1 << SIZE_A) - 1;
-        const BITMASK_AX: u32 = (1 << SIZE_AX) - 1;
-        const BITMASK_B: u32 = (1 << SIZE_B) - 1;
-        const BITMASK_BX: u32 = (1 << SIZE_BX) - 1;
-        const BITMASK_C: u32 = (1 << SIZE_C) - 1;
-
-        const BITMASK_IS_RK: u32 = 1 << (SIZE_B - 1);
-
-        // Is constant: C & BITMASK_IS_RK == 1
-        // Register number: (n as u32) & ~BITMASK_IS_RK
+        // Is constant: C & BITRK == 1
+        // Register number: (n as u32) & ~BITRK
 
         enum Argument {
             Register(u32),
@@ -254,13 +245,12 @@ pub fn bytecode(input: TokenStream) -> TokenStream {
                     Argument::Register(n) => write!(f, "Register({})", n),
                     Argument::Constant(n) => write!(f, "Constant({})", n),
                     Argument::RegisterConstant(n) => {
-                        write!(f, "RegisterConstant(");
-                        if n & BITMASK_IS_RK == 0 {
-                            write!(f, "Register({}))", n);
+                        write!(f, "RegisterConstant(")?;
+                        if n & BITRK == 0 {
+                            write!(f, "Register({}))", n)
                         } else {
-                            write!(f, "Constant({}))", n & !BITMASK_IS_RK);
+                            write!(f, "Constant({}))", n & !BITRK)
                         }
-                        Ok(())
                     },
                     _ => unimplemented!()
                 }
@@ -268,7 +258,7 @@ pub fn bytecode(input: TokenStream) -> TokenStream {
         }
 
         // Generate variants for opcode field names
-        #[derive(Debug, Eq, PartialEq)]
+        #[derive(Clone, Copy, Debug, Eq, PartialEq)]
         pub enum #opcode_name {
         #(
             #opcode_variant,
@@ -290,7 +280,7 @@ pub fn bytecode(input: TokenStream) -> TokenStream {
         }
 
         // Structure to hold bytecode variant types
-        #[derive(Debug, PartialEq)]
+        #[derive(Clone, Debug, PartialEq)]
         pub enum #instruction_name {
             ABC {
                 instruction: #opcode_name,
@@ -318,7 +308,7 @@ pub fn bytecode(input: TokenStream) -> TokenStream {
             type Error = #error_name;
 
             fn try_from(instr: Word) -> Result<#instruction_name> {
-                let opcode = (instr >> OFFSET_OP) & BITMASK_OP;
+                let opcode = (instr >> OFFSET_OP) & MAXARG_OP;
                 let _enum: #opcode_name = #opcode_name::try_from(opcode as u8)?;
                 Ok(match _enum {
                     #(
@@ -331,35 +321,257 @@ pub fn bytecode(input: TokenStream) -> TokenStream {
                     | #opcode_name::#a
                     )* => #instruction_name::ABC {
                         instruction: _enum,
-                        a: ((instr >> OFFSET_A) & BITMASK_A) as u8,
-                        b: ((instr >> OFFSET_B) & BITMASK_B) as u16,
-                        c: ((instr >> OFFSET_C) & BITMASK_C) as u16,
+                        a: ((instr >> OFFSET_A) & MAXARG_A) as u8,
+                        b: ((instr >> OFFSET_B) & MAXARG_B) as u16,
+                        c: ((instr >> OFFSET_C) & MAXARG_C) as u16,
                     },
                     #(
                     | #opcode_name::#abx
                     )* => #instruction_name::ABx {
                         instruction: _enum,
-                        a: ((instr >> OFFSET_A) & BITMASK_A) as u8,
-                        bx: ((instr >> OFFSET_BX) & BITMASK_BX) as u32,
+                        a: ((instr >> OFFSET_A) & MAXARG_A) as u8,
+                        bx: ((instr >> OFFSET_BX) & MAXARG_BX) as u32,
                     },
                     #(
                     | #opcode_name::#asbx
                     )* => #instruction_name::AsBx {
                         instruction: _enum,
-                        a: ((instr >> OFFSET_A) & BITMASK_A) as u8,
-                        sbx: ((instr >> OFFSET_BX) & BITMASK_BX) as i32,
+                        a: ((instr >> OFFSET_A) & MAXARG_A) as u8,
+                        sbx: ((instr >> OFFSET_BX) & MAXARG_BX) as i32 - MAXARG_SBX,
                     },
                     #(
                     | #opcode_name::#ax
                     )* => #instruction_name::Ax {
                         instruction: _enum,
-                        ax: ((instr >> OFFSET_A) & BITMASK_AX) as u32,
+                        ax: ((instr >> OFFSET_A) & MAXARG_AX) as u32,
                     }
                 })
             }
         }
 
+        // Reverse of TryFrom<Word>, used when writing chunks back out
+        impl<'a> ::std::convert::From<&'a #instruction_name> for Word {
+            fn from(instr: &'a #instruction_name) -> Word {
+                match *instr {
+                    #instruction_name::ABC { instruction, a, b, c } => {
+                        ((instruction as Word & MAXARG_OP) << OFFSET_OP)
+                            | ((a as Word & MAXARG_A) << OFFSET_A)
+                            | ((b as Word & MAXARG_B) << OFFSET_B)
+                            | ((c as Word & MAXARG_C) << OFFSET_C)
+                    },
+                    #instruction_name::ABx { instruction, a, bx } => {
+                        ((instruction as Word & MAXARG_OP) << OFFSET_OP)
+                            | ((a as Word & MAXARG_A) << OFFSET_A)
+                            | ((bx & MAXARG_BX) << OFFSET_BX)
+                    },
+                    #instruction_name::AsBx { instruction, a, sbx } => {
+                        ((instruction as Word & MAXARG_OP) << OFFSET_OP)
+                            | ((a as Word & MAXARG_A) << OFFSET_A)
+                            | (((sbx + MAXARG_SBX) as Word & MAXARG_BX) << OFFSET_BX)
+                    },
+                    #instruction_name::Ax { instruction, ax } => {
+                        ((instruction as Word & MAXARG_OP) << OFFSET_OP)
+                            | ((ax & MAXARG_AX) << OFFSET_AX)
+                    },
+                }
+            }
+        }
+
+        // One variant for every opcode, with the operands of its format
+        #[derive(Clone, Copy, Debug, PartialEq)]
+        pub enum #decoded_name {
+            #( #abc { a: u8, b: u16, c: u16 }, )*
+            #( #ab { a: u8, b: u16 }, )*
+            #( #a { a: u8 }, )*
+            #( #abx { a: u8, bx: u32 }, )*
+            #( #asbx { a: u8, sbx: i32 }, )*
+            #( #ax { ax: u32 }, )*
+        }
+
+        impl #decoded_name {
+            pub fn opcode(&self) -> #opcode_name {
+                match *self {
+                    #( #decoded_name::#abc { .. } => #opcode_name::#abc, )*
+                    #( #decoded_name::#ab { .. } => #opcode_name::#ab, )*
+                    #( #decoded_name::#a { .. } => #opcode_name::#a, )*
+                    #( #decoded_name::#abx { .. } => #opcode_name::#abx, )*
+                    #( #decoded_name::#asbx { .. } => #opcode_name::#asbx, )*
+                    #( #decoded_name::#ax { .. } => #opcode_name::#ax, )*
+                }
+            }
+        }
+
+        impl ::std::convert::TryFrom<Word> for #decoded_name {
+            type Error = #error_name;
+
+            fn try_from(instr: Word) -> Result<#decoded_name> {
+                let a = ((instr >> OFFSET_A) & MAXARG_A) as u8;
+                let b = ((instr >> OFFSET_B) & MAXARG_B) as u16;
+                let c = ((instr >> OFFSET_C) & MAXARG_C) as u16;
+                let bx = (instr >> OFFSET_BX) & MAXARG_BX;
+                let ax = (instr >> OFFSET_AX) & MAXARG_AX;
+                let opcode = (instr >> OFFSET_OP) & MAXARG_OP;
+                Ok(match #opcode_name::try_from(opcode as u8)? {
+                    #( #opcode_name::#abc => #decoded_name::#abc { a, b, c }, )*
+                    #( #opcode_name::#ab => #decoded_name::#ab { a, b }, )*
+                    #( #opcode_name::#a => #decoded_name::#a { a }, )*
+                    #( #opcode_name::#abx => #decoded_name::#abx { a, bx }, )*
+                    #( #opcode_name::#asbx => #decoded_name::#asbx {
+                        a,
+                        sbx: bx as i32 - MAXARG_SBX,
+                    }, )*
+                    #( #opcode_name::#ax => #decoded_name::#ax { ax }, )*
+                })
+            }
+        }
+
+        // Operands an instruction has no use for are left out, as zero
+        impl<'a> ::std::convert::From<&'a #instruction_name> for #decoded_name {
+            fn from(instr: &'a #instruction_name) -> #decoded_name {
+                let opcode = match *instr {
+                    #instruction_name::ABC { instruction, .. } |
+                    #instruction_name::ABx { instruction, .. } |
+                    #instruction_name::AsBx { instruction, .. } |
+                    #instruction_name::Ax { instruction, .. } => instruction,
+                };
+                let instr = Word::from(instr);
+                let a = ((instr >> OFFSET_A) & MAXARG_A) as u8;
+                let b = ((instr >> OFFSET_B) & MAXARG_B) as u16;
+                let c = ((instr >> OFFSET_C) & MAXARG_C) as u16;
+                let bx = (instr >> OFFSET_BX) & MAXARG_BX;
+                let ax = (instr >> OFFSET_AX) & MAXARG_AX;
+                match opcode {
+                    #( #opcode_name::#abc => #decoded_name::#abc { a, b, c }, )*
+                    #( #opcode_name::#ab => #decoded_name::#ab { a, b }, )*
+                    #( #opcode_name::#a => #decoded_name::#a { a }, )*
+                    #( #opcode_name::#abx => #decoded_name::#abx { a, bx }, )*
+                    #( #opcode_name::#asbx => #decoded_name::#asbx {
+                        a,
+                        sbx: bx as i32 - MAXARG_SBX,
+                    }, )*
+                    #( #opcode_name::#ax => #decoded_name::#ax { ax }, )*
+                }
+            }
+        }
+
+        impl<'a> ::std::convert::From<&'a #decoded_name> for #instruction_name {
+            fn from(decoded: &'a #decoded_name) -> #instruction_name {
+                let instruction = decoded.opcode();
+                match *decoded {
+                    #( #decoded_name::#abc { a, b, c } )|*
+                    => #instruction_name::ABC { instruction, a, b, c },
+                    #( #decoded_name::#ab { a, b } )|*
+                    => #instruction_name::ABC { instruction, a, b, c: 0 },
+                    #( #decoded_name::#a { a } )|*
+                    => #instruction_name::ABC { instruction, a, b: 0, c: 0 },
+                    #( #decoded_name::#abx { a, bx } )|*
+                    => #instruction_name::ABx { instruction, a, bx },
+                    #( #decoded_name::#asbx { a, sbx } )|*
+                    => #instruction_name::AsBx { instruction, a, sbx },
+                    #( #decoded_name::#ax { ax } )|*
+                    => #instruction_name::Ax { instruction, ax },
+                }
+            }
+        }
+
+        impl<'a> ::std::convert::From<&'a #decoded_name> for Word {
+            fn from(decoded: &'a #decoded_name) -> Word {
+                Word::from(&#instruction_name::from(decoded))
+            }
+        }
+
+    };
+
+    result.into()
+}
+
+// Fields of a struct to convert, as table keys and the expressions naming
+// them on `self`
+fn struct_fields(input: &syn::DeriveInput) -> Result<Vec<(proc_macro2::TokenStream, syn::Member)>> {
+    let fields = match input.data {
+        syn::Data::Struct(ref data) => &data.fields,
+        _ => return Err(Error::new(input.ident.span(), "only structs can be converted")),
     };
+    Ok(fields.iter().enumerate().map(|(i, field)| match field.ident {
+        Some(ref ident) => {
+            let name = ident.to_string();
+            (quote! { #name }, syn::Member::Named(ident.clone()))
+        }
+        None => {
+            // unsuffixed, to be whatever width SyxInteger is
+            let key = proc_macro2::Literal::usize_unsuffixed(i + 1);
+            (quote! { #key }, syn::Member::Unnamed(syn::Index::from(i)))
+        }
+    }).collect())
+}
 
+// Named fields are stored under their name, those of a tuple struct from 1
+fn set_key(key: &proc_macro2::TokenStream, named: bool) -> proc_macro2::TokenStream {
+    if named {
+        quote! { ::syx::object::SyxValue::String(state.intern(#key.as_bytes())) }
+    } else {
+        quote! { ::syx::object::SyxValue::Integer(#key) }
+    }
+}
+
+#[proc_macro_derive(IntoSyx)]
+pub fn derive_into_syx(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as syn::DeriveInput);
+    let fields = match struct_fields(&input) {
+        Ok(fields) => fields,
+        Err(error) => return error.to_compile_error().into(),
+    };
+    let name = &input.ident;
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+    let count = fields.len();
+    let sets = fields.iter().map(|(key, member)| {
+        let key = set_key(key, matches!(member, syn::Member::Named(_)));
+        quote! {
+            let key = #key;
+            let value = ::syx::convert::IntoSyx::into_syx(self.#member, state)?;
+            ::syx::convert::set_field(state, table, key, value)?;
+        }
+    });
+    let result = quote! {
+        impl #impl_generics ::syx::convert::IntoSyx for #name #type_generics #where_clause {
+            fn into_syx(self, state: &mut ::syx::state::SyxState)
+                -> ::syx::errors::Result<::syx::object::SyxValue>
+            {
+                let table = state.new_table(0, #count);
+                #(#sets)*
+                Ok(::syx::object::SyxValue::Table(table))
+            }
+        }
+    };
+    result.into()
+}
+
+#[proc_macro_derive(FromSyx)]
+pub fn derive_from_syx(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as syn::DeriveInput);
+    let fields = match struct_fields(&input) {
+        Ok(fields) => fields,
+        Err(error) => return error.to_compile_error().into(),
+    };
+    let name = &input.ident;
+    let type_name = name.to_string();
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+    let gets = fields.iter().map(|(key, member)| {
+        let value = match *member {
+            syn::Member::Named(_) => quote! { ::syx::convert::get_field(state, table, #key) },
+            syn::Member::Unnamed(_) => quote! { state.table(table).get_int(#key) },
+        };
+        quote! { #member: ::syx::convert::FromSyx::from_syx(#value, state)? }
+    });
+    let result = quote! {
+        impl #impl_generics ::syx::convert::FromSyx for #name #type_generics #where_clause {
+            fn from_syx(value: ::syx::object::SyxValue, state: &::syx::state::SyxState)
+                -> ::syx::errors::Result<Self>
+            {
+                let table = ::syx::convert::check_table(&value, #type_name)?;
+                Ok(#name { #(#gets),* })
+            }
+        }
+    };
     result.into()
 }